@@ -0,0 +1,559 @@
+//! Automatic certificates over ACME
+//!
+//! A minimal ACME (RFC 8555) client using HTTP-01 challenges: the server
+//! answers `/.well-known/acme-challenge/<token>` on a plain HTTP listener
+//! (port 80 by default), orders a certificate for the configured domains,
+//! and writes it to the certificate and key paths of the TLS
+//! configuration, from which it is loaded into [`crate::tls::Tls`].
+//! Certificates are renewed once their file is older than the renewal age.
+
+use crate::tls::Tls;
+use crate::{ApiError, ApiResult};
+use axum::extract::{Path as AxumPath, State};
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::Router;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, warn};
+
+/// Let's Encrypt production directory
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+/// Default age of a certificate after which it is renewed (Let's Encrypt
+/// certificates are valid for 90 days)
+const DEFAULT_RENEW_AFTER: Duration = Duration::from_secs(60 * 24 * 3600);
+/// Delay before retrying a failed issuance
+const RETRY_DELAY: Duration = Duration::from_secs(3600);
+/// Interval between two checks of the certificate age
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+
+/// Configuration of the ACME client
+#[derive(Debug, Clone)]
+pub struct AcmeConfig {
+    /// Domains the certificate is issued for
+    pub domains: Vec<String>,
+    /// Contact email of the account
+    pub email: Option<String>,
+    /// URL of the ACME directory
+    pub directory: String,
+    /// Address of the HTTP listener answering challenges
+    pub http_bind: String,
+    /// File holding the account key, created if missing
+    pub account_key_path: PathBuf,
+    /// Age of the certificate after which it is renewed
+    pub renew_after: Duration,
+}
+
+impl AcmeConfig {
+    /// Read the configuration from `ATOMIC_API_ACME_DOMAINS`
+    /// (comma-separated), `ATOMIC_API_ACME_EMAIL`,
+    /// `ATOMIC_API_ACME_DIRECTORY`, `ATOMIC_API_ACME_HTTP_BIND`,
+    /// `ATOMIC_API_ACME_ACCOUNT_KEY` and `ATOMIC_API_ACME_RENEW_DAYS`. ACME
+    /// is disabled if no domain is set.
+    pub fn from_env(cert_path: &Path) -> Option<Self> {
+        let domains: Vec<String> = std::env::var("ATOMIC_API_ACME_DOMAINS")
+            .ok()?
+            .split(',')
+            .map(|d| d.trim().to_string())
+            .filter(|d| !d.is_empty())
+            .collect();
+        if domains.is_empty() {
+            return None;
+        }
+        Some(AcmeConfig {
+            domains,
+            email: std::env::var("ATOMIC_API_ACME_EMAIL").ok(),
+            directory: std::env::var("ATOMIC_API_ACME_DIRECTORY")
+                .unwrap_or_else(|_| LETS_ENCRYPT_DIRECTORY.to_string()),
+            http_bind: std::env::var("ATOMIC_API_ACME_HTTP_BIND")
+                .unwrap_or_else(|_| "0.0.0.0:80".to_string()),
+            account_key_path: std::env::var_os("ATOMIC_API_ACME_ACCOUNT_KEY")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| cert_path.with_file_name("acme-account.der")),
+            renew_after: std::env::var("ATOMIC_API_ACME_RENEW_DAYS")
+                .ok()
+                .and_then(|s| s.parse::<u64>().ok())
+                .map(|d| Duration::from_secs(d * 24 * 3600))
+                .unwrap_or(DEFAULT_RENEW_AFTER),
+        })
+    }
+}
+
+/// Pending HTTP-01 challenges, by token
+#[derive(Debug, Clone, Default)]
+pub struct Challenges(Arc<RwLock<HashMap<String, String>>>);
+
+impl Challenges {
+    fn insert(&self, token: String, key_authorization: String) {
+        self.0.write().unwrap().insert(token, key_authorization);
+    }
+
+    fn remove(&self, token: &str) {
+        self.0.write().unwrap().remove(token);
+    }
+
+    /// Router answering the challenges
+    pub fn router(&self) -> Router {
+        Router::new()
+            .route("/.well-known/acme-challenge/:token", get(answer_challenge))
+            .with_state(self.clone())
+    }
+}
+
+async fn answer_challenge(
+    State(challenges): State<Challenges>,
+    AxumPath(token): AxumPath<String>,
+) -> Result<String, StatusCode> {
+    let key_authorization = challenges.0.read().unwrap().get(&token).cloned();
+    key_authorization.ok_or(StatusCode::NOT_FOUND)
+}
+
+/// Start the challenge listener and the renewal task
+pub async fn spawn(config: AcmeConfig, tls: Tls) -> ApiResult<()> {
+    let challenges = Challenges::default();
+    let listener = tokio::net::TcpListener::bind(&config.http_bind)
+        .await
+        .map_err(|e| {
+            ApiError::internal(format!(
+                "Failed to bind ACME challenge listener to {}: {}",
+                config.http_bind, e
+            ))
+        })?;
+    info!("Answering ACME challenges on {}", config.http_bind);
+    let router = challenges.router();
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, router).await {
+            warn!("ACME challenge listener stopped: {}", e);
+        }
+    });
+
+    tokio::spawn(async move {
+        let cert_path = tls.config().cert_path.clone();
+        let key_path = tls.config().key_path.clone();
+        loop {
+            if !needs_renewal(&cert_path, config.renew_after) {
+                tokio::time::sleep(CHECK_INTERVAL).await;
+                continue;
+            }
+            info!("Requesting certificate for {:?}", config.domains);
+            let result = async {
+                let (cert, key) = issue(&config, &challenges).await?;
+                // The key is written first, so that the reload task never
+                // pairs the new certificate with the old key.
+                write_atomic(&key_path, key.as_bytes())?;
+                write_atomic(&cert_path, cert.as_bytes())?;
+                tls.resolver().reload(&cert_path, &key_path)
+            }
+            .await;
+            match result {
+                Ok(()) => info!("Certificate issued for {:?}", config.domains),
+                Err(e) => {
+                    warn!("Failed to obtain certificate: {}", e);
+                    tokio::time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+fn needs_renewal(cert_path: &Path, renew_after: Duration) -> bool {
+    let modified = std::fs::metadata(cert_path).and_then(|m| m.modified());
+    match modified {
+        Ok(m) => SystemTime::now()
+            .duration_since(m)
+            .map(|age| age >= renew_after)
+            .unwrap_or(false),
+        Err(_) => true,
+    }
+}
+
+fn write_atomic(path: &Path, contents: &[u8]) -> ApiResult<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, contents)
+        .and_then(|_| std::fs::rename(&tmp, path))
+        .map_err(|e| ApiError::internal(format!("Failed to write {}: {}", path.display(), e)))
+}
+
+fn err<E: std::fmt::Display>(what: &str) -> impl FnOnce(E) -> ApiError + '_ {
+    move |e| ApiError::internal(format!("ACME: {}: {}", what, e))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    status: String,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    type_: String,
+    url: String,
+    token: String,
+}
+
+/// An ACME account, signing its requests with an ECDSA P-256 key
+struct Account {
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    client: reqwest::Client,
+    directory: Directory,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Account {
+    async fn open(config: &AcmeConfig) -> ApiResult<Self> {
+        let rng = SystemRandom::new();
+        let pkcs8 = match std::fs::read(&config.account_key_path) {
+            Ok(k) => k,
+            Err(_) => {
+                let k = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                    .map_err(err("generating account key"))?;
+                write_atomic(&config.account_key_path, k.as_ref())?;
+                k.as_ref().to_vec()
+            }
+        };
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(err("reading account key"))?;
+        let client = reqwest::Client::new();
+        let directory = client
+            .get(&config.directory)
+            .send()
+            .await
+            .map_err(err("fetching directory"))?
+            .json()
+            .await
+            .map_err(err("parsing directory"))?;
+        let mut account = Account {
+            key,
+            rng,
+            client,
+            directory,
+            kid: None,
+            nonce: None,
+        };
+        let contact: Vec<String> = config
+            .email
+            .iter()
+            .map(|e| format!("mailto:{}", e))
+            .collect();
+        let url = account.directory.new_account.clone();
+        let res = account
+            .post(
+                &url,
+                Some(&json!({ "termsOfServiceAgreed": true, "contact": contact })),
+            )
+            .await?;
+        account.kid = Some(location(&res)?);
+        Ok(account)
+    }
+
+    /// Signed request, with an empty payload for POST-as-GET
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> ApiResult<reqwest::Response> {
+        // A bad nonce is retried once with the fresh nonce the server sent.
+        for _ in 0..2 {
+            let nonce = match self.nonce.take() {
+                Some(n) => n,
+                None => self.new_nonce().await?,
+            };
+            let body = self.jws(url, &nonce, payload)?;
+            let res = self
+                .client
+                .post(url)
+                .header("Content-Type", "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await
+                .map_err(err(url))?;
+            self.nonce = replay_nonce(&res);
+            if res.status().is_success() {
+                return Ok(res);
+            }
+            let status = res.status();
+            let problem: Value = res.json().await.unwrap_or_default();
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                debug!("ACME: bad nonce, retrying");
+                continue;
+            }
+            return Err(ApiError::internal(format!(
+                "ACME: {} returned {}: {}",
+                url, status, problem
+            )));
+        }
+        Err(ApiError::internal(format!("ACME: {}: bad nonce", url)))
+    }
+
+    async fn new_nonce(&self) -> ApiResult<String> {
+        let res = self
+            .client
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .map_err(err("fetching nonce"))?;
+        replay_nonce(&res).ok_or_else(|| ApiError::internal("ACME: no nonce".to_string()))
+    }
+
+    fn jwk(&self) -> Value {
+        let public = self.key.public_key().as_ref();
+        // Uncompressed point: 0x04 || x || y
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": URL_SAFE_NO_PAD.encode(&public[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&public[33..65]),
+        })
+    }
+
+    /// JWK thumbprint (RFC 7638), used in key authorizations
+    fn thumbprint(&self) -> String {
+        let jwk = self.jwk();
+        // Required members only, in lexicographic order, no whitespace.
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#,
+            jwk["x"], jwk["y"]
+        );
+        URL_SAFE_NO_PAD.encode(ring::digest::digest(
+            &ring::digest::SHA256,
+            canonical.as_bytes(),
+        ))
+    }
+
+    fn key_authorization(&self, token: &str) -> String {
+        format!("{}.{}", token, self.thumbprint())
+    }
+
+    fn jws(&self, url: &str, nonce: &str, payload: Option<&Value>) -> ApiResult<Value> {
+        let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+        match self.kid {
+            Some(ref kid) => protected["kid"] = json!(kid),
+            None => protected["jwk"] = self.jwk(),
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = payload
+            .map(|p| URL_SAFE_NO_PAD.encode(p.to_string()))
+            .unwrap_or_default();
+        let signature = self
+            .key
+            .sign(&self.rng, format!("{}.{}", protected, payload).as_bytes())
+            .map_err(err("signing request"))?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        }))
+    }
+
+    async fn get<T: serde::de::DeserializeOwned>(&mut self, url: &str) -> ApiResult<T> {
+        self.post(url, None).await?.json().await.map_err(err(url))
+    }
+}
+
+fn replay_nonce(res: &reqwest::Response) -> Option<String> {
+    res.headers()
+        .get("replay-nonce")
+        .and_then(|n| n.to_str().ok())
+        .map(String::from)
+}
+
+fn location(res: &reqwest::Response) -> ApiResult<String> {
+    res.headers()
+        .get(reqwest::header::LOCATION)
+        .and_then(|l| l.to_str().ok())
+        .map(String::from)
+        .ok_or_else(|| ApiError::internal("ACME: missing Location header".to_string()))
+}
+
+/// Poll `url` until `status` leaves the pending states
+async fn poll<T, F>(account: &mut Account, url: &str, status: F) -> ApiResult<T>
+where
+    T: serde::de::DeserializeOwned,
+    F: Fn(&T) -> &str,
+{
+    for _ in 0..30 {
+        let t: T = account.get(url).await?;
+        match status(&t) {
+            "pending" | "processing" => tokio::time::sleep(Duration::from_secs(2)).await,
+            _ => return Ok(t),
+        }
+    }
+    Err(ApiError::internal(format!(
+        "ACME: timed out polling {}",
+        url
+    )))
+}
+
+/// Order a certificate for the configured domains, returning the PEM
+/// certificate chain and private key.
+async fn issue(config: &AcmeConfig, challenges: &Challenges) -> ApiResult<(String, String)> {
+    let mut account = Account::open(config).await?;
+
+    let identifiers: Vec<Value> = config
+        .domains
+        .iter()
+        .map(|d| json!({ "type": "dns", "value": d }))
+        .collect();
+    let new_order = account.directory.new_order.clone();
+    let res = account
+        .post(&new_order, Some(&json!({ "identifiers": identifiers })))
+        .await?;
+    let order_url = location(&res)?;
+    let order: Order = res.json().await.map_err(err("parsing order"))?;
+
+    for authz_url in order.authorizations.iter() {
+        let authz: Authorization = account.get(authz_url).await?;
+        if authz.status == "valid" {
+            continue;
+        }
+        let challenge = authz
+            .challenges
+            .iter()
+            .find(|c| c.type_ == "http-01")
+            .ok_or_else(|| ApiError::internal("ACME: no http-01 challenge".to_string()))?;
+        challenges.insert(
+            challenge.token.clone(),
+            account.key_authorization(&challenge.token),
+        );
+        let result = async {
+            account.post(&challenge.url, Some(&json!({}))).await?;
+            poll(&mut account, authz_url, |a: &Authorization| &a.status).await
+        }
+        .await;
+        challenges.remove(&challenge.token);
+        let authz = result?;
+        if authz.status != "valid" {
+            return Err(ApiError::internal(format!(
+                "ACME: authorization {} is {}",
+                authz_url, authz.status
+            )));
+        }
+    }
+
+    let mut params = rcgen::CertificateParams::new(config.domains.clone());
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    let cert = rcgen::Certificate::from_params(params).map_err(err("generating key"))?;
+    let csr = cert
+        .serialize_request_der()
+        .map_err(err("generating request"))?;
+    account
+        .post(
+            &order.finalize,
+            Some(&json!({ "csr": URL_SAFE_NO_PAD.encode(csr) })),
+        )
+        .await?;
+    let order = poll(&mut account, &order_url, |o: &Order| &o.status).await?;
+    let cert_url = match (order.status.as_str(), order.certificate) {
+        ("valid", Some(url)) => url,
+        (status, _) => {
+            return Err(ApiError::internal(format!("ACME: order is {}", status)));
+        }
+    };
+    let chain = account
+        .post(&cert_url, None)
+        .await?
+        .text()
+        .await
+        .map_err(err("downloading certificate"))?;
+    Ok((chain, cert.serialize_private_key_pem()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+
+    fn account() -> Account {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        Account {
+            key: EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                .unwrap(),
+            rng,
+            client: reqwest::Client::new(),
+            directory: Directory {
+                new_nonce: String::new(),
+                new_account: String::new(),
+                new_order: String::new(),
+            },
+            kid: None,
+            nonce: None,
+        }
+    }
+
+    #[test]
+    fn test_jws_signature() {
+        let mut account = account();
+        let jws = account
+            .jws("https://acme.test/new-order", "nonce", Some(&json!({})))
+            .unwrap();
+        let protected: Value = serde_json::from_slice(
+            &URL_SAFE_NO_PAD
+                .decode(jws["protected"].as_str().unwrap())
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(protected["jwk"]["kty"], "EC");
+        let input = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap(),
+            jws["payload"].as_str().unwrap()
+        );
+        let signature = URL_SAFE_NO_PAD
+            .decode(jws["signature"].as_str().unwrap())
+            .unwrap();
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, account.key.public_key().as_ref())
+            .verify(input.as_bytes(), &signature)
+            .unwrap();
+
+        // Once registered, requests name the account instead of its key.
+        account.kid = Some("https://acme.test/acct/1".to_string());
+        let jws = account.jws("https://acme.test/order", "n", None).unwrap();
+        assert_eq!(jws["payload"], "");
+        let protected: Value = serde_json::from_slice(
+            &URL_SAFE_NO_PAD
+                .decode(jws["protected"].as_str().unwrap())
+                .unwrap(),
+        )
+        .unwrap();
+        assert!(protected.get("jwk").is_none());
+        assert_eq!(protected["kid"], "https://acme.test/acct/1");
+    }
+
+    #[test]
+    fn test_key_authorization() {
+        let account = account();
+        let ka = account.key_authorization("token");
+        let (token, thumbprint) = ka.split_once('.').unwrap();
+        assert_eq!(token, "token");
+        // base64url of a SHA-256 digest
+        assert_eq!(thumbprint.len(), 43);
+        assert_eq!(account.key_authorization("token"), ka);
+    }
+}
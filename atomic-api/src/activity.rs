@@ -0,0 +1,212 @@
+//! Server-Sent Events stream of repository activity
+//!
+//! Clients behind proxies that don't pass WebSockets follow a repository
+//! with `GET /tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/events`,
+//! a `text/event-stream` of the events of the event log (see
+//! [`crate::event_log`]) about that repository: the changes and tags
+//! applied by applies and pushes (`node_applied`), the tags created
+//! (`tag_created`) and the remotes found diverged (`remote_diverged`).
+//!
+//! Each event is sent with its sequence number in the log as `id`, its
+//! name as `event`, and the recorded event as JSON `data`, with the
+//! repository relative to the base mount path. A client reconnecting
+//! with `Last-Event-ID` (which `EventSource` sends on its own), or with
+//! `?last_event_id=` on its first connection, first gets the events
+//! recorded since, as long as the log still has them. Comments are sent
+//! every [`KEEP_ALIVE`] so that proxies don't close idle streams, and the
+//! streams end when the server shuts down.
+
+use crate::event_log::{self, EventLog, RecordedEvent};
+use crate::shutdown::Shutdown;
+use crate::{ApiError, ApiResult};
+use axum::http::HeaderMap;
+use axum::response::sse::{Event as SseEvent, KeepAlive, Sse};
+use futures_util::Stream;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::debug;
+
+/// Interval of the keep-alive comments
+pub const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Header of the last event a reconnecting client received
+const LAST_EVENT_ID: &str = "last-event-id";
+
+/// Query parameters of `GET .../events`
+#[derive(Debug, Deserialize)]
+pub struct ActivityQuery {
+    /// Resume after this event, unless `Last-Event-ID` is sent
+    pub last_event_id: Option<u64>,
+}
+
+/// The sequence number of the last event the client received, from
+/// `Last-Event-ID` or else the query
+pub fn resume_after(headers: &HeaderMap, query: &ActivityQuery) -> ApiResult<Option<u64>> {
+    match headers.get(LAST_EVENT_ID) {
+        Some(value) => value
+            .to_str()
+            .ok()
+            .and_then(|s| s.trim().parse().ok())
+            .map(Some)
+            .ok_or_else(|| ApiError::invalid_query("Invalid Last-Event-ID")),
+        None => Ok(query.last_event_id),
+    }
+}
+
+/// The events of a repository, read from the log then as they are
+/// recorded
+struct Follower {
+    log: EventLog,
+    receiver: broadcast::Receiver<RecordedEvent>,
+    /// Events read from the log and not sent yet
+    backlog: VecDeque<RecordedEvent>,
+    /// Sequence number of the last event read
+    last: u64,
+    repository: PathBuf,
+    base: PathBuf,
+    shutdown: Shutdown,
+}
+
+impl Follower {
+    /// The next event of the repository, or `None` once the server shuts
+    /// down
+    async fn next(&mut self) -> Option<RecordedEvent> {
+        loop {
+            while let Some(recorded) = self.backlog.pop_front() {
+                // Events read both from the log and live are sent once.
+                if recorded.seq <= self.last {
+                    continue;
+                }
+                self.last = recorded.seq;
+                if event_log::repository(&recorded.event) == Some(self.repository.as_path()) {
+                    return Some(recorded);
+                }
+            }
+            let received = tokio::select! {
+                _ = self.shutdown.draining() => return None,
+                received = self.receiver.recv() => received,
+            };
+            match received {
+                Ok(recorded) => self.backlog.push_back(recorded),
+                Err(RecvError::Lagged(n)) => {
+                    debug!("Activity stream lagged by {} events", n);
+                    self.backlog.extend(self.log.since(self.last))
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// `recorded`, with the repository relative to the base mount path
+    fn relative(&self, recorded: RecordedEvent) -> RecordedEvent {
+        RecordedEvent {
+            event: event_log::relative(recorded.event, &self.base),
+            ..recorded
+        }
+    }
+
+    fn sse(&self, recorded: RecordedEvent) -> SseEvent {
+        let name = event_log::name(&recorded.event);
+        let recorded = self.relative(recorded);
+        SseEvent::default()
+            .id(recorded.seq.to_string())
+            .event(name)
+            .json_data(&recorded)
+            .unwrap_or_else(|_| SseEvent::default().comment("unserializable event"))
+    }
+}
+
+/// Stream the events of `log` about `repository`, after `after` if
+/// resuming, with the repository relative to `base`, until `shutdown`
+/// starts
+pub fn stream(
+    log: &EventLog,
+    repository: PathBuf,
+    base: PathBuf,
+    after: Option<u64>,
+    shutdown: Shutdown,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    // Follow first, so that no event is missed between the log and the
+    // live events.
+    let receiver = log.follow();
+    let backlog = after.map(|a| log.since(a)).unwrap_or_default();
+    let follower = Follower {
+        log: log.clone(),
+        receiver,
+        backlog: backlog.into(),
+        last: after.unwrap_or(0),
+        repository,
+        base,
+        shutdown,
+    };
+    let events = futures_util::stream::unfold(follower, |mut follower| async move {
+        let recorded = follower.next().await?;
+        Some((Ok(follower.sse(recorded)), follower))
+    });
+    Sse::new(events).keep_alive(KeepAlive::new().interval(KEEP_ALIVE))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_config::events::Event;
+
+    fn tag(repository: &str, state: &str) -> Event {
+        Event::TagCreated {
+            repository: repository.into(),
+            channel: "main".to_string(),
+            state: state.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_follower() {
+        let log = EventLog::new(10);
+        log.record(tag("/mnt/t/p/x", "A"));
+        log.record(tag("/mnt/t/p/y", "B"));
+        log.record(tag("/mnt/t/p/x", "C"));
+
+        let shutdown = Shutdown::default();
+        let mut follower = Follower {
+            log: log.clone(),
+            receiver: log.follow(),
+            backlog: log.since(1).into(),
+            last: 1,
+            repository: "/mnt/t/p/x".into(),
+            base: "/mnt".into(),
+            shutdown: shutdown.clone(),
+        };
+        // Resumed from the log, skipping the other repositories.
+        assert_eq!(follower.next().await.unwrap().seq, 3);
+
+        log.record(tag("/mnt/t/p/y", "D"));
+        log.record(tag("/mnt/t/p/x", "E"));
+        let next = follower.next().await.unwrap();
+        let recorded = follower.relative(next);
+        assert_eq!(recorded.seq, 5);
+        assert_eq!(
+            event_log::repository(&recorded.event),
+            Some(std::path::Path::new("t/p/x"))
+        );
+
+        shutdown.begin();
+        assert!(follower.next().await.is_none());
+    }
+
+    #[test]
+    fn test_resume_after() {
+        let query = ActivityQuery {
+            last_event_id: Some(4),
+        };
+        let mut headers = HeaderMap::new();
+        assert_eq!(resume_after(&headers, &query).unwrap(), Some(4));
+        headers.insert(LAST_EVENT_ID, "12".parse().unwrap());
+        assert_eq!(resume_after(&headers, &query).unwrap(), Some(12));
+        headers.insert(LAST_EVENT_ID, "x".parse().unwrap());
+        assert!(resume_after(&headers, &query).is_err());
+    }
+}
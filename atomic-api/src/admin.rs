@@ -0,0 +1,358 @@
+//! Administrative operations for repositories served by Atomic API
+//!
+//! These operations back the `atomic-api` subcommands (`verify`,
+//! `backfill-attribution`, `gc` and `create-repo`), so that operators can
+//! manage repositories on the host without going through HTTP. They reuse
+//! the same library code paths as the server and the CLI.
+
+use crate::{ApiError, ApiResult};
+use atomic_repository::lock::LockOptions;
+use atomic_repository::Repository;
+use libatomic::attribution::{ApplyAttributionContext, ApplyIntegrationConfig};
+use libatomic::changestore::ChangeStore;
+use libatomic::pristine::{Base32, Hash, Merkle};
+use libatomic::{MutTxnT, TxnT, TxnTExt};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+/// Result of verifying every change referenced by the channels of a repository
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    /// Number of distinct changes checked
+    pub checked: usize,
+    /// Changes referenced by a channel without a change file
+    pub missing: Vec<String>,
+    /// Changes whose file failed hash or contents verification
+    pub corrupted: Vec<String>,
+}
+
+impl VerifyReport {
+    /// Whether every change was found and verified
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.corrupted.is_empty()
+    }
+}
+
+/// Result of backfilling attribution records
+#[derive(Debug, Default, Serialize)]
+pub struct BackfillReport {
+    /// Number of distinct changes examined
+    pub examined: usize,
+    /// Number of attribution records written
+    pub backfilled: usize,
+    /// Number of changes that already had an attribution record
+    pub already_present: usize,
+}
+
+/// Result of collecting unreferenced change files
+#[derive(Debug, Default, Serialize)]
+pub struct GcReport {
+    /// Change files not referenced by any channel
+    pub unreferenced: Vec<String>,
+    /// Total size of these files, in bytes
+    pub bytes: u64,
+    /// Whether the files were actually deleted
+    pub deleted: bool,
+    /// Unreferenced change files kept because they are newer than
+    /// [`GC_GRACE_PERIOD`]
+    pub recent: usize,
+}
+
+/// Age under which unreferenced change files are kept: applies write the
+/// change file before validating and applying it, so a recent file may
+/// belong to an apply in progress.
+pub const GC_GRACE_PERIOD: std::time::Duration = std::time::Duration::from_secs(3600);
+
+fn open_repository(repo_path: &Path) -> ApiResult<Repository> {
+    if !repo_path.join(libatomic::DOT_DIR).exists() {
+        return Err(ApiError::repository_not_found(repo_path.to_string_lossy()));
+    }
+    Repository::find_root(Some(repo_path.to_path_buf()))
+        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))
+}
+
+/// Collect the changes of all channels, along with the position and state
+/// at which each was first found.
+pub(crate) fn channel_changes(repository: &Repository) -> ApiResult<Vec<(Hash, u64, Merkle)>> {
+    let txn = repository
+        .pristine
+        .txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+    let channels = txn
+        .channels("")
+        .map_err(|e| ApiError::internal(format!("Failed to list channels: {}", e)))?;
+
+    let mut seen = HashSet::new();
+    let mut changes = Vec::new();
+    for channel in channels {
+        let channel = channel.read();
+        let log = txn
+            .log(&*channel, 0)
+            .map_err(|e| ApiError::internal(format!("Failed to read log: {}", e)))?;
+        for entry in log {
+            let (n, (h, m)) =
+                entry.map_err(|e| ApiError::internal(format!("Failed to read log: {}", e)))?;
+            let hash: Hash = h.into();
+            if seen.insert(hash) {
+                changes.push((hash, n, m.into()));
+            }
+        }
+    }
+    Ok(changes)
+}
+
+/// Check that every change referenced by a channel has a change file, and
+/// that its hash and contents hash match.
+pub fn verify_repository(repo_path: &Path) -> ApiResult<VerifyReport> {
+    let repository = open_repository(repo_path)?;
+    let mut report = VerifyReport::default();
+    for (hash, _, _) in channel_changes(&repository)? {
+        report.checked += 1;
+        let path = repository.changes.filename(&hash);
+        let buf = match std::fs::read(&path) {
+            Ok(buf) => buf,
+            Err(e) => {
+                warn!("Missing change file {}: {}", path.display(), e);
+                report.missing.push(hash.to_base32());
+                continue;
+            }
+        };
+        if let Err(e) = libatomic::change::Change::check_from_buffer(&buf, &hash) {
+            warn!("Change {} failed verification: {}", hash.to_base32(), e);
+            report.corrupted.push(hash.to_base32());
+        }
+    }
+    info!(
+        "Verified {} changes in {}: {} missing, {} corrupted",
+        report.checked,
+        repo_path.display(),
+        report.missing.len(),
+        report.corrupted.len()
+    );
+    Ok(report)
+}
+
+/// Write attribution records for changes applied before attribution
+/// tracking was enabled, using the same hooks as `atomic apply`.
+pub fn backfill_attribution(repo_path: &Path) -> ApiResult<BackfillReport> {
+    let repository = open_repository(repo_path)?;
+    let changes = channel_changes(&repository)?;
+    let mut context = ApplyAttributionContext::with_database(
+        ApplyIntegrationConfig::default(),
+        repository.pristine.clone(),
+    )
+    .map_err(|e| ApiError::internal(format!("Failed to open attribution store: {}", e)))?;
+
+    let mut report = BackfillReport::default();
+    for (hash, n, merkle) in changes {
+        report.examined += 1;
+        let change = repository.changes.get_change(&hash).map_err(|e| {
+            ApiError::internal(format!("Failed to read change {}: {}", hash.to_base32(), e))
+        })?;
+        let patch = match context
+            .pre_apply_hook(&change, &hash)
+            .map_err(|e| ApiError::internal(e.to_string()))?
+        {
+            Some(patch) => patch,
+            None => continue,
+        };
+        if context
+            .get_attribution_from_database(&patch.patch_id)
+            .map_err(|e| ApiError::internal(e.to_string()))?
+            .is_some()
+        {
+            debug!("Attribution already present for {}", hash.to_base32());
+            report.already_present += 1;
+            continue;
+        }
+        context
+            .post_apply_hook(&patch.patch_id, &(n, merkle))
+            .map_err(|e| ApiError::internal(e.to_string()))?;
+        report.backfilled += 1;
+    }
+    info!(
+        "Backfilled attribution for {} of {} changes in {}",
+        report.backfilled,
+        report.examined,
+        repo_path.display()
+    );
+    Ok(report)
+}
+
+/// Find change files that no channel references, and delete them if
+/// `delete` is set. Tag change files, and files newer than
+/// [`GC_GRACE_PERIOD`], are always kept. The repository is locked with
+/// `options` for the whole collection, so that no change is applied
+/// between the scan of the channels and the deletions.
+pub fn gc(repo_path: &Path, delete: bool, options: &LockOptions) -> ApiResult<GcReport> {
+    let repository = open_repository(repo_path)?;
+    let _lock = crate::server::lock_repository(&repository, "api gc", options)?;
+    let referenced: HashSet<Hash> = channel_changes(&repository)?
+        .into_iter()
+        .map(|(h, _, _)| h)
+        .collect();
+
+    let mut report = GcReport {
+        deleted: delete,
+        ..GcReport::default()
+    };
+    let now = std::time::SystemTime::now();
+    for prefix in std::fs::read_dir(&repository.changes_dir)? {
+        let prefix = prefix?;
+        if !prefix.file_type()?.is_dir() {
+            continue;
+        }
+        let prefix_name = prefix.file_name().to_string_lossy().to_string();
+        for file in std::fs::read_dir(prefix.path())? {
+            let file = file?;
+            let path = file.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("change") {
+                continue;
+            }
+            let stem = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(stem) => stem,
+                None => continue,
+            };
+            let hash = match Hash::from_base32(format!("{}{}", prefix_name, stem).as_bytes()) {
+                Some(hash) => hash,
+                None => continue,
+            };
+            if referenced.contains(&hash) {
+                continue;
+            }
+            match repository.changes.get_change(&hash) {
+                Ok(change) if change.hashed.tag.is_some() => continue,
+                _ => {}
+            }
+            let metadata = file.metadata()?;
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|m| now.duration_since(m).ok())
+                .unwrap_or_default();
+            if age < GC_GRACE_PERIOD {
+                debug!("Keeping recent change file {}", path.display());
+                report.recent += 1;
+                continue;
+            }
+            report.bytes += metadata.len();
+            report.unreferenced.push(hash.to_base32());
+            if delete {
+                debug!("Deleting unreferenced change file {}", path.display());
+                std::fs::remove_file(&path)?;
+            }
+        }
+    }
+    info!(
+        "{} {} unreferenced change files ({} bytes, {} recent kept) in {}",
+        if delete { "Deleted" } else { "Found" },
+        report.unreferenced.len(),
+        report.bytes,
+        report.recent,
+        repo_path.display()
+    );
+    Ok(report)
+}
+
+/// Create an empty repository for a tenant/portfolio/project under the base
+/// mount path, with the same layout the server expects.
+pub fn create_repository(
+    base_mount_path: &Path,
+    tenant_id: &str,
+    portfolio_id: &str,
+    project_id: &str,
+) -> ApiResult<PathBuf> {
+    crate::server::validate_id(tenant_id, "tenant_id")?;
+    crate::server::validate_id(portfolio_id, "portfolio_id")?;
+    crate::server::validate_id(project_id, "project_id")?;
+
+    let repo_path =
+        crate::jail::Jail::new(base_mount_path)?.repository(tenant_id, portfolio_id, project_id)?;
+    std::fs::create_dir_all(&repo_path)?;
+    let repository = Repository::init(Some(repo_path.clone()), None, None)
+        .map_err(|e| ApiError::internal(format!("Failed to create repository: {}", e)))?;
+
+    // Same as `atomic init`: create and select the default channel.
+    let mut txn = repository
+        .pristine
+        .mut_txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+    txn.open_or_create_channel(libatomic::DEFAULT_CHANNEL)
+        .map_err(|e| ApiError::internal(format!("Failed to create channel: {}", e)))?;
+    txn.set_current_channel(libatomic::DEFAULT_CHANNEL)
+        .map_err(|e| ApiError::internal(format!("Failed to set channel: {}", e)))?;
+    txn.commit()
+        .map_err(|e| ApiError::internal(format!("Failed to commit: {}", e)))?;
+    info!("Created repository at {}", repo_path.display());
+    Ok(repo_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_create_verify_and_gc_empty_repository() {
+        let base = tempfile::tempdir().unwrap();
+        let repo = create_repository(base.path(), "tenant", "portfolio", "project").unwrap();
+        assert!(repo.join(libatomic::DOT_DIR).exists());
+
+        let report = verify_repository(&repo).unwrap();
+        assert_eq!(report.checked, 0);
+        assert!(report.is_ok());
+
+        let report = gc(&repo, false, &LockOptions::no_wait()).unwrap();
+        assert!(report.unreferenced.is_empty());
+        assert!(!report.deleted);
+    }
+
+    #[test]
+    fn test_gc_keeps_recent_files() {
+        let base = tempfile::tempdir().unwrap();
+        let repo = create_repository(base.path(), "tenant", "portfolio", "project").unwrap();
+        let mut hasher = libatomic::pristine::Hasher::default();
+        hasher.update(b"unreferenced");
+        let hash = hasher.finish();
+        let mut path = repo.join(libatomic::DOT_DIR).join("changes");
+        libatomic::changestore::filesystem::push_filename(&mut path, &hash);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, b"unreferenced").unwrap();
+
+        // A file being applied is kept.
+        let report = gc(&repo, true, &LockOptions::no_wait()).unwrap();
+        assert!(report.unreferenced.is_empty());
+        assert_eq!(report.recent, 1);
+        assert!(path.exists());
+
+        let old = std::time::SystemTime::now() - 2 * GC_GRACE_PERIOD;
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        let report = gc(&repo, false, &LockOptions::no_wait()).unwrap();
+        assert_eq!(report.unreferenced, vec![hash.to_base32()]);
+        assert!(path.exists());
+        let report = gc(&repo, true, &LockOptions::no_wait()).unwrap();
+        assert!(report.deleted);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_create_repository_rejects_invalid_ids() {
+        let base = tempfile::tempdir().unwrap();
+        assert!(create_repository(base.path(), "../tenant", "p", "q").is_err());
+    }
+
+    #[test]
+    fn test_verify_missing_repository() {
+        let base = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            verify_repository(&base.path().join("nope")),
+            Err(ApiError::Repository(_))
+        ));
+    }
+}
@@ -0,0 +1,536 @@
+//! Apply workers
+//!
+//! Applying a change takes the pristine's write lock and can take a while on
+//! large repositories, so running applies inside the request handlers left
+//! concurrent pushes to the same repository waiting on each other until
+//! their HTTP requests timed out. Applies are instead queued per
+//! repository, and a fixed pool of workers runs them:
+//!
+//! - applies to one repository run one at a time, in the order they were
+//!   submitted;
+//! - workers take repositories in turn, one apply each, so a repository
+//!   with a long queue doesn't starve the others;
+//! - each repository's queue is bounded, and submitting to a full queue
+//!   fails with [`ApiError::Busy`] (`503`) instead of piling up work.
+//!
+//! Clients either wait for their apply to complete, or get a `202` with an
+//! operation ID and poll `GET /operations/<id>`. Queue depths and counters
+//! are served by `GET /metrics/applies`.
+//!
+//! On shutdown, the queue is closed to new applies and drained, see
+//! [`crate::shutdown`].
+
+use crate::{ApiError, ApiResult};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, Notify};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// Default number of applies that can wait for one repository
+const DEFAULT_QUEUE_CAPACITY: usize = 64;
+/// Default number of workers
+const DEFAULT_WORKERS: usize = 4;
+/// Number of finished operations whose status is kept for polling
+const FINISHED_OPERATIONS: usize = 1024;
+
+/// Configuration of the apply workers
+#[derive(Debug, Clone)]
+pub struct ApplyQueueConfig {
+    /// Maximum number of applies waiting for one repository
+    pub capacity: usize,
+    /// Number of applies running concurrently, on different repositories
+    pub workers: usize,
+}
+
+impl Default for ApplyQueueConfig {
+    fn default() -> Self {
+        ApplyQueueConfig {
+            capacity: DEFAULT_QUEUE_CAPACITY,
+            workers: DEFAULT_WORKERS,
+        }
+    }
+}
+
+impl ApplyQueueConfig {
+    /// Read the configuration from `ATOMIC_API_APPLY_QUEUE` and
+    /// `ATOMIC_API_APPLY_WORKERS`, with defaults for unset variables.
+    pub fn from_env() -> Self {
+        let var = |var: &str| {
+            std::env::var(var)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .filter(|&n: &usize| n > 0)
+        };
+        ApplyQueueConfig {
+            capacity: var("ATOMIC_API_APPLY_QUEUE").unwrap_or(DEFAULT_QUEUE_CAPACITY),
+            workers: var("ATOMIC_API_APPLY_WORKERS").unwrap_or(DEFAULT_WORKERS),
+        }
+    }
+}
+
+/// Status of a submitted apply
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum OperationStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed { message: String },
+}
+
+/// Response to an apply submitted with `async=true`
+#[derive(Debug, Serialize)]
+pub struct OperationAccepted {
+    pub operation_id: String,
+    pub status_url: String,
+}
+
+/// Queue depths and counters, served by `GET /metrics/applies`
+#[derive(Debug, Default, Serialize)]
+pub struct ApplyQueueMetrics {
+    /// Applies waiting, for all repositories
+    pub queued: usize,
+    /// Applies currently running
+    pub running: usize,
+    /// Applies that completed, successfully or not
+    pub completed: u64,
+    /// Applies that failed
+    pub failed: u64,
+    /// Submissions rejected because a queue was full
+    pub rejected: u64,
+    /// Applies waiting, per repository with a non-empty queue
+    pub depths: BTreeMap<String, usize>,
+}
+
+type Work = Box<dyn FnOnce() -> ApiResult<()> + Send>;
+
+struct Job {
+    id: Uuid,
+    work: Work,
+    done: oneshot::Sender<ApiResult<()>>,
+}
+
+#[derive(Default)]
+struct Queues {
+    /// Waiting jobs, per repository
+    pending: HashMap<PathBuf, VecDeque<Job>>,
+    /// Repositories with waiting jobs and no running job, in the order
+    /// workers take them
+    ready: VecDeque<PathBuf>,
+    /// Repositories with a running job
+    running: HashSet<PathBuf>,
+}
+
+#[derive(Default)]
+struct Operations {
+    status: HashMap<Uuid, OperationStatus>,
+    finished: VecDeque<Uuid>,
+}
+
+impl Operations {
+    fn finish(&mut self, id: Uuid, status: OperationStatus) {
+        self.status.insert(id, status);
+        self.finished.push_back(id);
+        while self.finished.len() > FINISHED_OPERATIONS {
+            if let Some(old) = self.finished.pop_front() {
+                self.status.remove(&old);
+            }
+        }
+    }
+}
+
+struct Inner {
+    config: ApplyQueueConfig,
+    queues: Mutex<Queues>,
+    operations: Mutex<Operations>,
+    wakeup: Notify,
+    /// Notified whenever a job finishes or is cancelled
+    finished: Notify,
+    /// Whether new jobs are refused
+    closed: AtomicBool,
+    completed: AtomicU64,
+    failed: AtomicU64,
+    rejected: AtomicU64,
+}
+
+/// The per-repository apply queues and their workers
+#[derive(Clone)]
+pub struct ApplyQueue {
+    inner: Arc<Inner>,
+}
+
+/// A submitted apply
+pub struct Operation {
+    id: Uuid,
+    done: oneshot::Receiver<ApiResult<()>>,
+}
+
+impl Operation {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Wait for the apply to complete, and return its result
+    pub async fn wait(self) -> ApiResult<()> {
+        self.done
+            .await
+            .unwrap_or_else(|_| Err(ApiError::internal("Apply worker stopped")))
+    }
+}
+
+impl ApplyQueue {
+    /// Create the queues. Nothing runs until [`ApplyQueue::start`] is
+    /// called.
+    pub fn new(config: ApplyQueueConfig) -> Self {
+        ApplyQueue {
+            inner: Arc::new(Inner {
+                config,
+                queues: Mutex::new(Queues::default()),
+                operations: Mutex::new(Operations::default()),
+                wakeup: Notify::new(),
+                finished: Notify::new(),
+                closed: AtomicBool::new(false),
+                completed: AtomicU64::new(0),
+                failed: AtomicU64::new(0),
+                rejected: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Start the workers. Must be called once, from within a Tokio runtime.
+    pub fn start(&self) {
+        for _ in 0..self.inner.config.workers.max(1) {
+            tokio::spawn(self.clone().worker());
+        }
+    }
+
+    pub fn config(&self) -> &ApplyQueueConfig {
+        &self.inner.config
+    }
+
+    /// Queue `work` behind the other applies to `repository`
+    pub fn submit<F>(&self, repository: PathBuf, work: F) -> ApiResult<Operation>
+    where
+        F: FnOnce() -> ApiResult<()> + Send + 'static,
+    {
+        if self.inner.closed.load(Ordering::Relaxed) {
+            return Err(ApiError::shutting_down(
+                "Server shutting down, applies are not accepted",
+            ));
+        }
+        let id = Uuid::new_v4();
+        let (done, receiver) = oneshot::channel();
+        {
+            let mut queues = self.inner.queues.lock().unwrap();
+            let queues = &mut *queues;
+            let pending = queues.pending.entry(repository.clone()).or_default();
+            if pending.len() >= self.inner.config.capacity {
+                self.inner.rejected.fetch_add(1, Ordering::Relaxed);
+                warn!("Apply queue full for {}", repository.display());
+                return Err(ApiError::busy(format!(
+                    "Too many applies waiting for this repository ({})",
+                    self.inner.config.capacity
+                )));
+            }
+            // Recorded before a worker can see the job, so that it can't
+            // overwrite the worker's status.
+            self.inner
+                .operations
+                .lock()
+                .unwrap()
+                .status
+                .insert(id, OperationStatus::Queued);
+            let was_idle = pending.is_empty();
+            pending.push_back(Job {
+                id,
+                work: Box::new(work),
+                done,
+            });
+            debug!(
+                "Queued apply {} for {} ({} waiting)",
+                id,
+                repository.display(),
+                pending.len()
+            );
+            if was_idle && !queues.running.contains(&repository) {
+                queues.ready.push_back(repository);
+            }
+        }
+        self.inner.wakeup.notify_one();
+        Ok(Operation { id, done: receiver })
+    }
+
+    /// Status of the operation `id`, if it is known
+    pub fn status(&self, id: &Uuid) -> Option<OperationStatus> {
+        self.inner
+            .operations
+            .lock()
+            .unwrap()
+            .status
+            .get(id)
+            .cloned()
+    }
+
+    pub fn metrics(&self) -> ApplyQueueMetrics {
+        let queues = self.inner.queues.lock().unwrap();
+        let depths: BTreeMap<String, usize> = queues
+            .pending
+            .iter()
+            .filter(|(_, jobs)| !jobs.is_empty())
+            .map(|(repo, jobs)| (repo.display().to_string(), jobs.len()))
+            .collect();
+        ApplyQueueMetrics {
+            queued: depths.values().sum(),
+            running: queues.running.len(),
+            completed: self.inner.completed.load(Ordering::Relaxed),
+            failed: self.inner.failed.load(Ordering::Relaxed),
+            rejected: self.inner.rejected.load(Ordering::Relaxed),
+            depths,
+        }
+    }
+
+    /// Refuse new jobs, e.g. on shutdown. Queued and running jobs go on.
+    pub fn close(&self) {
+        self.inner.closed.store(true, Ordering::Relaxed);
+    }
+
+    fn is_idle(&self) -> bool {
+        let queues = self.inner.queues.lock().unwrap();
+        queues.running.is_empty() && queues.pending.values().all(|jobs| jobs.is_empty())
+    }
+
+    /// Wait until no job is queued or running
+    pub async fn idle(&self) {
+        loop {
+            let finished = self.inner.finished.notified();
+            tokio::pin!(finished);
+            // Registered before checking, so that a job finishing in
+            // between isn't missed.
+            finished.as_mut().enable();
+            if self.is_idle() {
+                return;
+            }
+            finished.await;
+        }
+    }
+
+    /// Fail the jobs that haven't started with `error()`, and return how
+    /// many there were
+    pub fn cancel_pending(&self, error: impl Fn() -> ApiError) -> usize {
+        let jobs: Vec<Job> = {
+            let mut queues = self.inner.queues.lock().unwrap();
+            queues.ready.clear();
+            queues
+                .pending
+                .values_mut()
+                .flat_map(|jobs| jobs.drain(..))
+                .collect()
+        };
+        let cancelled = jobs.len();
+        for job in jobs {
+            debug!("Cancelling apply {}", job.id);
+            let e = error();
+            self.inner.completed.fetch_add(1, Ordering::Relaxed);
+            self.inner.failed.fetch_add(1, Ordering::Relaxed);
+            self.inner.operations.lock().unwrap().finish(
+                job.id,
+                OperationStatus::Failed {
+                    message: e.to_string(),
+                },
+            );
+            let _ = job.done.send(Err(e));
+        }
+        self.inner.finished.notify_waiters();
+        cancelled
+    }
+
+    /// Take the first job of the next ready repository
+    fn next(&self) -> Option<(PathBuf, Job)> {
+        let mut queues = self.inner.queues.lock().unwrap();
+        while let Some(repository) = queues.ready.pop_front() {
+            let job = queues
+                .pending
+                .get_mut(&repository)
+                .and_then(|jobs| jobs.pop_front());
+            if let Some(job) = job {
+                queues.running.insert(repository.clone());
+                return Some((repository, job));
+            }
+        }
+        None
+    }
+
+    /// Mark `repository` as idle, and put it back in line if it has more
+    /// jobs.
+    fn release(&self, repository: PathBuf) {
+        let mut queues = self.inner.queues.lock().unwrap();
+        queues.running.remove(&repository);
+        let has_more = match queues.pending.get(&repository) {
+            Some(jobs) if !jobs.is_empty() => true,
+            _ => {
+                queues.pending.remove(&repository);
+                false
+            }
+        };
+        if has_more {
+            queues.ready.push_back(repository);
+            drop(queues);
+            self.inner.wakeup.notify_one();
+        }
+    }
+
+    async fn worker(self) {
+        loop {
+            let Some((repository, job)) = self.next() else {
+                self.inner.wakeup.notified().await;
+                continue;
+            };
+            self.set_status(job.id, OperationStatus::Running);
+            let work = job.work;
+            let result = match tokio::task::spawn_blocking(work).await {
+                Ok(result) => result,
+                Err(e) => Err(ApiError::internal(format!("Apply task failed: {}", e))),
+            };
+            self.inner.completed.fetch_add(1, Ordering::Relaxed);
+            let status = match result {
+                Ok(()) => OperationStatus::Succeeded,
+                Err(ref e) => {
+                    self.inner.failed.fetch_add(1, Ordering::Relaxed);
+                    OperationStatus::Failed {
+                        message: e.to_string(),
+                    }
+                }
+            };
+            self.inner.operations.lock().unwrap().finish(job.id, status);
+            // The submitter may have stopped waiting, e.g. for async applies.
+            let _ = job.done.send(result);
+            self.release(repository);
+            self.inner.finished.notify_waiters();
+        }
+    }
+
+    fn set_status(&self, id: Uuid, status: OperationStatus) {
+        self.inner
+            .operations
+            .lock()
+            .unwrap()
+            .status
+            .insert(id, status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn config(capacity: usize, workers: usize) -> ApplyQueueConfig {
+        ApplyQueueConfig { capacity, workers }
+    }
+
+    #[tokio::test]
+    async fn test_applies_to_one_repository_are_serialized() {
+        let queue = ApplyQueue::new(config(16, 4));
+        queue.start();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let ops: Vec<_> = (0..8)
+            .map(|i| {
+                let log = log.clone();
+                queue
+                    .submit(PathBuf::from("/repo"), move || {
+                        log.lock().unwrap().push(("start", i));
+                        std::thread::sleep(Duration::from_millis(5));
+                        log.lock().unwrap().push(("end", i));
+                        Ok(())
+                    })
+                    .unwrap()
+            })
+            .collect();
+        for op in ops {
+            op.wait().await.unwrap();
+        }
+        let log = log.lock().unwrap();
+        let expected: Vec<_> = (0..8).flat_map(|i| [("start", i), ("end", i)]).collect();
+        assert_eq!(*log, expected);
+    }
+
+    #[tokio::test]
+    async fn test_repositories_take_turns() {
+        // A single worker, blocked until both repositories have queued work.
+        let queue = ApplyQueue::new(config(16, 1));
+        queue.start();
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let gate = queue
+            .submit(PathBuf::from("/gate"), move || {
+                rx.recv().unwrap();
+                Ok(())
+            })
+            .unwrap();
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let mut ops = Vec::new();
+        for repo in ["/a", "/a", "/a", "/b"] {
+            let order = order.clone();
+            ops.push(
+                queue
+                    .submit(PathBuf::from(repo), move || {
+                        order.lock().unwrap().push(repo);
+                        Ok(())
+                    })
+                    .unwrap(),
+            );
+        }
+        tx.send(()).unwrap();
+        gate.wait().await.unwrap();
+        for op in ops {
+            op.wait().await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), vec!["/a", "/b", "/a", "/a"]);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_is_rejected() {
+        let queue = ApplyQueue::new(config(1, 1));
+        queue.start();
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let running = queue
+            .submit(PathBuf::from("/repo"), move || {
+                rx.recv().unwrap();
+                Ok(())
+            })
+            .unwrap();
+        // Wait for the first apply to leave the queue.
+        while queue.status(&running.id()) != Some(OperationStatus::Running) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let queued = queue
+            .submit(PathBuf::from("/repo"), || Err(ApiError::internal("boom")))
+            .unwrap();
+        assert!(matches!(
+            queue.submit(PathBuf::from("/repo"), || Ok(())),
+            Err(ApiError::Busy { .. })
+        ));
+        let metrics = queue.metrics();
+        assert_eq!(metrics.queued, 1);
+        assert_eq!(metrics.running, 1);
+        assert_eq!(metrics.rejected, 1);
+        assert_eq!(metrics.depths.get("/repo"), Some(&1));
+
+        let queued_id = queued.id();
+        tx.send(()).unwrap();
+        running.wait().await.unwrap();
+        assert!(queued.wait().await.is_err());
+        assert_eq!(
+            queue.status(&queued_id),
+            Some(OperationStatus::Failed {
+                message: "Internal server error: boom".to_string()
+            })
+        );
+        let metrics = queue.metrics();
+        assert_eq!((metrics.completed, metrics.failed), (2, 1));
+        assert!(metrics.depths.is_empty());
+    }
+}
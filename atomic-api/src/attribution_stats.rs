@@ -0,0 +1,310 @@
+//! Aggregated AI attribution statistics
+//!
+//! `GET .../code/attribution/stats` aggregates the attribution of the
+//! changes of a channel into an [`AttributionStats`]: the AI-assisted and
+//! human changes, the lines they insert, their breakdown by suggestion
+//! type and provider, and the average confidence of the AI-assisted
+//! changes, overall and by provider. The attribution of each change is
+//! read like the exports read it (see
+//! [`atomic_repository::attribution_export`]): from the metadata recorded
+//! with the change, or detected from its message if it was recorded
+//! without metadata.
+//!
+//! `since` and `until` (RFC 3339 times, or seconds since the epoch)
+//! restrict the statistics to the changes whose timestamp is in that
+//! window, and `author` to the changes of an author (by name, or public
+//! key for authors without a name), so that dashboards don't have to
+//! crawl every change.
+
+use crate::{ApiError, ApiResult};
+use atomic_repository::attribution_export::AttributionRecord;
+use chrono::{DateTime, Utc};
+use libatomic::attribution::{AttributionStats, SuggestionType};
+use libatomic::changestore::filesystem::FileSystem;
+use libatomic::changestore::ChangeStore;
+use libatomic::pristine::sanakirja::Txn;
+use libatomic::pristine::{ChannelRef, Hash};
+use libatomic::{ChannelTxnT, TxnTExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Query parameters of `GET .../code/attribution/stats`
+#[derive(Debug, Deserialize)]
+pub struct AttributionStatsQuery {
+    /// Channel to aggregate, the current channel by default
+    pub channel: Option<String>,
+    /// Only count the changes made at or after this time
+    pub since: Option<String>,
+    /// Only count the changes made before this time
+    pub until: Option<String>,
+    /// Only count the changes of this author
+    pub author: Option<String>,
+}
+
+/// Changes counted in the statistics
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StatsFilter {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub author: Option<String>,
+}
+
+impl StatsFilter {
+    pub fn parse(query: &AttributionStatsQuery) -> ApiResult<Self> {
+        let since = query
+            .since
+            .as_deref()
+            .map(crate::snapshot::parse_at)
+            .transpose()?;
+        let until = query
+            .until
+            .as_deref()
+            .map(crate::snapshot::parse_at)
+            .transpose()?;
+        if let (Some(since), Some(until)) = (since, until) {
+            if since >= until {
+                return Err(ApiError::invalid_query(format!(
+                    "Empty window: since {} is not before until {}",
+                    since, until
+                )));
+            }
+        }
+        Ok(StatsFilter {
+            since,
+            until,
+            author: query.author.clone().filter(|a| !a.is_empty()),
+        })
+    }
+
+    /// Whether `record` is counted
+    pub fn matches(&self, record: &AttributionRecord) -> bool {
+        self.since.map_or(true, |since| record.timestamp >= since)
+            && self.until.map_or(true, |until| record.timestamp < until)
+            && self
+                .author
+                .as_ref()
+                .map_or(true, |author| record.authors.iter().any(|a| a == author))
+    }
+}
+
+/// Statistics of the attribution of a channel, served by
+/// `GET .../code/attribution/stats`
+#[derive(Debug, Clone, Serialize)]
+pub struct AttributionStatsReport {
+    pub channel: String,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub author: Option<String>,
+    pub stats: AttributionStats,
+    /// Changes whose attribution was detected from their message, for
+    /// changes recorded without attribution metadata
+    pub detected: u64,
+    /// Average confidence of the AI-assisted changes of each provider
+    pub provider_confidence: BTreeMap<String, f64>,
+}
+
+/// Sums of the confidences of AI-assisted changes, to average them
+#[derive(Debug, Default)]
+struct Confidence {
+    sum: f64,
+    n: u64,
+}
+
+impl Confidence {
+    fn add(&mut self, confidence: f64) {
+        self.sum += confidence;
+        self.n += 1;
+    }
+
+    fn average(&self) -> f64 {
+        if self.n == 0 {
+            0.
+        } else {
+            self.sum / self.n as f64
+        }
+    }
+}
+
+/// Statistics being aggregated
+#[derive(Debug)]
+pub struct Aggregate {
+    stats: AttributionStats,
+    detected: u64,
+    confidence: Confidence,
+    providers: BTreeMap<String, Confidence>,
+}
+
+impl Default for Aggregate {
+    fn default() -> Self {
+        Aggregate {
+            stats: AttributionStats::new(),
+            detected: 0,
+            confidence: Confidence::default(),
+            providers: BTreeMap::new(),
+        }
+    }
+}
+
+impl Aggregate {
+    /// Count `record`, a change inserting `lines` lines
+    pub fn add(&mut self, record: &AttributionRecord, lines: u64) {
+        let stats = &mut self.stats;
+        stats.total_patches += 1;
+        stats.total_lines += lines;
+        if record.detected {
+            self.detected += 1
+        }
+        if !record.ai_assisted {
+            stats.human_patches += 1;
+            return;
+        }
+        stats.ai_assisted_patches += 1;
+        stats.ai_assisted_lines += lines;
+        if let Some(suggestion_type) = record.suggestion_type.as_deref().and_then(suggestion_type) {
+            *stats.suggestion_types.entry(suggestion_type).or_insert(0) += 1
+        }
+        if let Some(ref provider) = record.ai_provider {
+            *stats
+                .provider_breakdown
+                .entry(provider.clone())
+                .or_insert(0) += 1
+        }
+        if let Some(confidence) = record.confidence {
+            self.confidence.add(confidence);
+            if let Some(ref provider) = record.ai_provider {
+                self.providers
+                    .entry(provider.clone())
+                    .or_default()
+                    .add(confidence)
+            }
+        }
+    }
+
+    pub fn finish(mut self, channel: String, filter: StatsFilter) -> AttributionStatsReport {
+        self.stats.average_ai_confidence = self.confidence.average();
+        AttributionStatsReport {
+            channel,
+            since: filter.since,
+            until: filter.until,
+            author: filter.author,
+            stats: self.stats,
+            detected: self.detected,
+            provider_confidence: self
+                .providers
+                .into_iter()
+                .map(|(provider, c)| (provider, c.average()))
+                .collect(),
+        }
+    }
+}
+
+/// The suggestion type of a record, from its name
+fn suggestion_type(name: &str) -> Option<SuggestionType> {
+    serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+}
+
+/// Statistics of the changes of `channel` matching `filter`
+pub fn stats(
+    txn: &Txn,
+    changes: &FileSystem,
+    channel: &ChannelRef<Txn>,
+    filter: StatsFilter,
+) -> ApiResult<AttributionStatsReport> {
+    let channel = channel.read();
+    let name = txn.name(&*channel).to_string();
+    let mut aggregate = Aggregate::default();
+    for entry in txn
+        .log(&*channel, 0)
+        .map_err(|e| ApiError::internal(format!("Failed to read log: {}", e)))?
+    {
+        let (n, (h, _)) =
+            entry.map_err(|e| ApiError::internal(format!("Failed to read log entry: {}", e)))?;
+        let hash: Hash = h.into();
+        let change = changes
+            .get_change(&hash)
+            .map_err(|e| ApiError::internal(format!("Failed to read change: {}", e)))?;
+        let record = AttributionRecord::new(
+            n,
+            &name,
+            &hash,
+            &change.hashed.header,
+            &change.hashed.metadata,
+        );
+        if !filter.matches(&record) {
+            continue;
+        }
+        // The contents of a change are the lines it inserts.
+        let lines = change.contents.iter().filter(|&&b| b == b'\n').count() as u64;
+        aggregate.add(&record, lines);
+    }
+    Ok(aggregate.finish(name, filter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libatomic::change::{Author, ChangeHeader};
+
+    fn record(author: &str, timestamp: &str, metadata: Option<(&str, f64)>) -> AttributionRecord {
+        let mut a = BTreeMap::new();
+        a.insert("name".to_string(), author.to_string());
+        let header = ChangeHeader {
+            message: "Change".to_string(),
+            authors: vec![Author(a)],
+            timestamp: DateTime::parse_from_rfc3339(timestamp).unwrap().into(),
+            ..ChangeHeader::default()
+        };
+        let mut record = AttributionRecord::new(0, "main", &Hash::zero(), &header, &[]);
+        if let Some((provider, confidence)) = metadata {
+            record.detected = false;
+            record.ai_assisted = true;
+            record.ai_provider = Some(provider.to_string());
+            record.suggestion_type = Some("Partial".to_string());
+            record.confidence = Some(confidence);
+        }
+        record
+    }
+
+    #[test]
+    fn test_aggregate() {
+        let records = [
+            record("alice", "2026-01-01T00:00:00Z", Some(("anthropic", 0.9))),
+            record("alice", "2026-02-01T00:00:00Z", Some(("anthropic", 0.7))),
+            record("bob", "2026-02-01T00:00:00Z", Some(("openai", 0.5))),
+            record("bob", "2026-03-01T00:00:00Z", None),
+        ];
+        let mut aggregate = Aggregate::default();
+        for r in records.iter() {
+            aggregate.add(r, 10)
+        }
+        let report = aggregate.finish("main".to_string(), StatsFilter::default());
+        assert_eq!(report.stats.total_patches, 4);
+        assert_eq!(report.stats.ai_assisted_patches, 3);
+        assert_eq!(report.stats.human_patches, 1);
+        assert_eq!(report.stats.ai_assisted_lines, 30);
+        assert_eq!(report.detected, 1);
+        assert_eq!(report.stats.provider_breakdown["anthropic"], 2);
+        assert_eq!(report.stats.suggestion_types[&SuggestionType::Partial], 3);
+        assert!((report.stats.average_ai_confidence - 0.7).abs() < 1e-9);
+        assert!((report.provider_confidence["anthropic"] - 0.8).abs() < 1e-9);
+
+        let filter = StatsFilter::parse(&AttributionStatsQuery {
+            channel: None,
+            since: Some("2026-02-01T00:00:00Z".to_string()),
+            until: Some("2026-03-01T00:00:00Z".to_string()),
+            author: Some("bob".to_string()),
+        })
+        .unwrap();
+        let matching: Vec<_> = records.iter().filter(|r| filter.matches(r)).collect();
+        assert_eq!(matching.len(), 1);
+        assert_eq!(matching[0].ai_provider.as_deref(), Some("openai"));
+
+        assert!(StatsFilter::parse(&AttributionStatsQuery {
+            channel: None,
+            since: Some("2026-03-01T00:00:00Z".to_string()),
+            until: Some("2026-02-01T00:00:00Z".to_string()),
+            author: None,
+        })
+        .is_err());
+    }
+}
@@ -0,0 +1,395 @@
+//! Authentication and scopes of the requests
+//!
+//! The server used to trust the proxy in front of it entirely. With
+//! `ATOMIC_API_AUTH_FILE`, it authenticates the requests itself: every
+//! route but `/health` needs a bearer token (`Authorization: Bearer
+//! <token>`) listed in the file or, behind a proxy terminating mutual
+//! TLS, the subject of the client certificate the proxy verified, in the
+//! header named by `client_header`. The proxy must then drop that header
+//! from the requests it doesn't authenticate. Tokens are listed by the
+//! SHA-256 of their value, so that the file holds no secrets:
+//!
+//! ```toml
+//! client_header = "x-client-subject"
+//!
+//! [[tokens]]
+//! name = "ci"
+//! sha256 = "5e884898da28047151d0e56f8dc6292773603d0d6aabbdd62a11ef721d1542d8"
+//! scopes = ["read:changes", "write:push"]
+//! tenants = ["tenant-123"]
+//!
+//! [[clients]]
+//! subject = "CN=mirror,O=Example"
+//! scopes = ["read:changes"]
+//! tenants = ["*"]
+//! ```
+//!
+//! Each route needs a [`Scope`], from its [`RouteGroup`] and its method
+//! (see [`Scope::required`]), and the routes under `/tenant/:tenant_id`
+//! credentials granted for that tenant. The other admin routes span all
+//! tenants, and need credentials granted for `*`. Requests without valid
+//! credentials answer `401` (`unauthenticated`), credentials without the
+//! scope of the route `403` (`insufficient_scope`), and credentials of
+//! other tenants `403` (`tenant_denied`).
+
+use crate::exposure::RouteGroup;
+use crate::{ApiError, ApiResult};
+use axum::{
+    body::Body,
+    extract::{RawPathParams, Request, State},
+    http::{header, HeaderMap, Method, Response},
+    middleware::Next,
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// What credentials allow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Scope {
+    /// Reads of the protocol, change and collaboration routes: clones,
+    /// changes, channels, tags, comments, workflow states…
+    #[serde(rename = "read:changes")]
+    ReadChanges,
+    /// Pushes, uploads, applies and the other writes of the protocol and
+    /// change routes, e.g. saved filters
+    #[serde(rename = "write:push")]
+    WritePush,
+    /// Writes of the collaboration routes: notes, links, comments,
+    /// workflow transitions, sandboxes and submissions
+    #[serde(rename = "write:review")]
+    WriteReview,
+    /// Tag uploads
+    #[serde(rename = "admin:tags")]
+    AdminTags,
+    /// The admin routes: metrics, events, maintenance, storage, usage
+    /// and configuration
+    #[serde(rename = "admin")]
+    Admin,
+    /// Every scope
+    #[serde(rename = "*")]
+    All,
+}
+
+impl Scope {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scope::ReadChanges => "read:changes",
+            Scope::WritePush => "write:push",
+            Scope::WriteReview => "write:review",
+            Scope::AdminTags => "admin:tags",
+            Scope::Admin => "admin",
+            Scope::All => "*",
+        }
+    }
+
+    /// The scope needed by a `method` request with query `query` to a
+    /// route of `group`
+    pub fn required(group: RouteGroup, method: &Method, query: Option<&str>) -> Scope {
+        let read = matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS);
+        let tag_upload =
+            query.is_some_and(|q| q.split('&').any(|p| p.split('=').next() == Some("tagup")));
+        match group {
+            RouteGroup::Admin => Scope::Admin,
+            _ if read => Scope::ReadChanges,
+            RouteGroup::Protocol if tag_upload => Scope::AdminTags,
+            RouteGroup::Protocol | RouteGroup::Changes => Scope::WritePush,
+            RouteGroup::Collaboration => Scope::WriteReview,
+        }
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A bearer token, by the SHA-256 of its value in hexadecimal
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenGrant {
+    /// Name of the token, in the logs
+    pub name: String,
+    pub sha256: String,
+    pub scopes: BTreeSet<Scope>,
+    /// Tenants the token is granted for, `*` for all of them
+    pub tenants: BTreeSet<String>,
+}
+
+/// A client certificate verified by the proxy, by its subject
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientGrant {
+    pub subject: String,
+    pub scopes: BTreeSet<Scope>,
+    /// Tenants the client is granted for, `*` for all of them
+    pub tenants: BTreeSet<String>,
+}
+
+/// Credentials accepted by the server
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuthConfig {
+    /// Header in which the proxy passes the subject of the verified
+    /// client certificate, if any
+    #[serde(default)]
+    pub client_header: Option<String>,
+    #[serde(default)]
+    pub tokens: Vec<TokenGrant>,
+    #[serde(default)]
+    pub clients: Vec<ClientGrant>,
+}
+
+impl AuthConfig {
+    /// Read the credentials from the file at `path`
+    pub fn load(path: &Path) -> ApiResult<Self> {
+        let config = std::fs::read_to_string(path)?;
+        toml::from_str(&config)
+            .map_err(|e| ApiError::internal(format!("Invalid auth file {}: {}", path.display(), e)))
+    }
+
+    /// Read the credentials from the file `ATOMIC_API_AUTH_FILE`, if set.
+    /// An invalid file is an error, rather than a server accepting
+    /// everyone.
+    pub fn from_env() -> ApiResult<Option<Self>> {
+        match std::env::var_os("ATOMIC_API_AUTH_FILE") {
+            Some(path) if !path.is_empty() => Self::load(Path::new(&path)).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// The principal authenticated by the credentials of `headers`
+    pub fn authenticate(&self, headers: &HeaderMap) -> ApiResult<Principal> {
+        if let Some(value) = headers.get(header::AUTHORIZATION) {
+            let token = value
+                .to_str()
+                .ok()
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .map(str::trim)
+                .ok_or_else(|| ApiError::unauthenticated("Expected a bearer token"))?;
+            let digest = sha256(token);
+            return self
+                .tokens
+                .iter()
+                .find(|t| t.sha256.eq_ignore_ascii_case(&digest))
+                .map(|t| Principal {
+                    name: t.name.clone(),
+                    scopes: t.scopes.clone(),
+                    tenants: t.tenants.clone(),
+                })
+                .ok_or_else(|| ApiError::unauthenticated("Unknown token"));
+        }
+        let subject = self
+            .client_header
+            .as_deref()
+            .and_then(|h| headers.get(h))
+            .and_then(|v| v.to_str().ok());
+        if let Some(subject) = subject {
+            return self
+                .clients
+                .iter()
+                .find(|c| c.subject == subject)
+                .map(|c| Principal {
+                    name: c.subject.clone(),
+                    scopes: c.scopes.clone(),
+                    tenants: c.tenants.clone(),
+                })
+                .ok_or_else(|| ApiError::unauthenticated("Unknown client certificate"));
+        }
+        Err(ApiError::unauthenticated("Missing credentials"))
+    }
+}
+
+/// The SHA-256 of `token`, in hexadecimal
+pub fn sha256(token: &str) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, token.as_bytes());
+    let mut hex = String::with_capacity(64);
+    for b in digest.as_ref() {
+        hex.push_str(&format!("{:02x}", b))
+    }
+    hex
+}
+
+/// Who sent a request, added to the extensions of authenticated requests
+#[derive(Debug, Clone)]
+pub struct Principal {
+    /// Name of the token, or subject of the client certificate
+    pub name: String,
+    pub scopes: BTreeSet<Scope>,
+    pub tenants: BTreeSet<String>,
+}
+
+/// All tenants, in the `tenants` of grants
+pub const ALL_TENANTS: &str = "*";
+
+impl Principal {
+    /// Check that the principal has `scope`
+    pub fn check(&self, scope: Scope) -> ApiResult<()> {
+        if self.scopes.contains(&scope) || self.scopes.contains(&Scope::All) {
+            Ok(())
+        } else {
+            Err(ApiError::insufficient_scope(
+                format!("{} lacks the {} scope", self.name, scope),
+                scope,
+            ))
+        }
+    }
+
+    /// Check that the principal is granted for `tenant`, or for all
+    /// tenants if the route isn't a tenant's
+    pub fn check_tenant(&self, tenant: Option<&str>) -> ApiResult<()> {
+        if self.tenants.contains(ALL_TENANTS) {
+            return Ok(());
+        }
+        match tenant {
+            Some(tenant) if self.tenants.contains(tenant) => Ok(()),
+            Some(tenant) => Err(ApiError::tenant_denied(format!(
+                "{} isn't granted tenant {}",
+                self.name, tenant
+            ))),
+            None => Err(ApiError::tenant_denied(format!(
+                "{} isn't granted all tenants",
+                self.name
+            ))),
+        }
+    }
+}
+
+/// The tenant of a route, from its `tenant_id` parameter
+fn route_tenant(params: Option<&RawPathParams>) -> Option<&str> {
+    params?
+        .iter()
+        .find(|(name, _)| *name == "tenant_id")
+        .map(|(_, value)| value)
+}
+
+/// Authenticate the requests to the routes of a group, and check that
+/// they have the scope it needs
+pub async fn authorize(
+    State((config, group)): State<(Arc<AuthConfig>, RouteGroup)>,
+    params: Option<RawPathParams>,
+    mut request: Request,
+    next: Next,
+) -> Response<Body> {
+    let scope = Scope::required(group, request.method(), request.uri().query());
+    let tenant = route_tenant(params.as_ref());
+    // The admin routes outside of a tenant span all of them. Operations
+    // are only known by the random id given to whoever started them.
+    let spans_tenants = tenant.is_none() && group == RouteGroup::Admin;
+    let principal = match config.authenticate(request.headers()).and_then(|p| {
+        p.check(scope)?;
+        if tenant.is_some() || spans_tenants {
+            p.check_tenant(tenant)?;
+        }
+        Ok(p)
+    }) {
+        Ok(principal) => principal,
+        Err(e) => {
+            warn!("Rejected {} {}: {}", request.method(), request.uri(), e);
+            return e.into_response();
+        }
+    };
+    debug!(
+        "{} {} by {} ({})",
+        request.method(),
+        request.uri().path(),
+        principal.name,
+        scope
+    );
+    request.extensions_mut().insert(principal);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AuthConfig {
+        toml::from_str(&format!(
+            r#"
+            client_header = "x-client-subject"
+
+            [[tokens]]
+            name = "ci"
+            sha256 = "{}"
+            scopes = ["read:changes", "write:push"]
+            tenants = ["tenant-1"]
+
+            [[clients]]
+            subject = "CN=mirror"
+            scopes = ["*"]
+            tenants = ["*"]
+            "#,
+            sha256("secret")
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_required_scopes() {
+        let push = Some("apply=ABC");
+        assert_eq!(
+            Scope::required(RouteGroup::Protocol, &Method::GET, None),
+            Scope::ReadChanges
+        );
+        assert_eq!(
+            Scope::required(RouteGroup::Protocol, &Method::POST, push),
+            Scope::WritePush
+        );
+        assert_eq!(
+            Scope::required(RouteGroup::Protocol, &Method::POST, Some("tagup=XYZ")),
+            Scope::AdminTags
+        );
+        assert_eq!(
+            Scope::required(RouteGroup::Collaboration, &Method::POST, None),
+            Scope::WriteReview
+        );
+        assert_eq!(
+            Scope::required(RouteGroup::Admin, &Method::GET, None),
+            Scope::Admin
+        );
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let config = config();
+        let mut headers = HeaderMap::new();
+        assert!(matches!(
+            config.authenticate(&headers),
+            Err(ApiError::Unauthenticated { .. })
+        ));
+
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        let ci = config.authenticate(&headers).unwrap();
+        assert_eq!(ci.name, "ci");
+        assert!(ci.check(Scope::WritePush).is_ok());
+        assert!(matches!(
+            ci.check(Scope::AdminTags),
+            Err(ApiError::InsufficientScope {
+                scope: Scope::AdminTags,
+                ..
+            })
+        ));
+        assert!(ci.check_tenant(Some("tenant-1")).is_ok());
+        for tenant in [Some("tenant-2"), None] {
+            assert!(matches!(
+                ci.check_tenant(tenant),
+                Err(ApiError::TenantDenied { .. })
+            ));
+        }
+        headers.insert(header::AUTHORIZATION, "Bearer wrong".parse().unwrap());
+        assert!(config.authenticate(&headers).is_err());
+        headers.insert(header::AUTHORIZATION, "Basic c2VjcmV0".parse().unwrap());
+        assert!(config.authenticate(&headers).is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-client-subject", "CN=mirror".parse().unwrap());
+        let mirror = config.authenticate(&headers).unwrap();
+        assert!(mirror.check(Scope::Admin).is_ok());
+        assert!(mirror.check_tenant(Some("tenant-2")).is_ok());
+        assert!(mirror.check_tenant(None).is_ok());
+        headers.insert("x-client-subject", "CN=other".parse().unwrap());
+        assert!(config.authenticate(&headers).is_err());
+    }
+}
@@ -0,0 +1,301 @@
+//! Backups of repositories
+//!
+//! `GET .../code/backup` returns a tar archive of everything needed to
+//! restore a repository, laid out as its `.atomic` directory so that
+//! extracting it in an empty directory gives a working repository:
+//!
+//! ```text
+//! backup.json              manifest: version, time, channels, counts
+//! .atomic/pristine/db      snapshot of the pristine
+//! .atomic/changes/...      change and tag files referenced by a channel
+//! .atomic/config           configuration of the repository
+//! ```
+//!
+//! Commits rewrite the pristine in place, so a copy made while a commit
+//! runs can mix two states, even under a read transaction. The pristine is
+//! copied under the lock of the repository (see
+//! [`atomic_repository::lock`]), which the writers of the API server and
+//! of the CLI take, and the channels are then read from that copy, so the
+//! archive is a consistent state even while pushes are applied. Writers
+//! wait during the copy, but not while the change files are archived.
+//! The archive is kept in `.atomic/backup.tar`, and served
+//! again as long as the pristine and the configuration are unchanged:
+//! its version (the ETag of the response) is computed from them, so that
+//! an interrupted download can be resumed with a `Range` request.
+
+use crate::{ApiError, ApiResult};
+use atomic_repository::lock::{Lock, LockOptions, Locked};
+use atomic_repository::Repository;
+use chrono::{DateTime, Utc};
+use libatomic::pristine::sanakirja::Txn;
+use libatomic::pristine::{Base32, Hash, Merkle, SerializedTag};
+use libatomic::{ChannelTxnT, TxnT, TxnTExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+/// Archive of the last backup, in the `.atomic` directory
+pub const BACKUP_FILE: &str = "backup.tar";
+/// Manifest of the last backup, in the `.atomic` directory
+const BACKUP_MANIFEST_FILE: &str = "backup.json";
+
+/// A channel in a backup
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupChannel {
+    pub name: String,
+    /// Number of changes and tags in the channel log
+    pub length: u64,
+    /// State of the channel, in base32
+    pub state: String,
+}
+
+/// Manifest of a backup, the first entry of the archive
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    /// Version of the repository the backup was made of, see
+    /// [`version`]
+    pub version: String,
+    pub created: DateTime<Utc>,
+    pub channels: Vec<BackupChannel>,
+    /// Number of change files in the backup
+    pub changes: usize,
+    /// Number of tag files in the backup
+    pub tags: usize,
+    /// Size of the archive, in bytes
+    #[serde(default)]
+    pub size: u64,
+}
+
+/// The archive of a backup, ready to be served
+#[derive(Debug, Clone)]
+pub struct BackupFile {
+    pub path: PathBuf,
+    pub manifest: BackupManifest,
+}
+
+/// Version of the repository at `repo_path`: the SHA-256 of the size and
+/// modification time of the pristine, and of the configuration. It
+/// changes with every commit to the pristine.
+pub fn version(repo_path: &Path) -> ApiResult<String> {
+    let dot_dir = repo_path.join(libatomic::DOT_DIR);
+    let db = std::fs::metadata(dot_dir.join(atomic_repository::PRISTINE_DIR).join("db"))?;
+    let modified = db
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+    ctx.update(&db.len().to_le_bytes());
+    ctx.update(&modified.as_nanos().to_le_bytes());
+    match std::fs::read(dot_dir.join(atomic_repository::CONFIG_FILE)) {
+        Ok(config) => ctx.update(&config),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok(ctx
+        .finish()
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// The backup of the repository at `repo_path`, made now unless the last
+/// one is of the current version
+pub fn prepare(repo_path: &Path) -> ApiResult<BackupFile> {
+    let dot_dir = repo_path.join(libatomic::DOT_DIR);
+    if !dot_dir.is_dir() {
+        return Err(ApiError::repository_not_found(repo_path.to_string_lossy()));
+    }
+    let path = dot_dir.join(BACKUP_FILE);
+    let manifest_path = dot_dir.join(BACKUP_MANIFEST_FILE);
+    let current = version(repo_path)?;
+    if let Some(manifest) = last_manifest(&manifest_path, &path, &current) {
+        debug!("Reusing backup {} of {}", current, repo_path.display());
+        return Ok(BackupFile { path, manifest });
+    }
+
+    let snapshot = tempfile::NamedTempFile::new_in(&dot_dir)?;
+    let version = {
+        let _lock = Lock::new(dot_dir.join(atomic_repository::LOCK_FILE))
+            .acquire("api backup", &LockOptions::from_env())
+            .map_err(|e| match e.downcast_ref::<Locked>() {
+                Some(locked) => ApiError::locked(locked.to_string()),
+                None => ApiError::internal(format!("Failed to lock repository: {}", e)),
+            })?;
+        let db = dot_dir.join(atomic_repository::PRISTINE_DIR).join("db");
+        std::fs::copy(db, snapshot.path())?;
+        // The repository may have changed since the last manifest was
+        // checked.
+        version(repo_path)?
+    };
+    let internal = |e: &dyn std::fmt::Display| ApiError::internal(format!("Backup failed: {}", e));
+    let pristine =
+        libatomic::pristine::sanakirja::Pristine::new(snapshot.path()).map_err(|e| internal(&e))?;
+    let repository =
+        Repository::find_root_with_pristine(Some(repo_path.to_path_buf()), pristine)
+            .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+    let txn = repository
+        .pristine
+        .txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+
+    let tmp = tempfile::NamedTempFile::new_in(&dot_dir)?;
+    let mut manifest = write_archive(&repository, &txn, snapshot.path(), version, tmp.as_file())?;
+    std::mem::drop(txn);
+    std::mem::drop(repository);
+    manifest.size = tmp.as_file().metadata()?.len();
+
+    let manifest_tmp = tempfile::NamedTempFile::new_in(&dot_dir)?;
+    serde_json::to_writer(manifest_tmp.as_file(), &manifest)
+        .map_err(|e| ApiError::internal(format!("Failed to write backup manifest: {}", e)))?;
+    // The archive goes first: a manifest is only trusted with an archive
+    // of its size.
+    tmp.persist(&path).map_err(|e| ApiError::Io(e.error))?;
+    manifest_tmp
+        .persist(&manifest_path)
+        .map_err(|e| ApiError::Io(e.error))?;
+    info!(
+        "Backed up {} ({} changes, {} tags, {} bytes)",
+        repo_path.display(),
+        manifest.changes,
+        manifest.tags,
+        manifest.size
+    );
+    Ok(BackupFile { path, manifest })
+}
+
+/// The manifest of the last backup, if it is of `version` and its archive
+/// is complete
+fn last_manifest(manifest_path: &Path, path: &Path, version: &str) -> Option<BackupManifest> {
+    let manifest: BackupManifest =
+        serde_json::from_slice(&std::fs::read(manifest_path).ok()?).ok()?;
+    let size = std::fs::metadata(path).ok()?.len();
+    (manifest.version == version && manifest.size == size).then_some(manifest)
+}
+
+/// Write the archive of `repository`, as of `txn` on the copy `db` of its
+/// pristine, to `w`
+fn write_archive(
+    repository: &Repository,
+    txn: &Txn,
+    db: &Path,
+    version: String,
+    w: impl Write,
+) -> ApiResult<BackupManifest> {
+    let internal = |e: &dyn std::fmt::Display| ApiError::internal(format!("Backup failed: {}", e));
+    let mut channels = Vec::new();
+    let mut changes = BTreeSet::new();
+    let mut tags = BTreeSet::new();
+    for channel in txn.channels("").map_err(|e| internal(&e))? {
+        let channel = channel.read();
+        let mut length = 0;
+        let mut state = Merkle::zero();
+        for entry in txn.log(&*channel, 0).map_err(|e| internal(&e))? {
+            let (n, (h, m)) = entry.map_err(|e| internal(&e))?;
+            changes.insert(Hash::from(h));
+            length = n + 1;
+            state = m.into();
+        }
+        for entry in txn
+            .iter_tags(txn.tags(&*channel), 0)
+            .map_err(|e| internal(&e))?
+        {
+            let (_, tag_bytes) = entry.map_err(|e| internal(&e))?;
+            if let Ok(tag) = SerializedTag::from_bytes_wrapper(tag_bytes).to_tag() {
+                tags.insert(tag.state);
+                if let Some(h) = tag.change_file_hash {
+                    changes.insert(h);
+                }
+            }
+        }
+        channels.push(BackupChannel {
+            name: txn.name(&*channel).to_string(),
+            length,
+            state: state.to_base32(),
+        });
+    }
+
+    let root = &repository.path;
+    let dot_dir = root.join(libatomic::DOT_DIR);
+    let changes: Vec<_> = changes
+        .iter()
+        .map(|hash| repository.changes.filename(hash))
+        .filter(|path| path.is_file())
+        .collect();
+    let tags: Vec<_> = tags
+        .iter()
+        .map(|state| {
+            let mut path = repository.changes_dir.clone();
+            libatomic::changestore::filesystem::push_tag_filename(&mut path, state);
+            path
+        })
+        .filter(|path| path.is_file())
+        .collect();
+    let manifest = BackupManifest {
+        version,
+        created: Utc::now(),
+        channels,
+        changes: changes.len(),
+        tags: tags.len(),
+        size: 0,
+    };
+
+    let mut archive = tar::Builder::new(w);
+    let json = serde_json::to_vec_pretty(&manifest).map_err(|e| internal(&e))?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(json.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(manifest.created.timestamp().max(0) as u64);
+    header.set_cksum();
+    archive.append_data(&mut header, BACKUP_MANIFEST_FILE, &json[..])?;
+    let db_name = Path::new(libatomic::DOT_DIR)
+        .join(atomic_repository::PRISTINE_DIR)
+        .join("db");
+    archive.append_path_with_name(db, db_name)?;
+    let config = dot_dir.join(atomic_repository::CONFIG_FILE);
+    for path in changes
+        .iter()
+        .chain(tags.iter())
+        .chain(Some(&config).filter(|c| c.is_file()))
+    {
+        let name = path.strip_prefix(root).map_err(|e| internal(&e))?;
+        archive.append_path_with_name(path, name)?;
+    }
+    archive.into_inner()?.flush()?;
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backup_empty_repository() {
+        let base = tempfile::tempdir().unwrap();
+        let repo_path =
+            crate::admin::create_repository(base.path(), "tenant", "portfolio", "project").unwrap();
+        let backup = prepare(&repo_path).unwrap();
+        assert_eq!(backup.manifest.channels.len(), 1);
+        assert_eq!(backup.manifest.channels[0].name, libatomic::DEFAULT_CHANNEL);
+        assert_eq!(backup.manifest.channels[0].length, 0);
+        assert_eq!(
+            backup.manifest.size,
+            std::fs::metadata(&backup.path).unwrap().len()
+        );
+
+        let mut archive = tar::Archive::new(std::fs::File::open(&backup.path).unwrap());
+        let names: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(names[0], BACKUP_MANIFEST_FILE);
+        assert!(names.contains(&".atomic/pristine/db".to_string()));
+
+        // Unchanged repository, same archive.
+        let again = prepare(&repo_path).unwrap();
+        assert_eq!(again.manifest, backup.manifest);
+    }
+}
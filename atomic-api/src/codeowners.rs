@@ -0,0 +1,147 @@
+//! Code owners of the paths of a repository
+//!
+//! A `CODEOWNERS` file, at the root of the repository or in its `.github`
+//! or `docs` directory, lists the owners of paths, one pattern per line:
+//!
+//! ```text
+//! # Comments and empty lines are ignored
+//! *.rs        @rust-team
+//! /docs/      alice bob
+//! ```
+//!
+//! Patterns have the syntax of `[[forbid]]` rules: patterns without a `/`
+//! match names in any directory, other patterns match paths from the
+//! root of the repository, and a pattern matches everything below the
+//! paths it matches. The last line matching a path gives its owners,
+//! so that specific lines go after general ones; a line without owners
+//! leaves its paths unowned. The `@` in front of owners is optional.
+
+use crate::{ApiError, ApiResult};
+use libatomic::changestore::ChangeStore;
+use libatomic::pristine::{ArcTxn, ChannelRef};
+use libatomic::TxnTExt;
+use tracing::debug;
+
+/// Paths of the `CODEOWNERS` file, by order of precedence
+pub const CODEOWNERS_PATHS: &[&str] = &[".github/CODEOWNERS", "CODEOWNERS", "docs/CODEOWNERS"];
+
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: regex::Regex,
+    owners: Vec<String>,
+}
+
+/// The rules of a `CODEOWNERS` file
+#[derive(Debug, Clone, Default)]
+pub struct CodeOwners {
+    rules: Vec<Rule>,
+}
+
+impl CodeOwners {
+    /// Parse the contents of a `CODEOWNERS` file, skipping lines with an
+    /// invalid pattern
+    pub fn parse(contents: &str) -> Self {
+        let mut rules = Vec::new();
+        for line in contents.lines() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut words = line.split_whitespace();
+            let glob = match words.next() {
+                Some(glob) => glob.trim_end_matches('/'),
+                None => continue,
+            };
+            let regex = libatomic::working_copy::normalize::glob_to_regex(glob);
+            match regex::Regex::new(&regex) {
+                Ok(pattern) => rules.push(Rule {
+                    pattern,
+                    owners: words
+                        .map(|w| w.trim_start_matches('@').to_string())
+                        .collect(),
+                }),
+                Err(e) => debug!("Skipping CODEOWNERS pattern {:?}: {}", glob, e),
+            }
+        }
+        CodeOwners { rules }
+    }
+
+    /// The `CODEOWNERS` file of `channel`, or no rules if the channel has
+    /// none
+    pub fn read<T, C>(changes: &C, txn: &ArcTxn<T>, channel: &ChannelRef<T>) -> ApiResult<Self>
+    where
+        T: TxnTExt,
+        C: ChangeStore,
+    {
+        for path in CODEOWNERS_PATHS {
+            let pos = match txn.read().follow_oldest_path(changes, channel, path) {
+                Ok((pos, _)) => pos,
+                Err(_) => continue,
+            };
+            let mut out = libatomic::vertex_buffer::Writer::new(Vec::new());
+            libatomic::output::output_file(changes, txn, channel, pos, &mut out)
+                .map_err(|e| ApiError::internal(format!("Failed to read {}: {}", path, e)))?;
+            return Ok(Self::parse(&String::from_utf8_lossy(&out.into_inner())));
+        }
+        Ok(Self::default())
+    }
+
+    /// Owners of `path`, relative to the root of the repository
+    pub fn owners(&self, path: &str) -> &[String] {
+        let path = path.trim_start_matches('/');
+        // Test the path and all its ancestors.
+        let prefixes: Vec<&str> = path
+            .match_indices('/')
+            .map(|(i, _)| &path[..i])
+            .chain(std::iter::once(path))
+            .filter(|p| !p.is_empty())
+            .collect();
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| prefixes.iter().any(|p| rule.pattern.is_match(p)))
+            .map_or(&[][..], |rule| rule.owners.as_slice())
+    }
+
+    /// Owners of any of `paths`, in the order they are first found
+    pub fn owners_of<'a, I: IntoIterator<Item = &'a str>>(&self, paths: I) -> Vec<String> {
+        let mut owners: Vec<String> = Vec::new();
+        for path in paths {
+            for owner in self.owners(path) {
+                if !owners.contains(owner) {
+                    owners.push(owner.clone())
+                }
+            }
+        }
+        owners
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_owners() {
+        let owners = CodeOwners::parse(
+            "# Owners\n\
+             *           @core\n\
+             *.rs        @rust-team alice\n\
+             /docs/      bob # the docs\n\
+             /docs/generated\n",
+        );
+        assert_eq!(owners.owners("README.md"), &["core".to_string()]);
+        assert_eq!(
+            owners.owners("src/lib.rs"),
+            &["rust-team".to_string(), "alice".to_string()]
+        );
+        assert_eq!(owners.owners("docs/guide/intro.md"), &["bob".to_string()]);
+        assert!(owners.owners("docs/generated/api.md").is_empty());
+        assert_eq!(
+            owners.owners_of(["docs/a.md", "src/main.rs", "docs/b.md"]),
+            vec![
+                "bob".to_string(),
+                "rust-team".to_string(),
+                "alice".to_string()
+            ]
+        );
+        assert!(CodeOwners::default().owners("src/lib.rs").is_empty());
+    }
+}
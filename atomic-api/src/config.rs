@@ -0,0 +1,279 @@
+//! Configuration inheritance from the instance and tenants
+//!
+//! With hundreds of repositories per tenant, repeating the same
+//! `[[forbid]]` rules or hooks in each `.atomic/config` doesn't scale. The
+//! configuration of a repository is resolved from three layers, each
+//! overriding the previous one:
+//!
+//! 1. instance defaults, in `<base>/atomic.toml` (or the file given to
+//!    [`crate::ApiServer::with_instance_config`]),
+//! 2. tenant overrides, in `<base>/<tenant_id>/atomic.toml`,
+//! 3. repository overrides, in the repository's `.atomic/config`.
+//!
+//! All three have the syntax of the repository configuration, and any
+//! of them can be missing. Tables are merged key by key; other values,
+//! arrays included, replace the value of the previous layer, so that a
+//! repository with its own `[[forbid]]` rules replaces the rules of its
+//! tenant instead of adding to them.
+//!
+//! `GET .../code/config` returns the effective configuration, and for
+//! each value the layer it came from and the layers it overrides.
+
+use crate::jail::Jail;
+use crate::{ApiError, ApiResult};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Name of the instance and tenant configuration files
+pub const LAYER_FILE: &str = "atomic.toml";
+
+/// A layer of the configuration, from the least to the most specific
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layer {
+    Instance,
+    Tenant,
+    Repository,
+}
+
+/// An effective value, and where it came from
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResolvedValue {
+    /// Dotted path of the value, e.g. `ai_attribution.enabled`
+    pub key: String,
+    pub value: serde_json::Value,
+    /// Layer the value came from
+    pub source: Layer,
+    /// Less specific layers that set this value too
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub overrides: Vec<Layer>,
+}
+
+/// The configuration of a repository, resolved from its layers
+#[derive(Debug, Serialize)]
+pub struct ResolvedConfig {
+    /// The effective configuration, as merged from the layers
+    pub effective: serde_json::Value,
+    /// The effective values, ordered by key
+    pub values: Vec<ResolvedValue>,
+    #[serde(skip)]
+    pub config: atomic_config::Config,
+}
+
+/// Where a value of the merged table came from
+struct Origin {
+    value: toml::Value,
+    source: Layer,
+    overrides: Vec<Layer>,
+}
+
+/// Resolution of repository configurations
+#[derive(Debug, Clone)]
+pub struct ConfigLayers {
+    instance: PathBuf,
+}
+
+impl ConfigLayers {
+    /// Layers with instance defaults in the file `instance`
+    pub fn new(instance: impl Into<PathBuf>) -> Self {
+        ConfigLayers {
+            instance: instance.into(),
+        }
+    }
+
+    /// Path of the instance defaults
+    pub fn instance(&self) -> &Path {
+        &self.instance
+    }
+
+    /// Resolve the configuration of the repository at `repo_path`, of
+    /// tenant `tenant_id`
+    pub fn resolve(
+        &self,
+        jail: &Jail,
+        tenant_id: &str,
+        repo_path: &Path,
+    ) -> ApiResult<ResolvedConfig> {
+        let layers = [
+            (Layer::Instance, self.instance.clone()),
+            (Layer::Tenant, jail.resolve(tenant_id, LAYER_FILE)?),
+            (
+                Layer::Repository,
+                repo_path
+                    .join(libatomic::DOT_DIR)
+                    .join(atomic_repository::CONFIG_FILE),
+            ),
+        ];
+        let mut merged = toml::Table::new();
+        let mut origins = BTreeMap::new();
+        for (layer, path) in layers.iter() {
+            if let Some(table) = read_layer(path)? {
+                merge(&mut merged, table, *layer, "", &mut origins);
+            }
+        }
+        resolved(merged, origins)
+    }
+}
+
+/// Read the table of the layer in `path`, if it exists
+fn read_layer(path: &Path) -> ApiResult<Option<toml::Table>> {
+    let s = match std::fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    toml::from_str(&s).map(Some).map_err(|e| {
+        ApiError::internal(format!(
+            "Invalid configuration in {}: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+/// Merge `layer` into `merged`, recording the origins of the values
+/// below `prefix`
+fn merge(
+    merged: &mut toml::Table,
+    layer: toml::Table,
+    source: Layer,
+    prefix: &str,
+    origins: &mut BTreeMap<String, Origin>,
+) {
+    for (k, v) in layer {
+        let key = if prefix.is_empty() {
+            k.clone()
+        } else {
+            format!("{}.{}", prefix, k)
+        };
+        match (merged.get_mut(&k), v) {
+            (Some(toml::Value::Table(m)), toml::Value::Table(t)) => {
+                merge(m, t, source, &key, origins)
+            }
+            (_, toml::Value::Table(t)) => {
+                take_overridden(origins, &key);
+                let mut m = toml::Table::new();
+                merge(&mut m, t, source, &key, origins);
+                merged.insert(k, toml::Value::Table(m));
+            }
+            (_, v) => {
+                let overrides = take_overridden(origins, &key);
+                origins.insert(
+                    key,
+                    Origin {
+                        value: v.clone(),
+                        source,
+                        overrides,
+                    },
+                );
+                merged.insert(k, v);
+            }
+        }
+    }
+}
+
+/// Remove the origins of `key` and the values below it, returning the
+/// layers that set them
+fn take_overridden(origins: &mut BTreeMap<String, Origin>, key: &str) -> Vec<Layer> {
+    let below = format!("{}.", key);
+    let keys: Vec<_> = origins
+        .keys()
+        .filter(|k| *k == key || k.starts_with(&below))
+        .cloned()
+        .collect();
+    let mut layers = Vec::new();
+    for k in keys {
+        if let Some(origin) = origins.remove(&k) {
+            layers.extend(origin.overrides);
+            layers.push(origin.source);
+        }
+    }
+    layers.sort();
+    layers.dedup();
+    layers
+}
+
+fn to_json<T: Serialize>(v: &T) -> ApiResult<serde_json::Value> {
+    serde_json::to_value(v)
+        .map_err(|e| ApiError::internal(format!("Failed to serialize configuration: {}", e)))
+}
+
+fn resolved(merged: toml::Table, origins: BTreeMap<String, Origin>) -> ApiResult<ResolvedConfig> {
+    let values = origins
+        .into_iter()
+        .map(|(key, origin)| {
+            Ok(ResolvedValue {
+                key,
+                value: to_json(&origin.value)?,
+                source: origin.source,
+                overrides: origin.overrides,
+            })
+        })
+        .collect::<ApiResult<Vec<_>>>()?;
+    let effective = to_json(&merged)?;
+    let config = toml::Value::Table(merged)
+        .try_into()
+        .map_err(|e| ApiError::internal(format!("Invalid configuration: {}", e)))?;
+    Ok(ResolvedConfig {
+        effective,
+        values,
+        config,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_layers() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        let repo = base.join("t1").join("p1").join("r1");
+        std::fs::create_dir_all(repo.join(libatomic::DOT_DIR)).unwrap();
+        std::fs::write(
+            base.join(LAYER_FILE),
+            "unrecord_changes = 10\n\
+             [ai_attribution]\nenabled = true\nprovider = \"none\"\n\
+             [[forbid]]\npath = \"target\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            base.join("t1").join(LAYER_FILE),
+            "[ai_attribution]\nprovider = \"acme\"\n\
+             [[forbid]]\npath = \"*.o\"\n",
+        )
+        .unwrap();
+        std::fs::write(
+            repo.join(libatomic::DOT_DIR)
+                .join(atomic_repository::CONFIG_FILE),
+            "unrecord_changes = 3\n",
+        )
+        .unwrap();
+
+        let jail = Jail::new(base).unwrap();
+        let layers = ConfigLayers::new(base.join(LAYER_FILE));
+        let resolved = layers.resolve(&jail, "t1", &repo).unwrap();
+        assert_eq!(resolved.config.unrecord_changes, Some(3));
+        assert_eq!(resolved.config.forbid.len(), 1);
+        assert_eq!(resolved.config.forbid[0].path, "*.o");
+
+        let value = |key: &str| resolved.values.iter().find(|v| v.key == key).unwrap();
+        assert_eq!(value("unrecord_changes").source, Layer::Repository);
+        assert_eq!(value("unrecord_changes").overrides, vec![Layer::Instance]);
+        assert_eq!(value("ai_attribution.enabled").source, Layer::Instance);
+        assert!(value("ai_attribution.enabled").overrides.is_empty());
+        assert_eq!(value("ai_attribution.provider").source, Layer::Tenant);
+        assert_eq!(value("ai_attribution.provider").value, "acme");
+        assert_eq!(value("forbid").source, Layer::Tenant);
+        assert_eq!(resolved.effective["ai_attribution"]["enabled"], true);
+
+        // Other tenants only get the instance defaults.
+        let other = base.join("t2").join("p1").join("r1");
+        std::fs::create_dir_all(other.join(libatomic::DOT_DIR)).unwrap();
+        let resolved = layers.resolve(&jail, "t2", &other).unwrap();
+        assert!(resolved.values.iter().all(|v| v.source == Layer::Instance));
+        assert_eq!(resolved.config.forbid[0].path, "target");
+    }
+}
@@ -0,0 +1,260 @@
+//! Conflicts left by applies
+//!
+//! Applies that leave a channel in conflict used to only log it. The
+//! server now records the conflicts of each channel, as of its current
+//! state, in `.atomic/conflicts.json`: the kind of each conflict, its path
+//! and the changes involved. Applies update the record of their channel,
+//! so a change resolving the conflicts clears them.
+//!
+//! `GET .../code/conflicts?channel=<name>&state=<merkle>` serves the
+//! record of a channel. Channels modified without the server, e.g. by the
+//! CLI, are scanned again when their record is older than their state.
+
+use crate::error::RepositoryError;
+use crate::{ApiError, ApiResult};
+use atomic_repository::Repository;
+use chrono::{DateTime, Utc};
+use libatomic::output::{Archive, Conflict};
+use libatomic::{Base32, Merkle, TxnT};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Conflicts of the channels of a repository, in its `.atomic` directory
+pub const CONFLICTS_FILE: &str = "conflicts.json";
+
+/// Kind of a conflict, see [`libatomic::output::Conflict`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictKind {
+    /// Several files with the same name
+    Name,
+    /// A deleted file edited by another change
+    ZombieFile,
+    /// A file with several names
+    MultipleNames,
+    /// Deleted lines edited by another change
+    Zombie,
+    /// Lines whose order is cyclic
+    Cyclic,
+    /// Lines inserted at the same place by different changes
+    Order,
+}
+
+/// A conflict of a channel
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConflictRecord {
+    pub kind: ConflictKind,
+    pub path: String,
+    /// Line of the conflict in the file, for conflicts between lines
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    /// Changes involved in the conflict
+    pub changes: Vec<String>,
+}
+
+impl From<&Conflict> for ConflictRecord {
+    fn from(conflict: &Conflict) -> Self {
+        let (kind, path, line) = match conflict {
+            Conflict::Name { path, .. } => (ConflictKind::Name, path, None),
+            Conflict::ZombieFile { path, .. } => (ConflictKind::ZombieFile, path, None),
+            Conflict::MultipleNames { path, .. } => (ConflictKind::MultipleNames, path, None),
+            Conflict::Zombie { path, line, .. } => (ConflictKind::Zombie, path, Some(*line)),
+            Conflict::Cyclic { path, line, .. } => (ConflictKind::Cyclic, path, Some(*line)),
+            Conflict::Order { path, line, .. } => (ConflictKind::Order, path, Some(*line)),
+        };
+        ConflictRecord {
+            kind,
+            path: path.clone(),
+            line,
+            changes: conflict.changes().iter().map(|h| h.to_base32()).collect(),
+        }
+    }
+}
+
+/// Query parameters of `GET .../code/conflicts`
+#[derive(Debug, Deserialize)]
+pub struct ConflictQuery {
+    /// Channel, the current channel by default
+    pub channel: Option<String>,
+    /// State of the channel, in base32, its current state by default
+    pub state: Option<String>,
+}
+
+/// Conflicts of a channel at a state
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelConflicts {
+    pub channel: String,
+    pub state: String,
+    pub recorded_at: DateTime<Utc>,
+    pub conflicts: Vec<ConflictRecord>,
+}
+
+/// Record `conflicts`, the conflicts of `channel` at `state`, replacing
+/// those recorded for the channel
+pub fn record<'a, I: IntoIterator<Item = &'a Conflict>>(
+    dot_dir: &Path,
+    channel: &str,
+    state: &Merkle,
+    conflicts: I,
+) -> ApiResult<ChannelConflicts> {
+    let mut conflicts: Vec<ConflictRecord> = conflicts.into_iter().map(Into::into).collect();
+    conflicts.sort_by(|a, b| (&a.path, a.line).cmp(&(&b.path, b.line)));
+    conflicts.dedup();
+    let record = ChannelConflicts {
+        channel: channel.to_string(),
+        state: state.to_base32(),
+        recorded_at: Utc::now(),
+        conflicts,
+    };
+    let mut table = load(dot_dir);
+    table.insert(channel.to_string(), record.clone());
+    let mut tmp = tempfile::NamedTempFile::new_in(dot_dir)?;
+    serde_json::to_writer(&mut tmp, &table).map_err(std::io::Error::from)?;
+    tmp.persist(dot_dir.join(CONFLICTS_FILE))
+        .map_err(|e| e.error)?;
+    Ok(record)
+}
+
+/// The conflicts recorded for each channel. Unreadable records are
+/// ignored, and scanned again.
+fn load(dot_dir: &Path) -> BTreeMap<String, ChannelConflicts> {
+    std::fs::read(dot_dir.join(CONFLICTS_FILE))
+        .ok()
+        .and_then(|b| serde_json::from_slice(&b).ok())
+        .unwrap_or_default()
+}
+
+fn channel_not_found(channel: &str) -> ApiError {
+    ApiError::Repository(RepositoryError::ChannelNotFound {
+        channel: channel.to_string(),
+    })
+}
+
+/// Record the conflicts of `channel`: `output`, the conflicts found when
+/// outputting the channel to the working copy, or else those found by
+/// outputting it to nowhere
+pub fn update(
+    repository: &Repository,
+    channel: &str,
+    output: Option<Vec<Conflict>>,
+) -> ApiResult<ChannelConflicts> {
+    let txn = repository
+        .pristine
+        .arc_txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+    let (channel_ref, state) = {
+        let t = txn.read();
+        let channel_ref = t
+            .load_channel(channel)
+            .map_err(|e| ApiError::internal(format!("Failed to load channel: {}", e)))?
+            .ok_or_else(|| channel_not_found(channel))?;
+        let state = libatomic::pristine::current_state(&*t, &*channel_ref.read())
+            .map_err(|e| ApiError::internal(format!("Failed to get current state: {}", e)))?;
+        (channel_ref, state)
+    };
+    let conflicts = match output {
+        Some(conflicts) => conflicts,
+        None => txn
+            .archive(&repository.changes, &channel_ref, &mut Discard)
+            .map_err(|e| ApiError::internal(format!("Failed to output {}: {}", channel, e)))?,
+    };
+    record(
+        &repository.path.join(libatomic::DOT_DIR),
+        channel,
+        &state,
+        conflicts.iter(),
+    )
+}
+
+/// The conflicts of `channel` (the current channel by default) at `state`
+/// (its current state by default), scanning the channel if its record is
+/// outdated. Only the current state of a channel is recorded.
+pub fn read(
+    repository: &Repository,
+    channel: Option<&str>,
+    state: Option<Merkle>,
+) -> ApiResult<ChannelConflicts> {
+    let txn = repository
+        .pristine
+        .txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+    let channel = channel
+        .unwrap_or_else(|| txn.current_channel().unwrap_or(libatomic::DEFAULT_CHANNEL))
+        .to_string();
+    let channel = channel.as_str();
+    let channel_ref = txn
+        .load_channel(channel)
+        .map_err(|e| ApiError::internal(format!("Failed to load channel: {}", e)))?
+        .ok_or_else(|| channel_not_found(channel))?;
+    let current = libatomic::pristine::current_state(&txn, &*channel_ref.read())
+        .map_err(|e| ApiError::internal(format!("Failed to get current state: {}", e)))?;
+    std::mem::drop(txn);
+    if let Some(state) = state {
+        if state != current {
+            return Err(ApiError::invalid_query(format!(
+                "Conflicts of channel {} are only known at its current state {}, not {}",
+                channel,
+                current.to_base32(),
+                state.to_base32()
+            )));
+        }
+    }
+    match load(&repository.path.join(libatomic::DOT_DIR)).remove(channel) {
+        Some(record) if record.state == current.to_base32() => Ok(record),
+        _ => update(repository, channel, None),
+    }
+}
+
+/// Archive writing nothing, to list the conflicts of a channel
+struct Discard;
+
+impl Archive for Discard {
+    type File = std::io::Sink;
+    type Error = std::io::Error;
+    fn create_file(&mut self, _path: &str, _mtime: u64, _perm: u16) -> Self::File {
+        std::io::sink()
+    }
+    fn create_dir(&mut self, _path: &str, _mtime: u64, _perm: u16) -> std::io::Result<()> {
+        Ok(())
+    }
+    fn close_file(&mut self, _f: Self::File) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_repository::fixtures::Fixture;
+
+    #[test]
+    fn test_read_conflicts() {
+        let fixture = Fixture::new().unwrap();
+        let repository = &fixture.repo;
+
+        let recorded = read(repository, Some("main"), None).unwrap();
+        assert_eq!(recorded.channel, "main");
+        assert!(recorded.conflicts.is_empty());
+        let dot_dir = repository.path.join(libatomic::DOT_DIR);
+        assert!(dot_dir.join(CONFLICTS_FILE).exists());
+
+        // Up-to-date records are served without scanning again.
+        let again = read(repository, None, None).unwrap();
+        assert_eq!(again.recorded_at, recorded.recorded_at);
+
+        let state = Merkle::from_base32(recorded.state.as_bytes()).unwrap();
+        assert!(read(repository, Some("main"), Some(state)).is_ok());
+        let other = Merkle::zero().next(&Merkle::zero());
+        assert!(matches!(
+            read(repository, Some("main"), Some(other)),
+            Err(ApiError::InvalidQuery { .. })
+        ));
+        assert!(matches!(
+            read(repository, Some("unknown"), None),
+            Err(ApiError::Repository(
+                RepositoryError::ChannelNotFound { .. }
+            ))
+        ));
+    }
+}
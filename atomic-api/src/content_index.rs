@@ -0,0 +1,524 @@
+//! Full-text index of the contents of applied changes
+//!
+//! With the `content-index` feature and `ATOMIC_API_CONTENT_INDEX` set,
+//! the server keeps a [tantivy](https://docs.rs/tantivy) index per
+//! repository, in `.atomic/index/content`. Each document is a hunk of a
+//! change: the lines it adds to a file, with the hash of the change, the
+//! path of the file and the line of the hunk. Deleted lines aren't
+//! indexed.
+//!
+//! The index is updated incrementally, from the `node_applied` events of
+//! the event bus, and can be rebuilt from the changes of all channels,
+//! read from the changestore (`atomic-api reindex <repo>`). A repository
+//! is also indexed in full the first time it is searched.
+//!
+//! Searches are served by `GET .../code/search/content?q=`, with an
+//! optional `path` prefix, saved `filter` and `limit`, and return
+//! highlighted snippets.
+
+use crate::{ApiError, ApiResult};
+use atomic_config::events::{Event, EventBus, NodeKind};
+use atomic_repository::Repository;
+use libatomic::change::{Atom, Change, Hunk};
+use libatomic::changestore::ChangeStore;
+use libatomic::pristine::{Base32, Hash};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tantivy::collector::{Count, TopDocs};
+use tantivy::query::{BooleanQuery, Occur, Query, QueryParser, RegexQuery};
+use tantivy::schema::{Field, Schema, Value, STORED, STRING, TEXT};
+use tantivy::{doc, Index, IndexReader, IndexWriter, TantivyDocument, Term};
+use tracing::{debug, info, warn};
+
+/// Directory of the index, in the `.atomic` directory of a repository
+pub const INDEX_DIR: &str = "index/content";
+/// Number of hits when the request has no `limit`
+pub const DEFAULT_LIMIT: usize = 20;
+/// Largest number of hits of a search
+pub const MAX_LIMIT: usize = 100;
+/// Default memory budget of the index writers
+const DEFAULT_WRITER_MEMORY: usize = 50 * 1024 * 1024;
+
+/// Configuration of the content index
+#[derive(Debug, Clone)]
+pub struct ContentIndexConfig {
+    /// Memory budget of the writer of each repository, in bytes
+    pub writer_memory: usize,
+}
+
+impl Default for ContentIndexConfig {
+    fn default() -> Self {
+        ContentIndexConfig {
+            writer_memory: DEFAULT_WRITER_MEMORY,
+        }
+    }
+}
+
+impl ContentIndexConfig {
+    /// Read the configuration if `ATOMIC_API_CONTENT_INDEX` is set to a
+    /// true value. The writer memory is read from
+    /// `ATOMIC_API_CONTENT_INDEX_WRITER_MB`.
+    pub fn from_env() -> Option<Self> {
+        let enabled = std::env::var("ATOMIC_API_CONTENT_INDEX").ok()?;
+        if !matches!(enabled.as_str(), "1" | "true" | "yes") {
+            return None;
+        }
+        let writer_memory = std::env::var("ATOMIC_API_CONTENT_INDEX_WRITER_MB")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .map_or(DEFAULT_WRITER_MEMORY, |m| m * 1024 * 1024);
+        Some(ContentIndexConfig { writer_memory })
+    }
+}
+
+/// Query parameters of `GET .../code/search/content`
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    /// Query, in the syntax of tantivy's query parser
+    pub q: String,
+    /// Only return hits in files under this path
+    pub path: Option<String>,
+    pub limit: Option<usize>,
+    /// Name of a saved filter to apply, see [`crate::filters`]
+    pub filter: Option<String>,
+}
+
+/// A hunk matching a search
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchHit {
+    /// Hash of the change
+    pub change: String,
+    pub path: String,
+    /// Line of the hunk in the file, when the change was recorded
+    pub line: u64,
+    pub score: f32,
+    /// Excerpt of the added lines around the matches
+    pub fragment: String,
+    /// Byte ranges of the matches in `fragment`
+    pub highlights: Vec<(usize, usize)>,
+    /// `fragment`, with the matches in `<b>` tags
+    pub snippet: String,
+}
+
+/// Result of a search
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResults {
+    pub hits: Vec<SearchHit>,
+    /// Number of matching hunks
+    pub total: usize,
+}
+
+/// Result of a rebuild
+#[derive(Debug, Default, Serialize)]
+pub struct RebuildReport {
+    /// Changes indexed
+    pub changes: usize,
+    /// Hunks indexed
+    pub documents: usize,
+    /// Changes that couldn't be read from the changestore
+    pub unreadable: Vec<String>,
+}
+
+#[derive(Clone, Copy)]
+struct Fields {
+    change: Field,
+    path: Field,
+    line: Field,
+    content: Field,
+}
+
+fn schema() -> (Schema, Fields) {
+    let mut builder = Schema::builder();
+    let fields = Fields {
+        change: builder.add_text_field("change", STRING | STORED),
+        path: builder.add_text_field("path", STRING | STORED),
+        line: builder.add_u64_field("line", STORED),
+        content: builder.add_text_field("content", TEXT | STORED),
+    };
+    (builder.build(), fields)
+}
+
+fn index_error(e: impl std::fmt::Display) -> ApiError {
+    ApiError::internal(format!("Content index error: {}", e))
+}
+
+/// A hunk to index: path, line and added text
+type Document = (String, u64, String);
+
+/// The text added by each hunk of `change`, skipping binary files
+fn documents(change: &Change) -> Vec<Document> {
+    let mut documents = Vec::new();
+    for hunk in change.changes.iter() {
+        let (path, line, atom, encoding) = match hunk {
+            Hunk::FileAdd {
+                path,
+                contents: Some(contents),
+                encoding,
+                ..
+            } => (path, 1, contents, encoding),
+            Hunk::Edit {
+                change: atom,
+                local,
+                encoding,
+            }
+            | Hunk::Replacement {
+                replacement: atom,
+                local,
+                encoding,
+                ..
+            } => (&local.path, local.line, atom, encoding),
+            _ => continue,
+        };
+        let Atom::NewVertex(ref n) = atom else {
+            continue;
+        };
+        if encoding.is_none() {
+            continue;
+        }
+        let (start, end): (usize, usize) = (n.start.0.into(), n.end.0.into());
+        let Some(text) = change.contents.get(start..end) else {
+            continue;
+        };
+        let text = String::from_utf8_lossy(text);
+        if !text.trim().is_empty() {
+            documents.push((path.clone(), line as u64, text.into_owned()));
+        }
+    }
+    documents
+}
+
+/// The index of one repository
+struct RepoIndex {
+    index: Index,
+    reader: IndexReader,
+    writer: Mutex<IndexWriter>,
+    fields: Fields,
+}
+
+impl RepoIndex {
+    /// Open the index in `dir`, creating it if needed. Returns whether it
+    /// was created.
+    fn open(dir: &Path, writer_memory: usize) -> ApiResult<(Self, bool)> {
+        std::fs::create_dir_all(dir)?;
+        let (schema, fields) = schema();
+        let directory = tantivy::directory::MmapDirectory::open(dir).map_err(index_error)?;
+        let created = !Index::exists(&directory).map_err(index_error)?;
+        let index = Index::open_or_create(directory, schema).map_err(index_error)?;
+        let reader = index.reader().map_err(index_error)?;
+        let writer = index.writer(writer_memory).map_err(index_error)?;
+        Ok((
+            RepoIndex {
+                index,
+                reader,
+                writer: Mutex::new(writer),
+                fields,
+            },
+            created,
+        ))
+    }
+
+    /// Replace the documents of `change` with `documents`, without
+    /// committing
+    fn add(
+        &self,
+        writer: &mut IndexWriter,
+        change: &str,
+        documents: Vec<Document>,
+    ) -> ApiResult<()> {
+        let f = self.fields;
+        writer.delete_term(Term::from_field_text(f.change, change));
+        for (path, line, content) in documents {
+            writer
+                .add_document(doc!(
+                    f.change => change,
+                    f.path => path,
+                    f.line => line,
+                    f.content => content,
+                ))
+                .map_err(index_error)?;
+        }
+        Ok(())
+    }
+
+    fn commit(&self, writer: &mut IndexWriter) -> ApiResult<()> {
+        writer.commit().map_err(index_error)?;
+        self.reader.reload().map_err(index_error)
+    }
+
+    fn search(&self, query: &SearchQuery) -> ApiResult<SearchResults> {
+        self.search_limit(query, query.limit.unwrap_or(DEFAULT_LIMIT))
+    }
+
+    fn search_limit(&self, query: &SearchQuery, limit: usize) -> ApiResult<SearchResults> {
+        let f = self.fields;
+        let parser = QueryParser::for_index(&self.index, vec![f.content]);
+        let content_query = parser
+            .parse_query(&query.q)
+            .map_err(|e| ApiError::invalid_query(format!("Invalid search query: {}", e)))?;
+        let full_query: Box<dyn Query> = match query.path.as_deref() {
+            Some(prefix) if !prefix.is_empty() => {
+                let pattern = format!("{}.*", escape_regex(prefix.trim_start_matches('/')));
+                let path_query = RegexQuery::from_pattern(&pattern, f.path).map_err(index_error)?;
+                Box::new(BooleanQuery::new(vec![
+                    (Occur::Must, content_query.box_clone()),
+                    (Occur::Must, Box::new(path_query)),
+                ]))
+            }
+            _ => content_query.box_clone(),
+        };
+        let limit = limit.clamp(1, MAX_LIMIT);
+        let searcher = self.reader.searcher();
+        let (top, total) = searcher
+            .search(&full_query, &(TopDocs::with_limit(limit), Count))
+            .map_err(index_error)?;
+        let snippets =
+            tantivy::snippet::SnippetGenerator::create(&searcher, &*content_query, f.content)
+                .map_err(index_error)?;
+        let mut hits = Vec::with_capacity(top.len());
+        for (score, address) in top {
+            let document: TantivyDocument = searcher.doc(address).map_err(index_error)?;
+            let text = |field| {
+                document
+                    .get_first(field)
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_string()
+            };
+            let snippet = snippets.snippet_from_doc(&document);
+            hits.push(SearchHit {
+                change: text(f.change),
+                path: text(f.path),
+                line: document
+                    .get_first(f.line)
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or_default(),
+                score,
+                fragment: snippet.fragment().to_string(),
+                highlights: snippet
+                    .highlighted()
+                    .iter()
+                    .map(|r| (r.start, r.end))
+                    .collect(),
+                snippet: snippet.to_html(),
+            });
+        }
+        Ok(SearchResults { hits, total })
+    }
+}
+
+/// Escape the characters of `s` that are special in tantivy's regexes
+fn escape_regex(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\.+*?()|[]{}^$#&-~\"@<>".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The content indexes of the repositories of a server
+#[derive(Clone)]
+pub struct ContentIndex {
+    config: Arc<ContentIndexConfig>,
+    open: Arc<Mutex<HashMap<PathBuf, Arc<RepoIndex>>>>,
+}
+
+impl ContentIndex {
+    pub fn new(config: ContentIndexConfig) -> Self {
+        ContentIndex {
+            config: Arc::new(config),
+            open: Arc::default(),
+        }
+    }
+
+    /// The index of the repository at `repo_path`, built from its changes
+    /// if it doesn't exist yet
+    fn repo_index(&self, repo_path: &Path) -> ApiResult<Arc<RepoIndex>> {
+        if let Some(index) = self.open.lock().unwrap().get(repo_path) {
+            return Ok(index.clone());
+        }
+        let dir = repo_path.join(libatomic::DOT_DIR).join(INDEX_DIR);
+        let (index, created) = RepoIndex::open(&dir, self.config.writer_memory)?;
+        let index = Arc::new(index);
+        if created {
+            info!("Building the content index of {}", repo_path.display());
+            rebuild_index(&index, repo_path)?;
+        }
+        let mut open = self.open.lock().unwrap();
+        Ok(open.entry(repo_path.to_path_buf()).or_insert(index).clone())
+    }
+
+    /// Index the change `hash`, applied to the repository at `repo_path`
+    pub fn index_change(&self, repo_path: &Path, hash: &Hash) -> ApiResult<usize> {
+        let index = self.repo_index(repo_path)?;
+        let repository = open_repository(repo_path)?;
+        let change = repository.changes.get_change(hash).map_err(|e| {
+            ApiError::internal(format!("Failed to read change {}: {}", hash.to_base32(), e))
+        })?;
+        let documents = documents(&change);
+        let n = documents.len();
+        let mut writer = index.writer.lock().unwrap();
+        index.add(&mut writer, &hash.to_base32(), documents)?;
+        index.commit(&mut writer)?;
+        debug!("Indexed {} hunks of {}", n, hash.to_base32());
+        Ok(n)
+    }
+
+    /// Rebuild the index of the repository at `repo_path` from the changes
+    /// of its channels
+    pub fn rebuild(&self, repo_path: &Path) -> ApiResult<RebuildReport> {
+        let index = self.repo_index(repo_path)?;
+        rebuild_index(&index, repo_path)
+    }
+
+    pub fn search(&self, repo_path: &Path, query: &SearchQuery) -> ApiResult<SearchResults> {
+        self.repo_index(repo_path)?.search(query)
+    }
+
+    /// Search like [`ContentIndex::search`], keeping the hits for which
+    /// `keep` is true. Hits are filtered among the first [`MAX_LIMIT`]
+    /// ones, and `total` only counts the hits kept.
+    pub fn search_where(
+        &self,
+        repo_path: &Path,
+        query: &SearchQuery,
+        mut keep: impl FnMut(&SearchHit) -> bool,
+    ) -> ApiResult<SearchResults> {
+        let mut results = self.repo_index(repo_path)?.search_limit(query, MAX_LIMIT)?;
+        results.hits.retain(|hit| keep(hit));
+        results.total = results.hits.len();
+        results
+            .hits
+            .truncate(query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT));
+        Ok(results)
+    }
+
+    /// Index the changes applied from now on, as published on `bus`, on
+    /// a thread of its own
+    pub fn subscribe(&self, bus: &EventBus) {
+        let receiver = bus.channel();
+        let index = self.clone();
+        std::thread::spawn(move || {
+            for event in receiver {
+                let Event::NodeApplied {
+                    repository,
+                    kind: NodeKind::Change,
+                    hash,
+                    ..
+                } = event
+                else {
+                    continue;
+                };
+                let Some(h) = Hash::from_base32(hash.as_bytes()) else {
+                    continue;
+                };
+                if let Err(e) = index.index_change(&repository, &h) {
+                    warn!("Failed to index change {}: {}", hash, e);
+                }
+            }
+        });
+    }
+}
+
+fn open_repository(repo_path: &Path) -> ApiResult<Repository> {
+    Repository::find_root(Some(repo_path.to_path_buf()))
+        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))
+}
+
+fn rebuild_index(index: &RepoIndex, repo_path: &Path) -> ApiResult<RebuildReport> {
+    let repository = open_repository(repo_path)?;
+    let mut report = RebuildReport::default();
+    let mut writer = index.writer.lock().unwrap();
+    writer.delete_all_documents().map_err(index_error)?;
+    for (hash, _, _) in crate::admin::channel_changes(&repository)? {
+        let change = match repository.changes.get_change(&hash) {
+            Ok(change) => change,
+            Err(e) => {
+                warn!("Failed to read change {}: {}", hash.to_base32(), e);
+                report.unreadable.push(hash.to_base32());
+                continue;
+            }
+        };
+        let documents = documents(&change);
+        report.changes += 1;
+        report.documents += documents.len();
+        index.add(&mut writer, &hash.to_base32(), documents)?;
+    }
+    index.commit(&mut writer)?;
+    info!(
+        "Indexed {} hunks of {} changes in {}",
+        report.documents,
+        report.changes,
+        repo_path.display()
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(q: &str, path: Option<&str>) -> SearchQuery {
+        SearchQuery {
+            q: q.to_string(),
+            path: path.map(str::to_string),
+            limit: None,
+            filter: None,
+        }
+    }
+
+    #[test]
+    fn test_search() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (index, created) = RepoIndex::open(tmp.path(), DEFAULT_WRITER_MEMORY).unwrap();
+        assert!(created);
+        let mut writer = index.writer.lock().unwrap();
+        index
+            .add(
+                &mut writer,
+                "CHANGE1",
+                vec![
+                    (
+                        "src/main.rs".to_string(),
+                        3,
+                        "fn deserialize() {}\n".to_string(),
+                    ),
+                    (
+                        "docs/config.md".to_string(),
+                        1,
+                        "deserialize reads it\n".to_string(),
+                    ),
+                ],
+            )
+            .unwrap();
+        index.commit(&mut writer).unwrap();
+
+        let results = index.search(&query("deserialize", None)).unwrap();
+        assert_eq!(results.total, 2);
+        let results = index.search(&query("deserialize", Some("src/"))).unwrap();
+        assert_eq!(results.total, 1);
+        let hit = &results.hits[0];
+        assert_eq!((hit.change.as_str(), hit.line), ("CHANGE1", 3));
+        assert!(hit.snippet.contains("<b>deserialize</b>"));
+        assert_eq!(hit.highlights.len(), 1);
+
+        // Reindexing a change replaces its hunks
+        index.add(&mut writer, "CHANGE1", Vec::new()).unwrap();
+        index.commit(&mut writer).unwrap();
+        assert_eq!(index.search(&query("deserialize", None)).unwrap().total, 0);
+
+        assert!(matches!(
+            index.search(&query("content:(", None)),
+            Err(ApiError::InvalidQuery { .. })
+        ));
+    }
+
+    #[test]
+    fn test_escape_regex() {
+        assert_eq!(escape_regex("src/a.b-c"), "src/a\\.b\\-c");
+    }
+}
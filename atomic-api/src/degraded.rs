@@ -0,0 +1,335 @@
+//! Degraded mode
+//!
+//! When the disk holding the repositories is nearly full, or applies pile
+//! up behind the pristine write locks, accepting more pushes only makes
+//! things worse. A monitor checks these resources periodically, and while
+//! one of them is past its limit the server is degraded: requests that
+//! write (anything but `GET`, `HEAD` and `OPTIONS`) are rejected with a
+//! `503` and a `Retry-After` header, and reads are still served.
+//!
+//! Each resource has a second, lower threshold to leave the degraded mode,
+//! so that a server hovering around a limit doesn't flap between modes.
+//! The current mode and counters are served by `GET /metrics/degraded`.
+
+use crate::{ApiError, ApiResult};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+const MB: u64 = 1024 * 1024;
+/// Default free space under which writes are rejected
+const DEFAULT_MIN_FREE: u64 = 1024 * MB;
+/// Default number of queued applies from which writes are rejected
+const DEFAULT_MAX_QUEUED: usize = 256;
+/// Default interval between two checks
+const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// Default `Retry-After` of rejected writes, in seconds
+const DEFAULT_RETRY_AFTER: u64 = 30;
+
+/// Thresholds of the degraded mode
+#[derive(Debug, Clone)]
+pub struct DegradedConfig {
+    /// Free bytes under which the server becomes degraded
+    pub min_free: u64,
+    /// Free bytes from which it recovers, at least `min_free`
+    pub resume_free: u64,
+    /// Queued applies from which the server becomes degraded, `0` to
+    /// ignore the queue
+    pub max_queued: usize,
+    /// Queued applies at or under which it recovers, at most `max_queued`
+    pub resume_queued: usize,
+    /// Interval between two checks of the resources
+    pub check_interval: Duration,
+    /// `Retry-After` of rejected writes, in seconds
+    pub retry_after: u64,
+}
+
+impl Default for DegradedConfig {
+    fn default() -> Self {
+        DegradedConfig {
+            min_free: DEFAULT_MIN_FREE,
+            resume_free: 2 * DEFAULT_MIN_FREE,
+            max_queued: DEFAULT_MAX_QUEUED,
+            resume_queued: DEFAULT_MAX_QUEUED / 2,
+            check_interval: DEFAULT_CHECK_INTERVAL,
+            retry_after: DEFAULT_RETRY_AFTER,
+        }
+    }
+}
+
+impl DegradedConfig {
+    /// Read the configuration from the `ATOMIC_API_DEGRADED_*` variables,
+    /// with defaults for unset variables. The resume thresholds default
+    /// to twice the free space and half the queue depth.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(var: &str) -> Option<T> {
+            std::env::var(var).ok().and_then(|s| s.parse().ok())
+        }
+        let min_free =
+            var::<u64>("ATOMIC_API_DEGRADED_MIN_FREE_MB").map_or(DEFAULT_MIN_FREE, |m| m * MB);
+        let resume_free = var::<u64>("ATOMIC_API_DEGRADED_RESUME_FREE_MB")
+            .map_or(2 * min_free, |m| m * MB)
+            .max(min_free);
+        let max_queued = var("ATOMIC_API_DEGRADED_MAX_QUEUED").unwrap_or(DEFAULT_MAX_QUEUED);
+        let resume_queued = var("ATOMIC_API_DEGRADED_RESUME_QUEUED")
+            .unwrap_or(max_queued / 2)
+            .min(max_queued);
+        DegradedConfig {
+            min_free,
+            resume_free,
+            max_queued,
+            resume_queued,
+            check_interval: var("ATOMIC_API_DEGRADED_CHECK_INTERVAL")
+                .filter(|&s: &u64| s > 0)
+                .map_or(DEFAULT_CHECK_INTERVAL, Duration::from_secs),
+            retry_after: var("ATOMIC_API_DEGRADED_RETRY_AFTER").unwrap_or(DEFAULT_RETRY_AFTER),
+        }
+    }
+}
+
+/// Why the server is degraded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DegradedReason {
+    /// The disk holding the repositories is nearly full
+    LowDiskSpace,
+    /// Too many applies are waiting for the pristine write locks
+    ApplyBacklog,
+}
+
+/// Resources measured by a check
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Resources {
+    /// Free bytes on the disk holding the repositories, if known
+    pub free_bytes: Option<u64>,
+    /// Applies waiting, for all repositories
+    pub queued: usize,
+}
+
+impl Resources {
+    /// Measure the free space under `path`, with `queued` waiting applies
+    pub fn measure(path: &Path, queued: usize) -> Self {
+        let free_bytes = match fs2::available_space(path) {
+            Ok(free) => Some(free),
+            Err(e) => {
+                warn!("Failed to read free space of {}: {}", path.display(), e);
+                None
+            }
+        };
+        Resources { free_bytes, queued }
+    }
+}
+
+/// Mode and counters, served by `GET /metrics/degraded`
+#[derive(Debug, Clone, Serialize)]
+pub struct DegradedMetrics {
+    pub degraded: bool,
+    pub reasons: Vec<DegradedReason>,
+    /// When the server last became degraded, if it still is
+    pub since: Option<DateTime<Utc>>,
+    /// Free bytes at the last check
+    pub free_bytes: Option<u64>,
+    /// Queued applies at the last check
+    pub queued: usize,
+    /// Number of times the server became degraded
+    pub transitions: u64,
+    /// Writes rejected while degraded
+    pub rejected: u64,
+}
+
+#[derive(Default)]
+struct Status {
+    reasons: BTreeSet<DegradedReason>,
+    since: Option<DateTime<Utc>>,
+    last: Resources,
+}
+
+/// The current mode of the server
+#[derive(Clone)]
+pub struct DegradedMode {
+    config: Arc<DegradedConfig>,
+    status: Arc<Mutex<Status>>,
+    transitions: Arc<AtomicU64>,
+    rejected: Arc<AtomicU64>,
+}
+
+impl Default for DegradedMode {
+    fn default() -> Self {
+        Self::new(DegradedConfig::default())
+    }
+}
+
+impl DegradedMode {
+    pub fn new(config: DegradedConfig) -> Self {
+        DegradedMode {
+            config: Arc::new(config),
+            status: Arc::default(),
+            transitions: Arc::default(),
+            rejected: Arc::default(),
+        }
+    }
+
+    pub fn config(&self) -> &DegradedConfig {
+        &self.config
+    }
+
+    /// Update the mode from the last measure of the resources. A reason
+    /// only goes away once its resource is back under the resume
+    /// threshold.
+    pub fn update(&self, resources: Resources) {
+        let config = &self.config;
+        let mut status = self.status.lock().unwrap();
+        let was_degraded = !status.reasons.is_empty();
+
+        let low_disk = status.reasons.contains(&DegradedReason::LowDiskSpace);
+        let low_disk = match resources.free_bytes {
+            Some(free) if low_disk => free < config.resume_free,
+            Some(free) => free < config.min_free,
+            None => low_disk,
+        };
+        let backlog = status.reasons.contains(&DegradedReason::ApplyBacklog);
+        let backlog = config.max_queued > 0
+            && if backlog {
+                resources.queued > config.resume_queued
+            } else {
+                resources.queued >= config.max_queued
+            };
+
+        let mut reasons = BTreeSet::new();
+        if low_disk {
+            reasons.insert(DegradedReason::LowDiskSpace);
+        }
+        if backlog {
+            reasons.insert(DegradedReason::ApplyBacklog);
+        }
+        if reasons != status.reasons {
+            if reasons.is_empty() {
+                info!("Leaving degraded mode");
+                status.since = None;
+            } else {
+                warn!(
+                    "Degraded mode, rejecting writes: {:?} (free bytes {:?}, queued applies {})",
+                    reasons, resources.free_bytes, resources.queued
+                );
+                if !was_degraded {
+                    self.transitions.fetch_add(1, Ordering::Relaxed);
+                    status.since = Some(Utc::now());
+                }
+            }
+        }
+        status.reasons = reasons;
+        status.last = resources;
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        !self.status.lock().unwrap().reasons.is_empty()
+    }
+
+    /// Fail with [`ApiError::Degraded`] if writes are currently rejected
+    pub fn check_write(&self) -> ApiResult<()> {
+        let status = self.status.lock().unwrap();
+        if status.reasons.is_empty() {
+            return Ok(());
+        }
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+        let reasons: Vec<_> = status
+            .reasons
+            .iter()
+            .map(|r| match r {
+                DegradedReason::LowDiskSpace => "low disk space",
+                DegradedReason::ApplyBacklog => "too many applies waiting",
+            })
+            .collect();
+        Err(ApiError::degraded(
+            format!(
+                "Server degraded ({}), writes are rejected",
+                reasons.join(", ")
+            ),
+            self.config.retry_after,
+        ))
+    }
+
+    pub fn metrics(&self) -> DegradedMetrics {
+        let status = self.status.lock().unwrap();
+        DegradedMetrics {
+            degraded: !status.reasons.is_empty(),
+            reasons: status.reasons.iter().copied().collect(),
+            since: status.since,
+            free_bytes: status.last.free_bytes,
+            queued: status.last.queued,
+            transitions: self.transitions.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> DegradedConfig {
+        DegradedConfig {
+            min_free: 100,
+            resume_free: 200,
+            max_queued: 10,
+            resume_queued: 5,
+            ..DegradedConfig::default()
+        }
+    }
+
+    fn resources(free: u64, queued: usize) -> Resources {
+        Resources {
+            free_bytes: Some(free),
+            queued,
+        }
+    }
+
+    #[test]
+    fn test_disk_hysteresis() {
+        let mode = DegradedMode::new(config());
+        mode.update(resources(150, 0));
+        assert!(mode.check_write().is_ok());
+        mode.update(resources(50, 0));
+        assert!(matches!(
+            mode.check_write(),
+            Err(ApiError::Degraded {
+                retry_after: 30,
+                ..
+            })
+        ));
+        // Above the entry threshold, but not the resume one
+        mode.update(resources(150, 0));
+        assert!(mode.is_degraded());
+        // Unknown free space keeps the current mode
+        mode.update(Resources::default());
+        assert!(mode.is_degraded());
+        mode.update(resources(250, 0));
+        assert!(!mode.is_degraded());
+
+        let metrics = mode.metrics();
+        assert_eq!((metrics.transitions, metrics.rejected), (1, 1));
+    }
+
+    #[test]
+    fn test_queue_hysteresis() {
+        let mode = DegradedMode::new(config());
+        mode.update(resources(1000, 10));
+        assert_eq!(mode.metrics().reasons, vec![DegradedReason::ApplyBacklog]);
+        mode.update(resources(1000, 6));
+        assert!(mode.is_degraded());
+        mode.update(resources(1000, 5));
+        assert!(!mode.is_degraded());
+
+        let mode = DegradedMode::new(DegradedConfig {
+            max_queued: 0,
+            ..config()
+        });
+        mode.update(resources(1000, 1000));
+        assert!(!mode.is_degraded());
+    }
+}
@@ -0,0 +1,506 @@
+//! Isolated diffs of changes, for stacked reviews
+//!
+//! A change in the middle of a stack is reviewed against the state just
+//! before it, not against the tip of the channel, where the changes
+//! stacked on it are already applied.
+//! `GET .../code/changes/:change_id/diff?base=auto|<merkle>` finds the
+//! change in the log of the channel, outputs the channel at the base
+//! state and at the base state with the change applied, and renders a
+//! unified diff of the files that differ.
+//!
+//! The base is the state just before the change by default (`auto`), and
+//! can be any earlier state of the channel, or the empty state, to review
+//! a change along with the changes between that state and it. Only the
+//! change and its dependencies are applied to the base, never the changes
+//! after it. The outputs happen in forks of the channel, discarded with
+//! the transaction.
+
+use crate::error::RepositoryError;
+use crate::{ApiError, ApiResult};
+use atomic_repository::Repository;
+use libatomic::output::Archive;
+use libatomic::pristine::sanakirja::MutTxn;
+use libatomic::pristine::{ArcTxn, Base32, ChannelRef, Hash, Merkle};
+use libatomic::{MutTxnT, MutTxnTExt, TxnT, TxnTExt};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Lines of context around the changed lines, unless requested otherwise
+pub const DEFAULT_CONTEXT: usize = 3;
+
+/// Largest number of context lines served
+const MAX_CONTEXT: usize = 100;
+
+/// Largest product of the numbers of changed lines of the two versions of
+/// a file that are compared line by line. Larger changes of a file are
+/// rendered as the deletion of all the old lines and the insertion of
+/// all the new ones.
+const MAX_COMPARED: usize = 4_000_000;
+
+/// Query parameters of `GET .../code/changes/:change_id/diff`
+#[derive(Debug, Default, Deserialize)]
+pub struct DiffQuery {
+    /// Channel, the current channel by default
+    pub channel: Option<String>,
+    /// `auto` (the default) for the state just before the change, or a
+    /// state of the channel before the change, in base32
+    pub base: Option<String>,
+    /// Lines of context, [`DEFAULT_CONTEXT`] by default
+    pub context: Option<usize>,
+}
+
+/// What a change does to a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileStatus {
+    Added,
+    Deleted,
+    Modified,
+}
+
+/// Diff of a file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileDiff {
+    pub path: String,
+    pub status: FileStatus,
+    /// Whether one of the versions isn't UTF-8, in which case `diff` only
+    /// has the header
+    pub binary: bool,
+    /// Unified diff of the file
+    pub diff: String,
+}
+
+/// Diff of a change against a state of its channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeDiff {
+    pub hash: String,
+    pub channel: String,
+    /// Position of the change in the channel log
+    pub position: u64,
+    /// State the diff is against
+    pub base: String,
+    /// State of the channel just before the change
+    pub pre_state: String,
+    /// State of the channel just after the change
+    pub post_state: String,
+    pub files: Vec<FileDiff>,
+}
+
+fn txn_error(e: impl std::fmt::Display) -> ApiError {
+    ApiError::internal(format!("Diff transaction failed: {}", e))
+}
+
+/// Parse the `base` parameter: `None` for the state just before the
+/// change
+pub fn parse_base(base: Option<&str>) -> ApiResult<Option<Merkle>> {
+    match base {
+        None | Some("") | Some("auto") => Ok(None),
+        Some(s) => Merkle::from_base32(s.as_bytes())
+            .map(Some)
+            .ok_or_else(|| ApiError::invalid_query(format!("Invalid base state {}", s))),
+    }
+}
+
+/// The diff of change `change_id` of the channel of `query`
+pub fn read(repository: &Repository, change_id: &str, query: &DiffQuery) -> ApiResult<ChangeDiff> {
+    let not_found = || {
+        ApiError::Repository(RepositoryError::ChangeNotFound {
+            change_id: change_id.to_string(),
+        })
+    };
+    let hash = crate::resolve::resolve(repository, change_id)?;
+    let base = parse_base(query.base.as_deref())?;
+    let context = query.context.unwrap_or(DEFAULT_CONTEXT).min(MAX_CONTEXT);
+
+    let txn = repository.pristine.arc_txn_begin().map_err(txn_error)?;
+    let (channel_name, channel) = {
+        let t = txn.read();
+        let name = query
+            .channel
+            .as_deref()
+            .unwrap_or_else(|| t.current_channel().unwrap_or(libatomic::DEFAULT_CHANNEL))
+            .to_string();
+        let channel = t.load_channel(&name).map_err(txn_error)?.ok_or_else(|| {
+            ApiError::Repository(RepositoryError::ChannelNotFound {
+                channel: name.clone(),
+            })
+        })?;
+        (name, channel)
+    };
+
+    // The states of the channel before the change, and its position.
+    let mut earlier = vec![Merkle::zero()];
+    let mut found = None;
+    for entry in txn.read().log(&*channel.read(), 0).map_err(txn_error)? {
+        let (n, (h, m)) = entry.map_err(txn_error)?;
+        let m = Merkle::from(m);
+        if Hash::from(h) == hash {
+            found = Some((n, m));
+            break;
+        }
+        earlier.push(m);
+    }
+    let (position, post_state) = found.ok_or_else(not_found)?;
+    let pre_state = *earlier.last().unwrap();
+    let base = match base {
+        None => pre_state,
+        Some(base) if earlier.contains(&base) => base,
+        Some(base) => {
+            return Err(ApiError::invalid_query(format!(
+                "State {} of channel {} isn't before change {}",
+                base.to_base32(),
+                channel_name,
+                change_id
+            )))
+        }
+    };
+
+    let old = output(repository, &txn, &channel, &base, &[])?;
+    let new = output(repository, &txn, &channel, &base, &[hash])?;
+    // The transaction isn't committed, which discards the forks.
+    std::mem::drop(txn);
+
+    Ok(ChangeDiff {
+        hash: hash.to_base32(),
+        channel: channel_name,
+        position,
+        base: base.to_base32(),
+        pre_state: pre_state.to_base32(),
+        post_state: post_state.to_base32(),
+        files: diff_files(&old, &new, context),
+    })
+}
+
+/// The files of `channel` at `state`, with `extra` applied, output in a
+/// fork of the channel. `state` is a state of the channel or the empty
+/// state.
+fn output(
+    repository: &Repository,
+    txn: &ArcTxn<MutTxn<()>>,
+    channel: &ChannelRef<MutTxn<()>>,
+    state: &Merkle,
+    extra: &[Hash],
+) -> ApiResult<BTreeMap<String, Vec<u8>>> {
+    let name = format!("diff-{}", uuid::Uuid::new_v4().simple());
+    let mut files = Files::default();
+    let archived = if *state == Merkle::zero() {
+        // The empty state isn't in the log, start from an empty channel.
+        let fork = txn
+            .write()
+            .open_or_create_channel(&name)
+            .map_err(txn_error)?;
+        for h in extra {
+            txn.write()
+                .apply_change_rec(&repository.changes, &mut *fork.write(), h)
+                .map_err(|e| {
+                    ApiError::internal(format!("Failed to apply {}: {}", h.to_base32(), e))
+                })?;
+        }
+        txn.archive(&repository.changes, &fork, &mut files)
+    } else {
+        let fork = txn.write().fork(channel, &name).map_err(txn_error)?;
+        txn.archive_with_state(&repository.changes, &fork, state, extra, &mut files, 0)
+    };
+    archived.map_err(|e| {
+        ApiError::internal(format!(
+            "Failed to output state {}: {}",
+            state.to_base32(),
+            e
+        ))
+    })?;
+    Ok(files.files)
+}
+
+/// Archive keeping the files in memory
+#[derive(Default)]
+struct Files {
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+struct File {
+    path: String,
+    buf: Vec<u8>,
+}
+
+impl std::io::Write for File {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Archive for Files {
+    type File = File;
+    type Error = std::io::Error;
+    fn create_file(&mut self, path: &str, _mtime: u64, _perm: u16) -> Self::File {
+        File {
+            path: path.to_string(),
+            buf: Vec::new(),
+        }
+    }
+    fn create_dir(&mut self, _path: &str, _mtime: u64, _perm: u16) -> std::io::Result<()> {
+        Ok(())
+    }
+    fn close_file(&mut self, f: Self::File) -> std::io::Result<()> {
+        self.files.insert(f.path, f.buf);
+        Ok(())
+    }
+}
+
+/// The diffs of the files that differ between `old` and `new`, in path
+/// order
+pub fn diff_files(
+    old: &BTreeMap<String, Vec<u8>>,
+    new: &BTreeMap<String, Vec<u8>>,
+    context: usize,
+) -> Vec<FileDiff> {
+    let mut paths: Vec<&String> = old.keys().chain(new.keys()).collect();
+    paths.sort();
+    paths.dedup();
+    let mut files = Vec::new();
+    for path in paths {
+        let (a, b) = (old.get(path), new.get(path));
+        if a == b {
+            continue;
+        }
+        let status = match (a, b) {
+            (None, _) => FileStatus::Added,
+            (_, None) => FileStatus::Deleted,
+            _ => FileStatus::Modified,
+        };
+        let mut diff = format!(
+            "--- {}\n+++ {}\n",
+            a.map_or("/dev/null".to_string(), |_| format!("a/{}", path)),
+            b.map_or("/dev/null".to_string(), |_| format!("b/{}", path)),
+        );
+        let a = std::str::from_utf8(a.map_or(&[][..], |a| a));
+        let b = std::str::from_utf8(b.map_or(&[][..], |b| b));
+        let binary = match (a, b) {
+            (Ok(a), Ok(b)) => {
+                diff.push_str(&unified(a, b, context));
+                false
+            }
+            _ => true,
+        };
+        files.push(FileDiff {
+            path: path.clone(),
+            status,
+            binary,
+            diff,
+        })
+    }
+    files
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Keep,
+    Delete,
+    Insert,
+}
+
+/// The lines of `s`, with their line endings
+fn lines(s: &str) -> Vec<&str> {
+    s.split_inclusive('\n').collect()
+}
+
+/// Edit script turning `a` into `b`
+fn edits<'a>(a: &[&'a str], b: &[&'a str]) -> Vec<(Op, &'a str)> {
+    let prefix = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (ma, mb) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+    let mut ops: Vec<(Op, &str)> = a[..prefix].iter().map(|l| (Op::Keep, *l)).collect();
+    if ma.len() * mb.len() > MAX_COMPARED {
+        ops.extend(ma.iter().map(|l| (Op::Delete, *l)));
+        ops.extend(mb.iter().map(|l| (Op::Insert, *l)));
+    } else {
+        // Longest common subsequences of the suffixes of the middles.
+        let w = mb.len() + 1;
+        let mut lcs = vec![0u32; (ma.len() + 1) * w];
+        for i in (0..ma.len()).rev() {
+            for j in (0..mb.len()).rev() {
+                lcs[i * w + j] = if ma[i] == mb[j] {
+                    lcs[(i + 1) * w + j + 1] + 1
+                } else {
+                    lcs[(i + 1) * w + j].max(lcs[i * w + j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < ma.len() || j < mb.len() {
+            if i < ma.len() && j < mb.len() && ma[i] == mb[j] {
+                ops.push((Op::Keep, ma[i]));
+                i += 1;
+                j += 1;
+            } else if j == mb.len() || (i < ma.len() && lcs[(i + 1) * w + j] >= lcs[i * w + j + 1])
+            {
+                ops.push((Op::Delete, ma[i]));
+                i += 1;
+            } else {
+                ops.push((Op::Insert, mb[j]));
+                j += 1;
+            }
+        }
+    }
+    ops.extend(a[a.len() - suffix..].iter().map(|l| (Op::Keep, *l)));
+    ops
+}
+
+/// Unified diff of `a` and `b`, without the file header, with `context`
+/// lines around the changed lines
+pub fn unified(a: &str, b: &str, context: usize) -> String {
+    let ops = edits(&lines(a), &lines(b));
+    let changed: Vec<usize> = (0..ops.len()).filter(|&i| ops[i].0 != Op::Keep).collect();
+    let mut out = String::new();
+    // Lines of `a` and `b` before each operation.
+    let (mut old_line, mut new_line) = (0, 0);
+    let mut before = Vec::with_capacity(ops.len() + 1);
+    for (op, _) in ops.iter() {
+        before.push((old_line, new_line));
+        match op {
+            Op::Keep => {
+                old_line += 1;
+                new_line += 1
+            }
+            Op::Delete => old_line += 1,
+            Op::Insert => new_line += 1,
+        }
+    }
+    before.push((old_line, new_line));
+    let mut i = 0;
+    while i < changed.len() {
+        // Changes closer than twice the context share a hunk.
+        let mut j = i;
+        while j + 1 < changed.len() && changed[j + 1] - changed[j] <= 2 * context + 1 {
+            j += 1
+        }
+        let start = changed[i].saturating_sub(context);
+        let end = (changed[j] + 1 + context).min(ops.len());
+        let (old_start, new_start) = before[start];
+        let (old_end, new_end) = before[end];
+        let range = |start: usize, len: usize| {
+            // Empty ranges start at the line before them.
+            let start = if len == 0 { start } else { start + 1 };
+            format!("{},{}", start, len)
+        };
+        out.push_str(&format!(
+            "@@ -{} +{} @@\n",
+            range(old_start, old_end - old_start),
+            range(new_start, new_end - new_start)
+        ));
+        for (op, line) in &ops[start..end] {
+            out.push(match op {
+                Op::Keep => ' ',
+                Op::Delete => '-',
+                Op::Insert => '+',
+            });
+            out.push_str(line);
+            if !line.ends_with('\n') {
+                out.push_str("\n\\ No newline at end of file\n");
+            }
+        }
+        i = j + 1;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_repository::fixtures::Fixture;
+
+    #[test]
+    fn test_unified() {
+        let a = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let b = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk\n";
+        assert_eq!(
+            unified(a, b, 1),
+            "@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n@@ -10,1 +10,2 @@\n j\n+k\n"
+        );
+        // Close changes share a hunk.
+        assert_eq!(
+            unified(a, b, 4),
+            "@@ -1,10 +1,11 @@\n a\n-b\n+B\n c\n d\n e\n f\n g\n h\n i\n j\n+k\n"
+        );
+        assert_eq!(unified(a, a, 3), "");
+        assert_eq!(unified("", "x\n", 3), "@@ -0,0 +1,1 @@\n+x\n");
+        assert_eq!(
+            unified("x\n", "x", 3),
+            "@@ -1,1 +1,1 @@\n-x\n+x\n\\ No newline at end of file\n"
+        );
+    }
+
+    #[test]
+    fn test_diff_files() {
+        let old: BTreeMap<String, Vec<u8>> = [
+            ("same".to_string(), b"s\n".to_vec()),
+            ("gone".to_string(), b"g\n".to_vec()),
+            ("edited".to_string(), b"1\n2\n".to_vec()),
+        ]
+        .into_iter()
+        .collect();
+        let new: BTreeMap<String, Vec<u8>> = [
+            ("same".to_string(), b"s\n".to_vec()),
+            ("edited".to_string(), b"1\n3\n".to_vec()),
+            ("image".to_string(), vec![0xff, 0xfe]),
+        ]
+        .into_iter()
+        .collect();
+        let files = diff_files(&old, &new, DEFAULT_CONTEXT);
+        let summary: Vec<_> = files
+            .iter()
+            .map(|f| (f.path.as_str(), f.status, f.binary))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("edited", FileStatus::Modified, false),
+                ("gone", FileStatus::Deleted, false),
+                ("image", FileStatus::Added, true),
+            ]
+        );
+        assert_eq!(
+            files[0].diff,
+            "--- a/edited\n+++ b/edited\n@@ -1,2 +1,2 @@\n 1\n-2\n+3\n"
+        );
+        assert_eq!(
+            files[1].diff,
+            "--- a/gone\n+++ /dev/null\n@@ -1,1 +0,0 @@\n-g\n"
+        );
+        assert_eq!(files[2].diff, "--- /dev/null\n+++ b/image\n");
+    }
+
+    #[test]
+    fn test_read_errors() {
+        let fixture = Fixture::new().unwrap();
+        let repository = &fixture.repo;
+        assert!(matches!(parse_base(Some("auto")), Ok(None)));
+        assert!(matches!(
+            parse_base(Some("not a state")),
+            Err(ApiError::InvalidQuery { .. })
+        ));
+
+        let change_id = Hash::NONE.to_base32();
+        assert!(matches!(
+            read(repository, "not a hash", &DiffQuery::default()),
+            Err(ApiError::Repository(RepositoryError::ChangeNotFound { .. }))
+        ));
+        let query = DiffQuery {
+            channel: Some("unknown".to_string()),
+            ..DiffQuery::default()
+        };
+        assert!(matches!(
+            read(repository, &change_id, &query),
+            Err(ApiError::Repository(
+                RepositoryError::ChannelNotFound { .. }
+            ))
+        ));
+    }
+}
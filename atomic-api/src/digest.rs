@@ -0,0 +1,412 @@
+//! "What changed since my last visit" digests
+//!
+//! `GET .../code/digest?since=` summarizes the activity of a channel
+//! since a state (a previous digest's `state`) or a time (RFC 3339, or
+//! seconds since the epoch), for email or chat digests: the new changes,
+//! grouped by author and by area (the first directories of the paths
+//! they touch), the new tags, and the workflow transitions of these
+//! changes and tags. Every change and tag links back to its endpoint.
+//!
+//! Digests are bounded: at most [`MAX_CHANGES`] entries of the channel
+//! log are read, each group lists at most `limit` of its changes, and
+//! `truncated` tells whether older entries were left unread. Workflow
+//! transitions come from the recent events kept by the server
+//! (`GET /events`), so older transitions are missing from the digest.
+
+use crate::event_log::RecordedEvent;
+use crate::{ApiError, ApiResult};
+use atomic_config::events::{Event, NodeKind};
+use atomic_repository::Repository;
+use chrono::{DateTime, Utc};
+use libatomic::changestore::ChangeStore;
+use libatomic::pristine::{Base32, Merkle, TagMetadataTxnT};
+use libatomic::{ChannelTxnT, TxnTExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+/// Largest number of changes read for a digest
+pub const MAX_CHANGES: usize = 1000;
+/// Largest number of tags of a digest
+pub const MAX_TAGS: usize = 100;
+/// Changes listed per group when the request has no `limit`
+pub const DEFAULT_LIMIT: usize = 5;
+/// Largest number of changes listed per group
+pub const MAX_LIMIT: usize = 50;
+/// Largest depth of the areas
+const MAX_DEPTH: usize = 5;
+/// Area of the files at the root of the repository
+const ROOT_AREA: &str = "/";
+
+/// Query parameters of `GET .../code/digest`
+#[derive(Debug, Deserialize)]
+pub struct DigestQuery {
+    /// State or time the digest starts from
+    pub since: String,
+    /// Channel to summarize, the current channel by default
+    pub channel: Option<String>,
+    /// Changes listed per group
+    pub limit: Option<usize>,
+    /// Number of directories of the areas (default: 1)
+    pub depth: Option<usize>,
+}
+
+/// Start of a digest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Since {
+    State(Merkle),
+    Time(DateTime<Utc>),
+}
+
+impl Since {
+    pub fn parse(s: &str) -> ApiResult<Self> {
+        if let Some(t) = crate::snapshot::parse_time(s) {
+            return Ok(Since::Time(t));
+        }
+        if let Some(state) = Merkle::from_base32(s.as_bytes()) {
+            return Ok(Since::State(state));
+        }
+        Err(ApiError::invalid_query(format!(
+            "Invalid since {:?}: expected a state, an RFC 3339 time or a timestamp",
+            s
+        )))
+    }
+}
+
+/// A change of a digest
+#[derive(Debug, Clone, Serialize)]
+pub struct DigestChange {
+    pub hash: String,
+    pub message: String,
+    pub author: String,
+    pub timestamp: String,
+    pub link: String,
+}
+
+/// Changes sharing an author or an area
+#[derive(Debug, Clone, Serialize)]
+pub struct DigestGroup {
+    pub name: String,
+    /// Number of changes of the group, including those not listed
+    pub count: usize,
+    pub changes: Vec<DigestChange>,
+}
+
+/// A tag of a digest
+#[derive(Debug, Clone, Serialize)]
+pub struct DigestTag {
+    pub state: String,
+    pub version: Option<String>,
+    /// Consolidation time, in seconds since the epoch
+    pub timestamp: u64,
+    pub link: String,
+}
+
+/// A workflow transition of a change or tag of the digest
+#[derive(Debug, Clone, Serialize)]
+pub struct DigestTransition {
+    pub workflow: String,
+    pub kind: NodeKind,
+    pub change_id: String,
+    pub from: String,
+    pub to: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// What changed in a channel since a state or a time
+#[derive(Debug, Serialize)]
+pub struct Digest {
+    pub channel: String,
+    pub since: String,
+    /// Current state of the channel, the `since` of the next digest
+    pub state: String,
+    /// Number of new changes
+    pub changes: usize,
+    pub authors: Vec<DigestGroup>,
+    pub areas: Vec<DigestGroup>,
+    pub tags: Vec<DigestTag>,
+    pub transitions: Vec<DigestTransition>,
+    /// Whether older entries of the log were left unread
+    pub truncated: bool,
+}
+
+/// Area of `path`: its first `depth` directories
+pub fn area(path: &str, depth: usize) -> String {
+    let mut dirs: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+    // The last component is the file itself.
+    dirs.pop();
+    if dirs.is_empty() {
+        return ROOT_AREA.to_string();
+    }
+    dirs.truncate(depth.max(1));
+    dirs.join("/")
+}
+
+/// Groups of changes, the largest first, each listing at most `limit`
+/// changes, newest first
+fn groups(groups: BTreeMap<String, Vec<DigestChange>>, limit: usize) -> Vec<DigestGroup> {
+    let mut groups: Vec<DigestGroup> = groups
+        .into_iter()
+        .map(|(name, mut changes)| {
+            let count = changes.len();
+            changes.truncate(limit);
+            DigestGroup {
+                name,
+                count,
+                changes,
+            }
+        })
+        .collect();
+    groups.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+    groups
+}
+
+/// Builds a digest from the new changes, newest first
+struct Builder {
+    limit: usize,
+    depth: usize,
+    changes: usize,
+    authors: BTreeMap<String, Vec<DigestChange>>,
+    areas: BTreeMap<String, Vec<DigestChange>>,
+}
+
+impl Builder {
+    fn add(&mut self, change: DigestChange, paths: &[String]) {
+        self.changes += 1;
+        let areas: HashSet<String> = paths.iter().map(|p| area(p, self.depth)).collect();
+        for area in areas {
+            self.areas.entry(area).or_default().push(change.clone())
+        }
+        self.authors
+            .entry(change.author.clone())
+            .or_default()
+            .push(change);
+    }
+}
+
+fn digest_error(e: impl std::fmt::Display) -> ApiError {
+    ApiError::internal(format!("Failed to compute the digest: {}", e))
+}
+
+/// Compute the digest of `query`. `link_base` is the path of the
+/// repository's endpoints, and `events` the recent events of the server.
+pub fn read_digest(
+    repository: &Repository,
+    query: &DigestQuery,
+    events: &[RecordedEvent],
+    link_base: &str,
+) -> ApiResult<Digest> {
+    let since = Since::parse(&query.since)?;
+    let txn = repository.pristine.txn_begin().map_err(digest_error)?;
+    let channel = crate::server::load_listed_channel(&txn, query.channel.as_deref())?;
+    let channel = channel.read();
+
+    // Position of the `since` state: the digest starts after it.
+    let since_position: Option<u64> = match since {
+        Since::State(state) => Some(
+            txn.channel_has_state(txn.states(&*channel), &state.into())
+                .map_err(digest_error)?
+                .ok_or_else(|| {
+                    ApiError::invalid_query(format!(
+                        "State {} isn't a state of the channel",
+                        query.since
+                    ))
+                })?
+                .into(),
+        ),
+        Since::Time(_) => None,
+    };
+    let mut builder = Builder {
+        limit: query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT),
+        depth: query.depth.unwrap_or(1).clamp(1, MAX_DEPTH),
+        changes: 0,
+        authors: BTreeMap::new(),
+        areas: BTreeMap::new(),
+    };
+    let mut ids = HashSet::new();
+    let mut truncated = false;
+    let mut read = 0;
+    for entry in txn.reverse_log(&*channel, None).map_err(digest_error)? {
+        let (position, (hash, _)) = entry.map_err(digest_error)?;
+        if Some(position) <= since_position {
+            break;
+        }
+        if read >= MAX_CHANGES {
+            truncated = true;
+            break;
+        }
+        read += 1;
+        let hash: libatomic::Hash = hash.into();
+        let Ok(header) = repository.changes.get_header(&hash) else {
+            continue;
+        };
+        if let Since::Time(t) = since {
+            if header.timestamp <= t {
+                continue;
+            }
+        }
+        let paths: Vec<String> = repository
+            .changes
+            .get_change(&hash)
+            .map(|c| c.changes.iter().map(|h| h.path().to_string()).collect())
+            .unwrap_or_default();
+        let hash = hash.to_base32();
+        ids.insert(hash.clone());
+        builder.add(
+            DigestChange {
+                link: format!("{}/code/changes/{}", link_base, hash),
+                hash,
+                message: header.message,
+                author: crate::server::extract_author_name(&header.authors),
+                timestamp: header.timestamp.to_rfc3339(),
+            },
+            &paths,
+        );
+    }
+
+    let mut tags = Vec::new();
+    for entry in txn
+        .rev_iter_tags(txn.tags(&*channel), None)
+        .map_err(digest_error)?
+    {
+        let (position, tag_bytes) = entry.map_err(digest_error)?;
+        let position: u64 = (*position).into();
+        if Some(position) <= since_position || tags.len() >= MAX_TAGS {
+            break;
+        }
+        let Ok(minimal) =
+            libatomic::pristine::SerializedTag::from_bytes_wrapper(tag_bytes).to_tag()
+        else {
+            continue;
+        };
+        let tag = txn
+            .get_tag(&minimal.state)
+            .map_err(digest_error)?
+            .and_then(|t| t.to_tag().ok())
+            .unwrap_or(minimal);
+        if let Since::Time(t) = since {
+            if tag.consolidation_timestamp <= u64::try_from(t.timestamp()).unwrap_or(0) {
+                break;
+            }
+        }
+        let state = tag.state.to_base32();
+        ids.insert(state.clone());
+        tags.push(DigestTag {
+            link: format!("{}/code/tags/{}", link_base, state),
+            state,
+            version: tag.version,
+            timestamp: tag.consolidation_timestamp,
+        });
+    }
+
+    // Events aren't tied to a repository: only the transitions of the
+    // changes and tags of the digest are kept.
+    let mut transitions: Vec<DigestTransition> = events
+        .iter()
+        .filter_map(|e| match e.event {
+            Event::WorkflowTransition {
+                ref workflow,
+                kind,
+                ref change_id,
+                ref from,
+                ref to,
+            } if ids.contains(change_id) => Some(DigestTransition {
+                workflow: workflow.clone(),
+                kind,
+                change_id: change_id.clone(),
+                from: from.clone(),
+                to: to.clone(),
+                timestamp: e.timestamp,
+            }),
+            _ => None,
+        })
+        .collect();
+    transitions.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+
+    let state = libatomic::pristine::current_state(&txn, &*channel).map_err(digest_error)?;
+    Ok(Digest {
+        channel: txn.name(&*channel).to_string(),
+        since: query.since.clone(),
+        state: state.to_base32(),
+        changes: builder.changes,
+        authors: groups(builder.authors, builder.limit),
+        areas: groups(builder.areas, builder.limit),
+        tags,
+        transitions,
+        truncated,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn change(hash: &str, author: &str) -> DigestChange {
+        DigestChange {
+            hash: hash.to_string(),
+            message: String::new(),
+            author: author.to_string(),
+            timestamp: String::new(),
+            link: format!("/code/changes/{}", hash),
+        }
+    }
+
+    #[test]
+    fn test_area() {
+        assert_eq!(area("README.md", 1), "/");
+        assert_eq!(area("src/lib.rs", 1), "src");
+        assert_eq!(area("src/server/http.rs", 1), "src");
+        assert_eq!(area("src/server/http.rs", 2), "src/server");
+        assert_eq!(area("src/server/http.rs", 3), "src/server");
+    }
+
+    #[test]
+    fn test_since() {
+        assert_eq!(
+            Since::parse("1700000000").unwrap(),
+            Since::Time(Utc.timestamp_opt(1_700_000_000, 0).unwrap())
+        );
+        assert_eq!(
+            Since::parse("2023-11-14T22:13:20Z").unwrap(),
+            Since::Time(Utc.timestamp_opt(1_700_000_000, 0).unwrap())
+        );
+        let state = Merkle::zero().to_base32();
+        assert_eq!(Since::parse(&state).unwrap(), Since::State(Merkle::zero()));
+        assert!(Since::parse("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut builder = Builder {
+            limit: 1,
+            depth: 1,
+            changes: 0,
+            authors: BTreeMap::new(),
+            areas: BTreeMap::new(),
+        };
+        builder.add(
+            change("C", "bob"),
+            &["src/a.rs".to_string(), "src/b.rs".to_string()],
+        );
+        builder.add(change("B", "alice"), &["docs/x.md".to_string()]);
+        builder.add(
+            change("A", "alice"),
+            &["src/a.rs".to_string(), "README.md".to_string()],
+        );
+        assert_eq!(builder.changes, 3);
+
+        let authors = groups(builder.authors, builder.limit);
+        let summary: Vec<_> = authors
+            .iter()
+            .map(|g| (g.name.as_str(), g.count, g.changes[0].hash.as_str()))
+            .collect();
+        assert_eq!(summary, vec![("alice", 2, "B"), ("bob", 1, "C")]);
+
+        let areas = groups(builder.areas, builder.limit);
+        let summary: Vec<_> = areas
+            .iter()
+            .map(|g| (g.name.as_str(), g.count, g.changes.len()))
+            .collect();
+        assert_eq!(summary, vec![("src", 2, 1), ("/", 1, 1), ("docs", 1, 1)]);
+    }
+}
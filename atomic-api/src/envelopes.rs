@@ -0,0 +1,160 @@
+//! Encrypted changes of confidential channels
+//!
+//! Changes of `[[confidential]]` channels (see
+//! [`atomic_config::ConfidentialChannel`]) are only exchanged encrypted
+//! for the recipients of the channel, as envelopes (see
+//! [`libatomic::envelope`]). The server can't read them, and doesn't apply
+//! them: it stores each envelope in `.atomic/envelopes/`, named by the
+//! hash of its change, and the hashes of the envelopes of each channel, in
+//! the order they were uploaded, in `.atomic/envelopes.json`. Clients
+//! download the envelopes of a channel, open them and apply the changes
+//! themselves.
+//!
+//! - `POST <protocol>?envelope=<hash>&to_channel=<channel>` uploads an
+//!   envelope,
+//! - `GET <protocol>?envelopes=<channel>` lists the hashes of the
+//!   envelopes of a channel,
+//! - `GET <protocol>?envelope=<hash>` downloads an envelope.
+//!
+//! Changes sent in clear to a channel the configuration of the repository
+//! marks as confidential are rejected, so that the server never stores
+//! them.
+
+use crate::error::RepositoryError;
+use crate::{ApiError, ApiResult};
+use libatomic::envelope::Envelope;
+use libatomic::{Base32, Hash};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Envelopes of a repository, in its `.atomic` directory
+pub const ENVELOPES_DIR: &str = "envelopes";
+
+/// Envelopes of each channel, in the `.atomic` directory of a repository
+pub const ENVELOPES_FILE: &str = "envelopes.json";
+
+/// Whether `channel` is a `[[confidential]]` channel of `config`
+pub fn is_confidential(config: &atomic_config::Config, channel: &str) -> bool {
+    config.confidential.iter().any(|c| c.channel == channel)
+}
+
+/// Reject `change_id`, sent in clear to `channel`, if the channel is
+/// confidential
+pub fn check_clear(
+    config: &atomic_config::Config,
+    channel: &str,
+    change_id: &str,
+) -> ApiResult<()> {
+    if is_confidential(config, channel) {
+        return Err(ApiError::Repository(RepositoryError::Confidential {
+            change_id: change_id.to_string(),
+            channel: channel.to_string(),
+        }));
+    }
+    Ok(())
+}
+
+/// Store `body`, the envelope of change `hash`, in the envelopes of
+/// `channel`. Returns whether the channel didn't have it yet. Envelopes
+/// are only checked to be envelopes of `hash`.
+pub fn store(dot_dir: &Path, channel: &str, hash: &Hash, body: &[u8]) -> ApiResult<bool> {
+    let envelope = Envelope::from_bytes(body)
+        .map_err(|e| ApiError::invalid_query(format!("Invalid envelope: {}", e)))?;
+    if envelope.hash() != Some(*hash) {
+        return Err(ApiError::invalid_query(format!(
+            "Envelope of {} uploaded as {}",
+            envelope.hash,
+            hash.to_base32()
+        )));
+    }
+    let dir = dot_dir.join(ENVELOPES_DIR);
+    std::fs::create_dir_all(&dir)?;
+    let mut tmp = tempfile::NamedTempFile::new_in(&dir)?;
+    std::io::Write::write_all(&mut tmp, body)?;
+    tmp.persist(dir.join(hash.to_base32()))
+        .map_err(|e| e.error)?;
+
+    let mut table = load(dot_dir);
+    let hashes = table.entry(channel.to_string()).or_default();
+    if hashes.contains(&envelope.hash) {
+        return Ok(false);
+    }
+    hashes.push(envelope.hash);
+    let mut tmp = tempfile::NamedTempFile::new_in(dot_dir)?;
+    serde_json::to_writer(&mut tmp, &table).map_err(std::io::Error::from)?;
+    tmp.persist(dot_dir.join(ENVELOPES_FILE))
+        .map_err(|e| e.error)?;
+    Ok(true)
+}
+
+/// The hashes of the envelopes of `channel`, in the order they were
+/// uploaded
+pub fn list(dot_dir: &Path, channel: &str) -> Vec<String> {
+    load(dot_dir).remove(channel).unwrap_or_default()
+}
+
+/// The envelope of change `hash`
+pub fn read(dot_dir: &Path, hash: &Hash) -> ApiResult<Vec<u8>> {
+    match std::fs::read(dot_dir.join(ENVELOPES_DIR).join(hash.to_base32())) {
+        Ok(body) => Ok(body),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Err(ApiError::Repository(RepositoryError::ChangeNotFound {
+                change_id: hash.to_base32(),
+            }))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn load(dot_dir: &Path) -> BTreeMap<String, Vec<String>> {
+    std::fs::read(dot_dir.join(ENVELOPES_FILE))
+        .ok()
+        .and_then(|b| serde_json::from_slice(&b).ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_envelopes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dot_dir = tmp.path();
+        let change: Vec<u8> = (0..=255).collect();
+        let mut hasher = libatomic::pristine::Hasher::default();
+        hasher.update(&change);
+        let hash = hasher.finish();
+        let key = libatomic::key::SKey::generate(None);
+        let body = Envelope::seal(&hash, &change, &[key.pkey()])
+            .unwrap()
+            .to_bytes()
+            .unwrap();
+
+        assert!(store(dot_dir, "embargo", &hash, &body).unwrap());
+        assert!(!store(dot_dir, "embargo", &hash, &body).unwrap());
+        assert_eq!(list(dot_dir, "embargo"), vec![hash.to_base32()]);
+        assert!(list(dot_dir, "main").is_empty());
+        assert_eq!(read(dot_dir, &hash).unwrap(), body);
+
+        // Only envelopes of the hash they are uploaded as are stored.
+        assert!(matches!(
+            store(dot_dir, "embargo", &hash, &change),
+            Err(ApiError::InvalidQuery { .. })
+        ));
+        let mut other = libatomic::pristine::Hasher::default();
+        other.update(b"other");
+        assert!(matches!(
+            store(dot_dir, "embargo", &other.finish(), &body),
+            Err(ApiError::InvalidQuery { .. })
+        ));
+
+        let config: atomic_config::Config =
+            toml::from_str("[[confidential]]\nchannel = \"embargo\"\n").unwrap();
+        assert!(check_clear(&config, "main", "AAAA").is_ok());
+        assert!(matches!(
+            check_clear(&config, "embargo", "AAAA"),
+            Err(ApiError::Repository(RepositoryError::Confidential { .. }))
+        ));
+    }
+}
@@ -3,7 +3,8 @@
 //! Implements a focused error hierarchy using `thiserror` for Atomic VCS API operations
 //! with automatic error conversion and context-rich error messages.
 
-use axum::http::StatusCode;
+use axum::http::header::{RETRY_AFTER, WWW_AUTHENTICATE};
+use axum::http::{HeaderValue, StatusCode};
 use axum::response::{IntoResponse, Response};
 use axum::Json;
 use serde::{Deserialize, Serialize};
@@ -30,6 +31,78 @@ pub enum ApiError {
     /// Internal server errors
     #[error("Internal server error: {message}")]
     Internal { message: String },
+
+    /// The server is too busy to accept the request, e.g. a full apply queue
+    #[error("Server busy: {message}")]
+    Busy { message: String },
+
+    /// Invalid pagination, sorting or field selection parameters
+    #[error("Invalid query: {message}")]
+    InvalidQuery { message: String },
+
+    /// The server is degraded and rejects writes, e.g. its disk is nearly
+    /// full. Clients should retry after `retry_after` seconds.
+    #[error("Server degraded: {message}")]
+    Degraded { message: String, retry_after: u64 },
+
+    /// The server is shutting down and rejects writes, or cancelled an
+    /// apply
+    #[error("Server shutting down: {message}")]
+    ShuttingDown { message: String },
+
+    /// The repository is locked by another process, e.g. a CLI command,
+    /// see [`atomic_repository::lock`]
+    #[error("Repository locked: {message}")]
+    Locked { message: String },
+
+    /// The repository uses more than its storage quota, see
+    /// [`crate::storage`]
+    #[error("Quota exceeded: {message}")]
+    QuotaExceeded { message: String },
+
+    /// A workflow transition was refused, see [`crate::workflow`]
+    #[error("Workflow error: {0}")]
+    Workflow(#[from] atomic_workflows::WorkflowError),
+
+    /// Another instance holds the lease of the repository, and writes to
+    /// it aren't forwarded, see [`crate::lease`]
+    #[error("Not the leader: {message}")]
+    NotLeader {
+        message: String,
+        leader: Option<String>,
+    },
+
+    /// The protocol version of the request isn't served, see
+    /// [`crate::versions`]. Clients should retry at one of `served`.
+    #[error("Unsupported protocol: {message}")]
+    UnsupportedProtocol { message: String, served: Vec<u32> },
+
+    /// The request has no valid credentials, see [`crate::auth`]
+    #[error("Unauthenticated: {message}")]
+    Unauthenticated { message: String },
+
+    /// The credentials of the request don't have the scope of the route,
+    /// see [`crate::auth`]
+    #[error("Insufficient scope: {message}")]
+    InsufficientScope {
+        message: String,
+        scope: crate::auth::Scope,
+    },
+
+    /// The credentials of the request aren't granted for the tenant of
+    /// the route, see [`crate::auth`]
+    #[error("Tenant denied: {message}")]
+    TenantDenied { message: String },
+
+    /// The tenant sent too many requests, see [`crate::limits`]. Clients
+    /// should retry after `retry_after` seconds.
+    #[error("Rate limited: {message}")]
+    RateLimited { message: String, retry_after: u64 },
+
+    /// The body of the request is over `limit` bytes, see
+    /// [`crate::limits`]
+    #[error("Payload too large: {message}")]
+    PayloadTooLarge { message: String, limit: u64 },
 }
 
 /// Repository-specific errors following AGENTS.md error conversion patterns
@@ -52,6 +125,84 @@ pub enum RepositoryError {
 
     #[error("File '{file_path}' not found")]
     FileNotFound { file_path: String },
+
+    /// Dependencies of a change that are neither changes of the channel
+    /// nor tags known to the server
+    #[error("Change '{change_id}' has missing dependencies: {}", dependencies.join(", "))]
+    MissingDependencies {
+        change_id: String,
+        dependencies: Vec<String>,
+    },
+
+    /// Paths added or edited by a change and forbidden by the `[[forbid]]`
+    /// rules of the repository
+    #[error(
+        "Change '{change_id}' touches forbidden paths: {}",
+        violations.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    ForbiddenPaths {
+        change_id: String,
+        violations: Vec<libatomic::forbidden::Violation>,
+    },
+
+    /// The suggestion of a review comment no longer applies to the file
+    /// on the review channel
+    #[error("Suggestion of comment {comment_id} on '{change_id}' no longer applies to {path}")]
+    StaleSuggestion {
+        change_id: String,
+        comment_id: u64,
+        path: String,
+    },
+
+    /// A change entering a channel protected by a `[[protect]]` rule isn't
+    /// in the state the rule requires
+    #[error(
+        "Change '{change_id}' is {state} in workflow {workflow}, channel '{channel}' only takes {required} changes"
+    )]
+    NotApproved {
+        change_id: String,
+        channel: String,
+        workflow: String,
+        state: String,
+        required: String,
+    },
+
+    /// A change sent in clear to a `[[confidential]]` channel, whose
+    /// changes are only exchanged encrypted, see [`crate::envelopes`]
+    #[error(
+        "Channel '{channel}' only takes changes encrypted for its recipients, not '{change_id}'"
+    )]
+    Confidential { change_id: String, channel: String },
+
+    /// The message of a change doesn't follow the `[message_policy]` of
+    /// the repository, see [`crate::validate`]
+    #[error("Change '{change_id}' breaks the message policy: {reason}")]
+    MessagePolicy { change_id: String, reason: String },
+
+    /// A prefix of change hash matching several changes, see
+    /// [`crate::resolve`]
+    #[error(
+        "Prefix '{prefix}' matches several changes: {}",
+        candidates.join(", ")
+    )]
+    AmbiguousPrefix {
+        prefix: String,
+        candidates: Vec<String>,
+    },
+
+    /// The signature of an uploaded tag doesn't match its state and
+    /// header
+    #[error("Invalid signature of tag '{state}': {reason}")]
+    InvalidTagSignature { state: String, reason: String },
+
+    /// An uploaded tag is signed by a key the repository doesn't know
+    #[error("Tag '{state}' is signed by unknown key {key}")]
+    UntrustedTagSigner { state: String, key: String },
+
+    /// A tag was uploaded for a state that is already tagged: tags are
+    /// immutable
+    #[error("State '{state}' is already tagged")]
+    TagExists { state: String },
 }
 
 /// Error response format for JSON API responses
@@ -108,6 +259,66 @@ impl IntoResponse for ApiError {
                     err.to_string(),
                     "REPO_005".to_string(),
                 ),
+                RepositoryError::MissingDependencies { .. } => (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "missing_dependencies",
+                    err.to_string(),
+                    "REPO_006".to_string(),
+                ),
+                RepositoryError::ForbiddenPaths { .. } => (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "forbidden_paths",
+                    err.to_string(),
+                    "REPO_007".to_string(),
+                ),
+                RepositoryError::StaleSuggestion { .. } => (
+                    StatusCode::CONFLICT,
+                    "stale_suggestion",
+                    err.to_string(),
+                    "REPO_008".to_string(),
+                ),
+                RepositoryError::NotApproved { .. } => (
+                    StatusCode::FORBIDDEN,
+                    "not_approved",
+                    err.to_string(),
+                    "REPO_009".to_string(),
+                ),
+                RepositoryError::Confidential { .. } => (
+                    StatusCode::FORBIDDEN,
+                    "confidential_channel",
+                    err.to_string(),
+                    "REPO_010".to_string(),
+                ),
+                RepositoryError::MessagePolicy { .. } => (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "message_policy",
+                    err.to_string(),
+                    "REPO_011".to_string(),
+                ),
+                RepositoryError::AmbiguousPrefix { .. } => (
+                    StatusCode::CONFLICT,
+                    "ambiguous_prefix",
+                    err.to_string(),
+                    "REPO_012".to_string(),
+                ),
+                RepositoryError::InvalidTagSignature { .. } => (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "invalid_tag_signature",
+                    err.to_string(),
+                    "REPO_013".to_string(),
+                ),
+                RepositoryError::UntrustedTagSigner { .. } => (
+                    StatusCode::FORBIDDEN,
+                    "untrusted_tag_signer",
+                    err.to_string(),
+                    "REPO_014".to_string(),
+                ),
+                RepositoryError::TagExists { .. } => (
+                    StatusCode::CONFLICT,
+                    "tag_exists",
+                    err.to_string(),
+                    "REPO_015".to_string(),
+                ),
                 _ => (
                     StatusCode::INTERNAL_SERVER_ERROR,
                     "repository_error",
@@ -133,10 +344,136 @@ impl IntoResponse for ApiError {
                 message.clone(),
                 "INT_001".to_string(),
             ),
+            ApiError::Busy { message } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server_busy",
+                message.clone(),
+                "BUSY_001".to_string(),
+            ),
+            ApiError::InvalidQuery { message } => (
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+                message.clone(),
+                "QUERY_001".to_string(),
+            ),
+            ApiError::Degraded { message, .. } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server_degraded",
+                message.clone(),
+                "DEGRADED_001".to_string(),
+            ),
+            ApiError::ShuttingDown { message } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server_shutting_down",
+                message.clone(),
+                "SHUTDOWN_001".to_string(),
+            ),
+            ApiError::Locked { message } => (
+                StatusCode::CONFLICT,
+                "repository_locked",
+                message.clone(),
+                "LOCK_001".to_string(),
+            ),
+            ApiError::QuotaExceeded { message } => (
+                StatusCode::INSUFFICIENT_STORAGE,
+                "quota_exceeded",
+                message.clone(),
+                "QUOTA_001".to_string(),
+            ),
+            ApiError::Workflow(err) => match err {
+                atomic_workflows::WorkflowError::NeedRole(_) => (
+                    StatusCode::FORBIDDEN,
+                    "workflow_role_required",
+                    err.to_string(),
+                    "WORKFLOW_001".to_string(),
+                ),
+                _ => (
+                    StatusCode::CONFLICT,
+                    "workflow_transition_refused",
+                    err.to_string(),
+                    "WORKFLOW_002".to_string(),
+                ),
+            },
+            ApiError::NotLeader { message, .. } => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "not_leader",
+                message.clone(),
+                "LEASE_001".to_string(),
+            ),
+            ApiError::UnsupportedProtocol { message, .. } => (
+                StatusCode::BAD_REQUEST,
+                "unsupported_protocol_version",
+                message.clone(),
+                "PROTOCOL_001".to_string(),
+            ),
+            ApiError::Unauthenticated { message } => (
+                StatusCode::UNAUTHORIZED,
+                "unauthenticated",
+                message.clone(),
+                "AUTH_001".to_string(),
+            ),
+            ApiError::InsufficientScope { message, .. } => (
+                StatusCode::FORBIDDEN,
+                "insufficient_scope",
+                message.clone(),
+                "AUTH_002".to_string(),
+            ),
+            ApiError::TenantDenied { message } => (
+                StatusCode::FORBIDDEN,
+                "tenant_denied",
+                message.clone(),
+                "AUTH_003".to_string(),
+            ),
+            ApiError::RateLimited { message, .. } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+                message.clone(),
+                "LIMIT_001".to_string(),
+            ),
+            ApiError::PayloadTooLarge { message, .. } => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+                message.clone(),
+                "LIMIT_002".to_string(),
+            ),
         };
 
         let error_response = ErrorResponse::new(error_type, message, code);
-        (status, Json(error_response)).into_response()
+        let mut response = (status, Json(error_response)).into_response();
+        if let ApiError::Degraded { retry_after, .. } | ApiError::RateLimited { retry_after, .. } =
+            self
+        {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        } else if let ApiError::NotLeader {
+            leader: Some(leader),
+            ..
+        } = self
+        {
+            if let Ok(value) = HeaderValue::from_str(&leader) {
+                response
+                    .headers_mut()
+                    .insert(crate::lease::LEADER_HEADER, value);
+            }
+        } else if let ApiError::UnsupportedProtocol { served, .. } = self {
+            let served: Vec<_> = served.iter().map(|v| v.to_string()).collect();
+            if let Ok(value) = HeaderValue::from_str(&served.join(",")) {
+                response
+                    .headers_mut()
+                    .insert(crate::versions::VERSIONS_HEADER, value);
+            }
+        } else if let ApiError::Unauthenticated { .. } = self {
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        } else if let ApiError::InsufficientScope { scope, .. } = self {
+            let challenge = format!("Bearer error=\"insufficient_scope\", scope=\"{}\"", scope);
+            if let Ok(value) = HeaderValue::from_str(&challenge) {
+                response.headers_mut().insert(WWW_AUTHENTICATE, value);
+            }
+        }
+        response
     }
 }
 
@@ -162,6 +499,106 @@ impl ApiError {
             message: message.into(),
         }
     }
+
+    /// Create a busy error, for requests that should be retried later
+    pub fn busy(message: impl Into<String>) -> Self {
+        ApiError::Busy {
+            message: message.into(),
+        }
+    }
+
+    /// Create an error for writes rejected in degraded mode
+    pub fn degraded(message: impl Into<String>, retry_after: u64) -> Self {
+        ApiError::Degraded {
+            message: message.into(),
+            retry_after,
+        }
+    }
+
+    /// Create an error for writes rejected, or applies cancelled, by a
+    /// shutdown
+    pub fn shutting_down(message: impl Into<String>) -> Self {
+        ApiError::ShuttingDown {
+            message: message.into(),
+        }
+    }
+
+    /// Create an error for a repository locked by another process
+    pub fn locked(message: impl Into<String>) -> Self {
+        ApiError::Locked {
+            message: message.into(),
+        }
+    }
+
+    /// Create an error for writes to a repository over its storage quota
+    pub fn quota_exceeded(message: impl Into<String>) -> Self {
+        ApiError::QuotaExceeded {
+            message: message.into(),
+        }
+    }
+
+    /// Create an error for a write to a repository leased by another
+    /// instance, at `leader` if it advertises a URL
+    pub fn not_leader(message: impl Into<String>, leader: Option<String>) -> Self {
+        ApiError::NotLeader {
+            message: message.into(),
+            leader,
+        }
+    }
+
+    /// Create an error for a protocol version that isn't served, listing
+    /// the versions that are
+    pub fn unsupported_protocol(message: impl Into<String>, served: Vec<u32>) -> Self {
+        ApiError::UnsupportedProtocol {
+            message: message.into(),
+            served,
+        }
+    }
+
+    /// Create an error for a request without valid credentials
+    pub fn unauthenticated(message: impl Into<String>) -> Self {
+        ApiError::Unauthenticated {
+            message: message.into(),
+        }
+    }
+
+    /// Create an error for credentials lacking the scope of a route
+    pub fn insufficient_scope(message: impl Into<String>, scope: crate::auth::Scope) -> Self {
+        ApiError::InsufficientScope {
+            message: message.into(),
+            scope,
+        }
+    }
+
+    /// Create an error for credentials used on another tenant
+    pub fn tenant_denied(message: impl Into<String>) -> Self {
+        ApiError::TenantDenied {
+            message: message.into(),
+        }
+    }
+
+    /// Create an error for a tenant over its rate limit
+    pub fn rate_limited(message: impl Into<String>, retry_after: u64) -> Self {
+        ApiError::RateLimited {
+            message: message.into(),
+            retry_after,
+        }
+    }
+
+    /// Create an error for a body over `limit` bytes
+    pub fn payload_too_large(message: impl Into<String>, limit: u64) -> Self {
+        ApiError::PayloadTooLarge {
+            message: message.into(),
+            limit,
+        }
+    }
+
+    /// Create an error for invalid list query parameters
+    pub fn invalid_query(message: impl Into<String>) -> Self {
+        ApiError::InvalidQuery {
+            message: message.into(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -199,4 +636,162 @@ mod tests {
         // Test that the error can be converted to a response
         let _response = api_err.into_response();
     }
+
+    #[test]
+    fn test_missing_dependencies_response() {
+        let err = ApiError::Repository(RepositoryError::MissingDependencies {
+            change_id: "CHANGE".to_string(),
+            dependencies: vec!["DEP1".to_string(), "DEP2".to_string()],
+        });
+        assert_eq!(
+            err.to_string(),
+            "Repository error: Change 'CHANGE' has missing dependencies: DEP1, DEP2"
+        );
+        assert_eq!(
+            err.into_response().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[test]
+    fn test_forbidden_paths_error() {
+        let err = ApiError::Repository(RepositoryError::ForbiddenPaths {
+            change_id: "CHANGE".to_string(),
+            violations: vec![libatomic::forbidden::Violation {
+                path: "target/debug/app".to_string(),
+                pattern: "target".to_string(),
+                reason: Some("build artifacts".to_string()),
+            }],
+        });
+        assert_eq!(
+            err.to_string(),
+            "Repository error: Change 'CHANGE' touches forbidden paths: \
+             target/debug/app (forbidden by \"target\": build artifacts)"
+        );
+        assert_eq!(
+            err.into_response().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[test]
+    fn test_message_policy_response() {
+        let err = ApiError::Repository(RepositoryError::MessagePolicy {
+            change_id: "CHANGE".to_string(),
+            reason: "message is 80 characters long, at most 72 are allowed".to_string(),
+        });
+        assert_eq!(
+            err.to_string(),
+            "Repository error: Change 'CHANGE' breaks the message policy: \
+             message is 80 characters long, at most 72 are allowed"
+        );
+        assert_eq!(
+            err.into_response().status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+    }
+
+    #[test]
+    fn test_ambiguous_prefix_response() {
+        let err = ApiError::Repository(RepositoryError::AmbiguousPrefix {
+            prefix: "ABC".to_string(),
+            candidates: vec!["ABCD".to_string(), "ABCE".to_string()],
+        });
+        assert_eq!(
+            err.to_string(),
+            "Repository error: Prefix 'ABC' matches several changes: ABCD, ABCE"
+        );
+        assert_eq!(err.into_response().status(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn test_stale_suggestion_response() {
+        let err = ApiError::Repository(RepositoryError::StaleSuggestion {
+            change_id: "CHANGE".to_string(),
+            comment_id: 3,
+            path: "src/lib.rs".to_string(),
+        });
+        assert_eq!(
+            err.to_string(),
+            "Repository error: Suggestion of comment 3 on 'CHANGE' no longer applies to src/lib.rs"
+        );
+        assert_eq!(err.into_response().status(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn test_degraded_response() {
+        let response = ApiError::degraded("Disk full", 30).into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "30");
+    }
+
+    #[test]
+    fn test_locked_response() {
+        let response = ApiError::locked("Repository locked by PID 42 (record)").into_response();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn test_not_leader_response() {
+        let response =
+            ApiError::not_leader("Leased by api-2", Some("http://api-2:8080".to_string()))
+                .into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers()[crate::lease::LEADER_HEADER],
+            "http://api-2:8080"
+        );
+    }
+
+    #[test]
+    fn test_quota_exceeded_response() {
+        let response = ApiError::quota_exceeded("Over quota").into_response();
+        assert_eq!(response.status(), StatusCode::INSUFFICIENT_STORAGE);
+    }
+
+    #[test]
+    fn test_workflow_responses() {
+        let err = ApiError::from(atomic_workflows::WorkflowError::NeedRole(
+            "reviewer".to_string(),
+        ));
+        assert_eq!(err.into_response().status(), StatusCode::FORBIDDEN);
+        let err = ApiError::from(atomic_workflows::WorkflowError::UnknownTrigger {
+            state: "Approved".to_string(),
+            trigger: "submit".to_string(),
+        });
+        assert_eq!(err.into_response().status(), StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn test_auth_responses() {
+        let response = ApiError::unauthenticated("Missing credentials").into_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer");
+        let response =
+            ApiError::insufficient_scope("ci lacks admin:tags", crate::auth::Scope::AdminTags)
+                .into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.headers()[WWW_AUTHENTICATE],
+            "Bearer error=\"insufficient_scope\", scope=\"admin:tags\""
+        );
+        let response = ApiError::tenant_denied("ci isn't granted tenant-2").into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_limit_responses() {
+        let response = ApiError::rate_limited("Too many requests", 2).into_response();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "2");
+        let response = ApiError::payload_too_large("Too large", 10).into_response();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn test_shutting_down_response() {
+        let response = ApiError::shutting_down("Draining").into_response();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().get(RETRY_AFTER).is_none());
+    }
 }
@@ -0,0 +1,191 @@
+//! Recent events of the event bus, served by `GET /events`
+//!
+//! The server subscribes to the global bus of `atomic_config::events` and
+//! keeps the last events in memory, numbered in the order they were
+//! published. Older events are dropped once the buffer is full.
+//!
+//! Recorded events are also broadcast to the followers of the log, the
+//! Server-Sent Events streams of [`crate::activity`], which resume from
+//! the buffer after a disconnection.
+
+use atomic_config::events::{Event, EventBus};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+/// Number of events kept when `ATOMIC_API_EVENT_BUFFER` isn't set
+const DEFAULT_CAPACITY: usize = 1000;
+/// Events broadcast and not yet received by the slowest follower, after
+/// which it lags and reads the buffer instead
+const FOLLOWER_CAPACITY: usize = 256;
+
+/// An event, with its sequence number and reception time
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordedEvent {
+    pub seq: u64,
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Default)]
+struct Buffer {
+    next_seq: u64,
+    events: VecDeque<RecordedEvent>,
+}
+
+/// The last events published on a bus
+#[derive(Clone)]
+pub struct EventLog {
+    capacity: usize,
+    buffer: Arc<Mutex<Buffer>>,
+    followers: broadcast::Sender<RecordedEvent>,
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        EventLog {
+            capacity,
+            buffer: Arc::default(),
+            followers: broadcast::channel(FOLLOWER_CAPACITY).0,
+        }
+    }
+
+    /// Read the capacity from `ATOMIC_API_EVENT_BUFFER`
+    pub fn from_env() -> Self {
+        Self::new(
+            std::env::var("ATOMIC_API_EVENT_BUFFER")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(DEFAULT_CAPACITY),
+        )
+    }
+
+    /// Record the events published on `bus` from now on
+    pub fn subscribe(&self, bus: &EventBus) {
+        let log = self.clone();
+        bus.subscribe(move |event| log.record(event.clone()));
+    }
+
+    pub fn record(&self, event: Event) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.next_seq += 1;
+        let seq = buffer.next_seq;
+        let recorded = RecordedEvent {
+            seq,
+            timestamp: Utc::now(),
+            event,
+        };
+        // Sent with the buffer locked, so that followers receive the
+        // events in order. Sending only fails without followers.
+        self.followers.send(recorded.clone()).unwrap_or(0);
+        buffer.events.push_back(recorded);
+        while buffer.events.len() > self.capacity {
+            buffer.events.pop_front();
+        }
+    }
+
+    /// The recorded events, newest first
+    pub fn recent(&self) -> Vec<RecordedEvent> {
+        let buffer = self.buffer.lock().unwrap();
+        buffer.events.iter().rev().cloned().collect()
+    }
+
+    /// The recorded events after `seq`, oldest first
+    pub fn since(&self, seq: u64) -> Vec<RecordedEvent> {
+        let buffer = self.buffer.lock().unwrap();
+        buffer
+            .events
+            .iter()
+            .filter(|e| e.seq > seq)
+            .cloned()
+            .collect()
+    }
+
+    /// Receive the events recorded from now on
+    pub fn follow(&self) -> broadcast::Receiver<RecordedEvent> {
+        self.followers.subscribe()
+    }
+}
+
+/// Name of `event`, its `event` field once serialized
+pub fn name(event: &Event) -> String {
+    serde_json::to_value(event)
+        .ok()
+        .and_then(|v| v.get("event")?.as_str().map(String::from))
+        .unwrap_or_default()
+}
+
+/// The repository of `event`, if it is about one
+pub fn repository(event: &Event) -> Option<&Path> {
+    match event {
+        Event::NodeApplied { repository, .. }
+        | Event::TagCreated { repository, .. }
+        | Event::RemoteDiverged { repository, .. } => Some(repository),
+        Event::WorkflowTransition { .. } => None,
+    }
+}
+
+/// `event`, with its repository relative to `base`, the path clients
+/// know it by (`tenant/portfolio/project`)
+pub fn relative(mut event: Event, base: &Path) -> Event {
+    match &mut event {
+        Event::NodeApplied { repository, .. }
+        | Event::TagCreated { repository, .. }
+        | Event::RemoteDiverged { repository, .. } => {
+            if let Ok(path) = repository.strip_prefix(base) {
+                *repository = path.to_path_buf()
+            }
+        }
+        Event::WorkflowTransition { .. } => {}
+    }
+    event
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_log_keeps_last_events() {
+        let bus = EventBus::new();
+        let log = EventLog::new(2);
+        log.subscribe(&bus);
+        for state in ["A", "B", "C"] {
+            bus.publish(Event::TagCreated {
+                repository: "/repo".into(),
+                channel: "main".to_string(),
+                state: state.to_string(),
+            });
+        }
+        let seqs: Vec<_> = log.recent().iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![3, 2]);
+        let seqs: Vec<_> = log.since(2).iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, vec![3]);
+    }
+
+    #[test]
+    fn test_event_log_followers() {
+        let log = EventLog::new(10);
+        let mut follower = log.follow();
+        log.record(Event::TagCreated {
+            repository: "/mnt/t/p/x".into(),
+            channel: "main".to_string(),
+            state: "A".to_string(),
+        });
+        let recorded = follower.try_recv().unwrap();
+        assert_eq!(recorded.seq, 1);
+        assert_eq!(repository(&recorded.event), Some(Path::new("/mnt/t/p/x")));
+        let event = relative(recorded.event, Path::new("/mnt"));
+        assert_eq!(repository(&event), Some(Path::new("t/p/x")));
+    }
+}
@@ -0,0 +1,245 @@
+//! What the server exposes, and to whom
+//!
+//! The server used to answer cross-origin requests from any origin, and
+//! to serve all its endpoints. Deployments now choose:
+//!
+//! - the origins, methods and headers allowed by CORS
+//!   (`ATOMIC_API_CORS_ORIGINS`, `ATOMIC_API_CORS_METHODS`,
+//!   `ATOMIC_API_CORS_HEADERS`, comma-separated, and
+//!   `ATOMIC_API_CORS_MAX_AGE` in seconds). What isn't configured, or is
+//!   `*`, is allowed from anywhere, as before;
+//! - whether responses carry the standard security headers
+//!   (`ATOMIC_API_SECURITY_HEADERS`, on by default), with
+//!   `Strict-Transport-Security` when the server terminates TLS;
+//! - the groups of routes not served at all (`ATOMIC_API_DISABLED_ROUTES`,
+//!   comma-separated [`RouteGroup`] names), e.g. `admin` on an instance
+//!   exposing the protocol endpoints to the internet. `/health` is always
+//!   served.
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{header, HeaderName, HeaderValue, Method, Response},
+    middleware::Next,
+};
+use std::collections::BTreeSet;
+use std::str::FromStr;
+use std::time::Duration;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer, ExposeHeaders};
+use tracing::warn;
+
+/// Groups of routes, enabled or disabled together
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RouteGroup {
+    /// The Atomic protocol, clones, pushes, uploads and the status of
+    /// their operations
+    Protocol,
+    /// Changes, channels, tags, states, digests, attribution, provenance,
+    /// conflicts, saved filters, content search and activity streams
+    Changes,
+    /// Notes, issue links, review comments, workflows, review sandboxes
+    /// and email submissions
+    Collaboration,
+    /// Metrics, events, maintenance, storage, tenant usage and
+    /// configuration of repositories
+    Admin,
+}
+
+impl RouteGroup {
+    pub const ALL: [RouteGroup; 4] = [
+        RouteGroup::Protocol,
+        RouteGroup::Changes,
+        RouteGroup::Collaboration,
+        RouteGroup::Admin,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RouteGroup::Protocol => "protocol",
+            RouteGroup::Changes => "changes",
+            RouteGroup::Collaboration => "collaboration",
+            RouteGroup::Admin => "admin",
+        }
+    }
+}
+
+impl std::fmt::Display for RouteGroup {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for RouteGroup {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        RouteGroup::ALL
+            .into_iter()
+            .find(|g| g.name() == s)
+            .ok_or_else(|| format!("Unknown route group: {}", s))
+    }
+}
+
+/// What CORS allows. `None` allows anything.
+#[derive(Debug, Clone, Default)]
+pub struct CorsConfig {
+    pub origins: Option<Vec<HeaderValue>>,
+    pub methods: Option<Vec<Method>>,
+    pub headers: Option<Vec<HeaderName>>,
+    /// How long browsers may cache the answers to preflight requests
+    pub max_age: Option<Duration>,
+}
+
+impl CorsConfig {
+    pub fn layer(&self) -> CorsLayer {
+        let layer = CorsLayer::new()
+            .allow_origin(match self.origins {
+                Some(ref origins) => AllowOrigin::list(origins.iter().cloned()),
+                None => AllowOrigin::any(),
+            })
+            .allow_methods(match self.methods {
+                Some(ref methods) => AllowMethods::list(methods.iter().cloned()),
+                None => AllowMethods::any(),
+            })
+            .allow_headers(match self.headers {
+                Some(ref headers) => AllowHeaders::list(headers.iter().cloned()),
+                None => AllowHeaders::any(),
+            })
+            .expose_headers(ExposeHeaders::any());
+        match self.max_age {
+            Some(max_age) => layer.max_age(max_age),
+            None => layer,
+        }
+    }
+}
+
+/// What the server exposes
+#[derive(Debug, Clone)]
+pub struct ExposureConfig {
+    pub cors: CorsConfig,
+    /// Add the standard security headers to responses
+    pub security_headers: bool,
+    /// Route groups not served
+    pub disabled: BTreeSet<RouteGroup>,
+}
+
+impl Default for ExposureConfig {
+    fn default() -> Self {
+        ExposureConfig {
+            cors: CorsConfig::default(),
+            security_headers: true,
+            disabled: BTreeSet::new(),
+        }
+    }
+}
+
+impl ExposureConfig {
+    /// Read the configuration from the environment, see the module
+    /// documentation. Invalid entries are skipped with a warning.
+    pub fn from_env() -> Self {
+        let var = |name: &str| std::env::var(name).ok().filter(|s| !s.trim().is_empty());
+        ExposureConfig {
+            cors: CorsConfig {
+                origins: allowed("ATOMIC_API_CORS_ORIGINS"),
+                methods: allowed("ATOMIC_API_CORS_METHODS"),
+                headers: allowed("ATOMIC_API_CORS_HEADERS"),
+                max_age: var("ATOMIC_API_CORS_MAX_AGE")
+                    .and_then(|s| s.trim().parse().ok())
+                    .map(Duration::from_secs),
+            },
+            security_headers: !var("ATOMIC_API_SECURITY_HEADERS")
+                .is_some_and(|s| matches!(s.trim(), "0" | "false" | "off")),
+            disabled: var("ATOMIC_API_DISABLED_ROUTES")
+                .map(|s| parse_list("ATOMIC_API_DISABLED_ROUTES", &s))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether the routes of `group` are served
+    pub fn serves(&self, group: RouteGroup) -> bool {
+        !self.disabled.contains(&group)
+    }
+}
+
+/// The items allowed by variable `var`, `None` if it isn't set or allows
+/// `*`
+fn allowed<T: FromStr>(var: &str) -> Option<Vec<T>> {
+    let value = std::env::var(var).ok()?;
+    if value.trim().is_empty() || value.split(',').any(|item| item.trim() == "*") {
+        return None;
+    }
+    Some(parse_list(var, &value))
+}
+
+/// Parse the comma-separated items of `value`, the value of variable
+/// `var`
+fn parse_list<T: FromStr, C: FromIterator<T>>(var: &str, value: &str) -> C {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .filter_map(|item| match item.parse() {
+            Ok(item) => Some(item),
+            Err(_) => {
+                warn!("Ignoring invalid {} entry: {:?}", var, item);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Add the standard security headers to responses that don't set them.
+/// The state is whether the server terminates TLS, in which case
+/// `Strict-Transport-Security` is added too.
+pub async fn add_security_headers(
+    State(tls): State<bool>,
+    request: Request,
+    next: Next,
+) -> Response<Body> {
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    let mut set = |name: HeaderName, value: &'static str| {
+        headers
+            .entry(name)
+            .or_insert(HeaderValue::from_static(value));
+    };
+    set(header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+    set(header::X_FRAME_OPTIONS, "DENY");
+    set(header::REFERRER_POLICY, "no-referrer");
+    set(
+        header::CONTENT_SECURITY_POLICY,
+        "default-src 'none'; frame-ancestors 'none'",
+    );
+    if tls {
+        set(
+            header::STRICT_TRANSPORT_SECURITY,
+            "max-age=31536000; includeSubDomains",
+        );
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lists() {
+        let groups: BTreeSet<RouteGroup> = parse_list("VAR", "admin, collaboration,,unknown");
+        assert_eq!(
+            groups.into_iter().collect::<Vec<_>>(),
+            vec![RouteGroup::Collaboration, RouteGroup::Admin]
+        );
+        let methods: Vec<Method> = parse_list("VAR", "GET,POST");
+        assert_eq!(methods, vec![Method::GET, Method::POST]);
+        let origins: Vec<HeaderValue> =
+            parse_list("VAR", "https://app.example.com, https://admin.example.com");
+        assert_eq!(origins[1], "https://admin.example.com");
+
+        let config = ExposureConfig {
+            disabled: [RouteGroup::Admin].into_iter().collect(),
+            ..ExposureConfig::default()
+        };
+        assert!(config.serves(RouteGroup::Protocol));
+        assert!(!config.serves(RouteGroup::Admin));
+    }
+}
@@ -0,0 +1,265 @@
+//! Saved filters of the changes API
+//!
+//! Teams filter changes by the same criteria over and over: the changes of
+//! an author group, the ones touching an area of the tree, the ones with a
+//! label. A [`SavedFilter`] names such a [`FilterDefinition`], and the
+//! changes and search endpoints expand `?filter=<name>` server-side.
+//!
+//! Filters are stored per repository in `.atomic/filters`, a JSON file.
+//! Within a criterion, any value matches; across criteria, all of them
+//! must match. Labels are matched in the message, description and note of
+//! changes, since changes have no labels of their own.
+
+use crate::{ApiError, ApiResult};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// File of the saved filters, in the `.atomic` directory of a repository
+pub const FILTERS_FILE: &str = "filters";
+
+/// Criteria of a filter. Empty criteria match every change.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilterDefinition {
+    /// Changes by any of these authors, matched case-insensitively in the
+    /// author's name, username or email
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub authors: Vec<String>,
+    /// Changes touching files under any of these paths
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paths: Vec<String>,
+    /// Changes mentioning any of these labels in their message,
+    /// description or note, case-insensitively
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+}
+
+/// A named filter
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedFilter {
+    pub name: String,
+    #[serde(flatten)]
+    pub definition: FilterDefinition,
+    /// Time of the last edit
+    pub updated: DateTime<Utc>,
+}
+
+/// What a filter looks at in a change
+#[derive(Debug, Default)]
+pub struct Candidate<'a> {
+    pub author: &'a str,
+    pub message: &'a str,
+    pub description: Option<&'a str>,
+    pub note: Option<&'a str>,
+    /// Files touched by the change, only needed if [`FilterDefinition::paths`]
+    /// isn't empty
+    pub paths: &'a [String],
+}
+
+impl FilterDefinition {
+    pub fn is_empty(&self) -> bool {
+        self.authors.is_empty() && self.paths.is_empty() && self.labels.is_empty()
+    }
+
+    /// Whether the touched files of changes are needed to match them
+    pub fn needs_paths(&self) -> bool {
+        !self.paths.is_empty()
+    }
+
+    /// Whether the notes of changes are needed to match them
+    pub fn needs_notes(&self) -> bool {
+        !self.labels.is_empty()
+    }
+
+    /// Whether `path` is under one of the paths of this filter, or this
+    /// filter has no paths
+    pub fn matches_path(&self, path: &str) -> bool {
+        self.paths.is_empty() || self.paths.iter().any(|prefix| is_under(path, prefix))
+    }
+
+    pub fn matches(&self, candidate: &Candidate) -> bool {
+        let author = candidate.author.to_lowercase();
+        let authors = self.authors.is_empty()
+            || self
+                .authors
+                .iter()
+                .any(|a| author.contains(&a.to_lowercase()));
+        let paths = self.paths.is_empty() || candidate.paths.iter().any(|p| self.matches_path(p));
+        let labels = self.labels.is_empty() || {
+            let text = [
+                Some(candidate.message),
+                candidate.description,
+                candidate.note,
+            ]
+            .iter()
+            .flatten()
+            .map(|t| t.to_lowercase())
+            .collect::<Vec<_>>();
+            self.labels.iter().any(|l| {
+                let l = l.to_lowercase();
+                text.iter().any(|t| t.contains(&l))
+            })
+        };
+        authors && paths && labels
+    }
+}
+
+/// Whether `path` is `prefix` or a file under it
+fn is_under(path: &str, prefix: &str) -> bool {
+    let path = path.trim_start_matches('/');
+    let prefix = prefix.trim_matches('/');
+    prefix.is_empty()
+        || path
+            .strip_prefix(prefix)
+            .map_or(false, |rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+fn filters_error(e: impl std::fmt::Display) -> ApiError {
+    ApiError::internal(format!("Failed to access saved filters: {}", e))
+}
+
+/// The saved filters of a repository
+#[derive(Debug, Clone)]
+pub struct SavedFilters {
+    path: PathBuf,
+}
+
+impl SavedFilters {
+    /// Filters stored in `path` (usually `.atomic/filters`)
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        SavedFilters { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// All the filters, by name
+    pub fn list(&self) -> ApiResult<Vec<SavedFilter>> {
+        match std::fs::read(&self.path) {
+            Ok(buf) => serde_json::from_slice(&buf).map_err(filters_error),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(filters_error(e)),
+        }
+    }
+
+    fn write(&self, filters: &[SavedFilter]) -> ApiResult<()> {
+        if filters.is_empty() {
+            return match std::fs::remove_file(&self.path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(filters_error(e)),
+                _ => Ok(()),
+            };
+        }
+        let tmp = self.path.with_extension("tmp");
+        let buf = serde_json::to_vec_pretty(filters).map_err(filters_error)?;
+        std::fs::write(&tmp, buf)
+            .and_then(|_| std::fs::rename(&tmp, &self.path))
+            .map_err(filters_error)
+    }
+
+    pub fn get(&self, name: &str) -> ApiResult<Option<SavedFilter>> {
+        Ok(self.list()?.into_iter().find(|f| f.name == name))
+    }
+
+    /// The definition of the filter `name`, for `?filter=<name>`
+    pub fn expand(&self, name: &str) -> ApiResult<FilterDefinition> {
+        self.get(name)?
+            .map(|f| f.definition)
+            .ok_or_else(|| ApiError::invalid_query(format!("Unknown filter {:?}", name)))
+    }
+
+    /// Save `definition` as `name`, replacing the previous filter of that
+    /// name
+    pub fn put(&self, name: &str, definition: FilterDefinition) -> ApiResult<SavedFilter> {
+        if definition.is_empty() {
+            return Err(ApiError::invalid_query(
+                "A filter needs authors, paths or labels".to_string(),
+            ));
+        }
+        let filter = SavedFilter {
+            name: name.to_string(),
+            definition,
+            updated: Utc::now(),
+        };
+        let mut filters = self.list()?;
+        filters.retain(|f| f.name != name);
+        filters.push(filter.clone());
+        filters.sort_by(|a, b| a.name.cmp(&b.name));
+        self.write(&filters)?;
+        Ok(filter)
+    }
+
+    /// Delete the filter `name`. Returns `false` if there was no such
+    /// filter.
+    pub fn remove(&self, name: &str) -> ApiResult<bool> {
+        let mut filters = self.list()?;
+        let len = filters.len();
+        filters.retain(|f| f.name != name);
+        if filters.len() == len {
+            return Ok(false);
+        }
+        self.write(&filters)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definition(authors: &[&str], paths: &[&str], labels: &[&str]) -> FilterDefinition {
+        let strings = |v: &[&str]| -> Vec<String> { v.iter().map(|s| s.to_string()).collect() };
+        FilterDefinition {
+            authors: strings(authors),
+            paths: strings(paths),
+            labels: strings(labels),
+        }
+    }
+
+    #[test]
+    fn test_matches() {
+        let paths = vec!["src/api/server.rs".to_string(), "README.md".to_string()];
+        let change = Candidate {
+            author: "Ada Lovelace (ada) <ada@example.com>",
+            message: "Fix the [security] check",
+            note: Some("Ticket PLAT-12"),
+            paths: &paths,
+            ..Candidate::default()
+        };
+        assert!(definition(&["ADA", "bob"], &[], &[]).matches(&change));
+        assert!(!definition(&["bob"], &[], &[]).matches(&change));
+        assert!(definition(&[], &["/src/api/"], &[]).matches(&change));
+        assert!(!definition(&[], &["src/ap"], &[]).matches(&change));
+        assert!(definition(&[], &[], &["plat-12"]).matches(&change));
+        assert!(definition(&[], &[], &["[Security]"]).matches(&change));
+        // All criteria must match.
+        assert!(!definition(&["ada"], &["docs"], &[]).matches(&change));
+        assert!(definition(&["ada"], &["src", "docs"], &["security"]).matches(&change));
+    }
+
+    #[test]
+    fn test_crud() {
+        let tmp = tempfile::tempdir().unwrap();
+        let filters = SavedFilters::new(tmp.path().join(FILTERS_FILE));
+        assert!(filters.list().unwrap().is_empty());
+        assert!(filters.put("empty", FilterDefinition::default()).is_err());
+
+        filters.put("ui", definition(&[], &["web"], &[])).unwrap();
+        filters.put("core", definition(&["ada"], &[], &[])).unwrap();
+        filters.put("ui", definition(&[], &["app"], &[])).unwrap();
+        let names: Vec<_> = filters
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|f| f.name)
+            .collect();
+        assert_eq!(names, ["core", "ui"]);
+        assert_eq!(filters.expand("ui").unwrap().paths, ["app"]);
+        assert!(filters.expand("nope").is_err());
+
+        assert!(filters.remove("ui").unwrap());
+        assert!(!filters.remove("ui").unwrap());
+        assert!(filters.remove("core").unwrap());
+        assert!(!filters.path().exists());
+    }
+}
@@ -0,0 +1,436 @@
+//! Dependency-based grouping of the changes of a channel
+//!
+//! Review UIs present stacked patches as threads rather than a flat list. A
+//! thread (or dependency cluster) is a run of changes where each change
+//! depends on the previous one, and that previous change has no other
+//! dependent in the channel. Computing clusters requires reverse-dependency
+//! queries over the whole log, so results are cached per channel state.
+//!
+//! The changes of a channel can also be read in topological levels: level
+//! 0 has the changes without dependencies in the channel, and each other
+//! change is one level above its highest dependency. Changes of a level
+//! don't depend on each other, so reading levels in order presents every
+//! change after what it builds on, without interleaving unrelated stacks
+//! by timestamp.
+
+use libatomic::pristine::sanakirja::Txn;
+use libatomic::pristine::{ChannelRef, Hash, Merkle, NodeId};
+use libatomic::{ChannelTxnT, DepsTxnT, GraphTxnT, TxnTExt};
+use lru_cache::LruCache;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// A stack of changes, newest first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DependencyCluster {
+    /// Newest change of the stack
+    pub head: Hash,
+    /// Changes of the stack, from the head down to the oldest change
+    pub changes: Vec<Hash>,
+}
+
+/// Dependencies of `id` that are in `channel`
+fn dependencies_in_channel(
+    txn: &Txn,
+    channel: &<Txn as ChannelTxnT>::Channel,
+    id: &NodeId,
+) -> Result<Vec<NodeId>, anyhow::Error> {
+    let mut deps = Vec::new();
+    for x in txn.iter_dep(id)? {
+        let (id_, d) = x?;
+        if id_ > id {
+            break;
+        }
+        if id_ < id {
+            continue;
+        }
+        if txn.get_changeset(txn.changes(channel), d)?.is_some() {
+            deps.push(*d)
+        }
+    }
+    Ok(deps)
+}
+
+/// Number of dependents of `id` that are in `channel`
+fn dependents_in_channel(
+    txn: &Txn,
+    channel: &<Txn as ChannelTxnT>::Channel,
+    id: &NodeId,
+) -> Result<usize, anyhow::Error> {
+    let mut n = 0;
+    for x in txn.iter_revdep(id)? {
+        let (id_, t) = x?;
+        if id_ > id {
+            break;
+        }
+        if id_ < id {
+            continue;
+        }
+        if txn.get_changeset(txn.changes(channel), t)?.is_some() {
+            n += 1
+        }
+    }
+    Ok(n)
+}
+
+/// Compute the dependency clusters of a channel, most recent first
+pub fn dependency_clusters(
+    txn: &Txn,
+    channel: &ChannelRef<Txn>,
+) -> Result<Vec<DependencyCluster>, anyhow::Error> {
+    let channel = channel.read();
+    // Threads are built oldest first, along with the position of their head.
+    let mut threads: Vec<(u64, Vec<Hash>)> = Vec::new();
+    // Latest change of each thread, with the index of the thread.
+    let mut tails: HashMap<NodeId, usize> = HashMap::new();
+
+    for entry in txn.log(&*channel, 0)? {
+        let (n, (h, _)) = entry?;
+        let hash: Hash = h.into();
+        let id = *txn
+            .get_internal(h)?
+            .ok_or_else(|| anyhow::anyhow!("Change {:?} not found", hash))?;
+
+        let deps = dependencies_in_channel(txn, &*channel, &id)?;
+
+        let thread = match deps[..] {
+            [d] if tails.contains_key(&d) && dependents_in_channel(txn, &*channel, &d)? == 1 => {
+                tails.remove(&d)
+            }
+            _ => None,
+        };
+        let t = if let Some(t) = thread {
+            threads[t].0 = n;
+            threads[t].1.push(hash);
+            t
+        } else {
+            threads.push((n, vec![hash]));
+            threads.len() - 1
+        };
+        tails.insert(id, t);
+    }
+
+    threads.sort_by(|a, b| b.0.cmp(&a.0));
+    Ok(threads
+        .into_iter()
+        .map(|(_, mut changes)| {
+            changes.reverse();
+            DependencyCluster {
+                head: changes[0],
+                changes,
+            }
+        })
+        .collect())
+}
+
+/// Compute the topological levels of a channel, lowest level first, each
+/// level in log order
+pub fn topological_levels(
+    txn: &Txn,
+    channel: &ChannelRef<Txn>,
+) -> Result<Vec<Vec<Hash>>, anyhow::Error> {
+    let channel = channel.read();
+    let mut levels: Vec<Vec<Hash>> = Vec::new();
+    let mut level_of: HashMap<NodeId, usize> = HashMap::new();
+    // The log is in application order, so dependencies come first.
+    for entry in txn.log(&*channel, 0)? {
+        let (_, (h, _)) = entry?;
+        let hash: Hash = h.into();
+        let id = *txn
+            .get_internal(h)?
+            .ok_or_else(|| anyhow::anyhow!("Change {:?} not found", hash))?;
+        let level = dependencies_in_channel(txn, &*channel, &id)?
+            .iter()
+            .filter_map(|d| level_of.get(d))
+            .map(|l| l + 1)
+            .max()
+            .unwrap_or(0);
+        level_of.insert(id, level);
+        if level == levels.len() {
+            levels.push(Vec::new())
+        }
+        levels[level].push(hash);
+    }
+    Ok(levels)
+}
+
+/// Position in the topological levels of a channel: a level, and an
+/// index in that level
+pub type LevelPosition = (usize, usize);
+
+/// The next `limit` changes of `levels` from `from`, as slices of levels
+/// with their level number, and the position of the page after them if
+/// there are more changes. A level can be split across pages.
+pub fn level_page(
+    levels: &[Vec<Hash>],
+    from: LevelPosition,
+    limit: usize,
+) -> (Vec<(usize, &[Hash])>, Option<LevelPosition>) {
+    let mut page = Vec::new();
+    let mut left = limit;
+    let (mut level, mut index) = from;
+    while level < levels.len() {
+        let rest = levels[level].get(index..).unwrap_or(&[]);
+        if left == 0 {
+            if rest.is_empty() {
+                level += 1;
+                index = 0;
+                continue;
+            }
+            return (page, Some((level, index)));
+        }
+        let n = rest.len().min(left);
+        if n > 0 {
+            page.push((level, &rest[..n]));
+        }
+        left -= n;
+        if n < rest.len() {
+            return (page, Some((level, index + n)));
+        }
+        level += 1;
+        index = 0;
+    }
+    (page, None)
+}
+
+/// Number of channels whose clusters, and whose levels, are cached
+const CACHE_SIZE: usize = 256;
+
+/// Entries of a [`ClusterCache`]: the state of the channel when they were
+/// computed, and what was computed
+type CacheEntries<T> = Arc<Mutex<LruCache<(PathBuf, String), (Merkle, Arc<T>)>>>;
+
+/// Cache of dependency clusters and topological levels, keyed by
+/// repository and channel, and invalidated when the state of the channel
+/// changes. At most [`CACHE_SIZE`] channels are kept, forgetting the least
+/// recently used ones first.
+#[derive(Clone)]
+pub struct ClusterCache {
+    entries: CacheEntries<Vec<DependencyCluster>>,
+    levels: CacheEntries<Vec<Vec<Hash>>>,
+}
+
+impl Default for ClusterCache {
+    fn default() -> Self {
+        Self::with_capacity(CACHE_SIZE)
+    }
+}
+
+/// Return the entry of `channel` in `entries`, computing it with `compute`
+/// if it is missing or stale
+fn get_or_compute<T>(
+    entries: &CacheEntries<T>,
+    repo_path: PathBuf,
+    txn: &Txn,
+    channel: &ChannelRef<Txn>,
+    compute: impl FnOnce(&Txn, &ChannelRef<Txn>) -> Result<T, anyhow::Error>,
+) -> Result<Arc<T>, anyhow::Error> {
+    let (name, state) = {
+        let c = channel.read();
+        (txn.name(&*c).to_string(), txn.current_state(&*c)?)
+    };
+    let key = (repo_path, name);
+    if let Some((cached_state, value)) = entries.lock().unwrap().get_mut(&key) {
+        if *cached_state == state {
+            return Ok(value.clone());
+        }
+    }
+    let value = Arc::new(compute(txn, channel)?);
+    entries.lock().unwrap().insert(key, (state, value.clone()));
+    Ok(value)
+}
+
+impl ClusterCache {
+    /// A cache of the clusters and levels of `capacity` channels
+    pub fn with_capacity(capacity: usize) -> Self {
+        ClusterCache {
+            entries: Arc::new(Mutex::new(LruCache::new(capacity))),
+            levels: Arc::new(Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    /// Return the clusters of `channel` in the repository at `repo_path`,
+    /// computing them if the cached entry is missing or stale.
+    pub fn get_or_compute(
+        &self,
+        repo_path: PathBuf,
+        txn: &Txn,
+        channel: &ChannelRef<Txn>,
+    ) -> Result<Arc<Vec<DependencyCluster>>, anyhow::Error> {
+        get_or_compute(&self.entries, repo_path, txn, channel, dependency_clusters)
+    }
+
+    /// Return the topological levels of `channel`, like
+    /// [`ClusterCache::get_or_compute`]
+    pub fn levels(
+        &self,
+        repo_path: PathBuf,
+        txn: &Txn,
+        channel: &ChannelRef<Txn>,
+    ) -> Result<Arc<Vec<Vec<Hash>>>, anyhow::Error> {
+        get_or_compute(&self.levels, repo_path, txn, channel, topological_levels)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_repository::fixtures::hash;
+    use libatomic::change::Change;
+    use libatomic::changestore::ChangeStore;
+    use libatomic::{MutTxnT, MutTxnTExt, TxnT};
+
+    fn change(message: &str, dependencies: Vec<Hash>) -> Change {
+        let mut c = Change::new();
+        c.hashed.header.message = message.to_string();
+        c.hashed.dependencies = dependencies;
+        c
+    }
+
+    /// Apply `changes`, given as messages and indices of dependencies, to
+    /// a channel, and group them with `group`
+    fn grouped(
+        changes: &[(&str, Vec<usize>)],
+        group: impl Fn(&Txn, &ChannelRef<Txn>) -> Vec<Vec<Hash>>,
+    ) -> Vec<Vec<String>> {
+        let store = libatomic::changestore::memory::Memory::new();
+        let env = libatomic::pristine::sanakirja::Pristine::new_anon().unwrap();
+        let mut hashes = Vec::new();
+        let mut messages = HashMap::new();
+        {
+            let mut txn = env.mut_txn_begin().unwrap();
+            let channel = txn.open_or_create_channel("main").unwrap();
+            for (message, deps) in changes {
+                let mut c = change(message, deps.iter().map(|&i| hashes[i]).collect());
+                let h = store
+                    .save_change(&mut c, |_, _| Ok::<_, anyhow::Error>(()))
+                    .unwrap();
+                txn.apply_change(&store, &mut *channel.write(), &h).unwrap();
+                hashes.push(h);
+                messages.insert(h, message.to_string());
+            }
+            txn.commit().unwrap();
+        }
+        let txn = env.txn_begin().unwrap();
+        let channel = txn.load_channel("main").unwrap().unwrap();
+        group(&txn, &channel)
+            .into_iter()
+            .map(|g| g.iter().map(|h| messages[h].clone()).collect())
+            .collect()
+    }
+
+    fn clusters_of(changes: &[(&str, Vec<usize>)]) -> Vec<Vec<String>> {
+        grouped(changes, |txn, channel| {
+            dependency_clusters(txn, channel)
+                .unwrap()
+                .into_iter()
+                .map(|c| {
+                    assert_eq!(c.head, c.changes[0]);
+                    c.changes
+                })
+                .collect()
+        })
+    }
+
+    #[test]
+    fn test_stack_forms_one_cluster() {
+        let clusters = clusters_of(&[("a", vec![]), ("b", vec![0]), ("c", vec![1]), ("d", vec![])]);
+        assert_eq!(clusters, vec![vec!["d"], vec!["c", "b", "a"]]);
+    }
+
+    #[test]
+    fn test_branching_dependents_split_clusters() {
+        let clusters = clusters_of(&[("a", vec![]), ("b", vec![0]), ("c", vec![0])]);
+        assert_eq!(clusters, vec![vec!["c"], vec!["b"], vec!["a"]]);
+    }
+
+    #[test]
+    fn test_topological_levels() {
+        // Two stacks recorded alternately, and a merge of both.
+        let levels = grouped(
+            &[
+                ("a1", vec![]),
+                ("b1", vec![]),
+                ("a2", vec![0]),
+                ("b2", vec![1]),
+                ("b3", vec![3]),
+                ("merge", vec![2, 4]),
+            ],
+            |txn, channel| topological_levels(txn, channel).unwrap(),
+        );
+        assert_eq!(
+            levels,
+            vec![
+                vec!["a1", "b1"],
+                vec!["a2", "b2"],
+                vec!["b3"],
+                vec!["merge"]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_level_page() {
+        let levels = vec![
+            vec![hash(0), hash(1), hash(2)],
+            vec![hash(3)],
+            vec![hash(4)],
+        ];
+        let sizes = |page: &[(usize, &[Hash])]| -> Vec<(usize, usize)> {
+            page.iter().map(|(l, h)| (*l, h.len())).collect()
+        };
+
+        let (page, next) = level_page(&levels, (0, 0), 2);
+        assert_eq!(sizes(&page), [(0, 2)]);
+        assert_eq!(next, Some((0, 2)));
+        let (page, next) = level_page(&levels, (0, 2), 2);
+        assert_eq!(sizes(&page), [(0, 1), (1, 1)]);
+        assert_eq!(next, Some((2, 0)));
+        let (page, next) = level_page(&levels, (2, 0), 2);
+        assert_eq!(sizes(&page), [(2, 1)]);
+        assert_eq!(next, None);
+        // A page ending with the last change has no next page.
+        let (_, next) = level_page(&levels, (0, 0), 5);
+        assert_eq!(next, None);
+        let (page, next) = level_page(&levels, (7, 0), 2);
+        assert!(page.is_empty() && next.is_none());
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        let env = libatomic::pristine::sanakirja::Pristine::new_anon().unwrap();
+        {
+            let mut txn = env.mut_txn_begin().unwrap();
+            for name in ["a", "b"] {
+                txn.open_or_create_channel(name).unwrap();
+            }
+            txn.commit().unwrap();
+        }
+        let txn = env.txn_begin().unwrap();
+        let cache = ClusterCache::with_capacity(1);
+        let computed = std::cell::Cell::new(0);
+        let levels = |name: &str| {
+            let channel = txn.load_channel(name).unwrap().unwrap();
+            get_or_compute(
+                &cache.levels,
+                PathBuf::from("repo"),
+                &txn,
+                &channel,
+                |_, _| {
+                    computed.set(computed.get() + 1);
+                    Ok(Vec::new())
+                },
+            )
+            .unwrap();
+        };
+        levels("a");
+        levels("a");
+        assert_eq!(computed.get(), 1);
+        // Only one channel is kept.
+        levels("b");
+        levels("a");
+        assert_eq!(computed.get(), 3);
+        assert_eq!(cache.levels.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,434 @@
+//! Workflow inbox of a tenant
+//!
+//! Managers follow the changes awaiting a decision across the
+//! repositories of a tenant with `GET /tenant/:tenant_id/workflow/inbox`,
+//! instead of reading the workflow states of each repository. The server
+//! keeps an index of the pending workflow states of each tenant in
+//! `<tenant>/.inbox.json`: the states some transition leaves, with the
+//! portfolio and project of their repository, and the author and message
+//! of their change. Transitions, applies starting workflows (see
+//! `[[bootstrap]]` rules in [`crate::workflow`]) and submissions update
+//! the index, and states without transitions leaving them (e.g.
+//! "Approved") drop out of it. A missing index, e.g. of a tenant whose
+//! workflows predate it, is built again by scanning the repositories of
+//! the tenant; deleting it rebuilds it on the next query.
+//!
+//! The inbox is filtered by `role` (items with a transition needing that
+//! role, e.g. `?role=reviewer`), `reviewer` (items assigned to that
+//! user), `workflow` and `portfolio`, and paginated like the other lists.
+
+use crate::{ApiError, ApiResult};
+use atomic_config::events::NodeKind;
+use atomic_repository::Repository;
+use atomic_workflows::{WorkflowRegistry, WorkflowState, WorkflowStore};
+use chrono::{DateTime, Utc};
+use libatomic::changestore::ChangeStore;
+use libatomic::{Base32, Hash};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+/// Index of the pending workflow states of a tenant, in its directory
+pub const INBOX_FILE: &str = ".inbox.json";
+
+/// Fields the inbox can be sorted on
+pub const INBOX_SORT_FIELDS: &[&str] = &["updated", "portfolio", "project", "workflow", "state"];
+
+/// A workflow state awaiting a transition
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InboxItem {
+    pub portfolio: String,
+    pub project: String,
+    #[serde(flatten)]
+    pub state: WorkflowState,
+    /// Author of the change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// Message of the change
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// When the item was last indexed
+    pub updated: DateTime<Utc>,
+}
+
+impl InboxItem {
+    /// Identifies the item in the index and in cursors
+    pub fn key(&self) -> String {
+        key(
+            &self.portfolio,
+            &self.project,
+            &self.state.workflow,
+            &self.state.change_id,
+        )
+    }
+}
+
+fn key(portfolio: &str, project: &str, workflow: &str, change_id: &str) -> String {
+    format!("{}/{}/{}/{}", portfolio, project, workflow, change_id)
+}
+
+/// Query parameters of `GET /tenant/:tenant_id/workflow/inbox`
+#[derive(Debug, Default, Deserialize)]
+pub struct InboxQuery {
+    /// Only the items with a transition needing this role
+    pub role: Option<String>,
+    /// Only the items assigned to this reviewer
+    pub reviewer: Option<String>,
+    pub workflow: Option<String>,
+    pub portfolio: Option<String>,
+}
+
+impl InboxQuery {
+    /// Whether `item` is requested
+    pub fn matches(&self, registry: &WorkflowRegistry, item: &InboxItem) -> bool {
+        let state = &item.state;
+        if let Some(ref role) = self.role {
+            let needed = registry.definition(&state.workflow).is_some_and(|d| {
+                d.transitions_from(&state.state)
+                    .any(|t| t.needs_role.as_deref() == Some(role.as_str()))
+            });
+            if !needed {
+                return false;
+            }
+        }
+        if let Some(ref reviewer) = self.reviewer {
+            if !state.reviewers.contains(reviewer) {
+                return false;
+            }
+        }
+        if let Some(ref workflow) = self.workflow {
+            if *workflow != state.workflow {
+                return false;
+            }
+        }
+        if let Some(ref portfolio) = self.portfolio {
+            if *portfolio != item.portfolio {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Whether some transition leaves `state`
+fn pending(registry: &WorkflowRegistry, state: &WorkflowState) -> bool {
+    registry
+        .definition(&state.workflow)
+        .is_some_and(|d| d.transitions_from(&state.state).next().is_some())
+}
+
+/// The inbox item of `state`, with the author and message of its change
+fn item(
+    repository: &Repository,
+    portfolio: &str,
+    project: &str,
+    state: WorkflowState,
+) -> InboxItem {
+    let header = match state.kind {
+        NodeKind::Change => Hash::from_base32(state.change_id.as_bytes())
+            .and_then(|h| repository.changes.get_header(&h).ok()),
+        NodeKind::Tag => None,
+    };
+    let (author, message) = match header {
+        Some(header) => {
+            let author = crate::workflow::change_author(header.authors.first()).username;
+            ((!author.is_empty()).then_some(author), Some(header.message))
+        }
+        None => (None, None),
+    };
+    InboxItem {
+        portfolio: portfolio.to_string(),
+        project: project.to_string(),
+        state,
+        author,
+        message,
+        updated: Utc::now(),
+    }
+}
+
+/// Pending workflow states of the repositories of the tenants under a
+/// base mount path
+#[derive(Clone)]
+pub struct Inbox {
+    root: PathBuf,
+    /// Serializes the updates of the indexes
+    lock: Arc<Mutex<()>>,
+}
+
+impl Inbox {
+    /// Inboxes of the tenants under `root`, the canonical base mount path
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Inbox {
+            root: root.into(),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn path(&self, tenant_id: &str) -> PathBuf {
+        self.root.join(tenant_id).join(INBOX_FILE)
+    }
+
+    /// Tenant, portfolio and project ids of the repository at `repo_path`
+    fn ids<'a>(&self, repo_path: &'a Path) -> Option<(&'a str, &'a str, &'a str)> {
+        let mut ids = repo_path.strip_prefix(&self.root).ok()?.components();
+        match (ids.next(), ids.next(), ids.next(), ids.next()) {
+            (
+                Some(Component::Normal(tenant)),
+                Some(Component::Normal(portfolio)),
+                Some(Component::Normal(project)),
+                None,
+            ) => Some((tenant.to_str()?, portfolio.to_str()?, project.to_str()?)),
+            _ => None,
+        }
+    }
+
+    /// Pending items of `tenant_id`, building its index if missing
+    pub fn items(&self, tenant_id: &str, registry: &WorkflowRegistry) -> ApiResult<Vec<InboxItem>> {
+        let tenant_root = self.root.join(tenant_id);
+        if !tenant_root.is_dir() {
+            return Ok(Vec::new());
+        }
+        let _lock = self.lock.lock().unwrap();
+        let path = self.path(tenant_id);
+        if let Some(index) = load(&path) {
+            return Ok(index.into_values().collect());
+        }
+        let index = scan(&tenant_root, registry)?;
+        save(&path, &index)?;
+        Ok(index.into_values().collect())
+    }
+
+    /// Index the states of the workflows of `registry` on `change_ids`,
+    /// in `repository`. Indexes not built yet are left alone, the next
+    /// query scans the repositories.
+    pub fn refresh(
+        &self,
+        repository: &Repository,
+        registry: &WorkflowRegistry,
+        change_ids: &[String],
+    ) -> ApiResult<()> {
+        let Some((tenant_id, portfolio, project)) = self.ids(&repository.path) else {
+            return Ok(());
+        };
+        let txn = repository
+            .pristine
+            .txn_begin()
+            .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+        let mut states = Vec::new();
+        for change_id in change_ids {
+            for definition in registry.definitions() {
+                if let Some(state) = txn
+                    .get_workflow_state(&definition.name, change_id)
+                    .map_err(|e| {
+                        ApiError::internal(format!("Failed to access workflow states: {}", e))
+                    })?
+                {
+                    states.push(state)
+                }
+            }
+        }
+        drop(txn);
+
+        let _lock = self.lock.lock().unwrap();
+        let path = self.path(tenant_id);
+        let Some(mut index) = load(&path) else {
+            return Ok(());
+        };
+        for change_id in change_ids {
+            for definition in registry.definitions() {
+                index.remove(&key(portfolio, project, &definition.name, change_id));
+            }
+        }
+        for state in states.into_iter().filter(|s| pending(registry, s)) {
+            let item = item(repository, portfolio, project, state);
+            index.insert(item.key(), item);
+        }
+        save(&path, &index)
+    }
+
+    /// Like [`Inbox::refresh`], logging failures: the index is derived
+    /// from the workflow states, which are already stored
+    pub fn update(
+        &self,
+        repository: &Repository,
+        registry: &WorkflowRegistry,
+        change_ids: &[String],
+    ) {
+        if let Err(e) = self.refresh(repository, registry, change_ids) {
+            warn!(
+                "Failed to update the workflow inbox of {}: {}",
+                repository.path.display(),
+                e
+            )
+        }
+    }
+
+    /// Like [`Inbox::update`], opening the repository at `repo_path`
+    pub fn update_at(&self, repo_path: &Path, registry: &WorkflowRegistry, change_ids: &[String]) {
+        match Repository::find_root(Some(repo_path.to_path_buf())) {
+            Ok(repository) => self.update(&repository, registry, change_ids),
+            Err(e) => warn!(
+                "Failed to update the workflow inbox of {}: {}",
+                repo_path.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// The index at `path`. Unreadable indexes are built again.
+fn load(path: &Path) -> Option<BTreeMap<String, InboxItem>> {
+    let items: Vec<InboxItem> = serde_json::from_slice(&std::fs::read(path).ok()?).ok()?;
+    Some(items.into_iter().map(|i| (i.key(), i)).collect())
+}
+
+fn save(path: &Path, index: &BTreeMap<String, InboxItem>) -> ApiResult<()> {
+    let Some(dir) = path.parent() else {
+        return Ok(());
+    };
+    let items: Vec<&InboxItem> = index.values().collect();
+    let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+    serde_json::to_writer(&mut tmp, &items).map_err(std::io::Error::from)?;
+    tmp.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Directories of `path`, sorted. Symbolic links are skipped, as they may
+/// lead out of the tenant directory.
+fn dirs(path: &Path) -> std::io::Result<Vec<String>> {
+    let mut dirs = Vec::new();
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(dirs),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            if let Some(name) = entry.file_name().to_str() {
+                dirs.push(name.to_string())
+            }
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// The pending states of the repositories under `tenant_root`
+fn scan(tenant_root: &Path, registry: &WorkflowRegistry) -> ApiResult<BTreeMap<String, InboxItem>> {
+    let mut index = BTreeMap::new();
+    for portfolio in dirs(tenant_root)? {
+        for project in dirs(&tenant_root.join(&portfolio))? {
+            let repo_path = tenant_root.join(&portfolio).join(&project);
+            if !repo_path.join(libatomic::DOT_DIR).is_dir() {
+                continue;
+            }
+            let repository = match Repository::find_root(Some(repo_path.clone())) {
+                Ok(repository) => repository,
+                Err(e) => {
+                    warn!("Skipping {} in the inbox: {}", repo_path.display(), e);
+                    continue;
+                }
+            };
+            let txn = repository
+                .pristine
+                .txn_begin()
+                .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+            let mut states = Vec::new();
+            for definition in registry.definitions() {
+                states.extend(txn.iter_workflow_states(&definition.name).map_err(|e| {
+                    ApiError::internal(format!("Failed to access workflow states: {}", e))
+                })?)
+            }
+            drop(txn);
+            for state in states.into_iter().filter(|s| pending(registry, s)) {
+                let item = item(&repository, &portfolio, &project, state);
+                index.insert(item.key(), item);
+            }
+        }
+    }
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::workflow::{transition, TransitionRequest};
+    use atomic_config::Author;
+
+    fn request(trigger: &str, role: &str) -> TransitionRequest {
+        TransitionRequest {
+            workflow: "SimpleApproval".to_string(),
+            trigger: trigger.to_string(),
+            author: Author {
+                username: "alice".to_string(),
+                ..Author::default()
+            },
+            roles: vec![role.to_string()],
+        }
+    }
+
+    #[test]
+    fn test_inbox() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().canonicalize().unwrap();
+        let repo_path = root.join("t").join("p").join("x");
+        std::fs::create_dir_all(&repo_path).unwrap();
+        let repository = Repository::init(Some(repo_path), None, None).unwrap();
+        let registry = WorkflowRegistry::builtin();
+        let inbox = Inbox::new(&root);
+
+        // Indexes are built from the states already stored.
+        transition(
+            &repository,
+            &registry,
+            "AAAA",
+            request("submit", "developer"),
+        )
+        .unwrap();
+        transition(
+            &repository,
+            &registry,
+            "BBBB",
+            request("submit", "developer"),
+        )
+        .unwrap();
+        let items = inbox.items("t", &registry).unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            (items[0].portfolio.as_str(), items[0].project.as_str()),
+            ("p", "x")
+        );
+        assert_eq!(items[0].state.state, "Review");
+        assert!(root.join("t").join(INBOX_FILE).is_file());
+
+        let reviewers = InboxQuery {
+            role: Some("reviewer".to_string()),
+            ..InboxQuery::default()
+        };
+        assert!(items.iter().all(|i| reviewers.matches(&registry, i)));
+        let developers = InboxQuery {
+            role: Some("developer".to_string()),
+            ..InboxQuery::default()
+        };
+        assert!(!items.iter().any(|i| developers.matches(&registry, i)));
+
+        // Final states drop out of the index.
+        transition(
+            &repository,
+            &registry,
+            "AAAA",
+            request("approve", "reviewer"),
+        )
+        .unwrap();
+        inbox
+            .refresh(&repository, &registry, &["AAAA".to_string()])
+            .unwrap();
+        let items = inbox.items("t", &registry).unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].state.change_id, "BBBB");
+        assert!(inbox.items("other", &registry).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,382 @@
+//! Confinement of repository paths to their tenant directory
+//!
+//! Repository paths are `<base>/<tenant_id>/<portfolio_id>/<project_id>`.
+//! The ids are validated, but a misconfigured mount (e.g. a project
+//! symlinked to another tenant's project) or a future bug building paths
+//! could still make a path resolve outside of the tenant directory.
+//!
+//! A [`Jail`] holds the canonical base mount path, resolved once when the
+//! server starts. Paths are resolved one component at a time from the
+//! tenant directory, like `openat2` with `RESOLVE_BENEATH` would: only
+//! plain names are accepted, and symlinks are followed only if they stay
+//! in the tenant directory, relative `..` included. The tenant directory
+//! itself must not be a symlink. Components that don't exist yet, such as
+//! a repository being created, are left as they are.
+//!
+//! Repositories open many paths below `.atomic` (the pristine, change
+//! files, configuration, locks), so [`Jail::repository`] also walks
+//! `.atomic` and resolves every symlink found in it the same way.
+
+use crate::error::RepositoryError;
+use crate::ApiError;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::{Component, Path, PathBuf};
+use thiserror::Error;
+use tracing::warn;
+
+/// Maximal number of symlinks followed in a single resolution, as in
+/// Linux's `MAXSYMLINKS`
+const MAX_SYMLINKS: usize = 40;
+
+#[derive(Debug, Error)]
+pub enum JailError {
+    #[error("Invalid path {path}")]
+    InvalidPath { path: String },
+    #[error("{path} escapes its tenant directory")]
+    Escape { path: String },
+    #[error("Too many levels of symbolic links in {path}")]
+    SymlinkLoop { path: String },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl From<JailError> for ApiError {
+    fn from(e: JailError) -> Self {
+        match e {
+            JailError::Io(e) => ApiError::Io(e),
+            e => {
+                warn!("Denied path: {}", e);
+                ApiError::Repository(RepositoryError::AccessDenied {
+                    path: e.to_string(),
+                })
+            }
+        }
+    }
+}
+
+/// The base mount path, and the resolution of paths in tenant directories
+#[derive(Debug, Clone)]
+pub struct Jail {
+    root: PathBuf,
+}
+
+/// A component left to resolve
+enum Step {
+    Name(OsString),
+    Parent,
+}
+
+impl Jail {
+    /// Canonicalize `root`, which must exist
+    pub fn new(root: &Path) -> Result<Self, JailError> {
+        Ok(Jail {
+            root: root.canonicalize()?,
+        })
+    }
+
+    /// The canonical base mount path
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Path of the repository `tenant_id/portfolio_id/project_id`,
+    /// checking its `.atomic` directory and everything in it too
+    pub fn repository(
+        &self,
+        tenant_id: &str,
+        portfolio_id: &str,
+        project_id: &str,
+    ) -> Result<PathBuf, JailError> {
+        let relative = Path::new(portfolio_id).join(project_id);
+        self.resolve_tree(tenant_id, relative.join(libatomic::DOT_DIR))?;
+        self.resolve(tenant_id, &relative)
+    }
+
+    /// Resolve `relative` in the directory of `tenant_id`, and the
+    /// symlinks below it, following the symlinks to directories.
+    fn resolve_tree(&self, tenant_id: &str, relative: impl AsRef<Path>) -> Result<(), JailError> {
+        let mut dirs = vec![relative.as_ref().to_path_buf()];
+        // Resolved directories, since symlinks can lead back to them.
+        let mut seen = HashSet::new();
+        while let Some(dir) = dirs.pop() {
+            let resolved = self.resolve(tenant_id, &dir)?;
+            if !seen.insert(resolved.clone()) {
+                continue;
+            }
+            let entries = match std::fs::read_dir(&resolved) {
+                Ok(entries) => entries,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::NotFound
+                        || e.kind() == std::io::ErrorKind::NotADirectory =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e.into()),
+            };
+            for entry in entries {
+                let entry = entry?;
+                let file_type = entry.file_type()?;
+                let path = dir.join(entry.file_name());
+                if file_type.is_dir() {
+                    dirs.push(path)
+                } else if file_type.is_symlink() && self.resolve(tenant_id, &path)?.is_dir() {
+                    dirs.push(path)
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolve `relative` in the directory of `tenant_id`
+    pub fn resolve(
+        &self,
+        tenant_id: &str,
+        relative: impl AsRef<Path>,
+    ) -> Result<PathBuf, JailError> {
+        let relative = relative.as_ref();
+        let display = || Path::new(tenant_id).join(relative).display().to_string();
+        let tenant_root = match single_name(tenant_id) {
+            Some(name) => self.root.join(name),
+            None => return Err(JailError::InvalidPath { path: display() }),
+        };
+        match std::fs::symlink_metadata(&tenant_root) {
+            Ok(meta) if meta.file_type().is_symlink() => {
+                return Err(JailError::Escape { path: display() })
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        // Components left to resolve, the next one last.
+        let mut steps = Vec::new();
+        for c in relative.components().rev() {
+            match c {
+                Component::Normal(name) => steps.push(Step::Name(name.to_os_string())),
+                Component::CurDir => {}
+                _ => return Err(JailError::InvalidPath { path: display() }),
+            }
+        }
+
+        let mut resolved = tenant_root.clone();
+        let mut links = 0;
+        while let Some(step) = steps.pop() {
+            let name = match step {
+                Step::Name(name) => name,
+                Step::Parent => {
+                    // Only symlink targets have `..`, and `resolved` has
+                    // no symlinks, so popping is what the kernel would do.
+                    if resolved == tenant_root {
+                        return Err(JailError::Escape { path: display() });
+                    }
+                    resolved.pop();
+                    continue;
+                }
+            };
+            resolved.push(&name);
+            let meta = match std::fs::symlink_metadata(&resolved) {
+                Ok(meta) => meta,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    // Nothing left to follow below a missing component.
+                    while let Some(step) = steps.pop() {
+                        match step {
+                            Step::Name(name) => resolved.push(name),
+                            Step::Parent => return Err(JailError::InvalidPath { path: display() }),
+                        }
+                    }
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if !meta.file_type().is_symlink() {
+                continue;
+            }
+            links += 1;
+            if links > MAX_SYMLINKS {
+                return Err(JailError::SymlinkLoop { path: display() });
+            }
+            let target = std::fs::read_link(&resolved)?;
+            resolved.pop();
+            let target = if target.is_absolute() {
+                // Absolute targets must be spelled from the canonical
+                // tenant directory.
+                resolved = tenant_root.clone();
+                match target.strip_prefix(&tenant_root) {
+                    Ok(t) => t.to_path_buf(),
+                    Err(_) => return Err(JailError::Escape { path: display() }),
+                }
+            } else {
+                target
+            };
+            for c in target.components().rev() {
+                match c {
+                    Component::Normal(name) => steps.push(Step::Name(name.to_os_string())),
+                    Component::ParentDir => steps.push(Step::Parent),
+                    Component::CurDir => {}
+                    _ => return Err(JailError::Escape { path: display() }),
+                }
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+/// `id` as a single path component, if it is one
+fn single_name(id: &str) -> Option<&std::ffi::OsStr> {
+    let mut components = Path::new(id).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(name)), None) => Some(name),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jail() -> (tempfile::TempDir, Jail) {
+        let base = tempfile::tempdir().unwrap();
+        for tenant in ["a", "b"] {
+            std::fs::create_dir_all(base.path().join(tenant).join("p/project/.atomic")).unwrap();
+        }
+        let jail = Jail::new(base.path()).unwrap();
+        (base, jail)
+    }
+
+    #[test]
+    fn test_resolve_plain_paths() {
+        let (_base, jail) = jail();
+        assert_eq!(
+            jail.repository("a", "p", "project").unwrap(),
+            jail.root().join("a/p/project")
+        );
+        // Repositories being created don't exist yet.
+        assert_eq!(
+            jail.repository("a", "p", "new").unwrap(),
+            jail.root().join("a/p/new")
+        );
+        assert_eq!(
+            jail.resolve("c", "p/project").unwrap(),
+            jail.root().join("c/p/project")
+        );
+    }
+
+    #[test]
+    fn test_reject_traversal() {
+        let (_base, jail) = jail();
+        for (tenant, relative) in [
+            ("a", "../b/p/project"),
+            ("a", "p/../../b"),
+            ("a", "/etc"),
+            ("..", "a/p"),
+            ("a/p", "project"),
+            ("", "p/project"),
+        ] {
+            assert!(
+                matches!(
+                    jail.resolve(tenant, relative),
+                    Err(JailError::InvalidPath { .. })
+                ),
+                "{} {}",
+                tenant,
+                relative
+            );
+        }
+        assert!(jail.repository("a", "..", "b").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks() {
+        use std::os::unix::fs::symlink;
+        let (_base, jail) = jail();
+        let a = jail.root().join("a");
+
+        // Inside the tenant directory.
+        symlink("project", a.join("p/inside")).unwrap();
+        symlink(a.join("p"), a.join("absolute")).unwrap();
+        assert_eq!(
+            jail.repository("a", "p", "inside").unwrap(),
+            a.join("p/project")
+        );
+        assert_eq!(
+            jail.resolve("a", "absolute/project").unwrap(),
+            a.join("p/project")
+        );
+
+        // Into another tenant, relative and absolute.
+        symlink("../../b/p/project", a.join("p/relative")).unwrap();
+        symlink(jail.root().join("b/p/project"), a.join("p/other")).unwrap();
+        symlink("/", a.join("p/root")).unwrap();
+        for project in ["relative", "other", "root"] {
+            assert!(
+                matches!(
+                    jail.repository("a", "p", project),
+                    Err(JailError::Escape { .. })
+                ),
+                "{}",
+                project
+            );
+        }
+
+        // A `.atomic` leading out of the tenant.
+        std::fs::create_dir_all(a.join("p/dot")).unwrap();
+        symlink(
+            jail.root().join("b/p/project/.atomic"),
+            a.join("p/dot/.atomic"),
+        )
+        .unwrap();
+        assert!(matches!(
+            jail.repository("a", "p", "dot"),
+            Err(JailError::Escape { .. })
+        ));
+
+        // Or anything in `.atomic` leading out of it.
+        for (link, target) in [
+            ("pristine", "b/p/project/.atomic"),
+            ("changes/AB/CDEF.change", "b/p/project/.atomic/config"),
+            ("hooks", "/"),
+        ] {
+            std::fs::create_dir_all(a.join("p/deep/.atomic/changes/AB")).unwrap();
+            let link = a.join("p/deep/.atomic").join(link);
+            symlink(jail.root().join(target), &link).unwrap();
+            assert!(
+                matches!(
+                    jail.repository("a", "p", "deep"),
+                    Err(JailError::Escape { .. })
+                ),
+                "{}",
+                link.display()
+            );
+            std::fs::remove_file(&link).unwrap();
+        }
+        // Including through a directory symlinked in the tenant.
+        std::fs::create_dir_all(a.join("shared")).unwrap();
+        symlink(a.join("shared"), a.join("p/deep/.atomic/changes/AB/shared")).unwrap();
+        jail.repository("a", "p", "deep").unwrap();
+        symlink("/etc/passwd", a.join("shared/passwd")).unwrap();
+        assert!(matches!(
+            jail.repository("a", "p", "deep"),
+            Err(JailError::Escape { .. })
+        ));
+        std::fs::remove_file(a.join("shared/passwd")).unwrap();
+        // Loops through `.atomic` are resolved once.
+        symlink("..", a.join("p/deep/.atomic/changes/up")).unwrap();
+        jail.repository("a", "p", "deep").unwrap();
+
+        // Tenant directories can't be symlinks, even to other tenants.
+        symlink(jail.root().join("b"), jail.root().join("c")).unwrap();
+        assert!(matches!(
+            jail.repository("c", "p", "project"),
+            Err(JailError::Escape { .. })
+        ));
+
+        // Loops.
+        symlink("loop", a.join("p/loop")).unwrap();
+        assert!(matches!(
+            jail.repository("a", "p", "loop"),
+            Err(JailError::SymlinkLoop { .. })
+        ));
+    }
+}
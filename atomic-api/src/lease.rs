@@ -0,0 +1,380 @@
+//! Write leases, for several instances serving the same repositories
+//!
+//! Instances pointed at the same storage (e.g. an NFS mount) can all serve
+//! reads, but two of them writing the pristine of a repository at the same
+//! time corrupt it. With leases enabled, an instance takes the lease of a
+//! repository before writing to it, and only the holder of an unexpired
+//! lease writes:
+//!
+//! - the holder renews its leases periodically (a heartbeat), and releases
+//!   the leases of repositories it hasn't written to for a while, so that
+//!   another instance can take them over;
+//! - a lease that isn't renewed expires, so that a crashed holder doesn't
+//!   block the repository forever;
+//! - the other instances forward the writes to the holder, at the URL it
+//!   advertises in its lease, or reject them with a `503` naming the
+//!   holder in an `x-atomic-leader` header.
+//!
+//! Leases are kept by a [`LeaseProvider`], so that an external lock
+//! service can be plugged in with
+//! [`crate::ApiServer::with_lease_provider`]. The default provider,
+//! [`FileLeases`], keeps them in `.atomic/lease`, updated under an
+//! exclusive lock of the file.
+
+use crate::{ApiError, ApiResult};
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Lease of a repository, in its `.atomic` directory
+pub const LEASE_FILE: &str = "lease";
+/// Header naming the URL of the holder of the lease, on rejected writes
+pub const LEADER_HEADER: &str = "x-atomic-leader";
+/// Header naming the instance a write was forwarded by
+pub const FORWARDED_HEADER: &str = "x-atomic-forwarded-by";
+
+/// Default time a lease lasts without being renewed
+const DEFAULT_DURATION: Duration = Duration::from_secs(30);
+/// Default time without writes after which a lease is released
+const DEFAULT_IDLE: Duration = Duration::from_secs(300);
+
+/// Configuration of the leases of this instance
+#[derive(Debug, Clone)]
+pub struct LeaseConfig {
+    /// Name of this instance, unique among the instances sharing the
+    /// storage
+    pub instance: String,
+    /// URL where the other instances forward writes to this one, if any
+    pub url: Option<String>,
+    /// Time a lease lasts without being renewed. Leases are renewed three
+    /// times per duration.
+    pub duration: Duration,
+    /// Time without writes after which a lease is released
+    pub idle: Duration,
+    /// Whether writes to repositories leased by other instances are
+    /// forwarded to them, instead of being rejected
+    pub forward: bool,
+}
+
+impl LeaseConfig {
+    pub fn new(instance: impl Into<String>) -> Self {
+        LeaseConfig {
+            instance: instance.into(),
+            url: None,
+            duration: DEFAULT_DURATION,
+            idle: DEFAULT_IDLE,
+            forward: true,
+        }
+    }
+
+    /// Read the configuration from `ATOMIC_API_INSTANCE_ID`,
+    /// `ATOMIC_API_INSTANCE_URL`, `ATOMIC_API_LEASE_DURATION` and
+    /// `ATOMIC_API_LEASE_IDLE` (both in seconds), and
+    /// `ATOMIC_API_LEASE_FORWARD` (`false` to reject writes instead of
+    /// forwarding them). Leases are disabled if the instance isn't set.
+    pub fn from_env() -> Option<Self> {
+        fn var<T: std::str::FromStr>(var: &str) -> Option<T> {
+            std::env::var(var).ok().and_then(|s| s.parse().ok())
+        }
+        let instance = std::env::var("ATOMIC_API_INSTANCE_ID").ok()?;
+        Some(LeaseConfig {
+            url: std::env::var("ATOMIC_API_INSTANCE_URL").ok(),
+            duration: var("ATOMIC_API_LEASE_DURATION")
+                .filter(|&s: &u64| s > 0)
+                .map_or(DEFAULT_DURATION, Duration::from_secs),
+            idle: var("ATOMIC_API_LEASE_IDLE").map_or(DEFAULT_IDLE, Duration::from_secs),
+            forward: var("ATOMIC_API_LEASE_FORWARD").unwrap_or(true),
+            ..LeaseConfig::new(instance)
+        })
+    }
+
+    /// Interval between two renewals of the leases
+    pub fn heartbeat(&self) -> Duration {
+        self.duration / 3
+    }
+}
+
+/// The lease of a repository
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Lease {
+    /// Instance holding the lease
+    pub instance: String,
+    /// URL where writes are forwarded to the holder, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// When the lease expires, unless it is renewed
+    pub expires: DateTime<Utc>,
+}
+
+impl Lease {
+    fn expired(&self) -> bool {
+        self.expires <= Utc::now()
+    }
+}
+
+/// Where the leases of the repositories are kept
+pub trait LeaseProvider: Send + Sync {
+    /// Take or renew the lease of the repository at `repo_path` as
+    /// `candidate`, unless another instance holds an unexpired lease.
+    /// Returns the lease after the attempt, `candidate` if it was taken.
+    fn acquire(&self, repo_path: &Path, candidate: &Lease) -> std::io::Result<Lease>;
+    /// Release the lease of the repository at `repo_path`, if `instance`
+    /// holds it
+    fn release(&self, repo_path: &Path, instance: &str) -> std::io::Result<()>;
+}
+
+/// Leases in `.atomic/lease`, in the repositories
+#[derive(Debug, Clone, Default)]
+pub struct FileLeases;
+
+impl FileLeases {
+    /// Open the lease file of `repo_path`, locked exclusively, and read
+    /// the lease in it, if any
+    fn open(repo_path: &Path) -> std::io::Result<(std::fs::File, Option<Lease>)> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(repo_path.join(libatomic::DOT_DIR).join(LEASE_FILE))?;
+        file.lock_exclusive()?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        // An empty or unreadable file holds no lease.
+        Ok((file, serde_json::from_str(&contents).ok()))
+    }
+
+    fn write(file: &mut std::fs::File, lease: Option<&Lease>) -> std::io::Result<()> {
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        if let Some(lease) = lease {
+            file.write_all(&serde_json::to_vec(lease)?)?;
+        }
+        file.sync_all()
+    }
+}
+
+impl LeaseProvider for FileLeases {
+    fn acquire(&self, repo_path: &Path, candidate: &Lease) -> std::io::Result<Lease> {
+        let (mut file, current) = Self::open(repo_path)?;
+        match current {
+            Some(current) if current.instance != candidate.instance && !current.expired() => {
+                Ok(current)
+            }
+            _ => {
+                Self::write(&mut file, Some(candidate))?;
+                Ok(candidate.clone())
+            }
+        }
+    }
+
+    fn release(&self, repo_path: &Path, instance: &str) -> std::io::Result<()> {
+        let (mut file, current) = Self::open(repo_path)?;
+        match current {
+            Some(current) if current.instance == instance => Self::write(&mut file, None),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether this instance may write to a repository
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Leadership {
+    /// This instance holds the lease
+    Leader,
+    /// Another instance holds this lease
+    Follower(Lease),
+}
+
+/// The leases of this instance
+#[derive(Clone)]
+pub struct Leases {
+    config: LeaseConfig,
+    provider: Arc<dyn LeaseProvider>,
+    /// Repositories leased by this instance, and their last write
+    held: Arc<Mutex<HashMap<PathBuf, Instant>>>,
+}
+
+impl Leases {
+    pub fn new(config: LeaseConfig) -> Self {
+        Leases {
+            config,
+            provider: Arc::new(FileLeases),
+            held: Arc::default(),
+        }
+    }
+
+    /// Keep the leases in `provider` instead of the repositories
+    pub fn with_provider(mut self, provider: Arc<dyn LeaseProvider>) -> Self {
+        self.provider = provider;
+        self
+    }
+
+    pub fn config(&self) -> &LeaseConfig {
+        &self.config
+    }
+
+    fn candidate(&self) -> Lease {
+        Lease {
+            instance: self.config.instance.clone(),
+            url: self.config.url.clone(),
+            expires: Utc::now()
+                + chrono::Duration::from_std(self.config.duration)
+                    .unwrap_or_else(|_| chrono::Duration::seconds(30)),
+        }
+    }
+
+    /// Take or renew the lease of the repository at `repo_path` before a
+    /// write to it
+    pub fn check_write(&self, repo_path: &Path) -> ApiResult<Leadership> {
+        let lease = self
+            .provider
+            .acquire(repo_path, &self.candidate())
+            .map_err(|e| ApiError::internal(format!("Failed to take the lease: {}", e)))?;
+        if lease.instance == self.config.instance {
+            let mut held = self.held.lock().unwrap();
+            if held
+                .insert(repo_path.to_path_buf(), Instant::now())
+                .is_none()
+            {
+                info!("Took the lease of {}", repo_path.display());
+            }
+            Ok(Leadership::Leader)
+        } else {
+            self.held.lock().unwrap().remove(repo_path);
+            debug!(
+                "{} is leased by {} until {}",
+                repo_path.display(),
+                lease.instance,
+                lease.expires
+            );
+            Ok(Leadership::Follower(lease))
+        }
+    }
+
+    /// Renew the leases held by this instance, and release the leases of
+    /// the repositories that weren't written to for the idle time
+    pub fn heartbeat(&self) {
+        let held: Vec<_> = self
+            .held
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, last)| (path.clone(), *last))
+            .collect();
+        for (path, last) in held {
+            if last.elapsed() >= self.config.idle {
+                match self.provider.release(&path, &self.config.instance) {
+                    Ok(()) => info!("Released the lease of {}", path.display()),
+                    Err(e) => warn!("Failed to release the lease of {}: {}", path.display(), e),
+                }
+                self.held.lock().unwrap().remove(&path);
+                continue;
+            }
+            match self.provider.acquire(&path, &self.candidate()) {
+                Ok(lease) if lease.instance == self.config.instance => {}
+                Ok(lease) => {
+                    warn!("Lost the lease of {} to {}", path.display(), lease.instance);
+                    self.held.lock().unwrap().remove(&path);
+                }
+                Err(e) => warn!("Failed to renew the lease of {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Release all the leases held by this instance, e.g. when it stops
+    pub fn release_all(&self) {
+        let held: Vec<_> = self.held.lock().unwrap().drain().map(|(p, _)| p).collect();
+        for path in held {
+            if let Err(e) = self.provider.release(&path, &self.config.instance) {
+                warn!("Failed to release the lease of {}: {}", path.display(), e)
+            }
+        }
+    }
+
+    /// Repositories leased by this instance
+    pub fn held(&self) -> Vec<PathBuf> {
+        self.held.lock().unwrap().keys().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repository() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join(libatomic::DOT_DIR)).unwrap();
+        dir
+    }
+
+    fn leases(instance: &str, duration: Duration) -> Leases {
+        Leases::new(LeaseConfig {
+            url: Some(format!("http://{}:8080", instance)),
+            duration,
+            ..LeaseConfig::new(instance)
+        })
+    }
+
+    #[test]
+    fn test_one_writer() {
+        let repo = repository();
+        let a = leases("a", Duration::from_secs(60));
+        let b = leases("b", Duration::from_secs(60));
+
+        assert_eq!(a.check_write(repo.path()).unwrap(), Leadership::Leader);
+        assert_eq!(a.check_write(repo.path()).unwrap(), Leadership::Leader);
+        match b.check_write(repo.path()).unwrap() {
+            Leadership::Follower(lease) => {
+                assert_eq!(lease.instance, "a");
+                assert_eq!(lease.url.as_deref(), Some("http://a:8080"));
+            }
+            l => panic!("unexpected {:?}", l),
+        }
+        assert_eq!(a.held(), vec![repo.path().to_path_buf()]);
+        assert!(b.held().is_empty());
+
+        // Once released, the lease can be taken over.
+        a.release_all();
+        assert!(a.held().is_empty());
+        assert_eq!(b.check_write(repo.path()).unwrap(), Leadership::Leader);
+        assert!(matches!(
+            a.check_write(repo.path()).unwrap(),
+            Leadership::Follower(_)
+        ));
+    }
+
+    #[test]
+    fn test_expired_lease() {
+        let repo = repository();
+        let a = leases("a", Duration::ZERO);
+        let b = leases("b", Duration::from_secs(60));
+        assert_eq!(a.check_write(repo.path()).unwrap(), Leadership::Leader);
+        // `a` didn't renew its lease, e.g. because it crashed.
+        assert_eq!(b.check_write(repo.path()).unwrap(), Leadership::Leader);
+
+        // Its next heartbeat notices it lost the lease.
+        a.heartbeat();
+        assert!(a.held().is_empty());
+    }
+
+    #[test]
+    fn test_idle_lease_released() {
+        let repo = repository();
+        let a = Leases::new(LeaseConfig {
+            idle: Duration::ZERO,
+            ..LeaseConfig::new("a")
+        });
+        assert_eq!(a.check_write(repo.path()).unwrap(), Leadership::Leader);
+        a.heartbeat();
+        assert!(a.held().is_empty());
+        let contents =
+            std::fs::read(repo.path().join(libatomic::DOT_DIR).join(LEASE_FILE)).unwrap();
+        assert!(contents.is_empty());
+    }
+}
@@ -41,10 +41,49 @@ pub use crate::websocket::{
 };
 
 // Core modules following AGENTS.md code organization patterns
+pub mod acme;
+pub mod activity;
+pub mod admin;
+pub mod apply_queue;
+pub mod attribution_stats;
+pub mod auth;
+pub mod backup;
+pub mod codeowners;
+pub mod config;
+pub mod conflicts;
+#[cfg(feature = "content-index")]
+pub mod content_index;
+pub mod degraded;
+pub mod diff;
+pub mod digest;
+pub mod envelopes;
 pub mod error;
+pub mod event_log;
+pub mod exposure;
+pub mod filters;
+pub mod grouping;
+pub mod inbox;
+pub mod jail;
+pub mod lease;
+pub mod limits;
+pub mod maintenance;
 pub mod message;
+pub mod query;
+pub mod replica;
+pub mod resolve;
+pub mod sandbox;
 pub mod server;
+pub mod shutdown;
+pub mod snapshot;
+pub mod storage;
+pub mod stream;
+pub mod submission;
+pub mod tls;
+pub mod validate;
+pub mod versions;
+pub mod webhooks;
 pub mod websocket;
+pub mod workflow;
 
 /// Version information
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -0,0 +1,403 @@
+//! Rate and request size limits
+//!
+//! On a server shared by several tenants, one client flooding it or
+//! pushing huge bodies shouldn't starve the others. Each client has a
+//! token bucket: its requests (to the routes under `/tenant/:tenant_id`)
+//! take a token, tokens come back at `rate` per second up to `burst`, and
+//! requests finding the bucket empty are rejected with a `429` and a
+//! `Retry-After` header saying when the next token is available. Rate
+//! limiting is off unless `ATOMIC_API_RATE_LIMIT` is set.
+//!
+//! Clients are the authenticated principals, once authenticated, so that
+//! nobody can exhaust the bucket of somebody else: see [`RateKey`]. Without
+//! authentication, the clients of a tenant share its bucket. Before
+//! authentication, each peer address has a bucket of its own, at
+//! `peer_rate`, which is off unless `ATOMIC_API_PEER_RATE_LIMIT` is set
+//! (behind a proxy, all requests come from the proxy).
+//!
+//! The bodies of applies (`POST .../code`) and of uploads (`POST
+//! .../push` and `.../upload`) are limited to `max_apply_body` and
+//! `max_upload_body` bytes, and larger ones are rejected with a `413`,
+//! from their `Content-Length` when they have one, before reading them.
+
+use crate::{ApiError, ApiResult};
+use axum::http::Method;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+const MB: u64 = 1024 * 1024;
+/// Default limit of the bodies of applies
+const DEFAULT_MAX_APPLY_BODY: u64 = 64 * MB;
+/// Default limit of the bodies of uploads, which carry several changes
+const DEFAULT_MAX_UPLOAD_BODY: u64 = 256 * MB;
+/// Number of buckets kept: full buckets are forgotten first, and then the
+/// least recently used ones
+const MAX_BUCKETS: usize = 10_000;
+
+/// Configuration of the limits
+#[derive(Debug, Clone)]
+pub struct LimitsConfig {
+    /// Requests per second of each client, `0` for no rate limiting
+    pub rate: f64,
+    /// Requests a client can send at once after being idle, at least 1
+    pub burst: u32,
+    /// Requests per second of each peer address, before authentication,
+    /// `0` for no rate limiting
+    pub peer_rate: f64,
+    /// Requests a peer address can send at once after being idle
+    pub peer_burst: u32,
+    /// Bytes of the body of an apply
+    pub max_apply_body: u64,
+    /// Bytes of the body of an upload
+    pub max_upload_body: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            rate: 0.,
+            burst: 1,
+            peer_rate: 0.,
+            peer_burst: 1,
+            max_apply_body: DEFAULT_MAX_APPLY_BODY,
+            max_upload_body: DEFAULT_MAX_UPLOAD_BODY,
+        }
+    }
+}
+
+impl LimitsConfig {
+    /// Read the configuration from `ATOMIC_API_RATE_LIMIT` (requests per
+    /// second), `ATOMIC_API_RATE_BURST` (defaults to a second of
+    /// requests), `ATOMIC_API_PEER_RATE_LIMIT`, `ATOMIC_API_PEER_RATE_BURST`,
+    /// `ATOMIC_API_MAX_APPLY_BODY_MB` and `ATOMIC_API_MAX_UPLOAD_BODY_MB`,
+    /// with defaults for unset variables
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(var: &str) -> Option<T> {
+            std::env::var(var).ok().and_then(|s| s.parse().ok())
+        }
+        let rate_var = |name| {
+            var::<f64>(name)
+                .filter(|r| r.is_finite() && *r > 0.)
+                .unwrap_or(0.)
+        };
+        let rate = rate_var("ATOMIC_API_RATE_LIMIT");
+        let peer_rate = rate_var("ATOMIC_API_PEER_RATE_LIMIT");
+        LimitsConfig {
+            rate,
+            burst: var::<u32>("ATOMIC_API_RATE_BURST")
+                .unwrap_or(rate.ceil() as u32)
+                .max(1),
+            peer_rate,
+            peer_burst: var::<u32>("ATOMIC_API_PEER_RATE_BURST")
+                .unwrap_or(peer_rate.ceil() as u32)
+                .max(1),
+            max_apply_body: var::<u64>("ATOMIC_API_MAX_APPLY_BODY_MB")
+                .map_or(DEFAULT_MAX_APPLY_BODY, |m| m * MB),
+            max_upload_body: var::<u64>("ATOMIC_API_MAX_UPLOAD_BODY_MB")
+                .map_or(DEFAULT_MAX_UPLOAD_BODY, |m| m * MB),
+        }
+    }
+
+    /// The largest body accepted by any route
+    pub fn max_body(&self) -> u64 {
+        self.max_apply_body.max(self.max_upload_body)
+    }
+}
+
+/// The limited bodies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyKind {
+    Apply,
+    Upload,
+}
+
+impl BodyKind {
+    /// The kind of the body of a `method` request to `path`, if limited
+    pub fn of(method: &Method, path: &str) -> Option<BodyKind> {
+        if *method != Method::POST {
+            return None;
+        }
+        let mut segments = path.trim_start_matches('/').split('/').skip(6);
+        match (segments.next(), segments.next(), segments.next()) {
+            (Some("code"), None, None) | (Some("code"), Some(".atomic"), None) => {
+                Some(BodyKind::Apply)
+            }
+            (Some("push"), None, None) | (Some("upload"), None, None) => Some(BodyKind::Upload),
+            _ => None,
+        }
+    }
+}
+
+/// The tenant of a request to `path`, if any
+pub fn tenant_id(path: &str) -> Option<&str> {
+    let mut segments = path.trim_start_matches('/').split('/');
+    match (segments.next(), segments.next()) {
+        (Some("tenant"), Some(tenant_id)) if !tenant_id.is_empty() => Some(tenant_id),
+        _ => None,
+    }
+}
+
+/// Whose bucket a request takes a token from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateKey<'a> {
+    /// An authenticated principal, by name
+    Principal(&'a str),
+    /// A tenant, for requests to a server without authentication
+    Tenant(&'a str),
+    /// A peer address, before authentication
+    Peer(IpAddr),
+}
+
+impl std::fmt::Display for RateKey<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RateKey::Principal(name) => write!(f, "Principal {}", name),
+            RateKey::Tenant(tenant_id) => write!(f, "Tenant {}", tenant_id),
+            RateKey::Peer(addr) => write!(f, "Peer {}", addr),
+        }
+    }
+}
+
+/// Limits and counters, served by `GET /metrics/limits`
+#[derive(Debug, Clone, Serialize)]
+pub struct LimitsMetrics {
+    pub rate: f64,
+    pub burst: u32,
+    pub peer_rate: f64,
+    pub peer_burst: u32,
+    pub max_apply_body: u64,
+    pub max_upload_body: u64,
+    /// Principals, tenants and peers with a bucket
+    pub buckets: usize,
+    /// Requests rejected by the rate limits
+    pub rate_limited: u64,
+    /// Requests rejected for the size of their body
+    pub too_large: u64,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    rate: f64,
+    burst: f64,
+}
+
+/// The limits of the server, and the buckets of the clients
+#[derive(Clone)]
+pub struct Limits {
+    config: Arc<LimitsConfig>,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    rate_limited: Arc<AtomicU64>,
+    too_large: Arc<AtomicU64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::new(LimitsConfig::default())
+    }
+}
+
+impl Limits {
+    pub fn new(config: LimitsConfig) -> Self {
+        Limits {
+            config: Arc::new(config),
+            buckets: Arc::default(),
+            rate_limited: Arc::default(),
+            too_large: Arc::default(),
+        }
+    }
+
+    pub fn config(&self) -> &LimitsConfig {
+        &self.config
+    }
+
+    /// Take a token from the bucket of `key` at `now`, or fail with
+    /// [`ApiError::RateLimited`]
+    pub fn check_rate(&self, key: RateKey, now: Instant) -> ApiResult<()> {
+        let config = &self.config;
+        let (rate, burst) = match key {
+            RateKey::Peer(_) => (config.peer_rate, config.peer_burst as f64),
+            _ => (config.rate, config.burst as f64),
+        };
+        if rate <= 0. {
+            return Ok(());
+        }
+        let id = key.to_string();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(&id) {
+            // Full buckets are the same as new ones.
+            buckets.retain(|_, b| {
+                let elapsed = now.saturating_duration_since(b.last).as_secs_f64();
+                b.tokens + elapsed * b.rate < b.burst
+            });
+            if buckets.len() >= MAX_BUCKETS {
+                let lru = buckets
+                    .iter()
+                    .min_by_key(|(_, b)| b.last)
+                    .map(|(id, _)| id.clone());
+                if let Some(lru) = lru {
+                    buckets.remove(&lru);
+                }
+            }
+        }
+        let bucket = buckets.entry(id).or_insert(Bucket {
+            tokens: burst,
+            last: now,
+            rate,
+            burst,
+        });
+        let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.last = now;
+        if bucket.tokens >= 1. {
+            bucket.tokens -= 1.;
+            return Ok(());
+        }
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+        let retry_after = ((1. - bucket.tokens) / rate).ceil().max(1.) as u64;
+        Err(ApiError::rate_limited(
+            format!("{} is over {} requests per second", key, rate),
+            retry_after,
+        ))
+    }
+
+    /// The limit of a body of `kind`
+    pub fn max_body(&self, kind: BodyKind) -> u64 {
+        match kind {
+            BodyKind::Apply => self.config.max_apply_body,
+            BodyKind::Upload => self.config.max_upload_body,
+        }
+    }
+
+    /// Fail with [`ApiError::PayloadTooLarge`] if a body of `kind` of
+    /// `length` bytes is over its limit
+    pub fn check_body(&self, kind: BodyKind, length: u64) -> ApiResult<()> {
+        let limit = self.max_body(kind);
+        if length <= limit {
+            return Ok(());
+        }
+        self.too_large.fetch_add(1, Ordering::Relaxed);
+        Err(ApiError::payload_too_large(
+            format!("Body of {} bytes, at most {} are accepted", length, limit),
+            limit,
+        ))
+    }
+
+    pub fn metrics(&self) -> LimitsMetrics {
+        LimitsMetrics {
+            rate: self.config.rate,
+            burst: self.config.burst,
+            peer_rate: self.config.peer_rate,
+            peer_burst: self.config.peer_burst,
+            max_apply_body: self.config.max_apply_body,
+            max_upload_body: self.config.max_upload_body,
+            buckets: self.buckets.lock().unwrap().len(),
+            rate_limited: self.rate_limited.load(Ordering::Relaxed),
+            too_large: self.too_large.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let limits = Limits::new(LimitsConfig {
+            rate: 2.,
+            burst: 3,
+            ..LimitsConfig::default()
+        });
+        let now = Instant::now();
+        let acme = RateKey::Tenant("acme");
+        for _ in 0..3 {
+            assert!(limits.check_rate(acme, now).is_ok());
+        }
+        assert!(matches!(
+            limits.check_rate(acme, now),
+            Err(ApiError::RateLimited { retry_after: 1, .. })
+        ));
+        // Other tenants and principals have their own bucket.
+        assert!(limits.check_rate(RateKey::Tenant("globex"), now).is_ok());
+        assert!(limits.check_rate(RateKey::Principal("acme"), now).is_ok());
+        // A token every half second.
+        let later = now + Duration::from_millis(500);
+        assert!(limits.check_rate(acme, later).is_ok());
+        assert!(limits.check_rate(acme, later).is_err());
+        let metrics = limits.metrics();
+        assert_eq!((metrics.buckets, metrics.rate_limited), (3, 2));
+        // Peers aren't limited unless configured.
+        let peer = RateKey::Peer(IpAddr::from([127, 0, 0, 1]));
+        for _ in 0..100 {
+            assert!(limits.check_rate(peer, now).is_ok());
+        }
+
+        let unlimited = Limits::default();
+        for _ in 0..100 {
+            assert!(unlimited.check_rate(acme, now).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_bucket_eviction() {
+        let limits = Limits::new(LimitsConfig {
+            rate: 1.,
+            burst: 2,
+            ..LimitsConfig::default()
+        });
+        let now = Instant::now();
+        // No bucket is ever full again within this second.
+        for i in 0..MAX_BUCKETS {
+            let name = i.to_string();
+            let at = now + Duration::from_micros(i as u64);
+            limits.check_rate(RateKey::Principal(&name), at).unwrap();
+        }
+        let later = now + Duration::from_millis(100);
+        limits.check_rate(RateKey::Principal("new"), later).unwrap();
+        assert_eq!(limits.metrics().buckets, MAX_BUCKETS);
+        // The least recently used bucket went, the others are kept.
+        limits.check_rate(RateKey::Principal("1"), later).unwrap();
+        assert!(limits.check_rate(RateKey::Principal("1"), later).is_err());
+        limits.check_rate(RateKey::Principal("0"), later).unwrap();
+        limits.check_rate(RateKey::Principal("0"), later).unwrap();
+    }
+
+    #[test]
+    fn test_body_limits() {
+        let limits = Limits::new(LimitsConfig {
+            max_apply_body: 10,
+            max_upload_body: 100,
+            ..LimitsConfig::default()
+        });
+        let code = "/tenant/t/portfolio/p/project/r/code";
+        assert_eq!(BodyKind::of(&Method::POST, code), Some(BodyKind::Apply));
+        assert_eq!(
+            BodyKind::of(&Method::POST, &format!("{}/.atomic", code)),
+            Some(BodyKind::Apply)
+        );
+        assert_eq!(
+            BodyKind::of(&Method::POST, "/tenant/t/portfolio/p/project/r/upload"),
+            Some(BodyKind::Upload)
+        );
+        assert_eq!(BodyKind::of(&Method::GET, code), None);
+        assert_eq!(
+            BodyKind::of(&Method::POST, &format!("{}/sandboxes", code)),
+            None
+        );
+
+        assert!(limits.check_body(BodyKind::Apply, 10).is_ok());
+        assert!(matches!(
+            limits.check_body(BodyKind::Apply, 11),
+            Err(ApiError::PayloadTooLarge { limit: 10, .. })
+        ));
+        assert!(limits.check_body(BodyKind::Upload, 11).is_ok());
+        assert_eq!(tenant_id("/tenant/acme/workflow/inbox"), Some("acme"));
+        assert_eq!(tenant_id("/health"), None);
+    }
+}
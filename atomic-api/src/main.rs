@@ -2,13 +2,151 @@
 //!
 //! Standalone binary for running the Atomic VCS API server.
 //! Designed to serve a single repository behind a Fastify reverse proxy.
+//!
+//! Administrative subcommands (`verify`, `backfill-attribution`, `gc`,
+//! `create-repo`, and `reindex` with the `content-index` feature) operate
+//! directly on repositories on the host.
 
+#[cfg(feature = "content-index")]
+use atomic_api::content_index::{ContentIndex, ContentIndexConfig};
 use atomic_api::{
+    admin,
+    apply_queue::ApplyQueueConfig,
+    auth::AuthConfig,
+    degraded::DegradedConfig,
+    event_log::EventLog,
+    exposure::ExposureConfig,
+    lease::LeaseConfig,
+    limits::LimitsConfig,
+    maintenance::MaintenanceConfig,
+    replica::ReplicaConfig,
+    sandbox::SandboxConfig,
+    shutdown::{self, Shutdown, ShutdownConfig},
+    storage::StorageConfig,
+    tls::{Tls, TlsConfig},
+    webhooks::WebhookConfig,
     ApiServer, HealthCheckHandler, RepositoryStatusHandler, ServerConfig, WebSocketServer,
 };
+use atomic_repository::lock::LockOptions;
+use clap::{Parser, Subcommand};
 use std::env;
+use std::path::PathBuf;
 use tracing_subscriber;
 
+#[derive(Parser, Debug)]
+#[clap(name = "atomic-api", version, args_conflicts_with_subcommands = true)]
+struct Opts {
+    /// Base mount path containing the tenant repositories to serve
+    base_mount_path: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<AdminCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum AdminCommand {
+    /// Check that every change referenced by a channel is present and valid
+    Verify {
+        /// Path to the repository (the parent of its `.atomic` directory)
+        repo: PathBuf,
+    },
+    /// Record attribution for changes applied before attribution was enabled
+    #[clap(name = "backfill-attribution")]
+    BackfillAttribution {
+        /// Path to the repository (the parent of its `.atomic` directory)
+        repo: PathBuf,
+    },
+    /// Delete change files that no channel references
+    Gc {
+        /// Path to the repository (the parent of its `.atomic` directory)
+        repo: PathBuf,
+        /// Delete the files, instead of only listing them
+        #[clap(long = "delete")]
+        delete: bool,
+    },
+    /// Create an empty repository for a tenant/portfolio/project
+    #[clap(name = "create-repo")]
+    CreateRepo {
+        /// Base mount path of the server
+        base_mount_path: PathBuf,
+        tenant_id: String,
+        portfolio_id: String,
+        project_id: String,
+    },
+    /// Rebuild the content index of a repository from its changes
+    #[cfg(feature = "content-index")]
+    Reindex {
+        /// Path to the repository (the parent of its `.atomic` directory)
+        repo: PathBuf,
+    },
+}
+
+fn run_admin(command: AdminCommand) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        AdminCommand::Verify { repo } => {
+            let report = admin::verify_repository(&repo)?;
+            println!("Checked {} changes", report.checked);
+            for h in report.missing.iter() {
+                println!("missing: {}", h);
+            }
+            for h in report.corrupted.iter() {
+                println!("corrupted: {}", h);
+            }
+            if !report.is_ok() {
+                return Err(format!(
+                    "{} missing and {} corrupted changes",
+                    report.missing.len(),
+                    report.corrupted.len()
+                )
+                .into());
+            }
+        }
+        AdminCommand::BackfillAttribution { repo } => {
+            let report = admin::backfill_attribution(&repo)?;
+            println!(
+                "Examined {} changes: {} backfilled, {} already attributed",
+                report.examined, report.backfilled, report.already_present
+            );
+        }
+        AdminCommand::Gc { repo, delete } => {
+            let report = admin::gc(&repo, delete, &LockOptions::from_env())?;
+            for h in report.unreferenced.iter() {
+                println!("{}", h);
+            }
+            println!(
+                "{} {} unreferenced change files ({} bytes), kept {} newer than {} seconds",
+                if report.deleted { "Deleted" } else { "Found" },
+                report.unreferenced.len(),
+                report.bytes,
+                report.recent,
+                admin::GC_GRACE_PERIOD.as_secs()
+            );
+        }
+        AdminCommand::CreateRepo {
+            base_mount_path,
+            tenant_id,
+            portfolio_id,
+            project_id,
+        } => {
+            let path =
+                admin::create_repository(&base_mount_path, &tenant_id, &portfolio_id, &project_id)?;
+            println!("{}", path.display());
+        }
+        #[cfg(feature = "content-index")]
+        AdminCommand::Reindex { repo } => {
+            let config = ContentIndexConfig::from_env().unwrap_or_default();
+            let report = ContentIndex::new(config).rebuild(&repo)?;
+            for h in report.unreadable.iter() {
+                println!("unreadable: {}", h);
+            }
+            println!(
+                "Indexed {} hunks of {} changes",
+                report.documents, report.changes
+            );
+        }
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Initialize logging with DEBUG level by default
@@ -16,12 +154,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     if env::var("RUST_LOG").is_err() {
         env::set_var("RUST_LOG", "debug");
     }
+    // With `apply-spans`, report the time spent in the phases of applies
+    // and outputs when their span closes.
+    #[cfg(feature = "apply-spans")]
+    tracing_subscriber::fmt()
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .init();
+    #[cfg(not(feature = "apply-spans"))]
     tracing_subscriber::fmt::init();
 
+    let opts = Opts::parse();
+    if let Some(command) = opts.command {
+        return run_admin(command);
+    }
+
     // Get base mount path from command line arguments
-    let base_mount_path = env::args()
-        .nth(1)
-        .ok_or("Usage: atomic-api <base-mount-path>")?;
+    let base_mount_path = opts
+        .base_mount_path
+        .ok_or("Usage: atomic-api <base-mount-path>")?
+        .to_string_lossy()
+        .to_string();
 
     // Get bind addresses from environment or use defaults
     let rest_bind_addr =
@@ -42,11 +194,159 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("  ws://{}/", ws_bind_addr);
 
     // Create REST API server
-    let api_server = ApiServer::new(&base_mount_path).await?;
+    let apply_queue = ApplyQueueConfig::from_env();
+    println!(
+        "Apply workers: {} (queue capacity {} per repository)",
+        apply_queue.workers, apply_queue.capacity
+    );
+    let mut api_server = ApiServer::new(&base_mount_path)
+        .await?
+        .with_apply_queue(apply_queue);
+    if let Some(replicas) = ReplicaConfig::from_env() {
+        println!("Read replicas: {}", replicas.root.display());
+        api_server = api_server.with_replicas(replicas);
+    }
+    if let Some(leases) = LeaseConfig::from_env() {
+        println!(
+            "Write leases: instance {} ({}), {}s leases",
+            leases.instance,
+            leases.url.as_deref().unwrap_or("writes not forwarded here"),
+            leases.duration.as_secs()
+        );
+        api_server = api_server.with_leases(leases);
+    }
+    let sandboxes = SandboxConfig::from_env();
+    if let Some(ref root) = sandboxes.worktree_root {
+        println!("Sandbox worktrees: {}", root.display());
+    }
+    let degraded = DegradedConfig::from_env();
+    println!(
+        "Writes rejected under {} MB free or from {} queued applies",
+        degraded.min_free / (1024 * 1024),
+        degraded.max_queued
+    );
+    let limits = LimitsConfig::from_env();
+    if limits.rate > 0. {
+        println!(
+            "Rate limit: {} requests per second per client, bursts of {}",
+            limits.rate, limits.burst
+        );
+    }
+    if limits.peer_rate > 0. {
+        println!(
+            "Rate limit: {} requests per second per peer address, bursts of {}",
+            limits.peer_rate, limits.peer_burst
+        );
+    }
+    println!(
+        "Body limits: {} MB per apply, {} MB per upload",
+        limits.max_apply_body / (1024 * 1024),
+        limits.max_upload_body / (1024 * 1024)
+    );
+    println!(
+        "Change contents cache: {} MB",
+        libatomic::changestore::contents_cache::ContentsCache::global()
+            .metrics()
+            .capacity
+            / (1024 * 1024)
+    );
+    let storage = StorageConfig::from_env();
+    if let Some(quota) = storage.quota {
+        println!("Storage quota: {} MB per repository", quota / (1024 * 1024));
+    }
+    if let (Some(ref dir), Some(after)) = (&storage.archive_dir, storage.archive_after) {
+        println!(
+            "Archiving repositories idle for {}s to {}",
+            after.as_secs(),
+            dir.display()
+        );
+    }
+    if let Some(path) = std::env::var_os("ATOMIC_API_INSTANCE_CONFIG") {
+        let path = std::path::PathBuf::from(path);
+        println!("Instance configuration: {}", path.display());
+        api_server = api_server.with_instance_config(path);
+    }
+    api_server = api_server
+        .with_storage(storage)
+        .with_sandboxes(sandboxes)
+        .with_event_log(EventLog::from_env())
+        .with_degraded_mode(degraded)
+        .with_limits(limits);
+    let maintenance = MaintenanceConfig::from_env();
+    for (task, interval) in maintenance.schedules.iter() {
+        println!("Maintenance: {} every {}s", task, interval.as_secs());
+    }
+    api_server = api_server.with_maintenance(maintenance);
+    let exposure = ExposureConfig::from_env();
+    if let Some(ref origins) = exposure.cors.origins {
+        let origins: Vec<_> = origins
+            .iter()
+            .map(|o| o.to_str().unwrap_or("?").to_string())
+            .collect();
+        println!("CORS origins: {}", origins.join(", "));
+    }
+    if !exposure.disabled.is_empty() {
+        let disabled: Vec<_> = exposure.disabled.iter().map(|g| g.to_string()).collect();
+        println!("Disabled routes: {}", disabled.join(", "));
+    }
+    api_server = api_server.with_exposure(exposure);
+    let auth = AuthConfig::from_env()?;
+    if let Some(ref auth) = auth {
+        println!(
+            "Authentication: {} tokens, {} client certificates",
+            auth.tokens.len(),
+            auth.clients.len()
+        );
+        api_server = api_server.with_auth(auth.clone());
+    }
+    if let Some(webhooks) = WebhookConfig::from_env() {
+        let events: Vec<&str> = webhooks.events.iter().map(String::as_str).collect();
+        println!(
+            "Webhooks: {} (events: {})",
+            webhooks.urls.join(", "),
+            events.join(", ")
+        );
+        api_server = api_server.with_webhooks(webhooks);
+    }
+    #[cfg(feature = "content-index")]
+    if let Some(content_index) = ContentIndexConfig::from_env() {
+        println!("Content index: enabled");
+        api_server = api_server.with_content_index(content_index);
+    }
 
     // Create WebSocket server with configuration following AGENTS.md patterns
     let ws_config = ServerConfig::default();
-    let ws_server = WebSocketServer::new(&ws_bind_addr, ws_config);
+    let mut ws_server = WebSocketServer::new(&ws_bind_addr, ws_config);
+    if let Some(auth) = auth {
+        ws_server = ws_server.with_auth(auth);
+    }
+
+    // Terminate TLS on both servers if a certificate is configured
+    let tls = match TlsConfig::from_env() {
+        Some(config) => {
+            println!("TLS certificate: {}", config.cert_path.display());
+            if let Some(ref acme) = config.acme {
+                println!("ACME domains: {}", acme.domains.join(", "));
+            }
+            Some(Tls::start(config).await?)
+        }
+        None => None,
+    };
+    if let Some(ref tls) = tls {
+        api_server = api_server.with_tls(tls.clone());
+        ws_server = ws_server.with_tls(tls.clone());
+    }
+
+    // Drain applies and close WebSocket sessions on Ctrl-C or SIGTERM
+    let shutdown = Shutdown::new(ShutdownConfig::from_env());
+    api_server = api_server.with_shutdown(shutdown.clone());
+    ws_server = ws_server.with_shutdown(shutdown.clone());
+
+    let (http_scheme, ws_scheme) = if tls.is_some() {
+        ("https", "wss")
+    } else {
+        ("http", "ws")
+    };
 
     // Register default message handlers following AGENTS.md configuration-driven design
     let health_handler = HealthCheckHandler;
@@ -56,7 +356,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     ws_server.state().register_handler(repo_handler).await?;
 
     // Start both servers concurrently
-    let api_server_task = {
+    let mut api_server_task = {
         let bind_addr = rest_bind_addr.clone();
         tokio::spawn(async move {
             if let Err(e) = api_server.serve(&bind_addr).await {
@@ -65,29 +365,51 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         })
     };
 
-    let ws_server_task = tokio::spawn(async move {
+    let mut ws_server_task = tokio::spawn(async move {
         if let Err(e) = ws_server.start().await {
             eprintln!("WebSocket server error: {}", e);
         }
     });
 
     println!("✅ Both REST API and WebSocket servers started");
-    println!("REST API: http://{}", rest_bind_addr);
-    println!("WebSocket: ws://{}", ws_bind_addr);
+    println!("REST API: {}://{}", http_scheme, rest_bind_addr);
+    println!("WebSocket: {}://{}", ws_scheme, ws_bind_addr);
 
-    // Wait for either server to complete (or fail)
+    // Wait for a shutdown signal, or for either server to fail
+    let (mut api_done, mut ws_done) = (false, false);
     tokio::select! {
-        result = api_server_task => {
+        _ = shutdown::signal() => {
+            println!(
+                "Shutting down, draining applies for up to {}s",
+                shutdown.config().deadline.as_secs()
+            );
+        }
+        result = &mut api_server_task => {
+            api_done = true;
             if let Err(e) = result {
                 eprintln!("REST API server task failed: {}", e);
             }
         }
-        result = ws_server_task => {
+        result = &mut ws_server_task => {
+            ws_done = true;
             if let Err(e) = result {
                 eprintln!("WebSocket server task failed: {}", e);
             }
         }
     }
 
+    // Stop the other server gracefully too
+    shutdown.begin();
+    if !api_done {
+        if let Err(e) = api_server_task.await {
+            eprintln!("REST API server task failed: {}", e);
+        }
+    }
+    if !ws_done {
+        if let Err(e) = ws_server_task.await {
+            eprintln!("WebSocket server task failed: {}", e);
+        }
+    }
+
     Ok(())
 }
@@ -0,0 +1,512 @@
+//! Scheduled repository maintenance
+//!
+//! Garbage collection, integrity audits, content index rebuilds, replica
+//! revalidation and the archival of cold repositories all walk every
+//! repository under the base mount path. Rather than each running on its
+//! own timer, they are run by one orchestrator, one task at a time, each
+//! on its own schedule (`ATOMIC_API_MAINTENANCE_<TASK>`, in seconds, e.g.
+//! `ATOMIC_API_MAINTENANCE_GC=86400`; unscheduled tasks only run when
+//! triggered).
+//!
+//! A due task only starts while the server is idle: no more than
+//! `ATOMIC_API_MAINTENANCE_MAX_QUEUED` applies queued (0 by default), and
+//! not in degraded mode. A task deferred for a whole interval runs
+//! anyway, so that a busy server still gets its upkeep. The orchestrator
+//! checks for due tasks every `ATOMIC_API_MAINTENANCE_CHECK_INTERVAL`
+//! seconds (60 by default).
+//!
+//! `GET /maintenance` serves the schedule and last outcome of each task,
+//! and `POST /maintenance/<task>` runs a task now, idle or not.
+
+use crate::{admin, ApiError, ApiResult};
+use atomic_repository::lock::LockOptions;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Default interval between two checks for due tasks
+const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Tasks of the orchestrator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceTask {
+    /// Delete the change files no channel references
+    Gc,
+    /// Check that the changes of every channel are in the change store
+    Audit,
+    /// Rebuild the content index of every repository
+    Reindex,
+    /// Refresh the read replicas
+    RevalidateReplicas,
+    /// Archive the repositories not accessed for a while
+    Archive,
+}
+
+impl MaintenanceTask {
+    pub const ALL: [MaintenanceTask; 5] = [
+        MaintenanceTask::Gc,
+        MaintenanceTask::Audit,
+        MaintenanceTask::Reindex,
+        MaintenanceTask::RevalidateReplicas,
+        MaintenanceTask::Archive,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaintenanceTask::Gc => "gc",
+            MaintenanceTask::Audit => "audit",
+            MaintenanceTask::Reindex => "reindex",
+            MaintenanceTask::RevalidateReplicas => "revalidate_replicas",
+            MaintenanceTask::Archive => "archive",
+        }
+    }
+}
+
+impl std::fmt::Display for MaintenanceTask {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for MaintenanceTask {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        MaintenanceTask::ALL
+            .into_iter()
+            .find(|t| t.name() == s)
+            .ok_or_else(|| format!("Unknown maintenance task: {}", s))
+    }
+}
+
+/// Schedules of the tasks, and when the server counts as idle
+#[derive(Debug, Clone)]
+pub struct MaintenanceConfig {
+    /// Interval between two runs of each scheduled task
+    pub schedules: BTreeMap<MaintenanceTask, Duration>,
+    /// Queued applies up to which the server is idle
+    pub max_queued: usize,
+    /// Interval between two checks for due tasks
+    pub check_interval: Duration,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        MaintenanceConfig {
+            schedules: BTreeMap::new(),
+            max_queued: 0,
+            check_interval: DEFAULT_CHECK_INTERVAL,
+        }
+    }
+}
+
+impl MaintenanceConfig {
+    /// Read the configuration from `ATOMIC_API_MAINTENANCE_<TASK>` (e.g.
+    /// `ATOMIC_API_MAINTENANCE_REVALIDATE_REPLICAS`),
+    /// `ATOMIC_API_MAINTENANCE_MAX_QUEUED` and
+    /// `ATOMIC_API_MAINTENANCE_CHECK_INTERVAL`, in seconds.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(var: &str) -> Option<T> {
+            std::env::var(var).ok().and_then(|s| s.parse().ok())
+        }
+        let schedules = MaintenanceTask::ALL
+            .into_iter()
+            .filter_map(|task| {
+                let name = format!("ATOMIC_API_MAINTENANCE_{}", task.name().to_uppercase());
+                var(&name)
+                    .filter(|&s: &u64| s > 0)
+                    .map(|s| (task, Duration::from_secs(s)))
+            })
+            .collect();
+        MaintenanceConfig {
+            schedules,
+            max_queued: var("ATOMIC_API_MAINTENANCE_MAX_QUEUED").unwrap_or(0),
+            check_interval: var("ATOMIC_API_MAINTENANCE_CHECK_INTERVAL")
+                .filter(|&s: &u64| s > 0)
+                .map_or(DEFAULT_CHECK_INTERVAL, Duration::from_secs),
+        }
+    }
+
+    /// Schedule `task` every `interval`, unless it is already scheduled
+    pub fn or_schedule(mut self, task: MaintenanceTask, interval: Duration) -> Self {
+        self.schedules.entry(task).or_insert(interval);
+        self
+    }
+}
+
+/// Status of a task, served by `GET /maintenance`
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskStatus {
+    pub task: MaintenanceTask,
+    /// Seconds between two runs, if scheduled
+    pub schedule: Option<u64>,
+    pub running: bool,
+    pub last_started: Option<DateTime<Utc>>,
+    pub last_finished: Option<DateTime<Utc>>,
+    /// Summary of the last run, or its error
+    pub last_outcome: Option<String>,
+    pub runs: u64,
+    pub failures: u64,
+    /// Checks at which the task was due, but the server wasn't idle
+    pub deferred: u64,
+    /// Seconds until the task is due, if scheduled
+    pub next_run: Option<u64>,
+}
+
+/// Status of the orchestrator, served by `GET /maintenance`
+#[derive(Debug, Clone, Serialize)]
+pub struct MaintenanceStatus {
+    /// Whether the server was idle at the last check
+    pub idle: bool,
+    /// Task currently running, if any
+    pub running: Option<MaintenanceTask>,
+    pub tasks: Vec<TaskStatus>,
+}
+
+#[derive(Default)]
+struct TaskState {
+    next: Option<Instant>,
+    last_started: Option<DateTime<Utc>>,
+    last_finished: Option<DateTime<Utc>>,
+    last_outcome: Option<String>,
+    runs: u64,
+    failures: u64,
+    deferred: u64,
+}
+
+struct State {
+    running: Option<MaintenanceTask>,
+    idle: bool,
+    tasks: BTreeMap<MaintenanceTask, TaskState>,
+}
+
+type Runner = Arc<dyn Fn(MaintenanceTask) -> ApiResult<String> + Send + Sync>;
+type IdleCheck = Arc<dyn Fn() -> bool + Send + Sync>;
+
+/// The orchestrator, running `runner` for each due task
+#[derive(Clone)]
+pub struct Maintenance {
+    config: Arc<MaintenanceConfig>,
+    state: Arc<Mutex<State>>,
+    runner: Runner,
+    idle: IdleCheck,
+}
+
+impl Maintenance {
+    /// An orchestrator running the tasks with `runner`, while `idle`
+    /// returns `true`. Scheduled tasks are due at once.
+    pub fn new(
+        config: MaintenanceConfig,
+        runner: impl Fn(MaintenanceTask) -> ApiResult<String> + Send + Sync + 'static,
+        idle: impl Fn() -> bool + Send + Sync + 'static,
+    ) -> Self {
+        let now = Instant::now();
+        let tasks = MaintenanceTask::ALL
+            .into_iter()
+            .map(|task| {
+                let state = TaskState {
+                    next: config.schedules.get(&task).map(|_| now),
+                    ..TaskState::default()
+                };
+                (task, state)
+            })
+            .collect();
+        Maintenance {
+            config: Arc::new(config),
+            state: Arc::new(Mutex::new(State {
+                running: None,
+                idle: true,
+                tasks,
+            })),
+            runner: Arc::new(runner),
+            idle: Arc::new(idle),
+        }
+    }
+
+    pub fn config(&self) -> &MaintenanceConfig {
+        &self.config
+    }
+
+    /// The next task to run at `now`, marked as running. Due tasks are
+    /// deferred while the server isn't idle, for up to one interval.
+    fn due(&self, now: Instant) -> Option<MaintenanceTask> {
+        let mut state = self.state.lock().unwrap();
+        if state.running.is_some() {
+            return None;
+        }
+        let mut idle = None;
+        for (&task, &interval) in self.config.schedules.iter() {
+            let task_state = state.tasks.get_mut(&task).unwrap();
+            let next = match task_state.next {
+                Some(next) if next <= now => next,
+                _ => continue,
+            };
+            let idle = *idle.get_or_insert_with(|| (self.idle)());
+            if idle || now >= next + interval {
+                state.idle = idle;
+                state.running = Some(task);
+                return Some(task);
+            }
+            task_state.deferred += 1;
+        }
+        if let Some(idle) = idle {
+            state.idle = idle;
+        }
+        None
+    }
+
+    /// Mark `task` as running, unless another task is.
+    fn begin(&self, task: MaintenanceTask) -> ApiResult<()> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(running) = state.running {
+            return Err(ApiError::busy(format!(
+                "Maintenance task {} is running",
+                running
+            )));
+        }
+        state.running = Some(task);
+        Ok(())
+    }
+
+    /// Run `task`, already marked as running, and record its outcome.
+    /// This blocks for the whole run.
+    fn run(&self, task: MaintenanceTask) {
+        self.state
+            .lock()
+            .unwrap()
+            .tasks
+            .get_mut(&task)
+            .unwrap()
+            .last_started = Some(Utc::now());
+        info!("Running maintenance task {}", task);
+        let result = (self.runner)(task);
+        let mut state = self.state.lock().unwrap();
+        state.running = None;
+        let task_state = state.tasks.get_mut(&task).unwrap();
+        task_state.last_finished = Some(Utc::now());
+        task_state.runs += 1;
+        task_state.last_outcome = Some(match result {
+            Ok(outcome) => {
+                info!("Maintenance task {}: {}", task, outcome);
+                outcome
+            }
+            Err(e) => {
+                warn!("Maintenance task {} failed: {}", task, e);
+                task_state.failures += 1;
+                e.to_string()
+            }
+        });
+        if let Some(interval) = self.config.schedules.get(&task) {
+            task_state.next = Some(Instant::now() + *interval);
+        }
+    }
+
+    /// Run `task` now in the background, whether the server is idle or
+    /// not. Fails if another task is running.
+    pub fn trigger(&self, task: MaintenanceTask) -> ApiResult<TaskStatus> {
+        self.begin(task)?;
+        let this = self.clone();
+        tokio::task::spawn_blocking(move || this.run(task));
+        Ok(self.task_status(task))
+    }
+
+    /// Run the due tasks until the server stops
+    pub fn start(&self) {
+        let this = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(this.config.check_interval);
+            loop {
+                interval.tick().await;
+                let Some(task) = this.due(Instant::now()) else {
+                    continue;
+                };
+                let run = this.clone();
+                if let Err(e) = tokio::task::spawn_blocking(move || run.run(task)).await {
+                    warn!("Maintenance task {} panicked: {}", task, e);
+                    this.state.lock().unwrap().running = None;
+                }
+            }
+        });
+    }
+
+    fn task_status(&self, task: MaintenanceTask) -> TaskStatus {
+        let state = self.state.lock().unwrap();
+        status_of(&state, &self.config, task, Instant::now())
+    }
+
+    pub fn status(&self) -> MaintenanceStatus {
+        let state = self.state.lock().unwrap();
+        let now = Instant::now();
+        MaintenanceStatus {
+            idle: state.idle,
+            running: state.running,
+            tasks: MaintenanceTask::ALL
+                .into_iter()
+                .map(|task| status_of(&state, &self.config, task, now))
+                .collect(),
+        }
+    }
+}
+
+fn status_of(
+    state: &State,
+    config: &MaintenanceConfig,
+    task: MaintenanceTask,
+    now: Instant,
+) -> TaskStatus {
+    let task_state = &state.tasks[&task];
+    TaskStatus {
+        task,
+        schedule: config.schedules.get(&task).map(|d| d.as_secs()),
+        running: state.running == Some(task),
+        last_started: task_state.last_started,
+        last_finished: task_state.last_finished,
+        last_outcome: task_state.last_outcome.clone(),
+        runs: task_state.runs,
+        failures: task_state.failures,
+        deferred: task_state.deferred,
+        next_run: task_state
+            .next
+            .map(|next| next.saturating_duration_since(now).as_secs()),
+    }
+}
+
+/// Collect the garbage of the repositories under `base_mount_path`,
+/// skipping the ones locked by another operation
+pub fn gc_all(base_mount_path: &Path) -> ApiResult<String> {
+    let (mut collected, mut bytes, mut skipped) = (0, 0, 0);
+    for relative in crate::replica::repositories(base_mount_path)? {
+        let repo_path = base_mount_path.join(&relative);
+        let report = match admin::gc(&repo_path, true, &LockOptions::no_wait()) {
+            Ok(report) => report,
+            Err(ApiError::Locked { message }) => {
+                info!("Not collecting {}: {}", relative.display(), message);
+                skipped += 1;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        collected += report.unreferenced.len();
+        bytes += report.bytes;
+    }
+    Ok(format!(
+        "deleted {} change files ({} bytes), skipped {} locked repositories",
+        collected, bytes, skipped
+    ))
+}
+
+/// Verify the repositories under `base_mount_path`, failing if any has
+/// missing changes
+pub fn audit_all(base_mount_path: &Path) -> ApiResult<String> {
+    let mut broken = Vec::new();
+    let repositories = crate::replica::repositories(base_mount_path)?;
+    for relative in repositories.iter() {
+        let report = admin::verify_repository(&base_mount_path.join(relative))?;
+        if !report.is_ok() {
+            warn!("Repository {} failed its audit", relative.display());
+            broken.push(relative.display().to_string());
+        }
+    }
+    if broken.is_empty() {
+        Ok(format!("verified {} repositories", repositories.len()))
+    } else {
+        Err(ApiError::internal(format!(
+            "{} of {} repositories failed their audit: {}",
+            broken.len(),
+            repositories.len(),
+            broken.join(", ")
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn orchestrator(idle: Arc<AtomicBool>) -> Maintenance {
+        let config = MaintenanceConfig::default()
+            .or_schedule(MaintenanceTask::Gc, Duration::from_secs(60))
+            .or_schedule(MaintenanceTask::Audit, Duration::from_secs(600));
+        Maintenance::new(
+            config,
+            |task| match task {
+                MaintenanceTask::Audit => Err(ApiError::internal("broken")),
+                _ => Ok("done".to_string()),
+            },
+            move || idle.load(Ordering::Relaxed),
+        )
+    }
+
+    #[test]
+    fn test_task_names() {
+        for task in MaintenanceTask::ALL {
+            assert_eq!(task.name().parse::<MaintenanceTask>(), Ok(task));
+        }
+        assert!("defrag".parse::<MaintenanceTask>().is_err());
+    }
+
+    #[test]
+    fn test_schedule() {
+        let maintenance = orchestrator(Arc::new(AtomicBool::new(true)));
+        let now = Instant::now();
+        assert_eq!(maintenance.due(now), Some(MaintenanceTask::Gc));
+        // One task at a time
+        assert_eq!(maintenance.due(now), None);
+        maintenance.run(MaintenanceTask::Gc);
+        assert_eq!(maintenance.due(now), Some(MaintenanceTask::Audit));
+        maintenance.run(MaintenanceTask::Audit);
+        assert_eq!(maintenance.due(now), None);
+
+        let status = maintenance.status();
+        assert_eq!(status.running, None);
+        let gc = &status.tasks[0];
+        assert_eq!((gc.runs, gc.failures), (1, 0));
+        assert_eq!(gc.last_outcome.as_deref(), Some("done"));
+        assert!(gc.next_run.unwrap() > 0);
+        let audit = &status.tasks[1];
+        assert_eq!((audit.runs, audit.failures), (1, 1));
+        // Unscheduled tasks never come due
+        assert_eq!(status.tasks[2].schedule, None);
+        assert_eq!(status.tasks[2].next_run, None);
+
+        assert_eq!(
+            maintenance.due(now + Duration::from_secs(61)),
+            Some(MaintenanceTask::Gc)
+        );
+    }
+
+    #[test]
+    fn test_deferred_while_busy() {
+        let idle = Arc::new(AtomicBool::new(false));
+        let maintenance = orchestrator(idle.clone());
+        let now = Instant::now();
+        assert_eq!(maintenance.due(now), None);
+        assert_eq!(maintenance.status().tasks[0].deferred, 1);
+        assert!(!maintenance.status().idle);
+        // Overdue by a whole interval
+        assert_eq!(
+            maintenance.due(now + Duration::from_secs(60)),
+            Some(MaintenanceTask::Gc)
+        );
+        maintenance.run(MaintenanceTask::Gc);
+        idle.store(true, Ordering::Relaxed);
+        assert_eq!(maintenance.due(now), Some(MaintenanceTask::Audit));
+    }
+
+    #[test]
+    fn test_begin_while_running() {
+        let maintenance = orchestrator(Arc::new(AtomicBool::new(true)));
+        maintenance.begin(MaintenanceTask::Reindex).unwrap();
+        assert!(maintenance.begin(MaintenanceTask::Gc).is_err());
+        assert_eq!(maintenance.due(Instant::now()), None);
+        assert_eq!(maintenance.status().running, Some(MaintenanceTask::Reindex));
+        maintenance.run(MaintenanceTask::Reindex);
+        assert!(maintenance.begin(MaintenanceTask::Gc).is_ok());
+    }
+}
@@ -2,18 +2,40 @@
 //!
 //! Provides basic WebSocket message infrastructure that can be extended by configuration.
 //! Workflow definitions and states will be loaded from configuration, not defined in code.
+//!
+//! Envelopes carry the version of the protocol they are written in.
+//! Clients open a connection with a [`HelloMessage`] listing the highest
+//! version and the features they understand, and the server answers with
+//! a [`WelcomeMessage`] stating the negotiated version and features. Message
+//! types gated by a feature are only exchanged on connections that
+//! negotiated it, so that new event types don't break older consumers.
+//! Clients that don't say hello get version 1 and no features.
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use uuid::Uuid;
 
 /// Result type for message handling operations
 pub type MessageResult<T> = Result<T, MessageError>;
 
+/// Version of the protocol spoken by this server
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Oldest version of the protocol still accepted
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Version of envelopes without one, written before versioning
+fn legacy_version() -> u32 {
+    1
+}
+
 /// Base message envelope for all WebSocket communication following AGENTS.md patterns
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
+    /// Version of the protocol the message is written in
+    #[serde(default = "legacy_version")]
+    pub version: u32,
     /// Unique message identifier
     pub id: Uuid,
     /// Message timestamp
@@ -32,6 +54,7 @@ impl Message {
     /// Factory method following AGENTS.md factory patterns
     pub fn new(payload: MessagePayload) -> Self {
         Self {
+            version: PROTOCOL_VERSION,
             id: Uuid::new_v4(),
             timestamp: Utc::now(),
             sender: None,
@@ -57,6 +80,11 @@ impl Message {
         self
     }
 
+    pub fn with_version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
     /// Create a reply message following AGENTS.md patterns
     pub fn reply(&self, payload: MessagePayload) -> Self {
         Message::new(payload)
@@ -69,6 +97,10 @@ impl Message {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", content = "data")]
 pub enum MessagePayload {
+    // Handshake
+    Hello(HelloMessage),
+    Welcome(WelcomeMessage),
+
     // System Messages
     HealthCheck,
     HealthStatus(HealthStatusMessage),
@@ -100,6 +132,55 @@ pub enum MessagePayload {
     Broadcast(BroadcastMessage),
 }
 
+/// First message of a client: what it understands
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HelloMessage {
+    /// Highest protocol version the client speaks
+    pub version: u32,
+    /// Features the client wants, e.g. new event types
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// Client name and version, for logs
+    #[serde(default)]
+    pub client: Option<String>,
+}
+
+/// Answer to a [`HelloMessage`]: what the connection uses from now on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WelcomeMessage {
+    /// Negotiated protocol version
+    pub version: u32,
+    /// Features enabled on the connection, requested by the client and
+    /// supported by the server
+    pub features: Vec<String>,
+    /// All the features supported by the server
+    pub server_features: Vec<String>,
+    pub server_version: String,
+}
+
+/// What a connection negotiated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub version: u32,
+    pub features: BTreeSet<String>,
+}
+
+impl Default for Capabilities {
+    /// Capabilities of clients that didn't say hello
+    fn default() -> Self {
+        Capabilities {
+            version: legacy_version(),
+            features: BTreeSet::new(),
+        }
+    }
+}
+
+impl Capabilities {
+    pub fn has(&self, feature: &str) -> bool {
+        self.features.contains(feature)
+    }
+}
+
 /// Health status message following AGENTS.md patterns
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct HealthStatusMessage {
@@ -264,6 +345,26 @@ pub enum MessageError {
 
     #[error("Configuration error: {message}")]
     Configuration { message: String },
+
+    #[error("Unsupported protocol version {version}, expected {min} to {max}")]
+    UnsupportedVersion { version: u32, min: u32, max: u32 },
+
+    #[error("Message type {message_type} needs feature {feature}, not negotiated")]
+    FeatureNotNegotiated {
+        message_type: String,
+        feature: String,
+    },
+}
+
+impl MessageError {
+    /// Code of the error, for [`ErrorMessage::code`]
+    pub fn code(&self) -> &'static str {
+        match self {
+            MessageError::UnsupportedVersion { .. } => "UNSUPPORTED_VERSION",
+            MessageError::FeatureNotNegotiated { .. } => "FEATURE_NOT_NEGOTIATED",
+            _ => "SERVER_ERROR",
+        }
+    }
 }
 
 /// Trait for handling messages following AGENTS.md trait-based design
@@ -274,12 +375,23 @@ pub trait MessageHandler: Send + Sync + std::fmt::Debug {
 
     /// Get the message types this handler can process
     fn message_types(&self) -> Vec<String>;
+
+    /// Feature gating the message types of this handler, for handlers
+    /// added after clients started relying on the protocol. Connections
+    /// that didn't negotiate it can't use these message types.
+    fn feature(&self) -> Option<String> {
+        None
+    }
 }
 
 /// Message router for dispatching messages to appropriate handlers
 /// Following AGENTS.md composition patterns
 pub struct MessageRouter {
     handlers: HashMap<String, Box<dyn MessageHandler>>,
+    /// Feature gating each message type, if any
+    gated: HashMap<String, String>,
+    /// Features supported by the server
+    features: BTreeSet<String>,
 }
 
 impl std::fmt::Debug for MessageRouter {
@@ -287,6 +399,7 @@ impl std::fmt::Debug for MessageRouter {
         f.debug_struct("MessageRouter")
             .field("handler_count", &self.handlers.len())
             .field("handler_types", &self.handlers.keys().collect::<Vec<_>>())
+            .field("features", &self.features)
             .finish()
     }
 }
@@ -296,6 +409,70 @@ impl MessageRouter {
     pub fn new() -> Self {
         Self {
             handlers: HashMap::new(),
+            gated: HashMap::new(),
+            features: BTreeSet::new(),
+        }
+    }
+
+    /// Gate `message_type` behind `feature`, e.g. a new event type sent
+    /// by the server without a handler
+    pub fn gate(&mut self, message_type: impl Into<String>, feature: impl Into<String>) {
+        let feature = feature.into();
+        self.features.insert(feature.clone());
+        self.gated.insert(message_type.into(), feature);
+    }
+
+    /// Features supported by the server
+    pub fn features(&self) -> &BTreeSet<String> {
+        &self.features
+    }
+
+    /// Negotiate the capabilities of a connection from its hello
+    pub fn negotiate(&self, hello: &HelloMessage) -> MessageResult<Capabilities> {
+        if hello.version < MIN_PROTOCOL_VERSION {
+            return Err(MessageError::UnsupportedVersion {
+                version: hello.version,
+                min: MIN_PROTOCOL_VERSION,
+                max: PROTOCOL_VERSION,
+            });
+        }
+        Ok(Capabilities {
+            version: hello.version.min(PROTOCOL_VERSION),
+            features: hello
+                .features
+                .iter()
+                .filter(|f| self.features.contains(*f))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    /// Answer to a hello, once negotiated
+    pub fn welcome(&self, capabilities: &Capabilities) -> WelcomeMessage {
+        WelcomeMessage {
+            version: capabilities.version,
+            features: capabilities.features.iter().cloned().collect(),
+            server_features: self.features.iter().cloned().collect(),
+            server_version: crate::VERSION.to_string(),
+        }
+    }
+
+    /// Fail if `payload` can't be exchanged on a connection with
+    /// `capabilities`
+    pub fn check_enabled(
+        &self,
+        capabilities: &Capabilities,
+        payload: &MessagePayload,
+    ) -> MessageResult<()> {
+        let message_type = self.get_message_type(payload);
+        match self.gated.get(&message_type) {
+            Some(feature) if !capabilities.has(feature) => {
+                Err(MessageError::FeatureNotNegotiated {
+                    message_type,
+                    feature: feature.clone(),
+                })
+            }
+            _ => Ok(()),
         }
     }
 
@@ -305,17 +482,47 @@ impl MessageRouter {
         H: MessageHandler + 'static,
     {
         let message_types = handler.message_types();
+        let feature = handler.feature();
         let handler = Box::new(handler);
 
         // For now, each handler handles only its first message type
         // Future improvement: support multiple message types per handler
         if let Some(message_type) = message_types.first() {
+            if let Some(feature) = feature {
+                self.gate(message_type.clone(), feature);
+            }
             self.handlers.insert(message_type.clone(), handler);
         }
 
         Ok(())
     }
 
+    /// Route a message from a connection with `capabilities` to the
+    /// appropriate handler. The response is written in the negotiated
+    /// version, and dropped if the connection can't receive it.
+    pub async fn route_message_for(
+        &mut self,
+        capabilities: &Capabilities,
+        message: Message,
+    ) -> MessageResult<Option<Message>> {
+        if message.version > PROTOCOL_VERSION {
+            return Err(MessageError::UnsupportedVersion {
+                version: message.version,
+                min: MIN_PROTOCOL_VERSION,
+                max: PROTOCOL_VERSION,
+            });
+        }
+        self.check_enabled(capabilities, &message.payload)?;
+        let response = self.route_message(message).await?;
+        Ok(response.and_then(|response| {
+            if self.check_enabled(capabilities, &response.payload).is_ok() {
+                Some(response.with_version(capabilities.version))
+            } else {
+                None
+            }
+        }))
+    }
+
     /// Route a message to the appropriate handler
     pub async fn route_message(&mut self, message: Message) -> MessageResult<Option<Message>> {
         let message_type = self.get_message_type(&message.payload);
@@ -330,6 +537,8 @@ impl MessageRouter {
     /// Extract message type from payload following AGENTS.md patterns
     fn get_message_type(&self, payload: &MessagePayload) -> String {
         match payload {
+            MessagePayload::Hello(_) => "hello".to_string(),
+            MessagePayload::Welcome(_) => "welcome".to_string(),
             MessagePayload::HealthCheck => "health_check".to_string(),
             MessagePayload::HealthStatus(_) => "health_status".to_string(),
             MessagePayload::LoadWorkflows(_) => "load_workflows".to_string(),
@@ -421,4 +630,85 @@ mod tests {
         assert_eq!(message.id, deserialized.id);
         assert!(matches!(deserialized.payload, MessagePayload::Success(_)));
     }
+
+    #[derive(Debug)]
+    struct EchoHandler;
+
+    #[async_trait::async_trait]
+    impl MessageHandler for EchoHandler {
+        async fn handle_message(&mut self, message: Message) -> MessageResult<Option<Message>> {
+            Ok(Some(message.reply(message.payload.clone())))
+        }
+
+        fn message_types(&self) -> Vec<String> {
+            vec!["data_echo".to_string()]
+        }
+
+        fn feature(&self) -> Option<String> {
+            Some("echo".to_string())
+        }
+    }
+
+    fn echo() -> Message {
+        Message::new(MessagePayload::Data(DataMessage {
+            data_type: "echo".to_string(),
+            data: serde_json::json!(1),
+            metadata: HashMap::new(),
+        }))
+    }
+
+    #[test]
+    fn test_legacy_envelope() {
+        let message = Message::new(MessagePayload::HealthCheck);
+        let mut json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["version"], PROTOCOL_VERSION);
+        json.as_object_mut().unwrap().remove("version");
+        let legacy: Message = serde_json::from_value(json).unwrap();
+        assert_eq!(legacy.version, 1);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let mut router = MessageRouter::new();
+        router.register_handler(EchoHandler).unwrap();
+        router.gate("data_audit", "audit");
+
+        let hello = |version, features: &[&str]| HelloMessage {
+            version,
+            features: features.iter().map(|f| f.to_string()).collect(),
+            client: None,
+        };
+        let caps = router.negotiate(&hello(7, &["echo", "unknown"])).unwrap();
+        assert_eq!(caps.version, PROTOCOL_VERSION);
+        assert!(caps.has("echo"));
+        assert!(!caps.has("unknown"));
+        let welcome = router.welcome(&caps);
+        assert_eq!(welcome.features, ["echo"]);
+        assert_eq!(welcome.server_features, ["audit", "echo"]);
+        assert!(matches!(
+            router.negotiate(&hello(0, &[])),
+            Err(MessageError::UnsupportedVersion { version: 0, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_route_gated_message() {
+        let mut router = MessageRouter::new();
+        router.register_handler(EchoHandler).unwrap();
+
+        let legacy = Capabilities::default();
+        let err = router.route_message_for(&legacy, echo()).await.unwrap_err();
+        assert_eq!(err.code(), "FEATURE_NOT_NEGOTIATED");
+
+        let caps = Capabilities {
+            version: 1,
+            features: ["echo".to_string()].into_iter().collect(),
+        };
+        let response = router.route_message_for(&caps, echo()).await.unwrap();
+        assert_eq!(response.unwrap().version, 1);
+
+        let future = echo().with_version(PROTOCOL_VERSION + 1);
+        let err = router.route_message_for(&caps, future).await.unwrap_err();
+        assert_eq!(err.code(), "UNSUPPORTED_VERSION");
+    }
 }
@@ -0,0 +1,260 @@
+//! Pagination, sorting and field selection for list endpoints
+//!
+//! List endpoints read a [`ListQuery`] next to their own parameters:
+//!
+//! - `limit` (default 50, at most 1000) and `cursor`, the `next_cursor` of
+//!   the previous page. `offset` is still accepted when there is no cursor.
+//! - `sort=<field>` or `sort=-<field>` (descending), among the fields each
+//!   endpoint allows. Without `sort`, items come in the endpoint's natural
+//!   order, e.g. newest first for changes.
+//! - `fields=a,b,c` to only return these fields of each item.
+//!
+//! Pages are returned in a [`Page`] envelope, `{items, next_cursor,
+//! total_estimate}`. Cursors are opaque: they encode the key of the last
+//! item of the page, so that items added at the head of a list don't shift
+//! the next pages.
+
+use crate::{ApiError, ApiResult};
+use axum::http::{HeaderMap, HeaderValue};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::cmp::Ordering;
+
+/// Page size when the request has no `limit`
+pub const DEFAULT_LIMIT: usize = 50;
+/// Largest page size
+pub const MAX_LIMIT: usize = 1000;
+
+/// Query parameters shared by list endpoints
+#[derive(Debug, Default, Deserialize)]
+pub struct ListQuery {
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    pub cursor: Option<String>,
+    pub sort: Option<String>,
+    pub fields: Option<String>,
+}
+
+/// A page of items, with the cursor of the next page
+#[derive(Debug, Clone, Serialize)]
+pub struct Page {
+    pub items: Vec<Value>,
+    /// Cursor of the next page, `None` on the last page
+    pub next_cursor: Option<String>,
+    /// Number of items in the whole list. Items that can't be read when
+    /// the page is built (e.g. a change whose file is missing) are counted
+    /// but skipped, hence the estimate.
+    pub total_estimate: u64,
+}
+
+impl Page {
+    /// `X-Next-Cursor` and `X-Total-Estimate`, for endpoints that also
+    /// answer bare arrays
+    pub fn headers(&self) -> HeaderMap {
+        page_headers(self.next_cursor.as_deref(), self.total_estimate)
+    }
+}
+
+/// `X-Next-Cursor` and `X-Total-Estimate` of a page, for pages streamed
+/// before their items are read
+pub fn page_headers(next_cursor: Option<&str>, total_estimate: u64) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    if let Some(cursor) = next_cursor {
+        if let Ok(v) = HeaderValue::from_str(cursor) {
+            headers.insert("X-Next-Cursor", v);
+        }
+    }
+    headers.insert("X-Total-Estimate", HeaderValue::from(total_estimate));
+    headers
+}
+
+/// Encode the key of an item as a cursor
+pub fn encode_cursor(key: &str) -> String {
+    URL_SAFE_NO_PAD.encode(key)
+}
+
+impl ListQuery {
+    pub fn limit(&self) -> usize {
+        self.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT)
+    }
+
+    /// Key of the last item of the previous page
+    pub fn after(&self) -> ApiResult<Option<String>> {
+        let Some(ref cursor) = self.cursor else {
+            return Ok(None);
+        };
+        URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|key| String::from_utf8(key).ok())
+            .map(Some)
+            .ok_or_else(|| ApiError::invalid_query(format!("Invalid cursor: {}", cursor)))
+    }
+
+    /// Requested sort field, and whether it is descending. `sortable` is
+    /// the list of fields this endpoint can sort on.
+    pub fn sort(&self, sortable: &[&str]) -> ApiResult<Option<(String, bool)>> {
+        let Some(ref sort) = self.sort else {
+            return Ok(None);
+        };
+        let (field, descending) = match sort.strip_prefix('-') {
+            Some(field) => (field, true),
+            None => (sort.as_str(), false),
+        };
+        if !sortable.contains(&field) {
+            return Err(ApiError::invalid_query(format!(
+                "Cannot sort on {:?}, expected one of: {}",
+                field,
+                sortable.join(", ")
+            )));
+        }
+        Ok(Some((field.to_string(), descending)))
+    }
+
+    /// Sort `items` as requested, and return the page after the cursor.
+    /// `key` identifies items in cursors, and must be unique.
+    pub fn page<T: Serialize>(
+        &self,
+        items: Vec<T>,
+        key: impl Fn(&T) -> String,
+        sortable: &[&str],
+    ) -> ApiResult<Page> {
+        let sort = self.sort(sortable)?;
+        let after = self.after()?;
+        let mut items = items
+            .into_iter()
+            .map(|item| Ok((key(&item), to_value(&item)?)))
+            .collect::<ApiResult<Vec<(String, Value)>>>()?;
+        if let Some((field, descending)) = sort {
+            items.sort_by(|(_, a), (_, b)| {
+                let o = compare(&a[&field], &b[&field]);
+                if descending {
+                    o.reverse()
+                } else {
+                    o
+                }
+            });
+        }
+        let total_estimate = items.len() as u64;
+        let start = match after {
+            Some(after) => {
+                items.iter().position(|(k, _)| *k == after).ok_or_else(|| {
+                    ApiError::invalid_query("Cursor does not match any item".to_string())
+                })? + 1
+            }
+            None => self.offset.unwrap_or(0).min(items.len()),
+        };
+        let end = (start + self.limit()).min(items.len());
+        let next_cursor = if end < items.len() && end > start {
+            Some(encode_cursor(&items[end - 1].0))
+        } else {
+            None
+        };
+        let items = items.drain(start..end).map(|(_, v)| v).collect();
+        Ok(self.page_of(items, next_cursor, total_estimate))
+    }
+
+    /// Build a page from items already paginated by the endpoint, keeping
+    /// the requested fields.
+    pub fn page_of(
+        &self,
+        items: Vec<Value>,
+        next_cursor: Option<String>,
+        total_estimate: u64,
+    ) -> Page {
+        Page {
+            items: items.into_iter().map(|v| self.select(v)).collect(),
+            next_cursor,
+            total_estimate,
+        }
+    }
+
+    /// Keep only the requested fields of `value`
+    pub fn select(&self, value: Value) -> Value {
+        match (&self.fields, value) {
+            (Some(fields), Value::Object(mut object)) => {
+                let fields: Vec<&str> = fields.split(',').map(str::trim).collect();
+                object.retain(|k, _| fields.contains(&k.as_str()));
+                Value::Object(object)
+            }
+            (_, value) => value,
+        }
+    }
+}
+
+pub fn to_value<T: Serialize>(item: &T) -> ApiResult<Value> {
+    serde_json::to_value(item)
+        .map_err(|e| ApiError::internal(format!("Failed to serialize item: {}", e)))
+}
+
+/// Order of two JSON values of the same field: numbers and strings in
+/// their natural order, missing values and nulls first.
+fn compare(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Null, Value::Null) => Ordering::Equal,
+        (Value::Null, _) => Ordering::Less,
+        (_, Value::Null) => Ordering::Greater,
+        _ => Ordering::Equal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn query(q: &str) -> ListQuery {
+        serde_json::from_str(q).unwrap()
+    }
+
+    fn items() -> Vec<Value> {
+        (0..5)
+            .map(|i| json!({"name": format!("c{}", i), "size": (i * 7) % 5}))
+            .collect()
+    }
+
+    fn key(v: &Value) -> String {
+        v["name"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_cursor_pagination() {
+        let first = query(r#"{"limit": 2}"#).page(items(), key, &[]).unwrap();
+        assert_eq!(first.total_estimate, 5);
+        assert_eq!(first.items, items()[..2].to_vec());
+        let mut q = query(r#"{"limit": 2}"#);
+        q.cursor = first.next_cursor;
+        let second = q.page(items(), key, &[]).unwrap();
+        assert_eq!(second.items, items()[2..4].to_vec());
+        q.cursor = second.next_cursor;
+        let last = q.page(items(), key, &[]).unwrap();
+        assert_eq!(last.items, items()[4..].to_vec());
+        assert!(last.next_cursor.is_none());
+
+        let mut q = query("{}");
+        q.cursor = Some(encode_cursor("missing"));
+        assert!(q.page(items(), key, &[]).is_err());
+    }
+
+    #[test]
+    fn test_sort_and_fields() {
+        let page = query(r#"{"sort": "-size", "fields": "name"}"#)
+            .page(items(), key, &["size"])
+            .unwrap();
+        let names: Vec<_> = page.items.iter().map(key).collect();
+        assert_eq!(names, vec!["c2", "c4", "c1", "c3", "c0"]);
+        assert_eq!(page.items[0], json!({"name": "c2"}));
+
+        assert!(query(r#"{"sort": "name"}"#)
+            .page(items(), key, &["size"])
+            .is_err());
+    }
+}
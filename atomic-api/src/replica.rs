@@ -0,0 +1,280 @@
+//! Read replicas for the read-only endpoints
+//!
+//! Opening a pristine takes an exclusive lock on it, so concurrent reads of
+//! the same repository are serialised behind each other and behind pushes.
+//! The changes and changelist endpoints can instead read from a snapshot of
+//! the pristine, stored under a replica root with the same
+//! `tenant/portfolio/project` layout as the base mount path:
+//!
+//! ```text
+//! <replica root>/<tenant_id>/<portfolio_id>/<project_id>/pristine/db
+//! ```
+//!
+//! Snapshots are written either by the replication subsystem or by
+//! [`ReplicaSet::refresh`], and are never written to by the server, so they
+//! are opened without a lock. Change files are content-addressed and
+//! immutable, so they are still read from the primary repository. Snapshots
+//! older than the configured maximum staleness are ignored, and mutations
+//! always go to the primary.
+
+use atomic_repository::lock::{Lock, LockOptions};
+use atomic_repository::Repository;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use libatomic::pristine::sanakirja::Pristine;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, info, warn};
+
+/// Header naming the source of a read, `primary` or `replica`
+pub const READ_SOURCE_HEADER: &str = "x-atomic-read-source";
+/// Header with the age of the replica snapshot, in seconds
+pub const REPLICA_AGE_HEADER: &str = "x-atomic-replica-age";
+
+/// Default maximum age of a snapshot before reads fall back to the primary
+const DEFAULT_MAX_STALENESS: Duration = Duration::from_secs(30);
+
+/// Configuration of the read replicas
+#[derive(Debug, Clone)]
+pub struct ReplicaConfig {
+    /// Directory holding the pristine snapshots
+    pub root: PathBuf,
+    /// Maximum age of a snapshot that can still serve reads
+    pub max_staleness: Duration,
+    /// Interval at which the server refreshes the snapshots itself, if any
+    pub refresh_interval: Option<Duration>,
+}
+
+impl ReplicaConfig {
+    /// Read the configuration from `ATOMIC_API_REPLICA_DIR`,
+    /// `ATOMIC_API_REPLICA_MAX_STALENESS` and `ATOMIC_API_REPLICA_REFRESH`
+    /// (both in seconds). Replicas are disabled if the directory isn't set.
+    pub fn from_env() -> Option<Self> {
+        let root = std::env::var_os("ATOMIC_API_REPLICA_DIR")?;
+        let secs = |var: &str| {
+            std::env::var(var)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .map(Duration::from_secs)
+        };
+        Some(ReplicaConfig {
+            root: root.into(),
+            max_staleness: secs("ATOMIC_API_REPLICA_MAX_STALENESS")
+                .unwrap_or(DEFAULT_MAX_STALENESS),
+            refresh_interval: secs("ATOMIC_API_REPLICA_REFRESH"),
+        })
+    }
+}
+
+/// Where a read was served from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadSource {
+    Primary,
+    Replica {
+        /// Age of the snapshot when it was opened
+        age: Duration,
+    },
+}
+
+impl ReadSource {
+    /// Headers telling clients where the response was read from, and how
+    /// stale it may be.
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        match self {
+            ReadSource::Primary => {
+                headers.insert(
+                    HeaderName::from_static(READ_SOURCE_HEADER),
+                    HeaderValue::from_static("primary"),
+                );
+            }
+            ReadSource::Replica { age } => {
+                headers.insert(
+                    HeaderName::from_static(READ_SOURCE_HEADER),
+                    HeaderValue::from_static("replica"),
+                );
+                headers.insert(
+                    HeaderName::from_static(REPLICA_AGE_HEADER),
+                    HeaderValue::from(age.as_secs()),
+                );
+            }
+        }
+        headers
+    }
+}
+
+/// The set of pristine snapshots under a replica root
+#[derive(Debug, Clone)]
+pub struct ReplicaSet {
+    config: ReplicaConfig,
+}
+
+impl ReplicaSet {
+    pub fn new(config: ReplicaConfig) -> Self {
+        ReplicaSet { config }
+    }
+
+    pub fn config(&self) -> &ReplicaConfig {
+        &self.config
+    }
+
+    /// Path of the snapshot of the repository at `relative` (relative to
+    /// the base mount path)
+    pub fn snapshot_path(&self, relative: &Path) -> PathBuf {
+        self.config
+            .root
+            .join(relative)
+            .join(atomic_repository::PRISTINE_DIR)
+            .join("db")
+    }
+
+    /// Age of the snapshot of `relative`, if there is one
+    pub fn age(&self, relative: &Path) -> Option<Duration> {
+        let modified = std::fs::metadata(self.snapshot_path(relative))
+            .and_then(|m| m.modified())
+            .ok()?;
+        Some(
+            SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Open the repository at `repo_path` for reading, from its snapshot if
+    /// there is a fresh enough one, and from the primary otherwise.
+    pub fn open_for_read(
+        &self,
+        repo_path: &Path,
+        relative: &Path,
+    ) -> Result<(Repository, ReadSource), anyhow::Error> {
+        if let Some(age) = self.age(relative) {
+            if age <= self.config.max_staleness {
+                // Snapshots are only ever replaced by renaming a new file
+                // over them, never written in place, so no lock is needed.
+                match unsafe { Pristine::new_nolock(self.snapshot_path(relative)) } {
+                    Ok(pristine) => {
+                        debug!(
+                            "Reading {} from replica ({:?} old)",
+                            relative.display(),
+                            age
+                        );
+                        let repository = Repository::find_root_with_pristine(
+                            Some(repo_path.to_path_buf()),
+                            pristine,
+                        )?;
+                        return Ok((repository, ReadSource::Replica { age }));
+                    }
+                    Err(e) => warn!("Failed to open replica of {}: {}", relative.display(), e),
+                }
+            } else {
+                debug!(
+                    "Replica of {} is stale ({:?} old), reading from primary",
+                    relative.display(),
+                    age
+                );
+            }
+        }
+        let repository = Repository::find_root(Some(repo_path.to_path_buf()))?;
+        Ok((repository, ReadSource::Primary))
+    }
+
+    /// Replace the snapshot of `relative` with a copy of the primary
+    /// pristine of the repository at `repo_path`. The repository is locked
+    /// during the copy, so the snapshot is consistent.
+    pub fn refresh(&self, repo_path: &Path, relative: &Path) -> Result<(), anyhow::Error> {
+        let snapshot = self.snapshot_path(relative);
+        let dir = snapshot.parent().unwrap();
+        std::fs::create_dir_all(dir)?;
+        let tmp = tempfile::NamedTempFile::new_in(dir)?;
+
+        let dot_dir = repo_path.join(libatomic::DOT_DIR);
+        let primary = dot_dir.join(atomic_repository::PRISTINE_DIR).join("db");
+        {
+            // A read transaction doesn't stop writers from reusing the
+            // pages it doesn't read, so the copy is made under the lock
+            // of the repository, as for backups.
+            let _lock = Lock::new(dot_dir.join(atomic_repository::LOCK_FILE))
+                .acquire("api replica", &LockOptions::from_env())?;
+            std::fs::copy(&primary, tmp.path())?;
+        }
+
+        tmp.persist(&snapshot)?;
+        debug!("Refreshed replica {}", snapshot.display());
+        Ok(())
+    }
+
+    /// Refresh the snapshots of all the repositories under `base_mount_path`
+    pub fn refresh_all(&self, base_mount_path: &Path) -> Result<usize, anyhow::Error> {
+        let mut n = 0;
+        for relative in repositories(base_mount_path)? {
+            match self.refresh(&base_mount_path.join(&relative), &relative) {
+                Ok(()) => n += 1,
+                Err(e) => warn!("Failed to refresh replica of {}: {}", relative.display(), e),
+            }
+        }
+        info!("Refreshed {} replicas", n);
+        Ok(n)
+    }
+}
+
+/// Paths of the repositories under `base_mount_path`, relative to it
+pub(crate) fn repositories(base_mount_path: &Path) -> Result<Vec<PathBuf>, std::io::Error> {
+    let mut paths = vec![PathBuf::new()];
+    // tenant, portfolio and project
+    for _ in 0..3 {
+        let mut next = Vec::new();
+        for p in paths {
+            for entry in std::fs::read_dir(base_mount_path.join(&p))? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    next.push(p.join(entry.file_name()))
+                }
+            }
+        }
+        paths = next
+    }
+    paths.retain(|p| base_mount_path.join(p).join(libatomic::DOT_DIR).is_dir());
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libatomic::TxnT;
+
+    #[test]
+    fn test_reads_fall_back_to_primary() {
+        let base = tempfile::tempdir().unwrap();
+        let replicas = tempfile::tempdir().unwrap();
+        let relative = Path::new("tenant/portfolio/project");
+        let repo =
+            crate::admin::create_repository(base.path(), "tenant", "portfolio", "project").unwrap();
+        let set = ReplicaSet::new(ReplicaConfig {
+            root: replicas.path().to_path_buf(),
+            max_staleness: Duration::from_secs(60),
+            refresh_interval: None,
+        });
+
+        // No snapshot yet
+        let (_, source) = set.open_for_read(&repo, relative).unwrap();
+        assert_eq!(source, ReadSource::Primary);
+
+        assert_eq!(set.refresh_all(base.path()).unwrap(), 1);
+        let (repository, source) = set.open_for_read(&repo, relative).unwrap();
+        assert!(matches!(source, ReadSource::Replica { .. }));
+        let txn = repository.pristine.txn_begin().unwrap();
+        assert!(txn
+            .load_channel(libatomic::DEFAULT_CHANNEL)
+            .unwrap()
+            .is_some());
+        assert_eq!(source.headers()[READ_SOURCE_HEADER], "replica");
+
+        // Stale snapshots are ignored
+        let set = ReplicaSet::new(ReplicaConfig {
+            max_staleness: Duration::ZERO,
+            ..set.config().clone()
+        });
+        std::thread::sleep(Duration::from_millis(10));
+        let (_, source) = set.open_for_read(&repo, relative).unwrap();
+        assert_eq!(source, ReadSource::Primary);
+    }
+}
@@ -0,0 +1,117 @@
+//! Resolution of change hash prefixes
+//!
+//! The CLI and the UIs show changes by short prefixes of their hashes.
+//! `GET .../code/resolve?prefix=ABC123` resolves a prefix to the full
+//! hash like `atomic` does (`hash_from_prefix`), among the changes known
+//! to the pristine. A prefix matching several changes is rejected with an
+//! `ambiguous_prefix` error listing the changes of the channels it
+//! matches, so that the caller can show a longer prefix.
+//!
+//! The endpoints taking a `:change_id` accept prefixes as well, through
+//! [`resolve`]: full hashes are parsed without opening the pristine.
+
+use crate::error::RepositoryError;
+use crate::{ApiError, ApiResult};
+use atomic_repository::Repository;
+use libatomic::pristine::{Base32, Hash, HashPrefixError};
+use libatomic::TxnT;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Candidates listed in an ambiguity error, at most
+pub const MAX_CANDIDATES: usize = 10;
+
+/// Query parameters of `GET .../code/resolve`
+#[derive(Debug, Deserialize)]
+pub struct ResolveQuery {
+    pub prefix: String,
+}
+
+/// A resolved prefix
+#[derive(Debug, Serialize)]
+pub struct Resolved {
+    pub prefix: String,
+    pub hash: String,
+}
+
+/// The hash of the change `change_id`, a full hash or a prefix of the
+/// hash of a change of `repository`
+pub fn resolve(repository: &Repository, change_id: &str) -> ApiResult<Hash> {
+    if let Some(hash) = Hash::from_base32(change_id.as_bytes()) {
+        return Ok(hash);
+    }
+    let txn = repository
+        .pristine
+        .txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+    match txn.hash_from_prefix(change_id) {
+        Ok((hash, _)) => Ok(hash),
+        Err(HashPrefixError::Parse(_)) | Err(HashPrefixError::NotFound(_)) => {
+            Err(ApiError::Repository(RepositoryError::ChangeNotFound {
+                change_id: change_id.to_string(),
+            }))
+        }
+        Err(HashPrefixError::Ambiguous(_)) => {
+            drop(txn);
+            let changes = crate::admin::channel_changes(repository)?;
+            Err(ApiError::Repository(RepositoryError::AmbiguousPrefix {
+                prefix: change_id.to_string(),
+                candidates: candidates(change_id, changes.iter().map(|(h, _, _)| h)),
+            }))
+        }
+        Err(HashPrefixError::Txn(e)) => Err(ApiError::internal(format!(
+            "Failed to resolve {}: {}",
+            change_id, e
+        ))),
+    }
+}
+
+/// Like [`resolve`], opening the repository at `repo_path` only to
+/// resolve prefixes
+pub fn resolve_at(repo_path: &Path, change_id: &str) -> ApiResult<Hash> {
+    if let Some(hash) = Hash::from_base32(change_id.as_bytes()) {
+        return Ok(hash);
+    }
+    let repository = Repository::find_root(Some(repo_path.to_path_buf()))
+        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+    resolve(&repository, change_id)
+}
+
+/// The hashes of `hashes` starting with `prefix`, sorted, at most
+/// [`MAX_CANDIDATES`]
+fn candidates<'a>(prefix: &str, hashes: impl Iterator<Item = &'a Hash>) -> Vec<String> {
+    let mut candidates: Vec<String> = hashes
+        .map(|h| h.to_base32())
+        .filter(|h| h.starts_with(prefix))
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+    candidates.truncate(MAX_CANDIDATES);
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_repository::fixtures::hash;
+
+    #[test]
+    fn test_candidates() {
+        let hashes: Vec<Hash> = (0..3).map(hash).collect();
+        let all: Vec<String> = hashes.iter().map(|h| h.to_base32()).collect();
+        let prefix = &all[1][..4];
+        // Changes found in several channels are listed once.
+        let found = candidates(prefix, hashes.iter().chain(hashes.iter()));
+        assert!(found.contains(&all[1]));
+        assert!(found.iter().all(|h| h.starts_with(prefix)));
+        assert_eq!(
+            found.len(),
+            all.iter().filter(|h| h.starts_with(prefix)).count()
+        );
+
+        let many: Vec<Hash> = (0..=255).map(hash).collect();
+        let found = candidates("", many.iter());
+        assert_eq!(found.len(), MAX_CANDIDATES);
+        assert!(found.windows(2).all(|w| w[0] < w[1]));
+    }
+}
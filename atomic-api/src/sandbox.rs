@@ -0,0 +1,492 @@
+//! Review sandboxes
+//!
+//! A sandbox is a temporary channel, forked from a channel at one of its
+//! states, into which reviewers apply the changes they want to try without
+//! touching the channel they came from. Its expiry time is part of its
+//! name, `sandbox-<expiry>-<id>` (expiry in seconds since the epoch), so
+//! expired sandboxes are found from the channel list alone, even after a
+//! restart. The server deletes them in a background task for the
+//! repositories it created sandboxes in, and whenever the sandboxes of a
+//! repository are listed or created.
+//!
+//! Sandboxes can be downloaded as a tarball, or written to a worktree
+//! under the sandbox directory, laid out as
+//! `<root>/<tenant_id>/<portfolio_id>/<project_id>/<sandbox>`, for test
+//! runners on the same host.
+
+use crate::error::RepositoryError;
+use crate::{ApiError, ApiResult};
+use atomic_repository::Repository;
+use chrono::{DateTime, TimeZone, Utc};
+use libatomic::output::Archive;
+use libatomic::pristine::{Base32, ChannelRef, Hash, Merkle};
+use libatomic::{ChannelTxnT, MutTxnT, MutTxnTExt, TxnT, TxnTExt};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+/// Prefix of the names of sandbox channels
+pub const SANDBOX_PREFIX: &str = "sandbox-";
+
+/// Lifetime of a sandbox created without a TTL
+pub const DEFAULT_TTL: Duration = Duration::from_secs(3600);
+/// Longest lifetime of a sandbox
+pub const MAX_TTL: Duration = Duration::from_secs(7 * 24 * 3600);
+/// Default interval between two deletions of expired sandboxes
+const DEFAULT_REAP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Configuration of the sandboxes
+#[derive(Debug, Clone)]
+pub struct SandboxConfig {
+    /// Directory of the worktrees, if worktrees are enabled
+    pub worktree_root: Option<PathBuf>,
+    /// Interval between two deletions of expired sandboxes
+    pub reap_interval: Duration,
+}
+
+impl Default for SandboxConfig {
+    fn default() -> Self {
+        SandboxConfig {
+            worktree_root: None,
+            reap_interval: DEFAULT_REAP_INTERVAL,
+        }
+    }
+}
+
+impl SandboxConfig {
+    /// Read the configuration from `ATOMIC_API_SANDBOX_DIR` and
+    /// `ATOMIC_API_SANDBOX_REAP` (in seconds). Worktrees are disabled if
+    /// the directory isn't set.
+    pub fn from_env() -> Self {
+        SandboxConfig {
+            worktree_root: std::env::var_os("ATOMIC_API_SANDBOX_DIR").map(PathBuf::from),
+            reap_interval: std::env::var("ATOMIC_API_SANDBOX_REAP")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_REAP_INTERVAL),
+        }
+    }
+}
+
+/// Description of a sandbox
+#[derive(Debug, Clone, Serialize)]
+pub struct SandboxInfo {
+    pub name: String,
+    pub expires_at: DateTime<Utc>,
+    /// Current state of the sandbox channel
+    pub state: String,
+    /// Number of changes in the sandbox channel
+    pub changes: u64,
+}
+
+/// The sandbox configuration, and the repositories with sandboxes to reap
+#[derive(Clone, Default)]
+pub struct Sandboxes {
+    config: SandboxConfig,
+    repositories: Arc<Mutex<HashSet<(PathBuf, Option<PathBuf>)>>>,
+}
+
+impl Sandboxes {
+    pub fn new(config: SandboxConfig) -> Self {
+        Sandboxes {
+            config,
+            repositories: Arc::default(),
+        }
+    }
+
+    pub fn config(&self) -> &SandboxConfig {
+        &self.config
+    }
+
+    /// Directory of the worktrees of the repository at `relative` (relative
+    /// to the base mount path), if worktrees are enabled
+    pub fn worktrees(&self, relative: &Path) -> Option<PathBuf> {
+        self.config
+            .worktree_root
+            .as_ref()
+            .map(|root| root.join(relative))
+    }
+
+    /// Remember that the repository at `repo_path` has sandboxes, for the
+    /// background reaper
+    pub fn track(&self, repo_path: &Path, worktrees: Option<&Path>) {
+        self.repositories
+            .lock()
+            .unwrap()
+            .insert((repo_path.to_path_buf(), worktrees.map(Path::to_path_buf)));
+    }
+
+    /// Delete the expired sandboxes of all tracked repositories, and stop
+    /// tracking repositories without sandboxes left.
+    pub fn reap_all(&self) {
+        let repositories: Vec<_> = self.repositories.lock().unwrap().iter().cloned().collect();
+        let now = now();
+        for (repo_path, worktrees) in repositories {
+            let remaining = Repository::find_root(Some(repo_path.clone()))
+                .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))
+                .and_then(|repository| {
+                    reap_expired(&repository, worktrees.as_deref(), now)?;
+                    Ok(list(&repository)?.len())
+                });
+            match remaining {
+                Ok(0) => {
+                    self.repositories
+                        .lock()
+                        .unwrap()
+                        .remove(&(repo_path, worktrees));
+                }
+                Ok(_) => {}
+                Err(e) => warn!("Failed to reap sandboxes of {}: {}", repo_path.display(), e),
+            }
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Expiry time of the sandbox called `name`, or `None` if `name` isn't the
+/// name of a sandbox
+pub fn expiry(name: &str) -> Option<u64> {
+    let (expiry, id) = name.strip_prefix(SANDBOX_PREFIX)?.split_once('-')?;
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    expiry.parse().ok()
+}
+
+fn sandbox_name(expires_at: u64) -> String {
+    let id = uuid::Uuid::new_v4().simple().to_string();
+    format!("{}{}-{}", SANDBOX_PREFIX, expires_at, &id[..12])
+}
+
+fn txn_error(e: impl std::fmt::Display) -> ApiError {
+    ApiError::internal(format!("Sandbox transaction failed: {}", e))
+}
+
+fn channel_not_found(channel: &str) -> ApiError {
+    ApiError::Repository(RepositoryError::ChannelNotFound {
+        channel: channel.to_string(),
+    })
+}
+
+fn sandbox_info<T: TxnTExt>(txn: &T, channel: &ChannelRef<T>) -> ApiResult<SandboxInfo> {
+    let channel = channel.read();
+    let name = txn.name(&*channel).to_string();
+    let expires_at = expiry(&name).unwrap_or(0);
+    let state = libatomic::pristine::current_state(txn, &*channel).map_err(txn_error)?;
+    let changes = txn.log(&*channel, 0).map_err(txn_error)?.count() as u64;
+    Ok(SandboxInfo {
+        expires_at: Utc
+            .timestamp_opt(expires_at as i64, 0)
+            .single()
+            .unwrap_or_default(),
+        name,
+        state: state.to_base32(),
+        changes,
+    })
+}
+
+/// Fork `from` at `state` (its current state if `None`) into a new
+/// sandbox expiring after `ttl`, and apply `changes` to it.
+pub fn create(
+    repository: &Repository,
+    from: &str,
+    state: Option<&Merkle>,
+    changes: &[Hash],
+    ttl: Duration,
+) -> ApiResult<SandboxInfo> {
+    let expires_at = now() + ttl.min(MAX_TTL).as_secs();
+    let name = sandbox_name(expires_at);
+    let txn = repository.pristine.arc_txn_begin().map_err(txn_error)?;
+    let channel = {
+        let mut txn = txn.write();
+        let source = txn
+            .load_channel(from)
+            .map_err(txn_error)?
+            .ok_or_else(|| channel_not_found(from))?;
+        txn.fork(&source, &name).map_err(txn_error)?
+    };
+    if let Some(state) = state {
+        // Unrecord the changes after `state`, latest first.
+        let mut unrecord = Vec::new();
+        let mut found = false;
+        {
+            let txn = txn.read();
+            for entry in txn.reverse_log(&*channel.read(), None).map_err(txn_error)? {
+                let (_, (h, m)) = entry.map_err(txn_error)?;
+                if Merkle::from(m) == *state {
+                    found = true;
+                    break;
+                }
+                unrecord.push(Hash::from(h));
+            }
+        }
+        if !found {
+            return Err(ApiError::invalid_query(format!(
+                "State {} not found in channel {}",
+                state.to_base32(),
+                from
+            )));
+        }
+        let mut txn = txn.write();
+        for h in unrecord.iter() {
+            txn.unrecord(&repository.changes, &channel, h, 0)
+                .map_err(|e| {
+                    ApiError::internal(format!("Failed to unrecord {}: {}", h.to_base32(), e))
+                })?;
+        }
+    }
+    apply_to(repository, &mut *txn.write(), &channel, changes)?;
+    let info = sandbox_info(&*txn.read(), &channel)?;
+    txn.commit().map_err(txn_error)?;
+    info!(
+        "Created sandbox {} from {} in {}",
+        name,
+        from,
+        repository.path.display()
+    );
+    Ok(info)
+}
+
+fn apply_to<T: MutTxnTExt>(
+    repository: &Repository,
+    txn: &mut T,
+    channel: &ChannelRef<T>,
+    changes: &[Hash],
+) -> ApiResult<()> {
+    let mut channel = channel.write();
+    for h in changes {
+        txn.apply_change_rec(&repository.changes, &mut channel, h)
+            .map_err(|e| ApiError::internal(format!("Failed to apply {}: {}", h.to_base32(), e)))?;
+    }
+    Ok(())
+}
+
+/// Apply `changes` (and their dependencies) to the sandbox `name`
+pub fn apply(repository: &Repository, name: &str, changes: &[Hash]) -> ApiResult<SandboxInfo> {
+    let mut txn = repository.pristine.mut_txn_begin().map_err(txn_error)?;
+    let channel = load(&txn, name)?;
+    apply_to(repository, &mut txn, &channel, changes)?;
+    let info = sandbox_info(&txn, &channel)?;
+    txn.commit().map_err(txn_error)?;
+    Ok(info)
+}
+
+fn load<T: TxnT>(txn: &T, name: &str) -> ApiResult<ChannelRef<T>> {
+    if expiry(name).is_none() {
+        return Err(ApiError::invalid_query(format!("Not a sandbox: {}", name)));
+    }
+    txn.load_channel(name)
+        .map_err(txn_error)?
+        .ok_or_else(|| channel_not_found(name))
+}
+
+/// The sandboxes of a repository, expired or not
+pub fn list(repository: &Repository) -> ApiResult<Vec<SandboxInfo>> {
+    let txn = repository.pristine.txn_begin().map_err(txn_error)?;
+    let mut sandboxes = Vec::new();
+    for channel in txn.channels(SANDBOX_PREFIX).map_err(txn_error)? {
+        if expiry(txn.name(&*channel.read())).is_some() {
+            sandboxes.push(sandbox_info(&txn, &channel)?);
+        }
+    }
+    sandboxes.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(sandboxes)
+}
+
+/// Delete the sandbox `name` and its worktree. Returns `false` if there
+/// was no such sandbox.
+pub fn delete(repository: &Repository, name: &str, worktrees: Option<&Path>) -> ApiResult<bool> {
+    if expiry(name).is_none() {
+        return Ok(false);
+    }
+    let mut txn = repository.pristine.mut_txn_begin().map_err(txn_error)?;
+    let dropped = txn.drop_channel(name).map_err(txn_error)?;
+    txn.commit().map_err(txn_error)?;
+    if let Some(worktrees) = worktrees {
+        let worktree = worktrees.join(name);
+        if worktree.exists() {
+            std::fs::remove_dir_all(&worktree)?;
+        }
+    }
+    Ok(dropped)
+}
+
+/// Delete the sandboxes that expired before `now` (in seconds since the
+/// epoch), and their worktrees. Returns the names of the deleted sandboxes.
+pub fn reap_expired(
+    repository: &Repository,
+    worktrees: Option<&Path>,
+    now: u64,
+) -> ApiResult<Vec<String>> {
+    let expired: Vec<String> = {
+        let txn = repository.pristine.txn_begin().map_err(txn_error)?;
+        let channels = txn.channels(SANDBOX_PREFIX).map_err(txn_error)?;
+        channels
+            .iter()
+            .map(|c| txn.name(&*c.read()).to_string())
+            .filter(|name| expiry(name).map_or(false, |e| e <= now))
+            .collect()
+    };
+    for name in expired.iter() {
+        info!(
+            "Deleting expired sandbox {} in {}",
+            name,
+            repository.path.display()
+        );
+        delete(repository, name, worktrees)?;
+    }
+    Ok(expired)
+}
+
+/// Write the files of the sandbox `name` as a gzipped tarball to `w`
+pub fn archive<W: std::io::Write>(repository: &Repository, name: &str, w: W) -> ApiResult<()> {
+    let txn = repository.pristine.arc_txn_begin().map_err(txn_error)?;
+    let channel = load(&*txn.read(), name)?;
+    let mut tarball = libatomic::output::Tarball::new(w, None, 0o022);
+    let conflicts = txn
+        .archive(&repository.changes, &channel, &mut tarball)
+        .map_err(|e| ApiError::internal(format!("Failed to archive {}: {}", name, e)))?;
+    if !conflicts.is_empty() {
+        warn!("Sandbox {} has {} conflicts", name, conflicts.len());
+    }
+    tarball
+        .archive
+        .into_inner()
+        .and_then(|gz| gz.finish())
+        .map_err(|e| ApiError::internal(format!("Failed to archive {}: {}", name, e)))?;
+    Ok(())
+}
+
+/// Write the files of the sandbox `name` to `worktrees/name`, replacing
+/// its previous contents. Returns the path of the worktree.
+pub fn materialize(repository: &Repository, name: &str, worktrees: &Path) -> ApiResult<PathBuf> {
+    let txn = repository.pristine.arc_txn_begin().map_err(txn_error)?;
+    let channel = load(&*txn.read(), name)?;
+    let root = worktrees.join(name);
+    if root.exists() {
+        std::fs::remove_dir_all(&root)?;
+    }
+    std::fs::create_dir_all(&root)?;
+    let mut worktree = Worktree { root: root.clone() };
+    txn.archive(&repository.changes, &channel, &mut worktree)
+        .map_err(|e| ApiError::internal(format!("Failed to write worktree {}: {}", name, e)))?;
+    Ok(root)
+}
+
+/// Writes archived files to a directory
+struct Worktree {
+    root: PathBuf,
+}
+
+struct WorktreeFile {
+    path: PathBuf,
+    permissions: u16,
+    buf: Vec<u8>,
+}
+
+impl std::io::Write for WorktreeFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buf.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Archive for Worktree {
+    type File = WorktreeFile;
+    type Error = std::io::Error;
+    fn create_file(&mut self, path: &str, _mtime: u64, permissions: u16) -> Self::File {
+        WorktreeFile {
+            path: self.root.join(path),
+            permissions,
+            buf: Vec::new(),
+        }
+    }
+    fn create_dir(&mut self, path: &str, _mtime: u64, _permissions: u16) -> std::io::Result<()> {
+        std::fs::create_dir_all(self.root.join(path))
+    }
+    fn close_file(&mut self, f: Self::File) -> std::io::Result<()> {
+        if let Some(parent) = f.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&f.path, &f.buf)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = u32::from(f.permissions & 0o777 & !0o022);
+            std::fs::set_permissions(&f.path, std::fs::Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sandbox_names() {
+        let name = sandbox_name(1234);
+        assert!(name.starts_with("sandbox-1234-"));
+        assert_eq!(expiry(&name), Some(1234));
+        assert_eq!(expiry("main"), None);
+        assert_eq!(expiry("sandbox-1234"), None);
+        assert_eq!(expiry("sandbox-abc-def"), None);
+        assert_eq!(expiry("sandbox-1234-../x"), None);
+    }
+
+    #[test]
+    fn test_sandbox_lifecycle() {
+        let base = tempfile::tempdir().unwrap();
+        let repo_path =
+            crate::admin::create_repository(base.path(), "tenant", "portfolio", "project").unwrap();
+        let repository = Repository::find_root(Some(repo_path)).unwrap();
+        let worktrees = base.path().join("worktrees");
+
+        let sandbox = create(&repository, "main", None, &[], DEFAULT_TTL).unwrap();
+        assert_eq!(sandbox.changes, 0);
+        assert!(matches!(
+            create(&repository, "nope", None, &[], DEFAULT_TTL),
+            Err(ApiError::Repository(
+                RepositoryError::ChannelNotFound { .. }
+            ))
+        ));
+        let names: Vec<_> = list(&repository)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, vec![sandbox.name.clone()]);
+
+        let mut tarball = Vec::new();
+        archive(&repository, &sandbox.name, &mut tarball).unwrap();
+        assert!(tarball.starts_with(&[0x1f, 0x8b]));
+        let worktree = materialize(&repository, &sandbox.name, &worktrees).unwrap();
+        assert!(worktree.is_dir());
+
+        // Not expired yet.
+        assert!(reap_expired(&repository, Some(&worktrees), now())
+            .unwrap()
+            .is_empty());
+        let expires_at = expiry(&sandbox.name).unwrap();
+        assert_eq!(
+            reap_expired(&repository, Some(&worktrees), expires_at).unwrap(),
+            vec![sandbox.name.clone()]
+        );
+        assert!(list(&repository).unwrap().is_empty());
+        assert!(!worktree.exists());
+        assert!(!delete(&repository, &sandbox.name, None).unwrap());
+        assert!(!delete(&repository, "main", None).unwrap());
+    }
+}
@@ -3,28 +3,70 @@
 //! Provides a minimal REST API server that exposes core Atomic VCS operations
 //! for a single repository. Designed to be used behind a Fastify reverse proxy.
 
+use crate::activity::ActivityQuery;
+use crate::apply_queue::{ApplyQueue, ApplyQueueConfig, ApplyQueueMetrics, OperationAccepted};
+use crate::attribution_stats::{AttributionStatsQuery, AttributionStatsReport, StatsFilter};
+use crate::auth::AuthConfig;
+use crate::config::{ConfigLayers, ResolvedConfig};
+use crate::conflicts::{ChannelConflicts, ConflictQuery};
+#[cfg(feature = "content-index")]
+use crate::content_index::{ContentIndex, ContentIndexConfig, SearchQuery, SearchResults};
+use crate::degraded::{DegradedConfig, DegradedMetrics, DegradedMode, Resources};
+use crate::diff::{ChangeDiff, DiffQuery};
+use crate::digest::{Digest, DigestQuery};
+use crate::event_log::EventLog;
+use crate::exposure::{add_security_headers, ExposureConfig, RouteGroup};
+use crate::filters::{Candidate, FilterDefinition, SavedFilter, SavedFilters};
+use crate::grouping::ClusterCache;
+use crate::inbox::{Inbox, InboxQuery, INBOX_SORT_FIELDS};
+use crate::jail::Jail;
+use crate::lease::{Leadership, LeaseConfig, LeaseProvider, Leases};
+use crate::limits::{self, BodyKind, Limits, LimitsConfig, LimitsMetrics, RateKey};
+use crate::maintenance::{
+    self, Maintenance, MaintenanceConfig, MaintenanceStatus, MaintenanceTask, TaskStatus,
+};
+use crate::query::{encode_cursor, ListQuery, Page};
+use crate::replica::{ReadSource, ReplicaConfig, ReplicaSet};
+use crate::resolve::{ResolveQuery, Resolved};
+use crate::sandbox::{self, SandboxConfig, SandboxInfo, Sandboxes};
+use crate::shutdown::Shutdown;
+use crate::snapshot::{Snapshot, SnapshotQuery};
+use crate::storage::{self, ArchiveStore, Storage, StorageConfig, StorageReport, TenantUsage};
+use crate::stream::{page_trailer, JsonStream, Sink};
+use crate::submission::{Extracted, Submission, SubmissionQuery, SubmittedChange};
+use crate::tls::Tls;
+use crate::validate::Validation;
+use crate::versions::{self, ProtocolMetrics, ProtocolVersions};
+use crate::webhooks::{WebhookConfig, WebhookMetrics, Webhooks};
+use crate::workflow::{
+    EvaluationResponse, TransitionRequest, TransitionResponse, WorkflowQuery, WorkflowStateResponse,
+};
 use crate::{ApiError, ApiResult};
+use atomic_repository::lock::{LockOptions, Locked, RepositoryLock};
 use atomic_repository::Repository;
+use atomic_workflows::WorkflowRegistry;
 
 use axum::{
     body::Body,
-    extract::{Path, Query, State},
-    http::{Response, StatusCode},
-    response::Json,
-    routing::{get, post},
+    extract::{ConnectInfo, DefaultBodyLimit, Path, Query, Request, State},
+    http::{HeaderMap, Method, Response, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Json},
+    routing::{delete, get, post},
     Router,
 };
 use bytes::Bytes;
 use libatomic::attribution::SerializedAttribution;
+use libatomic::changestore::contents_cache::{ContentsCache, ContentsCacheMetrics};
 use libatomic::changestore::ChangeStore;
-use libatomic::pristine::TagMetadataMutTxnT;
 use libatomic::pristine::{Base32, L64};
+use libatomic::pristine::{TagMetadataMutTxnT, TagMetadataTxnT};
 use libatomic::{ChannelMutTxnT, ChannelTxnT, MutTxnT, MutTxnTExt, TxnT, TxnTExt};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 
 use byteorder::{BigEndian, WriteBytesExt};
-use tower_http::cors::CorsLayer;
 use tracing::{debug, error, info, warn};
 
 /// API Server state following AGENTS.md configuration patterns
@@ -32,11 +74,53 @@ use tracing::{debug, error, info, warn};
 pub struct AppState {
     /// Base mount path for tenant repositories
     base_mount_path: PathBuf,
+    /// Resolution of repository paths, confined to their tenant directory
+    jail: Jail,
+    /// Cached dependency clusters for `group_by=dependency_cluster`
+    clusters: ClusterCache,
+    /// Pristine snapshots serving the read-only endpoints, if configured
+    replicas: Option<ReplicaSet>,
+    /// Per-repository queues of the applies
+    applies: ApplyQueue,
+    /// Review sandboxes configuration, and repositories with sandboxes
+    sandboxes: Sandboxes,
+    /// Last events of the event bus
+    events: EventLog,
+    /// Whether writes are currently rejected to shed load
+    degraded: DegradedMode,
+    /// Whether the server is shutting down
+    shutdown: Shutdown,
+    /// Rate limits of the tenants, and limits of the request bodies
+    limits: Limits,
+    /// Storage quotas and archival of cold repositories
+    storage: Storage,
+    /// Instance and tenant layers of the repository configurations
+    configs: ConfigLayers,
+    /// Full-text index of the contents of applied changes, if enabled
+    #[cfg(feature = "content-index")]
+    content_index: Option<ContentIndex>,
+    /// Workflows of the workflow endpoints, by name
+    workflows: Arc<WorkflowRegistry>,
+    /// Pending workflow states of each tenant
+    inbox: Inbox,
+    /// Negotiation of the protocol versions, and requests by version
+    protocols: ProtocolVersions,
+    /// Orchestrator of the maintenance tasks, once the server is started
+    maintenance: Option<Maintenance>,
+    /// Write leases shared with the other instances, if enabled
+    leases: Option<Leases>,
+    /// Webhook notifications of the events, if configured
+    webhooks: Option<Webhooks>,
 }
 
 /// Main API server struct
 pub struct ApiServer {
     state: AppState,
+    tls: Option<Tls>,
+    exposure: ExposureConfig,
+    maintenance: MaintenanceConfig,
+    /// Credentials and scopes of the requests, if authenticated here
+    auth: Option<Arc<AuthConfig>>,
 }
 
 /// Health check response
@@ -63,6 +147,15 @@ pub struct ChangeInfo {
     /// AI attribution metadata
     #[serde(skip_serializing_if = "Option::is_none")]
     ai_attribution: Option<AIAttribution>,
+    /// Earlier versions of this change, see [`atomic_repository::supersede`]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    supersedes: Vec<String>,
+    /// Newer version of this change, if it was re-recorded or squashed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    superseded_by: Option<String>,
+    /// Issues linked to this change, see [`atomic_repository::links`]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    links: Vec<atomic_repository::links::Link>,
 }
 
 /// AI Attribution metadata matching the existing Atomic VCS attribution system
@@ -92,6 +185,57 @@ pub struct ChangesQuery {
     /// Whether to include AI attribution data (default: false)
     #[serde(default)]
     include_ai_attribution: bool,
+    /// Group changes into threads instead of returning a flat list
+    #[serde(default)]
+    group_by: Option<GroupBy>,
+    /// Return a page envelope (`items`, `next_cursor`, `total_estimate`)
+    /// instead of a bare array
+    #[serde(default)]
+    envelope: bool,
+    /// Name of a saved filter to apply, see [`crate::filters`]
+    #[serde(default)]
+    filter: Option<String>,
+    /// Order of the changes, the channel log (newest first) by default
+    #[serde(default)]
+    order: Option<ChangeOrder>,
+    /// Leave out the changes superseded by a newer version
+    #[serde(default)]
+    hide_superseded: bool,
+}
+
+/// Orders of the changes endpoint, besides the channel log
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeOrder {
+    /// Topological levels of the dependency graph, see [`crate::grouping`]
+    Topological,
+}
+
+/// Changes of a topological level, for `order=topological`. A level split
+/// across pages appears on each of them.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeLevel {
+    /// Level of the changes, 0 for changes without dependencies
+    level: usize,
+    /// Changes of the level, in log order
+    changes: Vec<ChangeInfo>,
+}
+
+/// Grouping modes for the changes endpoint
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupBy {
+    /// Stacks of changes where each change depends on the previous one
+    DependencyCluster,
+}
+
+/// A thread of stacked changes, for `group_by=dependency_cluster`
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeThread {
+    /// Hash of the newest change of the stack
+    head: String,
+    /// Changes of the stack, from the head down
+    changes: Vec<ChangeInfo>,
 }
 
 /// Query parameters for clone endpoint
@@ -243,6 +387,21 @@ pub struct AttributionSyncStatus {
     sync_duration_ms: Option<u64>,
 }
 
+/// Tag header and signature verification status
+#[derive(Debug, Serialize)]
+pub struct TagInfo {
+    /// Channel state of the tag, in base32
+    pub state: String,
+    pub message: String,
+    pub authors: Vec<String>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// Whether the tag is signed, and whether the signature is valid
+    pub signature: libatomic::tag::SignatureStatus,
+    /// Metadata of the consolidating tag, including its attribution
+    /// statistics (`attribution.*` keys)
+    pub metadata: std::collections::HashMap<String, String>,
+}
+
 fn default_limit() -> usize {
     50
 }
@@ -257,28 +416,428 @@ impl ApiServer {
             return Err(ApiError::repository_not_found(path.to_string_lossy()));
         }
 
+        let jail = Jail::new(&path)?;
         let state = AppState {
+            inbox: Inbox::new(jail.root()),
+            jail,
+            configs: ConfigLayers::new(path.join(crate::config::LAYER_FILE)),
             base_mount_path: path,
+            clusters: ClusterCache::default(),
+            replicas: None,
+            applies: ApplyQueue::new(ApplyQueueConfig::default()),
+            sandboxes: Sandboxes::default(),
+            events: EventLog::default(),
+            degraded: DegradedMode::default(),
+            shutdown: Shutdown::default(),
+            limits: Limits::default(),
+            storage: Storage::default(),
+            #[cfg(feature = "content-index")]
+            content_index: None,
+            workflows: Arc::new(WorkflowRegistry::builtin()),
+            protocols: ProtocolVersions::default(),
+            maintenance: None,
+            leases: None,
+            webhooks: None,
         };
 
-        Ok(Self { state })
+        Ok(Self {
+            state,
+            tls: None,
+            exposure: ExposureConfig::default(),
+            auth: None,
+            maintenance: MaintenanceConfig::default(),
+        })
+    }
+
+    /// Serve the changes and changelist endpoints from pristine snapshots
+    pub fn with_replicas(mut self, config: ReplicaConfig) -> Self {
+        self.state.replicas = Some(ReplicaSet::new(config));
+        self
+    }
+
+    /// Run applies on a worker pool with this configuration
+    pub fn with_apply_queue(mut self, config: ApplyQueueConfig) -> Self {
+        self.state.applies = ApplyQueue::new(config);
+        self
+    }
+
+    /// Configure the review sandboxes
+    pub fn with_sandboxes(mut self, config: SandboxConfig) -> Self {
+        self.state.sandboxes = Sandboxes::new(config);
+        self
+    }
+
+    /// Keep the last events of the event bus in `events`
+    pub fn with_event_log(mut self, events: EventLog) -> Self {
+        self.state.events = events;
+        self
+    }
+
+    /// Reject writes when resources run low, with these thresholds
+    pub fn with_degraded_mode(mut self, config: DegradedConfig) -> Self {
+        self.state.degraded = DegradedMode::new(config);
+        self
+    }
+
+    /// Limit the rate of the requests of each tenant and the size of the
+    /// bodies, see [`crate::limits`]
+    pub fn with_limits(mut self, config: LimitsConfig) -> Self {
+        self.state.limits = Limits::new(config);
+        self
+    }
+
+    /// Stop gracefully when `shutdown` starts, see [`crate::shutdown`]
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.state.shutdown = shutdown;
+        self
+    }
+
+    /// Enforce storage quotas and archive cold repositories, see
+    /// [`crate::storage`]
+    pub fn with_storage(mut self, config: StorageConfig) -> Self {
+        self.state.storage = Storage::new(config);
+        self
+    }
+
+    /// Archive cold repositories to `store` instead of the configured
+    /// directory
+    pub fn with_archive_store(mut self, store: Arc<dyn ArchiveStore>) -> Self {
+        self.state.storage = self.state.storage.clone().with_store(store);
+        self
+    }
+
+    /// Read the instance defaults of the repository configurations from
+    /// `path` instead of `atomic.toml` in the base mount path, see
+    /// [`crate::config`]
+    pub fn with_instance_config(mut self, path: impl Into<PathBuf>) -> Self {
+        self.state.configs = ConfigLayers::new(path);
+        self
+    }
+
+    /// Serve the workflows of `registry` instead of the workflows of
+    /// `atomic-workflows`, see [`crate::workflow`]
+    pub fn with_workflows(mut self, registry: WorkflowRegistry) -> Self {
+        self.state.workflows = Arc::new(registry);
+        self
+    }
+
+    /// Index the contents of applied changes, and serve content searches
+    #[cfg(feature = "content-index")]
+    pub fn with_content_index(mut self, config: ContentIndexConfig) -> Self {
+        self.state.content_index = Some(ContentIndex::new(config));
+        self
+    }
+
+    /// Take the lease of repositories before writing to them, for
+    /// instances sharing their storage, see [`crate::lease`]
+    pub fn with_leases(mut self, config: LeaseConfig) -> Self {
+        self.state.leases = Some(Leases::new(config));
+        self
+    }
+
+    /// Keep the leases in `provider` instead of the repositories
+    pub fn with_lease_provider(mut self, provider: Arc<dyn LeaseProvider>) -> Self {
+        self.state.leases = self.state.leases.map(|l| l.with_provider(provider));
+        self
+    }
+
+    /// POST the events to webhooks, see [`crate::webhooks`]
+    pub fn with_webhooks(mut self, config: WebhookConfig) -> Self {
+        self.state.webhooks = Some(Webhooks::new(config, self.state.jail.root()));
+        self
+    }
+
+    /// Terminate TLS in the server instead of serving plain HTTP
+    pub fn with_tls(mut self, tls: Tls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Restrict CORS, add security headers and disable route groups
+    pub fn with_exposure(mut self, config: ExposureConfig) -> Self {
+        self.exposure = config;
+        self
+    }
+
+    /// Authenticate the requests and check the scopes of their routes,
+    /// see [`crate::auth`]
+    pub fn with_auth(mut self, config: AuthConfig) -> Self {
+        self.auth = Some(Arc::new(config));
+        self
+    }
+
+    /// Schedule the maintenance tasks, see [`crate::maintenance`]. The
+    /// replica refresh and archive reaper intervals schedule their tasks
+    /// unless `config` does.
+    pub fn with_maintenance(mut self, config: MaintenanceConfig) -> Self {
+        self.maintenance = config;
+        self
     }
 
     /// Start the API server
-    pub async fn serve(self, addr: impl AsRef<str>) -> ApiResult<()> {
+    pub async fn serve(mut self, addr: impl AsRef<str>) -> ApiResult<()> {
         let addr = addr.as_ref();
         let base_path_display = self.state.base_mount_path.display().to_string();
 
-        let app = Router::new()
-            .route("/health", get(health_check))
-            .route(
-                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/changes",
-                get(get_changes),
-            )
-            .route(
-                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/changes/:change_id",
-                get(get_change),
-            )
+        self.state.applies.start();
+        self.state.events.subscribe(atomic_config::events::global());
+        if let Some(ref webhooks) = self.state.webhooks {
+            webhooks.subscribe(atomic_config::events::global());
+            webhooks.start();
+        }
+        #[cfg(feature = "content-index")]
+        if let Some(ref index) = self.state.content_index {
+            index.subscribe(atomic_config::events::global());
+        }
+
+        let maintenance = self.start_maintenance();
+        self.state.maintenance = Some(maintenance);
+
+        {
+            let sandboxes = self.state.sandboxes.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(sandboxes.config().reap_interval);
+                loop {
+                    interval.tick().await;
+                    let sandboxes = sandboxes.clone();
+                    if let Err(e) = tokio::task::spawn_blocking(move || sandboxes.reap_all()).await
+                    {
+                        warn!("Sandbox reaper task failed: {}", e);
+                    }
+                }
+            });
+        }
+
+        {
+            let degraded = self.state.degraded.clone();
+            let applies = self.state.applies.clone();
+            let base_mount_path = self.state.base_mount_path.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(degraded.config().check_interval);
+                loop {
+                    interval.tick().await;
+                    let queued = applies.metrics().queued;
+                    let path = base_mount_path.clone();
+                    match tokio::task::spawn_blocking(move || Resources::measure(&path, queued))
+                        .await
+                    {
+                        Ok(resources) => degraded.update(resources),
+                        Err(e) => warn!("Resource monitor task failed: {}", e),
+                    }
+                }
+            });
+        }
+
+        if let Some(leases) = self.state.leases.clone() {
+            info!("Leasing repositories as {}", leases.config().instance);
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(leases.config().heartbeat());
+                loop {
+                    interval.tick().await;
+                    let leases = leases.clone();
+                    if let Err(e) = tokio::task::spawn_blocking(move || leases.heartbeat()).await {
+                        warn!("Lease heartbeat task failed: {}", e);
+                    }
+                }
+            });
+        }
+
+        // Only matched routes are authenticated, so that `/health` and
+        // unknown paths don't need credentials. Clients are rate-limited
+        // once authenticated, by principal.
+        let auth = self.auth.clone();
+        let state = self.state.clone();
+        let guard = |router: Router<AppState>, group: RouteGroup| {
+            let router =
+                router.route_layer(middleware::from_fn_with_state(state.clone(), limit_rate));
+            match auth {
+                Some(ref config) => router.route_layer(middleware::from_fn_with_state(
+                    (config.clone(), group),
+                    crate::auth::authorize,
+                )),
+                None => router,
+            }
+        };
+        // Bodies are limited per route by `enforce_limits`, instead of
+        // axum's default of 2 MB.
+        let max_body = self.state.limits.config().max_body();
+        let mut app = Router::new().route("/health", get(health_check));
+        for group in RouteGroup::ALL {
+            if self.exposure.serves(group) {
+                let router = match group {
+                    RouteGroup::Protocol => routes(group).layer(DefaultBodyLimit::max(
+                        usize::try_from(max_body).unwrap_or(usize::MAX),
+                    )),
+                    _ => routes(group),
+                };
+                app = app.merge(guard(router, group));
+            } else {
+                info!("Not serving the {} routes", group);
+            }
+        }
+        #[cfg(feature = "content-index")]
+        let app = if self.state.content_index.is_some() && self.exposure.serves(RouteGroup::Changes)
+        {
+            app.merge(guard(
+                Router::new().route(
+                    "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/search/content",
+                    get(search_content),
+                ),
+                RouteGroup::Changes,
+            ))
+        } else {
+            app
+        };
+        // Stop accepting connections once the applies are drained.
+        let drained = {
+            let shutdown = self.state.shutdown.clone();
+            let applies = self.state.applies.clone();
+            let leases = self.state.leases.clone();
+            async move {
+                let report = shutdown.drain(&applies).await;
+                info!("Applies drained: {:?}", report);
+                // Let the other instances take over at once.
+                if let Some(leases) = leases {
+                    if let Err(e) = tokio::task::spawn_blocking(move || leases.release_all()).await
+                    {
+                        warn!("Failed to release the leases: {}", e);
+                    }
+                }
+            }
+        };
+        let app = app
+            .layer(middleware::from_fn_with_state(
+                self.state.clone(),
+                manage_storage,
+            ))
+            .layer(middleware::from_fn_with_state(
+                self.state.clone(),
+                coordinate_writes,
+            ))
+            .layer(middleware::from_fn_with_state(
+                self.state.clone(),
+                negotiate_protocol,
+            ))
+            .layer(middleware::from_fn_with_state(
+                self.state.clone(),
+                reject_writes_when_degraded,
+            ))
+            .layer(middleware::from_fn_with_state(
+                self.state.clone(),
+                reject_writes_when_shutting_down,
+            ))
+            .layer(middleware::from_fn_with_state(
+                self.state.clone(),
+                enforce_limits,
+            ))
+            .layer(self.exposure.cors.layer())
+            .with_state(self.state);
+        let app = if self.exposure.security_headers {
+            app.layer(middleware::from_fn_with_state(
+                self.tls.is_some(),
+                add_security_headers,
+            ))
+        } else {
+            app
+        };
+
+        info!(
+            "Starting Atomic API server on {} with base path: {}",
+            addr, base_path_display
+        );
+
+        let listener = tokio::net::TcpListener::bind(addr)
+            .await
+            .map_err(|e| ApiError::internal(format!("Failed to bind to {}: {}", addr, e)))?;
+
+        if let Some(ref tls) = self.tls {
+            info!("Terminating TLS on {}", addr);
+            crate::tls::serve(listener, app, tls, drained).await?;
+        } else {
+            let app = app.into_make_service_with_connect_info::<std::net::SocketAddr>();
+            axum::serve(listener, app)
+                .with_graceful_shutdown(drained)
+                .await
+                .map_err(|e| ApiError::internal(format!("Server error: {}", e)))?;
+        }
+
+        Ok(())
+    }
+
+    /// Start the orchestrator of the maintenance tasks, running them over
+    /// the repositories of the base mount path
+    fn start_maintenance(&self) -> Maintenance {
+        let mut config = self.maintenance.clone();
+        if let Some(interval) = self
+            .state
+            .replicas
+            .as_ref()
+            .and_then(|r| r.config().refresh_interval)
+        {
+            config = config.or_schedule(MaintenanceTask::RevalidateReplicas, interval);
+        }
+        if self.state.storage.archives() {
+            config = config.or_schedule(
+                MaintenanceTask::Archive,
+                self.state.storage.config().reap_interval,
+            );
+        }
+        let max_queued = config.max_queued;
+        let state = self.state.clone();
+        let runner = move |task| run_maintenance_task(&state, task);
+        let applies = self.state.applies.clone();
+        let degraded = self.state.degraded.clone();
+        let idle = move || applies.metrics().queued <= max_queued && !degraded.is_degraded();
+        let maintenance = Maintenance::new(config, runner, idle);
+        maintenance.start();
+        maintenance
+    }
+}
+
+/// Run a maintenance task over the repositories under the base mount path
+fn run_maintenance_task(state: &AppState, task: MaintenanceTask) -> ApiResult<String> {
+    let base_mount_path = &state.base_mount_path;
+    match task {
+        MaintenanceTask::Gc => maintenance::gc_all(base_mount_path),
+        MaintenanceTask::Audit => maintenance::audit_all(base_mount_path),
+        MaintenanceTask::RevalidateReplicas => match state.replicas {
+            Some(ref replicas) => {
+                let n = replicas.refresh_all(base_mount_path)?;
+                Ok(format!("refreshed {} replicas", n))
+            }
+            None => Ok("no replicas configured".to_string()),
+        },
+        MaintenanceTask::Archive => {
+            let n = state.storage.reap_all(base_mount_path);
+            Ok(format!("archived {} repositories", n))
+        }
+        #[cfg(feature = "content-index")]
+        MaintenanceTask::Reindex => match state.content_index {
+            Some(ref index) => {
+                let mut changes = 0;
+                let repositories = crate::replica::repositories(base_mount_path)?;
+                for relative in repositories.iter() {
+                    changes += index.rebuild(&base_mount_path.join(relative))?.changes;
+                }
+                Ok(format!(
+                    "indexed {} changes of {} repositories",
+                    changes,
+                    repositories.len()
+                ))
+            }
+            None => Ok("content index disabled".to_string()),
+        },
+        #[cfg(not(feature = "content-index"))]
+        MaintenanceTask::Reindex => Ok("content index disabled".to_string()),
+    }
+}
+
+/// The routes of `group`, see [`RouteGroup`]
+fn routes(group: RouteGroup) -> Router<AppState> {
+    let router = Router::new();
+    match group {
+        RouteGroup::Protocol => router
+            .route("/operations/:operation_id", get(get_operation))
             .route(
                 "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code",
                 get(get_atomic_protocol).post(post_atomic_protocol),
@@ -298,71 +857,888 @@ impl ApiServer {
             .route(
                 "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/upload",
                 post(post_upload_changes),
+            ),
+        RouteGroup::Changes => router
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/changes",
+                get(get_changes),
             )
-            .layer(CorsLayer::permissive())
-            .with_state(self.state);
-
-        info!(
-            "Starting Atomic API server on {} with base path: {}",
-            addr, base_path_display
-        );
-
-        let listener = tokio::net::TcpListener::bind(addr)
-            .await
-            .map_err(|e| ApiError::internal(format!("Failed to bind to {}: {}", addr, e)))?;
-
-        axum::serve(listener, app)
-            .await
-            .map_err(|e| ApiError::internal(format!("Server error: {}", e)))?;
-
-        Ok(())
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/changes/:change_id",
+                get(get_change),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/changes/:change_id/diff",
+                get(get_change_diff),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/resolve",
+                get(resolve_prefix),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/events",
+                get(stream_activity),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/filters",
+                get(list_filters),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/provenance",
+                get(list_provenance),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/provenance/:change_id",
+                get(get_provenance),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/filters/:name",
+                get(get_filter).put(put_filter).delete(delete_filter),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/channels",
+                get(list_channels),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/tags",
+                get(list_tags).post(create_tag),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/digest",
+                get(get_digest),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/state",
+                get(get_state_at),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/attribution",
+                get(list_attribution),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/attribution/export",
+                get(export_attribution),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/attribution/stats",
+                get(get_attribution_stats),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/conflicts",
+                get(get_conflicts),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/tags/:tag_state",
+                get(get_tag),
+            ),
+        RouteGroup::Collaboration => router
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/changes/:change_id/notes",
+                get(get_change_note)
+                    .put(put_change_note)
+                    .delete(delete_change_note),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/changes/:change_id/links",
+                get(list_change_links)
+                    .post(add_change_link)
+                    .delete(delete_change_link),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/links",
+                get(find_links),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/changes/:change_id/workflow/state",
+                get(get_workflow_state),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/changes/:change_id/workflow/transition",
+                post(post_workflow_transition),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/changes/:change_id/workflow/evaluate",
+                post(post_workflow_evaluate),
+            )
+            .route("/tenant/:tenant_id/workflow/inbox", get(get_workflow_inbox))
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/changes/:change_id/comments",
+                get(list_comments).post(add_comment),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/changes/:change_id/comments/:comment_id/accept",
+                post(accept_suggestion),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/sandboxes",
+                get(list_sandboxes).post(create_sandbox),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/submissions",
+                post(post_submission),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/sandboxes/:name",
+                delete(delete_sandbox),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/sandboxes/:name/apply",
+                post(apply_to_sandbox),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/sandboxes/:name/archive",
+                get(get_sandbox_archive),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/sandboxes/:name/worktree",
+                post(post_sandbox_worktree),
+            ),
+        RouteGroup::Admin => router
+            .route("/metrics/applies", get(get_apply_metrics))
+            .route("/metrics/contents-cache", get(get_contents_cache_metrics))
+            .route("/metrics/degraded", get(get_degraded_metrics))
+            .route("/metrics/limits", get(get_limits_metrics))
+            .route("/metrics/protocol", get(get_protocol_metrics))
+            .route("/metrics/webhooks", get(get_webhook_metrics))
+            .route("/maintenance", get(get_maintenance))
+            .route("/maintenance/:task", post(trigger_maintenance))
+            .route("/events", get(list_events))
+            .route("/tenant/:tenant_id/usage", get(get_tenant_usage))
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/storage",
+                get(get_storage),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/config",
+                get(get_config),
+            )
+            .route(
+                "/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id/code/backup",
+                get(get_backup),
+            ),
     }
 }
 
-/// Health check endpoint
-async fn health_check() -> Json<HealthResponse> {
-    Json(HealthResponse {
-        status: "healthy".to_string(),
-        version: crate::VERSION.to_string(),
-    })
-}
-
-/// Get list of changes for tenant/portfolio/project repository
-async fn get_changes(
+/// Full-text search of the contents of the changes applied to a
+/// repository
+#[cfg(feature = "content-index")]
+async fn search_content(
     State(state): State<AppState>,
     Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
-    Query(params): Query<ChangesQuery>,
-) -> ApiResult<Json<Vec<ChangeInfo>>> {
-    // Validate tenant, portfolio and project IDs following AGENTS.md validation patterns
+    Query(query): Query<SearchQuery>,
+) -> ApiResult<Json<SearchResults>> {
     validate_id(&tenant_id, "tenant_id")?;
     validate_id(&portfolio_id, "portfolio_id")?;
     validate_id(&project_id, "project_id")?;
 
-    // Construct repository path: /mount/tenant_id/portfolio_id/project_id/.atomic
     let repo_path = state
-        .base_mount_path
-        .join(&tenant_id)
-        .join(&portfolio_id)
-        .join(&project_id);
-
-    // Validate repository exists
-    if !repo_path.exists() {
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
+    if !repo_path.join(libatomic::DOT_DIR).exists() {
         warn!("Repository not found: {}", repo_path.display());
         return Err(ApiError::repository_not_found(repo_path.to_string_lossy()));
     }
+    let index = state
+        .content_index
+        .clone()
+        .ok_or_else(|| ApiError::internal("Content index is not enabled"))?;
+    let filter = match query.filter {
+        Some(ref name) => Some(saved_filters(&repo_path).expand(name)?),
+        None => None,
+    };
+    // The first search of a repository builds its index.
+    let results = tokio::task::spawn_blocking(move || match filter {
+        Some(filter) => search_filtered(&index, &repo_path, &query, filter),
+        None => index.search(&repo_path, &query),
+    })
+    .await
+    .map_err(|e| ApiError::internal(format!("Search task failed: {}", e)))??;
+    Ok(Json(results))
+}
 
-    // Validate .atomic directory exists
-    let atomic_path = repo_path.join(".atomic");
-    if !atomic_path.exists() {
-        warn!("No .atomic directory found at: {}", atomic_path.display());
-        return Err(ApiError::repository_not_found(
-            atomic_path.to_string_lossy(),
-        ));
-    }
-
-    // Open repository on demand to avoid thread safety issues
-    let repository = Repository::find_root(Some(repo_path.clone()))
+/// Search hits in files under the paths of `filter`, of changes matching
+/// its other criteria
+#[cfg(feature = "content-index")]
+fn search_filtered(
+    index: &ContentIndex,
+    repo_path: &std::path::Path,
+    query: &SearchQuery,
+    mut filter: FilterDefinition,
+) -> ApiResult<SearchResults> {
+    let repository = Repository::find_root(Some(repo_path.to_path_buf()))
         .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+    // Paths are matched against the file of each hit rather than against
+    // all the files of its change.
+    let paths = std::mem::take(&mut filter.paths);
+    let by_path = FilterDefinition {
+        paths,
+        ..FilterDefinition::default()
+    };
+    let mut changes = std::collections::HashMap::new();
+    index.search_where(repo_path, query, |hit| {
+        by_path.matches_path(&hit.path)
+            && *changes.entry(hit.change.clone()).or_insert_with(|| {
+                libatomic::Hash::from_base32(hit.change.as_bytes())
+                    .and_then(|h| change_info_summary(&repository, &h, false, &Default::default()))
+                    .map_or(false, |c| change_matches(&repository, &c, &filter))
+            })
+    })
+}
+
+/// Health check endpoint. A degraded server still serves reads, so it is
+/// reported but not as a failure.
+async fn health_check(State(state): State<AppState>) -> Json<HealthResponse> {
+    let status = if state.degraded.is_degraded() {
+        "degraded"
+    } else {
+        "healthy"
+    };
+    Json(HealthResponse {
+        status: status.to_string(),
+        version: crate::VERSION.to_string(),
+    })
+}
+
+/// Answer `503` to requests that write while the server is degraded
+async fn reject_writes_when_degraded(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response<Body> {
+    if !matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) {
+        if let Err(e) = state.degraded.check_write() {
+            warn!("Rejected {} {}: {}", request.method(), request.uri(), e);
+            return e.into_response();
+        }
+    }
+    next.run(request).await
+}
+
+/// Answer `429` to the requests of clients over their rate, see
+/// [`crate::limits`]. This runs after authentication: authenticated
+/// requests take a token from the bucket of their principal, and the
+/// others from the bucket of their tenant.
+async fn limit_rate(State(state): State<AppState>, request: Request, next: Next) -> Response<Body> {
+    let key = match request.extensions().get::<crate::auth::Principal>() {
+        Some(principal) => Some(RateKey::Principal(&principal.name)),
+        None => limits::tenant_id(request.uri().path()).map(RateKey::Tenant),
+    };
+    if let Some(key) = key {
+        if let Err(e) = state.limits.check_rate(key, std::time::Instant::now()) {
+            warn!("Rejected {} {}: {}", request.method(), request.uri(), e);
+            return e.into_response();
+        }
+    }
+    next.run(request).await
+}
+
+/// Answer `429` to the requests of peers over their rate, before
+/// authentication, and `413` to applies and uploads over their body limit,
+/// see [`crate::limits`]. Bodies without a `Content-Length` are read up to
+/// the limit.
+async fn enforce_limits(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response<Body> {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<std::net::SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    if let Some(peer) = peer {
+        if let Err(e) = state
+            .limits
+            .check_rate(RateKey::Peer(peer), std::time::Instant::now())
+        {
+            warn!("Rejected {} {}: {}", request.method(), request.uri(), e);
+            return e.into_response();
+        }
+    }
+    let path = request.uri().path();
+    let kind = match BodyKind::of(request.method(), path) {
+        Some(kind) => kind,
+        None => return next.run(request).await,
+    };
+    let length = request
+        .headers()
+        .get(axum::http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(length) = length {
+        if let Err(e) = state.limits.check_body(kind, length) {
+            warn!("Rejected {} {}: {}", request.method(), request.uri(), e);
+            return e.into_response();
+        }
+        return next.run(request).await;
+    }
+    use futures_util::StreamExt;
+    let (parts, body) = request.into_parts();
+    let mut stream = body.into_data_stream();
+    let mut buf = Vec::new();
+    while let Some(chunk) = stream.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                return ApiError::internal(format!("Failed to read request body: {}", e))
+                    .into_response()
+            }
+        };
+        buf.extend_from_slice(&chunk);
+        if let Err(e) = state.limits.check_body(kind, buf.len() as u64) {
+            warn!("Rejected {} {}: {}", parts.method, parts.uri, e);
+            return e.into_response();
+        }
+    }
+    next.run(Request::from_parts(parts, Body::from(buf))).await
+}
+
+/// Answer `503` to requests that write once the server is shutting down
+async fn reject_writes_when_shutting_down(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response<Body> {
+    if !matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) {
+        if let Err(e) = state.shutdown.check_write() {
+            warn!("Rejected {} {}: {}", request.method(), request.uri(), e);
+            return e.into_response();
+        }
+    }
+    next.run(request).await
+}
+
+/// Path of the repository of the request at `path`, if it is under a
+/// repository and its IDs are valid
+fn request_repository(state: &AppState, path: &str) -> Option<PathBuf> {
+    match storage::repository_ids(path) {
+        Some((tenant_id, portfolio_id, project_id))
+            if validate_id(tenant_id, "tenant_id").is_ok()
+                && validate_id(portfolio_id, "portfolio_id").is_ok()
+                && validate_id(project_id, "project_id").is_ok() =>
+        {
+            state
+                .jail
+                .resolve(
+                    tenant_id,
+                    std::path::Path::new(portfolio_id).join(project_id),
+                )
+                .ok()
+        }
+        _ => None,
+    }
+}
+
+/// Take the lease of the repository of requests that write, and forward
+/// them to the instance holding it (or reject them) if it's another one,
+/// see [`crate::lease`]
+async fn coordinate_writes(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response<Body> {
+    let leases = match state.leases {
+        Some(ref leases) => leases.clone(),
+        None => return next.run(request).await,
+    };
+    if matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) {
+        return next.run(request).await;
+    }
+    let repo_path = match request_repository(&state, request.uri().path()) {
+        // Repositories being created have no lease yet.
+        Some(repo_path) if repo_path.join(libatomic::DOT_DIR).is_dir() => repo_path,
+        _ => return next.run(request).await,
+    };
+    let leadership = {
+        let leases = leases.clone();
+        tokio::task::spawn_blocking(move || leases.check_write(&repo_path)).await
+    };
+    let lease = match leadership {
+        Ok(Ok(Leadership::Leader)) => return next.run(request).await,
+        Ok(Ok(Leadership::Follower(lease))) => lease,
+        Ok(Err(e)) => return e.into_response(),
+        Err(e) => return ApiError::internal(format!("Lease task failed: {}", e)).into_response(),
+    };
+    // Writes are forwarded once, so that instances disagreeing on the
+    // lease don't forward them back and forth.
+    let forwarded = request
+        .headers()
+        .contains_key(crate::lease::FORWARDED_HEADER);
+    match lease.url {
+        Some(ref url) if leases.config().forward && !forwarded => {
+            debug!(
+                "Forwarding {} {} to {}",
+                request.method(),
+                request.uri(),
+                url
+            );
+            forward_write(url, &leases.config().instance, request)
+                .await
+                .unwrap_or_else(|e| e.into_response())
+        }
+        _ => {
+            warn!(
+                "Rejected {} {}: leased by {}",
+                request.method(),
+                request.uri(),
+                lease.instance
+            );
+            ApiError::not_leader(
+                format!(
+                    "Repository leased by instance {} until {}",
+                    lease.instance, lease.expires
+                ),
+                lease.url,
+            )
+            .into_response()
+        }
+    }
+}
+
+/// Send `request` to the instance at `url`, on behalf of `instance`, and
+/// answer with its response
+async fn forward_write(url: &str, instance: &str, request: Request) -> ApiResult<Response<Body>> {
+    let (parts, body) = request.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to read request body: {}", e)))?;
+    let target = format!(
+        "{}{}",
+        url.trim_end_matches('/'),
+        parts.uri.path_and_query().map_or("", |p| p.as_str())
+    );
+    let method = reqwest::Method::from_bytes(parts.method.as_str().as_bytes())
+        .map_err(|e| ApiError::internal(format!("Invalid method: {}", e)))?;
+    let mut req = reqwest::Client::new().request(method, &target).body(body);
+    for (name, value) in parts.headers.iter() {
+        if name != axum::http::header::HOST {
+            req = req.header(name.as_str(), value.as_bytes());
+        }
+    }
+    let res = req
+        .header(crate::lease::FORWARDED_HEADER, instance)
+        .send()
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to forward to {}: {}", url, e)))?;
+    let mut response = Response::builder().status(res.status().as_u16());
+    for (name, value) in res.headers().iter() {
+        if name != reqwest::header::TRANSFER_ENCODING && name != reqwest::header::CONNECTION {
+            response = response.header(name.as_str(), value.as_bytes());
+        }
+    }
+    let body = res
+        .bytes()
+        .await
+        .map_err(|e| ApiError::internal(format!("Failed to read forwarded response: {}", e)))?;
+    response
+        .body(Body::from(body))
+        .map_err(|e| ApiError::internal(format!("Failed to build response: {}", e)))
+}
+
+/// Negotiate the version of protocol requests, rejecting the versions that
+/// aren't served, and answer with the negotiated version, see
+/// [`crate::versions`]
+async fn negotiate_protocol(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response<Body> {
+    if !versions::is_protocol_path(request.uri().path()) {
+        return next.run(request).await;
+    }
+    let version = match state.protocols.negotiate(request.headers()) {
+        Ok(version) => version,
+        Err(e) => {
+            warn!("Rejected {} {}: {}", request.method(), request.uri(), e);
+            return e.into_response();
+        }
+    };
+    debug!("Serving {} at protocol version {}", request.uri(), version);
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .insert(versions::VERSION_HEADER, version.into());
+    response
+}
+
+/// Rehydrate archived repositories before serving their requests, reject
+/// writes to repositories over their quota, and account for the storage,
+/// activity and API calls of the repositories, see [`crate::storage`]
+async fn manage_storage(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response<Body> {
+    let repo_path = match request_repository(&state, request.uri().path()) {
+        Some(repo_path) => repo_path,
+        // Let the handler answer.
+        None => return next.run(request).await,
+    };
+
+    if repo_path.join(storage::ARCHIVED_FILE).exists() {
+        let (storage, path) = (state.storage.clone(), repo_path.clone());
+        match tokio::task::spawn_blocking(move || storage.rehydrate(&path)).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                error!("Failed to rehydrate {}: {}", repo_path.display(), e);
+                return e.into_response();
+            }
+            Err(e) => {
+                return ApiError::internal(format!("Rehydration task failed: {}", e))
+                    .into_response()
+            }
+        }
+    }
+
+    let write = !matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    if write {
+        if let Err(e) = state.storage.check_write(&repo_path) {
+            warn!("Rejected {} {}: {}", request.method(), request.uri(), e);
+            return e.into_response();
+        }
+    }
+    // Validations of uploads are checked against the quota like the
+    // uploads, but don't write anything.
+    let validation = request
+        .uri()
+        .query()
+        .map_or(false, |q| q.split('&').any(|p| p.starts_with("validate=")));
+    let response = next.run(request).await;
+    if !repo_path.join(libatomic::DOT_DIR).is_dir() {
+        return response;
+    }
+    let call = write && !validation;
+    let write = call && response.status().is_success();
+    let storage = state.storage.clone();
+    tokio::task::spawn_blocking(move || {
+        storage.count_call(&repo_path, call);
+        let result = if write {
+            storage.record_write(&repo_path).map(|_| ())
+        } else {
+            storage.record_access(&repo_path)
+        };
+        if let Err(e) = result {
+            warn!("Failed to account for {}: {}", repo_path.display(), e);
+        }
+    });
+    response
+}
+
+/// Status of an apply submitted with `async=true`
+async fn get_operation(
+    State(state): State<AppState>,
+    Path(operation_id): Path<String>,
+) -> ApiResult<Response<Body>> {
+    let status = uuid::Uuid::parse_str(&operation_id)
+        .ok()
+        .and_then(|id| state.applies.status(&id));
+    let (code, body) = match status {
+        Some(status) => (
+            StatusCode::OK,
+            serde_json::to_vec(&status)
+                .map_err(|e| ApiError::internal(format!("Failed to serialize status: {}", e)))?,
+        ),
+        None => (
+            StatusCode::NOT_FOUND,
+            format!("{{\"error\":\"Unknown operation {}\"}}", operation_id).into_bytes(),
+        ),
+    };
+    Response::builder()
+        .status(code)
+        .header("content-type", "application/json")
+        .body(Body::from(body))
+        .map_err(|e| ApiError::internal(format!("Failed to build response: {}", e)))
+}
+
+/// Depths of the apply queues
+async fn get_apply_metrics(State(state): State<AppState>) -> Json<ApplyQueueMetrics> {
+    Json(state.applies.metrics())
+}
+
+/// Size and hit rate of the cache of decompressed change contents, shared
+/// by all the repositories of the server
+async fn get_contents_cache_metrics() -> Json<ContentsCacheMetrics> {
+    Json(ContentsCache::global().metrics())
+}
+
+/// Protocol requests by version, to know when a version can be retired
+async fn get_protocol_metrics(State(state): State<AppState>) -> Json<ProtocolMetrics> {
+    Json(state.protocols.metrics())
+}
+
+/// Schedules and last outcomes of the maintenance tasks
+async fn get_maintenance(State(state): State<AppState>) -> ApiResult<Json<MaintenanceStatus>> {
+    let maintenance = state
+        .maintenance
+        .as_ref()
+        .ok_or_else(|| ApiError::busy("Maintenance is not started"))?;
+    Ok(Json(maintenance.status()))
+}
+
+/// Run a maintenance task now, whether the server is idle or not
+async fn trigger_maintenance(
+    State(state): State<AppState>,
+    Path(task): Path<String>,
+) -> ApiResult<(StatusCode, Json<TaskStatus>)> {
+    let task: MaintenanceTask = task.parse().map_err(ApiError::invalid_query)?;
+    let maintenance = state
+        .maintenance
+        .as_ref()
+        .ok_or_else(|| ApiError::busy("Maintenance is not started"))?;
+    Ok((StatusCode::ACCEPTED, Json(maintenance.trigger(task)?)))
+}
+
+/// Counters of the webhook deliveries, zero if webhooks aren't configured
+async fn get_webhook_metrics(State(state): State<AppState>) -> Json<WebhookMetrics> {
+    Json(
+        state
+            .webhooks
+            .as_ref()
+            .map(Webhooks::metrics)
+            .unwrap_or_default(),
+    )
+}
+
+/// Current mode of the server, and resources at the last check
+async fn get_degraded_metrics(State(state): State<AppState>) -> Json<DegradedMetrics> {
+    Json(state.degraded.metrics())
+}
+
+/// Rate and body limits, and requests rejected by them
+async fn get_limits_metrics(State(state): State<AppState>) -> Json<LimitsMetrics> {
+    Json(state.limits.metrics())
+}
+
+/// Download a backup of a repository as a tar archive, see
+/// [`crate::backup`]. Interrupted downloads are resumed with `Range:
+/// bytes=<start>-`, and `If-Range` with the ETag of the first response
+/// makes sure that the rest is of the same archive.
+async fn get_backup(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> ApiResult<Response<Body>> {
+    use axum::http::header;
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+    validate_id(&tenant_id, "tenant_id")?;
+    validate_id(&portfolio_id, "portfolio_id")?;
+    validate_id(&project_id, "project_id")?;
+    let repo_path = state
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
+    // Open the archive right away, before a later backup replaces it.
+    let (backup, file) = tokio::task::spawn_blocking(move || {
+        let backup = crate::backup::prepare(&repo_path)?;
+        let file = std::fs::File::open(&backup.path)?;
+        Ok::<_, ApiError>((backup, file))
+    })
+    .await
+    .map_err(|e| ApiError::internal(format!("Backup task failed: {}", e)))??;
+
+    let etag = format!("\"{}\"", backup.manifest.version);
+    let len = backup.manifest.size;
+    let same_archive = !headers
+        .get(header::IF_RANGE)
+        .is_some_and(|v| v.as_bytes() != etag.as_bytes());
+    let start = headers
+        .get(header::RANGE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(range_start)
+        .filter(|_| same_archive);
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-tar")
+        .header(header::ETAG, &etag)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"{}-{}-{}.tar\"",
+                tenant_id, portfolio_id, project_id
+            ),
+        );
+    match start {
+        Some(start) if start >= len => {
+            return Ok(Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                .body(Body::empty())
+                .unwrap());
+        }
+        Some(start) => {
+            response = response.status(StatusCode::PARTIAL_CONTENT).header(
+                header::CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, len - 1, len),
+            )
+        }
+        None => response = response.status(StatusCode::OK),
+    }
+    let start = start.unwrap_or(0);
+    let mut file = tokio::fs::File::from_std(file);
+    file.seek(std::io::SeekFrom::Start(start)).await?;
+    let chunks = futures_util::stream::unfold(Some(file), |file| async move {
+        let mut file = file?;
+        let mut buf = vec![0; 64 * 1024];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), Some(file)))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    });
+    Ok(response
+        .header(header::CONTENT_LENGTH, len - start)
+        .body(Body::from_stream(chunks))
+        .unwrap())
+}
+
+/// Storage used by a repository, and its quota
+async fn get_storage(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+) -> ApiResult<Json<StorageReport>> {
+    validate_id(&tenant_id, "tenant_id")?;
+    validate_id(&portfolio_id, "portfolio_id")?;
+    validate_id(&project_id, "project_id")?;
+    let repo_path = state
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
+    if !repo_path.join(libatomic::DOT_DIR).is_dir() {
+        return Err(ApiError::repository_not_found(
+            repo_path.to_string_lossy().to_string(),
+        ));
+    }
+    Ok(Json(state.storage.report(&repo_path)?))
+}
+
+/// Usage of the repositories of a tenant, their totals and the headroom
+/// left under the quota
+async fn get_tenant_usage(
+    State(state): State<AppState>,
+    Path(tenant_id): Path<String>,
+) -> ApiResult<Json<TenantUsage>> {
+    validate_id(&tenant_id, "tenant_id")?;
+    let tenant_root = state.jail.resolve(&tenant_id, "")?;
+    let storage = state.storage.clone();
+    let usage = tokio::task::spawn_blocking(move || storage.tenant_usage(&tenant_root, &tenant_id))
+        .await
+        .map_err(|e| ApiError::internal(format!("Usage task failed: {}", e)))??;
+    Ok(Json(usage))
+}
+
+/// Effective configuration of a repository, and the layer each value
+/// came from, see [`crate::config`]
+async fn get_config(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+) -> ApiResult<Json<ResolvedConfig>> {
+    validate_id(&tenant_id, "tenant_id")?;
+    validate_id(&portfolio_id, "portfolio_id")?;
+    validate_id(&project_id, "project_id")?;
+    let repo_path = state
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
+    if !repo_path.join(libatomic::DOT_DIR).is_dir() {
+        return Err(ApiError::repository_not_found(
+            repo_path.to_string_lossy().to_string(),
+        ));
+    }
+    Ok(Json(state.configs.resolve(
+        &state.jail,
+        &tenant_id,
+        &repo_path,
+    )?))
+}
+
+/// Last events published by the server, newest first
+async fn list_events(
+    State(state): State<AppState>,
+    Query(list): Query<ListQuery>,
+) -> ApiResult<JsonStream> {
+    Ok(list
+        .page(
+            state.events.recent(),
+            |e| e.seq.to_string(),
+            &["seq", "timestamp"],
+        )?
+        .into())
+}
+
+/// Stream the events of a repository as Server-Sent Events, see
+/// [`crate::activity`]
+async fn stream_activity(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    Query(query): Query<ActivityQuery>,
+    headers: HeaderMap,
+) -> ApiResult<impl IntoResponse> {
+    validate_id(&tenant_id, "tenant_id")?;
+    validate_id(&portfolio_id, "portfolio_id")?;
+    validate_id(&project_id, "project_id")?;
+    let repo_path = state
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
+    if !repo_path.join(libatomic::DOT_DIR).exists() {
+        warn!("Repository not found: {}", repo_path.display());
+        return Err(ApiError::repository_not_found(repo_path.to_string_lossy()));
+    }
+    let after = crate::activity::resume_after(&headers, &query)?;
+    Ok(crate::activity::stream(
+        &state.events,
+        repo_path,
+        state.jail.root().to_path_buf(),
+        after,
+        state.shutdown.clone(),
+    ))
+}
+
+/// Get list of changes for tenant/portfolio/project repository
+async fn get_changes(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    Query(params): Query<ChangesQuery>,
+    Query(list): Query<ListQuery>,
+) -> ApiResult<(HeaderMap, JsonStream)> {
+    // Validate tenant, portfolio and project IDs following AGENTS.md validation patterns
+    validate_id(&tenant_id, "tenant_id")?;
+    validate_id(&portfolio_id, "portfolio_id")?;
+    validate_id(&project_id, "project_id")?;
+
+    // Construct repository path: /mount/tenant_id/portfolio_id/project_id/.atomic
+    let repo_path = state
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
+
+    // Validate repository exists
+    if !repo_path.exists() {
+        warn!("Repository not found: {}", repo_path.display());
+        return Err(ApiError::repository_not_found(repo_path.to_string_lossy()));
+    }
+
+    // Validate .atomic directory exists
+    let atomic_path = repo_path.join(".atomic");
+    if !atomic_path.exists() {
+        warn!("No .atomic directory found at: {}", atomic_path.display());
+        return Err(ApiError::repository_not_found(
+            atomic_path.to_string_lossy(),
+        ));
+    }
+
+    // Open repository on demand to avoid thread safety issues
+    let (repository, source) =
+        open_for_read(&state, &repo_path, &tenant_id, &portfolio_id, &project_id)?;
 
     debug!(
         "Opened repository at: {}, pristine path: {}",
@@ -370,143 +1746,2729 @@ async fn get_changes(
         repo_path.join(".atomic/pristine/db").display()
     );
 
+    if params.order.is_some() && (params.group_by.is_some() || params.filter.is_some()) {
+        return Err(ApiError::invalid_query(
+            "order cannot be combined with group_by or filter".to_string(),
+        ));
+    }
+
+    let filter = match params.filter {
+        Some(_) if params.group_by.is_some() => {
+            return Err(ApiError::invalid_query(
+                "filter cannot be combined with group_by".to_string(),
+            ))
+        }
+        Some(ref name) => Some(saved_filters(&repo_path).expand(name)?),
+        None => None,
+    };
+
+    if params.group_by == Some(GroupBy::DependencyCluster) {
+        let threads = read_change_threads(
+            &state.clusters,
+            &repository,
+            params.limit,
+            params.offset,
+            params.include_ai_attribution,
+            params.hide_superseded,
+        )
+        .map_err(|e| ApiError::internal(format!("Failed to group changes: {}", e)))?;
+        return Ok((source.headers(), JsonStream::items(threads)));
+    }
+
+    let mut headers = source.headers();
+    if params.order.is_none() && list.sort(CHANGE_SORT_FIELDS)?.is_none() && filter.is_none() {
+        let (page_headers, stream) = stream_changes_page(
+            repository,
+            list,
+            params.include_ai_attribution,
+            params.hide_superseded,
+            params.envelope,
+        )?;
+        headers.extend(page_headers);
+        return Ok((headers, stream));
+    }
+
     // Read actual changes from the filesystem changestore with AI attribution
-    let changes = read_changes_from_filesystem(
+    let page = if params.order == Some(ChangeOrder::Topological) {
+        read_change_levels(
+            &state.clusters,
+            &repository,
+            &list,
+            params.include_ai_attribution,
+            params.hide_superseded,
+        )?
+    } else {
+        read_changes_page(
+            &repository,
+            &list,
+            params.include_ai_attribution,
+            params.hide_superseded,
+            filter.as_ref(),
+        )?
+    };
+    headers.extend(page.headers());
+    if params.envelope {
+        Ok((headers, JsonStream::page(page)))
+    } else {
+        Ok((headers, JsonStream::items(page.items)))
+    }
+}
+
+/// Fields the changes endpoint can sort on
+const CHANGE_SORT_FIELDS: &[&str] = &["timestamp", "message", "author"];
+
+/// A page of the changes of the current channel, sorted or filtered.
+/// This reads the headers of all changes; pages in log order are streamed
+/// by [`stream_changes_page`] instead.
+fn read_changes_page(
+    repository: &Repository,
+    list: &ListQuery,
+    include_ai_attribution: bool,
+    hide_superseded: bool,
+    filter: Option<&FilterDefinition>,
+) -> ApiResult<Page> {
+    let mut changes = read_changes_from_filesystem(
+        repository,
+        None,
+        0,
+        usize::MAX,
+        include_ai_attribution,
+        hide_superseded,
+    )
+    .map_err(|e| ApiError::internal(format!("Failed to read changes: {}", e)))?
+    .changes;
+    if let Some(filter) = filter {
+        changes.retain(|c| change_matches(repository, c, filter))
+    }
+    list.page(changes, |c| c.hash.clone(), CHANGE_SORT_FIELDS)
+}
+
+/// Stream a page of the changes of the current channel, newest first.
+/// The hashes of the page are read from the log first, so that the
+/// pagination headers are known before the changes, which are then read
+/// and serialized one at a time, see [`crate::stream`]. Changes whose
+/// header can't be read are left out, but still take their place in the
+/// page.
+fn stream_changes_page(
+    repository: Repository,
+    list: ListQuery,
+    include_ai_attribution: bool,
+    hide_superseded: bool,
+    envelope: bool,
+) -> ApiResult<(HeaderMap, JsonStream)> {
+    let after = match list.after()? {
+        Some(after) => Some(
+            libatomic::Hash::from_base32(after.as_bytes())
+                .ok_or_else(|| ApiError::invalid_query("Invalid cursor".to_string()))?,
+        ),
+        None => None,
+    };
+    let supersessions = supersede_index(&repository);
+    let window = changes_window(
         &repository,
-        params.limit as u64,
-        params.offset as u64,
-        params.include_ai_attribution,
+        after.as_ref(),
+        list.offset.unwrap_or(0),
+        list.limit(),
+        hide_superseded,
+        &supersessions,
     )
     .map_err(|e| ApiError::internal(format!("Failed to read changes: {}", e)))?;
+    if after.is_some() && !window.found_cursor {
+        return Err(ApiError::invalid_query(
+            "Cursor does not match any change".to_string(),
+        ));
+    }
+    let next_cursor = if window.more {
+        window.hashes.last().map(|h| encode_cursor(&h.to_base32()))
+    } else {
+        None
+    };
+    let headers = crate::query::page_headers(next_cursor.as_deref(), window.total);
+    let (hashes, total) = (window.hashes, window.total);
+    let push_changes = move |sink: &mut Sink| -> ApiResult<()> {
+        for hash in hashes.iter() {
+            if let Some(change) =
+                change_info_summary(&repository, hash, include_ai_attribution, &supersessions)
+            {
+                sink.push(&list.select(crate::query::to_value(&change)?))?;
+            }
+        }
+        Ok(())
+    };
+    let stream = if envelope {
+        JsonStream::object("items", move |sink| {
+            push_changes(sink)?;
+            Ok(page_trailer(next_cursor, total))
+        })
+    } else {
+        JsonStream::array(push_changes)
+    };
+    Ok((headers, stream))
+}
 
-    // Apply pagination
-    let start = params.offset as usize;
-    let end = std::cmp::min(start + params.limit as usize, changes.len());
-    let page = if start < changes.len() {
-        changes[start..end].to_vec()
+/// Whether `change` matches `filter`, reading its note and touched files
+/// if the filter needs them. Changes that can't be read don't match.
+fn change_matches(repository: &Repository, change: &ChangeInfo, filter: &FilterDefinition) -> bool {
+    let Some(hash) = libatomic::Hash::from_base32(change.hash.as_bytes()) else {
+        return false;
+    };
+    let note = if filter.needs_notes() {
+        let dot_dir = repository.path.join(libatomic::DOT_DIR);
+        atomic_repository::notes::Notes::new(dot_dir.join(atomic_repository::NOTES_DIR))
+            .get(&hash)
+            .ok()
+            .flatten()
+    } else {
+        None
+    };
+    let paths: Vec<String> = if filter.needs_paths() {
+        match repository.changes.get_change(&hash) {
+            Ok(c) => c.changes.iter().map(|h| h.path().to_string()).collect(),
+            Err(e) => {
+                warn!("Failed to read change {}: {}", change.hash, e);
+                return false;
+            }
+        }
     } else {
         Vec::new()
     };
+    filter.matches(&Candidate {
+        author: &change.author,
+        message: &change.message,
+        description: change.description.as_deref(),
+        note: note.as_ref().map(|n| n.text.as_str()),
+        paths: &paths,
+    })
+}
+
+/// Saved filters of the repository at `repo_path`
+fn saved_filters(repo_path: &std::path::Path) -> SavedFilters {
+    SavedFilters::new(
+        repo_path
+            .join(libatomic::DOT_DIR)
+            .join(crate::filters::FILTERS_FILE),
+    )
+}
+
+/// Saved filters of the repository at `tenant_id/portfolio_id/project_id`,
+/// checking `name` if given
+fn repository_filters(
+    state: &AppState,
+    tenant_id: &str,
+    portfolio_id: &str,
+    project_id: &str,
+    name: Option<&str>,
+) -> ApiResult<SavedFilters> {
+    validate_id(tenant_id, "tenant_id")?;
+    validate_id(portfolio_id, "portfolio_id")?;
+    validate_id(project_id, "project_id")?;
+    if let Some(name) = name {
+        validate_id(name, "filter")?;
+    }
+    let repo_path = state.jail.repository(tenant_id, portfolio_id, project_id)?;
+    if !repo_path.join(libatomic::DOT_DIR).exists() {
+        warn!("Repository not found: {}", repo_path.display());
+        return Err(ApiError::repository_not_found(repo_path.to_string_lossy()));
+    }
+    Ok(saved_filters(&repo_path))
+}
+
+/// List the saved filters of a repository
+async fn list_filters(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+) -> ApiResult<JsonStream> {
+    let filters = repository_filters(&state, &tenant_id, &portfolio_id, &project_id, None)?;
+    Ok(JsonStream::items(filters.list()?))
+}
+
+/// Get a saved filter
+async fn get_filter(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, name)): Path<(String, String, String, String)>,
+) -> ApiResult<Response<Body>> {
+    let filters = repository_filters(&state, &tenant_id, &portfolio_id, &project_id, Some(&name))?;
+    Ok(match filters.get(&name)? {
+        Some(filter) => Json(filter).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+/// Save a filter, replacing the previous one of that name
+async fn put_filter(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, name)): Path<(String, String, String, String)>,
+    Json(definition): Json<FilterDefinition>,
+) -> ApiResult<Json<SavedFilter>> {
+    let filters = repository_filters(&state, &tenant_id, &portfolio_id, &project_id, Some(&name))?;
+    let filter = filters.put(&name, definition)?;
+    info!("Saved filter {} of {}", name, project_id);
+    Ok(Json(filter))
+}
+
+/// Delete a saved filter
+async fn delete_filter(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, name)): Path<(String, String, String, String)>,
+) -> ApiResult<StatusCode> {
+    let filters = repository_filters(&state, &tenant_id, &portfolio_id, &project_id, Some(&name))?;
+    Ok(if filters.remove(&name)? {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+/// Resolve a prefix of change hash to the full hash, see
+/// [`crate::resolve`]
+async fn resolve_prefix(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    Query(query): Query<ResolveQuery>,
+) -> ApiResult<Json<Resolved>> {
+    validate_id(&tenant_id, "tenant_id")?;
+    validate_id(&portfolio_id, "portfolio_id")?;
+    validate_id(&project_id, "project_id")?;
+    if query.prefix.is_empty() {
+        return Err(ApiError::invalid_query("Empty hash prefix"));
+    }
+    let repo_path = state
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
+    if !repo_path.join(libatomic::DOT_DIR).exists() {
+        warn!("Repository not found: {}", repo_path.display());
+        return Err(ApiError::repository_not_found(repo_path.to_string_lossy()));
+    }
+    let hash = crate::resolve::resolve_at(&repo_path, &query.prefix)?;
+    Ok(Json(Resolved {
+        prefix: query.prefix,
+        hash: hash.to_base32(),
+    }))
+}
+
+/// Get specific change by ID for tenant/portfolio/project repository
+async fn get_change(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, change_id)): Path<(String, String, String, String)>,
+    Query(params): Query<ChangesQuery>,
+) -> ApiResult<Json<ChangeInfo>> {
+    // Validate tenant, portfolio and project IDs following AGENTS.md validation patterns
+    validate_id(&tenant_id, "tenant_id")?;
+    validate_id(&portfolio_id, "portfolio_id")?;
+    validate_id(&project_id, "project_id")?;
+
+    // Construct repository path: /mount/tenant_id/portfolio_id/project_id/.atomic
+    let repo_path = state
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
+
+    // Validate repository exists
+    if !repo_path.exists() {
+        warn!("Repository not found: {}", repo_path.display());
+        return Err(ApiError::repository_not_found(repo_path.to_string_lossy()));
+    }
+
+    // Open repository on demand to avoid thread safety issues
+    let repository = Repository::find_root(Some(repo_path))
+        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+
+    // Changes can be referenced by a prefix of their hash
+    let change_id = crate::resolve::resolve(&repository, &change_id)?.to_base32();
+
+    // Read specific change from filesystem with optional diff and AI attribution
+    match read_change_from_filesystem(
+        &repository,
+        &change_id,
+        params.include_diff,
+        params.include_ai_attribution,
+    ) {
+        Ok(Some(change)) => Ok(Json(change)),
+        Ok(None) => Err(ApiError::Repository(
+            crate::error::RepositoryError::ChangeNotFound { change_id },
+        )),
+        Err(e) => Err(ApiError::internal(format!("Failed to read change: {}", e))),
+    }
+}
+
+/// Diff of a change against the state just before it in its channel, or
+/// an earlier state, see [`crate::diff`]
+async fn get_change_diff(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, change_id)): Path<(String, String, String, String)>,
+    Query(query): Query<DiffQuery>,
+) -> ApiResult<Json<ChangeDiff>> {
+    validate_id(&tenant_id, "tenant_id")?;
+    validate_id(&portfolio_id, "portfolio_id")?;
+    validate_id(&project_id, "project_id")?;
+    let repo_path = state
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
+    if !repo_path.join(libatomic::DOT_DIR).exists() {
+        return Err(ApiError::repository_not_found(repo_path.to_string_lossy()));
+    }
+    let repository = Repository::find_root(Some(repo_path))
+        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+    let diff =
+        tokio::task::spawn_blocking(move || crate::diff::read(&repository, &change_id, &query))
+            .await
+            .map_err(|e| ApiError::internal(format!("Diff task failed: {}", e)))??;
+    Ok(Json(diff))
+}
+
+/// Get a tag's header and signature verification status
+async fn get_tag(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, tag_state)): Path<(String, String, String, String)>,
+) -> ApiResult<Json<TagInfo>> {
+    validate_id(&tenant_id, "tenant_id")?;
+    validate_id(&portfolio_id, "portfolio_id")?;
+    validate_id(&project_id, "project_id")?;
+
+    let repo_path = state
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
+    let changes_dir = repo_path
+        .join(libatomic::DOT_DIR)
+        .join(atomic_repository::CHANGES_DIR);
+    if !changes_dir.exists() {
+        warn!("Repository not found: {}", repo_path.display());
+        return Err(ApiError::repository_not_found(repo_path.to_string_lossy()));
+    }
+
+    let not_found = || {
+        ApiError::Repository(crate::error::RepositoryError::ChangeNotFound {
+            change_id: tag_state.clone(),
+        })
+    };
+    let merkle = libatomic::Merkle::from_base32(tag_state.as_bytes()).ok_or_else(not_found)?;
+    let mut tag_path = changes_dir;
+    libatomic::changestore::filesystem::push_tag_filename(&mut tag_path, &merkle);
+    if !tag_path.exists() {
+        return Err(not_found());
+    }
+
+    let mut tag = libatomic::tag::OpenTagFile::open(&tag_path, &merkle)
+        .map_err(|e| ApiError::internal(format!("Failed to open tag file: {}", e)))?;
+    let header = tag
+        .header()
+        .map_err(|e| ApiError::internal(format!("Failed to read tag header: {}", e)))?;
+    let signature = tag
+        .verify()
+        .map_err(|e| ApiError::internal(format!("Failed to verify tag: {}", e)))?;
+    let metadata = {
+        let repository = Repository::find_root(Some(repo_path))
+            .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+        let txn = repository
+            .pristine
+            .txn_begin()
+            .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+        match txn
+            .get_tag(&merkle)
+            .map_err(|e| ApiError::internal(format!("Failed to read tag metadata: {}", e)))?
+        {
+            Some(serialized) => {
+                serialized
+                    .to_tag()
+                    .map_err(|e| ApiError::internal(format!("Failed to read tag metadata: {}", e)))?
+                    .metadata
+            }
+            None => Default::default(),
+        }
+    };
+
+    Ok(Json(TagInfo {
+        state: merkle.to_base32(),
+        authors: header
+            .authors
+            .iter()
+            .map(|a| extract_author_name(std::slice::from_ref(a)))
+            .collect(),
+        message: header.message,
+        timestamp: header.timestamp,
+        signature,
+        metadata,
+    }))
+}
+
+/// Query parameters of the endpoints listing the contents of a channel
+#[derive(Debug, Deserialize)]
+pub struct ChannelQuery {
+    /// Channel to read, the current channel by default
+    channel: Option<String>,
+}
+
+/// Query parameters of `GET .../code/tags`
+#[derive(Debug, Deserialize)]
+pub struct TagQuery {
+    /// Channel to read, the current channel by default
+    channel: Option<String>,
+    /// Only list the tags whose version is in this range, e.g.
+    /// `>=1.2.0, <2` or `1.x`, see [`libatomic::pristine::VersionRange`]
+    range: Option<String>,
+}
+
+/// Query parameters of `POST .../code/tags`
+#[derive(Debug, Deserialize)]
+pub struct CreateTagQuery {
+    /// Channel to tag, the current channel by default
+    channel: Option<String>,
+    /// Only compute what the tag would cover, without creating it
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Semantic version of a tag, `None` for tags without a valid one
+fn tag_version(tag: &TagSummary) -> Option<libatomic::pristine::SemanticVersion> {
+    let version = tag.version.as_deref()?;
+    libatomic::pristine::SemanticVersion::parse(version.strip_prefix('v').unwrap_or(version)).ok()
+}
+
+/// A channel, for the channels list
+#[derive(Debug, Serialize)]
+pub struct ChannelSummary {
+    name: String,
+    /// Number of changes and tags applied to the channel
+    changes: u64,
+    /// Current state, in base32
+    state: String,
+}
+
+/// A tag, for the tags list
+#[derive(Debug, Serialize)]
+pub struct TagSummary {
+    /// Channel state of the tag, in base32
+    state: String,
+    /// Position of the tag in the channel log
+    position: u64,
+    version: Option<String>,
+    consolidated_change_count: u64,
+    /// Consolidation time, in seconds since the epoch
+    timestamp: u64,
+    metadata: std::collections::HashMap<String, String>,
+}
+
+/// What a tag of the current state of a channel would cover, see
+/// [`libatomic::tag::preview`]
+#[derive(Debug, Serialize)]
+pub struct TagPreviewResponse {
+    /// Channel state the tag would be of, in base32
+    state: String,
+    previous_tag: Option<String>,
+    /// Position in the channel log of the first covered change
+    start_position: u64,
+    consolidated_change_count: u64,
+    consolidated_changes: Vec<String>,
+    /// Dependencies of a change recorded now, without and with the tag
+    dependency_count_before: u64,
+    dependency_count_after: u64,
+    dependency_reduction: u64,
+    authors: Vec<TagPreviewAuthor>,
+    ai_assisted_changes: u64,
+    human_authored_changes: u64,
+    /// Share of AI-assisted changes, in percent
+    ai_percentage: f32,
+    average_confidence: f32,
+    already_tagged: bool,
+}
+
+/// An author of the changes covered by a tag preview
+#[derive(Debug, Serialize)]
+pub struct TagPreviewAuthor {
+    name: String,
+    changes: u64,
+}
+
+/// Attribution of a change, for the attribution list
+#[derive(Debug, Serialize)]
+pub struct ChangeAttributionSummary {
+    hash: String,
+    timestamp: String,
+    #[serde(flatten)]
+    attribution: AIAttribution,
+}
+
+/// Repository at `tenant_id/portfolio_id/project_id`, opened for reading
+/// (from a replica if configured).
+fn open_listed_repository(
+    state: &AppState,
+    tenant_id: &str,
+    portfolio_id: &str,
+    project_id: &str,
+) -> ApiResult<(Repository, ReadSource)> {
+    validate_id(tenant_id, "tenant_id")?;
+    validate_id(portfolio_id, "portfolio_id")?;
+    validate_id(project_id, "project_id")?;
+
+    let repo_path = state.jail.repository(tenant_id, portfolio_id, project_id)?;
+    if !repo_path.join(libatomic::DOT_DIR).exists() {
+        warn!("Repository not found: {}", repo_path.display());
+        return Err(ApiError::repository_not_found(repo_path.to_string_lossy()));
+    }
+    open_for_read(state, &repo_path, tenant_id, portfolio_id, project_id)
+}
+
+pub(crate) fn load_listed_channel<T: TxnT>(
+    txn: &T,
+    channel: Option<&str>,
+) -> ApiResult<libatomic::pristine::ChannelRef<T>> {
+    let name =
+        channel.unwrap_or_else(|| txn.current_channel().unwrap_or(libatomic::DEFAULT_CHANNEL));
+    txn.load_channel(name)
+        .map_err(|e| ApiError::internal(format!("Failed to load channel {}: {}", name, e)))?
+        .ok_or_else(|| {
+            ApiError::Repository(crate::error::RepositoryError::ChannelNotFound {
+                channel: name.to_string(),
+            })
+        })
+}
+
+/// List the channels of a repository
+async fn list_channels(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    Query(list): Query<ListQuery>,
+) -> ApiResult<(HeaderMap, JsonStream)> {
+    let (repository, source) =
+        open_listed_repository(&state, &tenant_id, &portfolio_id, &project_id)?;
+    let txn = repository
+        .pristine
+        .txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+    let mut channels = Vec::new();
+    for channel in txn
+        .channels("")
+        .map_err(|e| ApiError::internal(format!("Failed to list channels: {}", e)))?
+    {
+        let channel = channel.read();
+        let changes = match txn
+            .reverse_log(&*channel, None)
+            .map_err(|e| ApiError::internal(format!("Failed to read log: {}", e)))?
+            .next()
+        {
+            Some(entry) => {
+                entry
+                    .map_err(|e| ApiError::internal(format!("Failed to read log entry: {}", e)))?
+                    .0
+                    + 1
+            }
+            None => 0,
+        };
+        let current = libatomic::pristine::current_state(&txn, &*channel)
+            .map_err(|e| ApiError::internal(format!("Failed to read channel state: {}", e)))?;
+        channels.push(ChannelSummary {
+            name: txn.name(&*channel).to_string(),
+            changes,
+            state: current.to_base32(),
+        });
+    }
+    let page = list.page(channels, |c| c.name.clone(), &["name", "changes"])?;
+    Ok((source.headers(), page.into()))
+}
+
+/// List the tags of a channel, newest first
+async fn list_tags(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    Query(params): Query<TagQuery>,
+    Query(list): Query<ListQuery>,
+) -> ApiResult<(HeaderMap, JsonStream)> {
+    const SORTABLE: &[&str] = &[
+        "position",
+        "timestamp",
+        "version",
+        "consolidated_change_count",
+        "semver",
+    ];
+    let range = params
+        .range
+        .as_deref()
+        .map(libatomic::pristine::VersionRange::parse)
+        .transpose()
+        .map_err(|e| ApiError::invalid_query(format!("Invalid version range: {}", e)))?;
+    let sort = list.sort(SORTABLE)?;
+    let (repository, source) =
+        open_listed_repository(&state, &tenant_id, &portfolio_id, &project_id)?;
+    let txn = repository
+        .pristine
+        .txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+    let channel = load_listed_channel(&txn, params.channel.as_deref())?;
+    let channel = channel.read();
+    let mut tags = Vec::new();
+    for entry in txn
+        .rev_iter_tags(txn.tags(&*channel), None)
+        .map_err(|e| ApiError::internal(format!("Failed to iterate tags: {}", e)))?
+    {
+        let (position, tag_bytes) =
+            entry.map_err(|e| ApiError::internal(format!("Failed to read tag entry: {}", e)))?;
+        let Ok(minimal) =
+            libatomic::pristine::SerializedTag::from_bytes_wrapper(tag_bytes).to_tag()
+        else {
+            continue;
+        };
+        let tag = txn
+            .get_tag(&minimal.state)
+            .map_err(|e| ApiError::internal(format!("Failed to read tag metadata: {}", e)))?
+            .and_then(|t| t.to_tag().ok())
+            .unwrap_or(minimal);
+        tags.push(TagSummary {
+            state: tag.state.to_base32(),
+            position: (*position).into(),
+            version: tag.version,
+            consolidated_change_count: tag.consolidated_change_count,
+            timestamp: tag.consolidation_timestamp,
+            metadata: tag.metadata,
+        });
+    }
+    if let Some(ref range) = range {
+        tags.retain(|t| tag_version(t).is_some_and(|v| range.matches(&v)));
+    }
+    let page = match sort {
+        Some((field, descending)) if field == "semver" => {
+            // Tags without a version come last, in either direction.
+            let mut versioned: Vec<_> = tags.into_iter().map(|t| (tag_version(&t), t)).collect();
+            versioned.sort_by(|(a, _), (b, _)| match (a, b) {
+                (Some(a), Some(b)) if descending => b.cmp(a),
+                (Some(a), Some(b)) => a.cmp(b),
+                (a, b) => b.is_some().cmp(&a.is_some()),
+            });
+            let tags = versioned.into_iter().map(|(_, t)| t).collect();
+            ListQuery { sort: None, ..list }.page(tags, |t| t.state.clone(), SORTABLE)?
+        }
+        _ => list.page(tags, |t| t.state.clone(), SORTABLE)?,
+    };
+    Ok((source.headers(), page.into()))
+}
+
+/// Preview the consolidating tag of the current state of a channel. Tags
+/// are created by pushing them, so only dry runs are supported: nothing is
+/// written.
+async fn create_tag(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    Query(params): Query<CreateTagQuery>,
+) -> ApiResult<(HeaderMap, Json<TagPreviewResponse>)> {
+    if !params.dry_run {
+        return Err(ApiError::invalid_query(
+            "Tags are created by pushing them, only dry_run=true is supported",
+        ));
+    }
+    let (repository, source) =
+        open_listed_repository(&state, &tenant_id, &portfolio_id, &project_id)?;
+    let preview = tokio::task::spawn_blocking(move || {
+        let txn = repository
+            .pristine
+            .txn_begin()
+            .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+        let channel = load_listed_channel(&txn, params.channel.as_deref())?;
+        let channel = channel.read();
+        libatomic::tag::preview::preview(&txn, &*channel, &repository.changes)
+            .map_err(|e| ApiError::internal(format!("Failed to preview tag: {}", e)))
+    })
+    .await
+    .map_err(|e| ApiError::internal(format!("Tag preview task failed: {}", e)))??;
+    Ok((
+        source.headers(),
+        Json(TagPreviewResponse {
+            state: preview.state.to_base32(),
+            previous_tag: preview.previous_tag.map(|m| m.to_base32()),
+            start_position: preview.start_position,
+            consolidated_change_count: preview.consolidated_changes.len() as u64,
+            consolidated_changes: preview
+                .consolidated_changes
+                .iter()
+                .map(|h| h.to_base32())
+                .collect(),
+            dependency_count_before: preview.dependency_count_before,
+            dependency_count_after: preview.dependency_count_after,
+            dependency_reduction: preview.dependency_reduction(),
+            authors: preview
+                .authors
+                .into_iter()
+                .map(|a| TagPreviewAuthor {
+                    name: extract_author_name(std::slice::from_ref(&a.author)),
+                    changes: a.changes,
+                })
+                .collect(),
+            ai_assisted_changes: preview.attribution.ai_assisted_changes,
+            human_authored_changes: preview.attribution.human_authored_changes,
+            ai_percentage: preview.attribution.ai_percentage(),
+            average_confidence: preview.attribution.average_confidence,
+            already_tagged: preview.already_tagged,
+        }),
+    ))
+}
+
+/// Summary of the activity of a channel since a state or a time, for
+/// email and chat digests
+async fn get_digest(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    Query(query): Query<DigestQuery>,
+) -> ApiResult<(HeaderMap, Json<Digest>)> {
+    let (repository, source) =
+        open_listed_repository(&state, &tenant_id, &portfolio_id, &project_id)?;
+    let link_base = format!(
+        "/tenant/{}/portfolio/{}/project/{}",
+        tenant_id, portfolio_id, project_id
+    );
+    let events = state.events.recent();
+    let digest = tokio::task::spawn_blocking(move || {
+        crate::digest::read_digest(&repository, &query, &events, &link_base)
+    })
+    .await
+    .map_err(|e| ApiError::internal(format!("Digest task failed: {}", e)))??;
+    Ok((source.headers(), Json(digest)))
+}
+
+/// Resolve a time to the state of a channel at that time
+async fn get_state_at(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    Query(query): Query<SnapshotQuery>,
+) -> ApiResult<(HeaderMap, Json<Snapshot>)> {
+    let at = crate::snapshot::parse_at(&query.at)?;
+    let (repository, source) =
+        open_listed_repository(&state, &tenant_id, &portfolio_id, &project_id)?;
+    let snapshot = tokio::task::spawn_blocking(move || {
+        let txn = repository
+            .pristine
+            .txn_begin()
+            .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+        let channel = load_listed_channel(&txn, query.channel.as_deref())?;
+        let channel = channel.read();
+        crate::snapshot::snapshot(&txn, &*channel, &repository.changes, at)
+    })
+    .await
+    .map_err(|e| ApiError::internal(format!("State task failed: {}", e)))??;
+    Ok((source.headers(), Json(snapshot)))
+}
+
+/// Conflicts of a channel left by applies, see [`crate::conflicts`]
+async fn get_conflicts(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    Query(query): Query<ConflictQuery>,
+) -> ApiResult<Json<ChannelConflicts>> {
+    validate_id(&tenant_id, "tenant_id")?;
+    validate_id(&portfolio_id, "portfolio_id")?;
+    validate_id(&project_id, "project_id")?;
+    let repo_path = state
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
+    if !repo_path.join(libatomic::DOT_DIR).exists() {
+        return Err(ApiError::repository_not_found(repo_path.to_string_lossy()));
+    }
+    let at = match query.state {
+        Some(ref s) => Some(
+            libatomic::Merkle::from_base32(s.as_bytes())
+                .ok_or_else(|| ApiError::invalid_query(format!("Invalid state {}", s)))?,
+        ),
+        None => None,
+    };
+    let repository = Repository::find_root(Some(repo_path))
+        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+    let conflicts = tokio::task::spawn_blocking(move || {
+        crate::conflicts::read(&repository, query.channel.as_deref(), at)
+    })
+    .await
+    .map_err(|e| ApiError::internal(format!("Conflicts task failed: {}", e)))??;
+    Ok(Json(conflicts))
+}
+
+/// List the attribution of the changes of a channel, newest first. Like
+/// the changes endpoint, this only reads the changes of the page unless
+/// sorting is requested.
+async fn list_attribution(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    Query(params): Query<ChannelQuery>,
+    Query(list): Query<ListQuery>,
+) -> ApiResult<(HeaderMap, JsonStream)> {
+    const SORT_FIELDS: &[&str] = &["timestamp", "ai_provider", "ai_confidence"];
+    let (repository, source) =
+        open_listed_repository(&state, &tenant_id, &portfolio_id, &project_id)?;
+    let sorted = list.sort(SORT_FIELDS)?.is_some();
+    let after = list.after()?;
+    let txn = repository
+        .pristine
+        .txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+    let channel = load_listed_channel(&txn, params.channel.as_deref())?;
+    let channel = channel.read();
+
+    let mut total = 0;
+    let mut hashes = Vec::new();
+    for entry in txn
+        .reverse_log(&*channel, None)
+        .map_err(|e| ApiError::internal(format!("Failed to read log: {}", e)))?
+    {
+        let (n, (h, _)) =
+            entry.map_err(|e| ApiError::internal(format!("Failed to read log entry: {}", e)))?;
+        total = total.max(n + 1);
+        hashes.push(libatomic::Hash::from(h));
+    }
+    let summary = |hash: &libatomic::Hash| {
+        let header = repository.changes.get_header(hash).ok()?;
+        Some(ChangeAttributionSummary {
+            hash: hash.to_base32(),
+            timestamp: header.timestamp.to_rfc3339(),
+            attribution: get_change_ai_attribution(&repository, hash).ok()?,
+        })
+    };
+    if sorted {
+        let items: Vec<_> = hashes.iter().filter_map(summary).collect();
+        return Ok((
+            source.headers(),
+            list.page(items, |a| a.hash.clone(), SORT_FIELDS)?.into(),
+        ));
+    }
+
+    let start = match after {
+        Some(after) => {
+            hashes
+                .iter()
+                .position(|h| h.to_base32() == after)
+                .ok_or_else(|| {
+                    ApiError::invalid_query("Cursor does not match any change".to_string())
+                })?
+                + 1
+        }
+        None => list.offset.unwrap_or(0).min(hashes.len()),
+    };
+    let end = (start + list.limit()).min(hashes.len());
+    let items = hashes[start..end]
+        .iter()
+        .filter_map(summary)
+        .map(|a| crate::query::to_value(&a))
+        .collect::<ApiResult<_>>()?;
+    let next_cursor = if end < hashes.len() && end > start {
+        Some(encode_cursor(&hashes[end - 1].to_base32()))
+    } else {
+        None
+    };
+    Ok((
+        source.headers(),
+        list.page_of(items, next_cursor, total).into(),
+    ))
+}
+
+/// Query parameters of `GET .../code/attribution/export`
+#[derive(Debug, Deserialize)]
+pub struct AttributionExportQuery {
+    /// Channel to export, the current channel by default
+    channel: Option<String>,
+    /// Cursor of the previous export (its `X-Next-Cursor`), to only export
+    /// the changes applied since
+    cursor: Option<String>,
+    /// Maximum number of records, see [`MAX_EXPORT_RECORDS`]
+    limit: Option<usize>,
+    /// `ndjson` (the default), or `parquet` with the `parquet` feature
+    format: Option<String>,
+}
+
+/// Default and maximum number of records of an attribution export
+pub const MAX_EXPORT_RECORDS: usize = 10_000;
+
+/// Export the attribution of the changes of a channel in bulk, in the
+/// order they were applied, see [`atomic_repository::attribution_export`].
+/// The cursor of the next export is in the `X-Next-Cursor` header.
+async fn export_attribution(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    Query(params): Query<AttributionExportQuery>,
+) -> ApiResult<Response<Body>> {
+    use atomic_repository::attribution_export::{self, ExportFormat};
+    let format: ExportFormat = params
+        .format
+        .as_deref()
+        .unwrap_or("ndjson")
+        .parse()
+        .map_err(|e: anyhow::Error| ApiError::invalid_query(e.to_string()))?;
+    attribution_export::parse_cursor(params.cursor.as_deref())
+        .map_err(|e| ApiError::invalid_query(e.to_string()))?;
+    let limit = params
+        .limit
+        .unwrap_or(MAX_EXPORT_RECORDS)
+        .min(MAX_EXPORT_RECORDS);
+
+    let (repository, source) =
+        open_listed_repository(&state, &tenant_id, &portfolio_id, &project_id)?;
+    let txn = repository
+        .pristine
+        .txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+    let channel = load_listed_channel(&txn, params.channel.as_deref())?;
+    let batch = attribution_export::collect(
+        &txn,
+        &repository.changes,
+        &channel,
+        params.cursor.as_deref(),
+        Some(limit),
+    )
+    .map_err(|e| ApiError::internal(format!("Failed to export attribution: {}", e)))?;
+    let mut body = Vec::new();
+    attribution_export::write(&batch.records, format, &mut body)
+        .map_err(|e| ApiError::internal(format!("Failed to write export: {}", e)))?;
+
+    let content_type = match format {
+        ExportFormat::Ndjson => "application/x-ndjson",
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => "application/vnd.apache.parquet",
+    };
+    let mut response = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", content_type)
+        .header("X-Next-Cursor", &batch.next_cursor)
+        .body(Body::from(body))
+        .map_err(|e| ApiError::internal(format!("Failed to build response: {}", e)))?;
+    response.headers_mut().extend(source.headers());
+    Ok(response)
+}
+
+/// Statistics of the attribution of the changes of a channel, see
+/// [`crate::attribution_stats`]
+async fn get_attribution_stats(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    Query(query): Query<AttributionStatsQuery>,
+) -> ApiResult<(HeaderMap, Json<AttributionStatsReport>)> {
+    let filter = StatsFilter::parse(&query)?;
+    let (repository, source) =
+        open_listed_repository(&state, &tenant_id, &portfolio_id, &project_id)?;
+    let report = tokio::task::spawn_blocking(move || {
+        let txn = repository
+            .pristine
+            .txn_begin()
+            .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+        let channel = load_listed_channel(&txn, query.channel.as_deref())?;
+        crate::attribution_stats::stats(&txn, &repository.changes, &channel, filter)
+    })
+    .await
+    .map_err(|e| ApiError::internal(format!("Attribution stats task failed: {}", e)))??;
+    Ok((source.headers(), Json(report)))
+}
+
+/// Body of a request setting the note of a change
+#[derive(Debug, Deserialize)]
+pub struct NoteRequest {
+    pub text: String,
+    pub author: Option<String>,
+}
+
+/// Notes of the repository at `tenant_id/portfolio_id/project_id`, and the
+/// hash of `change_id`, which must be a change of that repository.
+fn change_notes(
+    state: &AppState,
+    tenant_id: &str,
+    portfolio_id: &str,
+    project_id: &str,
+    change_id: &str,
+) -> ApiResult<(atomic_repository::notes::Notes, libatomic::Hash)> {
+    // Notes live outside the pristine, so there is no need to lock it.
+    let (dot_dir, hash) = existing_change(state, tenant_id, portfolio_id, project_id, change_id)?;
+    let notes = atomic_repository::notes::Notes::new(dot_dir.join(atomic_repository::NOTES_DIR));
+    Ok((notes, hash))
+}
+
+/// The `.atomic` directory of the repository at
+/// `tenant_id/portfolio_id/project_id`, and the hash of `change_id`, which
+/// must be a change of that repository, or a prefix of its hash.
+fn existing_change(
+    state: &AppState,
+    tenant_id: &str,
+    portfolio_id: &str,
+    project_id: &str,
+    change_id: &str,
+) -> ApiResult<(PathBuf, libatomic::Hash)> {
+    validate_id(tenant_id, "tenant_id")?;
+    validate_id(portfolio_id, "portfolio_id")?;
+    validate_id(project_id, "project_id")?;
+
+    let repo_path = state.jail.repository(tenant_id, portfolio_id, project_id)?;
+    let dot_dir = repo_path.join(libatomic::DOT_DIR);
+    if !dot_dir.exists() {
+        warn!("Repository not found: {}", dot_dir.display());
+        return Err(ApiError::repository_not_found(dot_dir.to_string_lossy()));
+    }
+
+    let not_found = || {
+        ApiError::Repository(crate::error::RepositoryError::ChangeNotFound {
+            change_id: change_id.to_string(),
+        })
+    };
+    let hash = crate::resolve::resolve_at(&repo_path, change_id)?;
+    let mut change_path = dot_dir.join(atomic_repository::CHANGES_DIR);
+    libatomic::changestore::filesystem::push_filename(&mut change_path, &hash);
+    if !change_path.exists() {
+        return Err(not_found());
+    }
+    Ok((dot_dir, hash))
+}
+
+/// Get the note attached to a change
+async fn get_change_note(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, change_id)): Path<(String, String, String, String)>,
+) -> ApiResult<Json<atomic_repository::notes::Note>> {
+    let (notes, hash) = change_notes(&state, &tenant_id, &portfolio_id, &project_id, &change_id)?;
+    let note = notes
+        .get(&hash)
+        .map_err(|e| ApiError::internal(format!("Failed to read note: {}", e)))?;
+    note.map(Json).ok_or_else(|| {
+        ApiError::Repository(crate::error::RepositoryError::ChangeNotFound {
+            change_id: format!("{} (note)", change_id),
+        })
+    })
+}
+
+/// Attach a note to a change, replacing the previous one
+async fn put_change_note(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, change_id)): Path<(String, String, String, String)>,
+    Json(request): Json<NoteRequest>,
+) -> ApiResult<Json<atomic_repository::notes::Note>> {
+    let (notes, hash) = change_notes(&state, &tenant_id, &portfolio_id, &project_id, &change_id)?;
+    let note = notes
+        .set(&hash, &request.text, request.author.as_deref())
+        .map_err(|e| ApiError::internal(format!("Failed to write note: {}", e)))?;
+    info!("Updated note of change {}", change_id);
+    Ok(Json(note))
+}
+
+/// Delete the note attached to a change
+async fn delete_change_note(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, change_id)): Path<(String, String, String, String)>,
+) -> ApiResult<StatusCode> {
+    let (notes, hash) = change_notes(&state, &tenant_id, &portfolio_id, &project_id, &change_id)?;
+    let removed = notes
+        .remove(&hash, None)
+        .map_err(|e| ApiError::internal(format!("Failed to delete note: {}", e)))?;
+    Ok(if removed {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+/// Body of `POST .../code/changes/:change_id/links`
+#[derive(Debug, Deserialize)]
+pub struct LinkRequest {
+    pub url: String,
+    /// `fixes` or `relates-to` (the default)
+    pub relation: Option<atomic_repository::links::Relation>,
+    pub author: Option<String>,
+}
+
+/// Query of `DELETE .../code/changes/:change_id/links`
+#[derive(Debug, Deserialize)]
+pub struct LinkUrlQuery {
+    pub url: String,
+}
+
+/// Query of `GET .../code/links`
+#[derive(Debug, Deserialize)]
+pub struct FindLinksQuery {
+    /// Issue key (`PROJ-123`, `org/repo#42`) or URL
+    #[serde(rename = "ref")]
+    pub reference: String,
+    /// Only return links with this relation
+    pub relation: Option<atomic_repository::links::Relation>,
+}
+
+/// Links of the repository at `tenant_id/portfolio_id/project_id`, and the
+/// hash of `change_id`, which must be a change of that repository.
+fn change_link_registry(
+    state: &AppState,
+    tenant_id: &str,
+    portfolio_id: &str,
+    project_id: &str,
+    change_id: &str,
+) -> ApiResult<(atomic_repository::links::Links, libatomic::Hash)> {
+    let (dot_dir, hash) = existing_change(state, tenant_id, portfolio_id, project_id, change_id)?;
+    let links = atomic_repository::links::Links::new(dot_dir.join(atomic_repository::LINKS_DIR));
+    Ok((links, hash))
+}
+
+/// List the external links of a change
+async fn list_change_links(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, change_id)): Path<(String, String, String, String)>,
+) -> ApiResult<Json<Vec<atomic_repository::links::Link>>> {
+    let (links, hash) =
+        change_link_registry(&state, &tenant_id, &portfolio_id, &project_id, &change_id)?;
+    let links = links
+        .get(&hash)
+        .map_err(|e| ApiError::internal(format!("Failed to read links: {}", e)))?;
+    Ok(Json(links))
+}
+
+/// Link a change to an external URL, replacing the relation of a previous
+/// link to the same URL
+async fn add_change_link(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, change_id)): Path<(String, String, String, String)>,
+    Json(request): Json<LinkRequest>,
+) -> ApiResult<(StatusCode, Json<atomic_repository::links::Link>)> {
+    let (links, hash) =
+        change_link_registry(&state, &tenant_id, &portfolio_id, &project_id, &change_id)?;
+    if !request.url.contains("://") {
+        return Err(ApiError::invalid_query(format!(
+            "Invalid link URL: {}",
+            request.url
+        )));
+    }
+    let link = links
+        .add(
+            &hash,
+            &request.url,
+            request
+                .relation
+                .unwrap_or(atomic_repository::links::Relation::RelatesTo),
+            request.author.as_deref(),
+        )
+        .map_err(|e| ApiError::internal(format!("Failed to write link: {}", e)))?;
+    info!("Linked change {} to {}", change_id, link.url);
+    Ok((StatusCode::CREATED, Json(link)))
+}
+
+/// Remove the link from a change to the `url` of the query
+async fn delete_change_link(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, change_id)): Path<(String, String, String, String)>,
+    Query(query): Query<LinkUrlQuery>,
+) -> ApiResult<StatusCode> {
+    let (links, hash) =
+        change_link_registry(&state, &tenant_id, &portfolio_id, &project_id, &change_id)?;
+    let removed = links
+        .remove(&hash, &query.url, None)
+        .map_err(|e| ApiError::internal(format!("Failed to delete link: {}", e)))?;
+    Ok(if removed {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    })
+}
+
+/// The links to an issue key or URL, i.e. the changes linked to it
+async fn find_links(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    Query(query): Query<FindLinksQuery>,
+) -> ApiResult<Json<Vec<atomic_repository::links::Link>>> {
+    validate_id(&tenant_id, "tenant_id")?;
+    validate_id(&portfolio_id, "portfolio_id")?;
+    validate_id(&project_id, "project_id")?;
+
+    let dot_dir = state
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?
+        .join(libatomic::DOT_DIR);
+    if !dot_dir.exists() {
+        warn!("Repository not found: {}", dot_dir.display());
+        return Err(ApiError::repository_not_found(dot_dir.to_string_lossy()));
+    }
+    let links = atomic_repository::links::Links::new(dot_dir.join(atomic_repository::LINKS_DIR));
+    let mut found = links
+        .find(&query.reference)
+        .map_err(|e| ApiError::internal(format!("Failed to read links: {}", e)))?;
+    if let Some(relation) = query.relation {
+        found.retain(|l| l.relation == relation)
+    }
+    Ok(Json(found))
+}
+
+/// Body of `POST .../code/changes/:change_id/comments`
+#[derive(Debug, Deserialize)]
+pub struct CommentRequest {
+    pub author: String,
+    pub text: String,
+    pub path: Option<String>,
+    pub line: Option<usize>,
+    /// Edit proposed by the reviewer, see [`atomic_repository::reviews`]
+    pub suggestion: Option<atomic_repository::reviews::Suggestion>,
+}
+
+/// Body of `POST .../code/changes/:change_id/comments/:comment_id/accept`
+#[derive(Debug, Deserialize)]
+pub struct AcceptSuggestionRequest {
+    /// Review channel the suggestion is recorded on
+    pub channel: String,
+    /// Author of the new change, the reviewer being its co-author
+    pub author: String,
+    pub message: Option<String>,
+}
+
+/// Response of `POST .../code/changes/:change_id/comments/:comment_id/accept`
+#[derive(Debug, Serialize)]
+pub struct AcceptSuggestionResponse {
+    /// Hash of the change recording the suggestion
+    pub hash: String,
+    /// State of the review channel after applying it
+    pub state: String,
+    pub comment: atomic_repository::reviews::Comment,
+}
+
+/// List the review comments on a change, oldest first
+async fn list_comments(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, change_id)): Path<(String, String, String, String)>,
+) -> ApiResult<JsonStream> {
+    let (dot_dir, hash) =
+        existing_change(&state, &tenant_id, &portfolio_id, &project_id, &change_id)?;
+    let reviews =
+        atomic_repository::reviews::Reviews::new(dot_dir.join(atomic_repository::REVIEWS_DIR));
+    let comments = reviews
+        .comments(&hash)
+        .map_err(|e| ApiError::internal(format!("Failed to read comments: {}", e)))?;
+    Ok(JsonStream::items(comments))
+}
+
+/// Comment on a change, optionally suggesting an edit
+async fn add_comment(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, change_id)): Path<(String, String, String, String)>,
+    Json(request): Json<CommentRequest>,
+) -> ApiResult<(StatusCode, Json<atomic_repository::reviews::Comment>)> {
+    let (dot_dir, hash) =
+        existing_change(&state, &tenant_id, &portfolio_id, &project_id, &change_id)?;
+    let mut suggestion = request.suggestion;
+    if let Some(ref mut s) = suggestion {
+        if s.start_line == 0 || s.end_line + 1 < s.start_line {
+            return Err(ApiError::invalid_query(format!(
+                "Invalid suggestion lines: {}-{}",
+                s.start_line, s.end_line
+            )));
+        }
+        // Only accepting a suggestion marks it as accepted.
+        s.accepted = None;
+    }
+    let reviews =
+        atomic_repository::reviews::Reviews::new(dot_dir.join(atomic_repository::REVIEWS_DIR));
+    let comment = reviews
+        .add(
+            &hash,
+            atomic_repository::reviews::Comment {
+                id: 0,
+                change: hash.to_base32(),
+                author: request.author,
+                text: request.text,
+                path: request.path,
+                line: request.line,
+                created: chrono::Utc::now(),
+                suggestion,
+            },
+        )
+        .map_err(|e| ApiError::internal(format!("Failed to write comment: {}", e)))?;
+    info!("Added comment {} on change {}", comment.id, change_id);
+    Ok((StatusCode::CREATED, Json(comment)))
+}
+
+/// Record the suggestion of a comment as a new change on a review channel,
+/// authored by the accepter with the reviewer as co-author
+async fn accept_suggestion(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, change_id, comment_id)): Path<(
+        String,
+        String,
+        String,
+        String,
+        u64,
+    )>,
+    Json(request): Json<AcceptSuggestionRequest>,
+) -> ApiResult<Json<AcceptSuggestionResponse>> {
+    let (dot_dir, hash) =
+        existing_change(&state, &tenant_id, &portfolio_id, &project_id, &change_id)?;
+    let repository = Repository::find_root(dot_dir.parent().map(|p| p.to_path_buf()))
+        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+    // Taken before reading the comment, so that it can only be accepted once.
+    let lock = lock_repository(&repository, "api review", &LockOptions::no_wait())?;
+    let comment = repository
+        .reviews()
+        .get(&hash, comment_id)
+        .map_err(|e| ApiError::internal(format!("Failed to read comments: {}", e)))?
+        .ok_or_else(|| {
+            ApiError::Repository(crate::error::RepositoryError::ChangeNotFound {
+                change_id: format!("{} (comment {})", change_id, comment_id),
+            })
+        })?;
+    let path = match comment.suggestion {
+        Some(ref s) if s.accepted.is_none() => s.path.clone(),
+        Some(_) => {
+            return Err(ApiError::invalid_query(format!(
+                "Suggestion of comment {} was already accepted",
+                comment_id
+            )))
+        }
+        None => {
+            return Err(ApiError::invalid_query(format!(
+                "Comment {} has no suggestion",
+                comment_id
+            )))
+        }
+    };
+    let message = request
+        .message
+        .unwrap_or_else(|| format!("Accept suggestion {} on {}", comment_id, hash.to_base32()));
+
+    let result = tokio::task::spawn_blocking(move || {
+        let _lock = lock;
+        {
+            let txn = repository
+                .pristine
+                .txn_begin()
+                .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+            let exists = txn
+                .load_channel(&request.channel)
+                .map_err(|e| ApiError::internal(format!("Failed to load channel: {}", e)))?
+                .is_some();
+            if !exists {
+                return Err(ApiError::Repository(
+                    crate::error::RepositoryError::ChannelNotFound {
+                        channel: request.channel.clone(),
+                    },
+                ));
+            }
+        }
+        let new_hash = repository
+            .record_suggestion(&request.channel, &comment, &request.author, &message)
+            .map_err(
+                |e| match e.downcast_ref::<atomic_repository::reviews::StaleSuggestion>() {
+                    Some(_) => {
+                        ApiError::Repository(crate::error::RepositoryError::StaleSuggestion {
+                            change_id: change_id.clone(),
+                            comment_id,
+                            path: path.clone(),
+                        })
+                    }
+                    None => ApiError::internal(format!("Failed to record suggestion: {}", e)),
+                },
+            )?;
+        let comment = repository
+            .reviews()
+            .accept(&hash, comment_id, &new_hash)
+            .map_err(|e| ApiError::internal(format!("Failed to update comment: {}", e)))?;
+
+        let txn = repository
+            .pristine
+            .txn_begin()
+            .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+        let channel = txn
+            .load_channel(&request.channel)
+            .map_err(|e| ApiError::internal(format!("Failed to load channel: {}", e)))?
+            .ok_or_else(|| ApiError::internal("Review channel disappeared"))?;
+        let state = libatomic::pristine::current_state(&txn, &*channel.read())
+            .map_err(|e| ApiError::internal(format!("Failed to read state: {}", e)))?;
+        info!(
+            "Recorded suggestion {} on {} as {} on channel {}",
+            comment_id,
+            change_id,
+            new_hash.to_base32(),
+            request.channel
+        );
+        atomic_config::events::publish(atomic_config::events::Event::NodeApplied {
+            repository: repository.path.clone(),
+            channel: request.channel.clone(),
+            kind: atomic_config::events::NodeKind::Change,
+            hash: new_hash.to_base32(),
+        });
+        Ok(AcceptSuggestionResponse {
+            hash: new_hash.to_base32(),
+            state: state.to_base32(),
+            comment,
+        })
+    })
+    .await
+    .map_err(|e| ApiError::internal(format!("Review task failed: {}", e)))??;
+    Ok(Json(result))
+}
+
+/// State of a workflow on a change, see [`crate::workflow`]
+async fn get_workflow_state(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, change_id)): Path<(String, String, String, String)>,
+    Query(query): Query<WorkflowQuery>,
+) -> ApiResult<Json<WorkflowStateResponse>> {
+    let (dot_dir, hash) =
+        existing_change(&state, &tenant_id, &portfolio_id, &project_id, &change_id)?;
+    let repository = Repository::find_root(dot_dir.parent().map(|p| p.to_path_buf()))
+        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+    let workflows = state.workflows.clone();
+    let response = tokio::task::spawn_blocking(move || {
+        crate::workflow::read_state(&repository, &workflows, &query.workflow, &hash.to_base32())
+    })
+    .await
+    .map_err(|e| ApiError::internal(format!("Workflow task failed: {}", e)))??;
+    Ok(Json(response))
+}
+
+/// Fire a workflow transition on a change, see [`crate::workflow`]
+async fn post_workflow_transition(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, change_id)): Path<(String, String, String, String)>,
+    Json(request): Json<TransitionRequest>,
+) -> ApiResult<Json<TransitionResponse>> {
+    let (dot_dir, hash) =
+        existing_change(&state, &tenant_id, &portfolio_id, &project_id, &change_id)?;
+    let repository = Repository::find_root(dot_dir.parent().map(|p| p.to_path_buf()))
+        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+    let lock = lock_repository(&repository, "api workflow", &LockOptions::no_wait())?;
+    let (workflows, inbox) = (state.workflows.clone(), state.inbox.clone());
+    let response = tokio::task::spawn_blocking(move || {
+        let _lock = lock;
+        let change_id = hash.to_base32();
+        let response = crate::workflow::transition(&repository, &workflows, &change_id, request)?;
+        inbox.update(&repository, &workflows, &[change_id]);
+        Ok::<_, ApiError>(response)
+    })
+    .await
+    .map_err(|e| ApiError::internal(format!("Workflow task failed: {}", e)))??;
+    info!(
+        "Workflow {} of change {} moved to {}",
+        response.state.workflow, change_id, response.state.state
+    );
+    Ok(Json(response))
+}
+
+/// Evaluate a workflow transition on a change without firing it, see
+/// [`crate::workflow`]
+async fn post_workflow_evaluate(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, change_id)): Path<(String, String, String, String)>,
+    Json(request): Json<TransitionRequest>,
+) -> ApiResult<Json<EvaluationResponse>> {
+    let (dot_dir, hash) =
+        existing_change(&state, &tenant_id, &portfolio_id, &project_id, &change_id)?;
+    let repository = Repository::find_root(dot_dir.parent().map(|p| p.to_path_buf()))
+        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+    let workflows = state.workflows.clone();
+    let response = tokio::task::spawn_blocking(move || {
+        crate::workflow::evaluate(&repository, &workflows, &hash.to_base32(), request)
+    })
+    .await
+    .map_err(|e| ApiError::internal(format!("Workflow task failed: {}", e)))??;
+    Ok(Json(response))
+}
+
+/// Pending workflow states of the repositories of a tenant, see
+/// [`crate::inbox`]
+async fn get_workflow_inbox(
+    State(state): State<AppState>,
+    Path(tenant_id): Path<String>,
+    Query(query): Query<InboxQuery>,
+    Query(list): Query<ListQuery>,
+) -> ApiResult<JsonStream> {
+    validate_id(&tenant_id, "tenant_id")?;
+    state.jail.resolve(&tenant_id, "")?;
+    let (workflows, inbox) = (state.workflows.clone(), state.inbox.clone());
+    let items = tokio::task::spawn_blocking(move || {
+        let mut items = inbox.items(&tenant_id, &workflows)?;
+        items.retain(|i| query.matches(&workflows, i));
+        Ok::<_, ApiError>(items)
+    })
+    .await
+    .map_err(|e| ApiError::internal(format!("Inbox task failed: {}", e)))??;
+    Ok(list.page(items, |i| i.key(), INBOX_SORT_FIELDS)?.into())
+}
+
+/// Query parameters of `GET .../code/provenance`
+#[derive(Debug, Deserialize)]
+pub struct ProvenanceQuery {
+    /// Only list the changes pulled from, or pushed to, this remote
+    remote: Option<String>,
+}
+
+/// Provenance of the changes of the repository at
+/// `tenant_id/portfolio_id/project_id`, see [`atomic_repository::provenance`]
+fn repository_provenance(
+    state: &AppState,
+    tenant_id: &str,
+    portfolio_id: &str,
+    project_id: &str,
+) -> ApiResult<atomic_repository::provenance::Provenance> {
+    validate_id(tenant_id, "tenant_id")?;
+    validate_id(portfolio_id, "portfolio_id")?;
+    validate_id(project_id, "project_id")?;
+    let dot_dir = state
+        .jail
+        .repository(tenant_id, portfolio_id, project_id)?
+        .join(libatomic::DOT_DIR);
+    if !dot_dir.exists() {
+        warn!("Repository not found: {}", dot_dir.display());
+        return Err(ApiError::repository_not_found(dot_dir.to_string_lossy()));
+    }
+    // Like notes, provenance lives outside the pristine.
+    Ok(atomic_repository::provenance::Provenance::new(
+        dot_dir.join(atomic_repository::PROVENANCE_FILE),
+    ))
+}
+
+/// List where the changes of a repository came from and were pushed to,
+/// in the order they were first seen
+async fn list_provenance(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    Query(params): Query<ProvenanceQuery>,
+    Query(list): Query<ListQuery>,
+) -> ApiResult<JsonStream> {
+    const SORT_FIELDS: &[&str] = &["first_seen", "source"];
+    let provenance = repository_provenance(&state, &tenant_id, &portfolio_id, &project_id)?;
+    let mut records = provenance
+        .list()
+        .map_err(|e| ApiError::internal(format!("Failed to read provenance: {}", e)))?;
+    if let Some(ref remote) = params.remote {
+        records.retain(|r| r.source.as_ref() == Some(remote) || r.pushed_to.contains_key(remote))
+    }
+    Ok(list.page(records, |r| r.hash.clone(), SORT_FIELDS)?.into())
+}
+
+/// Get where a change came from and was pushed to
+async fn get_provenance(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, change_id)): Path<(String, String, String, String)>,
+) -> ApiResult<Json<atomic_repository::provenance::ProvenanceRecord>> {
+    let provenance = repository_provenance(&state, &tenant_id, &portfolio_id, &project_id)?;
+    let not_found = || {
+        ApiError::Repository(crate::error::RepositoryError::ChangeNotFound {
+            change_id: format!("{} (provenance)", change_id),
+        })
+    };
+    let repo_path = state
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
+    let hash = crate::resolve::resolve_at(&repo_path, &change_id)?;
+    provenance
+        .get(&hash)
+        .map_err(|e| ApiError::internal(format!("Failed to read provenance: {}", e)))?
+        .map(Json)
+        .ok_or_else(not_found)
+}
+
+/// Body of a request creating a sandbox
+#[derive(Debug, Deserialize)]
+pub struct SandboxRequest {
+    /// Channel to fork, `main` by default
+    pub channel: Option<String>,
+    /// State to fork the channel at, its current state by default
+    pub state: Option<String>,
+    /// Time to fork the channel at, instead of a state
+    pub at: Option<String>,
+    /// Changes to apply to the sandbox
+    #[serde(default)]
+    pub changes: Vec<String>,
+    /// Lifetime of the sandbox in seconds, capped at a week
+    pub ttl_secs: Option<u64>,
+}
+
+/// Body of a request applying changes to a sandbox
+#[derive(Debug, Deserialize)]
+pub struct SandboxApplyRequest {
+    pub changes: Vec<String>,
+}
+
+/// Path of a sandbox worktree
+#[derive(Debug, Serialize)]
+pub struct WorktreeInfo {
+    pub path: String,
+}
+
+/// Repository at `tenant_id/portfolio_id/project_id`, and the directory of
+/// its sandbox worktrees, after deleting its expired sandboxes.
+fn sandbox_repository(
+    state: &AppState,
+    tenant_id: &str,
+    portfolio_id: &str,
+    project_id: &str,
+) -> ApiResult<(Repository, Option<PathBuf>)> {
+    validate_id(tenant_id, "tenant_id")?;
+    validate_id(portfolio_id, "portfolio_id")?;
+    validate_id(project_id, "project_id")?;
+
+    let relative = PathBuf::from(tenant_id).join(portfolio_id).join(project_id);
+    let repo_path = state.jail.repository(tenant_id, portfolio_id, project_id)?;
+    if !repo_path.join(libatomic::DOT_DIR).exists() {
+        warn!("Repository not found: {}", repo_path.display());
+        return Err(ApiError::repository_not_found(repo_path.to_string_lossy()));
+    }
+    let repository = Repository::find_root(Some(repo_path))
+        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+    let worktrees = state.sandboxes.worktrees(&relative);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    sandbox::reap_expired(&repository, worktrees.as_deref(), now)?;
+    Ok((repository, worktrees))
+}
+
+fn parse_hashes(hashes: &[String]) -> ApiResult<Vec<libatomic::Hash>> {
+    hashes
+        .iter()
+        .map(|h| {
+            libatomic::Hash::from_base32(h.as_bytes())
+                .ok_or_else(|| ApiError::internal(format!("Invalid change hash: {}", h)))
+        })
+        .collect()
+}
+
+/// List the sandboxes of a repository
+async fn list_sandboxes(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+) -> ApiResult<JsonStream> {
+    let (repository, _) = sandbox_repository(&state, &tenant_id, &portfolio_id, &project_id)?;
+    Ok(JsonStream::items(sandbox::list(&repository)?))
+}
+
+/// Fork a channel into a new sandbox, and apply changes to it
+async fn create_sandbox(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    Json(request): Json<SandboxRequest>,
+) -> ApiResult<(StatusCode, Json<SandboxInfo>)> {
+    let (repository, worktrees) =
+        sandbox_repository(&state, &tenant_id, &portfolio_id, &project_id)?;
+    let from = request
+        .channel
+        .as_deref()
+        .unwrap_or(libatomic::DEFAULT_CHANNEL);
+    let fork_state = match (&request.state, &request.at) {
+        (Some(_), Some(_)) => {
+            return Err(ApiError::invalid_query(
+                "Only one of state and at can be given",
+            ))
+        }
+        (Some(s), None) => Some(
+            libatomic::Merkle::from_base32(s.as_bytes())
+                .ok_or_else(|| ApiError::invalid_query(format!("Invalid state: {}", s)))?,
+        ),
+        (None, Some(at)) => Some(sandbox_state_at(&repository, from, at)?),
+        (None, None) => None,
+    };
+    let changes = parse_hashes(&request.changes)?;
+    let ttl = request
+        .ttl_secs
+        .map(std::time::Duration::from_secs)
+        .unwrap_or(sandbox::DEFAULT_TTL);
+    let _lock = lock_repository(&repository, "api sandbox", &LockOptions::no_wait())?;
+    let info = sandbox::create(&repository, from, fork_state.as_ref(), &changes, ttl)?;
+    state
+        .sandboxes
+        .track(&repository.path, worktrees.as_deref());
+    Ok((StatusCode::CREATED, Json(info)))
+}
+
+/// State of channel `from` at time `at`, to fork a sandbox from
+fn sandbox_state_at(repository: &Repository, from: &str, at: &str) -> ApiResult<libatomic::Merkle> {
+    let at = crate::snapshot::parse_at(at)?;
+    let txn = repository
+        .pristine
+        .txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+    let channel = load_listed_channel(&txn, Some(from))?;
+    let channel = channel.read();
+    match crate::snapshot::state_at(&txn, &*channel, &repository.changes, at)? {
+        Some((_, _, _, state)) => Ok(state),
+        None => Err(ApiError::invalid_query(format!(
+            "Channel {} has no changes at {}",
+            from,
+            at.to_rfc3339()
+        ))),
+    }
+}
+
+/// Apply changes to a sandbox
+async fn apply_to_sandbox(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, name)): Path<(String, String, String, String)>,
+    Json(request): Json<SandboxApplyRequest>,
+) -> ApiResult<Json<SandboxInfo>> {
+    let (repository, _) = sandbox_repository(&state, &tenant_id, &portfolio_id, &project_id)?;
+    let changes = parse_hashes(&request.changes)?;
+    let _lock = lock_repository(&repository, "api sandbox", &LockOptions::no_wait())?;
+    Ok(Json(sandbox::apply(&repository, &name, &changes)?))
+}
+
+/// Stage an emailed bundle of changes on a contribution channel, see
+/// [`crate::submission`]
+async fn post_submission(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
+    Query(query): Query<SubmissionQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> ApiResult<(StatusCode, Json<Submission>)> {
+    validate_id(&tenant_id, "tenant_id")?;
+    validate_id(&portfolio_id, "portfolio_id")?;
+    validate_id(&project_id, "project_id")?;
+    let repo_path = state
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
+    if !repo_path.join(libatomic::DOT_DIR).exists() {
+        warn!("Repository not found: {}", repo_path.display());
+        return Err(ApiError::repository_not_found(repo_path.to_string_lossy()));
+    }
+
+    let content_type = headers
+        .get(axum::http::header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    let extracted = crate::submission::extract(content_type, &body)?;
+    let changes = crate::submission::decode(&extracted.bundle)?;
+    let channel = query.contribution_channel();
+    let config = state.configs.resolve(&state.jail, &tenant_id, &repo_path)?;
+    for (hash, _) in changes.iter() {
+        crate::envelopes::check_clear(&config.config, &channel, &hash.to_base32())?;
+    }
+
+    let submission = Arc::new(Mutex::new(None));
+    let submission_ = submission.clone();
+    let shutdown = state.shutdown.clone();
+    let (configs, jail) = (state.configs.clone(), state.jail.clone());
+    let (workflows, inbox) = (state.workflows.clone(), state.inbox.clone());
+    let operation = state.applies.submit(repo_path.clone(), move || {
+        let config = configs.resolve(&jail, &tenant_id, &repo_path)?;
+        let submission = stage_submission(
+            &repo_path,
+            &config.config,
+            &workflows,
+            &query,
+            &extracted,
+            &changes,
+            &shutdown,
+        )?;
+        let started: Vec<String> = submission
+            .changes
+            .iter()
+            .filter(|c| !c.workflows.is_empty())
+            .map(|c| c.hash.clone())
+            .collect();
+        if !started.is_empty() {
+            inbox.update_at(&repo_path, &workflows, &started);
+        }
+        *submission_.lock().unwrap() = Some(submission);
+        Ok(())
+    })?;
+    operation.wait().await?;
+
+    let submission = submission
+        .lock()
+        .unwrap()
+        .take()
+        .ok_or_else(|| ApiError::internal("Submission was not staged"))?;
+    info!(
+        "Submission of {} changes staged on {}",
+        submission.changes.len(),
+        submission.channel
+    );
+    Ok((StatusCode::CREATED, Json(submission)))
+}
+
+/// Delete a sandbox and its worktree
+async fn delete_sandbox(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, name)): Path<(String, String, String, String)>,
+) -> ApiResult<StatusCode> {
+    let (repository, worktrees) =
+        sandbox_repository(&state, &tenant_id, &portfolio_id, &project_id)?;
+    let _lock = lock_repository(&repository, "api sandbox", &LockOptions::no_wait())?;
+    Ok(
+        if sandbox::delete(&repository, &name, worktrees.as_deref())? {
+            StatusCode::NO_CONTENT
+        } else {
+            StatusCode::NOT_FOUND
+        },
+    )
+}
+
+/// Download the files of a sandbox as a gzipped tarball
+async fn get_sandbox_archive(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, name)): Path<(String, String, String, String)>,
+) -> ApiResult<Response<Body>> {
+    let (repository, _) = sandbox_repository(&state, &tenant_id, &portfolio_id, &project_id)?;
+    let mut tarball = Vec::new();
+    sandbox::archive(&repository, &name, &mut tarball)?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/gzip")
+        .header(
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.tar.gz\"", name),
+        )
+        .body(Body::from(tarball))
+        .map_err(|e| ApiError::internal(format!("Failed to build response: {}", e)))
+}
+
+/// Write the files of a sandbox to its worktree on the server
+async fn post_sandbox_worktree(
+    State(state): State<AppState>,
+    Path((tenant_id, portfolio_id, project_id, name)): Path<(String, String, String, String)>,
+) -> ApiResult<Json<WorktreeInfo>> {
+    let (repository, worktrees) =
+        sandbox_repository(&state, &tenant_id, &portfolio_id, &project_id)?;
+    let worktrees = worktrees
+        .ok_or_else(|| ApiError::internal("Sandbox worktrees are not enabled".to_string()))?;
+    let path = sandbox::materialize(&repository, &name, &worktrees)?;
+    Ok(Json(WorktreeInfo {
+        path: path.display().to_string(),
+    }))
+}
+
+/// Validate that all dependencies for a change exist in the channel
+/// Following AGENTS.md error handling patterns
+///
+/// # Arguments
+/// * `repository` - Repository containing the change
+/// * `txn` - Transaction for checking dependencies
+/// * `channel` - Channel to check dependencies in
+/// * `change_hash` - Hash of the change to validate
+///
+/// Dependencies that aren't changes of the channel must be known tags,
+/// see [`is_known_tag`].
+///
+/// # Returns
+/// * `Ok(Vec::new())` - All dependencies satisfied
+/// * `Ok(Vec<Hash>)` - List of missing dependency hashes
+/// * `Err(ApiError)` - Failed to check dependencies
+///
+/// # Errors
+/// Returns ApiError if change cannot be read or dependency check fails
+fn validate_change_dependencies(
+    repository: &Repository,
+    txn: &libatomic::pristine::sanakirja::Txn,
+    channel: &libatomic::pristine::ChannelRef<libatomic::pristine::sanakirja::Txn>,
+    change_hash: &libatomic::Hash,
+) -> ApiResult<Vec<libatomic::Hash>> {
+    use libatomic::changestore::ChangeStore;
+
+    // 1. Read change file to get dependencies
+    let change = repository.changes.get_change(change_hash).map_err(|e| {
+        ApiError::internal(format!(
+            "Failed to read change {} for dependency validation: {}",
+            change_hash.to_base32(),
+            e
+        ))
+    })?;
+
+    // 2. Check each dependency exists in the channel OR is a tag
+    missing_dependencies(repository, txn, channel, change_hash, &change.dependencies)
+}
+
+/// The dependencies of `change_hash` among `dependencies` that are
+/// neither changes of `channel` nor known tags, see [`is_known_tag`]
+fn missing_dependencies(
+    repository: &Repository,
+    txn: &libatomic::pristine::sanakirja::Txn,
+    channel: &libatomic::pristine::ChannelRef<libatomic::pristine::sanakirja::Txn>,
+    change_hash: &libatomic::Hash,
+    dependencies: &[libatomic::Hash],
+) -> ApiResult<Vec<libatomic::Hash>> {
+    let mut missing = Vec::new();
+    for dep_hash in dependencies {
+        match txn.has_change(channel, dep_hash) {
+            Ok(Some(_)) => {
+                // Dependency exists as a regular change, continue
+                tracing::debug!(
+                    "Dependency {} found for change {}",
+                    dep_hash.to_base32(),
+                    change_hash.to_base32()
+                );
+            }
+            Ok(None) => {
+                // Not found as a regular change - might be a tag dependency.
+                // Tags are virtual dependencies used for O(1) dependency
+                // reduction, and don't have to be applied to the channel,
+                // but they must be tags this server knows about.
+                if is_known_tag(repository, txn, channel, dep_hash)? {
+                    tracing::debug!(
+                        "Dependency {} of change {} is a known tag",
+                        dep_hash.to_base32(),
+                        change_hash.to_base32()
+                    );
+                } else {
+                    warn!(
+                        "Dependency {} of change {} is neither a change nor a known tag",
+                        dep_hash.to_base32(),
+                        change_hash.to_base32()
+                    );
+                    missing.push(*dep_hash);
+                }
+            }
+            Err(e) => {
+                return Err(ApiError::internal(format!(
+                    "Failed to check dependency {}: {}",
+                    dep_hash.to_base32(),
+                    e
+                )));
+            }
+        }
+    }
+
+    Ok(missing)
+}
+
+/// Whether `hash`, a dependency that isn't a change of `channel`, is a tag
+/// the server knows about: registered as a tag node, in the tag metadata
+/// table, the dependency hash of one of the channel's tags, or a tag file
+/// already uploaded to the repository.
+fn is_known_tag(
+    repository: &Repository,
+    txn: &libatomic::pristine::sanakirja::Txn,
+    channel: &libatomic::pristine::ChannelRef<libatomic::pristine::sanakirja::Txn>,
+    hash: &libatomic::Hash,
+) -> ApiResult<bool> {
+    if txn.get_node_type_by_hash(hash) == Some(libatomic::pristine::NodeType::Tag) {
+        return Ok(true);
+    }
+    if txn
+        .get_tag(hash)
+        .map_err(|e| ApiError::internal(format!("Failed to read tag metadata: {}", e)))?
+        .is_some()
+    {
+        return Ok(true);
+    }
+
+    let channel = channel.read();
+    for entry in txn
+        .iter_tags(txn.tags(&*channel), 0)
+        .map_err(|e| ApiError::internal(format!("Failed to iterate tags: {}", e)))?
+    {
+        let (_, tag_bytes) =
+            entry.map_err(|e| ApiError::internal(format!("Failed to read tag entry: {}", e)))?;
+        let Ok(tag) = libatomic::pristine::SerializedTag::from_bytes_wrapper(tag_bytes).to_tag()
+        else {
+            continue;
+        };
+        if tag.change_file_hash.unwrap_or(tag.tag_hash) == *hash || tag.state == *hash {
+            return Ok(true);
+        }
+    }
+
+    let mut tag_path = repository.changes_dir.clone();
+    libatomic::changestore::filesystem::push_tag_filename(&mut tag_path, hash);
+    Ok(tag_path.exists())
+}
+
+/// Result of the upload of a change, sent back in the
+/// [`atomic_remote::http::APPLY_RESULT_HEADER`] header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ApplyOutcome {
+    Applied,
+    /// The channel already had the change, which wasn't written nor
+    /// applied again
+    AlreadyPresent,
+}
+
+impl ApplyOutcome {
+    fn header_value(self) -> &'static str {
+        match self {
+            ApplyOutcome::Applied => atomic_remote::http::APPLIED,
+            ApplyOutcome::AlreadyPresent => atomic_remote::http::ALREADY_PRESENT,
+        }
+    }
+}
+
+/// Check that `body` hashes to `change_hash`, and whether the main
+/// channel of the repository at `repo_path` already has it. Uploads are
+/// deduplicated by the hash of their bytes, not by the hash the client
+/// claims.
+fn has_uploaded_change(
+    repo_path: &std::path::Path,
+    change_hash: &libatomic::Hash,
+    body: &[u8],
+) -> ApiResult<bool> {
+    libatomic::change::Change::check_from_buffer(body, change_hash).map_err(|e| {
+        ApiError::invalid_query(format!(
+            "Uploaded data doesn't match change {}: {}",
+            change_hash.to_base32(),
+            e
+        ))
+    })?;
+    let repository = Repository::find_root(Some(repo_path.to_path_buf()))
+        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+    let txn = repository
+        .pristine
+        .txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin read transaction: {}", e)))?;
+    let Some(channel) = txn
+        .load_channel("main")
+        .map_err(|e| ApiError::internal(format!("Failed to load channel: {}", e)))?
+    else {
+        return Ok(false);
+    };
+    Ok(txn
+        .has_change(&channel, change_hash)
+        .map_err(|e| ApiError::internal(format!("Failed to read channel: {}", e)))?
+        .is_some())
+}
+
+/// Write the uploaded `body` of `change_hash` to the change store, unless
+/// an identical file is already there. Concurrent pushes of the same
+/// change then leave the file alone instead of rewriting it while
+/// another apply may be reading it.
+fn write_uploaded_change(change_path: &std::path::Path, body: &[u8]) -> ApiResult<()> {
+    if let Ok(existing) = std::fs::read(change_path) {
+        if existing == body {
+            debug!("Change file {:?} already present", change_path);
+            return Ok(());
+        }
+    }
+    if let Some(parent) = change_path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| ApiError::internal(format!("Failed to create change directory: {}", e)))?;
+    }
+    let tmp = change_path.with_extension("upload");
+    std::fs::write(&tmp, body)
+        .and_then(|_| std::fs::rename(&tmp, change_path))
+        .map_err(|e| ApiError::internal(format!("Failed to write change file: {}", e)))
+}
+
+/// Record the changes that the applied change `change_hash` supersedes,
+/// see [`atomic_repository::supersede`]. The change is already applied,
+/// so failures are only logged.
+fn record_supersessions(repository: &Repository, change_hash: &libatomic::Hash) {
+    let change = match repository.changes.get_change(change_hash) {
+        Ok(change) => change,
+        Err(e) => {
+            warn!("Failed to read change {}: {}", change_hash.to_base32(), e);
+            return;
+        }
+    };
+    if let Err(e) = repository
+        .supersedes()
+        .record_change(change_hash, &change, chrono::Utc::now())
+    {
+        warn!(
+            "Failed to record changes superseded by {}: {}",
+            change_hash.to_base32(),
+            e
+        );
+    }
+}
+
+/// Record the conflicts of `channel` after an apply, see
+/// [`crate::conflicts`]. `output` are the conflicts found while outputting
+/// the working copy, if it was output. The apply is committed, a failure
+/// to record its conflicts doesn't undo it.
+fn record_conflicts(
+    repository: &Repository,
+    channel: &str,
+    output: Option<Vec<libatomic::output::Conflict>>,
+) {
+    match crate::conflicts::update(repository, channel, output) {
+        Ok(recorded) if !recorded.conflicts.is_empty() => warn!(
+            "Channel {} has {} conflicts at {}",
+            channel,
+            recorded.conflicts.len(),
+            recorded.state
+        ),
+        Ok(_) => {}
+        Err(e) => warn!("Failed to record the conflicts of {}: {}", channel, e),
+    }
+}
+
+/// Reject the change `change_hash`, stored at `change_path`, if it adds
+/// or edits paths forbidden by the `[[forbid]]` rules of `config`, the
+/// resolved configuration of the repository. The change file is removed
+/// from the change store.
+fn check_forbidden_paths(
+    repository: &Repository,
+    config: &atomic_config::Config,
+    change_hash: &libatomic::Hash,
+    change_path: &std::path::Path,
+) -> ApiResult<()> {
+    let forbidden = atomic_repository::forbidden_paths(config)
+        .map_err(|e| ApiError::internal(format!("Invalid forbid rule: {}", e)))?;
+    if forbidden.is_empty() {
+        return Ok(());
+    }
+    let change = repository
+        .changes
+        .get_change(change_hash)
+        .map_err(|e| ApiError::internal(format!("Failed to read change: {}", e)))?;
+    if let Err(e) = forbidden.check_change(&change) {
+        warn!("Rejecting change {}: {}", change_hash.to_base32(), e);
+        std::fs::remove_file(change_path).unwrap_or(());
+        return Err(ApiError::Repository(
+            crate::error::RepositoryError::ForbiddenPaths {
+                change_id: change_hash.to_base32(),
+                violations: e.violations,
+            },
+        ));
+    }
+    Ok(())
+}
+
+/// Reject the change `change_hash`, stored at `change_path`, if its
+/// message doesn't follow the `[message_policy]` of `config`, the resolved
+/// configuration of the repository. The change file is removed from the
+/// change store.
+fn check_message_policy(
+    repository: &Repository,
+    config: &atomic_config::Config,
+    change_hash: &libatomic::Hash,
+    change_path: &std::path::Path,
+) -> ApiResult<()> {
+    if config.message_policy.is_none() {
+        return Ok(());
+    }
+    let header = repository
+        .changes
+        .get_header(change_hash)
+        .map_err(|e| ApiError::internal(format!("Failed to read change: {}", e)))?;
+    if let Err(e) =
+        crate::validate::check_message(config, &change_hash.to_base32(), &header.message)
+    {
+        warn!("Rejecting change {}: {}", change_hash.to_base32(), e);
+        std::fs::remove_file(change_path).unwrap_or(());
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Stage the submitted `changes` on the contribution channel of `query`,
+/// forked from its target channel if it doesn't exist yet, and start
+/// their workflows, see [`crate::submission`]. Either all the changes are
+/// applied, or none.
+fn stage_submission(
+    repo_path: &std::path::Path,
+    config: &atomic_config::Config,
+    registry: &WorkflowRegistry,
+    query: &SubmissionQuery,
+    extracted: &Extracted,
+    changes: &[(libatomic::Hash, Vec<u8>)],
+    shutdown: &Shutdown,
+) -> ApiResult<Submission> {
+    let repository = Repository::find_root(Some(repo_path.to_path_buf()))
+        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+    let (target, channel_name) = (query.target(), query.contribution_channel());
+
+    for (hash, change) in changes.iter() {
+        let mut change_path = repository.changes_dir.clone();
+        libatomic::changestore::filesystem::push_filename(&mut change_path, hash);
+        write_uploaded_change(&change_path, change)?;
+        check_forbidden_paths(&repository, config, hash, &change_path)?;
+        check_message_policy(&repository, config, hash, &change_path)?;
+    }
+
+    let _lock = lock_repository(&repository, "api submission", &LockOptions::from_env())?;
+    let txn = repository
+        .pristine
+        .arc_txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+    let channel = {
+        let mut txn = txn.write();
+        match txn
+            .load_channel(&channel_name)
+            .map_err(|e| ApiError::internal(format!("Failed to load channel: {}", e)))?
+        {
+            Some(channel) => channel,
+            None => {
+                let source = load_listed_channel(&*txn, Some(target))?;
+                info!(
+                    "Forking {} into contribution channel {}",
+                    target, channel_name
+                );
+                txn.fork(&source, &channel_name)
+                    .map_err(|e| ApiError::internal(format!("Failed to fork {}: {}", target, e)))?
+            }
+        }
+    };
+    let mut staged = Vec::with_capacity(changes.len());
+    for (hash, _) in changes.iter() {
+        let present = txn
+            .read()
+            .has_change(&channel, hash)
+            .map_err(|e| ApiError::internal(format!("Failed to read channel: {}", e)))?
+            .is_some();
+        if !present {
+            // Dropping the transaction on error stages none of the changes.
+            txn.write()
+                .apply_change_rec(&repository.changes, &mut channel.write(), hash)
+                .map_err(|e| {
+                    ApiError::invalid_query(format!("Failed to apply {}: {}", hash.to_base32(), e))
+                })?;
+        }
+        staged.push((*hash, !present));
+    }
+    shutdown.check_commit()?;
+    txn.commit()
+        .map_err(|e| ApiError::internal(format!("Failed to commit transaction: {}", e)))?;
+    info!(
+        "Staged {} submitted changes on {}",
+        staged.iter().filter(|(_, applied)| *applied).count(),
+        channel_name
+    );
+
+    // The changes are staged, a failure to start their workflows doesn't
+    // undo it.
+    let rules = crate::submission::bootstrap_rules(config, &channel_name);
+    let rules: Vec<_> = rules.iter().collect();
+    let mut submitted = Vec::with_capacity(staged.len());
+    for (hash, applied) in staged {
+        let workflows = if applied {
+            match crate::workflow::bootstrap_rules(
+                &repository,
+                registry,
+                &rules,
+                &channel_name,
+                &hash,
+            ) {
+                Ok(started) => started.into_iter().map(|b| b.workflow).collect(),
+                Err(e) => {
+                    warn!(
+                        "Failed to start the workflows of {}: {}",
+                        hash.to_base32(),
+                        e
+                    );
+                    Vec::new()
+                }
+            }
+        } else {
+            Vec::new()
+        };
+        submitted.push(SubmittedChange {
+            hash: hash.to_base32(),
+            message: repository
+                .changes
+                .get_header(&hash)
+                .map(|h| h.message)
+                .unwrap_or_default(),
+            applied,
+            workflows,
+        });
+    }
+    Ok(Submission {
+        channel: channel_name,
+        target: target.to_string(),
+        from: extracted.from.clone(),
+        subject: extracted.subject.clone(),
+        changes: submitted,
+    })
+}
+
+/// Whether the change `hash`, summarized by `summary`, would be applied to
+/// the main channel of the repository at `repo_path`, of resolved
+/// configuration `config`, whose `[[protect]]` rules refer to the
+/// workflows of `registry`. Fails with the error the apply would fail
+/// with, see [`crate::validate`].
+fn validate_upload(
+    repo_path: &std::path::Path,
+    config: &atomic_config::Config,
+    registry: &WorkflowRegistry,
+    hash: &libatomic::Hash,
+    summary: &atomic_remote::summary::ChangeSummary,
+) -> ApiResult<Validation> {
+    let change_id = hash.to_base32();
+    let repository = Repository::find_root(Some(repo_path.to_path_buf()))
+        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+    let txn = repository
+        .pristine
+        .txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin read transaction: {}", e)))?;
+    let channel_name = "main";
+    let channel = txn
+        .load_channel(channel_name)
+        .map_err(|e| ApiError::internal(format!("Failed to load channel: {}", e)))?
+        .ok_or_else(|| ApiError::internal(format!("Channel {} not found", channel_name)))?;
+    if txn
+        .has_change(&channel, hash)
+        .map_err(|e| ApiError::internal(format!("Failed to check change: {}", e)))?
+        .is_some()
+    {
+        return Ok(Validation {
+            change_id,
+            already_present: true,
+        });
+    }
+
+    crate::workflow::check_protected(&txn, registry, config, channel_name, &change_id)?;
+
+    let dependencies = summary
+        .dependencies
+        .iter()
+        .map(|dep| {
+            libatomic::Hash::from_base32(dep.as_bytes())
+                .ok_or_else(|| ApiError::invalid_query(format!("Invalid dependency {}", dep)))
+        })
+        .collect::<ApiResult<Vec<_>>>()?;
+    let missing = missing_dependencies(&repository, &txn, &channel, hash, &dependencies)?;
+    if !missing.is_empty() {
+        return Err(ApiError::Repository(
+            crate::error::RepositoryError::MissingDependencies {
+                change_id,
+                dependencies: missing.iter().map(|h| h.to_base32()).collect(),
+            },
+        ));
+    }
+
+    crate::validate::check_paths(config, &change_id, summary.paths())?;
+    crate::validate::check_message(config, &change_id, &summary.header.message)?;
+    Ok(Validation {
+        change_id,
+        already_present: false,
+    })
+}
+
+/// Apply the change `apply_hash`, whose contents are `body`, to the main
+/// channel of the repository at `repo_path`, of resolved configuration
+/// `config`, whose `[[protect]]` rules refer to the workflows of
+/// `registry`. Runs on an apply worker, see [`crate::apply_queue`], and
+/// rolls back if `shutdown` aborts it.
+fn apply_change(
+    repo_path: &std::path::Path,
+    config: &atomic_config::Config,
+    registry: &WorkflowRegistry,
+    apply_hash: &str,
+    body: &[u8],
+    shutdown: &Shutdown,
+) -> ApiResult<ApplyOutcome> {
+    // Parse the change hash
+    let change_hash = libatomic::Hash::from_base32(apply_hash.as_bytes())
+        .ok_or_else(|| ApiError::internal("Invalid change hash format".to_string()))?;
+
+    info!("Applying change {} to repository", apply_hash);
+
+    // Open repository and begin read transaction for change detection
+    let repository = Repository::find_root(Some(repo_path.to_path_buf()))
+        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+
+    let read_txn = repository
+        .pristine
+        .txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin read transaction: {}", e)))?;
+
+    // Get main channel for change detection
+    let channel_name = "main";
+    let channel = match read_txn.load_channel(channel_name) {
+        Ok(Some(channel)) => channel,
+        Ok(None) => {
+            return Err(ApiError::internal(format!(
+                "Channel {} not found",
+                channel_name
+            )));
+        }
+        Err(e) => return Err(ApiError::internal(format!("Failed to load channel: {}", e))),
+    };
+
+    // Check if change already exists in the channel
+    info!("Checking if change {} exists in channel 'main'", apply_hash);
+
+    match read_txn.has_change(&channel, &change_hash) {
+        Ok(Some(_)) => {
+            info!(
+                "Change {} already exists in repository, skipping",
+                apply_hash
+            );
+            return Ok(ApplyOutcome::AlreadyPresent);
+        }
+        Ok(None) => {
+            info!(
+                "Change {} does not exist in channel, proceeding with apply",
+                apply_hash
+            );
+        }
+        Err(e) => {
+            error!("Error checking if change {} exists: {}", apply_hash, e);
+        }
+    }
 
-    Ok(Json(page))
-}
+    if let Err(e) = crate::workflow::check_protected(
+        &read_txn,
+        registry,
+        config,
+        channel_name,
+        &change_hash.to_base32(),
+    ) {
+        warn!("Rejecting change {}: {}", apply_hash, e);
+        return Err(e);
+    }
 
-/// Get specific change by ID for tenant/portfolio/project repository
-async fn get_change(
-    State(state): State<AppState>,
-    Path((tenant_id, portfolio_id, project_id, change_id)): Path<(String, String, String, String)>,
-    Query(params): Query<ChangesQuery>,
-) -> ApiResult<Json<ChangeInfo>> {
-    // Validate tenant, portfolio and project IDs following AGENTS.md validation patterns
-    validate_id(&tenant_id, "tenant_id")?;
-    validate_id(&portfolio_id, "portfolio_id")?;
-    validate_id(&project_id, "project_id")?;
+    // Write change data to repository changes store using the repository's changes_dir
+    let mut change_path = repository.changes_dir.clone();
+    libatomic::changestore::filesystem::push_filename(&mut change_path, &change_hash);
+    write_uploaded_change(&change_path, body)?;
 
-    // Construct repository path: /mount/tenant_id/portfolio_id/project_id/.atomic
-    let repo_path = state
-        .base_mount_path
-        .join(&tenant_id)
-        .join(&portfolio_id)
-        .join(&project_id);
+    // Validate dependencies before applying - following AGENTS.md validation patterns
+    info!("Validating dependencies for change {}", apply_hash);
+    let missing_deps =
+        validate_change_dependencies(&repository, &read_txn, &channel, &change_hash)?;
 
-    // Validate repository exists
-    if !repo_path.exists() {
-        warn!("Repository not found: {}", repo_path.display());
-        return Err(ApiError::repository_not_found(repo_path.to_string_lossy()));
+    if !missing_deps.is_empty() {
+        let err = ApiError::Repository(crate::error::RepositoryError::MissingDependencies {
+            change_id: apply_hash.to_string(),
+            dependencies: missing_deps.iter().map(|h| h.to_base32()).collect(),
+        });
+        warn!("Cannot apply change: {}", err);
+        return Err(err);
     }
 
-    // Open repository on demand to avoid thread safety issues
-    let repository = Repository::find_root(Some(repo_path))
-        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+    info!("All dependencies satisfied for change {}", apply_hash);
 
-    // Read specific change from filesystem with optional diff and AI attribution
-    match read_change_from_filesystem(
-        &repository,
-        &change_id,
-        params.include_diff,
-        params.include_ai_attribution,
-    ) {
-        Ok(Some(change)) => Ok(Json(change)),
-        Ok(None) => Err(ApiError::Repository(
-            crate::error::RepositoryError::ChangeNotFound { change_id },
-        )),
-        Err(e) => Err(ApiError::internal(format!("Failed to read change: {}", e))),
-    }
-}
+    check_forbidden_paths(&repository, config, &change_hash, &change_path)?;
+    check_message_policy(&repository, config, &change_hash, &change_path)?;
 
-/// Validate that all dependencies for a change exist in the channel
-/// Following AGENTS.md error handling patterns
-///
-/// # Arguments
-/// * `repository` - Repository containing the change
-/// * `txn` - Transaction for checking dependencies
-/// * `channel` - Channel to check dependencies in
-/// * `change_hash` - Hash of the change to validate
-///
-/// # Returns
-/// * `Ok(Vec::new())` - All dependencies satisfied
-/// * `Ok(Vec<Hash>)` - List of missing dependency hashes
-/// * `Err(ApiError)` - Failed to check dependencies
-///
-/// # Errors
-/// Returns ApiError if change cannot be read or dependency check fails
-fn validate_change_dependencies(
-    repository: &Repository,
-    txn: &libatomic::pristine::sanakirja::Txn,
-    channel: &libatomic::pristine::ChannelRef<libatomic::pristine::sanakirja::Txn>,
-    change_hash: &libatomic::Hash,
-) -> ApiResult<Vec<libatomic::Hash>> {
-    use libatomic::changestore::ChangeStore;
+    // Applies run on blocking threads, and can wait for the CLI.
+    let _lock = lock_repository(&repository, "api apply", &LockOptions::from_env())?;
+
+    // If change doesn't exist, begin mutable transaction for applying
+    // Use arc_txn_begin instead of mut_txn_begin to get ArcTxn for output functions
+    let txn = repository
+        .pristine
+        .arc_txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin mutable transaction: {}", e)))?;
 
-    let missing = Vec::new();
+    // Get channel again in mutable transaction
+    let mut_channel = {
+        let mut txn_write = txn.write();
+        match txn_write.load_channel(channel_name) {
+            Ok(Some(channel)) => channel,
+            Ok(None) => txn_write
+                .open_or_create_channel(channel_name)
+                .map_err(|e| ApiError::internal(format!("Failed to create channel: {}", e)))?,
+            Err(e) => return Err(ApiError::internal(format!("Failed to load channel: {}", e))),
+        }
+    };
 
-    // 1. Read change file to get dependencies
-    let change = repository.changes.get_change(change_hash).map_err(|e| {
-        ApiError::internal(format!(
-            "Failed to read change {} for dependency validation: {}",
-            change_hash.to_base32(),
-            e
-        ))
-    })?;
+    // Apply the change to the channel
+    let apply_result = {
+        let mut channel_guard = mut_channel.write();
+        txn.write().apply_node_rec(
+            &repository.changes,
+            &mut channel_guard,
+            &change_hash,
+            libatomic::pristine::NodeType::Change,
+        )
+    };
 
-    // 2. Check each dependency exists in the channel OR is a tag
-    for dep_hash in &change.dependencies {
-        match txn.has_change(channel, dep_hash) {
-            Ok(Some(_)) => {
-                // Dependency exists as a regular change, continue
-                tracing::debug!(
-                    "Dependency {} found for change {}",
-                    dep_hash.to_base32(),
-                    change_hash.to_base32()
-                );
-            }
-            Ok(None) => {
-                // Not found as a regular change - might be a tag dependency
-                // Tags are virtual dependencies used for O(1) dependency reduction
-                // They don't need to be in the channel for validation since:
-                // 1. Tag files might not exist on server yet (pushed separately)
-                // 2. Tags represent consolidated changes, not actual changes
-                // 3. Client has already validated the dependency graph
-                //
-                // We trust the client's validation and skip server-side validation for tags
-                tracing::info!(
-                    "Dependency {} not found as change - assuming it's a tag dependency (skipping validation)",
-                    dep_hash.to_base32()
+    match apply_result {
+        Ok(_) => {
+            // Dropping the transaction rolls the apply back.
+            shutdown.check_commit()?;
+
+            // Output changes to working copy BEFORE committing
+            // Skip for bare/server repositories that don't have working copy files
+            let is_bare_repo = !repository.path.exists()
+                || repository
+                    .path
+                    .read_dir()
+                    .map(|mut d| d.next().is_none())
+                    .unwrap_or(true);
+
+            let output = if !is_bare_repo {
+                info!("Outputting applied change {} to working copy", apply_hash);
+                let conflicts = libatomic::output::output_repository_no_pending(
+                    &repository.working_copy,
+                    &repository.changes,
+                    &txn,
+                    &mut_channel,
+                    "",
+                    true,
+                    None,
+                    std::thread::available_parallelism()
+                        .map(|p| p.get())
+                        .unwrap_or(1),
+                    0,
+                )
+                .map_err(|e| {
+                    ApiError::internal(format!("Failed to output to working copy: {}", e))
+                })?;
+                Some(conflicts.into_iter().collect())
+            } else {
+                info!(
+                    "Skipping working copy output for bare repository (change {} applied to database only)",
+                    apply_hash
                 );
-                tracing::debug!("Tag dependencies are validated client-side and trusted by server");
-                // Don't add to missing list - tags are valid dependencies
+                None
+            };
+
+            // Commit the transaction
+            shutdown.check_commit()?;
+            txn.commit()
+                .map_err(|e| ApiError::internal(format!("Failed to commit transaction: {}", e)))?;
+
+            info!("Successfully applied change {} to repository", apply_hash);
+            record_supersessions(&repository, &change_hash);
+            record_conflicts(&repository, channel_name, output);
+            // The change is applied, a failure to start its workflows
+            // doesn't undo it.
+            if let Err(e) = crate::workflow::bootstrap(
+                &repository,
+                registry,
+                config,
+                channel_name,
+                &change_hash,
+            ) {
+                warn!("Failed to start workflows on {}: {}", apply_hash, e);
             }
-            Err(e) => {
-                return Err(ApiError::internal(format!(
-                    "Failed to check dependency {}: {}",
-                    dep_hash.to_base32(),
+            atomic_config::events::publish(atomic_config::events::Event::NodeApplied {
+                repository: repository.path.clone(),
+                channel: channel_name.to_string(),
+                kind: atomic_config::events::NodeKind::Change,
+                hash: apply_hash.to_string(),
+            });
+
+            // Check if the resulting state should have a tag file
+            // This ensures tag files exist for all tagged states
+            let txn = repository.pristine.txn_begin().map_err(|e| {
+                error!("Failed to begin transaction for tag generation: {}", e);
+                ApiError::internal(format!(
+                    "Failed to begin transaction for tag generation: {}",
                     e
-                )));
+                ))
+            })?;
+
+            if let Ok(Some(channel)) = txn.load_channel(channel_name) {
+                let channel_ref = channel.read();
+                match libatomic::pristine::current_state(&txn, &*channel_ref) {
+                    Ok(state) => {
+                        // Check if this state is actually tagged
+                        let is_tagged = if let Some(n) = txn
+                            .channel_has_state(&channel_ref.states, &state.into())
+                            .ok()
+                            .flatten()
+                        {
+                            txn.is_tagged(&channel_ref.tags, n.into()).unwrap_or(false)
+                        } else {
+                            false
+                        };
+
+                        if is_tagged {
+                            let mut tag_path = repository.changes_dir.clone();
+                            libatomic::changestore::filesystem::push_tag_filename(
+                                &mut tag_path,
+                                &state,
+                            );
+
+                            // Only generate tag file if it doesn't already exist
+                            if !tag_path.exists() {
+                                info!(
+                                    "Generating tag file for tagged state {} after applying change {}",
+                                    state.to_base32(),
+                                    apply_hash
+                                );
+
+                                // Create parent directories if needed
+                                if let Some(parent) = tag_path.parent() {
+                                    if let Err(e) = std::fs::create_dir_all(parent) {
+                                        error!("Failed to create tag directory: {}", e);
+                                    }
+                                }
+
+                                // Create a temporary file path for atomic write
+                                let temp_path = tag_path.with_extension("tmp");
+
+                                // Generate and write the tag file
+                                // Create a dummy header for the tag
+                                let header = libatomic::change::ChangeHeader {
+                                    message: format!("Tagged state {}", state.to_base32()),
+                                    description: None,
+                                    timestamp: chrono::Utc::now(),
+                                    authors: Vec::new(),
+                                };
+
+                                match std::fs::File::create(&temp_path) {
+                                    Ok(mut w) => {
+                                        match libatomic::tag::from_channel(
+                                            &txn,
+                                            channel_name,
+                                            &header,
+                                            &mut w,
+                                        ) {
+                                            Ok(_) => {
+                                                // Atomically rename temp file to final location
+                                                if let Err(e) =
+                                                    std::fs::rename(&temp_path, &tag_path)
+                                                {
+                                                    error!(
+                                                        "Failed to rename tag file for state {}: {}",
+                                                        state.to_base32(),
+                                                        e
+                                                    );
+                                                } else {
+                                                    info!(
+                                                        "Successfully generated tag file for tagged state {}",
+                                                        state.to_base32()
+                                                    );
+                                                }
+                                            }
+                                            Err(e) => {
+                                                error!(
+                                                    "Failed to generate tag file for state {}: {}",
+                                                    state.to_base32(),
+                                                    e
+                                                );
+                                                // Clean up temp file
+                                                let _ = std::fs::remove_file(&temp_path);
+                                            }
+                                        }
+                                    }
+                                    Err(e) => {
+                                        error!(
+                                            "Failed to create temp file for state {}: {}",
+                                            state.to_base32(),
+                                            e
+                                        );
+                                    }
+                                }
+                            } else {
+                                info!(
+                                    "Tag file already exists for tagged state {}",
+                                    state.to_base32()
+                                );
+                            }
+                        } else {
+                            debug!(
+                                "State {} is not tagged, skipping tag file generation",
+                                state.to_base32()
+                            );
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to get current state for tag generation: {}", e);
+                        // Don't fail the apply operation if we can't get the state
+                    }
+                }
+            } else {
+                error!("Failed to load channel for tag generation");
+                // Don't fail the apply operation if we can't load the channel
             }
+
+            Ok(ApplyOutcome::Applied)
+        }
+        Err(e) => {
+            error!("Failed to apply change {}: {}", apply_hash, e);
+
+            // Provide more specific error messages
+            let error_msg = if e.to_string().contains("fill whole buffer") {
+                format!(
+                    "Invalid change data format for change {}: {}",
+                    apply_hash, e
+                )
+            } else if e.to_string().contains("already") {
+                format!("Change {} already applied: {}", apply_hash, e)
+            } else {
+                format!("Failed to apply change {}: {}", apply_hash, e)
+            };
+
+            Err(ApiError::internal(error_msg))
         }
     }
+}
 
-    Ok(missing)
+/// Empty response to an apply (the atomic protocol expects a minimal
+/// response), telling whether the change was applied or already there
+fn apply_response(outcome: ApplyOutcome) -> ApiResult<Response<Body>> {
+    Response::builder()
+        .status(200)
+        .header("content-type", "application/octet-stream")
+        .header(
+            atomic_remote::http::APPLY_RESULT_HEADER,
+            outcome.header_value(),
+        )
+        .body(Body::empty())
+        .map_err(|e| ApiError::internal(format!("Failed to build response: {}", e)))
 }
 
 /// Atomic protocol endpoint - handles POST operations for applying changes
@@ -523,10 +4485,8 @@ async fn post_atomic_protocol(
 
     // Construct repository path: /mount/tenant_id/portfolio_id/project_id
     let repo_path = state
-        .base_mount_path
-        .join(&tenant_id)
-        .join(&portfolio_id)
-        .join(&project_id);
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
 
     // Validate repository exists
     if !repo_path.exists() {
@@ -544,325 +4504,203 @@ async fn post_atomic_protocol(
 
     // Handle apply operation
     if let Some(apply_hash) = params.get("apply") {
-        // Parse the change hash
-        let change_hash = libatomic::Hash::from_base32(apply_hash.as_bytes())
-            .ok_or_else(|| ApiError::internal("Invalid change hash format".to_string()))?;
-
-        info!("Applying change {} to repository", apply_hash);
-
-        // Open repository and begin read transaction for change detection
-        let repository = Repository::find_root(Some(repo_path))
-            .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
-
-        let read_txn = repository
-            .pristine
-            .txn_begin()
-            .map_err(|e| ApiError::internal(format!("Failed to begin read transaction: {}", e)))?;
+        let respond_async = params.get("async").map_or(false, |v| v == "true");
+        let apply_hash = apply_hash.clone();
 
-        // Write change data to repository changes store using the repository's changes_dir
-        let mut change_path = repository.changes_dir.clone();
-        libatomic::changestore::filesystem::push_filename(&mut change_path, &change_hash);
-
-        // Ensure parent directories exist
-        if let Some(parent) = change_path.parent() {
-            std::fs::create_dir_all(parent).map_err(|e| {
-                ApiError::internal(format!("Failed to create change directory: {}", e))
-            })?;
-        }
-
-        std::fs::write(&change_path, &body)
-            .map_err(|e| ApiError::internal(format!("Failed to write change file: {}", e)))?;
-
-        // Get main channel for change detection
-        let channel_name = "main";
-        let channel = match read_txn.load_channel(channel_name) {
-            Ok(Some(channel)) => channel,
-            Ok(None) => {
-                return Err(ApiError::internal(format!(
-                    "Channel {} not found",
-                    channel_name
-                )));
-            }
-            Err(e) => return Err(ApiError::internal(format!("Failed to load channel: {}", e))),
-        };
-
-        // Check if change already exists in the channel
-        info!("Checking if change {} exists in channel 'main'", apply_hash);
+        let hash = libatomic::Hash::from_base32(apply_hash.as_bytes()).ok_or_else(|| {
+            ApiError::invalid_query(format!("Invalid change hash {}", apply_hash))
+        })?;
 
-        match read_txn.has_change(&channel, &change_hash) {
-            Ok(Some(_)) => {
-                info!(
-                    "Change {} already exists in repository, skipping",
-                    apply_hash
-                );
-                // Return empty response for already applied changes (atomic protocol expects minimal response)
-                return Ok(Response::builder()
-                    .status(200)
-                    .header("content-type", "application/octet-stream")
-                    .body(Body::empty())
-                    .map_err(|e| {
-                        ApiError::internal(format!("Failed to build response: {}", e))
-                    })?);
-            }
-            Ok(None) => {
-                info!(
-                    "Change {} does not exist in channel, proceeding with apply",
-                    apply_hash
-                );
-            }
-            Err(e) => {
-                error!("Error checking if change {} exists: {}", apply_hash, e);
-            }
+        // Confidential channels only take envelopes, which aren't applied.
+        if libatomic::envelope::is_envelope(&body) {
+            return Err(ApiError::invalid_query(format!(
+                "Envelope of {} uploaded as a change, use ?envelope=",
+                apply_hash
+            )));
         }
-
-        // Validate dependencies before applying - following AGENTS.md validation patterns
-        info!("Validating dependencies for change {}", apply_hash);
-        let missing_deps =
-            validate_change_dependencies(&repository, &read_txn, &channel, &change_hash)?;
-
-        if !missing_deps.is_empty() {
-            let deps_str = missing_deps
-                .iter()
-                .map(|h| h.to_base32())
-                .collect::<Vec<_>>()
-                .join(", ");
-
-            let error_msg = format!(
-                "Cannot apply change {}: missing {} dependency/dependencies: {}",
-                apply_hash,
-                missing_deps.len(),
-                deps_str
-            );
-
-            warn!("{}", error_msg);
-            return Err(ApiError::internal(error_msg));
+        let to_channel = params.get("to_channel").map_or("main", String::as_str);
+        let config = state.configs.resolve(&state.jail, &tenant_id, &repo_path)?;
+        crate::envelopes::check_clear(&config.config, to_channel, &apply_hash)?;
+
+        // Changes the channel already has are acknowledged without queuing
+        // an apply, e.g. when concurrent pushes upload the same change.
+        let (path, body_) = (repo_path.clone(), body.clone());
+        let present =
+            tokio::task::spawn_blocking(move || has_uploaded_change(&path, &hash, &body_))
+                .await
+                .map_err(|e| ApiError::internal(format!("Apply task failed: {}", e)))??;
+        if present {
+            info!("Change {} already present, not applied again", apply_hash);
+            return apply_response(ApplyOutcome::AlreadyPresent);
         }
 
-        info!("All dependencies satisfied for change {}", apply_hash);
-
-        // If change doesn't exist, begin mutable transaction for applying
-        // Use arc_txn_begin instead of mut_txn_begin to get ArcTxn for output functions
-        let txn = repository.pristine.arc_txn_begin().map_err(|e| {
-            ApiError::internal(format!("Failed to begin mutable transaction: {}", e))
-        })?;
-
-        // Get channel again in mutable transaction
-        let mut_channel = {
-            let mut txn_write = txn.write();
-            match txn_write.load_channel(channel_name) {
-                Ok(Some(channel)) => channel,
-                Ok(None) => txn_write
-                    .open_or_create_channel(channel_name)
-                    .map_err(|e| ApiError::internal(format!("Failed to create channel: {}", e)))?,
-                Err(e) => return Err(ApiError::internal(format!("Failed to load channel: {}", e))),
-            }
-        };
-
-        // Apply the change to the channel
-        let apply_result = {
-            let mut channel_guard = mut_channel.write();
-            txn.write().apply_node_rec(
-                &repository.changes,
-                &mut channel_guard,
-                &change_hash,
-                libatomic::pristine::NodeType::Change,
-            )
-        };
-
-        match apply_result {
-            Ok(_) => {
-                // Output changes to working copy BEFORE committing
-                // Skip for bare/server repositories that don't have working copy files
-                let is_bare_repo = !repository.path.exists()
-                    || repository
-                        .path
-                        .read_dir()
-                        .map(|mut d| d.next().is_none())
-                        .unwrap_or(true);
-
-                if !is_bare_repo {
-                    info!("Outputting applied change {} to working copy", apply_hash);
-                    libatomic::output::output_repository_no_pending(
-                        &repository.working_copy,
-                        &repository.changes,
-                        &txn,
-                        &mut_channel,
-                        "",
-                        true,
-                        None,
-                        std::thread::available_parallelism()
-                            .map(|p| p.get())
-                            .unwrap_or(1),
-                        0,
-                    )
-                    .map_err(|e| {
-                        ApiError::internal(format!("Failed to output to working copy: {}", e))
-                    })?;
-                } else {
-                    info!(
-                        "Skipping working copy output for bare repository (change {} applied to database only)",
-                        apply_hash
-                    );
-                }
-
-                // Commit the transaction
-                txn.commit().map_err(|e| {
-                    ApiError::internal(format!("Failed to commit transaction: {}", e))
-                })?;
-
-                info!("Successfully applied change {} to repository", apply_hash);
-
-                // Check if the resulting state should have a tag file
-                // This ensures tag files exist for all tagged states
-                let txn = repository.pristine.txn_begin().map_err(|e| {
-                    error!("Failed to begin transaction for tag generation: {}", e);
-                    ApiError::internal(format!(
-                        "Failed to begin transaction for tag generation: {}",
-                        e
-                    ))
-                })?;
-
-                if let Ok(Some(channel)) = txn.load_channel(channel_name) {
-                    let channel_ref = channel.read();
-                    match libatomic::pristine::current_state(&txn, &*channel_ref) {
-                        Ok(state) => {
-                            // Check if this state is actually tagged
-                            let is_tagged = if let Some(n) = txn
-                                .channel_has_state(&channel_ref.states, &state.into())
-                                .ok()
-                                .flatten()
-                            {
-                                txn.is_tagged(&channel_ref.tags, n.into()).unwrap_or(false)
-                            } else {
-                                false
-                            };
-
-                            if is_tagged {
-                                let mut tag_path = repository.changes_dir.clone();
-                                libatomic::changestore::filesystem::push_tag_filename(
-                                    &mut tag_path,
-                                    &state,
-                                );
-
-                                // Only generate tag file if it doesn't already exist
-                                if !tag_path.exists() {
-                                    info!(
-                                        "Generating tag file for tagged state {} after applying change {}",
-                                        state.to_base32(),
-                                        apply_hash
-                                    );
-
-                                    // Create parent directories if needed
-                                    if let Some(parent) = tag_path.parent() {
-                                        if let Err(e) = std::fs::create_dir_all(parent) {
-                                            error!("Failed to create tag directory: {}", e);
-                                        }
-                                    }
-
-                                    // Create a temporary file path for atomic write
-                                    let temp_path = tag_path.with_extension("tmp");
-
-                                    // Generate and write the tag file
-                                    // Create a dummy header for the tag
-                                    let header = libatomic::change::ChangeHeader {
-                                        message: format!("Tagged state {}", state.to_base32()),
-                                        description: None,
-                                        timestamp: chrono::Utc::now(),
-                                        authors: Vec::new(),
-                                    };
-
-                                    match std::fs::File::create(&temp_path) {
-                                        Ok(mut w) => {
-                                            match libatomic::tag::from_channel(
-                                                &txn,
-                                                channel_name,
-                                                &header,
-                                                &mut w,
-                                            ) {
-                                                Ok(_) => {
-                                                    // Atomically rename temp file to final location
-                                                    if let Err(e) =
-                                                        std::fs::rename(&temp_path, &tag_path)
-                                                    {
-                                                        error!(
-                                                            "Failed to rename tag file for state {}: {}",
-                                                            state.to_base32(),
-                                                            e
-                                                        );
-                                                    } else {
-                                                        info!(
-                                                            "Successfully generated tag file for tagged state {}",
-                                                            state.to_base32()
-                                                        );
-                                                    }
-                                                }
-                                                Err(e) => {
-                                                    error!(
-                                                        "Failed to generate tag file for state {}: {}",
-                                                        state.to_base32(),
-                                                        e
-                                                    );
-                                                    // Clean up temp file
-                                                    let _ = std::fs::remove_file(&temp_path);
-                                                }
-                                            }
-                                        }
-                                        Err(e) => {
-                                            error!(
-                                                "Failed to create temp file for state {}: {}",
-                                                state.to_base32(),
-                                                e
-                                            );
-                                        }
-                                    }
-                                } else {
-                                    info!(
-                                        "Tag file already exists for tagged state {}",
-                                        state.to_base32()
-                                    );
-                                }
-                            } else {
-                                debug!(
-                                    "State {} is not tagged, skipping tag file generation",
-                                    state.to_base32()
-                                );
-                            }
-                        }
-                        Err(e) => {
-                            error!("Failed to get current state for tag generation: {}", e);
-                            // Don't fail the apply operation if we can't get the state
-                        }
-                    }
-                } else {
-                    error!("Failed to load channel for tag generation");
-                    // Don't fail the apply operation if we can't load the channel
-                }
-
-                // Return empty response for successful applies (atomic protocol expects minimal response)
-                Ok(Response::builder()
-                    .status(200)
-                    .header("content-type", "application/octet-stream")
-                    .body(Body::empty())
-                    .map_err(|e| ApiError::internal(format!("Failed to build response: {}", e)))?)
+        let outcome = Arc::new(Mutex::new(ApplyOutcome::Applied));
+        let outcome_ = outcome.clone();
+        let shutdown = state.shutdown.clone();
+        let (configs, jail) = (state.configs.clone(), state.jail.clone());
+        let (workflows, inbox) = (state.workflows.clone(), state.inbox.clone());
+        let operation = state.applies.submit(repo_path.clone(), move || {
+            let config = configs.resolve(&jail, &tenant_id, &repo_path)?;
+            *outcome_.lock().unwrap() = apply_change(
+                &repo_path,
+                &config.config,
+                &workflows,
+                &apply_hash,
+                &body,
+                &shutdown,
+            )?;
+            if !config.config.bootstrap.is_empty() {
+                inbox.update_at(&repo_path, &workflows, &[apply_hash.clone()]);
             }
-            Err(e) => {
-                error!("Failed to apply change {}: {}", apply_hash, e);
+            Ok(())
+        })?;
 
-                // Provide more specific error messages
-                let error_msg = if e.to_string().contains("fill whole buffer") {
-                    format!(
-                        "Invalid change data format for change {}: {}",
-                        apply_hash, e
-                    )
-                } else if e.to_string().contains("already") {
-                    format!("Change {} already applied: {}", apply_hash, e)
-                } else {
-                    format!("Failed to apply change {}: {}", apply_hash, e)
-                };
+        if respond_async {
+            // Let the client poll the operation instead of holding the
+            // connection open while the apply waits for its turn.
+            let accepted = OperationAccepted {
+                operation_id: operation.id().to_string(),
+                status_url: format!("/operations/{}", operation.id()),
+            };
+            return Ok(Response::builder()
+                .status(StatusCode::ACCEPTED)
+                .header("content-type", "application/json")
+                .header("location", &accepted.status_url)
+                .body(Body::from(serde_json::to_vec(&accepted).map_err(|e| {
+                    ApiError::internal(format!("Failed to serialize response: {}", e))
+                })?))
+                .map_err(|e| ApiError::internal(format!("Failed to build response: {}", e)))?);
+        }
 
-                Err(ApiError::internal(error_msg))
+        operation.wait().await?;
+        let outcome = *outcome.lock().unwrap();
+        apply_response(outcome)
+    } else if params.contains_key("applybundle") {
+        // Several changes in one bundle (see `atomic_remote::bundle`),
+        // applied in order in a single operation
+        let changes = atomic_remote::bundle::decode(&body)
+            .map_err(|e| ApiError::invalid_query(format!("Invalid bundle: {}", e)))?;
+        if changes.len() > atomic_remote::bundle::MAX_CHANGES {
+            return Err(ApiError::invalid_query(format!(
+                "Bundles hold at most {} changes",
+                atomic_remote::bundle::MAX_CHANGES
+            )));
+        }
+        let to_channel = params.get("to_channel").map_or("main", String::as_str);
+        let config = state.configs.resolve(&state.jail, &tenant_id, &repo_path)?;
+        for (hash, change) in changes.iter() {
+            if libatomic::envelope::is_envelope(change) {
+                return Err(ApiError::invalid_query(format!(
+                    "Envelope of {} uploaded as a change, use ?envelope=",
+                    hash.to_base32()
+                )));
             }
+            crate::envelopes::check_clear(&config.config, to_channel, &hash.to_base32())?;
         }
+
+        let outcomes = Arc::new(Mutex::new(Vec::with_capacity(changes.len())));
+        let outcomes_ = outcomes.clone();
+        let shutdown = state.shutdown.clone();
+        let (configs, jail) = (state.configs.clone(), state.jail.clone());
+        let (workflows, inbox) = (state.workflows.clone(), state.inbox.clone());
+        let operation = state.applies.submit(repo_path.clone(), move || {
+            let config = configs.resolve(&jail, &tenant_id, &repo_path)?;
+            for (hash, change) in changes.iter() {
+                let outcome = apply_change(
+                    &repo_path,
+                    &config.config,
+                    &workflows,
+                    &hash.to_base32(),
+                    change,
+                    &shutdown,
+                )?;
+                outcomes_.lock().unwrap().push(outcome);
+            }
+            if !config.config.bootstrap.is_empty() {
+                let change_ids: Vec<String> = changes.iter().map(|(h, _)| h.to_base32()).collect();
+                inbox.update_at(&repo_path, &workflows, &change_ids);
+            }
+            Ok(())
+        })?;
+        operation.wait().await?;
+
+        let outcomes = outcomes.lock().unwrap();
+        let applied = outcomes
+            .iter()
+            .filter(|o| matches!(o, ApplyOutcome::Applied))
+            .count();
+        info!(
+            "Bundle of {} changes applied, {} already present",
+            outcomes.len(),
+            outcomes.len() - applied
+        );
+        let result = serde_json::json!({
+            "applied": applied,
+            "already_present": outcomes.len() - applied,
+        });
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/json")
+            .body(Body::from(result.to_string()))
+            .map_err(|e| ApiError::internal(format!("Failed to build response: {}", e)))
+    } else if let Some(validate_hash) = params.get("validate") {
+        // Summaries of changes, checked before the changes are uploaded,
+        // see `crate::validate`
+        let hash = libatomic::Hash::from_base32(validate_hash.as_bytes()).ok_or_else(|| {
+            ApiError::invalid_query(format!("Invalid change hash {}", validate_hash))
+        })?;
+        let summary: atomic_remote::summary::ChangeSummary = serde_json::from_slice(&body)
+            .map_err(|e| ApiError::invalid_query(format!("Invalid change summary: {}", e)))?;
+        let to_channel = params.get("to_channel").map_or("main", String::as_str);
+        let config = state.configs.resolve(&state.jail, &tenant_id, &repo_path)?;
+        crate::envelopes::check_clear(&config.config, to_channel, validate_hash)?;
+
+        let workflows = state.workflows.clone();
+        let validation = tokio::task::spawn_blocking(move || {
+            validate_upload(&repo_path, &config.config, &workflows, &hash, &summary)
+        })
+        .await
+        .map_err(|e| ApiError::internal(format!("Validation task failed: {}", e)))??;
+        info!(
+            "Change {} validated, already present: {}",
+            validation.change_id, validation.already_present
+        );
+        Response::builder()
+            .status(StatusCode::OK)
+            .header("content-type", "application/json")
+            .body(Body::from(serde_json::to_vec(&validation).map_err(
+                |e| ApiError::internal(format!("Failed to serialize validation: {}", e)),
+            )?))
+            .map_err(|e| ApiError::internal(format!("Failed to build response: {}", e)))
+    } else if let Some(envelope_hash) = params.get("envelope") {
+        // Envelopes of confidential channels are stored as they are, see
+        // `crate::envelopes`
+        let hash = libatomic::Hash::from_base32(envelope_hash.as_bytes()).ok_or_else(|| {
+            ApiError::invalid_query(format!("Invalid change hash {}", envelope_hash))
+        })?;
+        let channel = params
+            .get("to_channel")
+            .cloned()
+            .unwrap_or_else(|| "main".to_string());
+        let repository = Repository::find_root(Some(repo_path))
+            .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+        let stored = tokio::task::spawn_blocking(move || {
+            let _lock = lock_repository(&repository, "api envelope", &LockOptions::from_env())?;
+            crate::envelopes::store(
+                &repository.path.join(libatomic::DOT_DIR),
+                &channel,
+                &hash,
+                &body,
+            )
+        })
+        .await
+        .map_err(|e| ApiError::internal(format!("Envelope task failed: {}", e)))??;
+        info!("Envelope of {} stored: {}", envelope_hash, stored);
+        apply_response(if stored {
+            ApplyOutcome::Applied
+        } else {
+            ApplyOutcome::AlreadyPresent
+        })
     } else if let Some(tagup_hash) = params.get("tagup") {
         // Handle tag upload operation (for state changes)
         // Following SSH protocol pattern: client sends SHORT tag data,
@@ -870,6 +4708,9 @@ async fn post_atomic_protocol(
         info!("Tag upload operation for state: {}", tagup_hash);
         info!("Tag upload body size: {} bytes (short format)", body.len());
 
+        let config = state.configs.resolve(&state.jail, &tenant_id, &repo_path)?;
+        let workflows = state.workflows.clone();
+
         // Open repository for tagup operation
         let repository = Repository::find_root(Some(repo_path))
             .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
@@ -880,11 +4721,35 @@ async fn post_atomic_protocol(
         })?;
 
         // 2. Parse the SHORT tag header sent by client (SSH protocol pattern)
-        let header = libatomic::tag::read_short(std::io::Cursor::new(&body[..]), &state)
-            .map_err(|e| ApiError::internal(format!("Failed to parse tag header: {}", e)))?;
+        let (header, unhashed) =
+            libatomic::tag::read_short_signed(std::io::Cursor::new(&body[..]), &state)
+                .map_err(|e| ApiError::invalid_query(format!("Invalid tag: {}", e)))?;
 
         info!("Tag header parsed successfully");
 
+        // Reject tags whose signature doesn't match their state and
+        // header, or whose signer the repository doesn't know
+        let dot_dir = repository.path.join(libatomic::DOT_DIR);
+        let signature_status = libatomic::tag::check_received(&state, &header, &unhashed, |key| {
+            atomic_identity::is_known_key(&dot_dir, key)
+        })
+        .map_err(|e| match e {
+            libatomic::tag::TagError::InvalidSignature { reason } => {
+                ApiError::Repository(crate::error::RepositoryError::InvalidTagSignature {
+                    state: state.to_base32(),
+                    reason,
+                })
+            }
+            libatomic::tag::TagError::UntrustedSigner { key } => {
+                ApiError::Repository(crate::error::RepositoryError::UntrustedTagSigner {
+                    state: state.to_base32(),
+                    key,
+                })
+            }
+            e => ApiError::internal(e.to_string()),
+        })?;
+        info!("Tag signature: {:?}", signature_status);
+
         // 3. Get channel name from to_channel parameter (or use default "main")
         let channel_name = params
             .get("to_channel")
@@ -920,20 +4785,32 @@ async fn post_atomic_protocol(
             state.to_base32()
         );
 
+        // Tags of protected channels only cover changes in the required
+        // workflow state.
+        crate::workflow::check_protected_tag(
+            &txn,
+            &workflows,
+            &config.config,
+            &*channel.read(),
+            channel_name,
+        )?;
+
         // 5. Construct tag file path and check if file already exists
         let mut tag_path = repository.changes_dir.clone();
         libatomic::changestore::filesystem::push_tag_filename(&mut tag_path, &state);
 
+        // Tags are immutable: a tagged state can't be tagged again.
+        let tag_exists = || {
+            ApiError::Repository(crate::error::RepositoryError::TagExists {
+                state: state.to_base32(),
+            })
+        };
         info!("Checking if tag file exists at: {:?}", tag_path);
         let file_exists = tag_path.exists();
         info!("Tag file exists: {}", file_exists);
 
         if file_exists {
-            return Err(ApiError::internal(format!(
-                "Tag for state {} already exists at {:?}",
-                state.to_base32(),
-                tag_path
-            )));
+            return Err(tag_exists());
         }
 
         // 6. Check if current state is already tagged in database (SSH protocol pattern)
@@ -950,10 +4827,7 @@ async fn post_atomic_protocol(
             .is_tagged(&channel.read().tags, last_t)
             .map_err(|e| ApiError::internal(format!("Failed to check if tagged: {}", e)))?
         {
-            return Err(ApiError::internal(format!(
-                "Current state {} is already tagged",
-                state.to_base32()
-            )));
+            return Err(tag_exists());
         }
 
         info!("State not yet tagged, proceeding with tag creation");
@@ -976,7 +4850,14 @@ async fn post_atomic_protocol(
                 ApiError::internal(format!("Failed to create temp tag file: {}", e))
             })?;
 
-            libatomic::tag::from_channel(&txn, channel_name, &header, &mut w).map_err(|e| {
+            libatomic::tag::from_channel_with_unhashed(
+                &txn,
+                channel_name,
+                &header,
+                &unhashed,
+                &mut w,
+            )
+            .map_err(|e| {
                 let _ = std::fs::remove_file(&temp_path); // Clean up on error
                 ApiError::internal(format!("Failed to generate tag file: {}", e))
             })?;
@@ -992,6 +4873,7 @@ async fn post_atomic_protocol(
 
         // 10. Update channel tags in database
         info!("Beginning database transaction for tag");
+        let _lock = lock_repository(&repository, "api tag", &LockOptions::no_wait())?;
         let mut txn = repository.pristine.mut_txn_begin().map_err(|e| {
             ApiError::internal(format!("Failed to begin mutable transaction: {}", e))
         })?;
@@ -1086,6 +4968,21 @@ async fn post_atomic_protocol(
                 // Set the change_file_hash to the merkle state
                 // This is what should be used as a dependency when recording changes after the tag
                 tag.change_file_hash = Some(state);
+                if let libatomic::tag::SignatureStatus::Valid {
+                    ref fingerprint, ..
+                } = signature_status
+                {
+                    tag.metadata
+                        .insert("signed_by".to_string(), fingerprint.clone());
+                }
+                libatomic::attribution::tag_summary::attach(
+                    &repository.changes,
+                    &mut txn,
+                    &mut tag,
+                )
+                .map_err(|e| {
+                    ApiError::internal(format!("Failed to store tag attribution: {}", e))
+                })?;
 
                 // Serialize and store consolidating tag metadata
                 let serialized =
@@ -1179,6 +5076,11 @@ async fn post_atomic_protocol(
                     "Successfully committed and uploaded tag for state {} in channel {}",
                     tagup_hash, channel_name
                 );
+                atomic_config::events::publish(atomic_config::events::Event::TagCreated {
+                    repository: repository.path.clone(),
+                    channel: channel_name.to_string(),
+                    state: state.to_base32(),
+                });
             }
             Ok(None) => {
                 return Err(ApiError::internal(format!(
@@ -1200,17 +5102,92 @@ async fn post_atomic_protocol(
             .header("content-type", "application/octet-stream")
             .body(Body::empty())
             .map_err(|e| ApiError::internal(format!("Failed to build response: {}", e)))?)
+    } else if params.contains_key("notes") {
+        // Merge notes pushed by a client, keeping the newest version of
+        // each. Notes live outside the pristine, so there is no need to
+        // open it.
+        let notes: Vec<atomic_repository::notes::Note> = serde_json::from_slice(&body)
+            .map_err(|e| ApiError::invalid_query(format!("Invalid notes: {}", e)))?;
+        if let Some(note) = notes.iter().find(|n| n.hash().is_none()) {
+            return Err(ApiError::invalid_query(format!(
+                "Invalid change hash in note: {:?}",
+                note.change
+            )));
+        }
+        let dot_dir = repo_path.join(libatomic::DOT_DIR);
+        let n = atomic_repository::notes::Notes::new(dot_dir.join(atomic_repository::NOTES_DIR))
+            .merge_all(notes)
+            .map_err(|e| ApiError::internal(format!("Failed to merge notes: {}", e)))?;
+        info!("Merged {} notes", n);
+        Ok(Response::builder()
+            .status(200)
+            .body(Body::empty())
+            .map_err(|e| ApiError::internal(format!("Failed to build response: {}", e)))?)
+    } else if params.contains_key("links") {
+        // Merge links pushed by a client, like notes
+        let links: Vec<atomic_repository::links::Link> = serde_json::from_slice(&body)
+            .map_err(|e| ApiError::internal(format!("Invalid links: {}", e)))?;
+        let repository = Repository::find_root(Some(repo_path))
+            .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+        let n = repository
+            .links()
+            .merge_all(links)
+            .map_err(|e| ApiError::internal(format!("Failed to merge links: {}", e)))?;
+        info!("Merged {} links", n);
+        Ok(Response::builder()
+            .status(200)
+            .body(Body::empty())
+            .map_err(|e| ApiError::internal(format!("Failed to build response: {}", e)))?)
+    } else if params.contains_key("attribution") {
+        // Store the attribution of changes pushed by a client, once they
+        // are applied (see `atomic_remote::attribution`)
+        let received: Vec<atomic_remote::attribution::ChangeAttribution> =
+            serde_json::from_slice(&body)
+                .map_err(|e| ApiError::invalid_query(format!("Invalid attribution: {}", e)))?;
+        let repository = Repository::find_root(Some(repo_path))
+            .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+        let config = atomic_remote::attribution::RemoteAttributionConfig::from_environment();
+        let n = tokio::task::spawn_blocking(move || {
+            atomic_remote::attribution::incoming(
+                &repository.pristine,
+                &repository.changes_dir,
+                received,
+                &config,
+            )
+        })
+        .await
+        .map_err(|e| ApiError::internal(format!("Attribution task failed: {}", e)))?
+        .map_err(|e| ApiError::internal(format!("Failed to store attribution: {}", e)))?;
+        info!("Stored the attribution of {} changes", n);
+        Ok(Response::builder()
+            .status(200)
+            .body(Body::empty())
+            .map_err(|e| ApiError::internal(format!("Failed to build response: {}", e)))?)
     } else {
         Err(ApiError::internal(
-            "Missing 'apply' or 'tagup' parameter for POST request".to_string(),
+            "Missing 'apply', 'tagup', 'notes', 'links' or 'attribution' parameter for POST request"
+                .to_string(),
         ))
     }
 }
 
+/// The comma-separated change hashes of a query parameter
+fn parse_hash_list(hashes: &str) -> ApiResult<Vec<libatomic::Hash>> {
+    hashes
+        .split(',')
+        .map(|h| {
+            libatomic::Hash::from_base32(h.as_bytes())
+                .ok_or_else(|| ApiError::invalid_query(format!("Invalid change hash {}", h)))
+        })
+        .collect()
+}
+
 async fn get_atomic_protocol(
     State(state): State<AppState>,
     Path((tenant_id, portfolio_id, project_id)): Path<(String, String, String)>,
     Query(params): Query<std::collections::HashMap<String, String>>,
+    Query(pairs): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
 ) -> ApiResult<Response<Body>> {
     use std::io::Write;
 
@@ -1221,10 +5198,8 @@ async fn get_atomic_protocol(
 
     // Construct repository path: /mount/tenant_id/portfolio_id/project_id
     let repo_path = state
-        .base_mount_path
-        .join(&tenant_id)
-        .join(&portfolio_id)
-        .join(&project_id);
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
 
     // Validate repository exists
     if !repo_path.exists() {
@@ -1237,9 +5212,15 @@ async fn get_atomic_protocol(
         tenant_id, portfolio_id, project_id, params
     );
 
-    // Open repository
-    let repository = Repository::find_root(Some(repo_path))
-        .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+    // Open repository, from a replica for changelists and their digests
+    let from_replica = params.contains_key("changelist") || params.contains_key("digest");
+    let (repository, source) = if from_replica {
+        open_for_read(&state, &repo_path, &tenant_id, &portfolio_id, &project_id)?
+    } else {
+        let repository = Repository::find_root(Some(repo_path.clone()))
+            .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
+        (repository, ReadSource::Primary)
+    };
 
     let txn = repository
         .pristine
@@ -1267,6 +5248,40 @@ async fn get_atomic_protocol(
                 }
                 Err(e) => return Err(ApiError::internal(format!("Failed to load channel: {}", e))),
             }
+        } else if params.contains_key("digest") {
+            // Handle "digest" command - return the position, state and tag
+            // state of the last change, with an ETag so that clients can
+            // check that nothing changed with a conditional request
+            let channel = load_listed_channel(&txn, Some(channel_name))?;
+            let (body, etag) = match state_digest(&txn, &channel)? {
+                Some((n, m, m2)) => (
+                    format!("{} {} {}\n", n, m.to_base32(), m2.to_base32()),
+                    atomic_remote::http::digest_etag(n, &m, &m2),
+                ),
+                None => ("-\n".to_string(), "\"-\"".to_string()),
+            };
+            let not_modified = headers
+                .get(axum::http::header::IF_NONE_MATCH)
+                .and_then(|v| v.to_str().ok())
+                .map_or(false, |v| v.split(',').any(|t| t.trim() == etag));
+            let mut response = Response::builder()
+                .status(if not_modified {
+                    StatusCode::NOT_MODIFIED
+                } else {
+                    StatusCode::OK
+                })
+                .header("Content-Type", "text/plain")
+                .header("X-Atomic-Protocol", "1.0")
+                .header(axum::http::header::ETAG, &etag)
+                .header(axum::http::header::CACHE_CONTROL, "no-cache")
+                .body(if not_modified {
+                    Body::empty()
+                } else {
+                    Body::from(body)
+                })
+                .map_err(|e| ApiError::internal(format!("Failed to build response: {}", e)))?;
+            response.headers_mut().extend(source.headers());
+            return Ok(response);
         } else if let Some(state_param) = params.get("state") {
             // Handle "state" command - return channel state
             match txn.load_channel(channel_name) {
@@ -1300,8 +5315,24 @@ async fn get_atomic_protocol(
         } else if let Some(changelist_param) = params.get("changelist") {
             // Handle "changelist" command - return list of changes
             let from: u64 = changelist_param.parse().unwrap_or(0);
+            let paths: Vec<String> = pairs
+                .iter()
+                .filter(|(k, _)| k == "path")
+                .map(|(_, v)| v.clone())
+                .collect();
 
             match txn.load_channel(channel_name) {
+                Ok(Some(channel)) if !paths.is_empty() => {
+                    sparse_changelist(
+                        &repository,
+                        &repo_path,
+                        &txn,
+                        &channel,
+                        from,
+                        &paths,
+                        &mut response_data,
+                    )?;
+                }
                 Ok(Some(channel)) => {
                     // Generate changelist response using atomic protocol
                     let mut counter = from;
@@ -1438,6 +5469,100 @@ async fn get_atomic_protocol(
         } else {
             error!("Failed to parse tag hash as Merkle: {}", tag_hash);
         }
+    } else if let Some(hashes) = params.get("bundle") {
+        // Several changes in one bundle (see `atomic_remote::bundle`)
+        let hashes = parse_hash_list(hashes)?;
+        if hashes.len() > atomic_remote::bundle::MAX_CHANGES {
+            return Err(ApiError::invalid_query(format!(
+                "Bundles hold at most {} changes",
+                atomic_remote::bundle::MAX_CHANGES
+            )));
+        }
+        let mut changes = Vec::with_capacity(hashes.len());
+        for hash in hashes.iter() {
+            let mut change_path = repository.changes_dir.clone();
+            libatomic::changestore::filesystem::push_filename(&mut change_path, hash);
+            match std::fs::read(&change_path) {
+                Ok(change) => changes.push(change),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(ApiError::Repository(
+                        crate::error::RepositoryError::ChangeNotFound {
+                            change_id: hash.to_base32(),
+                        },
+                    ))
+                }
+                Err(e) => {
+                    return Err(ApiError::internal(format!(
+                        "Failed to read change file: {}",
+                        e
+                    )))
+                }
+            }
+        }
+        response_data =
+            atomic_remote::bundle::encode(hashes.iter().zip(changes.iter().map(|c| &c[..])))
+                .map_err(|e| ApiError::internal(format!("Failed to bundle changes: {}", e)))?;
+    } else if let Some(channel_name) = params.get("envelopes") {
+        // Hashes of the envelopes of a confidential channel, in the order
+        // they were uploaded
+        let hashes =
+            crate::envelopes::list(&repository.path.join(libatomic::DOT_DIR), channel_name);
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_vec(&hashes).map_err(|e| {
+                ApiError::internal(format!("Failed to serialize envelopes: {}", e))
+            })?))
+            .unwrap());
+    } else if let Some(envelope_hash) = params.get("envelope") {
+        let hash = libatomic::Hash::from_base32(envelope_hash.as_bytes()).ok_or_else(|| {
+            ApiError::invalid_query(format!("Invalid change hash {}", envelope_hash))
+        })?;
+        response_data = crate::envelopes::read(&repository.path.join(libatomic::DOT_DIR), &hash)?;
+    } else if params.contains_key("notes") {
+        // All the notes, including tombstones, so that deletions propagate
+        let notes = repository
+            .notes()
+            .all(None)
+            .map_err(|e| ApiError::internal(format!("Failed to read notes: {}", e)))?;
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_vec(&notes).map_err(|e| {
+                ApiError::internal(format!("Failed to serialize notes: {}", e))
+            })?))
+            .unwrap());
+    } else if params.contains_key("links") {
+        // All the links, including tombstones, so that removals propagate
+        let links = repository
+            .links()
+            .all(None)
+            .map_err(|e| ApiError::internal(format!("Failed to read links: {}", e)))?;
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_vec(&links).map_err(|e| {
+                ApiError::internal(format!("Failed to serialize links: {}", e))
+            })?))
+            .unwrap());
+    } else if let Some(hashes) = params.get("attribution") {
+        // The attribution of changes (see `atomic_remote::attribution`)
+        let hashes = parse_hash_list(hashes)?;
+        if hashes.len() > atomic_remote::bundle::MAX_CHANGES {
+            return Err(ApiError::invalid_query(format!(
+                "Attribution is sent for at most {} changes",
+                atomic_remote::bundle::MAX_CHANGES
+            )));
+        }
+        let attribution = atomic_remote::attribution::outgoing(&repository.pristine, &hashes)
+            .map_err(|e| ApiError::internal(format!("Failed to read attribution: {}", e)))?;
+        return Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_vec(&attribution).map_err(
+                |e| ApiError::internal(format!("Failed to serialize attribution: {}", e)),
+            )?))
+            .unwrap());
     } else if params.contains_key("identities") {
         // Handle "identities" command - return proper JSON structure that atomic CLI expects
         // This prevents the JSON decode error at the end of clone operations
@@ -1452,12 +5577,25 @@ async fn get_atomic_protocol(
             .body(Body::from(identities_response.to_string()))
             .unwrap());
     } else {
-        // Default response for discovery - return JSON to prevent decode errors
-        let discovery_response = serde_json::json!({
+        // Default response for discovery - return JSON to prevent decode errors.
+        // Bundles are only announced to the clients that negotiated them.
+        let version = versions::request_version(&headers).unwrap_or(versions::LEGACY_VERSION);
+        let mut discovery_response = serde_json::json!({
             "status": "ready",
             "protocol": "atomic",
-            "version": "1.0"
+            "version": "1.0",
+            "protocol_versions": versions::SERVED,
+            "validate": true,
         });
+        if version >= versions::BUNDLES_VERSION {
+            discovery_response["bundles"] = serde_json::json!(["zstd"]);
+        }
+        discovery_response[atomic_remote::NOTES_CAPABILITY] = serde_json::json!(true);
+        // Older clients ignore the attribution protocol.
+        if atomic_remote::attribution::RemoteAttributionConfig::from_environment().enabled {
+            discovery_response["attribution"] =
+                serde_json::json!(atomic_remote::attribution::local_protocol());
+        }
 
         return Ok(Response::builder()
             .status(StatusCode::OK)
@@ -1470,16 +5608,146 @@ async fn get_atomic_protocol(
         "Preparing response, data size: {} bytes",
         response_data.len()
     );
-    let response = Response::builder()
+    // Clients resume interrupted downloads of change and tag files from
+    // the bytes they already have.
+    if params.contains_key("change") || params.contains_key("tag") {
+        if let Some(start) = headers
+            .get(axum::http::header::RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(range_start)
+        {
+            let len = response_data.len() as u64;
+            if start >= len {
+                return Ok(Response::builder()
+                    .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                    .header(
+                        axum::http::header::CONTENT_RANGE,
+                        format!("bytes */{}", len),
+                    )
+                    .body(Body::empty())
+                    .unwrap());
+            }
+            return Ok(Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header("Content-Type", "application/octet-stream")
+                .header("X-Atomic-Protocol", "1.0")
+                .header(
+                    axum::http::header::CONTENT_RANGE,
+                    format!("bytes {}-{}/{}", start, len - 1, len),
+                )
+                .body(Body::from(response_data.split_off(start as usize)))
+                .unwrap());
+        }
+    }
+    let mut response = Response::builder()
         .status(StatusCode::OK)
         .header("Content-Type", "application/octet-stream")
         .header("X-Atomic-Protocol", "1.0")
+        .header(axum::http::header::ACCEPT_RANGES, "bytes")
         .body(Body::from(response_data))
         .unwrap();
+    if params.contains_key("changelist") {
+        response.headers_mut().extend(source.headers());
+    }
     info!("Response built successfully, sending to client");
     Ok(response)
 }
 
+/// Write the changelist of `channel` from position `from`, restricted to
+/// the changes touching `paths` (and their dependencies), to `out`, after
+/// the positions of the files under `paths`. Paths missing from the
+/// channel are skipped, as by local remotes.
+fn sparse_changelist<T>(
+    repository: &Repository,
+    root: &std::path::Path,
+    txn: &T,
+    channel: &libatomic::pristine::ChannelRef<T>,
+    from: u64,
+    paths: &[String],
+    out: &mut Vec<u8>,
+) -> ApiResult<()>
+where
+    T: ChannelTxnT + TxnTExt + libatomic::DepsTxnT + libatomic::GraphTxnT,
+{
+    use atomic_remote::protocol::ListLine;
+    use std::io::Write;
+    let mut local = atomic_remote::local::Local {
+        channel: String::new(),
+        root: root.to_path_buf(),
+        changes_dir: repository.changes_dir.clone(),
+        pristine: std::sync::Arc::new(repository.pristine.clone()),
+        name: String::new(),
+    };
+    let mut changes = Vec::new();
+    let positions = local
+        .download_changelist_(
+            |changes: &mut Vec<u8>, n, h, m, tag| {
+                writeln!(changes, "{}", ListLine::Change { n, h, m, tag }.encode())?;
+                Ok(())
+            },
+            &mut changes,
+            from,
+            paths,
+            txn,
+            channel,
+        )
+        .map_err(|e| ApiError::internal(format!("Failed to filter changelist: {}", e)))?;
+    for pos in positions {
+        writeln!(out, "{}", ListLine::Position(pos).encode())
+            .map_err(|e| ApiError::internal(format!("Failed to write position: {}", e)))?;
+    }
+    out.extend_from_slice(&changes);
+    Ok(())
+}
+
+/// Start of a `Range` header of the form `bytes=<start>-`, the only ranges
+/// clients ask for, to resume a download. Other ranges are ignored.
+fn range_start(range: &str) -> Option<u64> {
+    range
+        .strip_prefix("bytes=")?
+        .strip_suffix('-')?
+        .parse()
+        .ok()
+}
+
+/// Position and state of the last change of `channel`, and state of its
+/// last tag (or zero), as in the SSH protocol's `state` command. `None` for
+/// empty channels.
+fn state_digest<T: libatomic::TxnTExt>(
+    txn: &T,
+    channel: &libatomic::pristine::ChannelRef<T>,
+) -> ApiResult<Option<(u64, libatomic::Merkle, libatomic::Merkle)>> {
+    let channel = channel.read();
+    let (n, m) = match txn
+        .reverse_log(&*channel, None)
+        .map_err(|e| ApiError::internal(format!("Failed to read log: {}", e)))?
+        .next()
+    {
+        Some(entry) => {
+            let (n, (_, m)) = entry
+                .map_err(|e| ApiError::internal(format!("Failed to read log entry: {}", e)))?;
+            (n, libatomic::Merkle::from(m))
+        }
+        None => return Ok(None),
+    };
+    let m2 = match txn
+        .rev_iter_tags(txn.tags(&*channel), Some(n))
+        .map_err(|e| ApiError::internal(format!("Failed to iterate tags: {}", e)))?
+        .next()
+    {
+        Some(entry) => {
+            let (_, tag_bytes) = entry
+                .map_err(|e| ApiError::internal(format!("Failed to read tag entry: {}", e)))?;
+            libatomic::pristine::SerializedTag::from_bytes_wrapper(tag_bytes)
+                .to_tag()
+                .map(|tag| tag.state)
+                .unwrap_or_else(|_| libatomic::Merkle::zero())
+        }
+        None => libatomic::Merkle::zero(),
+    };
+    Ok(Some((n, m, m2)))
+}
+
 /// Clone endpoint for repository cloning support
 async fn get_clone(
     State(state): State<AppState>,
@@ -1493,10 +5761,8 @@ async fn get_clone(
 
     // Construct repository path: /mount/tenant_id/portfolio_id/project_id/.atomic
     let repo_path = state
-        .base_mount_path
-        .join(&tenant_id)
-        .join(&portfolio_id)
-        .join(&project_id);
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
 
     // Validate repository exists
     if !repo_path.exists() {
@@ -1557,10 +5823,8 @@ async fn post_push(
 
     // Construct repository path: /mount/tenant_id/portfolio_id/project_id
     let repo_path = state
-        .base_mount_path
-        .join(&tenant_id)
-        .join(&portfolio_id)
-        .join(&project_id);
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
 
     // Validate repository exists
     if !repo_path.exists() {
@@ -1587,6 +5851,7 @@ async fn post_push(
     let repository = Repository::find_root(Some(repo_path))
         .map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))?;
 
+    let _lock = lock_repository(&repository, "api push", &LockOptions::no_wait())?;
     let txn = repository
         .pristine
         .arc_txn_begin()
@@ -1698,10 +5963,8 @@ async fn post_upload_changes(
 
     // Construct repository path: /mount/tenant_id/portfolio_id/project_id/.atomic
     let repo_path = state
-        .base_mount_path
-        .join(&tenant_id)
-        .join(&portfolio_id)
-        .join(&project_id);
+        .jail
+        .repository(&tenant_id, &portfolio_id, &project_id)?;
 
     // Validate repository exists
     if !repo_path.exists() {
@@ -1787,8 +6050,45 @@ async fn post_upload_changes(
     Ok(Json(response))
 }
 
+/// Open a repository for a read-only endpoint, from its replica if there is
+/// a fresh enough one.
+fn open_for_read(
+    state: &AppState,
+    repo_path: &std::path::Path,
+    tenant_id: &str,
+    portfolio_id: &str,
+    project_id: &str,
+) -> ApiResult<(Repository, ReadSource)> {
+    let result = if let Some(ref replicas) = state.replicas {
+        let relative = std::path::Path::new(tenant_id)
+            .join(portfolio_id)
+            .join(project_id);
+        replicas.open_for_read(repo_path, &relative)
+    } else {
+        Repository::find_root(Some(repo_path.to_path_buf())).map(|r| (r, ReadSource::Primary))
+    };
+    result.map_err(|e| ApiError::internal(format!("Failed to access repository: {}", e)))
+}
+
+/// Take the lock of `repository` for `operation`, see
+/// [`atomic_repository::lock`]. Handlers running on the async runtime
+/// don't wait for it, and answer `409` with the holder instead.
+pub(crate) fn lock_repository(
+    repository: &Repository,
+    operation: &str,
+    options: &LockOptions,
+) -> ApiResult<RepositoryLock> {
+    repository
+        .lock()
+        .acquire(operation, options)
+        .map_err(|e| match e.downcast_ref::<Locked>() {
+            Some(locked) => ApiError::locked(locked.to_string()),
+            None => ApiError::internal(format!("Failed to lock repository: {}", e)),
+        })
+}
+
 /// Validate ID following AGENTS.md security patterns
-fn validate_id(id: &str, field_name: &str) -> ApiResult<()> {
+pub(crate) fn validate_id(id: &str, field_name: &str) -> ApiResult<()> {
     if id.is_empty() || id.len() > 50 {
         return Err(ApiError::internal(format!("Invalid {} length", field_name)));
     }
@@ -1815,18 +6115,38 @@ fn validate_id(id: &str, field_name: &str) -> ApiResult<()> {
     Ok(())
 }
 
-/// Read changes from channel log with AI attribution support
+/// Changes read from a channel log
+struct ChangesRead {
+    changes: Vec<ChangeInfo>,
+    /// Whether the log has more changes after these
+    more: bool,
+    /// Whether the change to start after was found
+    found_cursor: bool,
+    /// Number of changes in the channel
+    total: u64,
+}
+
+/// Read changes from channel log with AI attribution support, newest
+/// first, starting after the change `after` if any, else after skipping
+/// `offset` changes. Superseded changes are skipped if `hide_superseded`
+/// is set.
 fn read_changes_from_filesystem(
     repository: &Repository,
-    limit: u64,
-    offset: u64,
+    after: Option<&libatomic::Hash>,
+    offset: usize,
+    limit: usize,
     include_ai_attribution: bool,
-) -> Result<Vec<ChangeInfo>, anyhow::Error> {
-    use libatomic::changestore::ChangeStore;
+    hide_superseded: bool,
+) -> Result<ChangesRead, anyhow::Error> {
     use libatomic::TxnT;
 
     debug!("read_changes_from_filesystem: starting");
-    let mut changes = Vec::new();
+    let mut changes = ChangesRead {
+        changes: Vec::new(),
+        more: false,
+        found_cursor: false,
+        total: 0,
+    };
 
     // Open pristine database like the CLI does
     debug!("read_changes_from_filesystem: opening pristine transaction");
@@ -1854,11 +6174,18 @@ fn read_changes_from_filesystem(
         return Ok(changes);
     };
 
+    // Positions in the log are contiguous, so the position of the last
+    // change gives the number of changes.
+    if let Some(last) = txn.reverse_log(&*channel_ref.read(), None)?.next() {
+        changes.total = last?.0 + 1;
+    }
+
     // Read from channel's reverse log like the CLI does
     debug!("read_changes_from_filesystem: reading reverse log");
     let reverse_log = txn.reverse_log(&*channel_ref.read(), None)?;
     debug!("read_changes_from_filesystem: reverse log obtained successfully");
 
+    let supersessions = supersede_index(repository);
     let mut count = 0;
     let mut current_offset = 0;
 
@@ -1876,19 +6203,28 @@ fn read_changes_from_filesystem(
             }
         };
 
-        // Apply offset
-        if current_offset < offset {
+        // Convert SerializedHash to Hash
+        let hash: libatomic::Hash = h.into();
+        if hide_superseded && supersessions.superseded_by(&hash.to_base32()).is_some() {
+            continue;
+        }
+
+        // Apply cursor or offset
+        if let Some(after) = after {
+            if !changes.found_cursor {
+                changes.found_cursor = hash == *after;
+                continue;
+            }
+        } else if current_offset < offset {
             current_offset += 1;
             continue;
         }
 
         // Apply limit
         if count >= limit {
+            changes.more = true;
             break;
         }
-
-        // Convert SerializedHash to Hash
-        let hash: libatomic::Hash = h.into();
         debug!(
             "read_changes_from_filesystem: processing hash {}",
             hash.to_base32()
@@ -1896,46 +6232,250 @@ fn read_changes_from_filesystem(
 
         // Get change header
         debug!("read_changes_from_filesystem: getting change header");
-        if let Ok(header) = repository.changes.get_header(&hash) {
+        if let Some(change_info) =
+            change_info_summary(repository, &hash, include_ai_attribution, &supersessions)
+        {
             debug!("read_changes_from_filesystem: header retrieved successfully");
-            let hash: libatomic::Hash = h.into();
-
-            // Get AI attribution if requested
-            let ai_attribution = if include_ai_attribution {
-                get_change_ai_attribution(repository, &hash).ok()
-            } else {
-                None
-            };
-
-            // Use the change hash as the ID to ensure global uniqueness across distributed systems
-            // This eliminates ID conflicts when changes are synced between repositories
-            let change_info = ChangeInfo {
-                id: hash.to_base32(),
-                hash: hash.to_base32(),
-                message: if header.message.is_empty() {
-                    "Untitled change".to_string()
-                } else {
-                    header.message
-                },
-                author: extract_author_name(&header.authors),
-                timestamp: header.timestamp.to_rfc3339(),
-                description: header.description.clone(),
-                diff: None, // No diff in list view for performance
-                files_changed: None,
-                ai_attribution,
-            };
-            changes.push(change_info);
+            changes.changes.push(change_info);
             count += 1;
         }
     }
 
     debug!(
         "read_changes_from_filesystem: completed successfully, found {} changes",
-        changes.len()
+        changes.changes.len()
     );
     Ok(changes)
 }
 
+/// Hashes of a page of a channel log
+struct ChangesWindow {
+    hashes: Vec<libatomic::Hash>,
+    /// Whether the log has more changes after these
+    more: bool,
+    /// Whether the change to start after was found
+    found_cursor: bool,
+    /// Number of changes in the channel
+    total: u64,
+}
+
+/// Hashes of at most `limit` changes of the current channel, newest
+/// first, starting as [`read_changes_from_filesystem`] does. Only the log
+/// is read, not the changes.
+fn changes_window(
+    repository: &Repository,
+    after: Option<&libatomic::Hash>,
+    offset: usize,
+    limit: usize,
+    hide_superseded: bool,
+    supersessions: &atomic_repository::supersede::SupersedeIndex,
+) -> Result<ChangesWindow, anyhow::Error> {
+    let mut window = ChangesWindow {
+        hashes: Vec::new(),
+        more: false,
+        found_cursor: false,
+        total: 0,
+    };
+    let txn = repository.pristine.txn_begin()?;
+    let channel_name = txn.current_channel().unwrap_or(libatomic::DEFAULT_CHANNEL);
+    let channel_ref = if let Some(channel) = txn.load_channel(channel_name)? {
+        channel
+    } else {
+        warn!("changes_window: channel not found, returning empty");
+        return Ok(window);
+    };
+    if let Some(last) = txn.reverse_log(&*channel_ref.read(), None)?.next() {
+        window.total = last?.0 + 1;
+    }
+    let mut skipped = 0;
+    for pr in txn.reverse_log(&*channel_ref.read(), None)? {
+        let (_, (h, _)) = pr?;
+        let hash: libatomic::Hash = h.into();
+        if hide_superseded && supersessions.superseded_by(&hash.to_base32()).is_some() {
+            continue;
+        }
+        if let Some(after) = after {
+            if !window.found_cursor {
+                window.found_cursor = hash == *after;
+                continue;
+            }
+        } else if skipped < offset {
+            skipped += 1;
+            continue;
+        }
+        if window.hashes.len() >= limit {
+            window.more = true;
+            break;
+        }
+        window.hashes.push(hash)
+    }
+    Ok(window)
+}
+
+/// The supersession table of `repository`, empty if it can't be read.
+fn supersede_index(repository: &Repository) -> atomic_repository::supersede::SupersedeIndex {
+    repository.supersedes().index().unwrap_or_else(|e| {
+        warn!("Failed to read superseded changes: {}", e);
+        Default::default()
+    })
+}
+
+/// Summary of a change for list views (no diff), or `None` if its header
+/// cannot be read.
+fn change_info_summary(
+    repository: &Repository,
+    hash: &libatomic::Hash,
+    include_ai_attribution: bool,
+    supersessions: &atomic_repository::supersede::SupersedeIndex,
+) -> Option<ChangeInfo> {
+    use libatomic::changestore::ChangeStore;
+    let header = repository.changes.get_header(hash).ok()?;
+
+    // Get AI attribution if requested
+    let ai_attribution = if include_ai_attribution {
+        get_change_ai_attribution(repository, hash).ok()
+    } else {
+        None
+    };
+
+    // Use the change hash as the ID to ensure global uniqueness across distributed systems
+    // This eliminates ID conflicts when changes are synced between repositories
+    Some(ChangeInfo {
+        id: hash.to_base32(),
+        hash: hash.to_base32(),
+        message: if header.message.is_empty() {
+            "Untitled change".to_string()
+        } else {
+            header.message
+        },
+        author: extract_author_name(&header.authors),
+        timestamp: header.timestamp.to_rfc3339(),
+        description: header.description.clone(),
+        diff: None, // No diff in list view for performance
+        files_changed: None,
+        ai_attribution,
+        supersedes: supersessions.supersedes(&hash.to_base32()).to_vec(),
+        superseded_by: supersessions
+            .superseded_by(&hash.to_base32())
+            .map(|h| h.to_string()),
+        links: change_links(repository, hash),
+    })
+}
+
+/// The links of change `hash`, empty if they can't be read.
+fn change_links(
+    repository: &Repository,
+    hash: &libatomic::Hash,
+) -> Vec<atomic_repository::links::Link> {
+    repository.links().get(hash).unwrap_or_else(|e| {
+        warn!("Failed to read links of {}: {}", hash.to_base32(), e);
+        Vec::new()
+    })
+}
+
+/// Read the changes of the current channel grouped in dependency clusters,
+/// paginating over threads rather than individual changes.
+fn read_change_threads(
+    clusters: &ClusterCache,
+    repository: &Repository,
+    limit: usize,
+    offset: usize,
+    include_ai_attribution: bool,
+    hide_superseded: bool,
+) -> Result<Vec<ChangeThread>, anyhow::Error> {
+    use libatomic::TxnT;
+
+    let txn = repository.pristine.txn_begin()?;
+    let channel_name = txn.current_channel().unwrap_or(libatomic::DEFAULT_CHANNEL);
+    let channel_ref = if let Some(channel) = txn.load_channel(channel_name)? {
+        channel
+    } else {
+        warn!("read_change_threads: channel not found, returning empty");
+        return Ok(Vec::new());
+    };
+
+    let clusters = clusters.get_or_compute(repository.path.clone(), &txn, &channel_ref)?;
+    let supersessions = supersede_index(repository);
+    Ok(clusters
+        .iter()
+        .skip(offset)
+        .take(limit)
+        .map(|cluster| ChangeThread {
+            head: cluster.head.to_base32(),
+            changes: cluster
+                .changes
+                .iter()
+                .filter_map(|h| {
+                    change_info_summary(repository, h, include_ai_attribution, &supersessions)
+                })
+                .filter(|c| !(hide_superseded && c.superseded_by.is_some()))
+                .collect(),
+        })
+        .collect())
+}
+
+/// A page of the changes of the current channel in topological levels,
+/// for `order=topological`. Items are [`ChangeLevel`]s, and `limit` counts
+/// changes rather than levels.
+fn read_change_levels(
+    clusters: &ClusterCache,
+    repository: &Repository,
+    list: &ListQuery,
+    include_ai_attribution: bool,
+    hide_superseded: bool,
+) -> ApiResult<Page> {
+    use libatomic::TxnT;
+
+    if list.sort.is_some() {
+        return Err(ApiError::invalid_query(
+            "order cannot be combined with sort".to_string(),
+        ));
+    }
+    let from = match list.after()? {
+        Some(after) => after
+            .split_once('.')
+            .and_then(|(l, i)| Some((l.parse().ok()?, i.parse().ok()?)))
+            .ok_or_else(|| ApiError::invalid_query("Invalid cursor".to_string()))?,
+        None => (0, 0),
+    };
+    fn levels_error(e: impl std::fmt::Display) -> ApiError {
+        ApiError::internal(format!("Failed to order changes: {}", e))
+    }
+    let txn = repository.pristine.txn_begin().map_err(levels_error)?;
+    let channel_name = txn.current_channel().unwrap_or(libatomic::DEFAULT_CHANNEL);
+    let Some(channel_ref) = txn.load_channel(channel_name).map_err(levels_error)? else {
+        warn!("read_change_levels: channel not found, returning empty");
+        return Ok(list.page_of(Vec::new(), None, 0));
+    };
+    let levels = clusters
+        .levels(repository.path.clone(), &txn, &channel_ref)
+        .map_err(levels_error)?;
+
+    let (page, next) = crate::grouping::level_page(&levels, from, list.limit());
+    let supersessions = supersede_index(repository);
+    let items = page
+        .into_iter()
+        .map(|(level, hashes)| {
+            crate::query::to_value(&ChangeLevel {
+                level,
+                changes: hashes
+                    .iter()
+                    .filter_map(|h| {
+                        change_info_summary(repository, h, include_ai_attribution, &supersessions)
+                    })
+                    .filter(|c| !(hide_superseded && c.superseded_by.is_some()))
+                    .collect(),
+            })
+        })
+        .collect::<ApiResult<_>>()?;
+    let total = levels.iter().map(|l| l.len() as u64).sum();
+    Ok(list.page_of(
+        items,
+        next.map(|(level, index)| encode_cursor(&format!("{}.{}", level, index))),
+        total,
+    ))
+}
+
 /// Read specific change from channel log with AI attribution support
 fn read_change_from_filesystem(
     repository: &Repository,
@@ -1991,6 +6531,7 @@ fn read_change_from_filesystem(
                 } else {
                     None
                 };
+                let supersessions = supersede_index(repository);
 
                 let change_info = ChangeInfo {
                     id: change_id.to_string(),
@@ -2006,6 +6547,11 @@ fn read_change_from_filesystem(
                     diff: diff_content,
                     files_changed: files_changed,
                     ai_attribution,
+                    supersedes: supersessions.supersedes(change_id).to_vec(),
+                    superseded_by: supersessions
+                        .superseded_by(change_id)
+                        .map(|h| h.to_string()),
+                    links: change_links(repository, &hash_bytes),
                 };
                 return Ok(Some(change_info));
             }
@@ -2017,7 +6563,7 @@ fn read_change_from_filesystem(
 
 /// Extract author name from authors list following AGENTS.md patterns
 /// This follows the same logic as the CLI log command for consistency
-fn extract_author_name(authors: &[libatomic::change::Author]) -> String {
+pub(crate) fn extract_author_name(authors: &[libatomic::change::Author]) -> String {
     if let Some(author) = authors.first() {
         // First try to get the key and look up the identity (like CLI does)
         if let Some(key) = author.0.get("key") {
@@ -2221,6 +6767,15 @@ mod tests {
         assert!(validate_id("", "test").is_err());
     }
 
+    #[test]
+    fn test_range_start() {
+        assert_eq!(range_start("bytes=0-"), Some(0));
+        assert_eq!(range_start("bytes=1024-"), Some(1024));
+        assert_eq!(range_start("bytes=0-99"), None);
+        assert_eq!(range_start("bytes=-100"), None);
+        assert_eq!(range_start("items=5-"), None);
+    }
+
     #[test]
     fn test_change_info_uses_hash_as_id() {
         let hash = "MNYNGT2VGEQZX4QA43FWBDVYQY7CGXN4J2CGE5FDFIHOWQFKFIJQC";
@@ -2234,6 +6789,9 @@ mod tests {
             diff: None,
             files_changed: None,
             ai_attribution: None,
+            supersedes: Vec::new(),
+            superseded_by: None,
+            links: Vec::new(),
         };
 
         assert_eq!(change_info.id, change_info.hash);
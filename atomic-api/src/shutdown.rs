@@ -0,0 +1,313 @@
+//! Graceful shutdown
+//!
+//! Stopping the server while an apply runs used to kill it wherever it
+//! was, including between its output to the working copy and its commit.
+//! On Ctrl-C or `SIGTERM`, both servers now stop in steps:
+//!
+//! 1. the server starts draining: requests that write are rejected with a
+//!    `503`, reads are still served, and WebSocket sessions are closed
+//!    with a "going away" frame;
+//! 2. the applies already queued or running go on, for up to the
+//!    deadline (`ATOMIC_API_SHUTDOWN_DEADLINE`, 30 seconds by default);
+//! 3. past the deadline, the applies still waiting are cancelled, and the
+//!    running ones are aborted before their output and commit: their
+//!    transactions are dropped, which rolls them back.
+//!
+//! The servers stop once the apply queue is empty.
+
+use crate::apply_queue::ApplyQueue;
+use crate::{ApiError, ApiResult};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Default time given to queued and running applies to complete
+const DEFAULT_DEADLINE: Duration = Duration::from_secs(30);
+
+/// Configuration of the shutdown
+#[derive(Debug, Clone)]
+pub struct ShutdownConfig {
+    /// Time given to queued and running applies once the shutdown starts
+    pub deadline: Duration,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            deadline: DEFAULT_DEADLINE,
+        }
+    }
+}
+
+impl ShutdownConfig {
+    /// Read the deadline, in seconds, from `ATOMIC_API_SHUTDOWN_DEADLINE`
+    pub fn from_env() -> Self {
+        ShutdownConfig {
+            deadline: std::env::var("ATOMIC_API_SHUTDOWN_DEADLINE")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .map_or(DEFAULT_DEADLINE, Duration::from_secs),
+        }
+    }
+}
+
+/// Steps of the shutdown, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Running,
+    /// Writes are rejected, and applies drain
+    Draining,
+    /// The deadline passed, running applies must not commit
+    Aborting,
+}
+
+/// What happened to the applies during a shutdown
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DrainReport {
+    /// Whether all the applies completed before the deadline
+    pub drained: bool,
+    /// Applies cancelled before they started
+    pub cancelled: usize,
+    /// Applies aborted while running, and rolled back
+    pub aborted: usize,
+}
+
+/// The shutdown controller, shared by both servers
+#[derive(Clone)]
+pub struct Shutdown {
+    config: Arc<ShutdownConfig>,
+    phase: Arc<watch::Sender<Phase>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new(ShutdownConfig::default())
+    }
+}
+
+impl Shutdown {
+    pub fn new(config: ShutdownConfig) -> Self {
+        Shutdown {
+            config: Arc::new(config),
+            phase: Arc::new(watch::Sender::new(Phase::Running)),
+        }
+    }
+
+    pub fn config(&self) -> &ShutdownConfig {
+        &self.config
+    }
+
+    pub fn phase(&self) -> Phase {
+        *self.phase.borrow()
+    }
+
+    /// Move to `phase`, unless the shutdown is already past it
+    fn advance(&self, phase: Phase) {
+        self.phase.send_if_modified(|current| {
+            if *current < phase {
+                *current = phase;
+                true
+            } else {
+                false
+            }
+        });
+    }
+
+    /// Start the shutdown: reject writes and drain the applies
+    pub fn begin(&self) {
+        if self.phase() == Phase::Running {
+            info!("Shutting down, draining applies");
+        }
+        self.advance(Phase::Draining)
+    }
+
+    /// Abort the running applies
+    pub fn abort(&self) {
+        self.advance(Phase::Aborting)
+    }
+
+    /// Wait until the shutdown reaches `phase`
+    async fn reached(&self, phase: Phase) {
+        let mut receiver = self.phase.subscribe();
+        // The sender lives as long as `self`, so this can't fail.
+        let _ = receiver.wait_for(|p| *p >= phase).await;
+    }
+
+    /// Wait until the shutdown starts
+    pub async fn draining(&self) {
+        self.reached(Phase::Draining).await
+    }
+
+    /// Fail with [`ApiError::ShuttingDown`] once the shutdown started
+    pub fn check_write(&self) -> ApiResult<()> {
+        if self.phase() == Phase::Running {
+            Ok(())
+        } else {
+            Err(ApiError::shutting_down(
+                "Server shutting down, writes are rejected",
+            ))
+        }
+    }
+
+    /// Fail with [`ApiError::ShuttingDown`] if the running applies were
+    /// aborted. Applies call this before writing anything outside of
+    /// their transaction, and before committing it.
+    pub fn check_commit(&self) -> ApiResult<()> {
+        if self.phase() == Phase::Aborting {
+            Err(ApiError::shutting_down(
+                "Apply aborted by the server shutdown, rolled back",
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Wait for the shutdown to start, then drain `applies` until the
+    /// deadline, and cancel or abort what is left after it
+    pub async fn drain(&self, applies: &ApplyQueue) -> DrainReport {
+        self.draining().await;
+        applies.close();
+        if tokio::time::timeout(self.config.deadline, applies.idle())
+            .await
+            .is_ok()
+        {
+            info!("All applies completed");
+            return DrainReport {
+                drained: true,
+                ..DrainReport::default()
+            };
+        }
+        self.abort();
+        let cancelled = applies
+            .cancel_pending(|| ApiError::shutting_down("Apply cancelled by the server shutdown"));
+        let aborted = applies.metrics().running;
+        warn!(
+            "Shutdown deadline passed: {} applies cancelled, {} aborted",
+            cancelled, aborted
+        );
+        // Aborted applies stop at their next check, without committing.
+        applies.idle().await;
+        DrainReport {
+            drained: false,
+            cancelled,
+            aborted,
+        }
+    }
+}
+
+/// Wait for Ctrl-C, or `SIGTERM` on unix
+pub async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apply_queue::{ApplyQueueConfig, OperationStatus};
+    use std::path::PathBuf;
+
+    fn shutdown(deadline: Duration) -> Shutdown {
+        Shutdown::new(ShutdownConfig { deadline })
+    }
+
+    #[tokio::test]
+    async fn test_drain_before_deadline() {
+        let shutdown = shutdown(Duration::from_secs(10));
+        let queue = ApplyQueue::new(ApplyQueueConfig::default());
+        queue.start();
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let running = queue
+            .submit(PathBuf::from("/repo"), move || {
+                rx.recv().unwrap();
+                Ok(())
+            })
+            .unwrap();
+
+        assert!(shutdown.check_write().is_ok());
+        shutdown.begin();
+        assert!(shutdown.check_write().is_err());
+        assert!(shutdown.check_commit().is_ok());
+        let drain = tokio::spawn({
+            let (shutdown, queue) = (shutdown.clone(), queue.clone());
+            async move { shutdown.drain(&queue).await }
+        });
+        // New applies are refused while draining.
+        while queue.submit(PathBuf::from("/other"), || Ok(())).is_ok() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        tx.send(()).unwrap();
+        running.wait().await.unwrap();
+        let report = drain.await.unwrap();
+        assert!(report.drained);
+        assert_eq!(shutdown.phase(), Phase::Draining);
+    }
+
+    #[tokio::test]
+    async fn test_abort_after_deadline() {
+        let shutdown = shutdown(Duration::from_millis(20));
+        let queue = ApplyQueue::new(ApplyQueueConfig {
+            workers: 1,
+            ..ApplyQueueConfig::default()
+        });
+        queue.start();
+        // A running apply that only finishes once aborted, and one
+        // waiting behind it.
+        let running = queue
+            .submit(PathBuf::from("/repo"), {
+                let shutdown = shutdown.clone();
+                move || loop {
+                    shutdown.check_commit()?;
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            })
+            .unwrap();
+        while queue.status(&running.id()) != Some(OperationStatus::Running) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let waiting = queue.submit(PathBuf::from("/repo"), || Ok(())).unwrap();
+
+        shutdown.begin();
+        let report = shutdown.drain(&queue).await;
+        assert_eq!(
+            report,
+            DrainReport {
+                drained: false,
+                cancelled: 1,
+                aborted: 1,
+            }
+        );
+        assert!(matches!(
+            running.wait().await,
+            Err(ApiError::ShuttingDown { .. })
+        ));
+        assert!(matches!(
+            waiting.wait().await,
+            Err(ApiError::ShuttingDown { .. })
+        ));
+    }
+}
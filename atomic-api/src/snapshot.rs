@@ -0,0 +1,211 @@
+//! States of a channel at a point in time
+//!
+//! `GET .../code/state?at=` answers "what did the channel look like on
+//! March 1st": it resolves a time (RFC 3339, or seconds since the epoch)
+//! to the state of the channel after its last change recorded at or
+//! before that time. Endpoints taking a state, such as sandbox creation,
+//! take an `at` time too and resolve it the same way.
+//!
+//! The resolution is a binary search on the timestamps of the changes of
+//! the channel log, so it reads a logarithmic number of change headers.
+//! It assumes that the log is in timestamp order, which holds for
+//! changes recorded on the channel but not always for pulled ones: the
+//! result is then a state where the changes are at or before `at` in log
+//! order, not necessarily all of them.
+
+use crate::{ApiError, ApiResult};
+use chrono::{DateTime, TimeZone, Utc};
+use libatomic::changestore::ChangeStore;
+use libatomic::pristine::{Base32, Hash, Merkle};
+use libatomic::TxnTExt;
+use serde::{Deserialize, Serialize};
+
+/// Query parameters of `GET .../code/state`
+#[derive(Debug, Deserialize)]
+pub struct SnapshotQuery {
+    /// Time to resolve
+    pub at: String,
+    /// Channel, the current channel by default
+    pub channel: Option<String>,
+}
+
+/// State of a channel at a time
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Snapshot {
+    /// The time that was resolved, in RFC 3339
+    pub at: String,
+    /// State of the channel, the empty state if it had no changes yet
+    pub state: String,
+    /// Position of the last change in the channel log
+    pub position: Option<u64>,
+    /// Last change, and its time
+    pub change: Option<String>,
+    pub timestamp: Option<String>,
+}
+
+/// Parse a time given as RFC 3339, or as seconds since the epoch
+pub fn parse_time(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Some(t.with_timezone(&Utc));
+    }
+    s.parse::<i64>()
+        .ok()
+        .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+}
+
+/// Parse the `at` parameter of a request
+pub fn parse_at(s: &str) -> ApiResult<DateTime<Utc>> {
+    parse_time(s).ok_or_else(|| {
+        ApiError::invalid_query(format!(
+            "Invalid time {:?}: expected an RFC 3339 time or a timestamp",
+            s
+        ))
+    })
+}
+
+fn snapshot_error(e: impl std::fmt::Display) -> ApiError {
+    ApiError::internal(format!("Failed to resolve the state: {}", e))
+}
+
+/// Last position at or before `at`, among positions up to `last`.
+/// `entry(p)` is the first entry at a position `>= p` with a readable
+/// timestamp, as its position and time.
+fn search<E>(
+    last: u64,
+    at: DateTime<Utc>,
+    mut entry: impl FnMut(u64) -> Result<Option<(u64, DateTime<Utc>)>, E>,
+) -> Result<Option<u64>, E> {
+    // Entries before `lo` are at or before `at`, entries from `hi` on are
+    // after it.
+    let mut lo = 0;
+    let mut hi = last + 1;
+    let mut found = None;
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match entry(mid)? {
+            Some((p, t)) if p < hi && t <= at => {
+                found = Some(p);
+                lo = p + 1
+            }
+            _ => hi = mid,
+        }
+    }
+    Ok(found)
+}
+
+/// Resolve `at` to the last change of `channel` at or before it, as its
+/// position, hash, time and the state of the channel after it
+pub fn state_at<T: TxnTExt, C: ChangeStore>(
+    txn: &T,
+    channel: &T::Channel,
+    changes: &C,
+    at: DateTime<Utc>,
+) -> ApiResult<Option<(u64, Hash, DateTime<Utc>, Merkle)>> {
+    let last = match txn
+        .reverse_log(channel, None)
+        .map_err(snapshot_error)?
+        .next()
+    {
+        Some(entry) => entry.map_err(snapshot_error)?.0,
+        None => return Ok(None),
+    };
+    let entry = |from: u64| -> ApiResult<Option<(u64, Hash, DateTime<Utc>, Merkle)>> {
+        for entry in txn.log(channel, from).map_err(snapshot_error)? {
+            let (p, (h, m)) = entry.map_err(snapshot_error)?;
+            let h: Hash = h.into();
+            // Tags and missing change files have no readable time.
+            if let Ok(header) = changes.get_header(&h) {
+                return Ok(Some((p, h, header.timestamp, m.into())));
+            }
+        }
+        Ok(None)
+    };
+    let position = search::<ApiError>(last, at, |p| Ok(entry(p)?.map(|(p, _, t, _)| (p, t))))?;
+    match position {
+        Some(p) => entry(p),
+        None => Ok(None),
+    }
+}
+
+/// The snapshot of `channel` at `at`
+pub fn snapshot<T: TxnTExt, C: ChangeStore>(
+    txn: &T,
+    channel: &T::Channel,
+    changes: &C,
+    at: DateTime<Utc>,
+) -> ApiResult<Snapshot> {
+    Ok(match state_at(txn, channel, changes, at)? {
+        Some((position, hash, timestamp, state)) => Snapshot {
+            at: at.to_rfc3339(),
+            state: state.to_base32(),
+            position: Some(position),
+            change: Some(hash.to_base32()),
+            timestamp: Some(timestamp.to_rfc3339()),
+        },
+        None => Snapshot {
+            at: at.to_rfc3339(),
+            state: Merkle::zero().to_base32(),
+            position: None,
+            change: None,
+            timestamp: None,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn t(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(secs, 0).unwrap()
+    }
+
+    /// `search` over a log given as (position, time), `None` for the
+    /// entries without a readable time
+    fn search_log(log: &[(u64, Option<i64>)], at: i64) -> Option<u64> {
+        let last = log.last().unwrap().0;
+        search::<()>(last, t(at), |from| {
+            Ok(log
+                .iter()
+                .filter(|(p, _)| *p >= from)
+                .find_map(|(p, time)| time.map(|time| (*p, t(time)))))
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_search() {
+        let log: Vec<_> = (0..10).map(|p| (p, Some(100 + 10 * p as i64))).collect();
+        assert_eq!(search_log(&log, 99), None);
+        assert_eq!(search_log(&log, 100), Some(0));
+        assert_eq!(search_log(&log, 155), Some(5));
+        assert_eq!(search_log(&log, 160), Some(6));
+        assert_eq!(search_log(&log, 1000), Some(9));
+    }
+
+    #[test]
+    fn test_search_gaps_and_tags() {
+        // Unrecorded changes leave gaps, and tags have no time.
+        let log = [
+            (0, Some(100)),
+            (1, None),
+            (3, Some(120)),
+            (4, None),
+            (5, None),
+            (8, Some(150)),
+            (9, None),
+        ];
+        assert_eq!(search_log(&log, 99), None);
+        assert_eq!(search_log(&log, 110), Some(0));
+        assert_eq!(search_log(&log, 120), Some(3));
+        assert_eq!(search_log(&log, 149), Some(3));
+        assert_eq!(search_log(&log, 200), Some(8));
+    }
+
+    #[test]
+    fn test_parse_at() {
+        assert_eq!(parse_at("2024-03-01T00:00:00Z").unwrap(), t(1_709_251_200));
+        assert_eq!(parse_at("1709251200").unwrap(), t(1_709_251_200));
+        assert!(parse_at("March 1st").is_err());
+    }
+}
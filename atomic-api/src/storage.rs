@@ -0,0 +1,817 @@
+//! Storage quotas and archival of cold repositories
+//!
+//! The server keeps the storage used by each repository (its `.atomic`
+//! directory, change files and pristine included) in
+//! `.atomic/storage.json`, measured again after every successful write to
+//! the repository, along with the times of the last write and access.
+//! With a quota configured, writes to a repository that uses more than
+//! its quota are rejected with a `507`. The API calls served for each
+//! repository are counted in memory, and added to the usage file when it
+//! is next saved.
+//!
+//! Tenant admins can see the usage of all their repositories, and the
+//! headroom left under the quota, with `GET /tenant/:tenant_id/usage`
+//! (see [`TenantUsage`]).
+//!
+//! With an archive store configured, a reaper archives repositories that
+//! haven't been accessed for a while: their `.atomic` directory is copied
+//! to the store, and replaced by an `.atomic-archived` marker next to it.
+//! The next request to the repository rehydrates it from the store before
+//! it is served, transparently to the client. Archival takes the
+//! repository lock, so repositories with a running apply or CLI command
+//! are skipped until the next round.
+//!
+//! Stores implement [`ArchiveStore`], so that object storage can be
+//! plugged in with [`crate::ApiServer::with_archive_store`]. The default
+//! store, [`DirectoryStore`], copies the repositories to a directory,
+//! such as a mounted bucket, laid out as
+//! `<archive root>/<tenant_id>/<portfolio_id>/<project_id>`.
+
+use crate::{ApiError, ApiResult};
+use atomic_repository::lock::{Lock, LockOptions, Locked};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Usage of a repository, in its `.atomic` directory
+pub const USAGE_FILE: &str = "storage.json";
+/// Marker of an archived repository, next to where its `.atomic` was
+pub const ARCHIVED_FILE: &str = ".atomic-archived";
+
+const MB: u64 = 1024 * 1024;
+/// Default interval between two rounds of the reaper
+const DEFAULT_REAP_INTERVAL: Duration = Duration::from_secs(3600);
+/// Accesses closer than this to the last recorded one aren't recorded,
+/// to avoid rewriting the usage file on every read
+const ACCESS_RESOLUTION: Duration = Duration::from_secs(60);
+
+/// Configuration of the quotas and archival
+#[derive(Debug, Clone)]
+pub struct StorageConfig {
+    /// Bytes a repository can use before writes to it are rejected, if
+    /// any
+    pub quota: Option<u64>,
+    /// Directory of the default archive store, if archival is enabled
+    pub archive_dir: Option<PathBuf>,
+    /// Inactivity after which a repository is archived, if archival is
+    /// enabled
+    pub archive_after: Option<Duration>,
+    /// Interval between two rounds of the reaper
+    pub reap_interval: Duration,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig {
+            quota: None,
+            archive_dir: None,
+            archive_after: None,
+            reap_interval: DEFAULT_REAP_INTERVAL,
+        }
+    }
+}
+
+impl StorageConfig {
+    /// Read the configuration from `ATOMIC_API_STORAGE_QUOTA_MB`,
+    /// `ATOMIC_API_ARCHIVE_DIR`, `ATOMIC_API_ARCHIVE_AFTER` and
+    /// `ATOMIC_API_ARCHIVE_REAP` (both in seconds). There is no quota if
+    /// the quota isn't set, and repositories are archived only if both
+    /// the inactivity and a store are configured.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(var: &str) -> Option<T> {
+            std::env::var(var).ok().and_then(|s| s.parse().ok())
+        }
+        StorageConfig {
+            quota: var::<u64>("ATOMIC_API_STORAGE_QUOTA_MB").map(|m| m * MB),
+            archive_dir: std::env::var_os("ATOMIC_API_ARCHIVE_DIR").map(PathBuf::from),
+            archive_after: var("ATOMIC_API_ARCHIVE_AFTER").map(Duration::from_secs),
+            reap_interval: var("ATOMIC_API_ARCHIVE_REAP")
+                .filter(|&s: &u64| s > 0)
+                .map_or(DEFAULT_REAP_INTERVAL, Duration::from_secs),
+        }
+    }
+}
+
+/// Where archived repositories are stored. `key` is the
+/// `tenant_id/portfolio_id/project_id` path of the repository.
+pub trait ArchiveStore: Send + Sync {
+    /// Store a copy of the directory `dir` under `key`, replacing any
+    /// previous archive of `key`
+    fn put(&self, key: &str, dir: &Path) -> std::io::Result<()>;
+    /// Copy the archive of `key` to the directory `dir`, which doesn't
+    /// exist yet
+    fn get(&self, key: &str, dir: &Path) -> std::io::Result<()>;
+    /// Delete the archive of `key`
+    fn delete(&self, key: &str) -> std::io::Result<()>;
+}
+
+/// An archive store in a local directory, e.g. a mounted bucket
+#[derive(Debug, Clone)]
+pub struct DirectoryStore {
+    root: PathBuf,
+}
+
+impl DirectoryStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        DirectoryStore { root: root.into() }
+    }
+}
+
+impl ArchiveStore for DirectoryStore {
+    fn put(&self, key: &str, dir: &Path) -> std::io::Result<()> {
+        let path = self.root.join(key);
+        let parent = path.parent().unwrap_or(&self.root);
+        std::fs::create_dir_all(parent)?;
+        // Copy to a temporary directory first, so that an interrupted
+        // copy never replaces a complete archive.
+        let tmp = tempfile::tempdir_in(parent)?;
+        copy_dir(dir, &tmp.path().join("archive"))?;
+        if path.exists() {
+            std::fs::remove_dir_all(&path)?;
+        }
+        std::fs::rename(tmp.path().join("archive"), &path)
+    }
+
+    fn get(&self, key: &str, dir: &Path) -> std::io::Result<()> {
+        copy_dir(&self.root.join(key), dir)
+    }
+
+    fn delete(&self, key: &str) -> std::io::Result<()> {
+        std::fs::remove_dir_all(self.root.join(key))
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to)?;
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else if file_type.is_file() {
+            std::fs::copy(entry.path(), &target)?;
+        }
+        // Sockets, such as the attribution hook's, are recreated by
+        // their owner.
+    }
+    Ok(())
+}
+
+/// API calls served for a repository
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiCalls {
+    pub reads: u64,
+    pub writes: u64,
+}
+
+impl std::ops::AddAssign for ApiCalls {
+    fn add_assign(&mut self, other: ApiCalls) {
+        self.reads += other.reads;
+        self.writes += other.writes;
+    }
+}
+
+/// Storage used by a repository, its last write and access, and the API
+/// calls it served
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StorageUsage {
+    /// Bytes in the `.atomic` directory
+    pub bytes: u64,
+    /// Number of change files
+    pub change_files: u64,
+    pub last_write: Option<DateTime<Utc>>,
+    pub last_access: Option<DateTime<Utc>>,
+    /// Missing from usage files written before calls were counted
+    #[serde(default)]
+    pub calls: ApiCalls,
+}
+
+impl StorageUsage {
+    fn path(repo_path: &Path) -> PathBuf {
+        repo_path.join(libatomic::DOT_DIR).join(USAGE_FILE)
+    }
+
+    /// The recorded usage of the repository at `repo_path`, or the
+    /// default usage if none was recorded yet
+    pub fn load(repo_path: &Path) -> Self {
+        std::fs::read(Self::path(repo_path))
+            .ok()
+            .and_then(|b| serde_json::from_slice(&b).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self, repo_path: &Path) -> std::io::Result<()> {
+        let path = Self::path(repo_path);
+        let mut tmp = tempfile::NamedTempFile::new_in(path.parent().unwrap())?;
+        serde_json::to_writer(&mut tmp, self)?;
+        tmp.persist(&path)?;
+        Ok(())
+    }
+
+    /// Measure the bytes and change files of the repository at
+    /// `repo_path`
+    pub fn measure(&mut self, repo_path: &Path) -> std::io::Result<()> {
+        fn walk(dir: &Path, in_changes: bool, usage: &mut StorageUsage) -> std::io::Result<()> {
+            for entry in std::fs::read_dir(dir)? {
+                let entry = entry?;
+                let meta = entry.metadata()?;
+                if meta.is_dir() {
+                    let changes = in_changes || entry.file_name() == atomic_repository::CHANGES_DIR;
+                    walk(&entry.path(), changes, usage)?;
+                } else if !in_changes && entry.file_name() == crate::backup::BACKUP_FILE {
+                    // Made by the server from the rest, not counted.
+                } else {
+                    usage.bytes += meta.len();
+                    if in_changes && entry.path().extension().is_some_and(|e| e == "change") {
+                        usage.change_files += 1
+                    }
+                }
+            }
+            Ok(())
+        }
+        self.bytes = 0;
+        self.change_files = 0;
+        walk(&repo_path.join(libatomic::DOT_DIR), false, self)
+    }
+
+    /// Last write or access, whichever is the latest
+    pub fn last_activity(&self) -> Option<DateTime<Utc>> {
+        self.last_write.max(self.last_access)
+    }
+}
+
+/// Marker of an archived repository
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchivedRepository {
+    /// Key of the archive in the store
+    pub key: String,
+    pub archived_at: DateTime<Utc>,
+    /// Usage of the repository when it was archived
+    pub usage: StorageUsage,
+}
+
+impl ArchivedRepository {
+    /// The marker of the repository at `repo_path`, if it is archived
+    pub fn load(repo_path: &Path) -> Option<Self> {
+        let bytes = std::fs::read(repo_path.join(ARCHIVED_FILE)).ok()?;
+        serde_json::from_slice(&bytes).ok()
+    }
+}
+
+/// Usage and quota of a repository, served by `GET .../code/storage`
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageReport {
+    #[serde(flatten)]
+    pub usage: StorageUsage,
+    pub quota: Option<u64>,
+}
+
+/// Usage of a repository in a [`TenantUsage`]
+#[derive(Debug, Clone, Serialize)]
+pub struct RepositoryUsage {
+    pub portfolio_id: String,
+    pub project_id: String,
+    /// Whether the repository is archived, in which case its usage is
+    /// the one it had when it was archived
+    pub archived: bool,
+    #[serde(flatten)]
+    pub usage: StorageUsage,
+    /// Bytes left under the quota, if there is one
+    pub headroom: Option<u64>,
+}
+
+/// Totals of a [`TenantUsage`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct UsageTotals {
+    pub repositories: u64,
+    pub changes: u64,
+    pub bytes: u64,
+    pub calls: ApiCalls,
+    /// Sum of the headrooms of the repositories, if there is a quota
+    pub headroom: Option<u64>,
+    /// Repositories at or over the quota, which reject writes
+    pub over_quota: u64,
+}
+
+/// Usage of the repositories of a tenant, served by
+/// `GET /tenant/:tenant_id/usage`
+#[derive(Debug, Clone, Serialize)]
+pub struct TenantUsage {
+    pub tenant_id: String,
+    /// Quota of each repository, if any
+    pub quota: Option<u64>,
+    pub totals: UsageTotals,
+    pub repositories: Vec<RepositoryUsage>,
+}
+
+/// The storage configuration and archive store
+#[derive(Clone, Default)]
+pub struct Storage {
+    config: Arc<StorageConfig>,
+    store: Option<Arc<dyn ArchiveStore>>,
+    /// Serialises the archival and rehydration of each repository
+    guards: Arc<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>>,
+    /// Calls not yet added to the usage file of each repository. Their
+    /// lock is held while the file is updated, so that concurrent updates
+    /// don't lose counts.
+    calls: Arc<Mutex<HashMap<PathBuf, Arc<Mutex<ApiCalls>>>>>,
+}
+
+impl Storage {
+    /// Quotas and archival with this configuration, archiving to a
+    /// [`DirectoryStore`] if `archive_dir` is set
+    pub fn new(config: StorageConfig) -> Self {
+        let store = config
+            .archive_dir
+            .as_ref()
+            .map(|dir| Arc::new(DirectoryStore::new(dir)) as Arc<dyn ArchiveStore>);
+        Storage {
+            config: Arc::new(config),
+            store,
+            guards: Arc::default(),
+            calls: Arc::default(),
+        }
+    }
+
+    /// Archive to `store` instead of the configured directory
+    pub fn with_store(mut self, store: Arc<dyn ArchiveStore>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    pub fn config(&self) -> &StorageConfig {
+        &self.config
+    }
+
+    /// Whether the reaper has anything to do
+    pub fn archives(&self) -> bool {
+        self.store.is_some() && self.config.archive_after.is_some()
+    }
+
+    fn guard(&self, repo_path: &Path) -> Arc<Mutex<()>> {
+        self.guards
+            .lock()
+            .unwrap()
+            .entry(repo_path.to_path_buf())
+            .or_default()
+            .clone()
+    }
+
+    fn pending_calls(&self, repo_path: &Path) -> Arc<Mutex<ApiCalls>> {
+        self.calls
+            .lock()
+            .unwrap()
+            .entry(repo_path.to_path_buf())
+            .or_default()
+            .clone()
+    }
+
+    /// Count an API call served for the repository at `repo_path`, added
+    /// to its usage file by the next [`Storage::record_access`] or
+    /// [`Storage::record_write`] that saves it
+    pub fn count_call(&self, repo_path: &Path, write: bool) {
+        let pending = self.pending_calls(repo_path);
+        let mut pending = pending.lock().unwrap();
+        if write {
+            pending.writes += 1
+        } else {
+            pending.reads += 1
+        }
+    }
+
+    /// Reject writes to the repository at `repo_path` if it uses more
+    /// than its quota
+    pub fn check_write(&self, repo_path: &Path) -> ApiResult<()> {
+        let quota = match self.config.quota {
+            Some(quota) => quota,
+            None => return Ok(()),
+        };
+        let usage = StorageUsage::load(repo_path);
+        if usage.bytes >= quota {
+            return Err(ApiError::quota_exceeded(format!(
+                "Repository uses {} bytes, over its quota of {} bytes",
+                usage.bytes, quota
+            )));
+        }
+        Ok(())
+    }
+
+    /// Record an access to the repository at `repo_path`
+    pub fn record_access(&self, repo_path: &Path) -> std::io::Result<()> {
+        let pending = self.pending_calls(repo_path);
+        let mut pending = pending.lock().unwrap();
+        let mut usage = StorageUsage::load(repo_path);
+        let now = Utc::now();
+        if usage
+            .last_access
+            .is_some_and(|last| (now - last).to_std().unwrap_or_default() < ACCESS_RESOLUTION)
+        {
+            return Ok(());
+        }
+        usage.last_access = Some(now);
+        usage.calls += *pending;
+        usage.save(repo_path)?;
+        *pending = ApiCalls::default();
+        Ok(())
+    }
+
+    /// Measure the repository at `repo_path` again after a write
+    pub fn record_write(&self, repo_path: &Path) -> std::io::Result<StorageUsage> {
+        let pending = self.pending_calls(repo_path);
+        let mut pending = pending.lock().unwrap();
+        let mut usage = StorageUsage::load(repo_path);
+        usage.measure(repo_path)?;
+        let now = Utc::now();
+        usage.last_write = Some(now);
+        usage.last_access = Some(now);
+        usage.calls += *pending;
+        usage.save(repo_path)?;
+        *pending = ApiCalls::default();
+        Ok(usage)
+    }
+
+    /// Usage and quota of the repository at `repo_path`, measured now if
+    /// it was never written to since accounting was enabled
+    pub fn report(&self, repo_path: &Path) -> std::io::Result<StorageReport> {
+        let mut usage = StorageUsage::load(repo_path);
+        if usage.last_write.is_none() {
+            usage.measure(repo_path)?;
+        }
+        usage.calls += *self.pending_calls(repo_path).lock().unwrap();
+        Ok(StorageReport {
+            usage,
+            quota: self.config.quota,
+        })
+    }
+
+    /// Usage of the repositories of `tenant_id`, whose directory is
+    /// `tenant_root`, without rehydrating the archived ones. Symbolic
+    /// links under `tenant_root` are skipped, as they may lead out of it.
+    pub fn tenant_usage(
+        &self,
+        tenant_root: &Path,
+        tenant_id: &str,
+    ) -> std::io::Result<TenantUsage> {
+        fn dirs(path: &Path) -> std::io::Result<Vec<String>> {
+            let mut dirs = Vec::new();
+            let entries = match std::fs::read_dir(path) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(dirs),
+                Err(e) => return Err(e),
+            };
+            for entry in entries {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    if let Some(name) = entry.file_name().to_str() {
+                        dirs.push(name.to_string())
+                    }
+                }
+            }
+            dirs.sort();
+            Ok(dirs)
+        }
+
+        let quota = self.config.quota;
+        let mut totals = UsageTotals {
+            headroom: quota.map(|_| 0),
+            ..UsageTotals::default()
+        };
+        let mut repositories = Vec::new();
+        for portfolio_id in dirs(tenant_root)? {
+            for project_id in dirs(&tenant_root.join(&portfolio_id))? {
+                let repo_path = tenant_root.join(&portfolio_id).join(&project_id);
+                let (usage, archived) = if repo_path.join(libatomic::DOT_DIR).is_dir() {
+                    (self.report(&repo_path)?.usage, false)
+                } else if let Some(archived) = ArchivedRepository::load(&repo_path) {
+                    (archived.usage, true)
+                } else {
+                    continue;
+                };
+                let headroom = quota.map(|quota| quota.saturating_sub(usage.bytes));
+                totals.repositories += 1;
+                totals.changes += usage.change_files;
+                totals.bytes += usage.bytes;
+                totals.calls += usage.calls;
+                if let (Some(total), Some(headroom)) = (totals.headroom.as_mut(), headroom) {
+                    *total += headroom;
+                    if headroom == 0 {
+                        totals.over_quota += 1
+                    }
+                }
+                repositories.push(RepositoryUsage {
+                    portfolio_id: portfolio_id.clone(),
+                    project_id,
+                    archived,
+                    usage,
+                    headroom,
+                });
+            }
+        }
+        Ok(TenantUsage {
+            tenant_id: tenant_id.to_string(),
+            quota,
+            totals,
+            repositories,
+        })
+    }
+
+    /// Archive the repository at `repo_path` under `key`, unless it is
+    /// locked
+    pub fn archive(&self, repo_path: &Path, key: &str) -> ApiResult<ArchivedRepository> {
+        let store = self
+            .store
+            .as_ref()
+            .ok_or_else(|| ApiError::internal("No archive store configured"))?;
+        let guard = self.guard(repo_path);
+        let _guard = guard.lock().unwrap();
+        let dot_dir = repo_path.join(libatomic::DOT_DIR);
+        let _lock = Lock::new(dot_dir.join(atomic_repository::LOCK_FILE))
+            .acquire("archive", &LockOptions::no_wait())
+            .map_err(|e| match e.downcast_ref::<Locked>() {
+                Some(locked) => ApiError::locked(locked.to_string()),
+                None => ApiError::internal(format!("Failed to lock repository: {}", e)),
+            })?;
+
+        let pending = self.pending_calls(repo_path);
+        let mut pending = pending.lock().unwrap();
+        let mut usage = StorageUsage::load(repo_path);
+        usage.measure(repo_path)?;
+        usage.calls += *pending;
+        usage.save(repo_path)?;
+        *pending = ApiCalls::default();
+        store.put(key, &dot_dir)?;
+        let archived = ArchivedRepository {
+            key: key.to_string(),
+            archived_at: Utc::now(),
+            usage,
+        };
+        let mut tmp = tempfile::NamedTempFile::new_in(repo_path)?;
+        serde_json::to_writer(&mut tmp, &archived).map_err(std::io::Error::from)?;
+        tmp.persist(repo_path.join(ARCHIVED_FILE))
+            .map_err(|e| e.error)?;
+        std::fs::remove_dir_all(&dot_dir)?;
+        info!(
+            "Archived {} ({} bytes) to {}",
+            repo_path.display(),
+            archived.usage.bytes,
+            key
+        );
+        Ok(archived)
+    }
+
+    /// Restore the repository at `repo_path` from the store if it is
+    /// archived. Returns whether it was archived.
+    pub fn rehydrate(&self, repo_path: &Path) -> ApiResult<bool> {
+        let guard = self.guard(repo_path);
+        let _guard = guard.lock().unwrap();
+        // Another request may have rehydrated it while we were waiting.
+        let archived = match ArchivedRepository::load(repo_path) {
+            Some(archived) => archived,
+            None => return Ok(false),
+        };
+        let store = self.store.as_ref().ok_or_else(|| {
+            ApiError::internal(format!(
+                "{} is archived, but no archive store is configured",
+                repo_path.display()
+            ))
+        })?;
+
+        let tmp = tempfile::tempdir_in(repo_path)?;
+        let restored = tmp.path().join(libatomic::DOT_DIR);
+        store.get(&archived.key, &restored)?;
+        std::fs::rename(&restored, repo_path.join(libatomic::DOT_DIR))?;
+        std::fs::remove_file(repo_path.join(ARCHIVED_FILE))?;
+        if let Err(e) = self.record_access(repo_path) {
+            warn!("Failed to record access to {}: {}", repo_path.display(), e);
+        }
+        if let Err(e) = store.delete(&archived.key) {
+            warn!("Failed to delete archive {}: {}", archived.key, e);
+        }
+        info!(
+            "Rehydrated {} from {} (archived at {})",
+            repo_path.display(),
+            archived.key,
+            archived.archived_at
+        );
+        Ok(true)
+    }
+
+    /// Archive the repositories under `base_mount_path` that weren't
+    /// accessed for the configured inactivity, returning how many were
+    /// archived
+    pub fn reap_all(&self, base_mount_path: &Path) -> usize {
+        let archive_after = match self.config.archive_after {
+            Some(after) if self.store.is_some() => after,
+            _ => return 0,
+        };
+        let repositories = match crate::replica::repositories(base_mount_path) {
+            Ok(repositories) => repositories,
+            Err(e) => {
+                warn!("Failed to list repositories: {}", e);
+                return 0;
+            }
+        };
+        let now = Utc::now();
+        let mut n = 0;
+        for relative in repositories {
+            let repo_path = base_mount_path.join(&relative);
+            let last = StorageUsage::load(&repo_path).last_activity().or_else(|| {
+                // Repositories not accessed since accounting was enabled
+                std::fs::metadata(repo_path.join(libatomic::DOT_DIR))
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .map(DateTime::<Utc>::from)
+            });
+            let idle = last.map_or(Duration::MAX, |last| {
+                (now - last).to_std().unwrap_or_default()
+            });
+            if idle < archive_after {
+                continue;
+            }
+            let key = archive_key(&relative);
+            match self.archive(&repo_path, &key) {
+                Ok(_) => n += 1,
+                Err(ApiError::Locked { message }) => {
+                    debug!("Not archiving {}: {}", repo_path.display(), message)
+                }
+                Err(e) => warn!("Failed to archive {}: {}", repo_path.display(), e),
+            }
+        }
+        if n > 0 {
+            info!("Archived {} repositories", n);
+        }
+        n
+    }
+}
+
+/// Key of the archive of the repository at `relative` (relative to the
+/// base mount path)
+pub fn archive_key(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Tenant, portfolio and project ids of a request path under
+/// `/tenant/:tenant_id/portfolio/:portfolio_id/project/:project_id`
+pub fn repository_ids(path: &str) -> Option<(&str, &str, &str)> {
+    let mut segments = path.trim_start_matches('/').split('/');
+    match (
+        segments.next(),
+        segments.next(),
+        segments.next(),
+        segments.next(),
+        segments.next(),
+        segments.next(),
+    ) {
+        (
+            Some("tenant"),
+            Some(tenant_id),
+            Some("portfolio"),
+            Some(portfolio_id),
+            Some("project"),
+            Some(project_id),
+        ) => Some((tenant_id, portfolio_id, project_id)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repository_ids() {
+        assert_eq!(
+            repository_ids("/tenant/t/portfolio/p/project/x/code/changes"),
+            Some(("t", "p", "x"))
+        );
+        assert_eq!(
+            repository_ids("/tenant/t/portfolio/p/project/x"),
+            Some(("t", "p", "x"))
+        );
+        assert_eq!(repository_ids("/tenant/t/portfolio/p"), None);
+        assert_eq!(repository_ids("/health"), None);
+        assert_eq!(archive_key(Path::new("t/p/x")), "t/p/x".to_string());
+    }
+
+    #[test]
+    fn test_quota() {
+        let base = tempfile::tempdir().unwrap();
+        let repo = crate::admin::create_repository(base.path(), "t", "p", "x").unwrap();
+        let storage = Storage::new(StorageConfig {
+            quota: Some(1),
+            ..StorageConfig::default()
+        });
+        // Nothing recorded yet
+        storage.check_write(&repo).unwrap();
+
+        let usage = storage.record_write(&repo).unwrap();
+        assert!(usage.bytes > 0);
+        assert!(usage.last_write.is_some());
+        assert_eq!(StorageUsage::load(&repo), usage);
+        assert!(matches!(
+            storage.check_write(&repo),
+            Err(ApiError::QuotaExceeded { .. })
+        ));
+        assert!(Storage::default().check_write(&repo).is_ok());
+    }
+
+    #[test]
+    fn test_tenant_usage() {
+        let base = tempfile::tempdir().unwrap();
+        let archives = tempfile::tempdir().unwrap();
+        let x = crate::admin::create_repository(base.path(), "t", "p", "x").unwrap();
+        let y = crate::admin::create_repository(base.path(), "t", "p", "y").unwrap();
+        crate::admin::create_repository(base.path(), "u", "p", "z").unwrap();
+        let storage = Storage::new(StorageConfig {
+            quota: Some(1 << 40),
+            archive_dir: Some(archives.path().to_path_buf()),
+            ..StorageConfig::default()
+        });
+
+        storage.count_call(&x, false);
+        storage.count_call(&x, true);
+        let usage = storage.record_write(&x).unwrap();
+        assert_eq!(
+            usage.calls,
+            ApiCalls {
+                reads: 1,
+                writes: 1
+            }
+        );
+        // Counted in reports before they are saved
+        storage.count_call(&y, false);
+        assert_eq!(storage.report(&y).unwrap().usage.calls.reads, 1);
+        storage.archive(&y, "t/p/y").unwrap();
+
+        let tenant = storage.tenant_usage(&base.path().join("t"), "t").unwrap();
+        assert_eq!(tenant.totals.repositories, 2);
+        assert_eq!(tenant.repositories[0].project_id, "x");
+        assert!(!tenant.repositories[0].archived);
+        assert!(tenant.repositories[1].archived);
+        assert_eq!(
+            tenant.totals.calls,
+            ApiCalls {
+                reads: 2,
+                writes: 1
+            }
+        );
+        assert_eq!(
+            tenant.totals.bytes,
+            tenant.repositories[0].usage.bytes + tenant.repositories[1].usage.bytes
+        );
+        assert_eq!(
+            tenant.totals.headroom,
+            Some(2 * (1 << 40) - tenant.totals.bytes)
+        );
+        assert_eq!(tenant.totals.over_quota, 0);
+
+        let empty = Storage::default()
+            .tenant_usage(&base.path().join("none"), "none")
+            .unwrap();
+        assert_eq!(empty.totals, UsageTotals::default());
+    }
+
+    #[test]
+    fn test_archive_and_rehydrate() {
+        let base = tempfile::tempdir().unwrap();
+        let archives = tempfile::tempdir().unwrap();
+        let repo = crate::admin::create_repository(base.path(), "t", "p", "x").unwrap();
+        let storage = Storage::new(StorageConfig {
+            archive_dir: Some(archives.path().to_path_buf()),
+            archive_after: Some(Duration::ZERO),
+            ..StorageConfig::default()
+        });
+        assert!(!storage.rehydrate(&repo).unwrap());
+
+        // A locked repository isn't archived.
+        let lock = Lock::new(
+            repo.join(libatomic::DOT_DIR)
+                .join(atomic_repository::LOCK_FILE),
+        )
+        .acquire("record", &LockOptions::no_wait())
+        .unwrap();
+        assert_eq!(storage.reap_all(base.path()), 0);
+        std::mem::drop(lock);
+
+        assert_eq!(storage.reap_all(base.path()), 1);
+        assert!(!repo.join(libatomic::DOT_DIR).exists());
+        let archived = ArchivedRepository::load(&repo).unwrap();
+        assert_eq!(archived.key, "t/p/x");
+        assert!(archives.path().join("t/p/x").is_dir());
+
+        assert!(storage.rehydrate(&repo).unwrap());
+        assert!(ArchivedRepository::load(&repo).is_none());
+        assert!(!archives.path().join("t/p/x").exists());
+        atomic_repository::Repository::find_root(Some(repo.clone())).unwrap();
+        assert!(StorageUsage::load(&repo).last_access.is_some());
+    }
+}
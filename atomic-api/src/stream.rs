@@ -0,0 +1,339 @@
+//! Streaming JSON responses for list endpoints
+//!
+//! Lists are serialized item by item on a blocking thread instead of being
+//! collected and serialized at once. Items are written to chunks of
+//! [`CHUNK_SIZE`] bytes, sent to the response body through a channel of
+//! [`BUFFERED_CHUNKS`] chunks: when the client reads slower than the list
+//! is produced, the producer blocks, so that a response never holds more
+//! than a few chunks in memory however long the list is.
+//!
+//! The status and headers are sent before the first item, so errors met
+//! while producing the list can only abort the response: the client then
+//! sees a truncated body. Handlers should check what they can (the
+//! repository, the cursor) before streaming.
+
+use crate::query::Page;
+use crate::{ApiError, ApiResult};
+use axum::body::Body;
+use axum::http::{header, HeaderValue};
+use axum::response::{IntoResponse, Response};
+use bytes::Bytes;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// Size from which a chunk is sent to the client
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// Number of chunks waiting for the client before the producer blocks
+pub const BUFFERED_CHUNKS: usize = 4;
+
+type Chunk = Result<Bytes, std::io::Error>;
+
+/// Bytes sent by producers and not yet taken by the response body
+#[derive(Debug, Default)]
+pub struct BufferGauge {
+    current: AtomicUsize,
+    peak: AtomicUsize,
+}
+
+impl BufferGauge {
+    /// Bytes currently waiting for the client
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    /// Largest number of bytes that waited for the client at once
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, n: usize) {
+        let current = self.current.fetch_add(n, Ordering::Relaxed) + n;
+        self.peak.fetch_max(current, Ordering::Relaxed);
+    }
+
+    fn sub(&self, n: usize) {
+        self.current.fetch_sub(n, Ordering::Relaxed);
+    }
+}
+
+/// The writing end of a streamed JSON array
+pub struct Sink {
+    tx: mpsc::Sender<Chunk>,
+    gauge: Arc<BufferGauge>,
+    buf: Vec<u8>,
+    items: usize,
+}
+
+impl Sink {
+    /// Append `item` to the array. Fails if the client went away.
+    pub fn push<T: Serialize + ?Sized>(&mut self, item: &T) -> ApiResult<()> {
+        if self.items > 0 {
+            self.buf.push(b',');
+        }
+        serde_json::to_writer(&mut self.buf, item)
+            .map_err(|e| ApiError::internal(format!("Failed to serialize item: {}", e)))?;
+        self.items += 1;
+        if self.buf.len() >= CHUNK_SIZE {
+            self.flush()?
+        }
+        Ok(())
+    }
+
+    /// Number of items pushed so far
+    pub fn len(&self) -> usize {
+        self.items
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items == 0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes)
+    }
+
+    fn flush(&mut self) -> ApiResult<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let chunk = std::mem::replace(&mut self.buf, Vec::with_capacity(CHUNK_SIZE));
+        let len = chunk.len();
+        self.gauge.add(len);
+        self.tx.blocking_send(Ok(Bytes::from(chunk))).map_err(|_| {
+            self.gauge.sub(len);
+            ApiError::Io(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "Client disconnected",
+            ))
+        })
+    }
+}
+
+/// A JSON response streamed from a producer, see the module documentation
+pub struct JsonStream {
+    body: Body,
+    gauge: Arc<BufferGauge>,
+}
+
+impl JsonStream {
+    /// Stream the array of the items pushed by `produce`
+    pub fn array<F>(produce: F) -> Self
+    where
+        F: FnOnce(&mut Sink) -> ApiResult<()> + Send + 'static,
+    {
+        Self::spawn(move |sink| {
+            sink.write(b"[");
+            produce(sink)?;
+            sink.write(b"]");
+            Ok(())
+        })
+    }
+
+    /// Stream an object whose `field` is the array of the items pushed by
+    /// `produce`, followed by the fields it returns, e.g. the cursor of the
+    /// next page once it is known.
+    pub fn object<F>(field: &'static str, produce: F) -> Self
+    where
+        F: FnOnce(&mut Sink) -> ApiResult<Map<String, Value>> + Send + 'static,
+    {
+        Self::spawn(move |sink| {
+            sink.write(b"{");
+            sink.write(&serde_json::to_vec(field).unwrap_or_default());
+            sink.write(b":[");
+            let trailer = produce(sink)?;
+            sink.write(b"]");
+            for (k, v) in trailer.iter() {
+                sink.write(b",");
+                let field = serde_json::to_vec(k).unwrap_or_default();
+                sink.write(&field);
+                sink.write(b":");
+                let value = serde_json::to_vec(v)
+                    .map_err(|e| ApiError::internal(format!("Failed to serialize: {}", e)))?;
+                sink.write(&value);
+            }
+            sink.write(b"}");
+            Ok(())
+        })
+    }
+
+    /// Stream the items of `items` as an array
+    pub fn items<T: Serialize + Send + 'static>(items: Vec<T>) -> Self {
+        Self::array(move |sink| items.iter().try_for_each(|item| sink.push(item)))
+    }
+
+    /// Stream `page` in its envelope, `{items, next_cursor, total_estimate}`
+    pub fn page(page: Page) -> Self {
+        let Page {
+            items,
+            next_cursor,
+            total_estimate,
+        } = page;
+        Self::object("items", move |sink| {
+            items.iter().try_for_each(|item| sink.push(item))?;
+            Ok(page_trailer(next_cursor, total_estimate))
+        })
+    }
+
+    /// Bytes of this response waiting for the client
+    pub fn gauge(&self) -> Arc<BufferGauge> {
+        self.gauge.clone()
+    }
+
+    /// The response body
+    pub fn into_body(self) -> Body {
+        self.body
+    }
+
+    fn spawn<F>(produce: F) -> Self
+    where
+        F: FnOnce(&mut Sink) -> ApiResult<()> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel::<Chunk>(BUFFERED_CHUNKS);
+        let gauge = Arc::new(BufferGauge::default());
+        let mut sink = Sink {
+            tx,
+            gauge: gauge.clone(),
+            buf: Vec::with_capacity(CHUNK_SIZE),
+            items: 0,
+        };
+        tokio::task::spawn_blocking(move || {
+            let result = produce(&mut sink).and_then(|()| sink.flush());
+            match result {
+                Ok(()) => debug!("Streamed {} items", sink.items),
+                Err(ApiError::Io(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                    debug!("Client left after {} items", sink.items)
+                }
+                Err(e) => {
+                    warn!(
+                        "Aborting streamed response after {} items: {}",
+                        sink.items, e
+                    );
+                    // Make the body fail, rather than end as if complete.
+                    sink.tx
+                        .blocking_send(Err(std::io::Error::new(
+                            std::io::ErrorKind::Other,
+                            e.to_string(),
+                        )))
+                        .unwrap_or(())
+                }
+            }
+        });
+        let gauge_ = gauge.clone();
+        let stream = futures_util::stream::unfold(rx, move |mut rx| {
+            let gauge = gauge_.clone();
+            async move {
+                let chunk = rx.recv().await?;
+                if let Ok(ref bytes) = chunk {
+                    gauge.sub(bytes.len())
+                }
+                Some((chunk, rx))
+            }
+        });
+        JsonStream {
+            body: Body::from_stream(stream),
+            gauge,
+        }
+    }
+}
+
+/// The fields following the items of a page
+pub fn page_trailer(next_cursor: Option<String>, total_estimate: u64) -> Map<String, Value> {
+    let mut trailer = Map::new();
+    trailer.insert(
+        "next_cursor".to_string(),
+        next_cursor.map_or(Value::Null, Value::String),
+    );
+    trailer.insert("total_estimate".to_string(), Value::from(total_estimate));
+    trailer
+}
+
+impl From<Page> for JsonStream {
+    fn from(page: Page) -> Self {
+        JsonStream::page(page)
+    }
+}
+
+impl IntoResponse for JsonStream {
+    fn into_response(self) -> Response {
+        let mut response = Response::new(self.body);
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::StreamExt;
+
+    /// Read `body` slowly, returning its bytes
+    async fn read_slowly(body: Body) -> Vec<u8> {
+        let mut stream = body.into_data_stream();
+        let mut out = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.unwrap();
+            assert!(chunk.len() < CHUNK_SIZE + 1024);
+            out.extend_from_slice(&chunk);
+            tokio::time::sleep(std::time::Duration::from_micros(200)).await;
+        }
+        out
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_large_list_uses_bounded_memory() {
+        const N: usize = 200_000;
+        let produced = Arc::new(AtomicUsize::new(0));
+        let produced_ = produced.clone();
+        let stream = JsonStream::object("items", move |sink| {
+            for i in 0..N {
+                let item = serde_json::json!({
+                    "id": i,
+                    "message": "x".repeat(64),
+                });
+                sink.push(&item)?;
+                produced_.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(page_trailer(Some("next".to_string()), N as u64))
+        });
+        let gauge = stream.gauge();
+        let body = read_slowly(stream.into_body()).await;
+
+        // About 18 MB went through, but never more than the channel
+        // (and the chunk being received) at once.
+        assert!(body.len() > 15 * 1024 * 1024);
+        assert!(gauge.peak() <= (BUFFERED_CHUNKS + 1) * (CHUNK_SIZE + 1024));
+        assert_eq!(gauge.current(), 0);
+        assert_eq!(produced.load(Ordering::Relaxed), N);
+
+        let value: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(value["items"].as_array().unwrap().len(), N);
+        assert_eq!(value["items"][N - 1]["id"], N - 1);
+        assert_eq!(value["next_cursor"], "next");
+        assert_eq!(value["total_estimate"], N);
+    }
+
+    #[tokio::test]
+    async fn test_errors_abort_the_body() {
+        let stream = JsonStream::array(|sink| {
+            sink.push(&1)?;
+            Err(ApiError::internal("boom".to_string()))
+        });
+        let mut data = stream.into_body().into_data_stream();
+        let mut failed = false;
+        while let Some(chunk) = data.next().await {
+            failed |= chunk.is_err();
+        }
+        assert!(failed);
+
+        let empty = JsonStream::items(Vec::<u32>::new()).into_body();
+        let bytes = axum::body::to_bytes(empty, usize::MAX).await.unwrap();
+        assert_eq!(&bytes[..], b"[]");
+    }
+}
@@ -0,0 +1,413 @@
+//! Submissions of changes by email
+//!
+//! Contributors who can only send email submit their changes as a bundle
+//! (an `atomic` bundle file, see [`atomic_remote::offline`], or a bundle
+//! of the protocol, see [`atomic_remote::bundle`]) to
+//! `POST .../code/submissions`, in one of three forms:
+//!
+//! - the bundle itself, as the body of the request,
+//! - a `multipart/form-data` upload with the bundle as one of its files,
+//! - the email, as received (`message/rfc822`), with the bundle attached.
+//!
+//! Attachments are looked up in nested multiparts and forwarded emails,
+//! and may be base64-encoded. The changes of the bundle are checked
+//! against their hashes, then applied, along with their dependencies, to
+//! a contribution channel: `contrib-<channel>` by default, forked from
+//! the target `channel` (`main` by default) the first time a change is
+//! submitted to it. Changes are checked like pushes, against the
+//! `[[forbid]]` rules and the `[message_policy]` of the repository, and
+//! all of them are staged or none.
+//!
+//! Workflows are then started on the staged changes like on the changes
+//! pushed to a channel with `[[bootstrap]]` rules (see
+//! [`crate::workflow::bootstrap`]): with the rules of the contribution
+//! channel if it has some, and in `SimpleApproval`, with the code owners
+//! of the paths they touch as reviewers, otherwise. Maintainers review
+//! and transition them like any other change, and apply them to the
+//! target channel once approved.
+
+use crate::{ApiError, ApiResult};
+use atomic_config::BootstrapRule;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use libatomic::pristine::{Base32, Hash, NodeType};
+use serde::{Deserialize, Serialize};
+
+/// Prefix of the default contribution channels
+pub const CONTRIB_PREFIX: &str = "contrib-";
+
+/// Depth of the nested multiparts and emails searched for a bundle
+const MAX_DEPTH: usize = 8;
+
+/// Query parameters of `POST .../code/submissions`
+#[derive(Debug, Deserialize)]
+pub struct SubmissionQuery {
+    /// Channel the submission targets, `main` by default
+    pub channel: Option<String>,
+    /// Contribution channel to stage the changes on,
+    /// `contrib-<channel>` by default
+    pub to_channel: Option<String>,
+}
+
+impl SubmissionQuery {
+    pub fn target(&self) -> &str {
+        self.channel
+            .as_deref()
+            .unwrap_or(libatomic::DEFAULT_CHANNEL)
+    }
+
+    pub fn contribution_channel(&self) -> String {
+        self.to_channel
+            .clone()
+            .unwrap_or_else(|| format!("{}{}", CONTRIB_PREFIX, self.target()))
+    }
+}
+
+/// A change of a submission
+#[derive(Debug, Clone, Serialize)]
+pub struct SubmittedChange {
+    pub hash: String,
+    pub message: String,
+    /// Whether the change was applied, `false` if the contribution channel
+    /// already had it
+    pub applied: bool,
+    /// Workflows started on the change
+    pub workflows: Vec<String>,
+}
+
+/// A submission staged on a contribution channel, served by
+/// `POST .../code/submissions`
+#[derive(Debug, Clone, Serialize)]
+pub struct Submission {
+    /// The contribution channel
+    pub channel: String,
+    /// The channel the contribution channel was forked from
+    pub target: String,
+    /// Sender of the email, if the bundle was emailed
+    pub from: Option<String>,
+    /// Subject of the email, if the bundle was emailed
+    pub subject: Option<String>,
+    pub changes: Vec<SubmittedChange>,
+}
+
+/// A bundle found in the body of a submission
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Extracted {
+    pub from: Option<String>,
+    pub subject: Option<String>,
+    pub bundle: Vec<u8>,
+}
+
+/// The bundle of the body of a submission, of content type
+/// `content_type`
+pub fn extract(content_type: Option<&str>, body: &[u8]) -> ApiResult<Extracted> {
+    if is_bundle(body) {
+        return Ok(Extracted {
+            from: None,
+            subject: None,
+            bundle: body.to_vec(),
+        });
+    }
+    let part = match content_type {
+        // A form upload: its body is the multipart.
+        Some(ct) if ct.trim().to_ascii_lowercase().starts_with("multipart/") => Part {
+            headers: vec![("content-type".to_string(), ct.to_string())],
+            body,
+        },
+        // Anything else is an email, headers included.
+        _ => Part::parse(body),
+    };
+    let bundle = find_bundle(&part, 0)
+        .ok_or_else(|| ApiError::invalid_query("No bundle found in the submission"))?;
+    Ok(Extracted {
+        from: part.header("from").map(str::to_string),
+        subject: part.header("subject").map(str::to_string),
+        bundle,
+    })
+}
+
+fn is_bundle(buf: &[u8]) -> bool {
+    buf.starts_with(atomic_remote::offline::MAGIC) || atomic_remote::bundle::is_bundle(buf)
+}
+
+/// The changes of `bundle`, checked against their hashes. Tags are
+/// rejected, submissions only take changes.
+pub fn decode(bundle: &[u8]) -> ApiResult<Vec<(Hash, Vec<u8>)>> {
+    let invalid = |e: anyhow::Error| ApiError::invalid_query(format!("Invalid bundle: {}", e));
+    let changes = if bundle.starts_with(atomic_remote::offline::MAGIC) {
+        let (_, nodes) = atomic_remote::offline::read(bundle).map_err(invalid)?;
+        let mut changes = Vec::with_capacity(nodes.len());
+        for (node, contents) in nodes {
+            if node.node_type != NodeType::Change {
+                return Err(ApiError::invalid_query(format!(
+                    "Submissions only take changes, {} is a tag",
+                    node.hash.to_base32()
+                )));
+            }
+            changes.push((node.hash, contents))
+        }
+        changes
+    } else {
+        atomic_remote::bundle::decode(bundle).map_err(invalid)?
+    };
+    if changes.is_empty() {
+        return Err(ApiError::invalid_query("Empty bundle"));
+    }
+    for (hash, change) in changes.iter() {
+        libatomic::change::Change::check_from_buffer(change, hash).map_err(|e| {
+            ApiError::invalid_query(format!(
+                "Bundled data doesn't match change {}: {}",
+                hash.to_base32(),
+                e
+            ))
+        })?;
+    }
+    Ok(changes)
+}
+
+/// The rules starting the workflows of the changes staged on `channel`:
+/// the `[[bootstrap]]` rules of the channel, or `SimpleApproval` with the
+/// code owners as reviewers if it has none.
+pub fn bootstrap_rules(config: &atomic_config::Config, channel: &str) -> Vec<BootstrapRule> {
+    let rules: Vec<_> = config
+        .bootstrap
+        .iter()
+        .filter(|r| r.channel == channel)
+        .cloned()
+        .collect();
+    if !rules.is_empty() {
+        return rules;
+    }
+    vec![BootstrapRule {
+        channel: channel.to_string(),
+        workflow: "SimpleApproval".to_string(),
+        state: None,
+        codeowners: true,
+    }]
+}
+
+/// A MIME entity: an email, or a part of a multipart
+struct Part<'a> {
+    /// Headers, with lowercase names
+    headers: Vec<(String, String)>,
+    body: &'a [u8],
+}
+
+impl<'a> Part<'a> {
+    /// Split `buf` into its headers and body, at the first empty line
+    fn parse(buf: &'a [u8]) -> Self {
+        let mut headers: Vec<(String, String)> = Vec::new();
+        let mut rest = buf;
+        loop {
+            let (line, next) = match rest.iter().position(|&b| b == b'\n') {
+                Some(i) => (&rest[..i], &rest[i + 1..]),
+                None => (rest, &rest[rest.len()..]),
+            };
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.is_empty() {
+                rest = next;
+                break;
+            }
+            let line = String::from_utf8_lossy(line);
+            if line.starts_with(' ') || line.starts_with('\t') {
+                // A folded header continues the previous one.
+                if let Some((_, value)) = headers.last_mut() {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+            } else if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+            } else {
+                // Not a header: there are no headers.
+                return Part {
+                    headers: Vec::new(),
+                    body: buf,
+                };
+            }
+            if next.is_empty() {
+                rest = next;
+                break;
+            }
+            rest = next;
+        }
+        Part {
+            headers,
+            body: rest,
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// The lowercase media type of the part, and its `boundary`
+    /// parameter
+    fn content_type(&self) -> (String, Option<String>) {
+        let ct = self.header("content-type").unwrap_or("text/plain");
+        let mut params = ct.split(';');
+        let media_type = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        let boundary = params.find_map(|p| {
+            let (name, value) = p.split_once('=')?;
+            if name.trim().eq_ignore_ascii_case("boundary") {
+                Some(value.trim().trim_matches('"').to_string())
+            } else {
+                None
+            }
+        });
+        (media_type, boundary)
+    }
+
+    /// The body of the part, decoded from its transfer encoding
+    fn decoded(&self) -> Option<Vec<u8>> {
+        match self.header("content-transfer-encoding") {
+            Some(e) if e.eq_ignore_ascii_case("base64") => {
+                let encoded: Vec<u8> = self
+                    .body
+                    .iter()
+                    .copied()
+                    .filter(|b| !b.is_ascii_whitespace())
+                    .collect();
+                STANDARD.decode(encoded).ok()
+            }
+            _ => Some(self.body.to_vec()),
+        }
+    }
+
+    /// The parts of a multipart body delimited by `boundary`
+    fn parts(&self, boundary: &str) -> Vec<Part<'a>> {
+        let delimiter = format!("--{}", boundary);
+        let delimiter = delimiter.as_bytes();
+        let body = self.body;
+        // Delimiters start a line.
+        let starts: Vec<usize> = (0..body.len())
+            .filter(|&i| (i == 0 || body[i - 1] == b'\n') && body[i..].starts_with(delimiter))
+            .collect();
+        let mut parts = Vec::new();
+        for w in starts.windows(2) {
+            let (start, end) = (w[0] + delimiter.len(), w[1]);
+            if body[start..].starts_with(b"--") {
+                break;
+            }
+            // The part starts after the end of the delimiter line, and
+            // ends before the line break preceding the next delimiter.
+            let start = match body[start..end].iter().position(|&b| b == b'\n') {
+                Some(i) => start + i + 1,
+                None => continue,
+            };
+            let contents = &body[start..end];
+            let contents = contents.strip_suffix(b"\n").unwrap_or(contents);
+            let contents = contents.strip_suffix(b"\r").unwrap_or(contents);
+            parts.push(Part::parse(contents))
+        }
+        parts
+    }
+}
+
+/// The first bundle in `part`, its subparts or the emails it forwards
+fn find_bundle(part: &Part, depth: usize) -> Option<Vec<u8>> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+    let (media_type, boundary) = part.content_type();
+    if media_type.starts_with("multipart/") {
+        let boundary = boundary?;
+        return part
+            .parts(&boundary)
+            .iter()
+            .find_map(|p| find_bundle(p, depth + 1));
+    }
+    let decoded = part.decoded()?;
+    if is_bundle(&decoded) {
+        Some(decoded)
+    } else if media_type == "message/rfc822" {
+        find_bundle(&Part::parse(&decoded), depth + 1)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle() -> Vec<u8> {
+        let mut bundle = atomic_remote::offline::MAGIC.to_vec();
+        bundle.extend_from_slice(b"\x00\x01\x02contents");
+        bundle
+    }
+
+    #[test]
+    fn test_extract() {
+        let raw = bundle();
+        assert_eq!(extract(None, &raw).unwrap().bundle, raw);
+
+        let encoded = STANDARD.encode(&raw);
+        let email = format!(
+            "From: Alice <alice@example.com>\r\n\
+             Subject: [PATCH] Fix the\r\n parser\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+             \r\n\
+             --outer\r\n\
+             Content-Type: text/plain\r\n\
+             \r\n\
+             Please review.\r\n\
+             --outer\r\n\
+             Content-Type: application/octet-stream; name=\"fix.bundle\"\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             Content-Disposition: attachment; filename=\"fix.bundle\"\r\n\
+             \r\n\
+             {}\r\n\
+             {}\r\n\
+             --outer--\r\n",
+            &encoded[..8],
+            &encoded[8..]
+        );
+        let extracted = extract(Some("message/rfc822"), email.as_bytes()).unwrap();
+        assert_eq!(extracted.bundle, raw);
+        assert_eq!(extracted.from.as_deref(), Some("Alice <alice@example.com>"));
+        assert_eq!(extracted.subject.as_deref(), Some("[PATCH] Fix the parser"));
+
+        // A form upload of the email, forwarded as an attachment.
+        let mut form = b"--form\r\n\
+            Content-Disposition: form-data; name=\"email\"; filename=\"fix.eml\"\r\n\
+            Content-Type: message/rfc822\r\n\
+            \r\n"
+            .to_vec();
+        form.extend_from_slice(email.as_bytes());
+        form.extend_from_slice(b"\r\n--form--\r\n");
+        let extracted = extract(Some("multipart/form-data; boundary=form"), &form).unwrap();
+        assert_eq!(extracted.bundle, raw);
+        assert_eq!(extracted.from, None);
+
+        assert!(extract(Some("text/plain"), b"Subject: no bundle\r\n\r\nHello").is_err());
+    }
+
+    #[test]
+    fn test_bootstrap_rules() {
+        let mut config = atomic_config::Config::default();
+        let rules = bootstrap_rules(&config, "contrib-main");
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].workflow, "SimpleApproval");
+        assert!(rules[0].codeowners);
+
+        config.bootstrap.push(BootstrapRule {
+            channel: "contrib-main".to_string(),
+            workflow: "TwoStageApproval".to_string(),
+            state: None,
+            codeowners: false,
+        });
+        let rules = bootstrap_rules(&config, "contrib-main");
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].workflow, "TwoStageApproval");
+
+        let query = SubmissionQuery {
+            channel: None,
+            to_channel: None,
+        };
+        assert_eq!(query.contribution_channel(), "contrib-main");
+    }
+}
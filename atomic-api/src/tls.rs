@@ -0,0 +1,287 @@
+//! Native TLS termination
+//!
+//! Deployments that can't put a proxy in front of the server can have the
+//! REST and WebSocket servers terminate TLS themselves. The certificate
+//! chain and private key are read from PEM files, which are watched for
+//! changes: replacing them on disk (for instance after a renewal by an
+//! external tool) switches new connections to the new certificate without
+//! a restart. Certificates can also be obtained and renewed automatically
+//! over ACME, see [`crate::acme`].
+
+use crate::acme::AcmeConfig;
+use crate::{ApiError, ApiResult};
+use axum::Router;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+
+/// Default interval between two checks of the certificate files
+const DEFAULT_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Configuration of the TLS termination
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM file containing the certificate chain, leaf first
+    pub cert_path: PathBuf,
+    /// PEM file containing the private key
+    pub key_path: PathBuf,
+    /// Interval at which the files are checked for changes
+    pub reload_interval: Duration,
+    /// Obtain and renew the certificate over ACME, writing it to
+    /// `cert_path` and `key_path`
+    pub acme: Option<AcmeConfig>,
+}
+
+impl TlsConfig {
+    /// Read the configuration from `ATOMIC_API_TLS_CERT`,
+    /// `ATOMIC_API_TLS_KEY` and `ATOMIC_API_TLS_RELOAD` (in seconds), and
+    /// the ACME configuration (see [`AcmeConfig::from_env`]). TLS is
+    /// disabled if the certificate path isn't set.
+    pub fn from_env() -> Option<Self> {
+        let cert_path = PathBuf::from(std::env::var_os("ATOMIC_API_TLS_CERT")?);
+        let key_path = std::env::var_os("ATOMIC_API_TLS_KEY")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| cert_path.with_extension("key"));
+        let reload_interval = std::env::var("ATOMIC_API_TLS_RELOAD")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_RELOAD_INTERVAL);
+        let acme = AcmeConfig::from_env(&cert_path);
+        Some(TlsConfig {
+            cert_path,
+            key_path,
+            reload_interval,
+            acme,
+        })
+    }
+}
+
+/// Read a certificate chain and its private key from PEM files
+pub fn load_certified_key(cert_path: &Path, key_path: &Path) -> ApiResult<CertifiedKey> {
+    let read = |path: &Path| {
+        std::fs::read(path)
+            .map_err(|e| ApiError::internal(format!("Failed to read {}: {}", path.display(), e)))
+    };
+    let certs = rustls_pemfile::certs(&mut &read(cert_path)?[..])
+        .map_err(|e| ApiError::internal(format!("Invalid certificate file: {}", e)))?;
+    if certs.is_empty() {
+        return Err(ApiError::internal(format!(
+            "No certificate found in {}",
+            cert_path.display()
+        )));
+    }
+
+    let key = rustls_pemfile::read_all(&mut &read(key_path)?[..])
+        .map_err(|e| ApiError::internal(format!("Invalid key file: {}", e)))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(k)
+            | rustls_pemfile::Item::RSAKey(k)
+            | rustls_pemfile::Item::ECKey(k) => Some(k),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            ApiError::internal(format!("No private key found in {}", key_path.display()))
+        })?;
+    let key = rustls::sign::any_supported_type(&rustls::PrivateKey(key))
+        .map_err(|e| ApiError::internal(format!("Unsupported private key: {}", e)))?;
+
+    Ok(CertifiedKey::new(
+        certs.into_iter().map(rustls::Certificate).collect(),
+        key,
+    ))
+}
+
+/// Certificate resolver whose certificate can be swapped at runtime
+#[derive(Default)]
+pub struct CertResolver {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    /// Use `key` for the next handshakes
+    pub fn set(&self, key: CertifiedKey) {
+        *self.current.write().unwrap() = Some(Arc::new(key))
+    }
+
+    /// The certificate currently served, if any
+    pub fn current(&self) -> Option<Arc<CertifiedKey>> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Reload the certificate from disk
+    pub fn reload(&self, cert_path: &Path, key_path: &Path) -> ApiResult<()> {
+        self.set(load_certified_key(cert_path, key_path)?);
+        info!("Loaded TLS certificate from {}", cert_path.display());
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current()
+    }
+}
+
+/// Running TLS termination, shared by the REST and WebSocket servers
+#[derive(Clone)]
+pub struct Tls {
+    config: TlsConfig,
+    resolver: Arc<CertResolver>,
+}
+
+impl Tls {
+    /// Load the certificate, and start watching it for changes. If ACME is
+    /// configured, also start the challenge server and the renewal task;
+    /// the certificate may then be missing until it is first issued.
+    pub async fn start(config: TlsConfig) -> ApiResult<Self> {
+        let resolver = Arc::new(CertResolver::default());
+        if let Err(e) = resolver.reload(&config.cert_path, &config.key_path) {
+            if config.acme.is_none() {
+                return Err(e);
+            }
+            info!("No usable certificate yet, waiting for ACME: {}", e);
+        }
+        let tls = Tls { config, resolver };
+        tls.spawn_reload();
+        if let Some(ref acme) = tls.config.acme {
+            crate::acme::spawn(acme.clone(), tls.clone()).await?;
+        }
+        Ok(tls)
+    }
+
+    pub fn config(&self) -> &TlsConfig {
+        &self.config
+    }
+
+    pub fn resolver(&self) -> &Arc<CertResolver> {
+        &self.resolver
+    }
+
+    /// Acceptor advertising the given ALPN protocols
+    pub fn acceptor(&self, alpn: &[&[u8]]) -> TlsAcceptor {
+        let mut config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.resolver.clone());
+        config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+        TlsAcceptor::from(Arc::new(config))
+    }
+
+    /// Reload the certificate whenever its files are modified
+    fn spawn_reload(&self) {
+        let tls = self.clone();
+        tokio::spawn(async move {
+            let config = &tls.config;
+            let mut last = modified(&config.cert_path, &config.key_path);
+            let mut interval = tokio::time::interval(config.reload_interval);
+            loop {
+                interval.tick().await;
+                let m = modified(&config.cert_path, &config.key_path);
+                if m == last {
+                    continue;
+                }
+                debug!("Certificate files changed, reloading");
+                match tls.resolver.reload(&config.cert_path, &config.key_path) {
+                    Ok(()) => last = m,
+                    // The files may be half-written, retry at the next tick.
+                    Err(e) => warn!("Failed to reload TLS certificate: {}", e),
+                }
+            }
+        });
+    }
+}
+
+fn modified(cert_path: &Path, key_path: &Path) -> Option<(SystemTime, SystemTime)> {
+    let m = |p: &Path| std::fs::metadata(p).and_then(|m| m.modified()).ok();
+    Some((m(cert_path)?, m(key_path)?))
+}
+
+/// Serve `app` over TLS on `listener`, with HTTP/1.1 and HTTP/2, until
+/// `shutdown` completes
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    tls: &Tls,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> ApiResult<()> {
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::service::TowerToHyperService;
+
+    let acceptor = tls.acceptor(&[b"h2", b"http/1.1"]);
+    tokio::pin!(shutdown);
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = &mut shutdown => return Ok(()),
+        };
+        let (stream, addr) = match accepted {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        // Peers are rate-limited by address, see `crate::limits`.
+        let service = TowerToHyperService::new(
+            app.clone()
+                .layer(axum::Extension(axum::extract::ConnectInfo(addr))),
+        );
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(s) => s,
+                Err(e) => {
+                    debug!("TLS handshake with {} failed: {}", addr, e);
+                    return;
+                }
+            };
+            if let Err(e) = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("Connection from {} closed: {}", addr, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_self_signed(dir: &Path, name: &str) -> (PathBuf, PathBuf) {
+        let cert = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        std::fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+        std::fs::write(&cert_path, cert.serialize_pem().unwrap()).unwrap();
+        (cert_path, key_path)
+    }
+
+    #[test]
+    fn test_reload_swaps_certificate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = write_self_signed(tmp.path(), "a.example.com");
+        let resolver = CertResolver::default();
+        assert!(resolver.current().is_none());
+
+        resolver.reload(&cert_path, &key_path).unwrap();
+        let first = resolver.current().unwrap().cert[0].clone();
+
+        write_self_signed(tmp.path(), "b.example.com");
+        resolver.reload(&cert_path, &key_path).unwrap();
+        assert_ne!(resolver.current().unwrap().cert[0], first);
+
+        // A broken file doesn't replace the current certificate.
+        std::fs::write(&key_path, "not a key").unwrap();
+        assert!(resolver.reload(&cert_path, &key_path).is_err());
+        assert!(resolver.current().is_some());
+    }
+}
@@ -0,0 +1,143 @@
+//! Validation of changes before their upload
+//!
+//! Clients ask whether a change would be applied before uploading it,
+//! with `POST <protocol>?validate=<hash>&to_channel=<channel>` and a
+//! summary of the change as JSON (see [`atomic_remote::summary`]): its
+//! header, dependencies and the paths it adds or edits. The summary goes
+//! through the checks of an apply that don't need the contents of the
+//! change, in the same order:
+//!
+//! - confidential channels, see [`crate::envelopes`],
+//! - storage quotas, like any other write, see [`crate::storage`],
+//! - `[[protect]]` rules, see [`crate::workflow`],
+//! - dependencies, which must be changes of the channel or known tags,
+//! - `[[forbid]]` rules, see [`libatomic::forbidden`],
+//! - the `[message_policy]` of the repository.
+//!
+//! A change passing them is answered with a [`Validation`], and a change
+//! failing one of them with the error its apply would return.
+
+use crate::error::RepositoryError;
+use crate::{ApiError, ApiResult};
+use serde::{Deserialize, Serialize};
+
+/// Answer to the validation of a change that would be applied
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Validation {
+    pub change_id: String,
+    /// Whether the channel already has the change, in which case its
+    /// upload is acknowledged without being applied again
+    pub already_present: bool,
+}
+
+/// Reject `change_id`, whose message is `message`, if the message doesn't
+/// follow the `[message_policy]` of `config`
+pub fn check_message(
+    config: &atomic_config::Config,
+    change_id: &str,
+    message: &str,
+) -> ApiResult<()> {
+    let policy = match config.message_policy {
+        Some(ref policy) => policy,
+        None => return Ok(()),
+    };
+    let mut problem = None;
+    if let Some(max) = policy.max_length {
+        let length = message.chars().count();
+        if length > max {
+            problem = Some(format!(
+                "message is {} characters long, at most {} are allowed",
+                length, max
+            ))
+        }
+    }
+    if let Some(ref pattern) = policy.pattern {
+        let regex = regex::Regex::new(pattern)
+            .map_err(|e| ApiError::internal(format!("Invalid message policy: {}", e)))?;
+        if problem.is_none() && !regex.is_match(message) {
+            problem = Some(format!("message doesn't match {:?}", pattern))
+        }
+    }
+    match problem {
+        Some(problem) => Err(ApiError::Repository(RepositoryError::MessagePolicy {
+            change_id: change_id.to_string(),
+            reason: match policy.reason {
+                Some(ref reason) => format!("{} ({})", problem, reason),
+                None => problem,
+            },
+        })),
+        None => Ok(()),
+    }
+}
+
+/// Reject `change_id` if `paths`, the paths it adds or edits, are
+/// forbidden by the `[[forbid]]` rules of `config`
+pub fn check_paths<'a, I: IntoIterator<Item = &'a str>>(
+    config: &atomic_config::Config,
+    change_id: &str,
+    paths: I,
+) -> ApiResult<()> {
+    let forbidden = atomic_repository::forbidden_paths(config)
+        .map_err(|e| ApiError::internal(format!("Invalid forbid rule: {}", e)))?;
+    forbidden.check_paths(paths).map_err(|e| {
+        ApiError::Repository(RepositoryError::ForbiddenPaths {
+            change_id: change_id.to_string(),
+            violations: e.violations,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_config::{Config, ForbidRule, MessagePolicy};
+
+    #[test]
+    fn test_check_message() {
+        let mut config = Config::default();
+        assert!(check_message(&config, "AAAA", "anything").is_ok());
+
+        config.message_policy = Some(MessagePolicy {
+            pattern: Some("^[A-Z]+-[0-9]+: ".to_string()),
+            max_length: Some(20),
+            reason: Some("start with an issue key".to_string()),
+        });
+        assert!(check_message(&config, "AAAA", "PROJ-1: Fix it").is_ok());
+        match check_message(&config, "AAAA", "Fix it") {
+            Err(ApiError::Repository(RepositoryError::MessagePolicy { change_id, reason })) => {
+                assert_eq!(change_id, "AAAA");
+                assert!(reason.contains("start with an issue key"));
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(matches!(
+            check_message(&config, "AAAA", "PROJ-1: Fix it, and more"),
+            Err(ApiError::Repository(RepositoryError::MessagePolicy { .. }))
+        ));
+
+        config.message_policy = Some(MessagePolicy {
+            pattern: Some("(".to_string()),
+            ..MessagePolicy::default()
+        });
+        assert!(matches!(
+            check_message(&config, "AAAA", "Fix it"),
+            Err(ApiError::Internal { .. })
+        ));
+    }
+
+    #[test]
+    fn test_check_paths() {
+        let mut config = Config::default();
+        assert!(check_paths(&config, "AAAA", ["target/app"]).is_ok());
+
+        config.forbid.push(ForbidRule {
+            path: "target".to_string(),
+            reason: None,
+        });
+        assert!(check_paths(&config, "AAAA", ["src/main.rs"]).is_ok());
+        assert!(matches!(
+            check_paths(&config, "AAAA", ["src/main.rs", "target/app"]),
+            Err(ApiError::Repository(RepositoryError::ForbiddenPaths { .. }))
+        ));
+    }
+}
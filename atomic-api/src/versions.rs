@@ -0,0 +1,155 @@
+//! Protocol versions
+//!
+//! Clients and servers can't all be upgraded at once, so the server
+//! serves every version of [`SERVED`] side by side, and negotiates the
+//! version of each protocol request (the `code` and `code/.atomic`
+//! endpoints) separately: clients send theirs in [`VERSION_HEADER`], and
+//! requests without it are from clients older than the negotiation, at
+//! [`LEGACY_VERSION`]. Requests at a version that isn't served are
+//! rejected with a `400` listing the served versions in
+//! [`VERSIONS_HEADER`], for the client to retry at one of them. Responses
+//! carry the version they were served at.
+//!
+//! Requests are counted by version, and served by `GET /metrics/protocol`,
+//! to know when clients have all left a version and it can be retired from
+//! [`SERVED`].
+
+use crate::{ApiError, ApiResult};
+use axum::http::HeaderMap;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Header of the protocol version of a request, and of its response
+pub const VERSION_HEADER: &str = "x-atomic-protocol-version";
+
+/// Header of the versions served, on rejected requests
+pub const VERSIONS_HEADER: &str = "x-atomic-protocol-versions";
+
+/// Protocol versions served, oldest first
+pub const SERVED: &[u32] = &[4, 5];
+
+/// Version of the requests without [`VERSION_HEADER`]
+pub const LEGACY_VERSION: u32 = 4;
+
+/// First version announcing bundles of changes in discovery
+pub const BUNDLES_VERSION: u32 = 5;
+
+/// Whether `path` is one of the protocol endpoints
+pub fn is_protocol_path(path: &str) -> bool {
+    crate::storage::repository_ids(path).is_some()
+        && (path.ends_with("/code") || path.ends_with("/code/.atomic"))
+}
+
+/// The version a request with `header` is served at.
+pub fn negotiate(header: Option<&str>) -> ApiResult<u32> {
+    let Some(header) = header else {
+        return Ok(LEGACY_VERSION);
+    };
+    match header.trim().parse::<u32>() {
+        Ok(version) if SERVED.contains(&version) => Ok(version),
+        Ok(version) => Err(ApiError::unsupported_protocol(
+            format!("Protocol version {} is not served", version),
+            SERVED.to_vec(),
+        )),
+        Err(_) => Err(ApiError::unsupported_protocol(
+            format!("Invalid protocol version {:?}", header),
+            SERVED.to_vec(),
+        )),
+    }
+}
+
+/// The version of a request with `headers`, see [`negotiate`].
+pub fn request_version(headers: &HeaderMap) -> ApiResult<u32> {
+    negotiate(
+        headers
+            .get(VERSION_HEADER)
+            .map(|v| v.to_str().unwrap_or_default()),
+    )
+}
+
+/// Requests by version, served by `GET /metrics/protocol`
+#[derive(Debug, Clone, Serialize)]
+pub struct ProtocolMetrics {
+    /// Versions served
+    pub served: Vec<u32>,
+    /// Requests served at each version since the server started
+    pub requests: BTreeMap<u32, u64>,
+    /// Requests rejected for their version
+    pub rejected: u64,
+}
+
+/// Negotiation of the protocol requests, and their counters
+#[derive(Clone, Default)]
+pub struct ProtocolVersions {
+    requests: Arc<Mutex<BTreeMap<u32, u64>>>,
+    rejected: Arc<AtomicU64>,
+}
+
+impl ProtocolVersions {
+    /// Negotiate the version of a request with `headers`, and count it.
+    pub fn negotiate(&self, headers: &HeaderMap) -> ApiResult<u32> {
+        match request_version(headers) {
+            Ok(version) => {
+                *self.requests.lock().unwrap().entry(version).or_insert(0) += 1;
+                Ok(version)
+            }
+            Err(e) => {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn metrics(&self) -> ProtocolMetrics {
+        ProtocolMetrics {
+            served: SERVED.to_vec(),
+            requests: self.requests.lock().unwrap().clone(),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(None).unwrap(), LEGACY_VERSION);
+        assert_eq!(negotiate(Some("4")).unwrap(), 4);
+        assert_eq!(negotiate(Some(" 5")).unwrap(), 5);
+        assert!(negotiate(Some("3")).is_err());
+        assert!(negotiate(Some("6")).is_err());
+        assert!(negotiate(Some("five")).is_err());
+    }
+
+    #[test]
+    fn test_metrics() {
+        let versions = ProtocolVersions::default();
+        let mut headers = HeaderMap::new();
+        versions.negotiate(&headers).unwrap();
+        headers.insert(VERSION_HEADER, "5".parse().unwrap());
+        versions.negotiate(&headers).unwrap();
+        versions.negotiate(&headers).unwrap();
+        headers.insert(VERSION_HEADER, "6".parse().unwrap());
+        assert!(versions.negotiate(&headers).is_err());
+
+        let metrics = versions.metrics();
+        assert_eq!(metrics.served, SERVED);
+        assert_eq!(metrics.requests.get(&4), Some(&1));
+        assert_eq!(metrics.requests.get(&5), Some(&2));
+        assert_eq!(metrics.rejected, 1);
+    }
+
+    #[test]
+    fn test_is_protocol_path() {
+        assert!(is_protocol_path("/tenant/t/portfolio/p/project/r/code"));
+        assert!(is_protocol_path(
+            "/tenant/t/portfolio/p/project/r/code/.atomic"
+        ));
+        assert!(!is_protocol_path("/tenant/t/portfolio/p/project/r/clone"));
+        assert!(!is_protocol_path("/health"));
+    }
+}
@@ -0,0 +1,436 @@
+//! Webhook notifications of repository activity
+//!
+//! External CI and chat systems react to what happens in the repositories
+//! through webhooks: the events of the bus (see `atomic_config::events`)
+//! are POSTed as JSON to each configured URL, with the event name in
+//! `X-Atomic-Event` and a unique delivery ID in `X-Atomic-Delivery`.
+//! By default changes and tags applied (`node_applied`), tags created
+//! (`tag_created`) and workflow transitions (`workflow_transition`) are
+//! sent.
+//!
+//! With a secret, the body is signed with HMAC-SHA256, and the signature
+//! sent as `X-Atomic-Signature: sha256=<hex>`, like GitHub does, so that
+//! receivers can check the events come from the server.
+//!
+//! Deliveries go through a bounded queue, one at a time. A delivery that
+//! fails (a network error, or a response that isn't `2xx`) is retried
+//! with an exponential backoff, up to a number of attempts, then dropped.
+//! Events published while the queue is full are dropped too. Counters are
+//! served by `GET /metrics/webhooks`.
+
+use atomic_config::events::{Event, EventBus};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// Header of the event name
+pub const EVENT_HEADER: &str = "X-Atomic-Event";
+/// Header of the delivery ID, the same for all the attempts of a delivery
+pub const DELIVERY_HEADER: &str = "X-Atomic-Delivery";
+/// Header of the signature of the body, if a secret is configured
+pub const SIGNATURE_HEADER: &str = "X-Atomic-Signature";
+
+/// Events sent unless `ATOMIC_API_WEBHOOK_EVENTS` is set
+const DEFAULT_EVENTS: [&str; 3] = ["node_applied", "tag_created", "workflow_transition"];
+/// Default number of attempts of a delivery
+const DEFAULT_ATTEMPTS: u32 = 5;
+/// Default delay before the first retry, doubled at each retry
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(2);
+/// Default timeout of a delivery
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default number of deliveries waiting
+const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+/// Configuration of the webhooks
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// URLs receiving the events
+    pub urls: Vec<String>,
+    /// Secret signing the bodies, if any
+    pub secret: Option<String>,
+    /// Names of the events sent
+    pub events: BTreeSet<String>,
+    /// Attempts of a delivery before it is dropped
+    pub attempts: u32,
+    /// Delay before the first retry, doubled at each retry
+    pub backoff: Duration,
+    /// Timeout of each attempt
+    pub timeout: Duration,
+    /// Deliveries waiting, at most
+    pub capacity: usize,
+}
+
+impl WebhookConfig {
+    /// Send the default events to `urls`
+    pub fn new(urls: Vec<String>) -> Self {
+        WebhookConfig {
+            urls,
+            secret: None,
+            events: DEFAULT_EVENTS.iter().map(|e| e.to_string()).collect(),
+            attempts: DEFAULT_ATTEMPTS,
+            backoff: DEFAULT_BACKOFF,
+            timeout: DEFAULT_TIMEOUT,
+            capacity: DEFAULT_QUEUE_CAPACITY,
+        }
+    }
+
+    /// Read the configuration if `ATOMIC_API_WEBHOOK_URLS` (comma-separated)
+    /// is set. The secret is read from `ATOMIC_API_WEBHOOK_SECRET`, the
+    /// events (comma-separated, `*` for all) from
+    /// `ATOMIC_API_WEBHOOK_EVENTS`, the attempts from
+    /// `ATOMIC_API_WEBHOOK_ATTEMPTS` and the backoff and timeout (in
+    /// seconds) from `ATOMIC_API_WEBHOOK_BACKOFF` and
+    /// `ATOMIC_API_WEBHOOK_TIMEOUT`.
+    pub fn from_env() -> Option<Self> {
+        let urls = list(&std::env::var("ATOMIC_API_WEBHOOK_URLS").ok()?);
+        if urls.is_empty() {
+            return None;
+        }
+        let mut config = Self::new(urls);
+        config.secret = std::env::var("ATOMIC_API_WEBHOOK_SECRET")
+            .ok()
+            .filter(|s| !s.is_empty());
+        if let Ok(events) = std::env::var("ATOMIC_API_WEBHOOK_EVENTS") {
+            config.events = list(&events).into_iter().collect();
+        }
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name).ok().and_then(|s| s.parse().ok())
+        }
+        config.attempts = var("ATOMIC_API_WEBHOOK_ATTEMPTS").unwrap_or(DEFAULT_ATTEMPTS);
+        config.backoff =
+            var("ATOMIC_API_WEBHOOK_BACKOFF").map_or(DEFAULT_BACKOFF, Duration::from_secs);
+        config.timeout =
+            var("ATOMIC_API_WEBHOOK_TIMEOUT").map_or(DEFAULT_TIMEOUT, Duration::from_secs);
+        Some(config)
+    }
+
+    /// Whether events named `name` are sent
+    pub fn sends(&self, name: &str) -> bool {
+        self.events.contains("*") || self.events.contains(name)
+    }
+}
+
+fn list(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Body of a delivery
+#[derive(Debug, Clone, Serialize)]
+pub struct Payload {
+    /// Delivery ID, also sent in `X-Atomic-Delivery`
+    pub id: String,
+    pub timestamp: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// Counters of the deliveries, served by `GET /metrics/webhooks`
+#[derive(Debug, Default, Serialize)]
+pub struct WebhookMetrics {
+    /// Deliveries waiting in the queue
+    pub queued: usize,
+    /// Deliveries that succeeded
+    pub delivered: u64,
+    /// Failed attempts that were retried
+    pub retried: u64,
+    /// Deliveries dropped after their last attempt
+    pub failed: u64,
+    /// Events dropped because the queue was full
+    pub dropped: u64,
+}
+
+/// An event to send to one URL
+#[derive(Debug)]
+struct Delivery {
+    id: String,
+    event: String,
+    url: String,
+    body: bytes::Bytes,
+    signature: Option<String>,
+    attempt: u32,
+}
+
+struct Inner {
+    config: WebhookConfig,
+    /// Repositories are sent relative to this path
+    base: PathBuf,
+    sender: mpsc::Sender<Delivery>,
+    receiver: Mutex<Option<mpsc::Receiver<Delivery>>>,
+    delivered: AtomicU64,
+    retried: AtomicU64,
+    failed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+/// The webhook dispatcher
+#[derive(Clone)]
+pub struct Webhooks {
+    inner: Arc<Inner>,
+}
+
+impl Webhooks {
+    /// Send events to the webhooks of `config`, with the repositories
+    /// relative to `base`, the base mount path of the server
+    pub fn new(config: WebhookConfig, base: impl Into<PathBuf>) -> Self {
+        let (sender, receiver) = mpsc::channel(config.capacity.max(1));
+        Webhooks {
+            inner: Arc::new(Inner {
+                config,
+                base: base.into(),
+                sender,
+                receiver: Mutex::new(Some(receiver)),
+                delivered: AtomicU64::new(0),
+                retried: AtomicU64::new(0),
+                failed: AtomicU64::new(0),
+                dropped: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    pub fn config(&self) -> &WebhookConfig {
+        &self.inner.config
+    }
+
+    /// Send the events published on `bus` from now on
+    pub fn subscribe(&self, bus: &EventBus) {
+        let webhooks = self.clone();
+        bus.subscribe(move |event| webhooks.send(event));
+    }
+
+    /// Queue `event` for all the URLs, if it is one of the events sent
+    pub fn send(&self, event: &Event) {
+        let Some(payload) = self.payload(event) else {
+            return;
+        };
+        let name = crate::event_log::name(event);
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => bytes::Bytes::from(body),
+            Err(e) => {
+                warn!("Failed to serialize webhook event {}: {}", name, e);
+                return;
+            }
+        };
+        let signature = self.inner.config.secret.as_ref().map(|s| sign(s, &body));
+        for url in self.inner.config.urls.iter() {
+            let delivery = Delivery {
+                id: payload.id.clone(),
+                event: name.clone(),
+                url: url.clone(),
+                body: body.clone(),
+                signature: signature.clone(),
+                attempt: 1,
+            };
+            if self.inner.sender.try_send(delivery).is_err() {
+                self.inner.dropped.fetch_add(1, Ordering::Relaxed);
+                warn!("Webhook queue full, dropping {} event for {}", name, url);
+            }
+        }
+    }
+
+    /// The body sent for `event`, if it is one of the events sent
+    pub fn payload(&self, event: &Event) -> Option<Payload> {
+        if !self.inner.config.sends(&crate::event_log::name(event)) {
+            return None;
+        }
+        Some(Payload {
+            id: Uuid::new_v4().to_string(),
+            timestamp: Utc::now(),
+            event: crate::event_log::relative(event.clone(), &self.inner.base),
+        })
+    }
+
+    /// Start the dispatcher. Must be called once, from within a Tokio
+    /// runtime.
+    pub fn start(&self) {
+        let Some(mut receiver) = self.inner.receiver.lock().unwrap().take() else {
+            return;
+        };
+        info!("Sending webhooks to {}", self.inner.config.urls.join(", "));
+        let webhooks = self.clone();
+        tokio::spawn(async move {
+            let client = reqwest::Client::new();
+            while let Some(delivery) = receiver.recv().await {
+                webhooks.deliver(&client, delivery).await
+            }
+        });
+    }
+
+    async fn deliver(&self, client: &reqwest::Client, mut delivery: Delivery) {
+        let config = &self.inner.config;
+        let mut request = client
+            .post(&delivery.url)
+            .timeout(config.timeout)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, &delivery.event)
+            .header(DELIVERY_HEADER, &delivery.id)
+            .body(delivery.body.clone());
+        if let Some(ref signature) = delivery.signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+        let error = match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                debug!("Delivered {} to {}", delivery.id, delivery.url);
+                self.inner.delivered.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            Ok(response) => format!("status {}", response.status()),
+            Err(e) => e.to_string(),
+        };
+        if delivery.attempt >= config.attempts {
+            warn!(
+                "Dropping delivery {} to {} after {} attempts: {}",
+                delivery.id, delivery.url, delivery.attempt, error
+            );
+            self.inner.failed.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let delay = config
+            .backoff
+            .saturating_mul(2u32.saturating_pow(delivery.attempt - 1));
+        warn!(
+            "Delivery {} to {} failed ({}), retrying in {}s",
+            delivery.id,
+            delivery.url,
+            error,
+            delay.as_secs()
+        );
+        self.inner.retried.fetch_add(1, Ordering::Relaxed);
+        delivery.attempt += 1;
+        // The retry waits outside the dispatcher, which goes on with the
+        // other deliveries.
+        let sender = self.inner.sender.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            sender.send(delivery).await.unwrap_or(())
+        });
+    }
+
+    pub fn metrics(&self) -> WebhookMetrics {
+        let sender = &self.inner.sender;
+        WebhookMetrics {
+            queued: sender.max_capacity() - sender.capacity(),
+            delivered: self.inner.delivered.load(Ordering::Relaxed),
+            retried: self.inner.retried.load(Ordering::Relaxed),
+            failed: self.inner.failed.load(Ordering::Relaxed),
+            dropped: self.inner.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Signature of `body` with `secret`, as sent in `X-Atomic-Signature`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = ring::hmac::sign(&key, body);
+    let mut signature = String::from("sha256=");
+    for b in tag.as_ref() {
+        signature.push_str(&format!("{:02x}", b))
+    }
+    signature
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_config::events::NodeKind;
+    use axum::http::{HeaderMap, StatusCode};
+
+    fn applied() -> Event {
+        Event::NodeApplied {
+            repository: PathBuf::from("/mnt/repos/t/p/x"),
+            channel: "main".to_string(),
+            kind: NodeKind::Change,
+            hash: "HASH".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign("key", b"The quick brown fox jumps over the lazy dog"),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn test_payload() {
+        let webhooks = Webhooks::new(
+            WebhookConfig::new(vec!["http://ci".to_string()]),
+            "/mnt/repos",
+        );
+        let payload = serde_json::to_value(webhooks.payload(&applied()).unwrap()).unwrap();
+        assert_eq!(payload["event"], "node_applied");
+        assert_eq!(payload["repository"], "t/p/x");
+        assert_eq!(payload["hash"], "HASH");
+        assert!(payload["id"].is_string());
+
+        let diverged = Event::RemoteDiverged {
+            repository: PathBuf::from("/mnt/repos/t/p/x"),
+            remote: "origin".to_string(),
+            channel: "main".to_string(),
+            unrecorded: Vec::new(),
+        };
+        assert!(webhooks.payload(&diverged).is_none());
+
+        let mut config = WebhookConfig::new(Vec::new());
+        config.events = ["*".to_string()].into_iter().collect();
+        assert!(config.sends("remote_diverged"));
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        use axum::routing::post;
+        use std::sync::atomic::AtomicUsize;
+
+        // Fails the first attempt, and records the signatures.
+        let calls = Arc::new(AtomicUsize::new(0));
+        let signatures = Arc::new(Mutex::new(Vec::new()));
+        let (calls_, signatures_) = (calls.clone(), signatures.clone());
+        let app = axum::Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: bytes::Bytes| async move {
+                let signature = headers[SIGNATURE_HEADER].to_str().unwrap().to_string();
+                signatures_.lock().unwrap().push((signature, body));
+                if calls_.fetch_add(1, Ordering::SeqCst) == 0 {
+                    StatusCode::INTERNAL_SERVER_ERROR
+                } else {
+                    StatusCode::OK
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let mut config = WebhookConfig::new(vec![format!("http://{}/hook", addr)]);
+        config.secret = Some("secret".to_string());
+        config.backoff = Duration::from_millis(10);
+        let webhooks = Webhooks::new(config, "/mnt/repos");
+        webhooks.start();
+        webhooks.send(&applied());
+
+        for _ in 0..200 {
+            if webhooks.metrics().delivered == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let metrics = webhooks.metrics();
+        assert_eq!((metrics.delivered, metrics.retried), (1, 1));
+        let signatures = signatures.lock().unwrap();
+        assert_eq!(signatures.len(), 2);
+        for (signature, body) in signatures.iter() {
+            assert_eq!(*signature, sign("secret", body));
+        }
+    }
+}
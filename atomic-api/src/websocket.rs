@@ -2,15 +2,28 @@
 //!
 //! Following AGENTS.md patterns for configuration-driven design and error handling.
 //! This provides the WebSocket infrastructure that will be extended by the atomic-workflow crate.
+//!
+//! With `ATOMIC_API_AUTH_FILE`, the upgrade requests are authenticated with
+//! the credentials of the REST routes (see [`crate::auth`]), and need the
+//! `read:changes` scope, granted for all tenants.
 
-use crate::message::{Message, MessageHandler, MessagePayload, MessageRouter};
+use crate::auth::{AuthConfig, Principal, Scope};
+use crate::message::{Capabilities, Message, MessageHandler, MessagePayload, MessageRouter};
+use crate::shutdown::Shutdown;
 use crate::{ApiError, ApiResult};
 use anyhow::Result;
+use axum::http::header::WWW_AUTHENTICATE;
+use axum::response::IntoResponse;
 use futures_util::{SinkExt, StreamExt};
 use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
 use tokio::sync::RwLock;
-use tokio_tungstenite::{accept_async, tungstenite::protocol::Message as WsMessage};
+use tokio::task::JoinSet;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::{accept_hdr_async, tungstenite::protocol::Message as WsMessage};
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
@@ -27,6 +40,8 @@ pub struct WebSocketConnection {
     pub addr: SocketAddr,
     /// Connection metadata
     pub metadata: HashMap<String, String>,
+    /// Protocol version and features negotiated by the client's hello
+    pub capabilities: Capabilities,
 }
 
 impl WebSocketConnection {
@@ -38,6 +53,7 @@ impl WebSocketConnection {
             session_id: None,
             addr,
             metadata: HashMap::new(),
+            capabilities: Capabilities::default(),
         }
     }
 
@@ -68,6 +84,8 @@ pub struct ServerState {
     pub connections: Arc<RwLock<HashMap<Uuid, WebSocketConnection>>>,
     /// Server configuration
     pub config: ServerConfig,
+    /// Credentials and scopes of the upgrade requests, if authenticated
+    pub auth: Option<Arc<AuthConfig>>,
 }
 
 /// Server configuration following AGENTS.md configuration-driven design
@@ -101,6 +119,7 @@ impl ServerState {
             message_router: Arc::new(RwLock::new(MessageRouter::new())),
             connections: Arc::new(RwLock::new(HashMap::new())),
             config,
+            auth: None,
         }
     }
 
@@ -137,6 +156,13 @@ impl ServerState {
         connection_id
     }
 
+    /// Record the capabilities negotiated by a connection
+    pub async fn set_capabilities(&self, connection_id: Uuid, capabilities: Capabilities) {
+        if let Some(connection) = self.connections.write().await.get_mut(&connection_id) {
+            connection.capabilities = capabilities;
+        }
+    }
+
     /// Remove connection from tracking
     pub async fn remove_connection(&self, connection_id: Uuid) {
         let mut connections = self.connections.write().await;
@@ -158,6 +184,10 @@ pub struct WebSocketServer {
     state: ServerState,
     /// Server bind address
     bind_addr: String,
+    /// TLS termination, for `wss://` connections
+    tls: Option<crate::tls::Tls>,
+    /// Closes the sessions and stops the server when it starts
+    shutdown: Shutdown,
 }
 
 impl WebSocketServer {
@@ -166,9 +196,30 @@ impl WebSocketServer {
         Self {
             state: ServerState::new(config),
             bind_addr: bind_addr.into(),
+            tls: None,
+            shutdown: Shutdown::default(),
         }
     }
 
+    /// Accept `wss://` connections instead of plain `ws://` ones
+    pub fn with_tls(mut self, tls: crate::tls::Tls) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Authenticate the upgrade requests with `config`, as the REST routes
+    pub fn with_auth(mut self, config: AuthConfig) -> Self {
+        self.state.auth = Some(Arc::new(config));
+        self
+    }
+
+    /// Close the sessions with a "going away" frame and stop accepting
+    /// connections when `shutdown` starts
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
     /// Get server state for external configuration
     pub fn state(&self) -> &ServerState {
         &self.state
@@ -186,10 +237,23 @@ impl WebSocketServer {
         info!("WebSocket server listening on {}", self.bind_addr);
         info!("Max connections: {}", self.state.config.max_connections);
 
-        while let Ok((stream, addr)) = listener.accept().await {
+        let acceptor = self.tls.as_ref().map(|tls| tls.acceptor(&[]));
+
+        let mut sessions = JoinSet::new();
+        loop {
+            let (stream, addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(_) => break,
+                },
+                _ = self.shutdown.draining() => break,
+            };
+            while sessions.try_join_next().is_some() {}
             let state = self.state.clone();
+            let acceptor = acceptor.clone();
+            let shutdown = self.shutdown.clone();
 
-            tokio::spawn(async move {
+            sessions.spawn(async move {
                 // Check connection limits
                 let current_connections = state.connection_count().await;
                 if current_connections >= state.config.max_connections {
@@ -201,32 +265,122 @@ impl WebSocketServer {
                 }
 
                 // Handle the connection
-                if let Err(e) = handle_connection(stream, addr, state).await {
+                let result = if let Some(acceptor) = acceptor {
+                    match acceptor.accept(stream).await {
+                        Ok(stream) => handle_connection(stream, addr, state, shutdown).await,
+                        Err(e) => {
+                            debug!("TLS handshake with {} failed: {}", addr, e);
+                            return;
+                        }
+                    }
+                } else {
+                    handle_connection(stream, addr, state, shutdown).await
+                };
+                if let Err(e) = result {
                     error!("WebSocket connection error from {}: {}", addr, e);
                 }
             });
         }
 
+        // Sessions close themselves once the shutdown starts.
+        info!(
+            "WebSocket server stopping, closing {} sessions",
+            sessions.len()
+        );
+        while sessions.join_next().await.is_some() {}
         Ok(())
     }
 }
 
+/// Authenticate the upgrade request of a connection, answering it with
+/// the status and challenge of the REST routes if it is rejected. Sessions
+/// only read, so they need the `read:changes` scope, but they aren't bound
+/// to a tenant, so they need it for all tenants.
+fn authenticate_upgrade(
+    config: &AuthConfig,
+    request: &Request,
+) -> Result<Principal, ErrorResponse> {
+    config
+        .authenticate(request.headers())
+        .and_then(|p| {
+            p.check(Scope::ReadChanges)?;
+            p.check_tenant(None)?;
+            Ok(p)
+        })
+        .map_err(|e| {
+            let message = e.to_string();
+            let response = e.into_response();
+            let mut rejection = ErrorResponse::new(Some(message));
+            *rejection.status_mut() = response.status();
+            if let Some(challenge) = response.headers().get(WWW_AUTHENTICATE) {
+                rejection
+                    .headers_mut()
+                    .insert(WWW_AUTHENTICATE, challenge.clone());
+            }
+            rejection
+        })
+}
+
 /// Handle individual WebSocket connection following AGENTS.md error handling patterns
-async fn handle_connection(stream: TcpStream, addr: SocketAddr, state: ServerState) -> Result<()> {
+async fn handle_connection<S>(
+    stream: S,
+    addr: SocketAddr,
+    state: ServerState,
+    shutdown: Shutdown,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     debug!("New WebSocket connection from {}", addr);
 
-    // Accept WebSocket connection
-    let ws_stream = accept_async(stream).await?;
+    // Accept WebSocket connection, if its upgrade request is authenticated
+    let mut principal = None;
+    let ws_stream = accept_hdr_async(
+        stream,
+        |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+            if let Some(ref config) = state.auth {
+                let authenticated = authenticate_upgrade(config, request).map_err(|rejection| {
+                    warn!("Rejected WebSocket connection from {}", addr);
+                    rejection
+                })?;
+                principal = Some(authenticated);
+            }
+            Ok(response)
+        },
+    )
+    .await?;
     info!("WebSocket connection established from {}", addr);
 
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
     // Create connection tracking
-    let connection = WebSocketConnection::new(addr);
+    let mut connection = WebSocketConnection::new(addr);
+    if let Some(principal) = principal {
+        connection = connection.with_user_id(principal.name);
+    }
     let connection_id = state.add_connection(connection).await;
-
-    // Handle incoming messages
-    while let Some(msg) = ws_receiver.next().await {
+    // Until the client says hello.
+    let mut capabilities = Capabilities::default();
+
+    // Handle incoming messages, until the client leaves or the server
+    // shuts down
+    loop {
+        let msg = tokio::select! {
+            msg = ws_receiver.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            _ = shutdown.draining() => {
+                let frame = CloseFrame {
+                    code: CloseCode::Away,
+                    reason: "Server shutting down".into(),
+                };
+                if let Err(e) = ws_sender.send(WsMessage::Close(Some(frame))).await {
+                    debug!("Failed to send close frame to {}: {}", addr, e);
+                }
+                break;
+            }
+        };
         match msg {
             Ok(WsMessage::Text(text)) => {
                 debug!("Received text message from {}: {}", addr, text);
@@ -234,10 +388,41 @@ async fn handle_connection(stream: TcpStream, addr: SocketAddr, state: ServerSta
                 // Parse message using configuration-driven approach
                 match serde_json::from_str::<Message>(&text) {
                     Ok(message) => {
-                        // Route message through configured handlers
-                        let response = {
+                        let response = if let MessagePayload::Hello(ref hello) = message.payload {
+                            // Negotiate the version and features of the
+                            // connection
+                            let negotiated = {
+                                let router = state.message_router.read().await;
+                                router.negotiate(hello).map(|n| {
+                                    let welcome = router.welcome(&n);
+                                    (n, welcome)
+                                })
+                            };
+                            match negotiated {
+                                Ok((negotiated, welcome)) => {
+                                    debug!(
+                                        "{} ({}) negotiated version {} with features {:?}",
+                                        addr,
+                                        hello.client.as_deref().unwrap_or("unknown client"),
+                                        negotiated.version,
+                                        negotiated.features
+                                    );
+                                    capabilities = negotiated;
+                                    state
+                                        .set_capabilities(connection_id, capabilities.clone())
+                                        .await;
+                                    Ok(Some(
+                                        message
+                                            .reply(MessagePayload::Welcome(welcome))
+                                            .with_version(capabilities.version),
+                                    ))
+                                }
+                                Err(e) => Err(e),
+                            }
+                        } else {
+                            // Route message through configured handlers
                             let mut router = state.message_router.write().await;
-                            router.route_message(message).await
+                            router.route_message_for(&capabilities, message).await
                         };
 
                         match response {
@@ -259,10 +444,11 @@ async fn handle_connection(stream: TcpStream, addr: SocketAddr, state: ServerSta
                                 let error_response = Message::new(MessagePayload::Error(
                                     crate::message::ErrorMessage {
                                         error: format!("Server error: {}", e),
-                                        code: Some("SERVER_ERROR".to_string()),
+                                        code: Some(e.code().to_string()),
                                         details: None,
                                     },
-                                ));
+                                ))
+                                .with_version(capabilities.version);
 
                                 let error_text = serde_json::to_string(&error_response)?;
                                 if let Err(e) = ws_sender.send(WsMessage::Text(error_text)).await {
@@ -281,7 +467,8 @@ async fn handle_connection(stream: TcpStream, addr: SocketAddr, state: ServerSta
                                 error: "Invalid message format".to_string(),
                                 code: Some("INVALID_MESSAGE".to_string()),
                                 details: Some(serde_json::json!({"parse_error": e.to_string()})),
-                            }));
+                            }))
+                            .with_version(capabilities.version);
 
                         let error_text = serde_json::to_string(&error_response)?;
                         if let Err(e) = ws_sender.send(WsMessage::Text(error_text)).await {
@@ -460,6 +647,50 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_authenticate_upgrade() {
+        let config: AuthConfig = toml::from_str(&format!(
+            r#"
+            [[tokens]]
+            name = "reader"
+            sha256 = "{}"
+            scopes = ["read:changes"]
+            tenants = ["*"]
+
+            [[tokens]]
+            name = "pusher"
+            sha256 = "{}"
+            scopes = ["write:push"]
+            tenants = ["*"]
+
+            [[tokens]]
+            name = "tenant"
+            sha256 = "{}"
+            scopes = ["read:changes"]
+            tenants = ["tenant-1"]
+            "#,
+            crate::auth::sha256("read"),
+            crate::auth::sha256("push"),
+            crate::auth::sha256("tenant")
+        ))
+        .unwrap();
+        let upgrade = |token: Option<&str>| {
+            let mut request = Request::builder().uri("/");
+            if let Some(token) = token {
+                request = request.header("authorization", format!("Bearer {}", token));
+            }
+            authenticate_upgrade(&config, &request.body(()).unwrap())
+        };
+
+        assert_eq!(upgrade(Some("read")).unwrap().name, "reader");
+        let missing = upgrade(None).unwrap_err();
+        assert_eq!(missing.status(), 401);
+        assert!(missing.headers().contains_key(WWW_AUTHENTICATE));
+        assert_eq!(upgrade(Some("unknown")).unwrap_err().status(), 401);
+        assert_eq!(upgrade(Some("push")).unwrap_err().status(), 403);
+        assert_eq!(upgrade(Some("tenant")).unwrap_err().status(), 403);
+    }
+
     #[test]
     fn test_handler_message_types() {
         let handler = HealthCheckHandler;
@@ -0,0 +1,668 @@
+//! Workflow operations on changes
+//!
+//! Frontends move changes through their workflows (submit, approve,
+//! reject…) without the CLI:
+//!
+//! - `GET .../code/changes/:change_id/workflow/state?workflow=<name>`
+//!   returns the state of a workflow on a change, its approvals and the
+//!   transitions leaving that state,
+//! - `POST .../code/changes/:change_id/workflow/transition` fires the
+//!   transition of a workflow triggered by `trigger`,
+//! - `POST .../code/changes/:change_id/workflow/evaluate` takes the same
+//!   request and tells whether the transition would succeed, with every
+//!   role, quorum and guard it checks, without firing it.
+//!
+//! Workflows are looked up by name in the [`WorkflowRegistry`] of the
+//! server, the workflows of `atomic-workflows` by default. States are
+//! stored in the pristine (see [`atomic_workflows::pristine`]), and every
+//! event is appended to the workflow log of the repository (see
+//! [`atomic_workflows::audit`]).
+//!
+//! Channels can be protected by `[[protect]]` rules of the repository
+//! configuration: applies to a protected channel are rejected unless the
+//! change is in the state the rule requires ("Approved" by default), and
+//! so are tags of the channel covering changes that aren't (see
+//! [`check_protected`] and [`check_protected_tag`]).
+//!
+//! Channels can also start a workflow on their new changes, with
+//! `[[bootstrap]]` rules: after a change is applied to such a channel,
+//! the workflow of each rule is started on it in the rule's state (its
+//! initial state by default), and the code owners of the paths it
+//! touches, from the `CODEOWNERS` file of the channel (see
+//! [`crate::codeowners`]), are assigned as its reviewers. Both events are
+//! logged like transitions (see [`bootstrap`]).
+//!
+//! The server doesn't authenticate users: the proxy in front of it passes
+//! the author of the request and the roles it has on the repository, and
+//! the transitions check these roles.
+
+use crate::{ApiError, ApiResult};
+use atomic_config::{Author, BootstrapRule, ProtectRule};
+use atomic_repository::Repository;
+use atomic_workflows::{
+    Approval, TransitionDefinition, TransitionEvaluation, WorkflowAuditLog, WorkflowContext,
+    WorkflowEvent, WorkflowMutStore, WorkflowRegistry, WorkflowStore,
+};
+use libatomic::changestore::ChangeStore;
+use libatomic::{Base32, Hash, MutTxnT, TxnTExt};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// Query parameters of `GET .../workflow/state`
+#[derive(Debug, Deserialize)]
+pub struct WorkflowQuery {
+    /// Name of the workflow
+    pub workflow: String,
+}
+
+/// Body of `POST .../workflow/transition`
+#[derive(Debug, Deserialize)]
+pub struct TransitionRequest {
+    /// Name of the workflow
+    pub workflow: String,
+    /// Trigger of the transition, e.g. `submit` or `approve`
+    pub trigger: String,
+    /// Author of the request
+    pub author: Author,
+    /// Roles of the author on the repository
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+/// State of a workflow on a change
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkflowStateResponse {
+    pub workflow: String,
+    pub change_id: String,
+    pub state: String,
+    pub approvals: Vec<Approval>,
+    /// Reviewers assigned to the change, e.g. by a `[[bootstrap]]` rule
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reviewers: Vec<String>,
+    /// Transitions leaving the state
+    pub transitions: Vec<TransitionDefinition>,
+}
+
+/// Result of a transition
+#[derive(Debug, Clone, Serialize)]
+pub struct TransitionResponse {
+    pub event: WorkflowEvent,
+    #[serde(flatten)]
+    pub state: WorkflowStateResponse,
+}
+
+/// Result of a dry run of a transition
+#[derive(Debug, Clone, Serialize)]
+pub struct EvaluationResponse {
+    pub workflow: String,
+    pub change_id: String,
+    #[serde(flatten)]
+    pub evaluation: TransitionEvaluation,
+}
+
+/// Error for workflows missing from `registry`
+fn unknown_workflow(name: &str) -> ApiError {
+    ApiError::invalid_query(format!("Unknown workflow: {}", name))
+}
+
+fn store_error<E: std::fmt::Display>(e: E) -> ApiError {
+    ApiError::internal(format!("Failed to access workflow states: {}", e))
+}
+
+fn state_response(
+    registry: &WorkflowRegistry,
+    workflow: &str,
+    change_id: &str,
+    state: String,
+    approvals: Vec<Approval>,
+    reviewers: Vec<String>,
+) -> ApiResult<WorkflowStateResponse> {
+    let definition = registry
+        .definition(workflow)
+        .ok_or_else(|| unknown_workflow(workflow))?;
+    Ok(WorkflowStateResponse {
+        workflow: workflow.to_string(),
+        change_id: change_id.to_string(),
+        transitions: definition.transitions_from(&state).cloned().collect(),
+        state,
+        approvals,
+        reviewers,
+    })
+}
+
+/// State of `workflow` on `change_id`, its initial state if the change
+/// didn't enter it yet
+pub fn read_state(
+    repository: &Repository,
+    registry: &WorkflowRegistry,
+    workflow: &str,
+    change_id: &str,
+) -> ApiResult<WorkflowStateResponse> {
+    let definition = registry
+        .definition(workflow)
+        .ok_or_else(|| unknown_workflow(workflow))?;
+    let txn = repository
+        .pristine
+        .txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+    let (state, approvals, reviewers) = match txn
+        .get_workflow_state(workflow, change_id)
+        .map_err(store_error)?
+    {
+        Some(state) => (state.state, state.approvals, state.reviewers),
+        None => (definition.initial_state.clone(), Vec::new(), Vec::new()),
+    };
+    state_response(registry, workflow, change_id, state, approvals, reviewers)
+}
+
+/// Fire the transition of `request.workflow` on `change_id` triggered by
+/// `request.trigger`, store the new state and log the event. The caller
+/// holds the lock of the repository.
+pub fn transition(
+    repository: &Repository,
+    registry: &WorkflowRegistry,
+    change_id: &str,
+    request: TransitionRequest,
+) -> ApiResult<TransitionResponse> {
+    let definition = registry
+        .definition(&request.workflow)
+        .ok_or_else(|| unknown_workflow(&request.workflow))?;
+    let mut txn = repository
+        .pristine
+        .mut_txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+    let mut context = txn
+        .load_context(
+            &request.workflow,
+            change_id,
+            request.author,
+            &definition.initial_state,
+        )
+        .map_err(store_error)?;
+    for role in request.roles {
+        context.add_role(role)
+    }
+    let event = registry
+        .fire(&request.workflow, &mut context, &request.trigger)
+        .ok_or_else(|| unknown_workflow(&request.workflow))??;
+    txn.save_context(&request.workflow, &context)
+        .map_err(store_error)?;
+    txn.commit()
+        .map_err(|e| ApiError::internal(format!("Failed to commit transaction: {}", e)))?;
+
+    // The transition is committed, a failure to log it doesn't undo it.
+    if let Err(e) = WorkflowAuditLog::in_dot_dir(&repository.path.join(libatomic::DOT_DIR)).record(
+        &request.workflow,
+        &context,
+        &event,
+    ) {
+        warn!("Failed to log workflow event on {}: {}", change_id, e)
+    }
+    Ok(TransitionResponse {
+        event,
+        state: state_response(
+            registry,
+            &request.workflow,
+            change_id,
+            context.current_state,
+            context.approvals,
+            context.reviewers,
+        )?,
+    })
+}
+
+/// Evaluate the transition of `request.workflow` on `change_id` triggered
+/// by `request.trigger`, without firing it or storing anything
+pub fn evaluate(
+    repository: &Repository,
+    registry: &WorkflowRegistry,
+    change_id: &str,
+    request: TransitionRequest,
+) -> ApiResult<EvaluationResponse> {
+    let definition = registry
+        .definition(&request.workflow)
+        .ok_or_else(|| unknown_workflow(&request.workflow))?;
+    let txn = repository
+        .pristine
+        .txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+    let mut context = txn
+        .load_context(
+            &request.workflow,
+            change_id,
+            request.author,
+            &definition.initial_state,
+        )
+        .map_err(store_error)?;
+    for role in request.roles {
+        context.add_role(role)
+    }
+    let evaluation = registry
+        .evaluate(&request.workflow, &context, &request.trigger)
+        .ok_or_else(|| unknown_workflow(&request.workflow))??;
+    Ok(EvaluationResponse {
+        workflow: request.workflow,
+        change_id: change_id.to_string(),
+        evaluation,
+    })
+}
+
+/// Reject `change_id` if it isn't in the state required by a `[[protect]]`
+/// rule of `config` for `channel`. Changes that never entered the workflow
+/// of a rule are in its initial state.
+pub fn check_protected<T: WorkflowStore>(
+    txn: &T,
+    registry: &WorkflowRegistry,
+    config: &atomic_config::Config,
+    channel: &str,
+    change_id: &str,
+) -> ApiResult<()> {
+    for rule in config.protect.iter().filter(|r| r.channel == channel) {
+        let state = match txn
+            .get_workflow_state(&rule.workflow, change_id)
+            .map_err(store_error)?
+        {
+            Some(state) => state.state,
+            None => registry
+                .definition(&rule.workflow)
+                .map(|d| d.initial_state.clone())
+                .unwrap_or_default(),
+        };
+        if state != rule.state {
+            return Err(not_approved(rule, change_id, state));
+        }
+    }
+    Ok(())
+}
+
+/// Reject a tag of `channel`, named `name`, if one of the changes applied
+/// since its last tag isn't in the state required by a `[[protect]]` rule
+/// of `config` for the channel
+pub fn check_protected_tag<T: TxnTExt + WorkflowStore>(
+    txn: &T,
+    registry: &WorkflowRegistry,
+    config: &atomic_config::Config,
+    channel: &T::Channel,
+    name: &str,
+) -> ApiResult<()> {
+    if !config.protect.iter().any(|r| r.channel == name) {
+        return Ok(());
+    }
+    for entry in txn.reverse_log(channel, None).map_err(log_error)? {
+        let (n, (h, _)) = entry.map_err(log_error)?;
+        if txn.is_tagged(txn.tags(channel), n).map_err(log_error)? {
+            break;
+        }
+        let hash: Hash = h.into();
+        check_protected(txn, registry, config, name, &hash.to_base32())?
+    }
+    Ok(())
+}
+
+/// A workflow started by a `[[bootstrap]]` rule, and its events
+#[derive(Debug, Clone)]
+pub struct Bootstrapped {
+    pub workflow: String,
+    pub context: WorkflowContext,
+    pub events: Vec<WorkflowEvent>,
+}
+
+/// Start the workflows of `rules` on `change_id`, by `author`, and store
+/// their states in `txn`. `reviewers` are assigned to the workflows of
+/// the rules assigning code owners. Workflows already started on the
+/// change are left alone.
+pub fn start_workflows<'a, T: WorkflowMutStore, I: IntoIterator<Item = &'a BootstrapRule>>(
+    txn: &mut T,
+    registry: &WorkflowRegistry,
+    rules: I,
+    change_id: &str,
+    author: &Author,
+    reviewers: &[String],
+) -> ApiResult<Vec<Bootstrapped>> {
+    let mut started = Vec::new();
+    for rule in rules {
+        let definition = registry
+            .definition(&rule.workflow)
+            .ok_or_else(|| unknown_workflow(&rule.workflow))?;
+        if txn
+            .get_workflow_state(&rule.workflow, change_id)
+            .map_err(store_error)?
+            .is_some()
+        {
+            continue;
+        }
+        let state = rule
+            .state
+            .clone()
+            .unwrap_or_else(|| definition.initial_state.clone());
+        if !definition.states.iter().any(|s| s.id == state) {
+            return Err(ApiError::invalid_query(format!(
+                "Workflow {} has no state {}",
+                rule.workflow, state
+            )));
+        }
+        let mut context = WorkflowContext::new(change_id.to_string(), author.clone(), state);
+        let mut events = vec![WorkflowEvent::Started {
+            state: context.current_state.clone(),
+        }];
+        if rule.codeowners {
+            // Authors don't review their own changes.
+            let reviewers = reviewers.iter().filter(|r| **r != author.username);
+            events.extend(context.assign_reviewers(reviewers.cloned()));
+        }
+        txn.save_context(&rule.workflow, &context)
+            .map_err(store_error)?;
+        started.push(Bootstrapped {
+            workflow: rule.workflow.clone(),
+            context,
+            events,
+        })
+    }
+    Ok(started)
+}
+
+/// Start the workflows of the `[[bootstrap]]` rules of `config` for
+/// `channel` on the change `hash`, just applied to it, and log their
+/// events. The author of the events is the first author of the change.
+/// The caller holds the lock of the repository.
+pub fn bootstrap(
+    repository: &Repository,
+    registry: &WorkflowRegistry,
+    config: &atomic_config::Config,
+    channel: &str,
+    hash: &Hash,
+) -> ApiResult<Vec<Bootstrapped>> {
+    let rules: Vec<_> = config
+        .bootstrap
+        .iter()
+        .filter(|r| r.channel == channel)
+        .collect();
+    bootstrap_rules(repository, registry, &rules, channel, hash)
+}
+
+/// Start the workflows of `rules` on the change `hash`, just applied to
+/// `channel`, and log their events, like [`bootstrap`] does for the
+/// rules of the configuration. The caller holds the lock of the
+/// repository.
+pub fn bootstrap_rules(
+    repository: &Repository,
+    registry: &WorkflowRegistry,
+    rules: &[&BootstrapRule],
+    channel: &str,
+    hash: &Hash,
+) -> ApiResult<Vec<Bootstrapped>> {
+    if rules.is_empty() {
+        return Ok(Vec::new());
+    }
+    let change_id = hash.to_base32();
+    let change = repository
+        .changes
+        .get_change(hash)
+        .map_err(|e| ApiError::internal(format!("Failed to read change: {}", e)))?;
+    let author = change_author(change.hashed.header.authors.first());
+
+    let txn = repository
+        .pristine
+        .arc_txn_begin()
+        .map_err(|e| ApiError::internal(format!("Failed to begin transaction: {}", e)))?;
+    let reviewers = if rules.iter().any(|r| r.codeowners) {
+        let channel_ref = crate::server::load_listed_channel(&*txn.read(), Some(channel))?;
+        crate::codeowners::CodeOwners::read(&repository.changes, &txn, &channel_ref)?
+            .owners_of(change.changes.iter().map(|h| h.path()))
+    } else {
+        Vec::new()
+    };
+    let started = start_workflows(
+        &mut *txn.write(),
+        registry,
+        rules.iter().copied(),
+        &change_id,
+        &author,
+        &reviewers,
+    )?;
+    txn.commit()
+        .map_err(|e| ApiError::internal(format!("Failed to commit transaction: {}", e)))?;
+
+    // The workflows are started, a failure to log it doesn't undo it.
+    let log = WorkflowAuditLog::in_dot_dir(&repository.path.join(libatomic::DOT_DIR));
+    for b in started.iter() {
+        for event in b.events.iter() {
+            if let Err(e) = log.record(&b.workflow, &b.context, event) {
+                warn!("Failed to log workflow event on {}: {}", change_id, e)
+            }
+        }
+    }
+    Ok(started)
+}
+
+/// The author of workflow events of a change by `author`
+pub(crate) fn change_author(author: Option<&libatomic::change::Author>) -> Author {
+    let field = |key: &str| author.and_then(|a| a.0.get(key)).cloned();
+    Author {
+        username: field("name").or_else(|| field("key")).unwrap_or_default(),
+        display_name: field("full_name").unwrap_or_default(),
+        email: field("email").unwrap_or_default(),
+        origin: String::new(),
+        key_path: None,
+    }
+}
+
+fn log_error<E: std::fmt::Debug>(e: E) -> ApiError {
+    ApiError::internal(format!("Failed to read the log: {:?}", e))
+}
+
+fn not_approved(rule: &ProtectRule, change_id: &str, state: String) -> ApiError {
+    ApiError::Repository(crate::error::RepositoryError::NotApproved {
+        change_id: change_id.to_string(),
+        channel: rule.channel.clone(),
+        workflow: rule.workflow.clone(),
+        state,
+        required: rule.state.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_repository::fixtures::Fixture;
+    use atomic_workflows::WorkflowError;
+
+    fn request(trigger: &str, roles: &[&str]) -> TransitionRequest {
+        TransitionRequest {
+            workflow: "SimpleApproval".to_string(),
+            trigger: trigger.to_string(),
+            author: Author {
+                username: "alice".to_string(),
+                ..Author::default()
+            },
+            roles: roles.iter().map(|r| r.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_submit_then_approve() {
+        let fixture = Fixture::new().unwrap();
+        let repository = &fixture.repo;
+        let registry = WorkflowRegistry::builtin();
+
+        let state = read_state(repository, &registry, "SimpleApproval", "AAAA").unwrap();
+        assert_eq!(state.state, "Recorded");
+        assert_eq!(state.transitions[0].trigger, "submit");
+        assert!(matches!(
+            read_state(repository, &registry, "Unknown", "AAAA"),
+            Err(ApiError::InvalidQuery { .. })
+        ));
+
+        assert!(matches!(
+            transition(repository, &registry, "AAAA", request("submit", &[])),
+            Err(ApiError::Workflow(WorkflowError::NeedRole(_)))
+        ));
+        let submitted = transition(
+            repository,
+            &registry,
+            "AAAA",
+            request("submit", &["developer"]),
+        )
+        .unwrap();
+        assert_eq!(submitted.state.state, "Review");
+        assert!(matches!(
+            transition(
+                repository,
+                &registry,
+                "AAAA",
+                request("submit", &["developer"])
+            ),
+            Err(ApiError::Workflow(WorkflowError::UnknownTrigger { .. }))
+        ));
+        transition(
+            repository,
+            &registry,
+            "AAAA",
+            request("approve", &["reviewer"]),
+        )
+        .unwrap();
+
+        let state = read_state(repository, &registry, "SimpleApproval", "AAAA").unwrap();
+        assert_eq!(state.state, "Approved");
+        assert!(state.transitions.is_empty());
+
+        let log = WorkflowAuditLog::in_dot_dir(&repository.path.join(libatomic::DOT_DIR));
+        let history: Vec<_> = log
+            .history("AAAA")
+            .unwrap()
+            .map(|record| record.unwrap().to_string())
+            .collect();
+        assert_eq!(history, vec!["Recorded -> Review", "Review -> Approved"]);
+    }
+
+    #[test]
+    fn test_evaluate_without_firing() {
+        let fixture = Fixture::new().unwrap();
+        let repository = &fixture.repo;
+        let registry = WorkflowRegistry::builtin();
+
+        let refused = evaluate(repository, &registry, "AAAA", request("submit", &[])).unwrap();
+        assert!(!refused.evaluation.allowed);
+        assert_eq!(refused.evaluation.to, "Review");
+        let allowed = evaluate(
+            repository,
+            &registry,
+            "AAAA",
+            request("submit", &["developer"]),
+        )
+        .unwrap();
+        assert!(allowed.evaluation.allowed);
+        let json = serde_json::to_value(&allowed).unwrap();
+        assert_eq!(json["checks"][0]["check"], "role");
+        assert_eq!(json["checks"][0]["passed"], true);
+
+        let state = read_state(repository, &registry, "SimpleApproval", "AAAA").unwrap();
+        assert_eq!(state.state, "Recorded");
+        assert!(matches!(
+            evaluate(repository, &registry, "AAAA", request("approve", &[])),
+            Err(ApiError::Workflow(WorkflowError::UnknownTrigger { .. }))
+        ));
+    }
+
+    #[test]
+    fn test_protected_channel() {
+        let fixture = Fixture::new().unwrap();
+        let repository = &fixture.repo;
+        let registry = WorkflowRegistry::builtin();
+        let config: atomic_config::Config =
+            toml::from_str("[[protect]]\nchannel = \"main\"\nworkflow = \"SimpleApproval\"\n")
+                .unwrap();
+        let check = |channel: &str| {
+            let txn = repository.pristine.txn_begin().unwrap();
+            check_protected(&txn, &registry, &config, channel, "AAAA")
+        };
+
+        assert!(check("dev").is_ok());
+        let err = check("main").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Repository error: Change 'AAAA' is Recorded in workflow SimpleApproval, \
+             channel 'main' only takes Approved changes"
+        );
+        transition(
+            repository,
+            &registry,
+            "AAAA",
+            request("submit", &["developer"]),
+        )
+        .unwrap();
+        assert!(check("main").is_err());
+        transition(
+            repository,
+            &registry,
+            "AAAA",
+            request("approve", &["reviewer"]),
+        )
+        .unwrap();
+        assert!(check("main").is_ok());
+    }
+
+    #[test]
+    fn test_start_workflows() {
+        let registry = WorkflowRegistry::builtin();
+        let config: atomic_config::Config = toml::from_str(
+            "[[bootstrap]]\nchannel = \"main\"\n\n\
+             [[bootstrap]]\nchannel = \"main\"\nworkflow = \"ReleaseApproval\"\n\
+             codeowners = false\n",
+        )
+        .unwrap();
+        let author = Author {
+            username: "alice".to_string(),
+            ..Author::default()
+        };
+        let reviewers = vec!["alice".to_string(), "bob".to_string()];
+        let mut store = atomic_workflows::MemoryStore::new();
+
+        let started = start_workflows(
+            &mut store,
+            &registry,
+            &config.bootstrap,
+            "AAAA",
+            &author,
+            &reviewers,
+        )
+        .unwrap();
+        assert_eq!(started.len(), 2);
+        assert_eq!(started[0].workflow, "SimpleApproval");
+        assert_eq!(
+            started[0].events,
+            vec![
+                WorkflowEvent::Started {
+                    state: "Recorded".to_string()
+                },
+                WorkflowEvent::ReviewersAssigned {
+                    reviewers: vec!["bob".to_string()]
+                }
+            ]
+        );
+        assert_eq!(started[1].events.len(), 1);
+        let state = store
+            .get_workflow_state("SimpleApproval", "AAAA")
+            .unwrap()
+            .unwrap();
+        assert_eq!(state.reviewers, vec!["bob".to_string()]);
+
+        // Started workflows are left alone.
+        let again = start_workflows(
+            &mut store,
+            &registry,
+            &config.bootstrap,
+            "AAAA",
+            &author,
+            &[],
+        )
+        .unwrap();
+        assert!(again.is_empty());
+
+        let rule = BootstrapRule {
+            state: Some("Nowhere".to_string()),
+            ..config.bootstrap[0].clone()
+        };
+        assert!(start_workflows(&mut store, &registry, [&rule], "BBBB", &author, &[]).is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//! In-process event bus
+//!
+//! Subsystems publish what they did (a node applied by a pull or by the
+//! API server, a tag created, a remote found diverged, a workflow
+//! transition) to a single bus, and programs embedding atomic subscribe
+//! to it once, whichever subsystem produced the event. Subscribers are
+//! called synchronously on the publishing thread, so they should be
+//! quick, or forward events to a channel with [`EventBus::channel`].
+//!
+//! Events carry hashes and states in their base32 form, since this crate
+//! sits below `libatomic`.
+
+use serde_derive::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+
+/// Kind of an applied node
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    Change,
+    Tag,
+}
+
+/// Something that happened in one of atomic's subsystems
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A change or tag was applied to a channel
+    NodeApplied {
+        repository: PathBuf,
+        channel: String,
+        kind: NodeKind,
+        /// Hash of the change, or state of the tag
+        hash: String,
+    },
+    /// A tag was created for a state of a channel
+    TagCreated {
+        repository: PathBuf,
+        channel: String,
+        state: String,
+    },
+    /// A remote no longer has nodes that the local cache of it lists,
+    /// e.g. because another client unrecorded them and force-pushed
+    RemoteDiverged {
+        repository: PathBuf,
+        remote: String,
+        channel: String,
+        /// Hashes of the changes, or states of the tags, gone from the
+        /// remote
+        unrecorded: Vec<String>,
+    },
+    /// A change or tag moved between two states of a workflow
+    WorkflowTransition {
+        workflow: String,
+        kind: NodeKind,
+        /// Hash of the change, or state of the tag
+        change_id: String,
+        from: String,
+        to: String,
+    },
+}
+
+/// Identifies a subscription, to cancel it with [`EventBus::unsubscribe`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+/// Returns `false` once the subscriber should be removed
+type Subscriber = Arc<dyn Fn(&Event) -> bool + Send + Sync>;
+
+/// A set of subscribers. Subsystems publish to the global bus, see
+/// [`global`].
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Mutex<Subscribers>,
+}
+
+#[derive(Default)]
+struct Subscribers {
+    next_id: u64,
+    list: Vec<(SubscriptionId, Subscriber)>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn add(&self, f: Subscriber) -> SubscriptionId {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.next_id += 1;
+        let id = SubscriptionId(subscribers.next_id);
+        subscribers.list.push((id, f));
+        id
+    }
+
+    /// Call `f` on every event published from now on
+    pub fn subscribe<F: Fn(&Event) + Send + Sync + 'static>(&self, f: F) -> SubscriptionId {
+        self.add(Arc::new(move |event| {
+            f(event);
+            true
+        }))
+    }
+
+    /// Receive the events published from now on. The subscription is
+    /// cancelled at the first event published after the receiver is
+    /// dropped.
+    pub fn channel(&self) -> mpsc::Receiver<Event> {
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        self.add(Arc::new(move |event| {
+            sender.lock().unwrap().send(event.clone()).is_ok()
+        }));
+        receiver
+    }
+
+    /// Cancel a subscription. Returns `false` if there was none with this
+    /// id.
+    pub fn unsubscribe(&self, id: SubscriptionId) -> bool {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        let len = subscribers.list.len();
+        subscribers.list.retain(|(i, _)| *i != id);
+        subscribers.list.len() != len
+    }
+
+    /// Call all subscribers on `event`
+    pub fn publish(&self, event: Event) {
+        // Subscribers are called without the lock, so that they can
+        // subscribe or unsubscribe.
+        let subscribers: Vec<(SubscriptionId, Subscriber)> = {
+            let subscribers = self.subscribers.lock().unwrap();
+            subscribers.list.clone()
+        };
+        for (id, f) in subscribers {
+            if !f(&event) {
+                self.unsubscribe(id);
+            }
+        }
+    }
+}
+
+/// The bus atomic's subsystems publish to
+pub fn global() -> &'static EventBus {
+    static BUS: OnceLock<EventBus> = OnceLock::new();
+    BUS.get_or_init(EventBus::new)
+}
+
+/// Publish `event` to the global bus
+pub fn publish(event: Event) {
+    global().publish(event)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(hash: &str) -> Event {
+        Event::NodeApplied {
+            repository: PathBuf::from("/repo"),
+            channel: "main".to_string(),
+            kind: NodeKind::Change,
+            hash: hash.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_subscribe_and_unsubscribe() {
+        let bus = EventBus::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_ = seen.clone();
+        let id = bus.subscribe(move |e| seen_.lock().unwrap().push(e.clone()));
+        bus.publish(applied("A"));
+        assert!(bus.unsubscribe(id));
+        assert!(!bus.unsubscribe(id));
+        bus.publish(applied("B"));
+        assert_eq!(*seen.lock().unwrap(), vec![applied("A")]);
+    }
+
+    #[test]
+    fn test_channel() {
+        let bus = EventBus::new();
+        let receiver = bus.channel();
+        bus.publish(applied("A"));
+        assert_eq!(receiver.try_recv().unwrap(), applied("A"));
+        drop(receiver);
+        bus.publish(applied("B"));
+        assert!(bus.subscribers.lock().unwrap().list.is_empty());
+    }
+}
@@ -9,6 +9,8 @@ use dialoguer::theme;
 use log::debug;
 use serde_derive::{Deserialize, Serialize};
 
+pub mod events;
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Global {
     #[serde(default)]
@@ -23,6 +25,10 @@ pub struct Global {
     pub ai_attribution: AIAttributionConfig,
     #[serde(default)]
     pub prompt: PromptConfig,
+    #[serde(default)]
+    pub change_cache: ChangeCacheConfig,
+    #[serde(default)]
+    pub download: DownloadConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -118,6 +124,45 @@ fn default_prompt_format() -> String {
     "[{channel}]".to_string()
 }
 
+/// Machine-wide cache of the change files downloaded from remotes, shared
+/// by all the clones of a user
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChangeCacheConfig {
+    /// Look for changes in the cache before downloading them
+    #[serde(default)]
+    pub enabled: bool,
+    /// Directory of the cache (default: `atomic/changes` in the user's cache
+    /// directory)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+}
+
+impl ChangeCacheConfig {
+    /// Directory of the cache, or `None` if the cache is disabled
+    pub fn dir(&self) -> Option<PathBuf> {
+        if !self.enabled {
+            return None;
+        }
+        self.path.clone().or_else(|| {
+            let mut dir = dirs_next::cache_dir()?;
+            dir.push(CONFIG_DIR);
+            dir.push("changes");
+            Some(dir)
+        })
+    }
+}
+
+/// Downloads of changes from remotes, in a `[download]` table
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DownloadConfig {
+    /// Changes downloaded at the same time from a remote: connections
+    /// opened to SSH remotes, and requests in flight to HTTP remotes (still
+    /// bounded by their `max_concurrent_requests`). By default, SSH
+    /// remotes use a single connection, and HTTP remotes up to 20 requests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub streams: Option<usize>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Choice {
     #[serde(rename = "auto")]
@@ -232,6 +277,166 @@ pub struct Config {
     pub pager: Option<Choice>,
     #[serde(default)]
     pub ai_attribution: AIAttributionConfig,
+    /// End-of-line and encoding policies, applied in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub normalize: Vec<NormalizeRule>,
+    /// Paths that changes may not add or edit, checked when recording
+    /// and by servers applying changes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forbid: Vec<ForbidRule>,
+    /// Channels only taking changes in a given workflow state, checked by
+    /// servers applying changes and tags
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub protect: Vec<ProtectRule>,
+    /// Channels whose new changes start a workflow, on servers applying
+    /// changes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bootstrap: Vec<BootstrapRule>,
+    /// Channels whose changes are only exchanged encrypted for their
+    /// recipients, which servers store without being able to read them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub confidential: Vec<ConfidentialChannel>,
+    /// Whether to exchange the external links of changes (to issue
+    /// trackers) with remotes on push and pull
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub sync_links: bool,
+    /// Rules on the messages of changes, checked by servers applying
+    /// changes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_policy: Option<MessagePolicy>,
+    /// Paths of a sparse clone, recorded by `atomic clone --path`: pulls
+    /// only download the changes touching them, and only output them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sparse: Vec<String>,
+}
+
+/// A `[[normalize]]` rule of the repository configuration, e.g.
+///
+/// ```toml
+/// [[normalize]]
+/// path = "*.bat"
+/// eol = "crlf"
+/// encoding = "windows-1252"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NormalizeRule {
+    /// Glob matching the paths of the rule
+    pub path: String,
+    /// `lf`, `crlf` or `native`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub eol: Option<String>,
+    /// Text encoding label, such as `utf-8`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+}
+
+/// A `[[forbid]]` rule of the repository configuration, e.g.
+///
+/// ```toml
+/// [[forbid]]
+/// path = "target"
+/// reason = "build artifacts"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ForbidRule {
+    /// Glob matching the forbidden paths, and everything below them
+    pub path: String,
+    /// Explanation shown when a change touches these paths
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// The `[message_policy]` of the repository configuration, e.g.
+///
+/// ```toml
+/// [message_policy]
+/// pattern = "^[A-Z]+-[0-9]+: "
+/// max_length = 72
+/// reason = "messages start with the key of their issue"
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MessagePolicy {
+    /// Regular expression the message of a change must match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    /// Maximum length of the message of a change, in characters
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<usize>,
+    /// Explanation shown when a message doesn't follow the policy
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// A `[[protect]]` rule of the repository configuration, e.g.
+///
+/// ```toml
+/// [[protect]]
+/// channel = "main"
+/// workflow = "SimpleApproval"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProtectRule {
+    /// Name of the protected channel
+    pub channel: String,
+    /// Workflow the changes of the channel go through
+    pub workflow: String,
+    /// State of the workflow a change must be in to enter the channel
+    #[serde(default = "default_protect_state")]
+    pub state: String,
+}
+
+fn default_protect_state() -> String {
+    "Approved".to_string()
+}
+
+/// A `[[bootstrap]]` rule of the repository configuration, starting a
+/// workflow on every new change of a channel, e.g.
+///
+/// ```toml
+/// [[bootstrap]]
+/// channel = "main"
+/// workflow = "SimpleApproval"
+/// state = "Recorded"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BootstrapRule {
+    /// Name of the channel
+    pub channel: String,
+    /// Workflow started on the new changes of the channel
+    #[serde(default = "default_bootstrap_workflow")]
+    pub workflow: String,
+    /// State the workflow starts in, its initial state by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    /// Whether to assign the code owners of the paths of the change, from
+    /// the `CODEOWNERS` file of the channel, as its reviewers
+    #[serde(default = "default_codeowners")]
+    pub codeowners: bool,
+}
+
+fn default_bootstrap_workflow() -> String {
+    "SimpleApproval".to_string()
+}
+
+fn default_codeowners() -> bool {
+    true
+}
+
+/// A `[[confidential]]` channel of the repository configuration, e.g.
+///
+/// ```toml
+/// [[confidential]]
+/// channel = "embargo"
+/// recipients = ["alice", "bob"]
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfidentialChannel {
+    /// Name of the channel
+    pub channel: String,
+    /// Users the changes of the channel are encrypted for, by username or
+    /// public key of their identity. Servers ignore them.
+    #[serde(default)]
+    pub recipients: Vec<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -246,9 +451,77 @@ pub enum RemoteConfig {
         http: String,
         #[serde(default)]
         headers: HashMap<String, RemoteHttpHeader>,
+        /// Connection tuning, in a `[remotes.connection]` table
+        #[serde(default, skip_serializing_if = "HttpConnection::is_default")]
+        connection: HttpConnection,
+        /// Retries of failed requests, in a `[remotes.retry]` table
+        #[serde(default, skip_serializing_if = "HttpRetry::is_default")]
+        retry: HttpRetry,
     },
 }
 
+/// Connection settings of an HTTP remote. Unset fields keep the defaults
+/// of the HTTP client, which suit one-off pushes and pulls; long-running
+/// sync daemons may want longer-lived connections.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct HttpConnection {
+    /// `true` to also use HTTP/2 over plain `http://` URLs (prior
+    /// knowledge), `false` to only use HTTP/1.1. By default, HTTP/2 is
+    /// negotiated over HTTPS.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http2: Option<bool>,
+    /// Requests sent to the remote at the same time, e.g. change uploads
+    /// and downloads (default: 8)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_requests: Option<usize>,
+    /// Idle connections kept open to the remote
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_max_idle: Option<usize>,
+    /// Seconds after which idle connections are closed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_idle_timeout: Option<u64>,
+    /// Interval in seconds of TCP keep-alive probes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_keepalive: Option<u64>,
+    /// Interval in seconds of HTTP/2 pings, sent even on idle connections
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http2_keepalive: Option<u64>,
+}
+
+impl HttpConnection {
+    pub fn is_default(&self) -> bool {
+        *self == HttpConnection::default()
+    }
+}
+
+/// Retries of the requests to an HTTP remote that fail on a network error
+/// or a `5xx`/`429` answer. The delay between two attempts doubles from
+/// `initial_delay` up to `max_delay`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HttpRetry {
+    /// Attempts of each request before giving up, including the first one
+    /// (default: 6)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<u32>,
+    /// Milliseconds before the first retry (default: 1000)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub initial_delay: Option<u64>,
+    /// Maximal milliseconds between two attempts (default: 30000)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_delay: Option<u64>,
+    /// Whether to wait a random part of each delay, between half of it and
+    /// all of it, so that the clients of a server that came back don't all
+    /// retry at once (default: `true`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jitter: Option<bool>,
+}
+
+impl HttpRetry {
+    pub fn is_default(&self) -> bool {
+        *self == HttpRetry::default()
+    }
+}
+
 impl RemoteConfig {
     pub fn name(&self) -> &str {
         match self {
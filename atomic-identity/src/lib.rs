@@ -37,7 +37,7 @@ mod create;
 mod load;
 mod repair;
 
-pub use load::{choose_identity_name, public_key};
+pub use load::{choose_identity_name, is_known_key, public_key};
 use log::warn;
 pub use repair::fix_identities;
 
@@ -5,7 +5,7 @@ use atomic_config as config;
 use libatomic::key::{PublicKey, SecretKey};
 
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::bail;
 use atomic_interaction::Select;
@@ -61,6 +61,24 @@ pub fn secret_key(name: &str) -> Result<SecretKey, anyhow::Error> {
     Ok(secret_key)
 }
 
+/// Whether `key` is the public key of an identity known to the repository
+/// whose `.atomic` directory is `dot_dir`, i.e. downloaded into its
+/// `identities` directory, or of an identity of the current user.
+#[must_use]
+pub fn is_known_key(dot_dir: &Path, key: &str) -> bool {
+    // Identities of the repository are stored under their public key.
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric()) {
+        let known = fs::File::open(dot_dir.join("identities").join(key))
+            .ok()
+            .and_then(|f| serde_json::from_reader::<_, Complete>(f).ok())
+            .is_some_and(|id| id.public_key.key == key);
+        if known {
+            return true;
+        }
+    }
+    Complete::load_all().is_ok_and(|ids| ids.iter().any(|id| id.public_key.key == key))
+}
+
 /// Choose an identity, either through defaults or a user prompt.
 ///
 /// # Errors
@@ -12,17 +12,47 @@
 //! - Backward compatibility with non-attribution remotes
 //! - Configuration-driven attribution sync
 //! - Efficient batching and compression
+//!
+//! ## Exchange with changes
+//!
+//! When both ends support [`ProtocolFeature::Attribution`], pushes and
+//! pulls send the attribution of the changes they transfer, as
+//! [`ChangeAttribution`]s, once the changes are applied. The protocol of
+//! the remote is negotiated when connecting:
+//!
+//! - over HTTP, servers list their [`AttributionProtocol`] under
+//!   `"attribution"` in their discovery answer. Clients download the
+//!   attribution of changes with `GET ?attribution=<hash>,<hash>…` and
+//!   upload it with `POST ?attribution`, as JSON;
+//! - over SSH, servers started with `--version 5` or later add
+//!   `attribution=<version>` to their answers to `state` (see
+//!   [`capability`]), and then take the `attribution` and
+//!   `attributionup` commands (see [`crate::protocol::Command`]);
+//! - local remotes always support it.
+//!
+//! Bundles travel without their patch data, since the change itself is
+//! transferred alongside: the receiving end reads it from its change
+//! store before checking the signature of the bundle, and ignores the
+//! attribution of changes it doesn't have.
 
 use crate::RemoteRepo;
-use anyhow::{anyhow, Result};
+use anyhow::Result;
 use async_trait::async_trait;
+use libatomic::attribution::SanakirjaAttributionStore;
+use libatomic::pristine::sanakirja::Pristine;
+use libatomic::{Base32, Hash, NodeId};
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::path::Path;
 use tokio::time::{timeout, Duration};
 
 // Import attribution types - these will need to be available from libatomic
 pub use libatomic::attribution::{
-    sync::{AttributedPatchBundle, AttributionRemoteSync, RemoteAttributionStats},
+    sync::{
+        AttributedPatchBundle, AttributionProtocol, AttributionRemoteSync, ProtocolFeature,
+        RemoteAttributionStats, VerificationLevel,
+    },
     AttributedPatch, PatchId,
 };
 
@@ -32,6 +62,10 @@ pub const ATTRIBUTION_PROTOCOL_VERSION: u32 = 1;
 /// Timeout for attribution protocol operations
 const ATTRIBUTION_TIMEOUT_SECS: u64 = 30;
 
+/// Capability announced by SSH servers exchanging attribution, followed by
+/// `=` and their protocol version
+pub const CAPABILITY: &str = "attribution";
+
 /// Error type for remote attribution operations
 #[derive(Debug, thiserror::Error)]
 pub enum RemoteAttributionError {
@@ -82,6 +116,9 @@ impl AttributionRemoteExt for RemoteRepo {
     async fn supports_attribution(&mut self) -> Result<bool> {
         match self {
             RemoteRepo::Local(_) => Ok(true), // Local repos always support attribution
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(_) => Ok(true),
+            RemoteRepo::ObjectStore(_) => Ok(false),
             RemoteRepo::LocalChannel(_) => Ok(true),
             RemoteRepo::Ssh(ssh) => ssh.supports_attribution().await,
             RemoteRepo::Http(http) => http.supports_attribution().await,
@@ -92,6 +129,11 @@ impl AttributionRemoteExt for RemoteRepo {
     async fn negotiate_attribution_protocol(&mut self) -> Result<u32> {
         match self {
             RemoteRepo::Local(_) => Ok(ATTRIBUTION_PROTOCOL_VERSION),
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(_) => Ok(ATTRIBUTION_PROTOCOL_VERSION),
+            RemoteRepo::ObjectStore(_) => Err(anyhow::anyhow!(
+                "Object store remotes don't support attribution"
+            )),
             RemoteRepo::LocalChannel(_) => Ok(ATTRIBUTION_PROTOCOL_VERSION),
             RemoteRepo::Ssh(ssh) => ssh
                 .negotiate_attribution_protocol()
@@ -117,6 +159,15 @@ impl AttributionRemoteExt for RemoteRepo {
                 .push_attributed_patches(bundles, channel)
                 .await
                 .map_err(|e| anyhow::anyhow!("{}", e)),
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(git) => git
+                .local
+                .push_attributed_patches(bundles, channel)
+                .await
+                .map_err(|e| anyhow::anyhow!("{}", e)),
+            RemoteRepo::ObjectStore(_) => Err(anyhow::anyhow!(
+                "Object store remotes don't support attribution"
+            )),
             RemoteRepo::LocalChannel(_) => {
                 // For local channels, store attribution in the local database
                 // This would be implemented by the caller using the attribution database
@@ -144,6 +195,13 @@ impl AttributionRemoteExt for RemoteRepo {
                 .pull_attributed_patches(from, channel)
                 .await
                 .map_err(|e| anyhow::anyhow!("{}", e)),
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(git) => git
+                .local
+                .pull_attributed_patches(from, channel)
+                .await
+                .map_err(|e| anyhow::anyhow!("{}", e)),
+            RemoteRepo::ObjectStore(_) => Ok(Vec::new()),
             RemoteRepo::LocalChannel(_) => {
                 // For local channels, load attribution from the local database
                 // This would be implemented by the caller using the attribution database
@@ -167,13 +225,21 @@ impl AttributionRemoteExt for RemoteRepo {
                 .get_remote_attribution_stats(channel)
                 .await
                 .map_err(|e| anyhow::anyhow!("{}", e)),
-            RemoteRepo::LocalChannel(_) => Ok(RemoteAttributionStats {
-                total_patches: 0,
-                ai_assisted_patches: 0,
-                unique_authors: 0,
-                unique_ai_providers: HashSet::new(),
-                last_sync_timestamp: None,
-            }),
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(git) => git
+                .local
+                .get_remote_attribution_stats(channel)
+                .await
+                .map_err(|e| anyhow::anyhow!("{}", e)),
+            RemoteRepo::LocalChannel(_) | RemoteRepo::ObjectStore(_) => {
+                Ok(RemoteAttributionStats {
+                    total_patches: 0,
+                    ai_assisted_patches: 0,
+                    unique_authors: 0,
+                    unique_ai_providers: HashSet::new(),
+                    last_sync_timestamp: None,
+                })
+            }
             RemoteRepo::Ssh(ssh) => ssh
                 .get_remote_attribution_stats(channel)
                 .await
@@ -203,39 +269,20 @@ impl crate::local::Local {
 
 /// Attribution support for SSH remotes
 impl crate::ssh::Ssh {
-    /// Check if SSH remote supports attribution
+    /// Check if SSH remote supports attribution, as announced in its last
+    /// answer to `state`
     pub async fn supports_attribution(&mut self) -> Result<bool> {
-        // Send attribution capability query
-        let query = AttributionProtocolMessage::CapabilityQuery;
-
-        match timeout(
-            Duration::from_secs(ATTRIBUTION_TIMEOUT_SECS),
-            self.send_attribution_message(query),
-        )
-        .await
-        {
-            Ok(Ok(AttributionProtocolMessage::CapabilityResponse { supported })) => Ok(supported),
-            Ok(Ok(_)) => Ok(false),  // Unexpected response
-            Ok(Err(_)) => Ok(false), // Error means no support
-            Err(_) => Ok(false),     // Timeout means no support
-        }
+        Ok(negotiated(
+            self.attribution_protocol().as_ref(),
+            &RemoteAttributionConfig::from_environment(),
+        ))
     }
 
     /// Negotiate attribution protocol version
     pub async fn negotiate_attribution_protocol(&mut self) -> Result<u32> {
-        let request = AttributionProtocolMessage::VersionNegotiation {
-            supported_versions: vec![1],
-        };
-
-        let response = timeout(
-            Duration::from_secs(ATTRIBUTION_TIMEOUT_SECS),
-            self.send_attribution_message(request),
-        )
-        .await??;
-
-        match response {
-            AttributionProtocolMessage::VersionResponse { version } => Ok(version),
-            _ => Err(anyhow!("Invalid response to version negotiation")),
+        match self.attribution_protocol() {
+            Some(remote) => Ok(local_protocol().negotiate(&remote).version),
+            None => Err(RemoteAttributionError::ProtocolNegotiationFailed.into()),
         }
     }
 
@@ -252,43 +299,21 @@ impl crate::ssh::Ssh {
 
 /// Attribution support for HTTP remotes
 impl crate::http::Http {
-    /// Check if HTTP remote supports attribution
+    /// Check if HTTP remote supports attribution, from its discovery
+    /// answer
     pub async fn supports_attribution(&mut self) -> Result<bool> {
-        // Try to access attribution endpoint
-        let url = format!("{}/attribution/capabilities", self.url);
-
-        match timeout(
-            Duration::from_secs(ATTRIBUTION_TIMEOUT_SECS),
-            self.client.get(&url).send(),
-        )
-        .await
-        {
-            Ok(Ok(response)) => Ok(response.status().is_success()),
-            _ => Ok(false), // Any error or timeout means no support
-        }
+        let remote = self.attribution_protocol().await;
+        Ok(negotiated(
+            remote.as_ref(),
+            &RemoteAttributionConfig::from_environment(),
+        ))
     }
 
     /// Negotiate attribution protocol version
     pub async fn negotiate_attribution_protocol(&mut self) -> Result<u32> {
-        let url = format!("{}/attribution/negotiate", self.url);
-        let request = AttributionNegotiationRequest {
-            supported_versions: vec![1],
-        };
-
-        let response = timeout(
-            Duration::from_secs(ATTRIBUTION_TIMEOUT_SECS),
-            self.client.post(&url).json(&request).send(),
-        )
-        .await??;
-
-        if response.status().is_success() {
-            let nego_response: AttributionNegotiationResponse = response.json().await?;
-            Ok(nego_response.version)
-        } else {
-            Err(anyhow!(
-                "Attribution protocol negotiation failed: {}",
-                response.status()
-            ))
+        match self.attribution_protocol().await {
+            Some(remote) => Ok(local_protocol().negotiate(&remote).version),
+            None => Err(RemoteAttributionError::ProtocolNegotiationFailed.into()),
         }
     }
 }
@@ -682,12 +707,25 @@ pub struct RemoteAttributionConfig {
     pub enabled: bool,
     /// Require signature verification
     pub require_signatures: bool,
+    /// Which signatures of pulled bundles to check
+    #[serde(default)]
+    pub verification: VerificationLevel,
     /// Batch size for attribution operations
     pub batch_size: usize,
     /// Timeout for remote attribution operations
     pub timeout_seconds: u64,
     /// Fall back to non-attribution sync if unsupported
     pub fallback_enabled: bool,
+    /// Send the attribution of pushed changes
+    #[serde(default = "enabled")]
+    pub sync_on_push: bool,
+    /// Receive the attribution of pulled changes
+    #[serde(default = "enabled")]
+    pub sync_on_pull: bool,
+}
+
+fn enabled() -> bool {
+    true
 }
 
 impl Default for RemoteAttributionConfig {
@@ -695,9 +733,12 @@ impl Default for RemoteAttributionConfig {
         Self {
             enabled: true,
             require_signatures: false,
+            verification: VerificationLevel::default(),
             batch_size: 50,
             timeout_seconds: 30,
             fallback_enabled: true,
+            sync_on_push: true,
+            sync_on_pull: true,
         }
     }
 }
@@ -715,6 +756,10 @@ impl RemoteAttributionConfig {
             config.require_signatures = value.parse().unwrap_or(false);
         }
 
+        if let Ok(value) = std::env::var("ATOMIC_ATTRIBUTION_VERIFY") {
+            config.verification = value.parse().unwrap_or_default();
+        }
+
         if let Ok(value) = std::env::var("ATOMIC_ATTRIBUTION_BATCH_SIZE") {
             config.batch_size = value.parse().unwrap_or(50);
         }
@@ -727,10 +772,138 @@ impl RemoteAttributionConfig {
             config.fallback_enabled = value.parse().unwrap_or(true);
         }
 
+        if let Ok(value) = std::env::var("ATOMIC_ATTRIBUTION_SYNC_PUSH") {
+            config.sync_on_push = value.parse().unwrap_or(true);
+        }
+
+        if let Ok(value) = std::env::var("ATOMIC_ATTRIBUTION_SYNC_PULL") {
+            config.sync_on_pull = value.parse().unwrap_or(true);
+        }
+
         config
     }
 }
 
+/// The attribution of a change, as exchanged with remotes. The bundle is
+/// sent without its patch data, which is the change itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeAttribution {
+    /// Hash of the change, in base32
+    pub hash: String,
+    pub bundle: AttributedPatchBundle,
+}
+
+/// The attribution protocol of this end.
+pub fn local_protocol() -> AttributionProtocol {
+    AttributionProtocol::new(ATTRIBUTION_PROTOCOL_VERSION)
+}
+
+/// The word added by servers to their answers to `state`, over SSH.
+pub fn capability() -> String {
+    format!("{}={}", CAPABILITY, ATTRIBUTION_PROTOCOL_VERSION)
+}
+
+/// The attribution protocol announced in an answer to `state`, over SSH.
+pub fn announced(state_line: &str) -> Option<AttributionProtocol> {
+    state_line.split_whitespace().find_map(|w| {
+        let version = w.strip_prefix(CAPABILITY)?.strip_prefix('=')?;
+        Some(AttributionProtocol::new(version.parse().ok()?))
+    })
+}
+
+/// Whether attribution is exchanged with a remote speaking `remote`:
+/// attribution sync must be enabled in `config`, and both ends must
+/// support [`ProtocolFeature::Attribution`].
+pub fn negotiated(remote: Option<&AttributionProtocol>, config: &RemoteAttributionConfig) -> bool {
+    config.enabled
+        && remote.map_or(false, |remote| {
+            local_protocol()
+                .negotiate(remote)
+                .features
+                .contains(&ProtocolFeature::Attribution)
+        })
+}
+
+/// The identifier of the attribution of change `hash`.
+pub fn patch_id(hash: &Hash) -> PatchId {
+    PatchId::from(NodeId::from_base32(hash.to_base32().as_bytes()).unwrap_or(NodeId::ROOT))
+}
+
+/// The attribution of the changes `hashes` stored in `pristine`, to send
+/// to a remote. Changes without attribution are skipped.
+pub fn outgoing(pristine: &Pristine, hashes: &[Hash]) -> Result<Vec<ChangeAttribution>> {
+    let store = SanakirjaAttributionStore::new(pristine.clone());
+    let mut outgoing = Vec::new();
+    for hash in hashes {
+        if let Some(attribution) = store.get_attribution(&patch_id(hash))? {
+            outgoing.push(ChangeAttribution {
+                hash: hash.to_base32(),
+                bundle: AttributedPatchBundle {
+                    patch_data: Vec::new(),
+                    attribution,
+                    signature: None,
+                },
+            })
+        }
+    }
+    Ok(outgoing)
+}
+
+/// Store the attribution received from a remote in `pristine`, and
+/// return the number of changes whose attribution was stored. The
+/// attribution of changes missing from `changes_dir` is ignored, and so
+/// are bundles with invalid signatures, or without signatures if
+/// `config` requires them.
+///
+/// This opens its own transaction, and must not be called while a
+/// mutable transaction on `pristine` is open.
+pub fn incoming(
+    pristine: &Pristine,
+    changes_dir: &Path,
+    received: Vec<ChangeAttribution>,
+    config: &RemoteAttributionConfig,
+) -> Result<usize> {
+    let store = SanakirjaAttributionStore::new(pristine.clone());
+    let mut stored = 0;
+    for ChangeAttribution { hash, mut bundle } in received {
+        let Some(h) = Hash::from_base32(hash.as_bytes()) else {
+            warn!("Invalid hash {:?} in received attribution", hash);
+            continue;
+        };
+        if bundle.attribution.patch_id != patch_id(&h) {
+            warn!(
+                "Attribution received for {} belongs to another change",
+                hash
+            );
+            continue;
+        }
+        let mut path = changes_dir.to_path_buf();
+        libatomic::changestore::filesystem::push_filename(&mut path, &h);
+        bundle.patch_data = match std::fs::read(&path) {
+            Ok(change) => change,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("ignoring the attribution of unknown change {}", hash);
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match bundle.verify_signature() {
+            None if config.require_signatures => {
+                warn!("Unsigned attribution received for {}, skipping", hash);
+                continue;
+            }
+            Some(false) if config.verification != VerificationLevel::None => {
+                warn!("Invalid signature on the attribution of {}, skipping", hash);
+                continue;
+            }
+            _ => {}
+        }
+        store.put_attribution(&bundle.attribution)?;
+        stored += 1;
+    }
+    Ok(stored)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -755,4 +928,33 @@ mod tests {
     fn test_protocol_version() {
         assert_eq!(ATTRIBUTION_PROTOCOL_VERSION, 1);
     }
+
+    #[test]
+    fn test_announced() {
+        let line = format!("3 MERKLE TAG bundle {}\n", capability());
+        let protocol = announced(&line).unwrap();
+        assert_eq!(protocol.version, ATTRIBUTION_PROTOCOL_VERSION);
+        assert!(protocol.features.contains(&ProtocolFeature::Attribution));
+
+        assert!(announced("3 MERKLE TAG bundle\n").is_none());
+        assert!(announced("-\n").is_none());
+        assert!(announced("3 MERKLE TAG attribution=x\n").is_none());
+    }
+
+    #[test]
+    fn test_negotiated() {
+        let config = RemoteAttributionConfig::default();
+        assert!(negotiated(Some(&local_protocol()), &config));
+        assert!(!negotiated(None, &config));
+        // Remotes predating the exchange.
+        let mut remote = local_protocol();
+        remote.features.remove(&ProtocolFeature::Attribution);
+        assert!(!negotiated(Some(&remote), &config));
+
+        let disabled = RemoteAttributionConfig {
+            enabled: false,
+            ..config
+        };
+        assert!(!negotiated(Some(&local_protocol()), &disabled));
+    }
 }
@@ -0,0 +1,153 @@
+//! Bundles of changes, to transfer several changes in one round trip.
+//!
+//! Since protocol version 5, clients and servers that both support it
+//! exchange changes in bundles instead of one request per change: a
+//! bundle holds up to [`MAX_CHANGES`] changes, compressed together as a
+//! single zstd frame, so that each change is compressed against the ones
+//! before it in the bundle. Changes of the same history share most of
+//! their headers, dependencies and context, which per-change transfers
+//! send again every time.
+//!
+//! A bundle is [`MAGIC`] followed by the zstd compression of its entries,
+//! each made of the length of the base32 hash of the change (`u16`, big
+//! endian), that hash, the length of the change (`u64`, big endian) and
+//! the change itself.
+//!
+//! Support is negotiated, and peers that don't announce it get one
+//! change per request as before:
+//!
+//! - over HTTP, servers list `"bundles": ["zstd"]` in their discovery
+//!   answer. Clients download bundles with `GET ?bundle=<hash>,<hash>…`
+//!   and upload them with `POST ?applybundle&to_channel=<channel>`;
+//! - over SSH, servers started with `--version 5` or later add `bundle`
+//!   to their answers to `state` ([`CAPABILITY`]), and then take the
+//!   `bundle`, `partialbundle` and `applybundle` commands (see
+//!   [`crate::protocol::Command`]).
+
+use crate::Node;
+use anyhow::bail;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use libatomic::{Base32, Hash};
+use std::io::Read;
+
+/// First bytes of bundles.
+pub const MAGIC: &[u8; 8] = b"ATOMBNDL";
+
+/// Capability announced by servers supporting bundles.
+pub const CAPABILITY: &str = "bundle";
+
+/// Maximum number of changes in a bundle.
+pub const MAX_CHANGES: usize = 32;
+
+const LEVEL: i32 = 3;
+
+/// Bundle `changes`, pairs of a hash and the contents of its change file.
+pub fn encode<'a, I: IntoIterator<Item = (&'a Hash, &'a [u8])>>(
+    changes: I,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let mut raw = Vec::new();
+    for (hash, change) in changes {
+        let hash = hash.to_base32();
+        raw.write_u16::<BigEndian>(hash.len() as u16)?;
+        raw.extend_from_slice(hash.as_bytes());
+        raw.write_u64::<BigEndian>(change.len() as u64)?;
+        raw.extend_from_slice(change);
+    }
+    let mut bundle = MAGIC.to_vec();
+    bundle.extend(zstd::stream::encode_all(&raw[..], LEVEL)?);
+    Ok(bundle)
+}
+
+/// The changes of `bundle`, in the order they were bundled.
+pub fn decode(bundle: &[u8]) -> Result<Vec<(Hash, Vec<u8>)>, anyhow::Error> {
+    if !is_bundle(bundle) {
+        bail!("Not a bundle of changes")
+    }
+    let raw = zstd::stream::decode_all(&bundle[MAGIC.len()..])?;
+    let mut r = &raw[..];
+    let mut changes = Vec::new();
+    while !r.is_empty() {
+        let len = r.read_u16::<BigEndian>()? as usize;
+        let mut hash = vec![0; len];
+        r.read_exact(&mut hash)?;
+        let Some(hash) = Hash::from_base32(&hash) else {
+            bail!("Invalid hash in bundle")
+        };
+        let len = r.read_u64::<BigEndian>()? as usize;
+        if len > r.len() {
+            bail!("Truncated bundle")
+        }
+        let (change, rest) = r.split_at(len);
+        changes.push((hash, change.to_vec()));
+        r = rest;
+    }
+    Ok(changes)
+}
+
+/// Whether `buf` starts like a bundle.
+pub fn is_bundle(buf: &[u8]) -> bool {
+    buf.starts_with(MAGIC)
+}
+
+/// Whether the answer of a server to `state`, over SSH, announces bundles.
+pub fn announced(state_line: &str) -> bool {
+    state_line.split_whitespace().any(|w| w == CAPABILITY)
+}
+
+/// The nodes to download in the next request: a single node, or up to
+/// [`MAX_CHANGES`] changes if `bundles` is set. A tag received while
+/// collecting changes is kept in `pending` for the next request.
+pub(crate) async fn next_nodes(
+    nodes: &mut tokio::sync::mpsc::UnboundedReceiver<Node>,
+    pending: &mut Option<Node>,
+    bundles: bool,
+) -> Option<Vec<Node>> {
+    let first = match pending.take() {
+        Some(node) => node,
+        None => nodes.recv().await?,
+    };
+    let mut next = vec![first];
+    if bundles && !first.is_tag() {
+        while next.len() < MAX_CHANGES {
+            match nodes.try_recv() {
+                Ok(node) if !node.is_tag() => next.push(node),
+                Ok(node) => {
+                    *pending = Some(node);
+                    break;
+                }
+                Err(_) => break,
+            }
+        }
+    }
+    Some(next)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_repository::fixtures::hash;
+
+    #[test]
+    fn test_encode_decode() {
+        let a: Vec<u8> = (0..200).collect();
+        let b: Vec<u8> = (0..200).chain(0..50).collect();
+        let bundle = encode(vec![
+            (&hash(0), &a[..]),
+            (&hash(1), &b[..]),
+            (&hash(2), &[][..]),
+        ])
+        .unwrap();
+        assert!(is_bundle(&bundle));
+        // The second change is compressed against the first one.
+        assert!(bundle.len() < a.len() + b.len());
+        assert_eq!(
+            decode(&bundle).unwrap(),
+            vec![(hash(0), a), (hash(1), b), (hash(2), Vec::new())]
+        );
+        assert!(decode(b"not a bundle").is_err());
+
+        assert!(announced("12 AAAA BBBB bundle\n"));
+        assert!(announced("- bundle\n"));
+        assert!(!announced("12 AAAA BBBB\n"));
+    }
+}
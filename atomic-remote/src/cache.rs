@@ -0,0 +1,192 @@
+//! Machine-wide cache of downloaded changes
+//!
+//! Clones of the same repository on one machine download the same change
+//! files. When `change_cache` is enabled in the global configuration, the
+//! changes downloaded over SSH or HTTP are also copied to a cache directory
+//! laid out like `.atomic/changes`, and later downloads are served from it
+//! before asking the remote.
+//!
+//! Entries are content-addressed and checked against their hash when read,
+//! so a corrupted or tampered entry is ignored (and replaced). They are
+//! written to a temporary file and renamed, so readers never see partial
+//! files; writers of the same entry are serialised with a lock file next
+//! to it, and only one of them copies it.
+
+use std::path::{Path, PathBuf};
+
+use libatomic::changestore::filesystem::{pop_filename, push_filename};
+use libatomic::pristine::{Base32, Hash};
+use log::debug;
+
+/// Age after which a lock file is considered left by a crashed process
+const STALE_LOCK: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// The shared change cache
+#[derive(Debug, Clone)]
+pub struct ChangeCache {
+    dir: PathBuf,
+}
+
+impl ChangeCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        ChangeCache { dir: dir.into() }
+    }
+
+    /// The cache configured in the global configuration, if enabled
+    pub fn from_global() -> Option<Self> {
+        let (global, _) = atomic_config::Global::load().ok()?;
+        global.change_cache.dir().map(Self::new)
+    }
+
+    fn entry(&self, hash: &Hash) -> PathBuf {
+        let mut path = self.dir.clone();
+        push_filename(&mut path, hash);
+        path
+    }
+
+    /// Copy the change `hash` from the cache into the changes directory
+    /// `changes_dir`. Returns `false` if the cache doesn't have a valid
+    /// copy of it.
+    pub fn fetch(&self, hash: &Hash, changes_dir: &mut PathBuf) -> bool {
+        let entry = self.entry(hash);
+        if std::fs::metadata(&entry).is_err() {
+            return false;
+        }
+        if let Err(e) = libatomic::change::Change::deserialize(&entry.to_string_lossy(), Some(hash))
+        {
+            debug!("invalid cache entry {:?}: {}", entry, e);
+            std::fs::remove_file(&entry).unwrap_or(());
+            return false;
+        }
+        push_filename(changes_dir, hash);
+        let result = copy_atomically(&entry, changes_dir);
+        pop_filename(changes_dir);
+        match result {
+            Ok(()) => {
+                debug!("change {} served from the cache", hash.to_base32());
+                true
+            }
+            Err(e) => {
+                debug!("failed to copy {:?} from the cache: {}", entry, e);
+                false
+            }
+        }
+    }
+
+    /// Copy the change `hash` from the changes directory `changes_dir`
+    /// into the cache, unless it is already there or being copied.
+    pub fn store(&self, hash: &Hash, changes_dir: &mut PathBuf) -> std::io::Result<()> {
+        let entry = self.entry(hash);
+        if std::fs::metadata(&entry).is_ok() {
+            return Ok(());
+        }
+        push_filename(changes_dir, hash);
+        let result = self.store_locked(&entry, changes_dir);
+        pop_filename(changes_dir);
+        result
+    }
+
+    fn store_locked(&self, entry: &Path, source: &Path) -> std::io::Result<()> {
+        if std::fs::metadata(source).is_err() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(entry.parent().unwrap())?;
+        let lock = entry.with_extension("lock");
+        let _lock = match Lock::acquire(&lock)? {
+            Some(lock) => lock,
+            None => return Ok(()),
+        };
+        if std::fs::metadata(entry).is_ok() {
+            return Ok(());
+        }
+        copy_atomically(source, entry)
+    }
+}
+
+/// Copy `from` to `to` through a temporary file next to `to`
+fn copy_atomically(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(to.parent().unwrap())?;
+    let tmp = to.with_extension(format!("tmp{}", std::process::id()));
+    std::fs::copy(from, &tmp)?;
+    std::fs::rename(&tmp, to).map_err(|e| {
+        std::fs::remove_file(&tmp).unwrap_or(());
+        e
+    })
+}
+
+/// A lock file, removed when dropped
+struct Lock(PathBuf);
+
+impl Lock {
+    /// Create the lock file `path`. Returns `None` if another process holds
+    /// it, unless it is stale.
+    fn acquire(path: &Path) -> std::io::Result<Option<Self>> {
+        for _ in 0..2 {
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+            {
+                Ok(_) => return Ok(Some(Lock(path.to_path_buf()))),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    let stale = std::fs::metadata(path)
+                        .and_then(|m| m.modified())
+                        .ok()
+                        .and_then(|t| t.elapsed().ok())
+                        .map_or(false, |age| age > STALE_LOCK);
+                    if !stale {
+                        return Ok(None);
+                    }
+                    debug!("removing stale lock {:?}", path);
+                    std::fs::remove_file(path).unwrap_or(());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).unwrap_or(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_repository::fixtures::hash;
+
+    #[test]
+    fn test_invalid_entries_are_not_served() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = ChangeCache::new(tmp.path().join("cache"));
+        let mut changes = tmp.path().join("changes");
+        let hash = hash(0);
+
+        // Not a change with this hash
+        push_filename(&mut changes, &hash);
+        std::fs::create_dir_all(changes.parent().unwrap()).unwrap();
+        std::fs::write(&changes, b"not a change").unwrap();
+        pop_filename(&mut changes);
+
+        cache.store(&hash, &mut changes).unwrap();
+        assert!(cache.entry(&hash).exists());
+        let mut other = tmp.path().join("other");
+        assert!(!cache.fetch(&hash, &mut other));
+        assert!(!cache.entry(&hash).exists());
+        assert_eq!(other, tmp.path().join("other"));
+    }
+
+    #[test]
+    fn test_lock() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("entry.lock");
+        let lock = Lock::acquire(&path).unwrap();
+        assert!(lock.is_some());
+        assert!(Lock::acquire(&path).unwrap().is_none());
+        drop(lock);
+        assert!(Lock::acquire(&path).unwrap().is_some());
+    }
+}
@@ -0,0 +1,210 @@
+//! Local and remote changes in one log
+//!
+//! The log of a channel only has the changes applied locally. The cache of
+//! a remote's changelist, updated by every push and pull, also lists the
+//! changes of the remote that weren't pulled yet. [`combined_log`] merges
+//! both, marking each change as [`Presence::Local`], [`Presence::Remote`]
+//! or [`Presence::Both`], so that the CLI and the API can show what a pull
+//! would bring, and what a push would send.
+//!
+//! The cache is only as recent as the last push or pull: callers that need
+//! the current changelist of the remote update the cache first (see
+//! [`RemoteRepo::update_changelist`](crate::RemoteRepo::update_changelist)).
+
+use std::collections::{HashMap, HashSet};
+
+use atomic_config::RemoteConfig;
+use atomic_repository::Repository;
+use libatomic::pristine::{Hash, Merkle, RemoteId, RemoteRef, TxnT};
+use libatomic::TxnTExt;
+use serde::{Deserialize, Serialize};
+
+/// Where a change of a [`combined_log`] is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Presence {
+    /// Only in the local channel: a push would send it
+    Local,
+    /// Only on the remote: a pull would bring it
+    Remote,
+    Both,
+}
+
+impl std::fmt::Display for Presence {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Presence::Local => write!(fmt, "local"),
+            Presence::Remote => write!(fmt, "remote"),
+            Presence::Both => write!(fmt, "both"),
+        }
+    }
+}
+
+/// A change of a [`combined_log`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CombinedEntry {
+    pub hash: Hash,
+    /// Position of the change in the local channel, and state of the
+    /// channel after it
+    pub local: Option<(u64, Merkle)>,
+    /// Position of the change in the cached changelist of the remote, and
+    /// state of the remote after it
+    pub remote: Option<(u64, Merkle)>,
+}
+
+impl CombinedEntry {
+    pub fn presence(&self) -> Presence {
+        match (self.local.is_some(), self.remote.is_some()) {
+            (true, true) => Presence::Both,
+            (true, false) => Presence::Local,
+            _ => Presence::Remote,
+        }
+    }
+}
+
+/// The changes of `channel` and of the cached changelist of `remote`,
+/// oldest first. Changes are in the order of the local channel, and the
+/// changes only on the remote come just after the change preceding them
+/// on the remote.
+pub fn combined_log<T: TxnTExt>(
+    txn: &T,
+    channel: &T::Channel,
+    remote: &RemoteRef<T>,
+) -> Result<Vec<CombinedEntry>, anyhow::Error> {
+    let mut local_log = Vec::new();
+    for x in txn.log(channel, 0)? {
+        let (n, (h, m)) = x?;
+        let hash: Hash = h.into();
+        let state: Merkle = m.into();
+        local_log.push((hash, (n, state)));
+    }
+
+    let mut remote_log = Vec::new();
+    for x in txn.iter_remote(&remote.lock().remote, 0)? {
+        let (n, p) = x?;
+        let n: u64 = (*n).into();
+        let hash: Hash = p.a.into();
+        let state: Merkle = p.b.into();
+        remote_log.push((hash, (n, state)));
+    }
+    Ok(merge(local_log, &remote_log))
+}
+
+/// Merge the logs of [`combined_log`]
+fn merge(
+    local_log: Vec<(Hash, (u64, Merkle))>,
+    remote_log: &[(Hash, (u64, Merkle))],
+) -> Vec<CombinedEntry> {
+    let local: HashSet<Hash> = local_log.iter().map(|(h, _)| *h).collect();
+    let on_remote: HashMap<Hash, usize> = remote_log
+        .iter()
+        .enumerate()
+        .map(|(i, (h, _))| (*h, i))
+        .collect();
+
+    let mut entries = Vec::with_capacity(local_log.len().max(remote_log.len()));
+    // Changes of the remote before this index were already considered.
+    let mut next_remote = 0;
+    for (hash, position) in local_log {
+        let remote = on_remote.get(&hash).map(|&i| {
+            for &(hash, remote) in &remote_log[next_remote.min(i)..i] {
+                if !local.contains(&hash) {
+                    entries.push(CombinedEntry {
+                        hash,
+                        local: None,
+                        remote: Some(remote),
+                    })
+                }
+            }
+            next_remote = next_remote.max(i + 1);
+            remote_log[i].1
+        });
+        entries.push(CombinedEntry {
+            hash,
+            local: Some(position),
+            remote,
+        })
+    }
+    for &(hash, remote) in &remote_log[next_remote..] {
+        if !local.contains(&hash) {
+            entries.push(CombinedEntry {
+                hash,
+                local: None,
+                remote: Some(remote),
+            })
+        }
+    }
+    entries
+}
+
+/// The name under which the changelist of remote `name`, as given to
+/// `atomic push` and `atomic pull`, is cached: the address of SSH remotes
+/// configured in `repo`, or `name` itself.
+pub fn cache_name<'a>(repo: &'a Repository, name: &'a str) -> &'a str {
+    match repo.config.remotes.iter().find(|r| r.name() == name) {
+        Some(RemoteConfig::Ssh { ssh, .. }) => ssh,
+        _ => name,
+    }
+}
+
+/// The cached changelists of remote `name`, or of the remote whose
+/// identifier (as listed by `atomic remote`) is `name`. `name` is the
+/// name of the cache (see [`cache_name`]). There is one changelist per
+/// channel of the remote that was pushed to or pulled from.
+pub fn cached_remotes<T: TxnT>(txn: &T, name: &str) -> Result<Vec<RemoteRef<T>>, anyhow::Error> {
+    let id = RemoteId::from_base32(name.as_bytes());
+    let mut remotes = Vec::new();
+    for r in txn.iter_remotes(&RemoteId::nil())? {
+        let r = r?;
+        if Some(*r.id()) == id || r.lock().path.as_str() == name {
+            remotes.push(r)
+        }
+    }
+    Ok(remotes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_repository::fixtures::hash;
+
+    #[test]
+    fn test_merge() {
+        let (a, b, c, x, y, z) = (hash(0), hash(1), hash(2), hash(3), hash(4), hash(5));
+        let log = |hashes: &[Hash]| -> Vec<(Hash, (u64, Merkle))> {
+            hashes
+                .iter()
+                .enumerate()
+                .map(|(n, h)| (*h, (n as u64, Merkle::zero())))
+                .collect()
+        };
+        // Local: a b x c, remote: a y b z c.
+        let entries = merge(log(&[a, b, x, c]), &log(&[a, y, b, z, c]));
+        let hashes: Vec<_> = entries.iter().map(|e| (e.hash, e.presence())).collect();
+        assert_eq!(
+            hashes,
+            vec![
+                (a, Presence::Both),
+                (y, Presence::Remote),
+                (b, Presence::Both),
+                (x, Presence::Local),
+                (z, Presence::Remote),
+                (c, Presence::Both),
+            ]
+        );
+        assert_eq!(entries[1].remote.unwrap().0, 1);
+        assert_eq!(entries[3].local.unwrap().0, 2);
+
+        // The remote has the local changes in another order, and more.
+        let entries = merge(log(&[a, b]), &log(&[b, a, z]));
+        let hashes: Vec<_> = entries.iter().map(|e| (e.hash, e.presence())).collect();
+        assert_eq!(
+            hashes,
+            vec![
+                (a, Presence::Both),
+                (b, Presence::Both),
+                (z, Presence::Remote)
+            ]
+        );
+    }
+}
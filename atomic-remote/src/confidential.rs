@@ -0,0 +1,109 @@
+//! Changes of confidential channels, encrypted for their recipients.
+//!
+//! Channels listed in the `[[confidential]]` sections of the repository
+//! configuration are only pushed to, and pulled from, as envelopes (see
+//! [`libatomic::envelope`]): pushes encrypt each change for the public keys
+//! of the recipients of the channel, and pulls decrypt them with the key of
+//! the current identity before applying them. The server stores the
+//! envelopes without being able to read or apply them.
+//!
+//! Recipients are identity names, usernames or public keys, looked up in
+//! the identities of the repository (`.atomic/identities`) and then in the
+//! local identities.
+
+use anyhow::{anyhow, bail};
+use atomic_repository::Repository;
+use libatomic::envelope::Envelope;
+use libatomic::key::{PKey, SKey};
+use libatomic::pristine::{Base32, Hash};
+use libatomic::DOT_DIR;
+use log::debug;
+
+/// Whether `channel` is a confidential channel of `repo`
+pub fn is_confidential(repo: &Repository, channel: &str) -> bool {
+    repo.config
+        .confidential
+        .iter()
+        .any(|c| c.channel == channel)
+}
+
+/// The public keys of the recipients of `channel`, or `None` if `channel`
+/// isn't confidential.
+pub fn recipients(repo: &Repository, channel: &str) -> Result<Option<Vec<PKey>>, anyhow::Error> {
+    let Some(confidential) = repo
+        .config
+        .confidential
+        .iter()
+        .find(|c| c.channel == channel)
+    else {
+        return Ok(None);
+    };
+    if confidential.recipients.is_empty() {
+        bail!("Confidential channel {} has no recipients", channel)
+    }
+    let mut identities = Vec::new();
+    if let Ok(dir) = std::fs::read_dir(repo.path.join(DOT_DIR).join("identities")) {
+        for entry in dir.flatten() {
+            if let Ok(f) = std::fs::File::open(entry.path()) {
+                if let Ok(id) = serde_json::from_reader::<_, atomic_identity::Complete>(f) {
+                    identities.push(id)
+                }
+            }
+        }
+    }
+    identities.extend(atomic_identity::Complete::load_all().unwrap_or_default());
+    let mut keys = Vec::with_capacity(confidential.recipients.len());
+    for recipient in confidential.recipients.iter() {
+        let id = identities
+            .iter()
+            .find(|id| {
+                id.public_key.key == *recipient
+                    || id.name == *recipient
+                    || id.config.author.username == *recipient
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unknown recipient {} of confidential channel {}",
+                    recipient,
+                    channel
+                )
+            })?;
+        keys.push(id.public_key.load()?);
+    }
+    Ok(Some(keys))
+}
+
+/// Encrypt the change `hash` of `repo` for `recipients`.
+pub fn seal(repo: &Repository, hash: &Hash, recipients: &[PKey]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut path = repo.changes_dir.clone();
+    libatomic::changestore::filesystem::push_filename(&mut path, hash);
+    let change = std::fs::read(&path)?;
+    Ok(Envelope::seal(hash, &change, recipients)?.to_bytes()?)
+}
+
+/// Decrypt `envelope`, which must be the envelope of `hash`, with `key`,
+/// and write the change to the change store of `repo`.
+pub fn open(
+    repo: &Repository,
+    hash: &Hash,
+    envelope: &[u8],
+    key: &SKey,
+) -> Result<(), anyhow::Error> {
+    let envelope = Envelope::from_bytes(envelope)?;
+    if envelope.hash() != Some(*hash) {
+        bail!(
+            "Received the envelope of {} instead of {}",
+            envelope.hash,
+            hash.to_base32()
+        )
+    }
+    let change = envelope.open(key)?;
+    let mut path = repo.changes_dir.clone();
+    libatomic::changestore::filesystem::push_filename(&mut path, hash);
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, &change)?;
+    std::fs::rename(&tmp, &path)?;
+    debug!("opened envelope of {}", hash.to_base32());
+    Ok(())
+}
@@ -0,0 +1,470 @@
+//! Protocol conformance suite
+//!
+//! Runs a server, reached through any [`RemoteRepo`] (HTTP, SSH or a local
+//! path), through the operations clients rely on, and compares its answers
+//! to golden expectations computed from a fixture repository:
+//!
+//! - changelists list every node of the channel at contiguous positions,
+//!   with the hashes and states of the fixture's log, start at the
+//!   requested position, and are empty past the end;
+//! - tags are marked on the changelist lines of their positions, and only
+//!   there;
+//! - states are reported for the last position and for each position;
+//! - uploaded changes can be downloaded back, and hash to their names;
+//! - after a change is unrecorded on the server, it is gone from the
+//!   changelist and the state moves back;
+//! - a missing channel is an error, reported over HTTP as a
+//!   [`libatomic::RemoteError::ChannelNotFound`].
+//!
+//! The channel under test must exist and be empty: the suite uploads the
+//! fixture channel to it. Servers can't unrecord through the protocol, so that
+//! check only runs when the caller provides a way to do it, with
+//! [`Suite::with_unrecord`].
+//!
+//! ```ignore
+//! let mut remote = atomic_remote::repository(&fixture, None, None, url, "conformance", false, false).await?;
+//! let report = Suite::new(&fixture, "main").run(&mut remote).await?;
+//! print!("{}", report);
+//! assert!(report.passed());
+//! ```
+
+use std::collections::HashSet;
+use std::fmt;
+
+use libatomic::pristine::{Base32, Hash, Merkle, TxnT};
+use libatomic::{ChannelTxnT, TxnTExt};
+
+use atomic_interaction::{ProgressBar, DOWNLOAD_MESSAGE};
+use atomic_repository::Repository;
+
+use crate::{Node, RemoteRepo};
+
+/// A changelist line: position, hash, state and tag marker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub n: u64,
+    pub hash: Hash,
+    pub state: Merkle,
+    pub tag: bool,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}{}",
+            self.n,
+            self.hash.to_base32(),
+            self.state.to_base32(),
+            if self.tag { ".tag" } else { "" }
+        )
+    }
+}
+
+/// Outcome of a check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    Failed(String),
+    Skipped(String),
+}
+
+/// A check of the suite, and its outcome
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub outcome: Outcome,
+}
+
+/// Results of a run, in the order the checks ran
+#[derive(Debug, Clone, Default)]
+pub struct Report {
+    pub results: Vec<CheckResult>,
+}
+
+impl Report {
+    /// Whether no check failed
+    pub fn passed(&self) -> bool {
+        !self
+            .results
+            .iter()
+            .any(|r| matches!(r.outcome, Outcome::Failed(_)))
+    }
+
+    fn record(&mut self, name: &'static str, result: Result<(), String>) -> bool {
+        let passed = result.is_ok();
+        self.results.push(CheckResult {
+            name,
+            outcome: match result {
+                Ok(()) => Outcome::Passed,
+                Err(e) => Outcome::Failed(e),
+            },
+        });
+        passed
+    }
+
+    fn skip(&mut self, name: &'static str, reason: &str) {
+        self.results.push(CheckResult {
+            name,
+            outcome: Outcome::Skipped(reason.to_string()),
+        })
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for r in self.results.iter() {
+            match r.outcome {
+                Outcome::Passed => writeln!(f, "ok    {}", r.name)?,
+                Outcome::Failed(ref e) => writeln!(f, "FAIL  {}: {}", r.name, e)?,
+                Outcome::Skipped(ref e) => writeln!(f, "skip  {}: {}", r.name, e)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+type Unrecord<'a> = Box<dyn FnMut(&Hash) -> Result<(), anyhow::Error> + Send + 'a>;
+
+/// The conformance suite, with its fixture
+pub struct Suite<'a> {
+    fixture: &'a Repository,
+    channel: &'a str,
+    unrecord: Option<Unrecord<'a>>,
+}
+
+impl<'a> Suite<'a> {
+    /// Check servers against the channel `channel` of `fixture`, which
+    /// must have at least two changes and a tag.
+    pub fn new(fixture: &'a Repository, channel: &'a str) -> Self {
+        Suite {
+            fixture,
+            channel,
+            unrecord: None,
+        }
+    }
+
+    /// Run the unrecord check, with `unrecord` unrecording a change from
+    /// the channel under test on the server
+    pub fn with_unrecord<F>(mut self, unrecord: F) -> Self
+    where
+        F: FnMut(&Hash) -> Result<(), anyhow::Error> + Send + 'a,
+    {
+        self.unrecord = Some(Box::new(unrecord));
+        self
+    }
+
+    /// The changelist the fixture channel should have on any server
+    pub fn golden(&self) -> Result<Vec<Entry>, anyhow::Error> {
+        let txn = self.fixture.pristine.txn_begin()?;
+        let channel = if let Some(channel) = txn.load_channel(self.channel)? {
+            channel
+        } else {
+            anyhow::bail!("No channel {} in the fixture", self.channel)
+        };
+        let channel = channel.read();
+        let mut tags = HashSet::new();
+        for t in txn.iter_tags(txn.tags(&*channel), 0)? {
+            let n: u64 = (*t?.0).into();
+            tags.insert(n);
+        }
+        let mut entries = Vec::new();
+        for x in txn.log(&*channel, 0)? {
+            let (n, (h, m)) = x?;
+            entries.push(Entry {
+                n,
+                hash: h.into(),
+                state: m.into(),
+                tag: tags.contains(&n),
+            })
+        }
+        Ok(entries)
+    }
+
+    /// Run the suite against `remote`. Errors are only returned when the
+    /// fixture can't be read; failures of the server are in the report.
+    pub async fn run(&mut self, remote: &mut RemoteRepo) -> Result<Report, anyhow::Error> {
+        let golden = self.golden()?;
+        if golden.len() < 2 || !golden.iter().any(|e| e.tag) {
+            anyhow::bail!("The fixture channel needs at least two changes and a tag")
+        }
+        let mut report = Report::default();
+
+        let empty = changelist(remote, 0).await.and_then(|list| {
+            if list.is_empty() {
+                Ok(())
+            } else {
+                Err(format!("channel under test has {} nodes", list.len()))
+            }
+        });
+        if !report.record("empty_channel", empty) {
+            return Ok(report);
+        }
+
+        let upload = self.upload(remote, &golden).await;
+        if !report.record("upload", upload) {
+            return Ok(report);
+        }
+
+        let list = changelist(remote, 0).await;
+        report.record(
+            "changelist",
+            list.clone().and_then(|list| compare(&golden, &list)),
+        );
+        report.record(
+            "tag_markers",
+            list.and_then(|list| {
+                let theirs: Vec<u64> = list.iter().filter(|e| e.tag).map(|e| e.n).collect();
+                let ours: Vec<u64> = golden.iter().filter(|e| e.tag).map(|e| e.n).collect();
+                if theirs == ours {
+                    Ok(())
+                } else {
+                    Err(format!("tags at {:?}, expected {:?}", theirs, ours))
+                }
+            }),
+        );
+
+        let from = golden.len() as u64 / 2;
+        report.record(
+            "changelist_from",
+            changelist(remote, from)
+                .await
+                .and_then(|list| compare(&golden[from as usize..], &list)),
+        );
+        report.record(
+            "changelist_past_end",
+            changelist(remote, golden.len() as u64 + 10)
+                .await
+                .and_then(|list| compare(&[], &list)),
+        );
+
+        report.record("state", self.check_states(remote, &golden).await);
+        report.record("download", self.download(remote, &golden).await);
+        report.record("missing_channel", missing_channel(remote).await);
+
+        if self.unrecord.is_some() {
+            let unrecord = self.unrecord_last(remote, &golden).await;
+            report.record("unrecord", unrecord);
+        } else {
+            report.skip("unrecord", "no unrecord hook");
+        }
+        Ok(report)
+    }
+
+    async fn upload(&self, remote: &mut RemoteRepo, golden: &[Entry]) -> Result<(), String> {
+        let mut nodes = Vec::new();
+        for e in golden {
+            nodes.push(Node::change(e.hash, e.state));
+            if e.tag {
+                nodes.push(Node::tag(e.hash, e.state));
+            }
+        }
+        let mut txn = self.fixture.pristine.mut_txn_begin().map_err(error)?;
+        remote
+            .upload_nodes(&mut txn, self.fixture.changes_dir.clone(), None, &nodes)
+            .await
+            .map_err(error)?;
+        remote.finish().await.map_err(error)
+    }
+
+    async fn download(&self, remote: &mut RemoteRepo, golden: &[Entry]) -> Result<(), String> {
+        let tmp = tempfile::tempdir().map_err(error)?;
+        let mut path = tmp.path().join("changes");
+        std::fs::create_dir_all(&path).map_err(error)?;
+
+        let (send_node, mut recv_node) = tokio::sync::mpsc::unbounded_channel();
+        let (mut send, mut recv) = tokio::sync::mpsc::channel(golden.len() + 1);
+        for e in golden {
+            send_node
+                .send(Node::change(e.hash, e.state))
+                .map_err(error)?;
+        }
+        drop(send_node);
+        let bar = ProgressBar::new(golden.len() as u64, DOWNLOAD_MESSAGE).map_err(error)?;
+        remote
+            .download_nodes(bar, &mut recv_node, &mut send, &mut path, true)
+            .await
+            .map_err(error)?;
+        drop(send);
+        let mut downloaded = HashSet::new();
+        while let Some((node, _)) = recv.recv().await {
+            downloaded.insert(node.hash);
+        }
+
+        for e in golden {
+            if !downloaded.contains(&e.hash) {
+                return Err(format!("{} was not downloaded", e.hash.to_base32()));
+            }
+            libatomic::changestore::filesystem::push_filename(&mut path, &e.hash);
+            let valid =
+                libatomic::change::Change::deserialize(&path.to_string_lossy(), Some(&e.hash));
+            libatomic::changestore::filesystem::pop_filename(&mut path);
+            if let Err(err) = valid {
+                return Err(format!("{} is invalid: {}", e.hash.to_base32(), err));
+            }
+        }
+        Ok(())
+    }
+
+    async fn check_states(&self, remote: &mut RemoteRepo, golden: &[Entry]) -> Result<(), String> {
+        let txn = self.fixture.pristine.txn_begin().map_err(error)?;
+        let last = golden.last().unwrap();
+        match remote.get_state(&txn, None).await.map_err(error)? {
+            Some((n, state, _)) if n == last.n && state == last.state => {}
+            other => {
+                return Err(format!(
+                    "last state {:?}, expected ({}, {})",
+                    other.map(|(n, m, _)| (n, m.to_base32())),
+                    last.n,
+                    last.state.to_base32()
+                ))
+            }
+        }
+        for e in golden {
+            match remote.get_state(&txn, Some(e.n)).await.map_err(error)? {
+                Some((n, state, _)) if n == e.n && state == e.state => {}
+                other => {
+                    return Err(format!(
+                        "state at {} is {:?}, expected {}",
+                        e.n,
+                        other.map(|(n, m, _)| (n, m.to_base32())),
+                        e.state.to_base32()
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn unrecord_last(
+        &mut self,
+        remote: &mut RemoteRepo,
+        golden: &[Entry],
+    ) -> Result<(), String> {
+        let last = golden.last().unwrap();
+        (self.unrecord.as_mut().unwrap())(&last.hash).map_err(error)?;
+        let list = changelist(remote, 0).await?;
+        if list.iter().any(|e| e.hash == last.hash) {
+            return Err(format!(
+                "{} still in the changelist after being unrecorded",
+                last.hash.to_base32()
+            ));
+        }
+        compare(&golden[..golden.len() - 1], &list)?;
+        let previous = &golden[golden.len() - 2];
+        let txn = self.fixture.pristine.txn_begin().map_err(error)?;
+        match remote.get_state(&txn, None).await.map_err(error)? {
+            Some((n, state, _)) if n == previous.n && state == previous.state => Ok(()),
+            other => Err(format!(
+                "state after unrecord {:?}, expected ({}, {})",
+                other.map(|(n, m, _)| (n, m.to_base32())),
+                previous.n,
+                previous.state.to_base32()
+            )),
+        }
+    }
+}
+
+fn error<E: fmt::Display>(e: E) -> String {
+    e.to_string()
+}
+
+async fn changelist(remote: &mut RemoteRepo, from: u64) -> Result<Vec<Entry>, String> {
+    let (_, list) = remote
+        .download_changelist_nocache(from, &[])
+        .await
+        .map_err(error)?;
+    Ok(list
+        .into_iter()
+        .map(|(n, hash, state, tag)| Entry {
+            n,
+            hash,
+            state,
+            tag,
+        })
+        .collect())
+}
+
+/// Compare a changelist to the expected one, ignoring tag markers
+fn compare(expected: &[Entry], list: &[Entry]) -> Result<(), String> {
+    for (i, e) in expected.iter().enumerate() {
+        match list.get(i) {
+            Some(l) if l.n == e.n && l.hash == e.hash && l.state == e.state => {}
+            Some(l) => return Err(format!("line {} is {}, expected {}", i, l, e)),
+            None => return Err(format!("line {} is missing, expected {}", i, e)),
+        }
+    }
+    if let Some(extra) = list.get(expected.len()) {
+        return Err(format!("unexpected line {}", extra));
+    }
+    Ok(())
+}
+
+async fn missing_channel(remote: &mut RemoteRepo) -> Result<(), String> {
+    let missing = format!("conformance-missing-{}", std::process::id());
+    let channel = match remote {
+        RemoteRepo::Http(ref mut h) => &mut h.channel,
+        RemoteRepo::Ssh(ref mut s) => &mut s.channel,
+        RemoteRepo::Local(ref mut l) => &mut l.channel,
+        _ => return Err("not a remote server".to_string()),
+    };
+    let channel_under_test = std::mem::replace(channel, missing);
+    let result = remote.download_changelist_nocache(0, &[]).await;
+    match remote {
+        RemoteRepo::Http(ref mut h) => h.channel = channel_under_test,
+        RemoteRepo::Ssh(ref mut s) => s.channel = channel_under_test,
+        RemoteRepo::Local(ref mut l) => l.channel = channel_under_test,
+        _ => unreachable!(),
+    }
+    match result {
+        Ok(_) => Err("changelist of a missing channel succeeded".to_string()),
+        Err(e) if matches!(remote, RemoteRepo::Http(_)) => {
+            match e.downcast_ref::<libatomic::RemoteError>() {
+                Some(libatomic::RemoteError::ChannelNotFound { .. }) => Ok(()),
+                _ => Err(format!("expected a ChannelNotFound error, got: {}", e)),
+            }
+        }
+        Err(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libatomic::pristine::Hasher;
+
+    fn entry(n: u64, tag: bool) -> Entry {
+        let mut hasher = Hasher::default();
+        hasher.update(&n.to_le_bytes());
+        let hash = hasher.finish();
+        Entry {
+            n,
+            hash,
+            state: Merkle::from(hash),
+            tag,
+        }
+    }
+
+    #[test]
+    fn test_compare_ignores_tag_markers() {
+        let golden = vec![entry(0, false), entry(1, true)];
+        assert!(compare(&golden, &[entry(0, false), entry(1, false)]).is_ok());
+        assert!(compare(&golden, &golden[..1]).is_err());
+        assert!(compare(&golden[..1], &golden).is_err());
+        assert!(compare(&golden, &[entry(0, false), entry(2, true)]).is_err());
+    }
+
+    #[test]
+    fn test_report() {
+        let mut report = Report::default();
+        assert!(report.record("changelist", Ok(())));
+        report.skip("unrecord", "no unrecord hook");
+        assert!(report.passed());
+        assert!(!report.record("state", Err("last state None".to_string())));
+        assert!(!report.passed());
+        assert_eq!(
+            report.to_string(),
+            "ok    changelist\nskip  unrecord: no unrecord hook\nFAIL  state: last state None\n"
+        );
+    }
+}
@@ -0,0 +1,266 @@
+//! Failures of sync operations.
+//!
+//! Scripts wrapping push and pull need to tell a remote that can't be
+//! reached from one refusing the credentials, or from a pull leaving
+//! conflicts, without matching error messages. The remotes fail with a
+//! [`SyncError`] of some [`FailureKind`], carried by the `anyhow::Error`
+//! of the functions of this crate, and [`FailureKind::of`] finds the kind
+//! of any error by looking down its chain for a `SyncError` or for an
+//! error of the transports (HTTP, SSH, I/O). Each kind has an exit code,
+//! used by the `atomic` command, and [`FailureReport`] is the
+//! machine-readable form of a failure.
+
+use serde::{Deserialize, Serialize};
+
+/// What went wrong in a sync operation, or what prevented it from doing
+/// anything
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// Nothing to push or pull, which is only a failure for scripts that
+    /// expected something
+    NothingToDo,
+    /// The changes were pulled, but left conflicts in the working copy
+    Conflicts,
+    /// Missing or refused credentials, unknown host key, insufficient
+    /// permissions
+    Auth,
+    /// The remote couldn't be reached, or the connection was lost, or the
+    /// remote is unavailable (`5xx`, `429`): trying again later may work
+    Network,
+    /// No such remote, repository, channel, path or change
+    NotFound,
+    /// The remote refused the operation: policies, validation, hooks,
+    /// quotas
+    Rejected,
+    /// The remote answered something unexpected
+    Protocol,
+    /// Anything else, often a local problem
+    Other,
+}
+
+impl FailureKind {
+    /// Exit code of the `atomic` command for this kind. `0` is success, and
+    /// `1` the failures of other commands.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            FailureKind::Other => 1,
+            FailureKind::NothingToDo => 2,
+            FailureKind::Conflicts => 3,
+            FailureKind::Auth => 4,
+            FailureKind::Network => 5,
+            FailureKind::NotFound => 6,
+            FailureKind::Rejected => 7,
+            FailureKind::Protocol => 8,
+        }
+    }
+
+    /// The kind of `e`: the first cause of `e` with a known kind, or
+    /// [`FailureKind::Other`]
+    pub fn of(e: &anyhow::Error) -> FailureKind {
+        e.chain()
+            .find_map(Self::of_cause)
+            .unwrap_or(FailureKind::Other)
+    }
+
+    fn of_cause(cause: &(dyn std::error::Error + 'static)) -> Option<FailureKind> {
+        if let Some(e) = cause.downcast_ref::<SyncError>() {
+            return Some(e.kind);
+        }
+        if let Some(e) = cause.downcast_ref::<libatomic::RemoteError>() {
+            return Some(match e {
+                libatomic::RemoteError::AmbiguousPath { .. } => FailureKind::Rejected,
+                _ => FailureKind::NotFound,
+            });
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            if let Some(status) = e.status() {
+                return Some(FailureKind::of_status(status));
+            }
+            if e.is_connect() || e.is_timeout() || e.is_request() || e.is_body() {
+                return Some(FailureKind::Network);
+            }
+            if e.is_decode() {
+                return Some(FailureKind::Protocol);
+            }
+        }
+        if let Some(e) = cause.downcast_ref::<thrussh::Error>() {
+            match e {
+                thrussh::Error::NotAuthenticated | thrussh::Error::UnknownKey => {
+                    return Some(FailureKind::Auth)
+                }
+                thrussh::Error::ConnectionTimeout | thrussh::Error::HUP => {
+                    return Some(FailureKind::Network)
+                }
+                _ => {}
+            }
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            use std::io::ErrorKind::*;
+            if matches!(
+                e.kind(),
+                ConnectionRefused
+                    | ConnectionReset
+                    | ConnectionAborted
+                    | NotConnected
+                    | AddrNotAvailable
+                    | TimedOut
+                    | UnexpectedEof
+            ) {
+                return Some(FailureKind::Network);
+            }
+        }
+        None
+    }
+
+    /// The kind of an HTTP answer with `status`, which isn't a success
+    pub fn of_status(status: reqwest::StatusCode) -> FailureKind {
+        match status.as_u16() {
+            401 | 403 | 407 => FailureKind::Auth,
+            404 | 410 => FailureKind::NotFound,
+            408 | 429 | 500..=599 => FailureKind::Network,
+            400..=499 => FailureKind::Rejected,
+            _ => FailureKind::Protocol,
+        }
+    }
+}
+
+impl std::fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            FailureKind::NothingToDo => "nothing_to_do",
+            FailureKind::Conflicts => "conflicts",
+            FailureKind::Auth => "auth",
+            FailureKind::Network => "network",
+            FailureKind::NotFound => "not_found",
+            FailureKind::Rejected => "rejected",
+            FailureKind::Protocol => "protocol",
+            FailureKind::Other => "other",
+        };
+        f.write_str(name)
+    }
+}
+
+/// An error of a sync operation, of a known kind
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("{message}")]
+pub struct SyncError {
+    pub kind: FailureKind,
+    pub message: String,
+}
+
+impl SyncError {
+    pub fn new(kind: FailureKind, message: impl Into<String>) -> Self {
+        SyncError {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn auth(message: impl Into<String>) -> Self {
+        Self::new(FailureKind::Auth, message)
+    }
+
+    pub fn network(message: impl Into<String>) -> Self {
+        Self::new(FailureKind::Network, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(FailureKind::NotFound, message)
+    }
+
+    pub fn rejected(message: impl Into<String>) -> Self {
+        Self::new(FailureKind::Rejected, message)
+    }
+
+    pub fn protocol(message: impl Into<String>) -> Self {
+        Self::new(FailureKind::Protocol, message)
+    }
+
+    /// An HTTP answer with `status`, which isn't a success
+    pub fn http(status: reqwest::StatusCode, message: impl Into<String>) -> Self {
+        Self::new(FailureKind::of_status(status), message)
+    }
+}
+
+/// Machine-readable form of the failure of a sync operation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailureReport {
+    pub kind: FailureKind,
+    pub exit_code: i32,
+    pub message: String,
+    /// Messages of the causes of the failure, outermost first, after
+    /// `message`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub causes: Vec<String>,
+}
+
+impl FailureReport {
+    pub fn new(kind: FailureKind, message: impl Into<String>) -> Self {
+        FailureReport {
+            kind,
+            exit_code: kind.exit_code(),
+            message: message.into(),
+            causes: Vec::new(),
+        }
+    }
+
+    /// The report of `e`
+    pub fn of(e: &anyhow::Error) -> Self {
+        FailureReport {
+            causes: e.chain().skip(1).map(|c| c.to_string()).collect(),
+            ..Self::new(FailureKind::of(e), e.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    #[test]
+    fn test_failure_kinds() {
+        let e = anyhow::Error::from(SyncError::auth("Not authenticated"));
+        assert_eq!(FailureKind::of(&e), FailureKind::Auth);
+        // Through context.
+        let e = Err::<(), _>(SyncError::network("Connection lost"))
+            .context("Pushing to origin")
+            .unwrap_err();
+        assert_eq!(FailureKind::of(&e), FailureKind::Network);
+        let report = FailureReport::of(&e);
+        assert_eq!(report.exit_code, 5);
+        assert_eq!(report.message, "Pushing to origin");
+        assert_eq!(report.causes, vec!["Connection lost".to_string()]);
+
+        let io = std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused");
+        assert_eq!(FailureKind::of(&io.into()), FailureKind::Network);
+        let e = anyhow::Error::from(libatomic::RemoteError::ChannelNotFound {
+            channel: "main".to_string(),
+            url: "https://example.com".to_string(),
+        });
+        assert_eq!(FailureKind::of(&e), FailureKind::NotFound);
+        assert_eq!(
+            FailureKind::of(&anyhow::anyhow!("Something else")),
+            FailureKind::Other
+        );
+
+        use reqwest::StatusCode;
+        assert_eq!(
+            FailureKind::of_status(StatusCode::UNAUTHORIZED),
+            FailureKind::Auth
+        );
+        assert_eq!(
+            FailureKind::of_status(StatusCode::SERVICE_UNAVAILABLE),
+            FailureKind::Network
+        );
+        assert_eq!(
+            FailureKind::of_status(StatusCode::UNPROCESSABLE_ENTITY),
+            FailureKind::Rejected
+        );
+        assert_eq!(
+            serde_json::to_string(&FailureKind::NothingToDo).unwrap(),
+            "\"nothing_to_do\""
+        );
+    }
+}
@@ -0,0 +1,538 @@
+//! Git remotes
+//!
+//! Remotes with a `git+https://`, `git+ssh://` or `git+file://` URL are
+//! Git repositories that an Atomic channel is mirrored to and from, so
+//! that teams can keep an existing Git hosting service in the loop. The
+//! branch of the remote channel is bridged through a mirror in the user's
+//! cache directory (`atomic/git/<url>#<branch>`), which is both a Git
+//! working tree and an Atomic repository with a single channel, named
+//! after the branch:
+//!
+//! - when connecting, the branch is fetched, and the commits not mirrored
+//!   yet are recorded as changes, one per commit along the first parents
+//!   of the branch. A merge becomes a single change, with everything it
+//!   brought in;
+//! - changes pushed to the remote are applied to the mirror one at a
+//!   time, each becoming a commit with the message, authors and timestamp
+//!   of the change and an `Atomic-Change` trailer, and the branch is
+//!   pushed when the push finishes.
+//!
+//! Everything else (changelists, states, downloads) is served by the
+//! mirror as a [`Local`] remote. The translation is best-effort: Git
+//! doesn't commute changes, so a branch rewritten on the Git side can't
+//! be mirrored anymore, and tags aren't translated.
+
+use crate::local::Local;
+use crate::Node;
+use anyhow::{bail, Context};
+use atomic_interaction::ProgressBar;
+use atomic_repository::lock::{LockOptions, RepositoryLock};
+use atomic_repository::Repository;
+use libatomic::change::{Author, ChangeHeader};
+use libatomic::changestore::ChangeStore;
+use libatomic::pristine::{Base32, Hash, NodeType};
+use libatomic::{MutTxnT, MutTxnTExt, DOT_DIR};
+use log::{debug, info};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Prefix of the URLs of Git remotes
+pub const URL_PREFIX: &str = "git+";
+
+/// Trailer of the commits made from changes
+pub const CHANGE_TRAILER: &str = "Atomic-Change";
+
+/// Attempts at authenticating to the Git server, before giving up
+const CREDENTIAL_ATTEMPTS: usize = 3;
+
+/// A Git branch, mirrored to and from an Atomic channel
+pub struct Git {
+    /// The mirror, as a local remote on the channel of the branch
+    pub local: Local,
+    /// URL of the Git repository, without the `git+` prefix
+    pub url: String,
+    /// Branch of the channel
+    pub branch: String,
+    /// Whether commits were made for pushed changes, and the branch
+    /// needs to be pushed
+    unpushed: bool,
+    _lock: RepositoryLock,
+}
+
+/// The URL of the Git repository of remote `name`, if it is a Git remote
+pub fn git_url(name: &str) -> Option<&str> {
+    let url = name.strip_prefix(URL_PREFIX)?;
+    if url.starts_with("https://") || url.starts_with("ssh://") || url.starts_with("file://") {
+        Some(url)
+    } else {
+        None
+    }
+}
+
+/// Directory of the mirror of `branch` of the Git repository at `url`
+pub fn mirror_dir(url: &str, branch: &str) -> Option<PathBuf> {
+    let mut dir = dirs_next::cache_dir()?;
+    dir.push("atomic");
+    dir.push("git");
+    dir.push(
+        format!("{}#{}", url, branch)
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>(),
+    );
+    Some(dir)
+}
+
+impl Git {
+    /// Connect to the Git remote `name` (with its `git+` prefix), mirroring
+    /// `branch` in the user's cache directory.
+    pub fn open(name: &str, branch: &str) -> Result<Self, anyhow::Error> {
+        let url = git_url(name).with_context(|| format!("Not a Git remote: {}", name))?;
+        let dir = mirror_dir(url, branch).context("Could not find the cache directory")?;
+        Self::open_in(&dir, name, branch)
+    }
+
+    /// Connect to the Git remote `name`, mirroring `branch` in `dir`, and
+    /// record the commits not mirrored yet.
+    pub fn open_in(dir: &Path, name: &str, branch: &str) -> Result<Self, anyhow::Error> {
+        let url = git_url(name).with_context(|| format!("Not a Git remote: {}", name))?;
+        std::fs::create_dir_all(dir)?;
+        let repo = if dir.join(DOT_DIR).is_dir() {
+            Repository::find_root(Some(dir.to_path_buf()))?
+        } else {
+            Repository::init(Some(dir.to_path_buf()), None, None)?
+        };
+        let lock = repo
+            .lock()
+            .acquire("git mirror", &LockOptions::from_env())?;
+        let git = open_git(dir, url, branch)?;
+
+        let imported = match fetch(&git, branch)? {
+            Some(tip) => import(&repo, &git, branch, tip)?,
+            None => {
+                debug!("no branch {} in {}", branch, url);
+                let txn = repo.pristine.arc_txn_begin()?;
+                txn.write().open_or_create_channel(branch)?;
+                txn.commit()?;
+                0
+            }
+        };
+        if imported > 0 {
+            info!("Mirrored {} commits of {} from {}", imported, branch, url);
+        }
+        let changes_dir = repo.changes_dir.clone();
+        std::mem::drop(repo);
+
+        Ok(Git {
+            local: Local {
+                channel: branch.to_string(),
+                root: dir.to_path_buf(),
+                changes_dir,
+                pristine: Arc::new(Repository::open_pristine(&dir.join(DOT_DIR))?),
+                name: name.to_string(),
+            },
+            url: url.to_string(),
+            branch: branch.to_string(),
+            unpushed: false,
+            _lock: lock,
+        })
+    }
+
+    fn git(&self) -> Result<git2::Repository, anyhow::Error> {
+        Ok(git2::Repository::open(&self.local.root)?)
+    }
+
+    /// Apply `nodes` to the mirror one at a time, committing each change
+    /// to the branch.
+    pub fn upload_nodes(
+        &mut self,
+        progress_bar: ProgressBar,
+        local: PathBuf,
+        to_channel: Option<&str>,
+        nodes: &[Node],
+    ) -> Result<(), anyhow::Error> {
+        if let Some(to_channel) = to_channel {
+            if to_channel != self.branch {
+                bail!(
+                    "Git remotes are pushed to the branch of their channel ({}), not {}",
+                    self.branch,
+                    to_channel
+                )
+            }
+        }
+        let git = self.git()?;
+        let store = libatomic::changestore::filesystem::FileSystem::from_root(
+            &self.local.root,
+            atomic_repository::max_files()?,
+        );
+        for node in nodes {
+            self.local.upload_nodes(
+                progress_bar.clone(),
+                local.clone(),
+                None,
+                std::slice::from_ref(node),
+            )?;
+            if node.node_type == NodeType::Change {
+                let header = store.get_header(&node.hash)?;
+                let oid = commit(&git, &self.branch, &node.hash, &header)?;
+                debug!("committed {} as {}", node.hash.to_base32(), oid);
+                self.unpushed = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// Push the commits made for the pushed changes to the branch.
+    pub fn finish(&mut self) -> Result<(), anyhow::Error> {
+        if !self.unpushed {
+            return Ok(());
+        }
+        let git = self.git()?;
+        let mut remote = git.find_remote("origin")?;
+        let refname = format!("refs/heads/{}", self.branch);
+        let mut rejected = None;
+        let mut callbacks = callbacks();
+        callbacks.push_update_reference(|_, status| {
+            rejected = status.map(|s| s.to_string());
+            Ok(())
+        });
+        let mut options = git2::PushOptions::new();
+        options.remote_callbacks(callbacks);
+        remote.push(&[format!("{0}:{0}", refname)], Some(&mut options))?;
+        std::mem::drop(options);
+        if let Some(status) = rejected {
+            bail!(
+                "Branch {} of {} rejected the push: {}. Pull the new commits first.",
+                self.branch,
+                self.url,
+                status
+            )
+        }
+        let oid = git.refname_to_id(&refname)?;
+        git.reference(
+            &format!("refs/remotes/origin/{}", self.branch),
+            oid,
+            true,
+            "atomic: pushed",
+        )?;
+        self.unpushed = false;
+        info!("Pushed {} to {}", self.branch, self.url);
+        Ok(())
+    }
+}
+
+/// Open or create the Git side of the mirror in `dir`, with `url` as its
+/// `origin` and `branch` as its head.
+fn open_git(dir: &Path, url: &str, branch: &str) -> Result<git2::Repository, anyhow::Error> {
+    let git = match git2::Repository::open(dir) {
+        Ok(git) => git,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => {
+            let git = git2::Repository::init(dir)?;
+            // Git must not see the Atomic side (Atomic ignores `.git`).
+            let exclude = dir.join(".git").join("info").join("exclude");
+            std::fs::create_dir_all(exclude.parent().unwrap())?;
+            std::fs::write(&exclude, format!("/{}\n", DOT_DIR))?;
+            git
+        }
+        Err(e) => return Err(e.into()),
+    };
+    match git.find_remote("origin") {
+        Ok(remote) if remote.url() == Some(url) => {}
+        Ok(_) => git.remote_set_url("origin", url)?,
+        Err(_) => {
+            git.remote("origin", url)?;
+        }
+    }
+    git.set_head(&format!("refs/heads/{}", branch))?;
+    Ok(git)
+}
+
+/// Credentials from the SSH agent, or the Git credential helpers
+fn callbacks<'a>() -> git2::RemoteCallbacks<'a> {
+    let mut callbacks = git2::RemoteCallbacks::new();
+    let mut attempts = 0;
+    callbacks.credentials(move |url, username, allowed| {
+        attempts += 1;
+        if attempts > CREDENTIAL_ATTEMPTS {
+            return Err(git2::Error::from_str(
+                "Authentication to the Git remote failed",
+            ));
+        }
+        if allowed.contains(git2::CredentialType::SSH_KEY) {
+            git2::Cred::ssh_key_from_agent(username.unwrap_or("git"))
+        } else if allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
+            git2::Cred::credential_helper(&git2::Config::open_default()?, url, username)
+        } else {
+            git2::Cred::default()
+        }
+    });
+    callbacks
+}
+
+/// Fetch `branch` from `origin`, returning its tip, or `None` if the
+/// remote doesn't have it.
+fn fetch(git: &git2::Repository, branch: &str) -> Result<Option<git2::Oid>, anyhow::Error> {
+    let mut remote = git.find_remote("origin")?;
+    let mut options = git2::FetchOptions::new();
+    options.remote_callbacks(callbacks());
+    let refspec = format!("+refs/heads/{0}:refs/remotes/origin/{0}", branch);
+    remote.fetch(&[refspec], Some(&mut options), None)?;
+    match git.refname_to_id(&format!("refs/remotes/origin/{}", branch)) {
+        Ok(oid) => Ok(Some(oid)),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Record the commits up to `tip` that aren't mirrored yet, returning how
+/// many changes were recorded.
+fn import(
+    repo: &Repository,
+    git: &git2::Repository,
+    branch: &str,
+    tip: git2::Oid,
+) -> Result<usize, anyhow::Error> {
+    let refname = format!("refs/heads/{}", branch);
+    let mirrored = git.refname_to_id(&refname).ok();
+    if let Some(mirrored) = mirrored {
+        // Up to date, or ahead with commits of changes not pushed yet.
+        if mirrored == tip || git.graph_descendant_of(mirrored, tip)? {
+            return Ok(0);
+        }
+        if !git.graph_descendant_of(tip, mirrored)? {
+            bail!(
+                "Branch {} was rewritten since it was mirrored to {}. Remove the mirror to mirror it again from scratch.",
+                branch,
+                repo.path.display()
+            )
+        }
+    }
+    let mut walk = git.revwalk()?;
+    walk.push(tip)?;
+    if let Some(mirrored) = mirrored {
+        walk.hide(mirrored)?;
+    }
+    walk.simplify_first_parent()?;
+    walk.set_sorting(git2::Sort::TOPOLOGICAL | git2::Sort::REVERSE)?;
+    let commits = walk.collect::<Result<Vec<_>, _>>()?;
+
+    let mut recorded = 0;
+    for oid in commits {
+        let commit = git.find_commit(oid)?;
+        let mut checkout = git2::build::CheckoutBuilder::new();
+        checkout.force().remove_untracked(true);
+        git.checkout_tree(commit.as_object(), Some(&mut checkout))?;
+        if let Some(hash) = record(repo, branch, &commit)? {
+            debug!("recorded {} as {}", oid, hash.to_base32());
+            recorded += 1;
+        }
+        git.reference(&refname, oid, true, "atomic: mirrored")?;
+    }
+    Ok(recorded)
+}
+
+/// Record the working copy of the mirror, checked out at `commit`, to
+/// `channel`. Commits that don't change anything aren't recorded.
+fn record(
+    repo: &Repository,
+    channel: &str,
+    commit: &git2::Commit,
+) -> Result<Option<Hash>, anyhow::Error> {
+    let txn = repo.pristine.arc_txn_begin()?;
+    let channel = txn.write().open_or_create_channel(channel)?;
+    {
+        let mut txn = txn.write();
+        let mut files = Vec::new();
+        commit
+            .tree()?
+            .walk(git2::TreeWalkMode::PreOrder, |dir, entry| {
+                if entry.kind() == Some(git2::ObjectType::Blob) {
+                    files.push(format!("{}{}", dir, entry.name().unwrap_or_default()));
+                }
+                git2::TreeWalkResult::Ok
+            })?;
+        for file in files {
+            match txn.add(&file, false, 0) {
+                Ok(_) | Err(libatomic::fs::FsError::AlreadyInRepo(_)) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    let mut state = libatomic::RecordBuilder::new();
+    state.record(
+        txn.clone(),
+        libatomic::Algorithm::default(),
+        false,
+        &libatomic::DEFAULT_SEPARATOR,
+        channel.clone(),
+        &repo.working_copy,
+        &repo.changes,
+        "",
+        1,
+    )?;
+    let rec = state.finish();
+    if rec.actions.is_empty() {
+        txn.commit()?;
+        return Ok(None);
+    }
+    let actions = rec
+        .actions
+        .into_iter()
+        .map(|rec| rec.globalize(&*txn.read()).unwrap())
+        .collect();
+    let contents = std::mem::take(&mut *rec.contents.lock());
+    let mut change = libatomic::change::Change::make_change(
+        &*txn.read(),
+        &channel,
+        actions,
+        contents,
+        header(commit),
+        Vec::new(),
+    )?;
+    let hash = repo
+        .changes
+        .save_change(&mut change, |_, _| Ok::<_, anyhow::Error>(()))?;
+    txn.write()
+        .apply_local_change(&channel, &change, &hash, &rec.updatables)?;
+    txn.commit()?;
+    Ok(Some(hash))
+}
+
+/// The header of the change recorded from `commit`: its first line as
+/// the message, the rest as the description, and its author.
+fn header(commit: &git2::Commit) -> ChangeHeader {
+    let mut lines = commit.message().unwrap_or_default().lines();
+    let message = lines.next().unwrap_or_default().to_string();
+    let description = lines.collect::<Vec<_>>().join("\n").trim().to_string();
+    let signature = commit.author();
+    let mut author = BTreeMap::new();
+    author.insert(
+        "name".to_string(),
+        signature.name().unwrap_or_default().to_string(),
+    );
+    author.insert(
+        "email".to_string(),
+        signature.email().unwrap_or_default().to_string(),
+    );
+    ChangeHeader {
+        message,
+        description: if description.is_empty() {
+            None
+        } else {
+            Some(description)
+        },
+        authors: vec![Author(author)],
+        timestamp: chrono::DateTime::from_timestamp(signature.when().seconds(), 0)
+            .unwrap_or_default(),
+    }
+}
+
+/// The message of the commit of the change `hash` with `header`
+fn commit_message(hash: &Hash, header: &ChangeHeader) -> String {
+    let mut message = header.message.clone();
+    if let Some(ref description) = header.description {
+        message.push_str("\n\n");
+        message.push_str(description.trim());
+    }
+    message.push_str(&format!("\n\n{}: {}\n", CHANGE_TRAILER, hash.to_base32()));
+    message
+}
+
+/// Commit the working copy of the mirror, where the change `hash` was
+/// just applied, to `branch`.
+fn commit(
+    git: &git2::Repository,
+    branch: &str,
+    hash: &Hash,
+    header: &ChangeHeader,
+) -> Result<git2::Oid, anyhow::Error> {
+    let mut index = git.index()?;
+    index.add_all(["*"], git2::IndexAddOption::DEFAULT, None)?;
+    index.update_all(["*"], None)?;
+    index.write()?;
+    let tree = git.find_tree(index.write_tree()?)?;
+
+    let author = header.authors.first().map(|a| &a.0);
+    let field = |key: &str| {
+        author
+            .and_then(|a| a.get(key))
+            .map(|s| s.as_str())
+            .filter(|s| !s.is_empty())
+    };
+    // Git wants both a name and an email, authors identified by their key
+    // only get a placeholder email.
+    let name = field("name").or_else(|| field("key")).unwrap_or("Atomic");
+    let email = match field("email") {
+        Some(email) => email.to_string(),
+        None => format!("{}@atomic", field("key").unwrap_or("unknown")),
+    };
+    let when = git2::Time::new(header.timestamp.timestamp(), 0);
+    let signature = git2::Signature::new(name, &email, &when)?;
+
+    let refname = format!("refs/heads/{}", branch);
+    let parent = match git.refname_to_id(&refname) {
+        Ok(oid) => Some(git.find_commit(oid)?),
+        Err(_) => None,
+    };
+    let parents: Vec<_> = parent.iter().collect();
+    Ok(git.commit(
+        Some(&refname),
+        &signature,
+        &signature,
+        &commit_message(hash, header),
+        &tree,
+        &parents,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_git_url() {
+        assert_eq!(
+            git_url("git+https://example.com/a/b.git"),
+            Some("https://example.com/a/b.git")
+        );
+        assert_eq!(
+            git_url("git+ssh://git@example.com/a/b.git"),
+            Some("ssh://git@example.com/a/b.git")
+        );
+        assert_eq!(git_url("https://example.com/a/b.git"), None);
+        assert_eq!(git_url("git+ftp://example.com/a/b.git"), None);
+    }
+
+    #[test]
+    fn test_mirror_dir() {
+        let Some(dir) = mirror_dir("https://example.com/a/b.git", "main") else {
+            return;
+        };
+        assert_eq!(dir.file_name().unwrap(), "https___example.com_a_b.git_main");
+    }
+
+    #[test]
+    fn test_commit_message() {
+        let hash = Hash::NONE;
+        let header = ChangeHeader {
+            message: "Fix the parser".to_string(),
+            description: Some("It was broken.\n".to_string()),
+            ..ChangeHeader::default()
+        };
+        assert_eq!(
+            commit_message(&hash, &header),
+            format!(
+                "Fix the parser\n\nIt was broken.\n\n{}: {}\n",
+                CHANGE_TRAILER,
+                hash.to_base32()
+            )
+        );
+    }
+}
@@ -1,29 +1,199 @@
 use anyhow::bail;
 use libatomic::pristine::{Base32, Position};
 use libatomic::Hash;
-use log::{debug, error, trace};
-use std::collections::HashSet;
+use log::{debug, error, info, trace};
+use std::collections::{HashMap, HashSet};
 use std::io::Write;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use crate::attribution::{AttributionProtocol, ChangeAttribution};
+use crate::bundle;
+use crate::failure::SyncError;
+use crate::protocol::ListLine;
+use crate::retry::{self, RetryPolicy};
+use crate::summary::{ChangeSummary, VALIDATE_SIZE};
 use crate::Node;
+use atomic_config::HttpConnection;
 use atomic_interaction::ProgressBar;
+use lazy_static::lazy_static;
 use libatomic::pristine::NodeType;
 
 const USER_AGENT: &str = concat!("atomic-", env!("CARGO_PKG_VERSION"));
 
+/// ETag of the state digest of a channel: the position and state of its
+/// last change, and the state of its last tag. Clients compute it from
+/// their cache of the remote, and servers answer `?digest` requests with it.
+pub fn digest_etag(n: u64, state: &libatomic::Merkle, tag_state: &libatomic::Merkle) -> String {
+    format!("\"{}-{}-{}\"", n, state.to_base32(), tag_state.to_base32())
+}
+
+/// Header of the protocol version of a request, and of the version the
+/// server answered at
+pub const VERSION_HEADER: &str = "X-Atomic-Protocol-Version";
+
+/// Header of the versions a server serves, when it rejects a request for
+/// its version
+pub const VERSIONS_HEADER: &str = "X-Atomic-Protocol-Versions";
+
+/// Version of the servers that predate the negotiation of versions
+pub const LEGACY_VERSION: usize = 4;
+
+/// The newest of the versions in `served` (as in [`VERSIONS_HEADER`]) that
+/// is older than `rejected`, if any.
+fn fallback_version(rejected: usize, served: &str) -> Option<usize> {
+    served
+        .split(',')
+        .filter_map(|v| v.trim().parse().ok())
+        .filter(|&v| v < rejected)
+        .max()
+}
+
+/// Requests sent to a remote at the same time, unless configured
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 8;
+
+/// Header of the response to a change upload, telling whether the server
+/// applied the change ([`APPLIED`]), or already had it
+/// ([`ALREADY_PRESENT`]), e.g. after a concurrent push of the same change.
+/// Servers compare the hash of the uploaded bytes, not the claimed one.
+pub const APPLY_RESULT_HEADER: &str = "X-Atomic-Apply";
+pub const APPLIED: &str = "applied";
+pub const ALREADY_PRESENT: &str = "already-present";
+
 pub struct Http {
     pub url: url::Url,
     pub channel: String,
     pub client: reqwest::Client,
     pub name: String,
     pub headers: Vec<(String, String)>,
+    /// Bounds the number of requests in flight to this remote. Over
+    /// HTTP/2, they are multiplexed on a single connection.
+    pub concurrency: Arc<tokio::sync::Semaphore>,
+    /// Whether the remote takes bundles of changes (see
+    /// [`crate::bundle`]), once asked.
+    pub bundles: Option<bool>,
+    /// Whether the remote validates the summaries of changes before their
+    /// upload (see [`crate::summary`]), once negotiated.
+    pub validates: bool,
+    /// The attribution protocol of the remote (see
+    /// [`crate::attribution`]), if it announced one once negotiated.
+    pub attribution: Option<AttributionProtocol>,
+    /// Whether the remote exchanges notes (see [`crate::NOTES_CAPABILITY`]),
+    /// once negotiated.
+    pub notes: bool,
+    /// Protocol version of the requests, lowered to one the remote serves
+    /// once negotiated.
+    pub version: usize,
+    /// How failed requests are retried
+    pub retry: RetryPolicy,
+}
+
+/// How a client checks the certificates of servers
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Trust {
+    /// The usual checks against the system's certificate authorities
+    Checked,
+    /// Only the certificate with this fingerprint is accepted
+    Pinned(String),
+    /// No check at all (`--no-cert-check`)
+    Insecure,
+}
+
+lazy_static! {
+    /// Clients shared by the remotes of this process, by trust and
+    /// connection settings, see [`shared_client`].
+    static ref CLIENTS: Mutex<HashMap<(Trust, HttpConnection), reqwest::Client>> =
+        Mutex::new(HashMap::new());
+}
+
+/// The client shared by the remotes with the certificate checks `trust`
+/// and the settings `connection`, built by `build` the first time. Clients keep a pool of connections to each server, so
+/// that the changelist and change requests of an operation, and the
+/// successive operations of a process (e.g. a pull followed by a push, or
+/// the rounds of a sync daemon), reuse the same connections, multiplexed
+/// over HTTP/2 when the server speaks it, instead of opening new ones.
+pub fn shared_client<F: FnOnce() -> Result<reqwest::Client, anyhow::Error>>(
+    trust: &Trust,
+    connection: &HttpConnection,
+    build: F,
+) -> Result<reqwest::Client, anyhow::Error> {
+    let key = (trust.clone(), connection.clone());
+    let mut clients = CLIENTS.lock().unwrap();
+    if let Some(client) = clients.get(&key) {
+        debug!("reusing the client of {:?}", key);
+        return Ok(client.clone());
+    }
+    let client = build()?;
+    clients.insert(key, client.clone());
+    Ok(client)
+}
+
+/// Builder of HTTP clients with the connection settings of a remote
+pub fn client_builder(connection: &HttpConnection) -> reqwest::ClientBuilder {
+    let mut builder = reqwest::ClientBuilder::new();
+    match connection.http2 {
+        Some(true) => builder = builder.http2_prior_knowledge(),
+        Some(false) => builder = builder.http1_only(),
+        None => {}
+    }
+    if let Some(max) = connection.pool_max_idle {
+        builder = builder.pool_max_idle_per_host(max)
+    }
+    if let Some(secs) = connection.pool_idle_timeout {
+        builder = builder.pool_idle_timeout(Duration::from_secs(secs))
+    }
+    if let Some(secs) = connection.tcp_keepalive {
+        builder = builder.tcp_keepalive(Duration::from_secs(secs))
+    }
+    if let Some(secs) = connection.http2_keepalive {
+        builder = builder
+            .http2_keep_alive_interval(Duration::from_secs(secs))
+            .http2_keep_alive_while_idle(true)
+    }
+    builder
+}
+
+/// Protocols offered in TLS handshakes, for the TLS configurations built
+/// here rather than by reqwest
+pub(crate) fn alpn_protocols(connection: &HttpConnection) -> Vec<Vec<u8>> {
+    match connection.http2 {
+        Some(true) => vec![b"h2".to_vec()],
+        Some(false) => vec![b"http/1.1".to_vec()],
+        None => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+    }
+}
+
+/// The limiter of concurrent requests to a remote
+pub fn limiter(connection: &HttpConnection) -> Arc<tokio::sync::Semaphore> {
+    let max = connection
+        .max_concurrent_requests
+        .unwrap_or(DEFAULT_MAX_CONCURRENT_REQUESTS)
+        .max(1);
+    Arc::new(tokio::sync::Semaphore::new(max))
+}
+
+/// Read the change file of `hash` at `path`, for an upload
+fn read_change(path: &std::path::Path, hash: &Hash) -> Result<Vec<u8>, anyhow::Error> {
+    std::fs::read(path).map_err(|e| read_error(path, hash, e))
+}
+
+fn read_error(path: &std::path::Path, hash: &Hash, e: std::io::Error) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Failed to read change file for hash {}: {} (path: {})",
+        hash.to_base32(),
+        e,
+        path.display()
+    )
 }
 
 async fn download_change(
     client: reqwest::Client,
+    concurrency: Arc<tokio::sync::Semaphore>,
     url: url::Url,
     headers: Vec<(String, String)>,
+    version: usize,
+    retry: RetryPolicy,
     mut path: PathBuf,
     node: Node,
 ) -> Result<Node, anyhow::Error> {
@@ -46,7 +216,6 @@ async fn download_change(
     let path_ = path.with_extension("tmp");
     let mut f = tokio::fs::File::create(&path_).await.unwrap();
     let url = format!("{}", url);
-    let mut delay = 1f64;
 
     let (send, mut recv) = tokio::sync::mpsc::channel::<Option<bytes::Bytes>>(100);
     let is_tag = node.is_tag();
@@ -79,31 +248,55 @@ async fn download_change(
         Ok::<_, std::io::Error>(())
     });
 
+    // Bytes of the body received so far, to resume from after an error.
+    let mut received = 0u64;
+    let mut attempt = 0;
     let mut done = false;
     while !done {
+        let _permit = concurrency.acquire().await?;
         let mut req = client
             .get(&url)
             .query(&[(req, &c32)])
-            .header(reqwest::header::USER_AGENT, USER_AGENT);
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, version);
+        if received > 0 {
+            req = req.header(reqwest::header::RANGE, format!("bytes={}-", received));
+        }
         for (k, v) in headers.iter() {
             debug!("kv = {:?} {:?}", k, v);
             req = req.header(k.as_str(), v.as_str());
         }
-        let mut res = if let Ok(res) = req.send().await {
-            delay = 1f64;
-            res
-        } else {
-            debug!("HTTP error, retrying in {} seconds", delay.round());
-            tokio::time::sleep(std::time::Duration::from_secs_f64(delay)).await;
-            send.send(None).await?;
-            delay *= 2.;
-            continue;
+        attempt += 1;
+        let mut res = match req.send().await {
+            Ok(res) => res,
+            Err(e) => {
+                debug!("HTTP error {:?}", e);
+                if !retry.wait(attempt).await {
+                    send.send(None).await?;
+                    bail!(SyncError::network(format!(
+                        "Failed to download {}: {}",
+                        c32, e
+                    )))
+                }
+                continue;
+            }
         };
         debug!("response {:?}", res);
         if !res.status().is_success() {
-            tokio::time::sleep(std::time::Duration::from_secs_f64(delay)).await;
+            if retry::is_transient(res.status()) && retry.wait(attempt).await {
+                continue;
+            }
+            send.send(None).await?;
+            bail!(SyncError::http(
+                res.status(),
+                format!("Server returned {}", res.status().as_u16())
+            ))
+        }
+        if received > 0 && res.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            // The server ignored the range, start over.
+            debug!("restarting the download of {:?}", c32);
             send.send(None).await?;
-            bail!("Server returned {}", res.status().as_u16())
+            received = 0;
         }
         let mut size: Option<usize> = res
             .headers()
@@ -116,6 +309,9 @@ async fn download_change(
                     if let Some(ref mut s) = size {
                         *s -= chunk.len();
                     }
+                    received += chunk.len() as u64;
+                    // Only failures in a row count against the attempts.
+                    attempt = 0;
                     send.send(Some(chunk)).await?;
                 }
                 Ok(None) => match size {
@@ -124,10 +320,17 @@ async fn download_change(
                 },
                 Err(e) => {
                     debug!("error {:?}", e);
-                    error!("Error while downloading {:?} from {:?}, retrying", c32, url);
-                    send.send(None).await?;
-                    tokio::time::sleep(std::time::Duration::from_secs_f64(delay)).await;
-                    delay *= 2.;
+                    error!(
+                        "Error while downloading {:?} from {:?}, resuming at {}",
+                        c32, url, received
+                    );
+                    if !retry.wait(attempt.max(1)).await {
+                        send.send(None).await?;
+                        bail!(SyncError::network(format!(
+                            "Failed to download {}: {}",
+                            c32, e
+                        )))
+                    }
                     break;
                 }
             }
@@ -150,9 +353,93 @@ async fn download_change(
     Ok(node)
 }
 
-const POOL_SIZE: usize = 20;
+/// Download the changes `nodes` in a single bundle (see [`crate::bundle`]).
+async fn download_bundle(
+    client: reqwest::Client,
+    concurrency: Arc<tokio::sync::Semaphore>,
+    url: url::Url,
+    headers: Vec<(String, String)>,
+    version: usize,
+    retry: RetryPolicy,
+    path: PathBuf,
+    nodes: Vec<Node>,
+) -> Result<Vec<Node>, anyhow::Error> {
+    let hashes: Vec<_> = nodes.iter().map(|n| n.hash.to_base32()).collect();
+    let hashes = hashes.join(",");
+    let mut attempt = 0;
+    let body = loop {
+        let _permit = concurrency.acquire().await?;
+        let mut req = client
+            .get(url.clone())
+            .query(&[("bundle", &hashes)])
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, version);
+        for (k, v) in headers.iter() {
+            req = req.header(k.as_str(), v.as_str());
+        }
+        attempt += 1;
+        let res = match req.send().await {
+            Ok(res) => res,
+            Err(e) => {
+                debug!("HTTP error {:?}", e);
+                if !retry.wait(attempt).await {
+                    bail!(SyncError::network(format!(
+                        "Failed to download a bundle: {}",
+                        e
+                    )))
+                }
+                continue;
+            }
+        };
+        if !res.status().is_success() {
+            if retry::is_transient(res.status()) && retry.wait(attempt).await {
+                continue;
+            }
+            bail!(SyncError::http(
+                res.status(),
+                format!("Server returned {}", res.status().as_u16())
+            ))
+        }
+        match res.bytes().await {
+            Ok(body) => break body,
+            Err(e) => {
+                error!("Error while downloading a bundle from {:?}, retrying", url);
+                debug!("error {:?}", e);
+                if !retry.wait(attempt).await {
+                    bail!(SyncError::network(format!(
+                        "Failed to download a bundle: {}",
+                        e
+                    )))
+                }
+            }
+        }
+    };
+    let changes = bundle::decode(&body)?;
+    for node in nodes.iter() {
+        let Some((_, change)) = changes.iter().find(|(h, _)| *h == node.hash) else {
+            bail!(SyncError::protocol(format!(
+                "Change {} missing from bundle",
+                node.hash.to_base32()
+            )))
+        };
+        let mut path = path.clone();
+        libatomic::changestore::filesystem::push_filename(&mut path, &node.hash);
+        tokio::fs::create_dir_all(&path.parent().unwrap()).await?;
+        let path_ = path.with_extension("tmp");
+        tokio::fs::write(&path_, change).await?;
+        tokio::fs::rename(&path_, &path).await?;
+    }
+    debug!("download_bundle returning {} changes", nodes.len());
+    Ok(nodes)
+}
+
+/// Default number of downloads in flight
+pub const POOL_SIZE: usize = 20;
 
 impl Http {
+    /// Download `nodes`, with up to `streams` downloads in flight. Nodes
+    /// are sent to `send` as their download completes, which may not be
+    /// the order of `nodes`.
     pub async fn download_nodes(
         &mut self,
         progress_bar: ProgressBar,
@@ -160,69 +447,63 @@ impl Http {
         send: &mut tokio::sync::mpsc::Sender<(Node, bool)>,
         path: &PathBuf,
         _full: bool,
+        streams: usize,
     ) -> Result<(), anyhow::Error> {
         debug!("starting download_nodes http");
-        let mut pool: [Option<tokio::task::JoinHandle<Result<Node, _>>>; POOL_SIZE] =
-            <[_; POOL_SIZE]>::default();
+        let bundles = self.negotiate().await;
+        let pool_size = streams.max(1);
+        let mut pool: Vec<Option<tokio::task::JoinHandle<Result<Vec<Node>, _>>>> =
+            (0..pool_size).map(|_| None).collect();
+        let mut pending = None;
         let mut cur = 0;
-        loop {
+        'outer: loop {
             if let Some(t) = pool[cur].take() {
                 debug!("waiting for process {:?}", cur);
-                let node_ = t.await.unwrap().unwrap();
-                debug!("sending {:?}", node_);
-                progress_bar.inc(1);
-                if send.send((node_, true)).await.is_err() {
-                    debug!("err for {:?}", node_);
-                    break;
+                for node_ in t.await.unwrap().unwrap() {
+                    debug!("sending {:?}", node_);
+                    progress_bar.inc(1);
+                    if send.send((node_, true)).await.is_err() {
+                        debug!("err for {:?}", node_);
+                        break 'outer;
+                    }
+                    debug!("sent {:?}", node_);
                 }
-                debug!("sent {:?}", node_);
                 continue;
             }
             let mut next = cur;
-            for i in 1..POOL_SIZE {
-                if pool[(cur + i) % POOL_SIZE].is_some() {
-                    next = (cur + i) % POOL_SIZE;
+            for i in 1..pool_size {
+                if pool[(cur + i) % pool_size].is_some() {
+                    next = (cur + i) % pool_size;
                     break;
                 }
             }
             if next == cur {
-                if let Some(node) = nodes.recv().await {
-                    debug!("downloading on process {:?}: {:?}", cur, node);
-                    pool[cur] = Some(tokio::spawn(download_change(
-                        self.client.clone(),
-                        self.url.clone(),
-                        self.headers.clone(),
-                        path.clone(),
-                        node,
-                    )));
-                    cur = (cur + 1) % POOL_SIZE;
+                if let Some(next_nodes) = bundle::next_nodes(nodes, &mut pending, bundles).await {
+                    debug!("downloading on process {:?}: {:?}", cur, next_nodes);
+                    pool[cur] = Some(self.spawn_download(path, next_nodes));
+                    cur = (cur + 1) % pool_size;
                 } else {
                     break;
                 }
             } else {
                 tokio::select! {
-                    node = nodes.recv() => {
-                        if let Some(node) = node {
-                            debug!("downloading on process {:?}: {:?}", cur, node);
-                            pool[cur] = Some(tokio::spawn(download_change(
-                                self.client.clone(),
-                                self.url.clone(),
-                                self.headers.clone(),
-                                path.clone(),
-                                node,
-                            )));
-                            cur = (cur + 1) % POOL_SIZE;
+                    next_nodes = bundle::next_nodes(nodes, &mut pending, bundles) => {
+                        if let Some(next_nodes) = next_nodes {
+                            debug!("downloading on process {:?}: {:?}", cur, next_nodes);
+                            pool[cur] = Some(self.spawn_download(path, next_nodes));
+                            cur = (cur + 1) % pool_size;
                         } else {
                             break;
                         }
                     }
-                    node = pool[next].as_mut().unwrap() => {
+                    downloaded = pool[next].as_mut().unwrap() => {
                         pool[next] = None;
-                        let node = node??;
-                        progress_bar.inc(1);
-                        if send.send((node, true)).await.is_err() {
-                            debug!("err for {:?}", node);
-                            break;
+                        for node in downloaded?? {
+                            progress_bar.inc(1);
+                            if send.send((node, true)).await.is_err() {
+                                debug!("err for {:?}", node);
+                                break 'outer;
+                            }
                         }
                     }
                 }
@@ -231,98 +512,420 @@ impl Http {
         Ok(())
     }
 
+    /// Download `nodes`, a single node or a bundle of changes.
+    fn spawn_download(
+        &self,
+        path: &PathBuf,
+        mut nodes: Vec<Node>,
+    ) -> tokio::task::JoinHandle<Result<Vec<Node>, anyhow::Error>> {
+        let (client, concurrency, url, headers, version, retry, path) = (
+            self.client.clone(),
+            self.concurrency.clone(),
+            self.url.clone(),
+            self.headers.clone(),
+            self.version,
+            self.retry,
+            path.clone(),
+        );
+        if nodes.len() > 1 {
+            tokio::spawn(download_bundle(
+                client,
+                concurrency,
+                url,
+                headers,
+                version,
+                retry,
+                path,
+                nodes,
+            ))
+        } else {
+            let node = nodes.pop().unwrap();
+            tokio::spawn(async move {
+                Ok(vec![
+                    download_change(
+                        client,
+                        concurrency,
+                        url,
+                        headers,
+                        version,
+                        retry,
+                        path,
+                        node,
+                    )
+                    .await?,
+                ])
+            })
+        }
+    }
+
+    /// Negotiate the protocol version with the remote, whether it takes
+    /// bundles of changes and its attribution protocol, from its discovery
+    /// answer. Remotes that
+    /// reject [`crate::PROTOCOL_VERSION`] are asked again at the newest
+    /// version they list that this client also speaks, and remotes that
+    /// predate the negotiation are at [`LEGACY_VERSION`].
+    pub async fn negotiate(&mut self) -> bool {
+        if let Some(bundles) = self.bundles {
+            return bundles;
+        }
+        let discovery = loop {
+            let mut req = self
+                .client
+                .get(self.url.clone())
+                .header(reqwest::header::USER_AGENT, USER_AGENT)
+                .header(VERSION_HEADER, self.version);
+            for (k, v) in self.headers.iter() {
+                req = req.header(k.as_str(), v.as_str());
+            }
+            let res = match req.send().await {
+                Ok(res) => res,
+                Err(e) => {
+                    debug!("discovery failed: {:?}", e);
+                    break None;
+                }
+            };
+            if res.status() == reqwest::StatusCode::BAD_REQUEST {
+                let served = res
+                    .headers()
+                    .get(VERSIONS_HEADER)
+                    .and_then(|v| v.to_str().ok());
+                if let Some(version) = served.and_then(|s| fallback_version(self.version, s)) {
+                    info!(
+                        "Remote rejected protocol version {}, falling back to {}",
+                        self.version, version
+                    );
+                    self.version = version;
+                    continue;
+                }
+                break None;
+            }
+            if !res.status().is_success() {
+                break None;
+            }
+            self.version = res
+                .headers()
+                .get(VERSION_HEADER)
+                .and_then(|v| v.to_str().ok()?.parse().ok())
+                .unwrap_or(LEGACY_VERSION)
+                .min(self.version);
+            break res.json::<serde_json::Value>().await.ok();
+        };
+        let bundles = discovery
+            .as_ref()
+            .and_then(|d| d.get("bundles"))
+            .and_then(|b| b.as_array())
+            .map_or(false, |b| b.iter().any(|c| c == "zstd"));
+        self.validates = discovery
+            .as_ref()
+            .and_then(|d| d.get("validate"))
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false);
+        self.attribution = discovery
+            .as_ref()
+            .and_then(|d| d.get("attribution"))
+            .and_then(|a| serde_json::from_value(a.clone()).ok());
+        self.notes = discovery
+            .as_ref()
+            .and_then(|d| d.get(crate::NOTES_CAPABILITY))
+            .and_then(|n| n.as_bool())
+            .unwrap_or(false);
+        debug!(
+            "protocol version: {:?}, bundles: {:?}, validates: {:?}, attribution: {:?}, notes: {:?}",
+            self.version, bundles, self.validates, self.attribution, self.notes
+        );
+        self.bundles = Some(bundles);
+        bundles
+    }
+
+    /// Upload `batches`, as returned by [`crate::order::upload_batches`]:
+    /// the nodes of a batch are sent concurrently, up to the limit of
+    /// requests of this remote. If the remote takes bundles, the changes
+    /// of each batch are sent in bundles of up to [`bundle::MAX_CHANGES`].
     pub async fn upload_nodes(
         &mut self,
         progress_bar: ProgressBar,
-        mut local: PathBuf,
+        local: PathBuf,
         to_channel: Option<&str>,
-        nodes: &[Node],
+        batches: &[Vec<Node>],
     ) -> Result<(), anyhow::Error> {
-        for node in nodes {
-            let url = self.url.clone();
-            let channel_name = to_channel;
-            let mut to_channel = if let Some(ch) = channel_name {
-                vec![("to_channel", ch)]
+        let bundles = self.negotiate().await;
+        let this = &*self;
+        let mut already_present = 0;
+        for batch in batches {
+            let (changes, singles): (Vec<&Node>, Vec<&Node>) = if bundles {
+                batch.iter().partition(|node| !node.is_tag())
             } else {
-                Vec::new()
+                (Vec::new(), batch.iter().collect())
             };
-            let base32;
-            let body = match node.node_type {
-                NodeType::Change => {
-                    libatomic::changestore::filesystem::push_filename(&mut local, &node.hash);
-                    let change = std::fs::read(&local).map_err(|e| {
+            let bundled =
+                futures::future::try_join_all(changes.chunks(bundle::MAX_CHANGES).map(|chunk| {
+                    let local = local.clone();
+                    let progress_bar = &progress_bar;
+                    async move {
+                        let present = this.upload_bundle(local, to_channel, chunk).await?;
+                        progress_bar.inc(chunk.len() as u64);
+                        Ok::<_, anyhow::Error>(present)
+                    }
+                }));
+            let single = futures::future::try_join_all(singles.into_iter().map(|node| {
+                let local = local.clone();
+                let progress_bar = &progress_bar;
+                async move {
+                    let present = this.upload_node(local, to_channel, node).await?;
+                    progress_bar.inc(1);
+                    Ok::<_, anyhow::Error>(present)
+                }
+            }));
+            let (bundled, single) = futures::future::try_join(bundled, single).await?;
+            already_present += bundled.into_iter().sum::<usize>();
+            already_present += single.into_iter().filter(|p| *p).count();
+        }
+        if already_present > 0 {
+            info!(
+                "{} change(s) were already present on the remote",
+                already_present
+            );
+        }
+        Ok(())
+    }
+
+    /// Upload the changes `nodes` in a single bundle, returning how many
+    /// of them the remote already had
+    async fn upload_bundle(
+        &self,
+        local: PathBuf,
+        to_channel: Option<&str>,
+        nodes: &[&Node],
+    ) -> Result<usize, anyhow::Error> {
+        if self.validates {
+            for node in nodes {
+                let mut path = local.clone();
+                libatomic::changestore::filesystem::push_filename(&mut path, &node.hash);
+                self.validate_large(&path, &node.hash, to_channel).await?;
+            }
+        }
+        // Change files are only read once the bundle can be sent, so that
+        // waiting uploads don't hold their changes in memory.
+        let _permit = self.concurrency.acquire().await?;
+        let mut changes = Vec::with_capacity(nodes.len());
+        for node in nodes {
+            let mut path = local.clone();
+            libatomic::changestore::filesystem::push_filename(&mut path, &node.hash);
+            changes.push(read_change(&path, &node.hash)?);
+        }
+        let body = bundle::encode(
+            nodes
+                .iter()
+                .map(|n| &n.hash)
+                .zip(changes.iter().map(|c| &c[..])),
+        )?;
+        let mut query = vec![("applybundle", "")];
+        if let Some(ch) = to_channel {
+            query.push(("to_channel", ch))
+        }
+        let mut req = self
+            .client
+            .post(self.url.clone())
+            .query(&query)
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version);
+        for (k, v) in self.headers.iter() {
+            req = req.header(k.as_str(), v.as_str());
+        }
+        let resp = req.body(body).send().await?;
+        let stat = resp.status();
+        if !stat.is_success() {
+            let body = resp.text().await?;
+            if !body.is_empty() {
+                bail!(SyncError::http(
+                    stat,
+                    format!("The HTTP server returned an error: {}", body)
+                ))
+            } else {
+                bail!(SyncError::http(
+                    stat,
+                    format!("HTTP Error {}", stat.as_u16())
+                ))
+            }
+        }
+        let result: serde_json::Value = resp.json().await?;
+        Ok(result
+            .get("already_present")
+            .and_then(|n| n.as_u64())
+            .unwrap_or(0) as usize)
+    }
+
+    /// Upload `node`, returning whether the remote already had it
+    async fn upload_node(
+        &self,
+        mut local: PathBuf,
+        to_channel: Option<&str>,
+        node: &Node,
+    ) -> Result<bool, anyhow::Error> {
+        let url = self.url.clone();
+        let channel_name = to_channel;
+        let mut to_channel = if let Some(ch) = channel_name {
+            vec![("to_channel", ch)]
+        } else {
+            Vec::new()
+        };
+        if self.validates && matches!(node.node_type, NodeType::Change) {
+            let mut path = local.clone();
+            libatomic::changestore::filesystem::push_filename(&mut path, &node.hash);
+            self.validate_large(&path, &node.hash, channel_name).await?;
+        }
+        // The node is only read once it can be sent, so that waiting
+        // uploads don't hold their changes in memory.
+        let _permit = self.concurrency.acquire().await?;
+        let base32;
+        let body = match node.node_type {
+            NodeType::Change => {
+                libatomic::changestore::filesystem::push_filename(&mut local, &node.hash);
+                let change = read_change(&local, &node.hash)?;
+                base32 = node.hash.to_base32();
+                to_channel.push(("apply", &base32));
+                change
+            }
+            NodeType::Tag => {
+                // Tag upload: send SHORT tag data to server
+                // Server will regenerate full tag file from channel state
+                libatomic::changestore::filesystem::push_tag_filename(&mut local, &node.state);
+
+                // Open tag file and extract short version
+                let mut tag_file =
+                    libatomic::tag::OpenTagFile::open(&local, &node.state).map_err(|e| {
                         anyhow::anyhow!(
-                            "Failed to read change file for hash {}: {} (path: {})",
-                            node.hash.to_base32(),
+                            "Failed to open tag file for state {}: {} (path: {})",
+                            node.state.to_base32(),
                             e,
                             local.display()
                         )
                     })?;
-                    base32 = node.hash.to_base32();
-                    to_channel.push(("apply", &base32));
-                    change
-                }
-                NodeType::Tag => {
-                    // Tag upload: send SHORT tag data to server
-                    // Server will regenerate full tag file from channel state
-                    libatomic::changestore::filesystem::push_tag_filename(&mut local, &node.state);
-
-                    // Open tag file and extract short version
-                    let mut tag_file = libatomic::tag::OpenTagFile::open(&local, &node.state)
-                        .map_err(|e| {
-                            anyhow::anyhow!(
-                                "Failed to open tag file for state {}: {} (path: {})",
-                                node.state.to_base32(),
-                                e,
-                                local.display()
-                            )
-                        })?;
-                    let mut short_data = Vec::new();
-                    tag_file.short(&mut short_data)?;
-
-                    base32 = node.state.to_base32();
-                    to_channel.push(("tagup", &base32));
-
-                    libatomic::changestore::filesystem::pop_filename(&mut local);
-                    short_data
-                }
-            };
-            libatomic::changestore::filesystem::pop_filename(&mut local);
-            debug!("url {:?} {:?}", url, to_channel);
-            let mut req = self
-                .client
-                .post(url)
-                .query(&to_channel)
-                .header(reqwest::header::USER_AGENT, USER_AGENT);
-            for (k, v) in self.headers.iter() {
-                debug!("kv = {:?} {:?}", k, v);
-                req = req.header(k.as_str(), v.as_str());
-            }
-            let resp = req.body(body).send().await?;
-            let stat = resp.status();
+                let mut short_data = Vec::new();
+                tag_file.short(&mut short_data)?;
+
+                base32 = node.state.to_base32();
+                to_channel.push(("tagup", &base32));
 
-            // DIAGNOSTIC: Log response for tag uploads
-            if to_channel.iter().any(|(k, _)| *k == "tagup") {
-                log::info!("Tag upload response status: {}", stat);
+                libatomic::changestore::filesystem::pop_filename(&mut local);
+                short_data
             }
+        };
+        libatomic::changestore::filesystem::pop_filename(&mut local);
+        debug!("url {:?} {:?}", url, to_channel);
+        let mut req = self
+            .client
+            .post(url)
+            .query(&to_channel)
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version);
+        for (k, v) in self.headers.iter() {
+            debug!("kv = {:?} {:?}", k, v);
+            req = req.header(k.as_str(), v.as_str());
+        }
+        let resp = req.body(body).send().await?;
+        let stat = resp.status();
+
+        // DIAGNOSTIC: Log response for tag uploads
+        if to_channel.iter().any(|(k, _)| *k == "tagup") {
+            log::info!("Tag upload response status: {}", stat);
+        }
 
-            if !stat.is_success() {
-                let body = resp.text().await?;
-                if !body.is_empty() {
-                    bail!("The HTTP server returned an error: {}", body)
+        if !stat.is_success() {
+            let body = resp.text().await?;
+            if !body.is_empty() {
+                bail!(SyncError::http(
+                    stat,
+                    format!("The HTTP server returned an error: {}", body)
+                ))
+            } else {
+                if let Some(reason) = stat.canonical_reason() {
+                    bail!(SyncError::http(
+                        stat,
+                        format!("HTTP Error {}: {}", stat.as_u16(), reason)
+                    ))
                 } else {
-                    if let Some(reason) = stat.canonical_reason() {
-                        bail!("HTTP Error {}: {}", stat.as_u16(), reason)
-                    } else {
-                        bail!("HTTP Error {}", stat.as_u16())
-                    }
+                    bail!(SyncError::http(
+                        stat,
+                        format!("HTTP Error {}", stat.as_u16())
+                    ))
                 }
             }
-            progress_bar.inc(1);
+        }
+        let already_present = resp
+            .headers()
+            .get(APPLY_RESULT_HEADER)
+            .map_or(false, |v| v == ALREADY_PRESENT);
+        if already_present {
+            debug!("{} already present on the remote", base32);
+        }
+        Ok(already_present)
+    }
+
+    /// [`Http::validate`] the change `hash`, stored at `path`, if it is at
+    /// least [`VALIDATE_SIZE`] bytes long.
+    async fn validate_large(
+        &self,
+        path: &std::path::Path,
+        hash: &Hash,
+        to_channel: Option<&str>,
+    ) -> Result<(), anyhow::Error> {
+        let size = std::fs::metadata(path)
+            .map_err(|e| read_error(path, hash, e))?
+            .len();
+        if size >= VALIDATE_SIZE {
+            self.validate(path, hash, size, to_channel).await?;
         }
         Ok(())
     }
 
+    /// Ask the remote whether it would apply the change `hash`, stored at
+    /// `path` and `size` bytes long, before uploading it (see
+    /// [`crate::summary`]). Only the policies of the remote fail the
+    /// upload early: other failures are left for the upload to report.
+    async fn validate(
+        &self,
+        path: &std::path::Path,
+        hash: &Hash,
+        size: u64,
+        to_channel: Option<&str>,
+    ) -> Result<(), anyhow::Error> {
+        let change = libatomic::change::Change::deserialize(&path.to_string_lossy(), Some(hash))?;
+        let summary = ChangeSummary::new(&change, size);
+        let base32 = hash.to_base32();
+        let mut query = vec![("validate", base32.as_str())];
+        if let Some(ch) = to_channel {
+            query.push(("to_channel", ch))
+        }
+        let mut req = self
+            .client
+            .post(self.url.clone())
+            .query(&query)
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(VERSION_HEADER, self.version);
+        for (k, v) in self.headers.iter() {
+            req = req.header(k.as_str(), v.as_str());
+        }
+        let _permit = self.concurrency.acquire().await?;
+        let resp = req.body(serde_json::to_vec(&summary)?).send().await?;
+        let stat = resp.status();
+        if stat.is_success() {
+            return Ok(());
+        }
+        let body = resp.text().await?;
+        // Missing dependencies and forbidden paths (422), unapproved
+        // changes and confidential channels (403), and quotas (507).
+        if matches!(stat.as_u16(), 403 | 422 | 507) {
+            bail!(SyncError::rejected(format!(
+                "The HTTP server rejected change {}: {}",
+                base32, body
+            )))
+        }
+        debug!("validation of {} failed ({}): {}", base32, stat, body);
+        Ok(())
+    }
+
     pub async fn download_changelist<
         A,
         F: FnMut(&mut A, u64, Hash, libatomic::Merkle, bool) -> Result<(), anyhow::Error>,
@@ -343,20 +946,27 @@ impl Http {
             .client
             .get(url)
             .query(&query)
-            .header(reqwest::header::USER_AGENT, USER_AGENT);
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version);
         for (k, v) in self.headers.iter() {
             debug!("kv = {:?} {:?}", k, v);
             req = req.header(k.as_str(), v.as_str());
         }
-        let res = req.send().await?;
+        let res = retry::send(&self.retry, req).await?;
         let status = res.status();
         if !status.is_success() {
             match serde_json::from_slice::<libatomic::RemoteError>(&*res.bytes().await?) {
                 Ok(remote_err) => return Err(remote_err.into()),
                 Err(_) if status.as_u16() == 404 => {
-                    bail!("Repository `{}` not found (404)", self.url)
+                    bail!(SyncError::not_found(format!(
+                        "Repository `{}` not found (404)",
+                        self.url
+                    )))
                 }
-                Err(_) => bail!("Http request failed with status code: {}", status),
+                Err(_) => bail!(SyncError::http(
+                    status,
+                    format!("Http request failed with status code: {}", status)
+                )),
             }
         }
         let resp = res.bytes().await?;
@@ -365,12 +975,12 @@ impl Http {
             for l in data.lines() {
                 debug!("l = {:?}", l);
                 if !l.is_empty() {
-                    match super::parse_line(l)? {
-                        super::ListLine::Change { n, m, h, tag } => f(a, n, h, m, tag)?,
-                        super::ListLine::Position(pos) => {
+                    match ListLine::decode(l)? {
+                        ListLine::Change { n, m, h, tag } => f(a, n, h, m, tag)?,
+                        ListLine::Position(pos) => {
                             result.insert(pos);
                         }
-                        super::ListLine::Error(e) => {
+                        ListLine::Error(e) => {
                             let mut stderr = std::io::stderr();
                             writeln!(stderr, "{}", e)?;
                         }
@@ -389,6 +999,7 @@ impl Http {
         mid: Option<u64>,
     ) -> Result<Option<(u64, libatomic::Merkle, libatomic::Merkle)>, anyhow::Error> {
         debug!("get_state {:?}", self.url);
+        self.negotiate().await;
         let url = format!("{}", self.url);
         let q = if let Some(mid) = mid {
             [
@@ -402,14 +1013,18 @@ impl Http {
             .client
             .get(&url)
             .query(&q)
-            .header(reqwest::header::USER_AGENT, USER_AGENT);
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version);
         for (k, v) in self.headers.iter() {
             debug!("kv = {:?} {:?}", k, v);
             req = req.header(k.as_str(), v.as_str());
         }
-        let res = req.send().await?;
+        let res = retry::send(&self.retry, req).await?;
         if !res.status().is_success() {
-            bail!("HTTP error {:?}", res.status())
+            bail!(SyncError::http(
+                res.status(),
+                format!("HTTP error {:?}", res.status())
+            ))
         }
         let resp = res.bytes().await?;
         let resp = std::str::from_utf8(&resp)?;
@@ -428,6 +1043,38 @@ impl Http {
         }
     }
 
+    /// Whether the channel's state digest is still `etag`, asked with a
+    /// conditional request. `None` if the server doesn't serve digests.
+    pub async fn digest_matches(&self, etag: &str) -> Result<Option<bool>, anyhow::Error> {
+        debug!("digest_matches {:?} {:?}", self.url, etag);
+        let url = format!("{}", self.url);
+        let q = [("channel", self.channel.clone()), ("digest", String::new())];
+        let mut req = self
+            .client
+            .get(&url)
+            .query(&q)
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version)
+            .header(reqwest::header::IF_NONE_MATCH, etag);
+        for (k, v) in self.headers.iter() {
+            debug!("kv = {:?} {:?}", k, v);
+            req = req.header(k.as_str(), v.as_str());
+        }
+        let res = retry::send(&self.retry, req).await?;
+        if res.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(Some(true));
+        }
+        if !res.status().is_success() {
+            return Ok(None);
+        }
+        // Proxies may drop the condition and forward the full response.
+        Ok(res
+            .headers()
+            .get(reqwest::header::ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v == etag))
+    }
+
     pub async fn get_id(&self) -> Result<Option<libatomic::pristine::RemoteId>, anyhow::Error> {
         debug!("get_state {:?}", self.url);
         let url = format!("{}", self.url);
@@ -436,14 +1083,18 @@ impl Http {
             .client
             .get(&url)
             .query(&q)
-            .header(reqwest::header::USER_AGENT, USER_AGENT);
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version);
         for (k, v) in self.headers.iter() {
             debug!("kv = {:?} {:?}", k, v);
             req = req.header(k.as_str(), v.as_str());
         }
-        let res = req.send().await?;
+        let res = retry::send(&self.retry, req).await?;
         if !res.status().is_success() {
-            bail!("HTTP error {:?}", res.status())
+            bail!(SyncError::http(
+                res.status(),
+                format!("HTTP error {:?}", res.status())
+            ))
         }
         let resp = res.bytes().await?;
         debug!("resp = {:?}", resp);
@@ -472,10 +1123,14 @@ impl Http {
         };
         let res = res
             .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version)
             .send()
             .await?;
         if !res.status().is_success() {
-            bail!("HTTP error {:?}", res.status())
+            bail!(SyncError::http(
+                res.status(),
+                format!("HTTP error {:?}", res.status())
+            ))
         }
         use futures_util::StreamExt;
         let mut stream = res.bytes_stream();
@@ -511,14 +1166,18 @@ impl Http {
                     0u32.to_string()
                 },
             )])
-            .header(reqwest::header::USER_AGENT, USER_AGENT);
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version);
         for (k, v) in self.headers.iter() {
             debug!("kv = {:?} {:?}", k, v);
             req = req.header(k.as_str(), v.as_str());
         }
         let res = req.send().await?;
         if !res.status().is_success() {
-            bail!("HTTP error {:?}", res.status())
+            bail!(SyncError::http(
+                res.status(),
+                format!("HTTP error {:?}", res.status())
+            ))
         }
         use serde_derive::*;
         #[derive(Debug, Deserialize)]
@@ -551,14 +1210,18 @@ impl Http {
             .client
             .get(&url)
             .query(&q)
-            .header(reqwest::header::USER_AGENT, USER_AGENT);
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version);
         for (k, v) in self.headers.iter() {
             debug!("kv = {:?} {:?}", k, v);
             req = req.header(k.as_str(), v.as_str());
         }
         let res = req.send().await?;
         if !res.status().is_success() {
-            bail!("HTTP error {:?}", res.status())
+            bail!(SyncError::http(
+                res.status(),
+                format!("HTTP error {:?}", res.status())
+            ))
         }
         let resp = res.bytes().await?;
         debug!("resp = {:?}", resp);
@@ -570,16 +1233,287 @@ impl Http {
             .client
             .get(&url)
             .query(&q)
-            .header(reqwest::header::USER_AGENT, USER_AGENT);
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version);
         for (k, v) in self.headers.iter() {
             debug!("kv = {:?} {:?}", k, v);
             req = req.header(k.as_str(), v.as_str());
         }
         let res = req.send().await?;
         if !res.status().is_success() {
-            bail!("HTTP error {:?}", res.status())
+            bail!(SyncError::http(
+                res.status(),
+                format!("HTTP error {:?}", res.status())
+            ))
+        }
+
+        Ok(())
+    }
+
+    /// The attribution protocol of the remote, if it announced one.
+    pub async fn attribution_protocol(&mut self) -> Option<AttributionProtocol> {
+        self.negotiate().await;
+        self.attribution.clone()
+    }
+
+    /// Download the attribution of the changes `hashes`, asked for in runs
+    /// of up to [`bundle::MAX_CHANGES`] changes.
+    pub async fn download_attribution(
+        &mut self,
+        hashes: &[Hash],
+    ) -> Result<Vec<ChangeAttribution>, anyhow::Error> {
+        let mut received = Vec::new();
+        for hashes in hashes.chunks(bundle::MAX_CHANGES) {
+            let hashes: Vec<_> = hashes.iter().map(|h| h.to_base32()).collect();
+            let mut req = self
+                .client
+                .get(self.url.clone())
+                .query(&[("attribution", hashes.join(","))])
+                .header(reqwest::header::USER_AGENT, USER_AGENT)
+                .header(VERSION_HEADER, self.version);
+            for (k, v) in self.headers.iter() {
+                req = req.header(k.as_str(), v.as_str());
+            }
+            let res = req.send().await?;
+            if !res.status().is_success() {
+                bail!(SyncError::http(
+                    res.status(),
+                    format!("HTTP error {:?}", res.status())
+                ))
+            }
+            received.extend(res.json::<Vec<ChangeAttribution>>().await?);
+        }
+        Ok(received)
+    }
+
+    /// Send the attribution of changes to the remote repository.
+    pub async fn upload_attribution(
+        &mut self,
+        attribution: &[ChangeAttribution],
+    ) -> Result<(), anyhow::Error> {
+        let mut req = self
+            .client
+            .post(self.url.clone())
+            .query(&[("attribution", "")])
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version)
+            .json(attribution);
+        for (k, v) in self.headers.iter() {
+            req = req.header(k.as_str(), v.as_str());
+        }
+        let res = req.send().await?;
+        if !res.status().is_success() {
+            bail!(SyncError::http(
+                res.status(),
+                format!("HTTP error {:?}", res.status())
+            ))
+        }
+        Ok(())
+    }
+
+    /// Whether the remote exchanges notes. Older servers answer the
+    /// requests exchanging notes with errors.
+    pub async fn exchanges_notes(&mut self) -> bool {
+        self.negotiate().await;
+        self.notes
+    }
+
+    /// Download the notes of the remote repository.
+    pub async fn download_notes(
+        &mut self,
+    ) -> Result<Vec<atomic_repository::notes::Note>, anyhow::Error> {
+        let mut req = self
+            .client
+            .get(self.url.clone())
+            .query(&[("notes", "")])
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version);
+        for (k, v) in self.headers.iter() {
+            req = req.header(k.as_str(), v.as_str());
+        }
+        let res = req.send().await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            // Servers without notes support
+            return Ok(Vec::new());
+        }
+        if !res.status().is_success() {
+            bail!(SyncError::http(
+                res.status(),
+                format!("HTTP error {:?}", res.status())
+            ))
         }
+        Ok(res.json().await?)
+    }
+
+    /// Send notes to the remote repository, which merges them with its own.
+    pub async fn upload_notes(
+        &mut self,
+        notes: &[atomic_repository::notes::Note],
+    ) -> Result<(), anyhow::Error> {
+        let mut req = self
+            .client
+            .post(self.url.clone())
+            .query(&[("notes", "")])
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version)
+            .json(notes);
+        for (k, v) in self.headers.iter() {
+            req = req.header(k.as_str(), v.as_str());
+        }
+        let res = req.send().await?;
+        if !res.status().is_success() {
+            bail!(SyncError::http(
+                res.status(),
+                format!("HTTP error {:?}", res.status())
+            ))
+        }
+        Ok(())
+    }
 
+    /// Download the external links of the changes of the remote repository.
+    pub async fn download_links(
+        &mut self,
+    ) -> Result<Vec<atomic_repository::links::Link>, anyhow::Error> {
+        let mut req = self
+            .client
+            .get(self.url.clone())
+            .query(&[("links", "")])
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version);
+        for (k, v) in self.headers.iter() {
+            req = req.header(k.as_str(), v.as_str());
+        }
+        let res = req.send().await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            // Servers without links support
+            return Ok(Vec::new());
+        }
+        if !res.status().is_success() {
+            bail!(SyncError::http(
+                res.status(),
+                format!("HTTP error {:?}", res.status())
+            ))
+        }
+        Ok(res.json().await?)
+    }
+
+    /// Send links to the remote repository, which merges them with its own.
+    pub async fn upload_links(
+        &mut self,
+        links: &[atomic_repository::links::Link],
+    ) -> Result<(), anyhow::Error> {
+        let mut req = self
+            .client
+            .post(self.url.clone())
+            .query(&[("links", "")])
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version)
+            .json(links);
+        for (k, v) in self.headers.iter() {
+            req = req.header(k.as_str(), v.as_str());
+        }
+        let res = req.send().await?;
+        if !res.status().is_success() {
+            bail!(SyncError::http(
+                res.status(),
+                format!("HTTP error {:?}", res.status())
+            ))
+        }
         Ok(())
     }
+
+    /// Upload `envelope`, the encrypted change `hash`, to the confidential
+    /// channel `to_channel`, returning whether the remote already had it
+    pub async fn upload_envelope(
+        &self,
+        to_channel: &str,
+        hash: &libatomic::Hash,
+        envelope: Vec<u8>,
+    ) -> Result<bool, anyhow::Error> {
+        let base32 = hash.to_base32();
+        let mut req = self
+            .client
+            .post(self.url.clone())
+            .query(&[("envelope", base32.as_str()), ("to_channel", to_channel)])
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version);
+        for (k, v) in self.headers.iter() {
+            req = req.header(k.as_str(), v.as_str());
+        }
+        let _permit = self.concurrency.acquire().await?;
+        let res = req.body(envelope).send().await?;
+        if !res.status().is_success() {
+            let status = res.status();
+            let body = res.text().await?;
+            if !body.is_empty() {
+                bail!(SyncError::http(
+                    status,
+                    format!("The HTTP server returned an error: {}", body)
+                ))
+            }
+            bail!(SyncError::http(status, format!("HTTP error {:?}", status)))
+        }
+        Ok(res
+            .headers()
+            .get(APPLY_RESULT_HEADER)
+            .map_or(false, |v| v == ALREADY_PRESENT))
+    }
+
+    /// The hashes of the envelopes of the confidential channel `channel`,
+    /// in the order they were uploaded
+    pub async fn list_envelopes(
+        &self,
+        channel: &str,
+    ) -> Result<Vec<libatomic::Hash>, anyhow::Error> {
+        let mut req = self
+            .client
+            .get(self.url.clone())
+            .query(&[("envelopes", channel)])
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version);
+        for (k, v) in self.headers.iter() {
+            req = req.header(k.as_str(), v.as_str());
+        }
+        let res = req.send().await?;
+        if !res.status().is_success() {
+            bail!(SyncError::http(
+                res.status(),
+                format!("HTTP error {:?}", res.status())
+            ))
+        }
+        let hashes: Vec<String> = res.json().await?;
+        hashes
+            .iter()
+            .map(|h| {
+                libatomic::Hash::from_base32(h.as_bytes()).ok_or_else(|| {
+                    anyhow::anyhow!(SyncError::protocol(format!("Invalid envelope hash {}", h)))
+                })
+            })
+            .collect()
+    }
+
+    /// Download the envelope of change `hash`
+    pub async fn download_envelope(
+        &self,
+        hash: &libatomic::Hash,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        let mut req = self
+            .client
+            .get(self.url.clone())
+            .query(&[("envelope", hash.to_base32())])
+            .header(reqwest::header::USER_AGENT, USER_AGENT)
+            .header(VERSION_HEADER, self.version);
+        for (k, v) in self.headers.iter() {
+            req = req.header(k.as_str(), v.as_str());
+        }
+        let _permit = self.concurrency.acquire().await?;
+        let res = req.send().await?;
+        if !res.status().is_success() {
+            bail!(SyncError::http(
+                res.status(),
+                format!("HTTP error {:?}", res.status())
+            ))
+        }
+        Ok(res.bytes().await?.to_vec())
+    }
 }
@@ -1,11 +1,10 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use anyhow::{bail, Context};
 use async_trait::async_trait;
-use lazy_static::lazy_static;
 use libatomic::pristine::{
     sanakirja::MutTxn, Base32, ChannelRef, GraphIter, Hash, Merkle, MutTxnT, NodeId, NodeType,
     RemoteRef, SerializedMerkle, TxnT,
@@ -16,6 +15,7 @@ use log::{debug, info};
 
 use atomic_config::*;
 use atomic_identity::Complete;
+use atomic_repository::hooks::{self, HookNode};
 use atomic_repository::*;
 
 pub mod ssh;
@@ -29,16 +29,71 @@ use http::*;
 
 pub mod attribution;
 
+pub mod bundle;
+
+pub mod cache;
+
+pub mod combined;
+
+pub mod confidential;
+
+#[cfg(feature = "conformance")]
+pub mod conformance;
+
+pub mod failure;
+use failure::SyncError;
+
+#[cfg(feature = "git")]
+pub mod git;
+
+pub mod object_store;
+
+pub mod offline;
+
+pub mod order;
+
+pub mod pin;
+
+pub mod protocol;
+
+pub mod quarantine;
+
+pub mod retry;
+
+pub mod revalidate;
+
+pub mod sparse;
+
+pub mod summary;
+
+pub mod timing;
+use timing::PullPhase;
+
 use atomic_interaction::{
     ProgressBar, Spinner, APPLY_MESSAGE, COMPLETE_MESSAGE, DOWNLOAD_MESSAGE, UPLOAD_MESSAGE,
 };
 
-pub const PROTOCOL_VERSION: usize = 4;
+pub const PROTOCOL_VERSION: usize = 5;
+
+/// Capability announced by servers exchanging notes (see
+/// [`atomic_repository::notes`]): over SSH after their answers to `state`,
+/// and over HTTP as `"notes": true` in their discovery answer.
+pub const NOTES_CAPABILITY: &str = "notes";
+
+/// Whether the answer of a server to `state`, over SSH, announces notes.
+pub fn notes_announced(state_line: &str) -> bool {
+    state_line.split_whitespace().any(|w| w == NOTES_CAPABILITY)
+}
 
 pub enum RemoteRepo {
     Local(Local),
     Ssh(Ssh),
     Http(Http),
+    /// A Git branch mirrored to and from the channel, see [`git`]
+    #[cfg(feature = "git")]
+    Git(git::Git),
+    /// A bucket of an S3-compatible object store, see [`object_store`]
+    ObjectStore(object_store::ObjectStore),
     LocalChannel(String),
     None,
 }
@@ -86,6 +141,17 @@ impl Node {
         self.node_type == NodeType::Tag
     }
 
+    /// The node, as given to hooks. Nodes built without a state, such as
+    /// the nodes selected for a push, have no state in the payload.
+    pub fn hook_node(&self) -> HookNode {
+        let state = if self.state == Merkle::zero() {
+            None
+        } else {
+            Some(&self.state)
+        };
+        HookNode::new(&self.hash, state, self.node_type)
+    }
+
     /// Get the node type as a string marker for protocol serialization
     pub fn type_marker(&self) -> &'static str {
         match self.node_type {
@@ -204,12 +270,14 @@ impl ToRemote for RemoteConfig {
                         return Ok(RemoteRepo::Ssh(c));
                     }
                 }
-                bail!("Remote not found: {:?}", ssh)
+                bail!(SyncError::not_found(format!("Remote not found: {:?}", ssh)))
             }
             RemoteConfig::Http {
                 http,
                 headers,
                 name,
+                connection,
+                retry,
             } => {
                 let mut h = Vec::new();
                 for (k, v) in headers.iter() {
@@ -222,14 +290,20 @@ impl ToRemote for RemoteConfig {
                         }
                     }
                 }
+                let url: url::Url = http.parse()?;
                 return Ok(RemoteRepo::Http(Http {
-                    url: http.parse().unwrap(),
+                    client: pin::client(&url, no_cert_check, connection).await?,
+                    url,
                     channel: channel.to_string(),
-                    client: reqwest::ClientBuilder::new()
-                        .danger_accept_invalid_certs(no_cert_check)
-                        .build()?,
                     headers: h,
                     name: name.to_string(),
+                    concurrency: http::limiter(connection),
+                    bundles: None,
+                    validates: false,
+                    attribution: None,
+                    notes: false,
+                    version: PROTOCOL_VERSION,
+                    retry: retry::RetryPolicy::new(retry),
                 }));
             }
         }
@@ -244,18 +318,37 @@ pub async fn unknown_remote(
     no_cert_check: bool,
     with_path: bool,
 ) -> Result<RemoteRepo, anyhow::Error> {
+    #[cfg(feature = "git")]
+    if git::git_url(name).is_some() {
+        debug!("unknown_remote, git = {:?}", name);
+        let (name, channel) = (name.to_string(), channel.to_string());
+        let g = tokio::task::spawn_blocking(move || git::Git::open(&name, &channel)).await??;
+        return Ok(RemoteRepo::Git(g));
+    }
+    if object_store::s3_url(name).is_some() {
+        debug!("unknown_remote, object store = {:?}", name);
+        return Ok(RemoteRepo::ObjectStore(object_store::ObjectStore::open(
+            name, channel,
+        )?));
+    }
     if let Ok(url) = url::Url::parse(name) {
         let scheme = url.scheme();
         if scheme == "http" || scheme == "https" {
             debug!("unknown_remote, http = {:?}", name);
+            let connection = HttpConnection::default();
             return Ok(RemoteRepo::Http(Http {
+                client: pin::client(&url, no_cert_check, &connection).await?,
                 url,
                 channel: channel.to_string(),
-                client: reqwest::ClientBuilder::new()
-                    .danger_accept_invalid_certs(no_cert_check)
-                    .build()?,
                 headers: Vec::new(),
                 name: name.to_string(),
+                concurrency: http::limiter(&connection),
+                bundles: None,
+                validates: false,
+                attribution: None,
+                notes: false,
+                version: PROTOCOL_VERSION,
+                retry: retry::RetryPolicy::default(),
             }));
         } else if scheme == "ssh" {
             if let Some(mut ssh) = ssh_remote(user, name, with_path) {
@@ -264,7 +357,10 @@ pub async fn unknown_remote(
                     return Ok(RemoteRepo::Ssh(c));
                 }
             }
-            bail!("Remote not found: {:?}", name)
+            bail!(SyncError::not_found(format!(
+                "Remote not found: {:?}",
+                name
+            )))
         } else {
             bail!("Remote scheme not supported: {:?}", scheme)
         }
@@ -277,12 +373,11 @@ pub async fn unknown_remote(
             }
         }
 
-        let mut dot_dir = root.join(DOT_DIR);
+        let dot_dir = root.join(DOT_DIR);
         let changes_dir = dot_dir.join(CHANGES_DIR);
 
-        dot_dir.push(PRISTINE_DIR);
         debug!("dot_dir = {:?}", dot_dir);
-        match libatomic::pristine::sanakirja::Pristine::new(&dot_dir.join("db")) {
+        match Repository::open_pristine(&dot_dir) {
             Ok(pristine) => {
                 debug!("pristine done");
                 return Ok(RemoteRepo::Local(Local {
@@ -307,7 +402,10 @@ pub async fn unknown_remote(
             return Ok(RemoteRepo::Ssh(c));
         }
     }
-    bail!("Remote not found: {:?}", name)
+    bail!(SyncError::not_found(format!(
+        "Remote not found: {:?}",
+        name
+    )))
 }
 
 // Extracting this saves a little bit of duplication.
@@ -363,6 +461,8 @@ pub struct PushDelta {
 /// This struct will be created by both a push and pull operation since both
 /// need to update the changelist and will at least try to update the local
 /// remote cache. For a push, this later gets turned into [`PushDelta`].
+/// `discarded` lists the entries of the cache discarded by a forced update
+/// (see [`CacheUpdate::Force`]).
 pub struct RemoteDelta<T: MutTxnTExt + TxnTExt> {
     pub inodes: HashSet<Position<Hash>>,
     pub to_download: Vec<Node>,
@@ -373,6 +473,35 @@ pub struct RemoteDelta<T: MutTxnTExt + TxnTExt> {
     // for unknown changes during shows the hashes in order.
     pub theirs_ge_dichotomy: Vec<(u64, Node)>,
     pub remote_unrecs: Vec<(u64, Node)>,
+    pub discarded: Vec<(u64, Node)>,
+}
+
+/// How a push or pull updates the local cache of a remote
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CacheUpdate {
+    /// Update the cache, unless the remote unrecorded nodes of the current
+    /// channel
+    #[default]
+    Safe,
+    /// Overwrite the cache even if it discards the entries of nodes the
+    /// remote unrecorded. The discarded entries are appended to the cache
+    /// journal of the repository (see
+    /// [`atomic_repository::cache_journal`]).
+    Force(DiscardCapability),
+}
+
+/// Permission to discard entries of the cache of a remote. It is only
+/// obtained from [`DiscardCapability::confirmed`], which callers invoke
+/// when the user explicitly asked for it (e.g. `--force-cache`), rather
+/// than from a flag passed around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiscardCapability(());
+
+impl DiscardCapability {
+    /// The user confirmed that entries of the cache may be discarded
+    pub fn confirmed() -> Self {
+        DiscardCapability(())
+    }
 }
 
 impl RemoteDelta<MutTxn<()>> {
@@ -588,6 +717,7 @@ pub fn update_changelist_local_channel(
             theirs_ge_dichotomy: Vec::new(),
             theirs_ge_dichotomy_set: HashSet::new(),
             remote_unrecs: Vec::new(),
+            discarded: Vec::new(),
         })
     } else {
         let mut inodes = HashSet::new();
@@ -633,6 +763,7 @@ pub fn update_changelist_local_channel(
             theirs_ge_dichotomy: Vec::new(),
             theirs_ge_dichotomy_set: HashSet::new(),
             remote_unrecs: Vec::new(),
+            discarded: Vec::new(),
         })
     }
 }
@@ -643,6 +774,9 @@ impl RemoteRepo {
             RemoteRepo::Ssh(ref s) => Some(s.name.as_str()),
             RemoteRepo::Local(ref l) => Some(l.name.as_str()),
             RemoteRepo::Http(ref h) => Some(h.name.as_str()),
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(ref g) => Some(g.local.name.as_str()),
+            RemoteRepo::ObjectStore(ref o) => Some(o.name.as_str()),
             RemoteRepo::LocalChannel(_) => None,
             RemoteRepo::None => unreachable!(),
         }
@@ -729,14 +863,26 @@ impl RemoteRepo {
                 }
                 Ok(h.url.host().map(|h| h.to_string()))
             }
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(ref g) => Ok(libatomic::path::file_name(&g.url)
+                .map(|name| name.trim_end_matches(".git").to_string())
+                .filter(|name| !name.is_empty())),
+            RemoteRepo::ObjectStore(ref o) => Ok(Some(
+                libatomic::path::file_name(&o.prefix)
+                    .unwrap_or(&o.bucket.name)
+                    .to_string(),
+            )),
             RemoteRepo::LocalChannel(_) => Ok(None),
             RemoteRepo::None => unreachable!(),
         }
     }
 
     pub async fn finish(&mut self) -> Result<(), anyhow::Error> {
-        if let RemoteRepo::Ssh(s) = self {
-            s.finish().await?
+        match self {
+            RemoteRepo::Ssh(s) => s.finish().await?,
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(g) => g.finish()?,
+            _ => {}
         }
         Ok(())
     }
@@ -859,6 +1005,7 @@ impl RemoteRepo {
             theirs_ge_dichotomy: theirs_ge_dichotomy_nodes,
             theirs_ge_dichotomy_set,
             remote_unrecs: Vec::new(),
+            discarded: Vec::new(),
         })
     }
 
@@ -874,12 +1021,17 @@ impl RemoteRepo {
     ///    no remote unrecords, update the local remote cache. If there are remote unrecords,
     ///    calculate and return information about the difference between our cached version
     ///    of the remote, and their version of the remote.
+    ///
+    /// With [`CacheUpdate::Force`], the cache is updated even if there are
+    /// remote unrecords, and the entries it discards are journaled (see
+    /// [`atomic_repository::cache_journal`]) before being returned in
+    /// `discarded`.
     pub async fn update_changelist_pushpull(
         &mut self,
         txn: &mut MutTxn<()>,
         path: &[String],
         current_channel: &ChannelRef<MutTxn<()>>,
-        force_cache: Option<bool>,
+        cache: CacheUpdate,
         repo: &Repository,
         specific_changes: &[String],
         is_pull: bool,
@@ -905,7 +1057,18 @@ impl RemoteRepo {
                 .await;
         };
         let mut remote_ref = txn.open_or_create_remote(id, self.name().unwrap()).unwrap();
-        let dichotomy_n = self.dichotomy_changelist(txn, &remote_ref.lock()).await?;
+        // Path-restricted pulls need the changelist for its inodes.
+        let unchanged = if path.is_empty() {
+            self.unchanged_since_cache(txn, &remote_ref.lock()).await?
+        } else {
+            None
+        };
+        let dichotomy_n = if let Some(n) = unchanged {
+            debug!("remote unchanged since the last update, skipping the changelist");
+            n
+        } else {
+            self.dichotomy_changelist(txn, &remote_ref.lock()).await?
+        };
         let ours_ge_dichotomy: Vec<(u64, Node)> = txn
             .iter_remote(&remote_ref.lock().remote, dichotomy_n)?
             .filter_map(|k| {
@@ -925,8 +1088,11 @@ impl RemoteRepo {
                 }
             })
             .collect();
-        let (inodes, theirs_ge_dichotomy) =
-            self.download_changelist_nocache(dichotomy_n, path).await?;
+        let (inodes, theirs_ge_dichotomy) = if unchanged.is_some() {
+            (HashSet::new(), Vec::new())
+        } else {
+            self.download_changelist_nocache(dichotomy_n, path).await?
+        };
         debug!("theirs_ge_dichotomy = {:?}", theirs_ge_dichotomy);
         let ours_ge_dichotomy_set = ours_ge_dichotomy
             .iter()
@@ -952,15 +1118,33 @@ impl RemoteRepo {
             &ours_ge_dichotomy,
             &theirs_ge_dichotomy_set,
         )?;
-        let should_cache = if let Some(true) = force_cache {
-            true
-        } else {
-            remote_unrecs.is_empty()
+        let should_cache = match cache {
+            CacheUpdate::Force(_) => true,
+            CacheUpdate::Safe => remote_unrecs.is_empty(),
         };
         debug!(
             "should_cache = {:?} {:?} {:?}",
-            force_cache, remote_unrecs, should_cache
+            cache, remote_unrecs, should_cache
         );
+        // A forced update discards the entries the remote doesn't have
+        // anymore, journal them first.
+        let discarded: Vec<(u64, Node)> = if should_cache && !remote_unrecs.is_empty() {
+            ours_ge_dichotomy
+                .iter()
+                .filter(|(_, node)| !theirs_ge_dichotomy_set.contains(node))
+                .copied()
+                .collect()
+        } else {
+            Vec::new()
+        };
+        if !discarded.is_empty() {
+            journal_discarded(
+                repo,
+                self.name().unwrap_or_default(),
+                txn.name(&*current_channel.read()),
+                &discarded,
+            )?;
+        }
         if should_cache {
             use libatomic::ChannelMutTxnT;
             for (k, node) in ours_ge_dichotomy.iter().copied() {
@@ -1014,6 +1198,7 @@ impl RemoteRepo {
                 theirs_ge_dichotomy: theirs_ge_dichotomy_nodes,
                 theirs_ge_dichotomy_set,
                 remote_unrecs,
+                discarded,
             })
         } else {
             let mut to_download: Vec<Node> = Vec::new();
@@ -1090,6 +1275,7 @@ impl RemoteRepo {
                 theirs_ge_dichotomy: theirs_ge_dichotomy_nodes,
                 theirs_ge_dichotomy_set,
                 remote_unrecs,
+                discarded,
             })
         }
     }
@@ -1111,12 +1297,48 @@ impl RemoteRepo {
             RemoteRepo::Local(ref mut l) => l.download_changelist(f, &mut v, from, paths)?,
             RemoteRepo::Ssh(ref mut s) => s.download_changelist(f, &mut v, from, paths).await?,
             RemoteRepo::Http(ref h) => h.download_changelist(f, &mut v, from, paths).await?,
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(ref mut g) => g.local.download_changelist(f, &mut v, from, paths)?,
+            RemoteRepo::ObjectStore(ref mut o) => {
+                o.download_changelist(f, &mut v, from, paths).await?
+            }
             RemoteRepo::LocalChannel(_) => HashSet::new(),
             RemoteRepo::None => unreachable!(),
         };
         Ok((r, v))
     }
 
+    /// If the remote is still at the last state of our cache of it, the
+    /// position following the last cached one. This takes a single
+    /// conditional request, and only HTTP remotes serve state digests.
+    async fn unchanged_since_cache<T: MutTxnT + TxnTExt>(
+        &mut self,
+        txn: &T,
+        remote: &libatomic::pristine::Remote<T>,
+    ) -> Result<Option<u64>, anyhow::Error> {
+        let h = if let RemoteRepo::Http(ref h) = *self {
+            h
+        } else {
+            return Ok(None);
+        };
+        let (n, state): (_, Merkle) = if let Some((u, v)) = txn.last_remote(&remote.remote)? {
+            (u, (&v.b).into())
+        } else {
+            return Ok(None);
+        };
+        let tag_state = if let Some((_, _, v)) = txn.last_remote_tag(&remote.tags)? {
+            v.into()
+        } else {
+            Merkle::zero()
+        };
+        let etag = http::digest_etag(n, &state, &tag_state);
+        if let Some(true) = h.digest_matches(&etag).await? {
+            Ok(Some(n + 1))
+        } else {
+            Ok(None)
+        }
+    }
+
     /// Uses a binary search to find the integer identifier of the last point
     /// at which our locally cached version of the remote was the same as the 'actual'
     /// state of the remote.
@@ -1195,6 +1417,9 @@ impl RemoteRepo {
             RemoteRepo::Local(ref mut l) => l.get_state(mid),
             RemoteRepo::Ssh(ref mut s) => s.get_state(mid).await,
             RemoteRepo::Http(ref mut h) => h.get_state(mid).await,
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(ref mut g) => g.local.get_state(mid),
+            RemoteRepo::ObjectStore(ref mut o) => o.get_state(mid).await,
             RemoteRepo::LocalChannel(ref channel) => {
                 if let Some(channel) = txn.load_channel(&channel)? {
                     local::get_state(txn, &channel, mid)
@@ -1217,6 +1442,9 @@ impl RemoteRepo {
             RemoteRepo::Local(ref l) => Ok(Some(l.get_id()?)),
             RemoteRepo::Ssh(ref mut s) => s.get_id().await,
             RemoteRepo::Http(ref h) => h.get_id().await,
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(ref g) => Ok(Some(g.local.get_id()?)),
+            RemoteRepo::ObjectStore(ref mut o) => o.get_id().await,
             RemoteRepo::LocalChannel(ref channel) => {
                 if let Some(channel) = txn.load_channel(&channel)? {
                     Ok(txn.id(&*channel.read()).cloned())
@@ -1238,32 +1466,12 @@ impl RemoteRepo {
         w: W,
     ) -> Result<u64, anyhow::Error> {
         match *self {
-            RemoteRepo::Local(ref mut l) => {
-                debug!("archiving local repo");
-                let changes = libatomic::changestore::filesystem::FileSystem::from_root(
-                    &l.root,
-                    atomic_repository::max_files()?,
-                );
-                let mut tarball = libatomic::output::Tarball::new(w, prefix, umask);
-                let conflicts = if let Some((state, extra)) = state {
-                    let txn = l.pristine.arc_txn_begin()?;
-                    let channel = {
-                        let txn = txn.read();
-                        txn.load_channel(&l.channel)?.unwrap()
-                    };
-                    txn.archive_with_state(&changes, &channel, &state, extra, &mut tarball, 0)?
-                } else {
-                    let txn = l.pristine.arc_txn_begin()?;
-                    let channel = {
-                        let txn = txn.read();
-                        txn.load_channel(&l.channel)?.unwrap()
-                    };
-                    txn.archive(&changes, &channel, &mut tarball)?
-                };
-                Ok(conflicts.len() as u64)
-            }
+            RemoteRepo::Local(ref mut l) => l.archive(prefix, state, umask, w),
             RemoteRepo::Ssh(ref mut s) => s.archive(prefix, state, w).await,
             RemoteRepo::Http(ref mut h) => h.archive(prefix, state, w).await,
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(ref mut g) => g.local.archive(prefix, state, umask, w),
+            RemoteRepo::ObjectStore(_) => bail!("Object store remotes don't serve archives"),
             RemoteRepo::LocalChannel(_) => unreachable!(),
             RemoteRepo::None => unreachable!(),
         }
@@ -1296,6 +1504,15 @@ impl RemoteRepo {
                 h.download_changelist(f, &mut (txn, remote), from, paths)
                     .await
             }
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(ref mut g) => {
+                g.local
+                    .download_changelist(f, &mut (txn, remote), from, paths)
+            }
+            RemoteRepo::ObjectStore(ref mut o) => {
+                o.download_changelist(f, &mut (txn, remote), from, paths)
+                    .await
+            }
             RemoteRepo::LocalChannel(_) => Ok(HashSet::new()),
             RemoteRepo::None => unreachable!(),
         }
@@ -1308,6 +1525,12 @@ impl RemoteRepo {
         to_channel: Option<&str>,
         nodes: &[Node],
     ) -> Result<(), anyhow::Error> {
+        use libatomic::changestore::ChangeStore;
+        let store = libatomic::changestore::filesystem::FileSystem::from_changes(
+            local.clone(),
+            atomic_repository::max_files()?,
+        );
+        let nodes = &order::upload_order(nodes, |h| store.get_dependencies(h))?;
         let upload_bar = ProgressBar::new(nodes.len() as u64, UPLOAD_MESSAGE)?;
 
         match self {
@@ -1316,14 +1539,17 @@ impl RemoteRepo {
                 s.upload_nodes(upload_bar, local, to_channel, nodes).await?
             }
             RemoteRepo::Http(ref mut h) => {
-                h.upload_nodes(upload_bar, local, to_channel, nodes).await?
+                let batches = order::upload_batches(nodes, |hash| store.get_dependencies(hash))?;
+                h.upload_nodes(upload_bar, local, to_channel, &batches)
+                    .await?
+            }
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(ref mut g) => g.upload_nodes(upload_bar, local, to_channel, nodes)?,
+            RemoteRepo::ObjectStore(ref mut o) => {
+                o.upload_nodes(upload_bar, local, to_channel, nodes).await?
             }
             RemoteRepo::LocalChannel(ref channel) => {
                 let mut channel = txn.open_or_create_channel(channel)?;
-                let store = libatomic::changestore::filesystem::FileSystem::from_changes(
-                    local,
-                    atomic_repository::max_files()?,
-                );
                 local::upload_nodes(upload_bar, &store, txn, &mut channel, nodes)?
             }
             RemoteRepo::None => unreachable!(),
@@ -1332,6 +1558,10 @@ impl RemoteRepo {
     }
 
     /// Start (and possibly complete) the download of a node.
+    ///
+    /// Over SSH and HTTP, changes are taken from the shared change cache
+    /// when it is enabled and has them, and the downloaded changes are
+    /// added to it.
     pub async fn download_nodes(
         &mut self,
         progress_bar: ProgressBar,
@@ -1339,18 +1569,106 @@ impl RemoteRepo {
         send: &mut tokio::sync::mpsc::Sender<(Node, bool)>,
         path: &mut PathBuf,
         full: bool,
+    ) -> Result<bool, anyhow::Error> {
+        let cache = match *self {
+            RemoteRepo::Ssh(_) | RemoteRepo::Http(_) | RemoteRepo::ObjectStore(_) => {
+                cache::ChangeCache::from_global()
+            }
+            _ => None,
+        };
+        let Some(cache) = cache else {
+            return self
+                .download_nodes_uncached(progress_bar, nodes, send, path, full)
+                .await;
+        };
+
+        let (to_remote, mut remote_nodes) = tokio::sync::mpsc::unbounded_channel();
+        let (mut remote_send, mut remote_recv) = tokio::sync::mpsc::channel(100);
+        let mut remote_path = path.clone();
+        let mut cache_path = path.clone();
+        let mut store_path = path.clone();
+        let store_cache = cache.clone();
+        let from_cache_send = send.clone();
+        let from_cache_bar = progress_bar.clone();
+        let from_cache = async move {
+            while let Some(node) = nodes.recv().await {
+                if node.node_type == NodeType::Change && cache.fetch(&node.hash, &mut cache_path)
+                {
+                    from_cache_bar.inc(1);
+                    from_cache_send.send((node, true)).await?;
+                } else {
+                    to_remote.send(node)?;
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        let remote = async {
+            let r = self
+                .download_nodes_uncached(
+                    progress_bar,
+                    &mut remote_nodes,
+                    &mut remote_send,
+                    &mut remote_path,
+                    full,
+                )
+                .await;
+            std::mem::drop(remote_send);
+            r
+        };
+        let to_cache = async {
+            while let Some((node, follow)) = remote_recv.recv().await {
+                if node.node_type == NodeType::Change {
+                    if let Err(e) = store_cache.store(&node.hash, &mut store_path) {
+                        debug!("failed to cache {}: {}", node.hash.to_base32(), e)
+                    }
+                }
+                send.send((node, follow)).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        };
+        let (from_cache, remote, to_cache) = tokio::join!(from_cache, remote, to_cache);
+        from_cache?;
+        to_cache?;
+        remote
+    }
+
+    async fn download_nodes_uncached(
+        &mut self,
+        progress_bar: ProgressBar,
+        nodes: &mut tokio::sync::mpsc::UnboundedReceiver<Node>,
+        send: &mut tokio::sync::mpsc::Sender<(Node, bool)>,
+        path: &mut PathBuf,
+        full: bool,
     ) -> Result<bool, anyhow::Error> {
         debug!("download_nodes");
         match *self {
             RemoteRepo::Local(ref mut l) => {
                 l.download_nodes(progress_bar, nodes, send, path).await?
             }
-            RemoteRepo::Ssh(ref mut s) => {
-                s.download_nodes(progress_bar, nodes, send, path, full)
+            RemoteRepo::Ssh(ref mut s) => match download_streams() {
+                Some(streams) if streams > 1 => {
+                    s.download_nodes_parallel(streams, progress_bar, nodes, send, path, full)
+                        .await?
+                }
+                _ => {
+                    s.download_nodes(progress_bar, nodes, send, path, full)
+                        .await?
+                }
+            },
+            RemoteRepo::Http(ref mut h) => {
+                let streams = download_streams().unwrap_or(http::POOL_SIZE);
+                h.download_nodes(progress_bar, nodes, send, path, full, streams)
                     .await?
             }
-            RemoteRepo::Http(ref mut h) => {
-                h.download_nodes(progress_bar, nodes, send, path, full)
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(ref mut g) => {
+                g.local
+                    .download_nodes(progress_bar, nodes, send, path)
+                    .await?
+            }
+            RemoteRepo::ObjectStore(ref mut o) => {
+                let streams = download_streams().unwrap_or(object_store::DEFAULT_STREAMS);
+                o.download_nodes(progress_bar, nodes, send, path, streams)
                     .await?
             }
             RemoteRepo::LocalChannel(_) => {
@@ -1377,6 +1695,9 @@ impl RemoteRepo {
             RemoteRepo::Local(ref mut l) => l.update_identities(rev, id_path).await?,
             RemoteRepo::Ssh(ref mut s) => s.update_identities(rev, id_path).await?,
             RemoteRepo::Http(ref mut h) => h.update_identities(rev, id_path).await?,
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(_) => 0,
+            RemoteRepo::ObjectStore(_) => 0,
             RemoteRepo::LocalChannel(_) => 0,
             RemoteRepo::None => unreachable!(),
         };
@@ -1384,6 +1705,393 @@ impl RemoteRepo {
         Ok(())
     }
 
+    /// Whether notes are exchanged with this remote: older servers don't
+    /// know about them. Local remotes always exchange them.
+    pub async fn exchanges_notes(&mut self) -> bool {
+        match *self {
+            RemoteRepo::Local(_) => true,
+            RemoteRepo::Ssh(ref s) => s.exchanges_notes(),
+            RemoteRepo::Http(ref mut h) => h.exchanges_notes().await,
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(_) => false,
+            RemoteRepo::ObjectStore(_) => false,
+            RemoteRepo::LocalChannel(_) => false,
+            RemoteRepo::None => unreachable!(),
+        }
+    }
+
+    /// Download the notes of the remote and merge them into the local
+    /// notes, if the remote exchanges notes. Returns the number of local
+    /// notes that were updated.
+    pub async fn update_notes(&mut self, repo: &Repository) -> Result<usize, anyhow::Error> {
+        if !self.exchanges_notes().await {
+            debug!("The remote doesn't exchange notes");
+            return Ok(0);
+        }
+        debug!("Downloading notes");
+        let notes = match *self {
+            RemoteRepo::Local(ref l) => l.notes().all(None)?,
+            RemoteRepo::Ssh(ref mut s) => s.download_notes().await?,
+            RemoteRepo::Http(ref mut h) => h.download_notes().await?,
+            _ => return Ok(0),
+        };
+        repo.notes()
+            .pull_all(self.name().unwrap_or_default(), notes)
+    }
+
+    /// Send the local notes edited since they were last exchanged with the
+    /// remote, if it exchanges notes. The remote keeps the newest version
+    /// of each note.
+    pub async fn upload_notes(&mut self, repo: &Repository) -> Result<(), anyhow::Error> {
+        let name = self.name().unwrap_or_default().to_string();
+        let local = repo.notes();
+        let notes = local.unsynced(&name)?;
+        if notes.is_empty() || !self.exchanges_notes().await {
+            return Ok(());
+        }
+        debug!("Uploading {} notes", notes.len());
+        match *self {
+            RemoteRepo::Local(ref l) => {
+                l.notes().merge_all(notes.iter().cloned())?;
+            }
+            RemoteRepo::Ssh(ref mut s) => s.upload_notes(&notes).await?,
+            RemoteRepo::Http(ref mut h) => h.upload_notes(&notes).await?,
+            _ => return Ok(()),
+        }
+        local.mark_synced(&name, &notes)
+    }
+
+    /// Download the external links of the remote and merge them into the
+    /// local links. Returns the number of local links that were updated.
+    pub async fn update_links(&mut self, repo: &Repository) -> Result<usize, anyhow::Error> {
+        debug!("Downloading links");
+        let links = match *self {
+            RemoteRepo::Local(ref l) => l.links().all(None)?,
+            RemoteRepo::Ssh(ref mut s) => s.download_links().await?,
+            RemoteRepo::Http(ref mut h) => h.download_links().await?,
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(_) => return Ok(0),
+            RemoteRepo::ObjectStore(_) => return Ok(0),
+            RemoteRepo::LocalChannel(_) => return Ok(0),
+            RemoteRepo::None => unreachable!(),
+        };
+        repo.links().merge_all(links)
+    }
+
+    /// Send the local external links to the remote, which keeps the most
+    /// recently updated version of each link.
+    pub async fn upload_links(&mut self, repo: &Repository) -> Result<(), anyhow::Error> {
+        let links = repo.links().all(None)?;
+        if links.is_empty() {
+            return Ok(());
+        }
+        debug!("Uploading {} links", links.len());
+        match *self {
+            RemoteRepo::Local(ref l) => {
+                l.links().merge_all(links)?;
+            }
+            RemoteRepo::Ssh(ref mut s) => s.upload_links(&links).await?,
+            RemoteRepo::Http(ref mut h) => h.upload_links(&links).await?,
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(_) => {}
+            RemoteRepo::ObjectStore(_) => {}
+            RemoteRepo::LocalChannel(_) => {}
+            RemoteRepo::None => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Whether the attribution of changes is exchanged with this remote
+    /// (see [`attribution`]). Local remotes always support it.
+    pub async fn exchanges_attribution(&mut self) -> bool {
+        let remote = match *self {
+            RemoteRepo::Local(_) => Some(attribution::local_protocol()),
+            RemoteRepo::Ssh(ref s) => s.attribution_protocol(),
+            RemoteRepo::Http(ref mut h) => h.attribution_protocol().await,
+            #[cfg(feature = "git")]
+            RemoteRepo::Git(_) => None,
+            RemoteRepo::ObjectStore(_) => None,
+            RemoteRepo::LocalChannel(_) => None,
+            RemoteRepo::None => unreachable!(),
+        };
+        let config = attribution::RemoteAttributionConfig::from_environment();
+        attribution::negotiated(remote.as_ref(), &config)
+    }
+
+    /// Send the attribution of the pushed changes `hashes`, if both ends
+    /// support it. Returns the number of changes whose attribution was
+    /// sent.
+    pub async fn upload_attribution(
+        &mut self,
+        repo: &Repository,
+        hashes: &[libatomic::Hash],
+    ) -> Result<usize, anyhow::Error> {
+        let config = attribution::RemoteAttributionConfig::from_environment();
+        if hashes.is_empty() || !config.sync_on_push || !self.exchanges_attribution().await {
+            return Ok(0);
+        }
+        let outgoing = attribution::outgoing(&repo.pristine, hashes)?;
+        if outgoing.is_empty() {
+            return Ok(0);
+        }
+        debug!("Uploading the attribution of {} changes", outgoing.len());
+        let n = outgoing.len();
+        match *self {
+            RemoteRepo::Local(ref l) => {
+                attribution::incoming(&l.pristine, &l.changes_dir, outgoing, &config)?;
+            }
+            RemoteRepo::Ssh(ref mut s) => s.upload_attribution(&outgoing).await?,
+            RemoteRepo::Http(ref mut h) => h.upload_attribution(&outgoing).await?,
+            _ => return Ok(0),
+        }
+        Ok(n)
+    }
+
+    /// Download the attribution of the pulled changes `hashes`, if both
+    /// ends support it. The attribution is stored with
+    /// [`attribution::incoming`], once the pull is committed.
+    pub async fn download_attribution(
+        &mut self,
+        hashes: &[libatomic::Hash],
+    ) -> Result<Vec<attribution::ChangeAttribution>, anyhow::Error> {
+        let config = attribution::RemoteAttributionConfig::from_environment();
+        if hashes.is_empty() || !config.sync_on_pull || !self.exchanges_attribution().await {
+            return Ok(Vec::new());
+        }
+        debug!("Downloading the attribution of {} changes", hashes.len());
+        Ok(match *self {
+            RemoteRepo::Local(ref l) => attribution::outgoing(&l.pristine, hashes)?,
+            RemoteRepo::Ssh(ref mut s) => s.download_attribution(hashes).await?,
+            RemoteRepo::Http(ref mut h) => h.download_attribution(hashes).await?,
+            _ => Vec::new(),
+        })
+    }
+
+    /// Upload the changes of `nodes` to the confidential channel
+    /// `channel`, encrypted for its recipients (see [`confidential`]).
+    pub async fn upload_envelopes(
+        &mut self,
+        repo: &Repository,
+        channel: &str,
+        nodes: &[Node],
+    ) -> Result<(), anyhow::Error> {
+        use libatomic::changestore::ChangeStore;
+        let Some(recipients) = confidential::recipients(repo, channel)? else {
+            bail!("Channel {} isn't confidential", channel)
+        };
+        let RemoteRepo::Http(ref h) = *self else {
+            bail!("Confidential channels need an HTTP remote")
+        };
+        let nodes = order::upload_order(nodes, |h| repo.changes.get_dependencies(h))?;
+        let upload_bar = ProgressBar::new(nodes.len() as u64, UPLOAD_MESSAGE)?;
+        for node in nodes.iter() {
+            if node.is_change() {
+                let envelope = confidential::seal(repo, &node.hash, &recipients)?;
+                h.upload_envelope(channel, &node.hash, envelope).await?;
+            } else {
+                log::warn!(
+                    "Tag {} not pushed to confidential channel {}",
+                    node.state.to_base32(),
+                    channel
+                );
+            }
+            upload_bar.inc(1);
+        }
+        Ok(())
+    }
+
+    /// Download and decrypt with `key` the changes of the confidential
+    /// channel `from_channel` that `channel` doesn't have, returning them
+    /// in the reverse of their upload order, like [`RemoteRepo::pull`].
+    pub async fn download_envelopes<T: TxnTExt>(
+        &mut self,
+        repo: &Repository,
+        txn: &T,
+        channel: &ChannelRef<T>,
+        from_channel: &str,
+        key: &libatomic::key::SKey,
+    ) -> Result<Vec<Node>, anyhow::Error> {
+        let RemoteRepo::Http(ref h) = *self else {
+            bail!("Confidential channels need an HTTP remote")
+        };
+        let mut to_download = Vec::new();
+        for hash in h.list_envelopes(from_channel).await? {
+            if txn.get_revchanges(channel, &hash)?.is_none() {
+                to_download.push(hash)
+            }
+        }
+        let download_bar = ProgressBar::new(to_download.len() as u64, DOWNLOAD_MESSAGE)?;
+        let mut nodes = Vec::with_capacity(to_download.len());
+        for hash in to_download.iter().rev() {
+            if !repo.changes.has_change(hash) {
+                let envelope = h.download_envelope(hash).await?;
+                confidential::open(repo, hash, &envelope, key)?;
+            }
+            download_bar.inc(1);
+            nodes.push(Node::change(*hash, libatomic::Merkle::zero()));
+        }
+        Ok(nodes)
+    }
+
+    /// Write `nodes` of `channel`, along with every change they depend on
+    /// (the changes before them in the channel, for tags), to the bundle
+    /// file `w` (see [`offline`]), returning its manifest.
+    pub fn export_bundle<T: TxnTExt, W: Write>(
+        repo: &Repository,
+        txn: &T,
+        channel: &ChannelRef<T>,
+        nodes: &[Node],
+        w: W,
+    ) -> Result<offline::Manifest, anyhow::Error> {
+        use libatomic::changestore::ChangeStore;
+        let channel = channel.read();
+        let mut log = Vec::new();
+        for x in txn.log(&*channel, 0)? {
+            let (_, (h, m)) = x?;
+            log.push((Hash::from(h), Merkle::from(m)));
+        }
+        let states: HashMap<Hash, Merkle> = log.iter().cloned().collect();
+        let mut closure = nodes.to_vec();
+        let mut seen: HashSet<Hash> = nodes
+            .iter()
+            .filter(|n| n.is_change())
+            .map(|n| n.hash)
+            .collect();
+        // A tag seals the changes of the channel up to its state.
+        for tag in nodes.iter().filter(|n| n.is_tag()) {
+            let Some(end) = log.iter().position(|(_, m)| *m == tag.state) else {
+                bail!(
+                    "Tag {} is not in channel {}",
+                    tag.state.to_base32(),
+                    txn.name(&*channel)
+                )
+            };
+            for &(h, m) in &log[..=end] {
+                if seen.insert(h) {
+                    closure.push(Node::change(h, m))
+                }
+            }
+        }
+        let mut stack: Vec<Hash> = seen.iter().cloned().collect();
+        while let Some(h) = stack.pop() {
+            for dep in repo.changes.get_dependencies(&h)? {
+                if !seen.insert(dep) {
+                    continue;
+                }
+                let Some(state) = states.get(&dep) else {
+                    bail!(
+                        "Dependency {} of {} is not in channel {}",
+                        dep.to_base32(),
+                        h.to_base32(),
+                        txn.name(&*channel)
+                    )
+                };
+                closure.push(Node::change(dep, *state));
+                stack.push(dep);
+            }
+        }
+        let closure = order::upload_order(&closure, |h| repo.changes.get_dependencies(h))?;
+        let mut contents = Vec::with_capacity(closure.len());
+        for node in closure {
+            let mut path = repo.changes_dir.clone();
+            match node.node_type {
+                NodeType::Change => {
+                    libatomic::changestore::filesystem::push_filename(&mut path, &node.hash)
+                }
+                NodeType::Tag => {
+                    libatomic::changestore::filesystem::push_tag_filename(&mut path, &node.state)
+                }
+            }
+            let node_contents =
+                std::fs::read(&path).with_context(|| format!("Failed to read {:?}", path))?;
+            contents.push((node, node_contents));
+        }
+        let state = txn.current_state(&*channel)?;
+        debug!("exporting {} nodes", contents.len());
+        offline::write(w, txn.name(&*channel), &state, &contents)
+    }
+
+    /// Add the nodes of the bundle file `r` (see [`offline`]) to the
+    /// change store of `repo`, and apply them to `channel` in the order of
+    /// the bundle, returning the nodes `channel` didn't have, to publish
+    /// with [`publish_applied`] once `txn` is committed.
+    pub fn import_bundle<T: MutTxnTExt + TxnTExt + 'static, R: std::io::Read>(
+        repo: &Repository,
+        txn: &mut T,
+        channel: &mut ChannelRef<T>,
+        r: R,
+    ) -> Result<Vec<Node>, anyhow::Error> {
+        let (manifest, nodes) = offline::read(r)?;
+        debug!(
+            "importing {} nodes of channel {} at {}",
+            nodes.len(),
+            manifest.channel,
+            manifest.state
+        );
+        let mut ws = libatomic::ApplyWorkspace::new();
+        let mut applied = Vec::new();
+        for (node, contents) in nodes {
+            let mut path = repo.changes_dir.clone();
+            match node.node_type {
+                NodeType::Change => {
+                    libatomic::change::Change::check_from_buffer(&contents, &node.hash)?;
+                    libatomic::changestore::filesystem::push_filename(&mut path, &node.hash)
+                }
+                NodeType::Tag => {
+                    libatomic::changestore::filesystem::push_tag_filename(&mut path, &node.state)
+                }
+            }
+            if std::fs::metadata(&path).is_err() {
+                std::fs::create_dir_all(path.parent().unwrap())?;
+                let tmp = path.with_extension("tmp");
+                std::fs::write(&tmp, &contents)?;
+                std::fs::rename(&tmp, &path)?;
+            }
+            if node.is_tag() {
+                libatomic::tag::OpenTagFile::open(&path, &node.state)?;
+            }
+            let present = if node.is_tag() {
+                let channel = channel.read();
+                if let Some(n) = txn.channel_has_state(txn.states(&*channel), &node.state.into())? {
+                    txn.is_tagged(txn.tags(&*channel), n.into())?
+                } else {
+                    false
+                }
+            } else {
+                txn.has_change(channel, &node.hash)?.is_some()
+            };
+            if present {
+                continue;
+            }
+            let mut channel = channel.write();
+            if node.is_tag() {
+                // The changes before the tag are in the bundle, tag the
+                // state they lead to.
+                let Some(n) = txn.channel_has_state(txn.states(&*channel), &node.state.into())?
+                else {
+                    bail!(
+                        "State {} of the tag is not in channel {}",
+                        node.state.to_base32(),
+                        txn.name(&*channel)
+                    )
+                };
+                store_tag_metadata(txn, &*channel, &node)?;
+                let tags = txn.tags_mut(&mut *channel);
+                txn.put_tags(tags, n.into(), &node.state)?;
+            } else {
+                txn.apply_node_rec_ws(
+                    &repo.changes,
+                    &mut channel,
+                    &node.hash,
+                    node.node_type,
+                    &mut ws,
+                )?;
+            }
+            applied.push(node);
+        }
+        Ok(applied)
+    }
+
     pub async fn prove(&mut self, key: libatomic::key::SKey) -> Result<(), anyhow::Error> {
         match *self {
             RemoteRepo::Ssh(ref mut s) => s.prove(key).await,
@@ -1393,6 +2101,11 @@ impl RemoteRepo {
         }
     }
 
+    /// Download `to_apply`, and apply it to `channel` if `do_apply` is
+    /// true. The report lists the nodes of `to_apply` touching `inodes`,
+    /// the nodes applied and quarantined if `do_apply` is true, to
+    /// publish with [`publish_applied`] once `txn` is committed, and the
+    /// time spent in each phase.
     pub async fn pull<T: MutTxnTExt + TxnTExt + GraphIter + 'static>(
         &mut self,
         repo: &mut Repository,
@@ -1401,7 +2114,17 @@ impl RemoteRepo {
         to_apply: &[Node],
         inodes: &HashSet<Position<Hash>>,
         do_apply: bool,
-    ) -> Result<Vec<Node>, anyhow::Error> {
+    ) -> Result<quarantine::PullReport, anyhow::Error> {
+        let mut report = quarantine::PullReport::new();
+        // `pre-pull` hooks may refuse the nodes before they are downloaded.
+        if !to_apply.is_empty() {
+            let channel_name = txn.name(&*channel.read()).to_string();
+            let nodes = to_apply.iter().map(|n| n.hook_node()).collect();
+            let payload =
+                repo.hook_payload(hooks::Event::PrePull, self.name(), &channel_name, nodes);
+            repo.hooks().run(hooks::Event::PrePull, &payload)?;
+        }
+        let download_start = std::time::Instant::now();
         let apply_len = to_apply.len() as u64;
         let download_bar = ProgressBar::new(apply_len, DOWNLOAD_MESSAGE)?;
         let apply_bar = if do_apply {
@@ -1410,7 +2133,13 @@ impl RemoteRepo {
             None
         };
 
-        let (mut send, recv) = tokio::sync::mpsc::channel(100);
+        let (mut send, mut recv_downloaded) = tokio::sync::mpsc::channel(100);
+
+        // Changes downloaded by an interrupted pull are taken from the
+        // change store instead.
+        let checkpoint = repo.checkpoint();
+        let verified = checkpoint.verified()?;
+        let resumed_send = send.clone();
 
         let mut self_ = std::mem::replace(self, RemoteRepo::None);
         let (hash_send, mut hash_recv) = tokio::sync::mpsc::unbounded_channel();
@@ -1432,11 +2161,34 @@ impl RemoteRepo {
             Ok::<_, anyhow::Error>(self_)
         });
 
+        // Nodes downloaded concurrently complete in any order, apply them
+        // in the order of `to_apply`.
+        let (send_ordered, recv) = tokio::sync::mpsc::channel(100);
+        let mut in_order = order::InOrder::new(to_apply);
+        let mut changes_dir = repo.changes_dir.clone();
+        let verified_ = verified.clone();
+        let reorder = tokio::spawn(async move {
+            let mut follow = HashMap::new();
+            while let Some((node, f)) = recv_downloaded.recv().await {
+                if node.node_type == NodeType::Change && !verified_.contains(&node.hash) {
+                    checkpoint_downloaded(&checkpoint, &mut changes_dir, &node.hash)
+                }
+                follow.insert(node, f);
+                for node in in_order.downloaded(node) {
+                    let f = follow.remove(&node).unwrap_or(true);
+                    if send_ordered.send((node, f)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
         let mut change_path_ = repo.changes_dir.clone();
         let mut waiting = 0;
         let (send_ready, mut recv_ready) = tokio::sync::mpsc::channel(100);
 
         let mut asked = HashSet::new();
+        let mut resumed = Vec::new();
         for node in to_apply {
             debug!("to_apply {:?}", node);
             match node.node_type {
@@ -1454,10 +2206,29 @@ impl RemoteRepo {
                 }
             }
             asked.insert(*node);
-            hash_send.send(*node)?;
+            if node.node_type == NodeType::Change
+                && verified.contains(&node.hash)
+                && std::fs::metadata(&change_path_).is_ok()
+            {
+                debug!("already downloaded {:?}", node);
+                download_bar.inc(1);
+                resumed.push(*node);
+            } else {
+                hash_send.send(*node)?;
+            }
             waiting += 1;
             libatomic::changestore::filesystem::pop_filename(&mut change_path_);
         }
+        if !resumed.is_empty() {
+            info!("Resuming after {} downloaded changes", resumed.len());
+        }
+        let resume = tokio::spawn(async move {
+            for node in resumed {
+                if resumed_send.send((node, true)).await.is_err() {
+                    break;
+                }
+            }
+        });
 
         let u = self
             .download_changes_rec(
@@ -1470,10 +2241,17 @@ impl RemoteRepo {
                 asked,
             )
             .await?;
+        report.timings.since(PullPhase::Download, download_start);
 
         let mut ws = libatomic::ApplyWorkspace::new();
         let mut to_apply_inodes = HashSet::new();
-        while let Some(node) = recv_ready.recv().await {
+        loop {
+            // Time waiting for the next node to be downloaded.
+            let wait_start = std::time::Instant::now();
+            let Some(node) = recv_ready.recv().await else {
+                break;
+            };
+            report.timings.since(PullPhase::Download, wait_start);
             debug!("to_apply: {:?}", node);
             let touches_inodes = match node.node_type {
                 NodeType::Tag => {
@@ -1514,96 +2292,15 @@ impl RemoteRepo {
                 debug!("apply");
                 // Use unified apply for both changes and tags
                 let mut channel = channel.write();
-                txn.apply_node_rec_ws(
-                    &repo.changes,
-                    &mut channel,
-                    &node.hash,
-                    node.node_type,
-                    &mut ws,
-                )?;
+                let apply_start = std::time::Instant::now();
+                let applied = report.apply(&repo.changes, txn, &mut channel, &node, &mut ws)?;
+                report.timings.since(PullPhase::Apply, apply_start);
 
                 // If it's a tag, store consolidating metadata
-                if node.node_type == NodeType::Tag {
-                    let serialized_state: libatomic::pristine::SerializedMerkle =
-                        (&node.state).into();
-                    if let Some(_n) =
-                        txn.channel_has_state(txn.states(&*channel), &serialized_state)?
-                    {
-                        // Tag file reading removed - breaking change for MVP
-                        // Tags must be regenerated in new format
-                        // Use current timestamp since we can't read tag files
-                        let original_timestamp = std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs();
-
-                        // Calculate consolidating tag metadata
-                        let start_position = {
-                            let mut last_tag_pos = None;
-                            for entry in txn.rev_iter_tags(txn.tags(&*channel), None)? {
-                                let (pos, _merkle_pair) = entry?;
-                                debug!("Found previous tag at position: {:?}", pos);
-                                last_tag_pos = Some(pos);
-                                break;
-                            }
-                            last_tag_pos.map(|p| p.0 + 1).unwrap_or(0)
-                        };
-
-                        // Collect changes from last tag onwards
-                        let mut consolidated_changes = Vec::new();
-                        let mut change_count = 0u64;
-
-                        for entry in txn.log(&*channel, start_position)? {
-                            let (pos, (hash, _)) = entry?;
-                            let hash: libatomic::pristine::Hash = hash.into();
-                            debug!("  Position {}: including change {}", pos, hash.to_base32());
-                            consolidated_changes.push(hash);
-                            change_count += 1;
-                        }
-
-                        debug!(
-                            "Tag consolidation: {} changes since position {}",
-                            change_count, start_position
-                        );
-
-                        let dependency_count_before = change_count;
-                        let consolidated_change_count = change_count;
-
-                        // Get channel name
-                        let channel_name = txn.name(&*channel).to_string();
-
-                        // Create consolidating tag metadata with original timestamp
-                        // Hash IS Merkle now, so we can use it directly
-                        let tag_hash = node.state;
-                        let mut tag = libatomic::pristine::Tag::new(
-                            tag_hash,
-                            node.state,
-                            channel_name,
-                            None,
-                            dependency_count_before,
-                            consolidated_change_count,
-                            consolidated_changes,
-                        );
-                        tag.consolidation_timestamp = original_timestamp;
-                        // Set the change_file_hash to the merkle state
-                        // This is what should be used as a dependency when recording changes after the tag
-                        tag.change_file_hash = Some(node.state);
-
-                        // Serialize and store consolidating tag metadata
-                        let serialized = libatomic::pristine::SerializedTag::from_tag(&tag)?;
-
-                        debug!("Storing consolidating tag metadata");
-                        txn.put_tag(&tag_hash, &serialized)?;
-                        debug!(
-                            "Tagged state {} with consolidating metadata",
-                            node.state.to_base32()
-                        );
-                    } else {
-                        debug!(
-                            "Warning: Cannot add tag metadata {}: channel does not have that state yet",
-                            node.state.to_base32()
-                        );
-                    }
+                if applied && node.node_type == NodeType::Tag {
+                    let tag_start = std::time::Instant::now();
+                    store_tag_metadata(txn, &*channel, &node)?;
+                    report.timings.since(PullPhase::TagMetadata, tag_start);
                 }
                 debug!("applied");
             } else {
@@ -1611,18 +2308,22 @@ impl RemoteRepo {
             }
         }
 
-        let mut result = Vec::with_capacity(to_apply_inodes.len());
         for h in to_apply {
             if to_apply_inodes.contains(&h) {
-                result.push(*h)
+                report.pulled.push(*h)
             }
         }
 
         debug!("finished");
         debug!("waiting for spawned process");
+        let wait_start = std::time::Instant::now();
         *self = t.await??;
         u.await??;
-        Ok(result)
+        resume.await?;
+        reorder.await?;
+        report.timings.since(PullPhase::Download, wait_start);
+        repo.checkpoint().clear()?;
+        Ok(report)
     }
 
     async fn download_changes_rec(
@@ -1701,13 +2402,15 @@ impl RemoteRepo {
         Ok(t)
     }
 
+    /// Apply the changes or tags `tag` and their dependencies to `channel`, returning the nodes applied, to publish with
+    /// [`publish_applied`] once `txn` is committed.
     pub async fn clone_tag<T: MutTxnTExt + TxnTExt + GraphIter + 'static>(
         &mut self,
         repo: &mut Repository,
         txn: &mut T,
         channel: &mut ChannelRef<T>,
         tag: &[Hash],
-    ) -> Result<(), anyhow::Error> {
+    ) -> Result<Vec<Node>, anyhow::Error> {
         let (send_hash, mut recv_hash) = tokio::sync::mpsc::unbounded_channel();
         let (mut send_signal, recv_signal) = tokio::sync::mpsc::channel(100);
         let mut self_ = std::mem::replace(self, RemoteRepo::None);
@@ -1771,9 +2474,11 @@ impl RemoteRepo {
         u.await??;
         self.complete_changes(repo, txn, channel, &hashes, false)
             .await?;
-        Ok(())
+        Ok(hashes)
     }
 
+    /// Apply the changes of the remote channel up to `state` to `channel`, returning the nodes applied, to publish with
+    /// [`publish_applied`] once `txn` is committed.
     pub async fn clone_state<T: MutTxnTExt + TxnTExt + GraphIter + 'static>(
         &mut self,
         repo: &mut Repository,
@@ -1781,11 +2486,11 @@ impl RemoteRepo {
         channel: &mut ChannelRef<T>,
         state: Merkle,
         _changes: &[Node],
-    ) -> Result<(), anyhow::Error> {
+    ) -> Result<Vec<Node>, anyhow::Error> {
         let id = if let Some(id) = self.get_id(txn).await? {
             id
         } else {
-            return Ok(());
+            return Ok(Vec::new());
         };
         self.update_changelist(txn, &[]).await?;
         let remote = txn.open_or_create_remote(id, self.name().unwrap()).unwrap();
@@ -1801,15 +2506,24 @@ impl RemoteRepo {
             }
         }
         if !found {
-            bail!("State not found: {:?}", state)
+            bail!(SyncError::not_found(format!(
+                "State not found: {:?}",
+                state
+            )))
         }
-        self.pull(repo, txn, channel, &to_pull, &HashSet::new(), true)
+        let report = self
+            .pull(repo, txn, channel, &to_pull, &HashSet::new(), true)
             .await?;
+        report
+            .timings
+            .emit(self.name().unwrap_or(""), to_pull.len());
+        let applied = report.complete()?;
         self.update_identities(repo, &remote).await?;
+        self.update_notes(repo).await?;
 
         self.complete_changes(repo, txn, channel, &to_pull, false)
             .await?;
-        Ok(())
+        Ok(applied)
     }
 
     pub async fn complete_changes<T: MutTxnT + TxnTExt + GraphIter>(
@@ -1901,18 +2615,29 @@ impl RemoteRepo {
         Ok(())
     }
 
+    /// Apply the remote channel to `local_channel`, restricted to `path`, returning the nodes applied, to publish with
+    /// [`publish_applied`] once `txn` is committed.
     pub async fn clone_channel<T: MutTxnTExt + TxnTExt + GraphIter + 'static>(
         &mut self,
         repo: &mut Repository,
         txn: &mut T,
         local_channel: &mut ChannelRef<T>,
         path: &[String],
-    ) -> Result<(), anyhow::Error> {
+    ) -> Result<Vec<Node>, anyhow::Error> {
+        let path = &sparse::normalize(path);
         let (inodes, remote_changes) = if let Some(x) = self.update_changelist(txn, path).await? {
             x
         } else {
-            bail!("Channel not found")
+            bail!(SyncError::not_found("Channel not found"))
         };
+        // Remotes that don't know the paths send their whole changelist,
+        // and the clone wouldn't be sparse.
+        if !path.is_empty() && inodes.is_empty() {
+            bail!(
+                "None of the paths {:?} were found in the remote channel",
+                path
+            )
+        }
         let mut pullable = Vec::new();
         {
             let rem = remote_changes.lock();
@@ -1925,72 +2650,187 @@ impl RemoteRepo {
                 pullable.len()
             );
         }
-        self.pull(repo, txn, local_channel, &pullable, &inodes, true)
+        let report = self
+            .pull(repo, txn, local_channel, &pullable, &inodes, true)
             .await?;
+        report
+            .timings
+            .emit(self.name().unwrap_or(""), pullable.len());
+        let applied = report.complete()?;
         self.update_identities(repo, &remote_changes).await?;
+        self.update_notes(repo).await?;
 
         self.complete_changes(repo, txn, local_channel, &pullable, false)
             .await?;
-        Ok(())
+        if !path.is_empty() {
+            repo.config.sparse = path.clone();
+            repo.update_config()?;
+        }
+        Ok(applied)
     }
 }
 
-use libatomic::pristine::{ChangePosition, Position};
-use regex::Regex;
+use libatomic::pristine::Position;
 
-lazy_static! {
-    static ref CHANGELIST_LINE: Regex = Regex::new(
-        r#"(?P<num>[0-9]+)\.(?P<hash>[A-Za-z0-9]+)\.(?P<merkle>[A-Za-z0-9]+)(?P<tag>\.)?"#
-    )
-    .unwrap();
-    static ref PATHS_LINE: Regex =
-        Regex::new(r#"(?P<hash>[A-Za-z0-9]+)\.(?P<num>[0-9]+)"#).unwrap();
+/// Changes downloaded at the same time from a remote, set in the
+/// `[download]` table of the global configuration
+fn download_streams() -> Option<usize> {
+    let (global, _) = atomic_config::Global::load().ok()?;
+    global.download.streams.map(|n| n.max(1))
 }
 
-enum ListLine {
-    Change {
-        n: u64,
-        h: Hash,
-        m: Merkle,
-        tag: bool,
-    },
-    Position(Position<Hash>),
-    Error(String),
+/// Record `hash` in `checkpoint` if its change file, in `changes_dir`,
+/// matches it. Changes that don't are downloaded again by the next pull.
+fn checkpoint_downloaded(
+    checkpoint: &atomic_repository::checkpoint::Checkpoint,
+    changes_dir: &mut PathBuf,
+    hash: &Hash,
+) {
+    libatomic::changestore::filesystem::push_filename(changes_dir, hash);
+    let checked = std::fs::read(&changes_dir)
+        .map_err(anyhow::Error::from)
+        .and_then(|buf| Ok(libatomic::change::Change::check_from_buffer(&buf, hash)?));
+    libatomic::changestore::filesystem::pop_filename(changes_dir);
+    match checked.and_then(|()| checkpoint.add(hash)) {
+        Ok(()) => {}
+        Err(e) => debug!("not checkpointing {}: {}", hash.to_base32(), e),
+    }
 }
 
-fn parse_line(data: &str) -> Result<ListLine, anyhow::Error> {
-    debug!("data = {:?}", data);
-    if let Some(caps) = CHANGELIST_LINE.captures(data) {
-        if let (Some(h), Some(m)) = (
-            Hash::from_base32(caps.name("hash").unwrap().as_str().as_bytes()),
-            Merkle::from_base32(caps.name("merkle").unwrap().as_str().as_bytes()),
-        ) {
-            return Ok(ListLine::Change {
-                n: caps.name("num").unwrap().as_str().parse().unwrap(),
-                h,
-                m,
-                tag: caps.name("tag").is_some(),
-            });
+/// Store the consolidating metadata of the tag `node`, just applied to
+/// `channel`: the changes since the previous tag of the channel.
+fn store_tag_metadata<T: MutTxnTExt + TxnTExt>(
+    txn: &mut T,
+    channel: &T::Channel,
+    node: &Node,
+) -> Result<(), anyhow::Error> {
+    let serialized_state: libatomic::pristine::SerializedMerkle = (&node.state).into();
+    if let Some(_n) = txn.channel_has_state(txn.states(channel), &serialized_state)? {
+        // Tag file reading removed - breaking change for MVP
+        // Tags must be regenerated in new format
+        // Use current timestamp since we can't read tag files
+        let original_timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        // Calculate consolidating tag metadata
+        let start_position = {
+            let mut last_tag_pos = None;
+            for entry in txn.rev_iter_tags(txn.tags(channel), None)? {
+                let (pos, _merkle_pair) = entry?;
+                debug!("Found previous tag at position: {:?}", pos);
+                last_tag_pos = Some(pos);
+                break;
+            }
+            last_tag_pos.map(|p| p.0 + 1).unwrap_or(0)
+        };
+
+        // Collect changes from last tag onwards
+        let mut consolidated_changes = Vec::new();
+        let mut change_count = 0u64;
+
+        for entry in txn.log(channel, start_position)? {
+            let (pos, (hash, _)) = entry?;
+            let hash: libatomic::pristine::Hash = hash.into();
+            debug!("  Position {}: including change {}", pos, hash.to_base32());
+            consolidated_changes.push(hash);
+            change_count += 1;
         }
+
+        debug!(
+            "Tag consolidation: {} changes since position {}",
+            change_count, start_position
+        );
+
+        let dependency_count_before = change_count;
+        let consolidated_change_count = change_count;
+
+        // Get channel name
+        let channel_name = txn.name(channel).to_string();
+
+        // Create consolidating tag metadata with original timestamp
+        // Hash IS Merkle now, so we can use it directly
+        let tag_hash = node.state;
+        let mut tag = libatomic::pristine::Tag::new(
+            tag_hash,
+            node.state,
+            channel_name,
+            None,
+            dependency_count_before,
+            consolidated_change_count,
+            consolidated_changes,
+        );
+        tag.consolidation_timestamp = original_timestamp;
+        // Set the change_file_hash to the merkle state
+        // This is what should be used as a dependency when recording changes after the tag
+        tag.change_file_hash = Some(node.state);
+
+        // Serialize and store consolidating tag metadata
+        let serialized = libatomic::pristine::SerializedTag::from_tag(&tag)?;
+
+        debug!("Storing consolidating tag metadata");
+        txn.put_tag(&tag_hash, &serialized)?;
+        debug!(
+            "Tagged state {} with consolidating metadata",
+            node.state.to_base32()
+        );
+    } else {
+        debug!(
+            "Warning: Cannot add tag metadata {}: channel does not have that state yet",
+            node.state.to_base32()
+        );
     }
-    if data.starts_with("error:") {
-        return Ok(ListLine::Error(data.split_at(6).1.to_string()));
-    }
-    if let Some(caps) = PATHS_LINE.captures(data) {
-        return Ok(ListLine::Position(Position {
-            change: Hash::from_base32(caps.name("hash").unwrap().as_str().as_bytes()).unwrap(),
-            pos: ChangePosition(
-                caps.name("num")
-                    .unwrap()
-                    .as_str()
-                    .parse::<u64>()
-                    .unwrap()
-                    .into(),
-            ),
-        }));
+    Ok(())
+}
+
+/// Append the entries of the cache of `remote` discarded by a forced
+/// update to the cache journal of `repo`
+fn journal_discarded(
+    repo: &Repository,
+    remote: &str,
+    channel: &str,
+    discarded: &[(u64, Node)],
+) -> Result<(), anyhow::Error> {
+    use atomic_repository::cache_journal::{CacheOverwrite, DiscardedNode};
+    let journal = repo.cache_journal();
+    info!(
+        "discarding {} entries from the cache of {}, journaled in {:?}",
+        discarded.len(),
+        remote,
+        journal.path()
+    );
+    journal
+        .append(&CacheOverwrite {
+            remote: remote.to_string(),
+            channel: channel.to_string(),
+            time: chrono::Utc::now(),
+            discarded: discarded
+                .iter()
+                .map(|(n, node)| DiscardedNode::new(*n, &node.hash, &node.state, node.node_type))
+                .collect(),
+        })
+        .context("Failed to journal the discarded cache entries")
+}
+
+/// Publish the nodes `applied` to `channel` of `repo` to the event bus.
+/// Subscribers may read the repository as soon as they are called, so
+/// this is only called once the transaction applying the nodes is
+/// committed.
+pub fn publish_applied(repo: &Repository, channel: &str, applied: &[Node]) {
+    use atomic_config::events::{self, Event, NodeKind};
+    for node in applied {
+        let (kind, hash) = match node.node_type {
+            NodeType::Change => (NodeKind::Change, node.hash.to_base32()),
+            NodeType::Tag => (NodeKind::Tag, node.state.to_base32()),
+        };
+        events::publish(Event::NodeApplied {
+            repository: repo.path.clone(),
+            channel: channel.to_string(),
+            kind,
+            hash,
+        });
     }
-    debug!("offending line: {:?}", data);
-    bail!("Protocol error")
 }
 
 /// Compare the remote set (theirs_ge_dichotomy) with our current
@@ -7,6 +7,7 @@ use libatomic::pristine::{Hash, Merkle, MutTxnT, NodeType, Position, TxnT};
 use libatomic::*;
 use log::debug;
 
+use crate::failure::SyncError;
 use crate::Node;
 use atomic_interaction::ProgressBar;
 
@@ -57,6 +58,34 @@ impl Local {
         }
     }
 
+    /// Write a tarball of the channel, or of its state `state` if given,
+    /// to `w`, returning the number of conflicts.
+    pub fn archive<W: std::io::Write + Send + 'static>(
+        &mut self,
+        prefix: Option<String>,
+        state: Option<(Merkle, &[Hash])>,
+        umask: u16,
+        w: W,
+    ) -> Result<u64, anyhow::Error> {
+        debug!("archiving local repo");
+        let changes = libatomic::changestore::filesystem::FileSystem::from_root(
+            &self.root,
+            atomic_repository::max_files()?,
+        );
+        let mut tarball = libatomic::output::Tarball::new(w, prefix, umask);
+        let txn = self.pristine.arc_txn_begin()?;
+        let channel = {
+            let txn = txn.read();
+            txn.load_channel(&self.channel)?.unwrap()
+        };
+        let conflicts = if let Some((state, extra)) = state {
+            txn.archive_with_state(&changes, &channel, &state, extra, &mut tarball, 0)?
+        } else {
+            txn.archive(&changes, &channel, &mut tarball)?
+        };
+        Ok(conflicts.len() as u64)
+    }
+
     pub fn download_changelist<
         A,
         F: FnMut(&mut A, u64, Hash, Merkle, bool) -> Result<(), anyhow::Error>,
@@ -75,7 +104,10 @@ impl Local {
                 "Local::download_changelist found no channel named {:?}",
                 self.channel
             );
-            bail!("No channel {} found for remote {}", self.name, self.channel)
+            bail!(SyncError::not_found(format!(
+                "No channel {} found for remote {}",
+                self.name, self.channel
+            )))
         };
         self.download_changelist_(f, a, from, paths, &remote_txn, &remote_channel)
     }
@@ -339,6 +371,20 @@ impl Local {
         }
         Ok(0)
     }
+
+    /// The notes of the other repository.
+    pub fn notes(&self) -> atomic_repository::notes::Notes {
+        atomic_repository::notes::Notes::new(
+            self.root.join(DOT_DIR).join(atomic_repository::NOTES_DIR),
+        )
+    }
+
+    /// The external links of the other repository.
+    pub fn links(&self) -> atomic_repository::links::Links {
+        atomic_repository::links::Links::new(
+            self.root.join(DOT_DIR).join(atomic_repository::LINKS_DIR),
+        )
+    }
 }
 
 pub fn upload_nodes<T: MutTxnTExt + 'static, C: libatomic::changestore::ChangeStore>(
@@ -0,0 +1,170 @@
+//! Bundle files, to move changes between repositories that can't reach
+//! each other, such as air-gapped ones.
+//!
+//! [`crate::RemoteRepo::export_bundle`] writes nodes of a channel, along
+//! with every change they depend on, to a single file, and
+//! [`crate::RemoteRepo::import_bundle`] adds them to the change store of
+//! another repository and applies them to one of its channels.
+//!
+//! A bundle file is [`MAGIC`], the length of its [`Manifest`] (`u64`, big
+//! endian), the manifest in JSON, and then the contents of each node of
+//! the manifest, in the order of the manifest: its length (`u64`, big
+//! endian) followed by the change file, or the tag file. Nodes come after
+//! the nodes they depend on, so they can be applied in that order.
+
+use crate::Node;
+use anyhow::bail;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use libatomic::pristine::{Base32, Hash, Merkle};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// First bytes of bundle files.
+pub const MAGIC: &[u8; 8] = b"ATOMBNDF";
+
+/// Version of the format of bundle files written by this version.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// The description of the nodes of a bundle file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// The channel the nodes were exported from
+    pub channel: String,
+    /// The state of that channel at the time of the export
+    pub state: String,
+    /// The nodes of the bundle, dependencies first
+    pub nodes: Vec<Entry>,
+}
+
+/// A node of a bundle file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// The hash of the change, or the state of the tag
+    pub hash: String,
+    /// See [`Node::type_marker`]
+    #[serde(rename = "type")]
+    pub node_type: String,
+    /// The state of the channel after this node
+    pub state: String,
+    /// The size of the contents of this node, in bytes
+    pub size: u64,
+}
+
+impl Entry {
+    pub fn new(node: &Node, size: u64) -> Self {
+        Entry {
+            hash: node.hash.to_base32(),
+            node_type: node.type_marker().to_string(),
+            state: node.state.to_base32(),
+            size,
+        }
+    }
+
+    pub fn node(&self) -> Result<Node, anyhow::Error> {
+        let (Some(hash), Some(state)) = (
+            Hash::from_base32(self.hash.as_bytes()),
+            Merkle::from_base32(self.state.as_bytes()),
+        ) else {
+            bail!("Invalid node in bundle manifest: {}", self.hash)
+        };
+        Node::from_type_marker(hash, state, &self.node_type)
+    }
+}
+
+/// Write a bundle file of `nodes`, pairs of a node and its contents, to `w`.
+pub fn write<W: Write>(
+    mut w: W,
+    channel: &str,
+    state: &Merkle,
+    nodes: &[(Node, Vec<u8>)],
+) -> Result<Manifest, anyhow::Error> {
+    let manifest = Manifest {
+        version: FORMAT_VERSION,
+        channel: channel.to_string(),
+        state: state.to_base32(),
+        nodes: nodes
+            .iter()
+            .map(|(node, contents)| Entry::new(node, contents.len() as u64))
+            .collect(),
+    };
+    let json = serde_json::to_vec(&manifest)?;
+    w.write_all(MAGIC)?;
+    w.write_u64::<BigEndian>(json.len() as u64)?;
+    w.write_all(&json)?;
+    for (_, contents) in nodes {
+        w.write_u64::<BigEndian>(contents.len() as u64)?;
+        w.write_all(contents)?;
+    }
+    w.flush()?;
+    Ok(manifest)
+}
+
+/// Read the manifest of a bundle file from `r`.
+pub fn read_manifest<R: Read>(mut r: R) -> Result<Manifest, anyhow::Error> {
+    let mut magic = [0; MAGIC.len()];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("Not a bundle file")
+    }
+    let len = r.read_u64::<BigEndian>()?;
+    let mut json = Vec::new();
+    (&mut r).take(len).read_to_end(&mut json)?;
+    let manifest: Manifest = serde_json::from_slice(&json)?;
+    if manifest.version > FORMAT_VERSION {
+        bail!(
+            "Bundle file of version {}, this version reads up to {}",
+            manifest.version,
+            FORMAT_VERSION
+        )
+    }
+    Ok(manifest)
+}
+
+/// Read a bundle file from `r`: its manifest, and each of its nodes with
+/// its contents.
+pub fn read<R: Read>(mut r: R) -> Result<(Manifest, Vec<(Node, Vec<u8>)>), anyhow::Error> {
+    let manifest = read_manifest(&mut r)?;
+    let mut nodes = Vec::with_capacity(manifest.nodes.len());
+    for entry in manifest.nodes.iter() {
+        let node = entry.node()?;
+        let len = r.read_u64::<BigEndian>()?;
+        if len != entry.size {
+            bail!("Size of {} doesn't match the manifest", entry.hash)
+        }
+        let mut contents = Vec::with_capacity(len as usize);
+        (&mut r).take(len).read_to_end(&mut contents)?;
+        if contents.len() as u64 != len {
+            bail!("Truncated bundle file")
+        }
+        nodes.push((node, contents))
+    }
+    Ok((manifest, nodes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_repository::fixtures::hash;
+
+    #[test]
+    fn test_write_read() {
+        let state = Merkle::zero().next(&hash(0));
+        let nodes = vec![
+            (Node::change(hash(0), state), vec![1, 2, 3]),
+            (Node::tag(state, state), vec![4, 5]),
+        ];
+        let mut file = Vec::new();
+        let manifest = write(&mut file, "main", &state, &nodes).unwrap();
+        assert_eq!(manifest.nodes[0].node_type, "C");
+        assert_eq!(manifest.nodes[1].node_type, "T");
+
+        assert_eq!(read_manifest(&file[..]).unwrap(), manifest);
+        let (read_manifest, read_nodes) = read(&file[..]).unwrap();
+        assert_eq!(read_manifest, manifest);
+        assert_eq!(read_nodes, nodes);
+
+        assert!(read(&file[..file.len() - 1]).is_err());
+        assert!(read(&b"ATOMBNDL"[..]).is_err());
+    }
+}
@@ -0,0 +1,333 @@
+//! Upload order of nodes
+//!
+//! Remotes apply the nodes they receive in order, so a change must be sent
+//! after the changes it depends on, and a tag after the change producing
+//! the state it seals. [`upload_order`] is the single place where this
+//! order is computed, and every upload path goes through it.
+//!
+//! The order only depends on the input order and on the dependencies: among
+//! the nodes that can be sent, the one that comes first in the input is
+//! sent first. An input that is already correctly ordered is therefore left
+//! unchanged, and the result is the same on every platform.
+//!
+//! Remotes that accept concurrent uploads get the nodes in batches, from
+//! [`upload_batches`]: the nodes of a batch don't depend on each other, and
+//! can be sent in any order.
+//!
+//! Downloads go the other way: nodes downloaded concurrently complete in
+//! any order, and [`InOrder`] hands them back in the order of the pull.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+
+use libatomic::pristine::{Hash, Merkle};
+use log::warn;
+
+use crate::Node;
+
+/// Order `nodes` so that every node comes after the nodes it depends on.
+/// `dependencies` returns the direct dependencies of a change; the
+/// dependencies that aren't in `nodes` are ignored. Duplicate nodes are
+/// only sent once.
+pub fn upload_order<E, F>(nodes: &[Node], mut dependencies: F) -> Result<Vec<Node>, E>
+where
+    F: FnMut(&Hash) -> Result<Vec<Hash>, E>,
+{
+    let mut unique: Vec<Node> = Vec::with_capacity(nodes.len());
+    let mut changes = HashMap::new();
+    let mut tags = HashMap::new();
+    let mut states = HashMap::new();
+    for node in nodes {
+        let index = unique.len();
+        let by_hash = if node.is_change() {
+            &mut changes
+        } else {
+            &mut tags
+        };
+        if by_hash.contains_key(&node.hash) {
+            continue;
+        }
+        by_hash.insert(node.hash, index);
+        if node.is_change() {
+            states.entry(node.state).or_insert(index);
+        }
+        unique.push(*node)
+    }
+
+    // Edges from each node to the nodes that must be sent after it.
+    let mut after: Vec<Vec<usize>> = vec![Vec::new(); unique.len()];
+    let mut before = vec![0usize; unique.len()];
+    let mut edge = |from: usize, to: usize| {
+        if from != to {
+            after[from].push(to);
+            before[to] += 1;
+        }
+    };
+    for (i, node) in unique.iter().enumerate() {
+        if node.is_change() {
+            for dep in dependencies(&node.hash)? {
+                // Consolidating tags can be dependencies too.
+                if let Some(&j) = changes.get(&dep).or_else(|| tags.get(&dep)) {
+                    edge(j, i)
+                }
+            }
+        } else if let Some(&j) = states.get::<Merkle>(&node.state) {
+            edge(j, i)
+        }
+    }
+
+    let mut ready: BinaryHeap<Reverse<usize>> = (0..unique.len())
+        .filter(|&i| before[i] == 0)
+        .map(Reverse)
+        .collect();
+    let mut order = Vec::with_capacity(unique.len());
+    let mut sent = vec![false; unique.len()];
+    while let Some(Reverse(i)) = ready.pop() {
+        order.push(unique[i]);
+        sent[i] = true;
+        for &j in after[i].iter() {
+            before[j] -= 1;
+            if before[j] == 0 {
+                ready.push(Reverse(j))
+            }
+        }
+    }
+    if order.len() < unique.len() {
+        // Only possible with corrupted dependencies, let the remote report
+        // the actual problem.
+        warn!("Dependency cycle among the nodes to upload");
+        order.extend(unique.iter().zip(sent).filter(|(_, s)| !s).map(|(n, _)| *n));
+    }
+    Ok(order)
+}
+
+/// Split `order`, as returned by [`upload_order`], into batches that can
+/// each be sent concurrently, one batch after the other.
+///
+/// A tag seals the state of the remote, which depends on the order in
+/// which changes were applied. The nodes up to the last tag are therefore
+/// sent one at a time; after it, a batch ends before the first change
+/// depending on a change of the batch.
+pub fn upload_batches<E, F>(order: &[Node], mut dependencies: F) -> Result<Vec<Vec<Node>>, E>
+where
+    F: FnMut(&Hash) -> Result<Vec<Hash>, E>,
+{
+    let sequential = order.iter().rposition(|n| n.is_tag()).map_or(0, |i| i + 1);
+    let mut batches: Vec<Vec<Node>> = order[..sequential].iter().map(|n| vec![*n]).collect();
+    let mut batch = Vec::new();
+    let mut in_batch = HashSet::new();
+    for node in &order[sequential..] {
+        if dependencies(&node.hash)?
+            .iter()
+            .any(|d| in_batch.contains(d))
+        {
+            batches.push(std::mem::take(&mut batch));
+            in_batch.clear();
+        }
+        in_batch.insert(node.hash);
+        batch.push(*node)
+    }
+    if !batch.is_empty() {
+        batches.push(batch)
+    }
+    Ok(batches)
+}
+
+/// Downloaded nodes, released in the order they were requested
+///
+/// A node is released once it and every node requested before it are
+/// downloaded, so nodes are applied as if they had been downloaded one at
+/// a time, whatever the number of concurrent downloads. Nodes that weren't
+/// requested, such as missing dependencies fetched along the way, are
+/// released as soon as they are downloaded.
+#[derive(Debug, Default)]
+pub struct InOrder {
+    order: VecDeque<Node>,
+    requested: HashSet<Node>,
+    downloaded: HashSet<Node>,
+}
+
+impl InOrder {
+    pub fn new(requested: &[Node]) -> Self {
+        let mut in_order = InOrder::default();
+        for node in requested {
+            if in_order.requested.insert(*node) {
+                in_order.order.push_back(*node)
+            }
+        }
+        in_order
+    }
+
+    /// Mark `node` as downloaded, and return the nodes this releases, in
+    /// order
+    pub fn downloaded(&mut self, node: Node) -> Vec<Node> {
+        if !self.requested.contains(&node) {
+            return vec![node];
+        }
+        self.downloaded.insert(node);
+        let mut released = Vec::new();
+        while let Some(next) = self.order.front() {
+            if !self.downloaded.remove(next) {
+                break;
+            }
+            // Downloading it again releases it at once.
+            self.requested.remove(next);
+            released.extend(self.order.pop_front())
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_repository::fixtures::hash;
+    use quickcheck::{Arbitrary, Gen, QuickCheck};
+
+    /// A random DAG of changes, some of them tagged, in a random order.
+    #[derive(Debug, Clone)]
+    struct Dag {
+        nodes: Vec<Node>,
+        deps: HashMap<Hash, Vec<Hash>>,
+    }
+
+    impl Arbitrary for Dag {
+        fn arbitrary(g: &mut Gen) -> Self {
+            let n = usize::arbitrary(g) % 20;
+            let mut nodes = Vec::new();
+            let mut deps = HashMap::new();
+            let mut state = Merkle::zero();
+            for i in 0..n {
+                let h = hash(i as u64);
+                // Dependencies, including on changes not being uploaded.
+                let d: Vec<Hash> = (0..i + 3)
+                    .filter(|_| u8::arbitrary(g) % 4 == 0)
+                    .map(|j| hash(if j < i { j } else { 1000 + j } as u64))
+                    .collect();
+                deps.insert(h, d);
+                state = state.next(&h);
+                nodes.push(Node::change(h, state));
+                if bool::arbitrary(g) {
+                    nodes.push(Node::tag(h, state))
+                }
+            }
+            if !nodes.is_empty() && bool::arbitrary(g) {
+                nodes.push(nodes[usize::arbitrary(g) % nodes.len()])
+            }
+            // Shuffle
+            for i in (1..nodes.len()).rev() {
+                nodes.swap(i, usize::arbitrary(g) % (i + 1));
+            }
+            Dag { nodes, deps }
+        }
+    }
+
+    impl Dag {
+        fn deps(&self, h: &Hash) -> Result<Vec<Hash>, ()> {
+            Ok(self.deps.get(h).cloned().unwrap_or_default())
+        }
+
+        fn is_valid(&self, order: &[Node]) -> bool {
+            let position = |n: &Node| order.iter().position(|m| m == n).expect("node was dropped");
+            let unique: std::collections::HashSet<_> = self.nodes.iter().collect();
+            order.len() == unique.len()
+                && self.nodes.iter().all(|n| {
+                    let p = position(n);
+                    if n.is_change() {
+                        self.deps[&n.hash].iter().all(|d| {
+                            order
+                                .iter()
+                                .position(|m| m.is_change() && m.hash == *d)
+                                .map(|q| q < p)
+                                .unwrap_or(true)
+                        })
+                    } else {
+                        position(&Node::change(n.hash, n.state)) < p
+                    }
+                })
+        }
+    }
+
+    #[test]
+    fn test_upload_order_is_topological() {
+        fn prop(dag: Dag) -> bool {
+            let order = upload_order(&dag.nodes, |h| dag.deps(h)).unwrap();
+            dag.is_valid(&order)
+                // Deterministic, and stable on an already sorted input.
+                && upload_order(&dag.nodes, |h| dag.deps(h)).unwrap() == order
+                && upload_order(&order, |h| dag.deps(h)).unwrap() == order
+        }
+        QuickCheck::new()
+            .tests(200)
+            .quickcheck(prop as fn(Dag) -> bool);
+    }
+
+    #[test]
+    fn test_tag_after_sealed_state() {
+        let (a, b) = (hash(0), hash(1));
+        let (sa, sb) = (Merkle::zero().next(&a), Merkle::zero().next(&a).next(&b));
+        // As built by a push with path filters: tag first.
+        let nodes = [
+            Node::tag(b, sb),
+            Node::change(b, sb),
+            Node::change(a, sa),
+            Node::change(a, sa),
+        ];
+        let order = upload_order(&nodes, |h| {
+            Ok::<_, ()>(if *h == b { vec![a] } else { Vec::new() })
+        })
+        .unwrap();
+        assert_eq!(
+            order,
+            vec![Node::change(a, sa), Node::change(b, sb), Node::tag(b, sb)]
+        );
+    }
+
+    #[test]
+    fn test_upload_batches() {
+        fn prop(dag: Dag) -> bool {
+            let order = upload_order(&dag.nodes, |h| dag.deps(h)).unwrap();
+            let batches = upload_batches(&order, |h| dag.deps(h)).unwrap();
+            let last_tag = order.iter().rposition(|n| n.is_tag());
+            // Flattening the batches gives back the order, and any order
+            // within the batches is valid.
+            batches.concat() == order
+                && batches.iter().all(|b| {
+                    let reversed: Vec<Node> = batches
+                        .iter()
+                        .flat_map(|c| {
+                            let mut c = c.clone();
+                            if c == *b {
+                                c.reverse()
+                            }
+                            c
+                        })
+                        .collect();
+                    dag.is_valid(&reversed)
+                })
+                && batches
+                    .iter()
+                    .take(last_tag.map_or(0, |i| i + 1))
+                    .all(|b| b.len() == 1)
+        }
+        QuickCheck::new()
+            .tests(200)
+            .quickcheck(prop as fn(Dag) -> bool);
+    }
+
+    #[test]
+    fn test_in_order() {
+        let (a, b, c, x) = (hash(0), hash(1), hash(2), hash(3));
+        let node = |h| Node::change(h, Merkle::zero());
+        let mut in_order = InOrder::new(&[node(a), node(b), node(b), node(c)]);
+        assert!(in_order.downloaded(node(c)).is_empty());
+        assert!(in_order.downloaded(node(b)).is_empty());
+        // Not requested, released at once.
+        assert_eq!(in_order.downloaded(node(x)), vec![node(x)]);
+        assert_eq!(
+            in_order.downloaded(node(a)),
+            vec![node(a), node(b), node(c)]
+        );
+        assert_eq!(in_order.downloaded(node(b)), vec![node(b)]);
+    }
+}
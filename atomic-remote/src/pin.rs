@@ -0,0 +1,395 @@
+//! Pinning of HTTPS server certificates
+//!
+//! Servers with self-signed certificates otherwise require
+//! `--no-cert-check`, which disables every check. `atomic remote pin`
+//! records the SHA-256 fingerprint of the certificate a server presents in
+//! `known_certificates` (in the global configuration directory, one
+//! `host:port fingerprint` per line, like SSH's `known_hosts`), and every
+//! later connection to that server is only accepted if it presents the
+//! same certificate. When the server's certificate changes, `atomic remote
+//! unpin` removes the pin, and `atomic remote pin` replaces it.
+//! `--no-cert-check` still disables every check, pinned or not.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::http::{shared_client, Trust};
+use anyhow::{bail, Context};
+use atomic_config::HttpConnection;
+use log::{debug, warn};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ServerName};
+
+/// Name of the file holding the pins, in the global configuration directory
+pub const KNOWN_CERTIFICATES: &str = "known_certificates";
+
+/// SHA-256 fingerprint of a DER-encoded certificate
+pub fn fingerprint(der: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, der);
+    let hex: Vec<String> = digest
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("SHA256:{}", hex.join(""))
+}
+
+/// The `host:port` a pin applies to, for HTTPS URLs only
+pub fn origin(url: &url::Url) -> Option<String> {
+    if url.scheme() != "https" {
+        return None;
+    }
+    Some(format!(
+        "{}:{}",
+        url.host_str()?,
+        url.port_or_known_default()?
+    ))
+}
+
+/// The pinned certificates
+#[derive(Debug, Clone)]
+pub struct Pins {
+    path: PathBuf,
+}
+
+impl Pins {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Pins { path: path.into() }
+    }
+
+    /// The pins of the global configuration directory
+    pub fn global() -> Result<Self, anyhow::Error> {
+        let dir = atomic_config::global_config_dir()
+            .context("Could not find the global configuration directory")?;
+        Ok(Pins::new(dir.join(KNOWN_CERTIFICATES)))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read(&self) -> Result<Vec<(String, String)>, anyhow::Error> {
+        let s = match std::fs::read_to_string(&self.path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(s.lines()
+            .filter_map(|l| {
+                let mut it = l.split_whitespace();
+                Some((it.next()?.to_string(), it.next()?.to_string()))
+            })
+            .collect())
+    }
+
+    fn write(&self, pins: &[(String, String)]) -> Result<(), anyhow::Error> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut s = String::new();
+        for (origin, fp) in pins {
+            s.push_str(origin);
+            s.push(' ');
+            s.push_str(fp);
+            s.push('\n');
+        }
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, s)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// The fingerprint pinned for `origin`, if any
+    pub fn get(&self, origin: &str) -> Result<Option<String>, anyhow::Error> {
+        Ok(self
+            .read()?
+            .into_iter()
+            .find(|(o, _)| o == origin)
+            .map(|(_, fp)| fp))
+    }
+
+    /// Pin `fingerprint` for `origin`, replacing the previous pin
+    pub fn set(&self, origin: &str, fingerprint: &str) -> Result<(), anyhow::Error> {
+        let mut pins = self.read()?;
+        pins.retain(|(o, _)| o != origin);
+        pins.push((origin.to_string(), fingerprint.to_string()));
+        self.write(&pins)
+    }
+
+    /// Remove the pin of `origin`. Returns `false` if there was none.
+    pub fn remove(&self, origin: &str) -> Result<bool, anyhow::Error> {
+        let mut pins = self.read()?;
+        let len = pins.len();
+        pins.retain(|(o, _)| o != origin);
+        if pins.len() == len {
+            return Ok(false);
+        }
+        self.write(&pins)?;
+        Ok(true)
+    }
+}
+
+/// Accepts exactly one certificate, whoever signed it. Handshake
+/// signatures are still checked against that certificate's key.
+struct PinnedVerifier {
+    fingerprint: String,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fp = fingerprint(&end_entity.0);
+        if fp == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(format!(
+                "server certificate {} doesn't match the pinned certificate {}",
+                fp, self.fingerprint
+            )))
+        }
+    }
+}
+
+/// Records the certificate presented by the server, without checking it.
+#[derive(Default)]
+struct Recorder {
+    certificate: Mutex<Option<Vec<u8>>>,
+}
+
+impl ServerCertVerifier for Recorder {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        *self.certificate.lock().unwrap() = Some(end_entity.0.clone());
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn tls_config(verifier: Arc<dyn ServerCertVerifier>) -> rustls::ClientConfig {
+    rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth()
+}
+
+/// Fingerprint of the certificate currently presented by the server of
+/// `url`. Only a TLS handshake is made, no request is sent.
+pub async fn fetch_fingerprint(url: &url::Url) -> Result<String, anyhow::Error> {
+    let host = url.host_str().context("URL without a host")?;
+    let port = url.port_or_known_default().context("URL without a port")?;
+    let recorder = Arc::new(Recorder::default());
+    let connector = tokio_rustls::TlsConnector::from(Arc::new(tls_config(recorder.clone())));
+    let server_name = ServerName::try_from(host.trim_matches(|c| c == '[' || c == ']'))
+        .map_err(|e| anyhow::anyhow!("Invalid server name {:?}: {}", host, e))?;
+    let stream = tokio::net::TcpStream::connect((host, port)).await?;
+    connector.connect(server_name, stream).await?;
+    let certificate = recorder.certificate.lock().unwrap().take();
+    match certificate {
+        Some(der) => Ok(fingerprint(&der)),
+        None => bail!("{} didn't present a certificate", host),
+    }
+}
+
+/// HTTP client that only accepts the certificate with `fingerprint`
+pub fn pinned_client(fingerprint: &str) -> Result<reqwest::Client, anyhow::Error> {
+    pinned_client_with(fingerprint, &HttpConnection::default())
+}
+
+fn pinned_client_with(
+    fingerprint: &str,
+    connection: &HttpConnection,
+) -> Result<reqwest::Client, anyhow::Error> {
+    let verifier = Arc::new(PinnedVerifier {
+        fingerprint: fingerprint.to_string(),
+    });
+    // reqwest only negotiates HTTP/2 on its own TLS configurations.
+    let mut tls = tls_config(verifier);
+    tls.alpn_protocols = crate::http::alpn_protocols(connection);
+    Ok(crate::http::client_builder(connection)
+        .use_preconfigured_tls(tls)
+        .build()?)
+}
+
+/// HTTP client for `url`: without any certificate check if
+/// `no_cert_check` is set, pinned if the server has a pin, and with the
+/// usual certificate checks otherwise. Clients are shared, see
+/// [`shared_client`].
+pub async fn client(
+    url: &url::Url,
+    no_cert_check: bool,
+    connection: &HttpConnection,
+) -> Result<reqwest::Client, anyhow::Error> {
+    let pinned = match origin(url) {
+        Some(origin) => Pins::global()?.get(&origin)?.map(|fp| (origin, fp)),
+        None => None,
+    };
+    if no_cert_check {
+        if let Some((origin, fp)) = pinned {
+            warn!(
+                "Not checking the certificate of {}, although {} is pinned",
+                origin, fp
+            );
+        }
+        return shared_client(&Trust::Insecure, connection, || {
+            Ok(crate::http::client_builder(connection)
+                .danger_accept_invalid_certs(true)
+                .build()?)
+        });
+    }
+    if let Some((origin, fp)) = pinned {
+        debug!("Using pinned certificate {} for {}", fp, origin);
+        return shared_client(&Trust::Pinned(fp.clone()), connection, || {
+            pinned_client_with(&fp, connection)
+        });
+    }
+    shared_client(&Trust::Checked, connection, || {
+        Ok(crate::http::client_builder(connection).build()?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// An HTTPS server with a fresh self-signed certificate, answering
+    /// `ok` to every request.
+    async fn server() -> (url::Url, String) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        let fp = fingerprint(&der);
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(der)],
+                rustls::PrivateKey(cert.serialize_private_key_der()),
+            )
+            .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "https://localhost:{}/",
+            listener.local_addr().unwrap().port()
+        );
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(mut s) = acceptor.accept(stream).await {
+                        let mut buf = [0; 1024];
+                        let _ = s.read(&mut buf).await;
+                        let _ = s
+                            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                            .await;
+                        let _ = s.shutdown().await;
+                    }
+                });
+            }
+        });
+        (url.parse().unwrap(), fp)
+    }
+
+    #[tokio::test]
+    async fn test_pinned_client() {
+        let (url, fp) = server().await;
+        assert_eq!(fetch_fingerprint(&url).await.unwrap(), fp);
+
+        let res = pinned_client(&fp).unwrap().get(url.clone()).send().await;
+        assert_eq!(res.unwrap().text().await.unwrap(), "ok");
+
+        // Another certificate is rejected.
+        let (_, other) = server().await;
+        assert!(pinned_client(&other)
+            .unwrap()
+            .get(url)
+            .send()
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_pins_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let pins = Pins::new(tmp.path().join("config").join(KNOWN_CERTIFICATES));
+        let url: url::Url = "https://example.com/repo".parse().unwrap();
+        let origin = origin(&url).unwrap();
+        assert_eq!(origin, "example.com:443");
+        assert!(super::origin(&"http://example.com/".parse().unwrap()).is_none());
+
+        assert_eq!(pins.get(&origin).unwrap(), None);
+        pins.set(&origin, "SHA256:aa").unwrap();
+        pins.set("other:8443", "SHA256:bb").unwrap();
+        pins.set(&origin, "SHA256:cc").unwrap();
+        assert_eq!(pins.get(&origin).unwrap().as_deref(), Some("SHA256:cc"));
+        assert!(pins.remove(&origin).unwrap());
+        assert!(!pins.remove(&origin).unwrap());
+        assert_eq!(
+            pins.get("other:8443").unwrap().as_deref(),
+            Some("SHA256:bb")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shared_client() {
+        let (url, fp) = server().await;
+        let connection = HttpConnection {
+            pool_max_idle: Some(3),
+            ..HttpConnection::default()
+        };
+        let trust = Trust::Pinned(fp.clone());
+        let mut built = 0;
+        for _ in 0..2 {
+            let client = shared_client(&trust, &connection, || {
+                built += 1;
+                pinned_client_with(&fp, &connection)
+            })
+            .unwrap();
+            assert!(client.get(url.clone()).send().await.is_ok());
+        }
+        assert_eq!(built, 1);
+        // Other settings get their own client.
+        shared_client(&trust, &HttpConnection::default(), || {
+            built += 1;
+            pinned_client(&fp)
+        })
+        .unwrap();
+        assert_eq!(built, 2);
+        // And so do other certificate checks.
+        shared_client(&Trust::Insecure, &connection, || {
+            built += 1;
+            Ok(crate::http::client_builder(&connection).build()?)
+        })
+        .unwrap();
+        assert_eq!(built, 3);
+    }
+
+    #[tokio::test]
+    async fn test_no_cert_check() {
+        let (url, _) = server().await;
+        let connection = HttpConnection::default();
+        // Self-signed certificates are rejected, unless pinned...
+        let checked = client(&url, false, &connection).await.unwrap();
+        assert!(checked.get(url.clone()).send().await.is_err());
+        // ...or unless every check is disabled, which pins nothing.
+        let insecure = client(&url, true, &connection).await.unwrap();
+        let res = insecure.get(url.clone()).send().await;
+        assert_eq!(res.unwrap().text().await.unwrap(), "ok");
+        let origin = origin(&url).unwrap();
+        assert_eq!(Pins::global().unwrap().get(&origin).unwrap(), None);
+    }
+}
@@ -0,0 +1,644 @@
+//! Commands and responses of the SSH protocol
+//!
+//! The client sends one command per line to `atomic protocol`, running on
+//! the server, sometimes followed by a binary payload whose size is part
+//! of the command (`apply`, `applybundle`, `tagup`, `notesup`).
+//! [`Command`] is the single definition of these lines: the client encodes
+//! them, and the server decodes them, so that both sides can't drift apart.
+//!
+//! Most responses are binary (changes, tags, archives) or JSON lines
+//! (identities, notes). The text ones have their own types:
+//! [`StateLine`] answers `state`, and [`ListLine`] is a line of the answer
+//! to `changelist`, which the HTTP protocol shares.
+
+use anyhow::bail;
+use libatomic::pristine::{ChangePosition, Position};
+use libatomic::{Base32, Hash, Merkle};
+
+/// A command of the protocol
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Id of a channel
+    Id { channel: String },
+    /// State of a channel: at position `position`, or its current state
+    State {
+        channel: String,
+        position: Option<u64>,
+    },
+    /// Nodes of a channel from position `from`, touching `paths` if any
+    Changelist {
+        channel: String,
+        from: u64,
+        paths: Vec<String>,
+    },
+    /// Download a change
+    Change { hash: Hash },
+    /// Download a change, without its contents if it is large
+    Partial { hash: Hash },
+    /// Download a bundle of changes (see [`crate::bundle`])
+    Bundle { hashes: Vec<Hash> },
+    /// Download a bundle of changes, without the contents of the large
+    /// ones
+    PartialBundle { hashes: Vec<Hash> },
+    /// Download the short version of a tag
+    Tag { state: Merkle },
+    /// Upload a tag of the current state of `channel`, followed by `size`
+    /// bytes of short tag
+    Tagup {
+        state: Merkle,
+        channel: String,
+        size: usize,
+    },
+    /// Apply a change to `channel`, followed by `size` bytes of change
+    Apply {
+        channel: String,
+        hash: Hash,
+        size: usize,
+    },
+    /// Apply the changes of a bundle to `channel`, in order, followed by
+    /// `size` bytes of bundle
+    Applybundle { channel: String, size: usize },
+    /// Tarball of a channel, at a state plus extra changes if any
+    Archive {
+        channel: String,
+        state: Option<(Merkle, Vec<Hash>)>,
+        prefix: Option<String>,
+    },
+    /// Identities modified since a timestamp
+    Identities { since: Option<u64> },
+    /// Download the notes
+    Notes,
+    /// Upload notes, followed by `size` bytes of JSON
+    Notesup { size: usize },
+    /// Download the external links of changes
+    Links,
+    /// Upload links, followed by `size` bytes of JSON
+    Linksup { size: usize },
+    /// Download the attribution of changes (see [`crate::attribution`])
+    Attribution { hashes: Vec<Hash> },
+    /// Upload the attribution of changes, followed by `size` bytes of JSON
+    Attributionup { size: usize },
+    /// Ask the server for a challenge, to prove the ownership of `key`
+    /// (a JSON public key)
+    Challenge { key: String },
+    /// Signature of a challenge
+    Prove { signature: String },
+}
+
+impl Command {
+    /// The line of this command, including the final newline
+    pub fn encode(&self) -> String {
+        let mut line = match *self {
+            Command::Id { ref channel } => format!("id {}", channel),
+            Command::State {
+                ref channel,
+                position: Some(position),
+            } => format!("state {} {}", channel, position),
+            Command::State {
+                ref channel,
+                position: None,
+            } => format!("state {}", channel),
+            Command::Changelist {
+                ref channel,
+                from,
+                ref paths,
+            } => {
+                let mut line = format!("changelist {} {}", channel, from);
+                for path in paths {
+                    line.push(' ');
+                    quote(&mut line, path)
+                }
+                line
+            }
+            Command::Change { ref hash } => format!("change {}", hash.to_base32()),
+            Command::Partial { ref hash } => format!("partial {}", hash.to_base32()),
+            Command::Bundle { ref hashes } => hashes_line("bundle", hashes),
+            Command::PartialBundle { ref hashes } => hashes_line("partialbundle", hashes),
+            Command::Tag { ref state } => format!("tag {}", state.to_base32()),
+            Command::Tagup {
+                ref state,
+                ref channel,
+                size,
+            } => format!("tagup {} {} {}", state.to_base32(), channel, size),
+            Command::Apply {
+                ref channel,
+                ref hash,
+                size,
+            } => format!("apply {} {} {}", channel, hash.to_base32(), size),
+            Command::Applybundle { ref channel, size } => {
+                format!("applybundle {} {}", channel, size)
+            }
+            Command::Archive {
+                ref channel,
+                ref state,
+                ref prefix,
+            } => {
+                let mut line = format!("archive {}", channel);
+                if let Some((ref state, ref extra)) = *state {
+                    line.push(' ');
+                    line.push_str(&state.to_base32());
+                    for e in extra {
+                        line.push(' ');
+                        line.push_str(&e.to_base32());
+                    }
+                }
+                if let Some(ref prefix) = *prefix {
+                    line.push_str(" :");
+                    line.push_str(prefix)
+                }
+                line
+            }
+            Command::Identities { since: Some(since) } => format!("identities {}", since),
+            Command::Identities { since: None } => "identities".to_string(),
+            Command::Notes => "notes".to_string(),
+            Command::Notesup { size } => format!("notesup {}", size),
+            Command::Links => "links".to_string(),
+            Command::Linksup { size } => format!("linksup {}", size),
+            Command::Attribution { ref hashes } => hashes_line("attribution", hashes),
+            Command::Attributionup { size } => format!("attributionup {}", size),
+            Command::Challenge { ref key } => format!("challenge {}", key),
+            Command::Prove { ref signature } => format!("prove {}", signature),
+        };
+        line.push('\n');
+        line
+    }
+
+    /// Decode a command line, with or without its final newline
+    pub fn decode(line: &str) -> Result<Self, anyhow::Error> {
+        let line = line.strip_suffix('\n').unwrap_or(line);
+        let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let mut args = rest.split_whitespace();
+        let command = match name {
+            "id" => Command::Id {
+                channel: word(args.next())?,
+            },
+            "state" => Command::State {
+                channel: word(args.next())?,
+                position: args.next().map(str::parse).transpose()?,
+            },
+            "changelist" => {
+                let mut args = rest.trim_start().splitn(3, ' ');
+                Command::Changelist {
+                    channel: word(args.next())?,
+                    from: word(args.next())?.parse()?,
+                    paths: unquote_all(args.next().unwrap_or(""))?,
+                }
+            }
+            "change" => Command::Change {
+                hash: hash(args.next())?,
+            },
+            "partial" => Command::Partial {
+                hash: hash(args.next())?,
+            },
+            "bundle" => Command::Bundle {
+                hashes: hashes(args)?,
+            },
+            "partialbundle" => Command::PartialBundle {
+                hashes: hashes(args)?,
+            },
+            "tag" => Command::Tag {
+                state: merkle(args.next())?,
+            },
+            "tagup" => Command::Tagup {
+                state: merkle(args.next())?,
+                channel: word(args.next())?,
+                size: word(args.next())?.parse()?,
+            },
+            "apply" => Command::Apply {
+                channel: word(args.next())?,
+                hash: hash(args.next())?,
+                size: word(args.next())?.parse()?,
+            },
+            "applybundle" => Command::Applybundle {
+                channel: word(args.next())?,
+                size: word(args.next())?.parse()?,
+            },
+            "archive" => {
+                let (rest, prefix) = match rest.split_once(" :") {
+                    Some((rest, prefix)) => (rest, Some(prefix.to_string())),
+                    None => (rest, None),
+                };
+                let mut args = rest.split_whitespace();
+                let channel = word(args.next())?;
+                let state = if let Some(state) = args.next() {
+                    let state = merkle(Some(state))?;
+                    let extra = args.map(|e| hash(Some(e))).collect::<Result<_, _>>()?;
+                    Some((state, extra))
+                } else {
+                    None
+                };
+                Command::Archive {
+                    channel,
+                    state,
+                    prefix,
+                }
+            }
+            "identities" => Command::Identities {
+                since: args.next().map(str::parse).transpose()?,
+            },
+            "notes" => Command::Notes,
+            "notesup" => Command::Notesup {
+                size: word(args.next())?.parse()?,
+            },
+            "links" => Command::Links,
+            "linksup" => Command::Linksup {
+                size: word(args.next())?.parse()?,
+            },
+            "attribution" => Command::Attribution {
+                hashes: hashes(args)?,
+            },
+            "attributionup" => Command::Attributionup {
+                size: word(args.next())?.parse()?,
+            },
+            "challenge" if !rest.is_empty() => Command::Challenge {
+                key: rest.to_string(),
+            },
+            "prove" if !rest.is_empty() => Command::Prove {
+                signature: rest.to_string(),
+            },
+            _ => bail!("Protocol error: unknown command {:?}", line),
+        };
+        Ok(command)
+    }
+}
+
+fn word(w: Option<&str>) -> Result<String, anyhow::Error> {
+    match w {
+        Some(w) if !w.is_empty() => Ok(w.to_string()),
+        _ => bail!("Protocol error: missing argument"),
+    }
+}
+
+fn hash(w: Option<&str>) -> Result<Hash, anyhow::Error> {
+    let w = word(w)?;
+    match Hash::from_base32(w.as_bytes()) {
+        Some(h) => Ok(h),
+        None => bail!("Protocol error: invalid hash {:?}", w),
+    }
+}
+
+/// The hashes of a bundle command, at least one
+fn hashes<'a, I: Iterator<Item = &'a str>>(args: I) -> Result<Vec<Hash>, anyhow::Error> {
+    let hashes = args.map(|h| hash(Some(h))).collect::<Result<Vec<_>, _>>()?;
+    if hashes.is_empty() {
+        bail!("Protocol error: missing argument")
+    }
+    Ok(hashes)
+}
+
+fn hashes_line(name: &str, hashes: &[Hash]) -> String {
+    let mut line = name.to_string();
+    for h in hashes {
+        line.push(' ');
+        line.push_str(&h.to_base32());
+    }
+    line
+}
+
+fn merkle(w: Option<&str>) -> Result<Merkle, anyhow::Error> {
+    let w = word(w)?;
+    match Merkle::from_base32(w.as_bytes()) {
+        Some(m) => Ok(m),
+        None => bail!("Protocol error: invalid state {:?}", w),
+    }
+}
+
+/// Quote a path of `changelist`, as Rust's `{:?}` does for the
+/// characters that can appear in paths
+fn quote(line: &mut String, path: &str) {
+    line.push('"');
+    for c in path.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+}
+
+/// The quoted paths of `s`, separated by spaces
+fn unquote_all(s: &str) -> Result<Vec<String>, anyhow::Error> {
+    let mut paths = Vec::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' => continue,
+            '"' => {}
+            _ => bail!("Protocol error: unquoted path in {:?}", s),
+        }
+        let mut path = String::new();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => match chars.next() {
+                    Some('n') => path.push('\n'),
+                    Some('r') => path.push('\r'),
+                    Some('t') => path.push('\t'),
+                    Some('0') => path.push('\0'),
+                    // `{:?}` escapes other control characters as `\u{..}`.
+                    Some('u') => path.push(unicode_escape(&mut chars, s)?),
+                    Some(c) => path.push(c),
+                    None => bail!("Protocol error: unterminated path in {:?}", s),
+                },
+                Some(c) => path.push(c),
+                None => bail!("Protocol error: unterminated path in {:?}", s),
+            }
+        }
+        paths.push(path)
+    }
+    Ok(paths)
+}
+
+fn unicode_escape(chars: &mut std::str::Chars, s: &str) -> Result<char, anyhow::Error> {
+    if chars.next() == Some('{') {
+        let hex: String = chars.by_ref().take_while(|c| *c != '}').collect();
+        if let Some(c) = u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+            return Ok(c);
+        }
+    }
+    bail!("Protocol error: invalid escape in {:?}", s)
+}
+
+/// Answer to a `state` command: the position and state of a change, with
+/// the state of the last tag at or before it, or nothing if the channel
+/// has no change at that position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateLine(pub Option<(u64, Merkle, Merkle)>);
+
+impl StateLine {
+    pub fn encode(&self) -> String {
+        match self.0 {
+            Some((n, ref m, ref tag)) => format!("{} {} {}\n", n, m.to_base32(), tag.to_base32()),
+            None => "-\n".to_string(),
+        }
+    }
+
+    /// The line of this answer, followed by the capabilities of the server
+    /// (such as [`crate::bundle::CAPABILITY`]), which older clients ignore.
+    pub fn encode_with(&self, capabilities: &[&str]) -> String {
+        let mut line = self.encode();
+        line.pop();
+        for c in capabilities {
+            line.push(' ');
+            line.push_str(c);
+        }
+        line.push('\n');
+        line
+    }
+
+    /// Decode a line. Anything else than a state, such as the standard
+    /// `-`, is decoded as no state.
+    pub fn decode(line: &str) -> Self {
+        let mut s = line.split_whitespace();
+        if let (Some(n), Some(m), Some(tag)) = (s.next(), s.next(), s.next()) {
+            if let (Ok(n), Some(m), Some(tag)) = (
+                n.parse(),
+                Merkle::from_base32(m.as_bytes()),
+                Merkle::from_base32(tag.as_bytes()),
+            ) {
+                return StateLine(Some((n, m, tag)));
+            }
+        }
+        StateLine(None)
+    }
+}
+
+/// A line of the answer to `changelist`, which ends with an empty line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListLine {
+    /// A node of the channel, at position `n`
+    Change {
+        n: u64,
+        h: Hash,
+        m: Merkle,
+        tag: bool,
+    },
+    /// The position of one of the paths of the command
+    Position(Position<Hash>),
+    Error(String),
+}
+
+impl ListLine {
+    /// The line, without its final newline
+    pub fn encode(&self) -> String {
+        match *self {
+            ListLine::Change {
+                n,
+                ref h,
+                ref m,
+                tag,
+            } => format!(
+                "{}.{}.{}{}",
+                n,
+                h.to_base32(),
+                m.to_base32(),
+                if tag { "." } else { "" }
+            ),
+            ListLine::Position(ref pos) => format!("{}.{}", pos.change.to_base32(), pos.pos.0),
+            ListLine::Error(ref e) => format!("error:{}", e),
+        }
+    }
+
+    pub fn decode(line: &str) -> Result<Self, anyhow::Error> {
+        if let Some(e) = line.strip_prefix("error:") {
+            return Ok(ListLine::Error(e.to_string()));
+        }
+        let fields: Vec<&str> = line.trim_end().split('.').collect();
+        match fields[..] {
+            [n, h, m] | [n, h, m, ""] => {
+                if let (Ok(n), Some(h), Some(m)) = (
+                    n.parse(),
+                    Hash::from_base32(h.as_bytes()),
+                    Merkle::from_base32(m.as_bytes()),
+                ) {
+                    return Ok(ListLine::Change {
+                        n,
+                        h,
+                        m,
+                        tag: fields.len() == 4,
+                    });
+                }
+            }
+            [h, pos] => {
+                if let (Some(change), Ok(pos)) =
+                    (Hash::from_base32(h.as_bytes()), pos.parse::<u64>())
+                {
+                    return Ok(ListLine::Position(Position {
+                        change,
+                        pos: ChangePosition(pos.into()),
+                    }));
+                }
+            }
+            _ => {}
+        }
+        bail!("Protocol error: {:?}", line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_repository::fixtures::hash;
+
+    #[test]
+    fn test_command_round_trip() {
+        let state = Merkle::zero().next(&hash(0));
+        let commands = [
+            Command::Id {
+                channel: "main".to_string(),
+            },
+            Command::State {
+                channel: "main".to_string(),
+                position: None,
+            },
+            Command::State {
+                channel: "main".to_string(),
+                position: Some(12),
+            },
+            Command::Changelist {
+                channel: "main".to_string(),
+                from: 3,
+                paths: Vec::new(),
+            },
+            Command::Changelist {
+                channel: "main".to_string(),
+                from: 0,
+                paths: vec![
+                    "src/lib.rs".to_string(),
+                    "with space/\"quoted\" \\ file".to_string(),
+                    "tab\tand\nnewline".to_string(),
+                    "zero\u{200b}width".to_string(),
+                ],
+            },
+            Command::Change { hash: hash(1) },
+            Command::Partial { hash: hash(2) },
+            Command::Bundle {
+                hashes: vec![hash(1), hash(2)],
+            },
+            Command::PartialBundle {
+                hashes: vec![hash(3)],
+            },
+            Command::Tag { state },
+            Command::Tagup {
+                state,
+                channel: "main".to_string(),
+                size: 120,
+            },
+            Command::Apply {
+                channel: "main".to_string(),
+                hash: hash(3),
+                size: 4096,
+            },
+            Command::Applybundle {
+                channel: "main".to_string(),
+                size: 4096,
+            },
+            Command::Archive {
+                channel: "main".to_string(),
+                state: None,
+                prefix: None,
+            },
+            Command::Archive {
+                channel: "main".to_string(),
+                state: None,
+                prefix: Some("repo-1.0".to_string()),
+            },
+            Command::Archive {
+                channel: "main".to_string(),
+                state: Some((state, vec![hash(4), hash(5)])),
+                prefix: Some("with: colon".to_string()),
+            },
+            Command::Archive {
+                channel: "main".to_string(),
+                state: Some((state, Vec::new())),
+                prefix: None,
+            },
+            Command::Identities { since: None },
+            Command::Identities {
+                since: Some(1_700_000_000),
+            },
+            Command::Notes,
+            Command::Notesup { size: 42 },
+            Command::Links,
+            Command::Linksup { size: 7 },
+            Command::Attribution {
+                hashes: vec![hash(1), hash(4)],
+            },
+            Command::Attributionup { size: 512 },
+            Command::Challenge {
+                key: r#"{"version":0,"algorithm":"Ed25519","key":"abc"}"#.to_string(),
+            },
+            Command::Prove {
+                signature: "SIGNATURE".to_string(),
+            },
+        ];
+        for command in commands {
+            let line = command.encode();
+            assert!(line.ends_with('\n') && !line[..line.len() - 1].contains('\n'));
+            assert_eq!(Command::decode(&line).unwrap(), command, "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn test_command_wire_format() {
+        // The lines sent by earlier clients.
+        let paths = ["a \"b\"".to_string()];
+        let line = format!("changelist main 7 {:?}\n", paths[0]);
+        assert_eq!(
+            Command::decode(&line).unwrap(),
+            Command::Changelist {
+                channel: "main".to_string(),
+                from: 7,
+                paths: paths.to_vec(),
+            }
+        );
+        assert_eq!(
+            Command::State {
+                channel: "main".to_string(),
+                position: Some(3)
+            }
+            .encode(),
+            "state main 3\n"
+        );
+        assert!(Command::decode("state\n").is_err());
+        assert!(Command::decode("change NOTAHASH\n").is_err());
+        assert!(Command::decode("launch main\n").is_err());
+    }
+
+    #[test]
+    fn test_response_round_trip() {
+        let state = Merkle::zero().next(&hash(0));
+        for line in [StateLine(None), StateLine(Some((4, state, Merkle::zero())))] {
+            assert_eq!(StateLine::decode(&line.encode()), line);
+            // Older clients ignore the capabilities of the server.
+            assert_eq!(StateLine::decode(&line.encode_with(&["bundle"])), line);
+        }
+        let lines = [
+            ListLine::Change {
+                n: 0,
+                h: hash(1),
+                m: state,
+                tag: false,
+            },
+            ListLine::Change {
+                n: 5,
+                h: hash(2),
+                m: state,
+                tag: true,
+            },
+            ListLine::Position(Position {
+                change: hash(3),
+                pos: ChangePosition(17u64.into()),
+            }),
+            ListLine::Error("path not found".to_string()),
+        ];
+        for line in lines {
+            assert_eq!(ListLine::decode(&line.encode()).unwrap(), line);
+        }
+        assert!(ListLine::decode("garbage").is_err());
+    }
+}
@@ -0,0 +1,282 @@
+//! Pulls that go on after a node fails to apply
+//!
+//! A [`PullReport`] applies the downloaded nodes one at a time, in order.
+//! A node that can't be applied (corrupt change file, missing dependency…)
+//! is quarantined instead of aborting the pull, and so are the nodes after
+//! it that depend on it, and the tags after it, since the state they seal
+//! can't be reached anymore. The other nodes are applied as usual.
+//!
+//! Applying a change writes to the channel before all its hunks are in,
+//! so nodes are checked before the transaction is touched: every change
+//! (or tag) they bring in must load from the change store. An error once
+//! the check passed is returned, and the transaction must not be
+//! committed.
+//!
+//! The report is saved to the quarantine of the repository
+//! ([`atomic_repository::quarantine`]), and [`retry`] applies the
+//! quarantined nodes of a channel again once the cause is fixed.
+
+use std::collections::HashSet;
+
+use atomic_repository::quarantine::{Quarantine, QuarantinedNode};
+use libatomic::changestore::ChangeStore;
+use libatomic::pristine::{Base32, Hash};
+use libatomic::{ApplyWorkspace, MutTxnTExt};
+use log::{debug, warn};
+
+use crate::timing::PullTimings;
+use crate::Node;
+
+/// A node that wasn't applied, and why
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quarantined {
+    pub node: Node,
+    pub reason: String,
+}
+
+/// Nodes applied and quarantined by a pull
+#[derive(Debug, Clone, Default)]
+pub struct PullReport {
+    pub applied: Vec<Node>,
+    pub quarantined: Vec<Quarantined>,
+    /// Time spent in each phase of the pull
+    pub timings: PullTimings,
+    /// Nodes downloaded by [`crate::RemoteRepo::pull`] that touch the
+    /// paths pulled, in order
+    pub pulled: Vec<Node>,
+    failed: HashSet<Hash>,
+}
+
+impl PullReport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether some of the nodes were quarantined
+    pub fn is_partial(&self) -> bool {
+        !self.quarantined.is_empty()
+    }
+
+    /// Why `node` can't be applied without trying, if it can't
+    fn blocked<C: ChangeStore>(&self, changes: &C, node: &Node) -> Option<String> {
+        if self.failed.is_empty() {
+            return None;
+        }
+        if node.is_tag() {
+            return Some(format!(
+                "State {} depends on quarantined nodes",
+                node.state.to_base32()
+            ));
+        }
+        match changes.get_dependencies(&node.hash) {
+            Ok(deps) => deps
+                .iter()
+                .find(|d| self.failed.contains(d))
+                .map(|d| format!("Depends on quarantined change {}", d.to_base32())),
+            Err(e) => Some(e.to_string()),
+        }
+    }
+
+    fn quarantine(&mut self, node: &Node, reason: String) {
+        warn!("Quarantining {}: {}", node.hash.to_base32(), reason);
+        self.failed.insert(node.hash);
+        self.quarantined.push(Quarantined {
+            node: *node,
+            reason,
+        })
+    }
+
+    /// Why `node` can't be applied to `channel`, if it can't: one of the
+    /// nodes it brings in is missing from the channel and can't be loaded,
+    /// the same way [`libatomic::apply::apply_node_rec_ws`] loads them.
+    fn missing<T: MutTxnTExt, C: ChangeStore>(
+        &self,
+        changes: &C,
+        txn: &T,
+        channel: &T::Channel,
+        node: &Node,
+    ) -> Result<Option<String>, anyhow::Error> {
+        let mut stack = vec![node.hash];
+        let mut visited = HashSet::new();
+        while let Some(hash) = stack.pop() {
+            if !visited.insert(hash) {
+                continue;
+            }
+            if let Some(int) = txn.get_internal(&(&hash).into())? {
+                if txn.get_changeset(txn.changes(channel), int)?.is_some() {
+                    continue;
+                }
+            }
+            if self.failed.contains(&hash) {
+                return Ok(Some(format!(
+                    "Depends on quarantined change {}",
+                    hash.to_base32()
+                )));
+            }
+            let deps = match changes.get_change(&hash) {
+                Ok(change) => change.hashed.dependencies,
+                Err(e) => match txn.get_tag(&hash)? {
+                    Some(tag) => tag.to_tag()?.consolidated_changes,
+                    None => {
+                        return Ok(Some(if hash == node.hash {
+                            e.to_string()
+                        } else {
+                            format!("Dependency {}: {}", hash.to_base32(), e)
+                        }))
+                    }
+                },
+            };
+            stack.extend(deps.into_iter().filter(|d| !d.is_none()));
+        }
+        Ok(None)
+    }
+
+    /// Apply `node` and its dependencies to `channel`, or quarantine it
+    /// if they can't be loaded. Returns whether `node` was applied.
+    ///
+    /// Nodes are quarantined before `txn` is modified. An error means
+    /// `txn` may hold part of a change, and must be dropped.
+    pub fn apply<T: MutTxnTExt, C: ChangeStore>(
+        &mut self,
+        changes: &C,
+        txn: &mut T,
+        channel: &mut T::Channel,
+        node: &Node,
+        ws: &mut ApplyWorkspace,
+    ) -> Result<bool, anyhow::Error> {
+        let reason = match self.blocked(changes, node) {
+            Some(reason) => Some(reason),
+            None => self.missing(changes, txn, channel, node)?,
+        };
+        if let Some(reason) = reason {
+            self.quarantine(node, reason);
+            return Ok(false);
+        }
+        txn.apply_node_rec_ws(changes, channel, &node.hash, node.node_type, ws)
+            .map_err(|e| anyhow::anyhow!("Failed to apply {}: {}", node.hash.to_base32(), e))?;
+        self.applied.push(*node);
+        Ok(true)
+    }
+
+    /// The nodes applied, or an error if some of them were quarantined,
+    /// for pulls that need all of them, such as clones
+    pub fn complete(self) -> Result<Vec<Node>, anyhow::Error> {
+        if let Some(q) = self.quarantined.first() {
+            anyhow::bail!(
+                "Could not apply {} node(s), {}: {}",
+                self.quarantined.len(),
+                q.node.hash.to_base32(),
+                q.reason
+            )
+        }
+        Ok(self.applied)
+    }
+
+    /// Record the quarantined nodes in `quarantine`, and remove the
+    /// applied ones from it
+    pub fn save(&self, quarantine: &Quarantine, channel: &str) -> Result<(), anyhow::Error> {
+        let failed = self
+            .quarantined
+            .iter()
+            .map(|q| {
+                QuarantinedNode::new(
+                    channel,
+                    &q.node.hash,
+                    &q.node.state,
+                    q.node.node_type,
+                    q.reason.as_str(),
+                )
+            })
+            .collect();
+        quarantine.update(channel, failed, self.applied.iter().map(|n| &n.hash))
+    }
+}
+
+/// Apply the nodes of `channel_name` quarantined by previous pulls again,
+/// in the order they were pulled. The report is meant to be saved once
+/// `txn` is committed, and `txn` dropped if this returns an error.
+pub fn retry<T: MutTxnTExt, C: ChangeStore>(
+    quarantine: &Quarantine,
+    changes: &C,
+    txn: &mut T,
+    channel: &mut T::Channel,
+    channel_name: &str,
+) -> Result<PullReport, anyhow::Error> {
+    let mut ws = ApplyWorkspace::new();
+    let mut report = PullReport::new();
+    for q in quarantine.list(Some(channel_name))? {
+        let (hash, state) = match (q.hash(), q.state()) {
+            (Some(hash), Some(state)) => (hash, state),
+            _ => anyhow::bail!("Invalid quarantined node {:?}", q.hash),
+        };
+        let node = Node {
+            hash,
+            node_type: q.node_type(),
+            state,
+        };
+        debug!("retrying {:?}", node);
+        report.apply(changes, txn, channel, &node, &mut ws)?;
+    }
+    Ok(report)
+}
+
+/// Print a summary of the quarantined nodes of `report`
+pub fn print_report<W: std::io::Write>(mut w: W, report: &PullReport) -> std::io::Result<()> {
+    if !report.is_partial() {
+        return Ok(());
+    }
+    writeln!(
+        w,
+        "Applied {} node(s), quarantined {}:",
+        report.applied.len(),
+        report.quarantined.len()
+    )?;
+    for q in report.quarantined.iter() {
+        let id = if q.node.is_tag() {
+            q.node.state.to_base32()
+        } else {
+            q.node.hash.to_base32()
+        };
+        writeln!(w, "  {} {}", id, q.reason)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_repository::fixtures::hash;
+    use libatomic::pristine::Merkle;
+
+    #[test]
+    fn test_save() {
+        let tmp = tempfile::tempdir().unwrap();
+        let quarantine = Quarantine::new(tmp.path().join("quarantine"));
+        let mut report = PullReport::new();
+        report.quarantine(
+            &Node::change(hash(0), Merkle::zero()),
+            "corrupt".to_string(),
+        );
+        report.quarantine(&Node::tag(hash(1), Merkle::zero()), "blocked".to_string());
+        report.applied.push(Node::change(hash(2), Merkle::zero()));
+        assert!(report.is_partial());
+        report.save(&quarantine, "main").unwrap();
+
+        let nodes = quarantine.list(Some("main")).unwrap();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].hash(), Some(hash(0)));
+        assert_eq!(nodes[0].reason, "corrupt");
+        assert_eq!(nodes[1].node_type(), libatomic::pristine::NodeType::Tag);
+
+        // Applying them later clears the quarantine.
+        let report = PullReport {
+            applied: vec![
+                Node::change(hash(0), Merkle::zero()),
+                Node::tag(hash(1), Merkle::zero()),
+            ],
+            ..PullReport::default()
+        };
+        report.save(&quarantine, "main").unwrap();
+        assert!(quarantine.list(None).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+//! Retries of the requests to HTTP remotes.
+//!
+//! Requests that fail on a network error, or that the server answers with
+//! a `5xx` or `429`, are sent again after an exponential backoff, up to
+//! the attempts of the remote's [`RetryPolicy`], configured in the
+//! `[remotes.retry]` table of the remote (see [`HttpRetry`]). Change and
+//! tag downloads interrupted mid-body are resumed from the bytes already
+//! written, with a `Range` request.
+
+use atomic_config::HttpRetry;
+use log::debug;
+use std::time::Duration;
+
+/// Attempts of a request, unless configured
+pub const DEFAULT_ATTEMPTS: u32 = 6;
+/// Delay before the first retry, unless configured
+pub const DEFAULT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+/// Maximal delay between two attempts, unless configured
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// How failed requests to a remote are retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts of each request, including the first one, at least 1
+    pub attempts: u32,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Whether to wait between half of each delay and all of it
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: DEFAULT_ATTEMPTS,
+            initial_delay: DEFAULT_INITIAL_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(config: &HttpRetry) -> Self {
+        let initial_delay = config
+            .initial_delay
+            .map_or(DEFAULT_INITIAL_DELAY, Duration::from_millis);
+        RetryPolicy {
+            attempts: config.attempts.unwrap_or(DEFAULT_ATTEMPTS).max(1),
+            initial_delay,
+            max_delay: config
+                .max_delay
+                .map_or(DEFAULT_MAX_DELAY, Duration::from_millis)
+                .max(initial_delay),
+            jitter: config.jitter.unwrap_or(true),
+        }
+    }
+
+    /// The delay before attempt `attempt + 1`, after `attempt` failed
+    /// attempts, without the jitter.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        self.initial_delay
+            .checked_mul(factor)
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay)
+    }
+
+    /// The delay before attempt `attempt + 1`, or `None` if `attempt`
+    /// attempts were all the attempts of this policy.
+    pub fn delay(&self, attempt: u32) -> Option<Duration> {
+        if attempt >= self.attempts {
+            return None;
+        }
+        let backoff = self.backoff(attempt);
+        if !self.jitter {
+            return Some(backoff);
+        }
+        // No need for a good source of randomness to spread clients out.
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.subsec_nanos());
+        Some(backoff / 2 + backoff.mul_f64((nanos % 1000) as f64 / 2000.))
+    }
+
+    /// Wait before attempt `attempt + 1`, returning `false` if there are
+    /// no attempts left.
+    pub async fn wait(&self, attempt: u32) -> bool {
+        if let Some(delay) = self.delay(attempt) {
+            debug!("attempt {} failed, retrying in {:?}", attempt, delay);
+            tokio::time::sleep(delay).await;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Whether an answer with `status` is worth retrying
+pub fn is_transient(status: reqwest::StatusCode) -> bool {
+    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Send `req`, retrying it according to `policy`. Answers that aren't
+/// transient are returned as they are, errors or not, as well as the last
+/// transient one. Requests with a streamed body are sent once.
+pub async fn send(
+    policy: &RetryPolicy,
+    req: reqwest::RequestBuilder,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let mut attempt = 0;
+    loop {
+        let Some(this) = req.try_clone() else {
+            return req.send().await;
+        };
+        attempt += 1;
+        match this.send().await {
+            Ok(res) if is_transient(res.status()) && policy.wait(attempt).await => {
+                debug!("server answered {}, retrying", res.status());
+            }
+            Ok(res) => return Ok(res),
+            Err(e) if policy.wait(attempt).await => {
+                debug!("HTTP error {:?}, retrying", e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::new(&HttpRetry {
+            attempts: Some(4),
+            initial_delay: Some(100),
+            max_delay: Some(300),
+            jitter: Some(false),
+        });
+        assert_eq!(policy.delay(1), Some(Duration::from_millis(100)));
+        assert_eq!(policy.delay(2), Some(Duration::from_millis(200)));
+        assert_eq!(policy.delay(3), Some(Duration::from_millis(300)));
+        assert_eq!(policy.delay(4), None);
+        assert_eq!(policy.backoff(100), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let policy = RetryPolicy::default();
+        for attempt in 1..policy.attempts {
+            let delay = policy.delay(attempt).unwrap();
+            let backoff = policy.backoff(attempt);
+            assert!(delay >= backoff / 2 && delay <= backoff);
+        }
+    }
+
+    #[test]
+    fn test_default() {
+        assert_eq!(
+            RetryPolicy::new(&HttpRetry::default()),
+            RetryPolicy::default()
+        );
+        let once = RetryPolicy::new(&HttpRetry {
+            attempts: Some(0),
+            ..HttpRetry::default()
+        });
+        assert_eq!(once.attempts, 1);
+        assert_eq!(once.delay(1), None);
+    }
+}
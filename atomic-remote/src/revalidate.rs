@@ -0,0 +1,232 @@
+//! Background revalidation of remote caches
+//!
+//! The local cache of a remote (its changelist, as of the last push or
+//! pull) silently goes stale when another client unrecords changes on
+//! the remote and pushes again. This is only noticed at the next push or
+//! pull, by the dichotomy between the cache and the remote.
+//!
+//! A revalidation runs that dichotomy ahead of time: for each configured
+//! remote, it compares the cache with the remote's changelist, and
+//! publishes an [`Event::RemoteDiverged`] to the event bus when changes
+//! listed in the cache and applied locally are gone from the remote.
+//! Revalidations never modify the repository: the transaction they run
+//! in is dropped without being committed.
+//!
+//! [`spawn`] runs revalidations in the background, on an interval, for a
+//! daemon or a server embedding atomic.
+
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Duration;
+
+use atomic_config::events::{self, Event};
+use atomic_repository::Repository;
+use libatomic::pristine::{Base32, TxnT};
+use log::{debug, info, warn};
+
+use crate::Node;
+
+/// Default interval between two revalidations
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(600);
+
+/// What to revalidate, and how often
+#[derive(Debug, Clone)]
+pub struct RevalidationConfig {
+    /// Names or addresses of the remotes, as given to `atomic pull`
+    pub remotes: Vec<String>,
+    /// Channel compared, locally and on the remotes
+    pub channel: String,
+    /// Interval between two revalidations
+    pub interval: Duration,
+    pub no_cert_check: bool,
+}
+
+impl RevalidationConfig {
+    pub fn new(remotes: Vec<String>, channel: impl Into<String>) -> Self {
+        RevalidationConfig {
+            remotes,
+            channel: channel.into(),
+            interval: DEFAULT_INTERVAL,
+            no_cert_check: false,
+        }
+    }
+}
+
+/// Nodes of the cache of a remote that the remote doesn't have anymore
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub remote: String,
+    pub channel: String,
+    /// Positions in the cache, and nodes
+    pub unrecorded: Vec<(u64, Node)>,
+}
+
+impl Divergence {
+    fn event(&self, repository: PathBuf) -> Event {
+        Event::RemoteDiverged {
+            repository,
+            remote: self.remote.clone(),
+            channel: self.channel.clone(),
+            unrecorded: self
+                .unrecorded
+                .iter()
+                .map(|(_, node)| {
+                    if node.is_tag() {
+                        node.state.to_base32()
+                    } else {
+                        node.hash.to_base32()
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Compare the cache of `remote` with its changelist, without changing
+/// the repository. Returns `None` if they agree, or if `remote` has no
+/// cache yet.
+pub async fn revalidate(
+    repo: &Repository,
+    remote: &str,
+    channel: &str,
+    no_cert_check: bool,
+) -> Result<Option<Divergence>, anyhow::Error> {
+    let mut remote_repo =
+        crate::repository(repo, None, None, remote, channel, no_cert_check, false).await?;
+    if let crate::RemoteRepo::LocalChannel(_) = remote_repo {
+        return Ok(None);
+    }
+    let mut txn = repo.pristine.mut_txn_begin()?;
+    let current_channel = if let Some(c) = txn.load_channel(channel)? {
+        c
+    } else {
+        anyhow::bail!("No channel {} in the repository", channel)
+    };
+    // `CacheUpdate::Safe`: the cache is left as it is, and the transaction
+    // is dropped anyway.
+    let delta = remote_repo
+        .update_changelist_pushpull(
+            &mut txn,
+            &[],
+            &current_channel,
+            crate::CacheUpdate::Safe,
+            repo,
+            &[],
+            true,
+        )
+        .await?;
+    remote_repo.finish().await?;
+    if delta.remote_unrecs.is_empty() {
+        debug!("cache of {} is up to date", remote);
+        Ok(None)
+    } else {
+        Ok(Some(Divergence {
+            remote: remote.to_string(),
+            channel: channel.to_string(),
+            unrecorded: delta.remote_unrecs,
+        }))
+    }
+}
+
+/// Revalidate the configured remotes of the repository at `path` once,
+/// publishing an event for each remote that diverged
+pub async fn revalidate_all(
+    path: &std::path::Path,
+    config: &RevalidationConfig,
+) -> Result<Vec<Divergence>, anyhow::Error> {
+    let repo = Repository::find_root(Some(path.to_path_buf()))?;
+    let mut diverged = Vec::new();
+    for remote in config.remotes.iter() {
+        match revalidate(&repo, remote, &config.channel, config.no_cert_check).await {
+            Ok(Some(d)) => {
+                warn!(
+                    "{} no longer has {} node(s) of its cache of {}",
+                    remote,
+                    d.unrecorded.len(),
+                    config.channel
+                );
+                events::publish(d.event(repo.path.clone()));
+                diverged.push(d)
+            }
+            Ok(None) => {}
+            Err(e) => info!("Failed to revalidate the cache of {}: {}", remote, e),
+        }
+    }
+    Ok(diverged)
+}
+
+/// A background revalidation, stopped when dropped
+pub struct Revalidation {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Revalidation {
+    /// Stop the revalidations, and wait for the current one to finish
+    pub fn stop(mut self) {
+        self.stop_and_join()
+    }
+
+    fn stop_and_join(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(())
+        }
+    }
+}
+
+impl Drop for Revalidation {
+    fn drop(&mut self) {
+        self.stop_and_join()
+    }
+}
+
+/// Revalidate the caches of the repository at `path` every
+/// `config.interval`, on a thread of its own, starting now
+pub fn spawn(path: PathBuf, config: RevalidationConfig) -> Result<Revalidation, anyhow::Error> {
+    let (stop, stopped) = mpsc::channel::<()>();
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let thread = std::thread::Builder::new()
+        .name("atomic-revalidate".to_string())
+        .spawn(move || loop {
+            if let Err(e) = runtime.block_on(revalidate_all(&path, &config)) {
+                info!("Failed to revalidate {:?}: {}", path, e);
+            }
+            match stopped.recv_timeout(config.interval) {
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                _ => break,
+            }
+        })?;
+    Ok(Revalidation {
+        stop: Some(stop),
+        thread: Some(thread),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_repository::fixtures::hash;
+    use libatomic::pristine::Merkle;
+
+    #[test]
+    fn test_divergence_event() {
+        let hash = hash(0);
+        let divergence = Divergence {
+            remote: "origin".to_string(),
+            channel: "main".to_string(),
+            unrecorded: vec![(3, Node::change(hash, Merkle::zero()))],
+        };
+        assert_eq!(
+            divergence.event(PathBuf::from("/repo")),
+            Event::RemoteDiverged {
+                repository: PathBuf::from("/repo"),
+                remote: "origin".to_string(),
+                channel: "main".to_string(),
+                unrecorded: vec![hash.to_base32()],
+            }
+        );
+    }
+}
@@ -0,0 +1,110 @@
+//! Sparse clones, restricted to some paths of the remote channel
+//!
+//! `atomic clone --path <prefix>` only downloads and applies the changes
+//! touching the files under its prefixes, along with their dependencies,
+//! and only outputs these files. The prefixes are recorded in the
+//! `sparse` list of the repository configuration, and later pulls without
+//! `--path` are restricted to them, so that the repository stays sparse.
+//!
+//! Remotes filter their changelists by path: local and SSH remotes, and
+//! HTTP servers answering `path` parameters of changelist requests.
+
+/// The prefixes of `paths`, relative to the root of the repository,
+/// without `.` components, duplicates and prefixes contained in other
+/// prefixes. Empty if one of the prefixes is the root, since the clone
+/// isn't sparse then.
+pub fn normalize(paths: &[String]) -> Vec<String> {
+    let mut prefixes: Vec<String> = paths
+        .iter()
+        .map(|p| {
+            p.split(|c| c == '/' || c == '\\')
+                .filter(|c| !c.is_empty() && *c != ".")
+                .collect::<Vec<_>>()
+                .join("/")
+        })
+        .collect();
+    if prefixes.iter().any(|p| p.is_empty()) {
+        return Vec::new();
+    }
+    // Prefixes sort before the paths they contain.
+    prefixes.sort();
+    let mut result: Vec<String> = Vec::with_capacity(prefixes.len());
+    for p in prefixes {
+        if !result.iter().any(|r| contains(r, &p)) {
+            result.push(p)
+        }
+    }
+    result
+}
+
+/// Whether `path` is `prefix` or a path under it. The empty prefix, the
+/// root, contains every path.
+pub fn contains(prefix: &str, path: &str) -> bool {
+    prefix.is_empty()
+        || (path.starts_with(prefix)
+            && (path.len() == prefix.len() || path.as_bytes()[prefix.len()] == b'/'))
+}
+
+/// The paths to output after pulling changes touching `touched` into a
+/// sparse clone of `prefixes`: the touched paths under the prefixes, and
+/// the prefixes under the other touched paths.
+pub fn restrict<'a, I: IntoIterator<Item = &'a str>>(
+    prefixes: &[String],
+    touched: I,
+) -> std::collections::BTreeSet<String> {
+    let mut result = std::collections::BTreeSet::new();
+    for path in touched {
+        if prefixes.iter().any(|p| contains(p, path)) {
+            result.insert(path.to_string());
+        } else {
+            result.extend(prefixes.iter().filter(|p| contains(path, p)).cloned())
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(paths: &[&str]) -> Vec<String> {
+        normalize(&paths.iter().map(|p| p.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn normalize_prefixes() {
+        assert_eq!(
+            normalized(&["./src/", "docs//api"]),
+            vec!["docs/api", "src"]
+        );
+        assert_eq!(normalized(&["src/lib", "src", "src"]), vec!["src"]);
+        // `src-old` isn't under `src`.
+        assert_eq!(
+            normalized(&["src/lib", "src-old", "src"]),
+            vec!["src", "src-old"]
+        );
+        assert!(contains("src", "src/lib.rs"));
+        assert!(!contains("src", "src-old"));
+        assert!(contains("", "src"));
+        assert!(normalized(&["src", "."]).is_empty());
+        assert!(normalized(&[]).is_empty());
+    }
+
+    #[test]
+    fn restrict_touched() {
+        let prefixes = normalized(&["src/lib", "docs"]);
+        let restricted = restrict(&prefixes, ["src/lib/a.rs", "src/main.rs", "docs"]);
+        assert_eq!(
+            restricted.into_iter().collect::<Vec<_>>(),
+            vec!["docs", "src/lib/a.rs"]
+        );
+        // Outputting the whole repository outputs the prefixes.
+        let restricted = restrict(&prefixes, [""]);
+        assert_eq!(
+            restricted.into_iter().collect::<Vec<_>>(),
+            vec!["docs", "src/lib"]
+        );
+        let restricted = restrict(&prefixes, ["src"]);
+        assert_eq!(restricted.into_iter().collect::<Vec<_>>(), vec!["src/lib"]);
+    }
+}
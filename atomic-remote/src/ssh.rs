@@ -3,6 +3,7 @@ use std::convert::TryInto;
 use std::io::Write;
 use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
@@ -16,7 +17,10 @@ use regex::Regex;
 use thrussh::client::Session;
 use tokio::sync::Mutex;
 
-use super::parse_line;
+use crate::attribution::{self, AttributionProtocol, ChangeAttribution};
+use crate::bundle;
+use crate::failure::SyncError;
+use crate::protocol::{Command, ListLine, StateLine};
 use crate::Node;
 use atomic_interaction::ProgressBar;
 use libatomic::pristine::NodeType;
@@ -31,6 +35,27 @@ pub struct Ssh {
     pub name: String,
     state: Arc<Mutex<State>>,
     has_errors: Arc<Mutex<bool>>,
+    /// How to open other connections to the remote, see [`Ssh::reconnect`]
+    address: Address,
+    /// Index of this connection among those downloading changes together
+    stream: usize,
+    /// Whether the remote announced bundles of changes (see
+    /// [`crate::bundle`]) in its last answer to `state`
+    bundles: Arc<AtomicBool>,
+    /// Version of the attribution protocol announced by the remote in its
+    /// last answer to `state` (see [`crate::attribution`]), 0 if none
+    attribution: Arc<AtomicU32>,
+    /// Whether the remote announced notes (see [`crate::NOTES_CAPABILITY`])
+    /// in its last answer to `state`
+    notes: Arc<AtomicBool>,
+}
+
+/// The address of an SSH remote, as given to [`ssh_remote`]
+#[derive(Debug, Clone)]
+struct Address {
+    user: Option<String>,
+    addr: String,
+    with_path: bool,
 }
 
 lazy_static! {
@@ -49,6 +74,7 @@ lazy_static! {
 pub struct Remote<'a> {
     path: &'a str,
     config: thrussh_config::Config,
+    address: Address,
 }
 
 pub fn ssh_remote<'a>(user: Option<&str>, addr: &'a str, with_path: bool) -> Option<Remote<'a>> {
@@ -91,7 +117,15 @@ pub fn ssh_remote<'a>(user: Option<&str>, addr: &'a str, with_path: bool) -> Opt
     } else {
         ""
     };
-    Some(Remote { path, config })
+    Some(Remote {
+        path,
+        config,
+        address: Address {
+            user: user.map(|u| u.to_string()),
+            addr: addr.to_string(),
+            with_path,
+        },
+    })
 }
 
 impl<'a> Remote<'a> {
@@ -105,6 +139,9 @@ impl<'a> Remote<'a> {
         home.push("known_hosts");
         let state = Arc::new(Mutex::new(State::None));
         let has_errors = Arc::new(Mutex::new(false));
+        let bundles = Arc::new(AtomicBool::new(false));
+        let attribution = Arc::new(AtomicU32::new(0));
+        let notes = Arc::new(AtomicBool::new(false));
         let client = SshClient {
             addr: self.config.host_name.clone(),
             port: self.config.port,
@@ -112,12 +149,18 @@ impl<'a> Remote<'a> {
             last_window_adjustment: SystemTime::now(),
             state: state.clone(),
             has_errors: has_errors.clone(),
+            bundles: bundles.clone(),
+            attribution: attribution.clone(),
+            notes: notes.clone(),
         };
         let stream = match self.config.stream().await {
             Ok(stream) => stream,
             Err(e) => {
                 info!("remote connect error: {:?}", e);
-                return Ok(None);
+                bail!(SyncError::network(format!(
+                    "Failed to connect to {}: {}",
+                    self.config.host_name, e
+                )))
             }
         };
         let config = Arc::new(thrussh::client::Config::default());
@@ -142,7 +185,9 @@ impl<'a> Remote<'a> {
         };
 
         if !authenticated {
-            bail!("Not authenticated. Please check your credentials and try again.");
+            bail!(SyncError::auth(
+                "Not authenticated. Please check your credentials and try again."
+            ));
         }
 
         let c = h.channel_open_session().await?;
@@ -161,6 +206,11 @@ impl<'a> Remote<'a> {
             name: name.to_string(),
             state,
             has_errors,
+            address: self.address.clone(),
+            stream: 0,
+            bundles,
+            attribution,
+            notes,
         }))
     }
 
@@ -326,6 +376,9 @@ pub struct SshClient {
     last_window_adjustment: SystemTime,
     state: Arc<Mutex<State>>,
     has_errors: Arc<Mutex<bool>>,
+    bundles: Arc<AtomicBool>,
+    attribution: Arc<AtomicU32>,
+    notes: Arc<AtomicBool>,
 }
 
 enum State {
@@ -344,9 +397,12 @@ enum State {
         final_path: PathBuf,
         hashes: Vec<Node>,
         current: usize,
+        /// Number of nodes in each response, more than one for bundles
+        batches: Vec<usize>,
+        batch: usize,
     },
     Changelist {
-        sender: tokio::sync::mpsc::Sender<Option<super::ListLine>>,
+        sender: tokio::sync::mpsc::Sender<Option<ListLine>>,
         pending: Vec<u8>,
     },
     Archive {
@@ -365,6 +421,18 @@ enum State {
         sender: Option<tokio::sync::mpsc::Sender<atomic_identity::Complete>>,
         buf: Vec<u8>,
     },
+    Notes {
+        sender: Option<tokio::sync::mpsc::Sender<atomic_repository::notes::Note>>,
+        buf: Vec<u8>,
+    },
+    Links {
+        sender: Option<tokio::sync::mpsc::Sender<atomic_repository::links::Link>>,
+        buf: Vec<u8>,
+    },
+    Attribution {
+        sender: Option<tokio::sync::mpsc::Sender<ChangeAttribution>>,
+        buf: Vec<u8>,
+    },
 }
 
 type BoxFuture<T> = Pin<Box<dyn futures::future::Future<Output = T> + Send>>;
@@ -487,19 +555,15 @@ impl thrussh::client::Handler for SshClient {
                         // If we can't parse `data` (for example if the
                         // remote returns the standard "-\n"), this
                         // returns None.
-                        let mut s = std::str::from_utf8(&data).unwrap().split(' ');
-                        if let (Some(n), Some(m), Some(m2)) = (s.next(), s.next(), s.next()) {
-                            let n = n.parse().unwrap();
-                            sender
-                                .send(Some((
-                                    n,
-                                    Merkle::from_base32(m.trim().as_bytes()).unwrap(),
-                                    Merkle::from_base32(m2.trim().as_bytes()).unwrap(),
-                                )))
-                                .unwrap_or(());
-                        } else {
-                            sender.send(None).unwrap_or(());
-                        }
+                        let data = String::from_utf8_lossy(&data);
+                        self.bundles
+                            .store(bundle::announced(&data), Ordering::Relaxed);
+                        let version = attribution::announced(&data).map_or(0, |p| p.version);
+                        self.attribution.store(version, Ordering::Relaxed);
+                        self.notes
+                            .store(crate::notes_announced(&data), Ordering::Relaxed);
+                        let line = StateLine::decode(&data);
+                        sender.send(line.0).unwrap_or(());
                     }
                 }
                 State::Id { ref mut sender } => {
@@ -525,6 +589,8 @@ impl thrussh::client::Handler for SshClient {
                     ref mut final_path,
                     ref hashes,
                     ref mut current,
+                    ref batches,
+                    ref mut batch,
                 } => {
                     trace!("state changes");
                     let mut p = 0;
@@ -544,38 +610,83 @@ impl thrussh::client::Handler for SshClient {
                             *remaining_len = 0;
                             file.flush()?;
 
-                            match hashes[*current].node_type {
-                                NodeType::Change => {
-                                    libatomic::changestore::filesystem::push_filename(
-                                        final_path,
-                                        &hashes[*current].hash,
-                                    );
-                                    debug!("moving {:?} to {:?}", path, final_path);
-                                    std::fs::create_dir_all(&final_path.parent().unwrap())?;
-                                    let r = std::fs::rename(&path, &final_path);
-                                    libatomic::changestore::filesystem::pop_filename(final_path);
-                                    r?;
+                            let n = batches[*batch];
+                            *batch += 1;
+                            if n == 1 {
+                                match hashes[*current].node_type {
+                                    NodeType::Change => {
+                                        libatomic::changestore::filesystem::push_filename(
+                                            final_path,
+                                            &hashes[*current].hash,
+                                        );
+                                        debug!("moving {:?} to {:?}", path, final_path);
+                                        std::fs::create_dir_all(&final_path.parent().unwrap())?;
+                                        let r = std::fs::rename(&path, &final_path);
+                                        libatomic::changestore::filesystem::pop_filename(
+                                            final_path,
+                                        );
+                                        r?;
+                                    }
+                                    NodeType::Tag => {
+                                        libatomic::changestore::filesystem::push_tag_filename(
+                                            final_path,
+                                            &hashes[*current].state,
+                                        );
+                                        debug!("moving {:?} to {:?}", path, final_path);
+                                        std::fs::create_dir_all(&final_path.parent().unwrap())?;
+                                        let r = std::fs::rename(&path, &final_path);
+                                        libatomic::changestore::filesystem::pop_filename(
+                                            final_path,
+                                        );
+                                        r?;
+                                    }
                                 }
-                                NodeType::Tag => {
-                                    libatomic::changestore::filesystem::push_tag_filename(
-                                        final_path,
-                                        &hashes[*current].state,
+                            } else {
+                                let changes = bundle::decode(&std::fs::read(&path)?)?;
+                                if changes.len() != n {
+                                    bail!(SyncError::protocol(format!(
+                                        "Received {} changes instead of {}",
+                                        changes.len(),
+                                        n
+                                    )))
+                                }
+                                for (node, (h, change)) in
+                                    hashes[*current..*current + n].iter().zip(changes)
+                                {
+                                    if h != node.hash {
+                                        bail!(SyncError::protocol(format!(
+                                            "Received change {} instead of {}",
+                                            h.to_base32(),
+                                            node.hash.to_base32()
+                                        )))
+                                    }
+                                    libatomic::changestore::filesystem::push_filename(
+                                        final_path, &h,
                                     );
-                                    debug!("moving {:?} to {:?}", path, final_path);
+                                    debug!("writing {:?}", final_path);
                                     std::fs::create_dir_all(&final_path.parent().unwrap())?;
-                                    let r = std::fs::rename(&path, &final_path);
+                                    let tmp = final_path.with_extension("tmp");
+                                    let r = std::fs::write(&tmp, &change)
+                                        .and_then(|_| std::fs::rename(&tmp, &final_path));
                                     libatomic::changestore::filesystem::pop_filename(final_path);
                                     r?;
                                 }
                             }
-                            debug!("sending {:?}", hashes[*current]);
-                            if let Some(ref mut sender) = sender {
-                                if sender.send(hashes[*current]).await.is_err() {
-                                    break;
+                            let mut closed = false;
+                            for node in hashes[*current..*current + n].iter() {
+                                debug!("sending {:?}", node);
+                                if let Some(ref mut sender) = sender {
+                                    if sender.send(*node).await.is_err() {
+                                        closed = true;
+                                        break;
+                                    }
                                 }
                             }
+                            if closed {
+                                break;
+                            }
                             debug!("sent");
-                            *current += 1;
+                            *current += n;
                             if *current < hashes.len() {
                                 // If we're still waiting for another
                                 // change.
@@ -625,7 +736,7 @@ impl thrussh::client::Handler for SshClient {
                             let l = std::str::from_utf8(line)?;
                             if !l.is_empty() {
                                 debug!("line = {:?}", l);
-                                sender.send(parse_line(l).ok()).await.unwrap_or(())
+                                sender.send(ListLine::decode(l).ok()).await.unwrap_or(())
                             } else {
                                 sender.send(None).await.unwrap_or(());
                             }
@@ -677,7 +788,13 @@ impl thrussh::client::Handler for SshClient {
                             let s = key.sign_raw(data.as_bytes())?;
                             session.data(
                                 channel,
-                                thrussh::CryptoVec::from_slice(format!("prove {}\n", s).as_bytes()),
+                                thrussh::CryptoVec::from_slice(
+                                    Command::Prove {
+                                        signature: s.to_string(),
+                                    }
+                                    .encode()
+                                    .as_bytes(),
+                                ),
                             );
                             if let Some(sender) = sender.take() {
                                 sender.send(()).unwrap_or(());
@@ -715,6 +832,65 @@ impl thrussh::client::Handler for SshClient {
                         buf.extend(&data);
                     }
                 }
+                State::Notes {
+                    ref mut sender,
+                    ref mut buf,
+                } => {
+                    buf.extend(&data);
+                    // Notes are sent as JSON lines, terminated by an empty line.
+                    while let Some(i) = buf.iter().position(|c| *c == 10) {
+                        let line: Vec<u8> = buf.drain(..=i).collect();
+                        if let Ok(note) = serde_json::from_slice(&line[..i]) {
+                            if let Some(ref mut sender) = sender {
+                                sender.send(note).await?;
+                            }
+                        } else {
+                            debug!("end of notes {:?}", std::str::from_utf8(&line));
+                            *sender = None;
+                            buf.clear();
+                            break;
+                        }
+                    }
+                }
+                State::Links {
+                    ref mut sender,
+                    ref mut buf,
+                } => {
+                    buf.extend(&data);
+                    while let Some(i) = buf.iter().position(|c| *c == 10) {
+                        let line: Vec<u8> = buf.drain(..=i).collect();
+                        if let Ok(link) = serde_json::from_slice(&line[..i]) {
+                            if let Some(ref mut sender) = sender {
+                                sender.send(link).await?;
+                            }
+                        } else {
+                            debug!("end of links {:?}", std::str::from_utf8(&line));
+                            *sender = None;
+                            buf.clear();
+                            break;
+                        }
+                    }
+                }
+                State::Attribution {
+                    ref mut sender,
+                    ref mut buf,
+                } => {
+                    buf.extend(&data);
+                    // Like notes, one JSON attribution per line.
+                    while let Some(i) = buf.iter().position(|c| *c == 10) {
+                        let line: Vec<u8> = buf.drain(..=i).collect();
+                        if let Ok(attribution) = serde_json::from_slice(&line[..i]) {
+                            if let Some(ref mut sender) = sender {
+                                sender.send(attribution).await?;
+                            }
+                        } else {
+                            debug!("end of attribution {:?}", std::str::from_utf8(&line));
+                            *sender = None;
+                            buf.clear();
+                            break;
+                        }
+                    }
+                }
                 State::None => {
                     debug!("None state");
                 }
@@ -761,7 +937,10 @@ impl Ssh {
                 thrussh::ChannelMsg::Eof => {}
                 thrussh::ChannelMsg::ExitStatus { exit_status } => {
                     if exit_status != 0 {
-                        bail!("Remote exited with status {:?}", exit_status)
+                        bail!(SyncError::rejected(format!(
+                            "Remote exited with status {:?}",
+                            exit_status
+                        )))
                     }
                 }
                 msg => error!("wrong message {:?}", msg),
@@ -780,15 +959,11 @@ impl Ssh {
             sender: Some(sender),
         };
         self.run_protocol().await?;
-        if let Some(mid) = mid {
-            self.c
-                .data(format!("state {} {}\n", self.channel, mid).as_bytes())
-                .await?;
-        } else {
-            self.c
-                .data(format!("state {}\n", self.channel).as_bytes())
-                .await?;
-        }
+        self.send(Command::State {
+            channel: self.channel.clone(),
+            position: mid,
+        })
+        .await?;
         Ok(receiver.await?)
     }
 
@@ -798,9 +973,10 @@ impl Ssh {
             sender: Some(sender),
         };
         self.run_protocol().await?;
-        self.c
-            .data(format!("id {}\n", self.channel).as_bytes())
-            .await?;
+        self.send(Command::Id {
+            channel: self.channel.clone(),
+        })
+        .await?;
         Ok(receiver.await?)
     }
 
@@ -814,7 +990,7 @@ impl Ssh {
             signed: false,
         };
         self.run_protocol().await?;
-        self.c.data(format!("challenge {}\n", k).as_bytes()).await?;
+        self.send(Command::Challenge { key: k }).await?;
         Ok(receiver.await?)
     }
 
@@ -834,34 +1010,22 @@ impl Ssh {
             w: Box::new(w),
         };
         self.run_protocol().await?;
-        if let Some((ref state, ref extra)) = state {
-            let mut cmd = format!("archive {} {}", self.channel, state.to_base32(),);
-            for e in extra.iter() {
-                cmd.push_str(&format!(" {}", e.to_base32()));
-            }
-            if let Some(ref p) = prefix {
-                cmd.push_str(" :");
-                cmd.push_str(p)
-            }
-            cmd.push('\n');
-            self.c.data(cmd.as_bytes()).await?;
-        } else {
-            self.c
-                .data(
-                    format!(
-                        "archive {}{}{}\n",
-                        self.channel,
-                        if prefix.is_some() { " :" } else { "" },
-                        prefix.unwrap_or_else(String::new)
-                    )
-                    .as_bytes(),
-                )
-                .await?;
-        }
+        self.send(Command::Archive {
+            channel: self.channel.clone(),
+            state: state.map(|(state, extra)| (state, extra.to_vec())),
+            prefix,
+        })
+        .await?;
         let conflicts = receiver.await.unwrap_or(0);
         Ok(conflicts)
     }
 
+    async fn send(&mut self, command: Command) -> Result<(), anyhow::Error> {
+        debug!("command {:?}", command);
+        self.c.data(command.encode().as_bytes()).await?;
+        Ok(())
+    }
+
     pub async fn run_protocol(&mut self) -> Result<(), anyhow::Error> {
         if !self.is_running {
             self.is_running = true;
@@ -886,7 +1050,10 @@ impl Ssh {
                     thrussh::ChannelMsg::Eof => {}
                     thrussh::ChannelMsg::ExitStatus { exit_status } => {
                         if exit_status != 0 {
-                            bail!("Remote exited with status {:?}", exit_status)
+                            bail!(SyncError::rejected(format!(
+                                "Remote exited with status {:?}",
+                                exit_status
+                            )))
                         }
                     }
                     _ => {}
@@ -914,28 +1081,27 @@ impl Ssh {
         };
         self.run_protocol().await?;
         debug!("download_changelist");
-        let mut command = Vec::new();
-        write!(command, "changelist {} {}", self.channel, from).unwrap();
-        for p in paths {
-            write!(command, " {:?}", p).unwrap()
-        }
-        command.push(b'\n');
-        self.c.data(&command[..]).await?;
-        debug!("waiting ssh, command: {:?}", std::str::from_utf8(&command));
+        self.send(Command::Changelist {
+            channel: self.channel.clone(),
+            from,
+            paths: paths.to_vec(),
+        })
+        .await?;
+        debug!("waiting ssh");
         let mut result = HashSet::new();
         while let Some(Some(m)) = receiver.recv().await {
             match m {
-                super::ListLine::Change { n, h, m, tag } => f(a, n, h, m, tag)?,
-                super::ListLine::Position(pos) => {
+                ListLine::Change { n, h, m, tag } => f(a, n, h, m, tag)?,
+                ListLine::Position(pos) => {
                     result.insert(pos);
                 }
-                super::ListLine::Error(err) => {
-                    bail!(err)
+                ListLine::Error(err) => {
+                    bail!(SyncError::rejected(err))
                 }
             }
         }
         if *self.has_errors.lock().await {
-            bail!("Remote sent an error")
+            bail!(SyncError::rejected("Remote sent an error"))
         }
         debug!("no msg, result = {:?}", result);
         Ok(result)
@@ -950,13 +1116,49 @@ impl Ssh {
     ) -> Result<(), anyhow::Error> {
         self.run_protocol().await?;
         debug!("upload_nodes");
-        for node in nodes {
+        let bundles = self.bundles.load(Ordering::Relaxed);
+        let mut i = 0;
+        while i < nodes.len() {
+            let node = &nodes[i];
             debug!("{:?}", node);
             let to_channel = if let Some(t) = to_channel {
                 t
             } else {
                 self.channel.as_str()
             };
+            // Runs of changes go in bundles, if the remote takes them.
+            let run = if bundles {
+                nodes[i..]
+                    .iter()
+                    .take(bundle::MAX_CHANGES)
+                    .take_while(|n| !n.is_tag())
+                    .count()
+            } else {
+                0
+            };
+            if run > 1 {
+                let run = &nodes[i..i + run];
+                let mut changes = Vec::with_capacity(run.len());
+                for node in run {
+                    libatomic::changestore::filesystem::push_filename(&mut local, &node.hash);
+                    changes.push(std::fs::read(&local)?);
+                    libatomic::changestore::filesystem::pop_filename(&mut local);
+                }
+                let body = bundle::encode(
+                    run.iter()
+                        .map(|n| &n.hash)
+                        .zip(changes.iter().map(|c| &c[..])),
+                )?;
+                self.send(Command::Applybundle {
+                    channel: to_channel.to_string(),
+                    size: body.len(),
+                })
+                .await?;
+                self.c.data(&body[..]).await?;
+                progress_bar.inc(run.len() as u64);
+                i += run.len();
+                continue;
+            }
             match node.node_type {
                 NodeType::Change => {
                     libatomic::changestore::filesystem::push_filename(&mut local, &node.hash);
@@ -965,17 +1167,12 @@ impl Ssh {
                     let mut change = thrussh::CryptoVec::new_zeroed(change_len as usize);
                     use std::io::Read;
                     change_file.read_exact(&mut change[..])?;
-                    self.c
-                        .data(
-                            format!(
-                                "apply {} {} {}\n",
-                                to_channel,
-                                node.hash.to_base32(),
-                                change_len
-                            )
-                            .as_bytes(),
-                        )
-                        .await?;
+                    self.send(Command::Apply {
+                        channel: to_channel.to_string(),
+                        hash: node.hash,
+                        size: change_len as usize,
+                    })
+                    .await?;
                     self.c.data(&change[..]).await?;
                     libatomic::changestore::filesystem::pop_filename(&mut local);
                 }
@@ -989,20 +1186,12 @@ impl Ssh {
                     let mut short_data = Vec::new();
                     tag_file.short(&mut short_data)?;
 
-                    let channel_name = to_channel;
-
-                    // Send tagup command: "tagup STATE CHANNEL SIZE\n"
-                    self.c
-                        .data(
-                            format!(
-                                "tagup {} {} {}\n",
-                                node.state.to_base32(),
-                                channel_name,
-                                short_data.len()
-                            )
-                            .as_bytes(),
-                        )
-                        .await?;
+                    self.send(Command::Tagup {
+                        state: node.state,
+                        channel: to_channel.to_string(),
+                        size: short_data.len(),
+                    })
+                    .await?;
 
                     // Send short tag data
                     self.c.data(&short_data[..]).await?;
@@ -1011,6 +1200,7 @@ impl Ssh {
                 }
             }
             progress_bar.inc(1);
+            i += 1;
         }
         Ok(())
     }
@@ -1027,6 +1217,93 @@ impl Ssh {
             .await
     }
 
+    /// Open another connection to the same remote and channel
+    pub async fn reconnect(&self) -> Result<Option<Ssh>, anyhow::Error> {
+        let address = &self.address;
+        let Some(mut remote) =
+            ssh_remote(address.user.as_deref(), &address.addr, address.with_path)
+        else {
+            return Ok(None);
+        };
+        remote.connect(&self.name, &self.channel).await
+    }
+
+    /// Download `nodes` over up to `streams` connections: this one, and
+    /// new ones opened with [`Ssh::reconnect`]. Nodes are handed to the
+    /// connections in turn, and each connection sends the nodes it
+    /// downloaded to `send` as soon as they are written, so nodes may
+    /// arrive out of order (see [`crate::RemoteRepo::pull`] for how
+    /// dependencies are still applied first).
+    pub async fn download_nodes_parallel(
+        &mut self,
+        streams: usize,
+        progress_bar: ProgressBar,
+        nodes: &mut tokio::sync::mpsc::UnboundedReceiver<Node>,
+        send: &mut tokio::sync::mpsc::Sender<(Node, bool)>,
+        path: &mut PathBuf,
+        full: bool,
+    ) -> Result<(), anyhow::Error> {
+        let mut others = Vec::new();
+        while others.len() + 1 < streams {
+            match self.reconnect().await {
+                Ok(Some(mut other)) => {
+                    other.stream = others.len() + 1;
+                    // Only the first connection asked for the state.
+                    other
+                        .bundles
+                        .store(self.bundles.load(Ordering::Relaxed), Ordering::Relaxed);
+                    others.push(other)
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Failed to open another connection to {}: {}", self.name, e);
+                    break;
+                }
+            }
+        }
+        if others.is_empty() {
+            return self
+                .download_nodes(progress_bar, nodes, send, path, full)
+                .await;
+        }
+        debug!("downloading over {} connections", others.len() + 1);
+
+        let (queues, mut receivers): (Vec<_>, Vec<_>) = (0..=others.len())
+            .map(|_| tokio::sync::mpsc::unbounded_channel())
+            .unzip();
+        let dispatch = async move {
+            let mut next = 0;
+            while let Some(node) = nodes.recv().await {
+                queues[next].send(node)?;
+                next = (next + 1) % queues.len();
+            }
+            // Dropping the queues ends the downloads.
+            Ok::<_, anyhow::Error>(())
+        };
+        let downloads = futures::future::try_join_all(
+            std::iter::once(&mut *self)
+                .chain(others.iter_mut())
+                .zip(receivers.iter_mut())
+                .map(|(connection, queue)| {
+                    let progress_bar = progress_bar.clone();
+                    let mut send = send.clone();
+                    let mut path = path.clone();
+                    async move {
+                        connection
+                            .download_nodes(progress_bar, queue, &mut send, &mut path, full)
+                            .await
+                    }
+                }),
+        );
+        let (dispatch, downloads) = tokio::join!(dispatch, downloads);
+        dispatch?;
+        downloads?;
+        for mut other in others {
+            other.finish().await?
+        }
+        Ok(())
+    }
+
     async fn download_nodes_(
         &mut self,
         progress_bar: ProgressBar,
@@ -1036,7 +1313,12 @@ impl Ssh {
         full: bool,
     ) -> Result<(), anyhow::Error> {
         let (sender_, mut recv) = tokio::sync::mpsc::channel(100);
-        let tmp_path = path.join("tmp");
+        // Connections downloading together write to different files.
+        let tmp_path = if self.stream == 0 {
+            path.join("tmp")
+        } else {
+            path.join(format!("tmp.{}", self.stream))
+        };
         std::fs::create_dir_all(&path)?;
         let file = std::fs::File::create(&tmp_path)?;
         *self.state.lock().await = State::Changes {
@@ -1047,6 +1329,8 @@ impl Ssh {
             file,
             hashes: Vec::new(),
             current: 0,
+            batches: Vec::new(),
+            batch: 0,
         };
         self.run_protocol().await?;
         let mut sender = sender.map(|x| x.clone());
@@ -1060,30 +1344,37 @@ impl Ssh {
                 }
             }
         });
+        let bundles = self.bundles.load(Ordering::Relaxed);
+        let mut pending = None;
         let mut received = false;
-        while let Some(node) = nodes.recv().await {
+        while let Some(next) = bundle::next_nodes(nodes, &mut pending, bundles).await {
             received = true;
-            if let State::Changes { ref mut hashes, .. } = *self.state.lock().await {
-                hashes.push(node);
+            if let State::Changes {
+                ref mut hashes,
+                ref mut batches,
+                ..
+            } = *self.state.lock().await
+            {
+                hashes.extend(next.iter().cloned());
+                batches.push(next.len());
             }
-            debug!("download_node {:?} {:?}", node, full);
-            match node.node_type {
-                NodeType::Change if full => {
-                    self.c
-                        .data(format!("change {}\n", node.hash.to_base32()).as_bytes())
-                        .await?;
-                }
-                NodeType::Change => {
-                    self.c
-                        .data(format!("partial {}\n", node.hash.to_base32()).as_bytes())
-                        .await?;
-                }
-                NodeType::Tag => {
-                    self.c
-                        .data(format!("tag {}\n", node.state.to_base32()).as_bytes())
-                        .await?;
+            debug!("download_nodes {:?} {:?}", next, full);
+            let command = match next[0].node_type {
+                _ if next.len() > 1 => {
+                    let hashes = next.iter().map(|n| n.hash).collect();
+                    if full {
+                        Command::Bundle { hashes }
+                    } else {
+                        Command::PartialBundle { hashes }
+                    }
                 }
-            }
+                NodeType::Change if full => Command::Change { hash: next[0].hash },
+                NodeType::Change => Command::Partial { hash: next[0].hash },
+                NodeType::Tag => Command::Tag {
+                    state: next[0].state,
+                },
+            };
+            self.send(command).await?;
         }
         if !received {
             *self.state.lock().await = State::None;
@@ -1104,13 +1395,7 @@ impl Ssh {
             buf: Vec::new(),
         };
         self.run_protocol().await?;
-        if let Some(rev) = rev {
-            self.c
-                .data(format!("identities {}\n", rev).as_bytes())
-                .await?;
-        } else {
-            self.c.data("identities\n".as_bytes()).await?;
-        }
+        self.send(Command::Identities { since: rev }).await?;
         let mut revision = 0;
         std::fs::create_dir_all(&path)?;
         while let Some(id) = recv.recv().await {
@@ -1124,4 +1409,116 @@ impl Ssh {
         debug!("done receiving");
         Ok(revision.try_into().unwrap())
     }
+
+    /// Whether the remote announced notes in its last answer to `state`.
+    /// Older servers would take the commands exchanging notes for other
+    /// commands, and never answer them.
+    pub fn exchanges_notes(&self) -> bool {
+        self.notes.load(Ordering::Relaxed)
+    }
+
+    pub async fn download_notes(
+        &mut self,
+    ) -> Result<Vec<atomic_repository::notes::Note>, anyhow::Error> {
+        let (sender_, mut recv) = tokio::sync::mpsc::channel(100);
+        *self.state.lock().await = State::Notes {
+            sender: Some(sender_),
+            buf: Vec::new(),
+        };
+        self.run_protocol().await?;
+        self.send(Command::Notes).await?;
+        let mut notes = Vec::new();
+        while let Some(note) = recv.recv().await {
+            notes.push(note)
+        }
+        debug!("received {} notes", notes.len());
+        Ok(notes)
+    }
+
+    pub async fn upload_notes(
+        &mut self,
+        notes: &[atomic_repository::notes::Note],
+    ) -> Result<(), anyhow::Error> {
+        self.run_protocol().await?;
+        let body = serde_json::to_vec(notes)?;
+        self.send(Command::Notesup { size: body.len() }).await?;
+        self.c.data(&body[..]).await?;
+        Ok(())
+    }
+
+    pub async fn download_links(
+        &mut self,
+    ) -> Result<Vec<atomic_repository::links::Link>, anyhow::Error> {
+        let (sender_, mut recv) = tokio::sync::mpsc::channel(100);
+        *self.state.lock().await = State::Links {
+            sender: Some(sender_),
+            buf: Vec::new(),
+        };
+        self.run_protocol().await?;
+        self.send(Command::Links).await?;
+        let mut links = Vec::new();
+        while let Some(link) = recv.recv().await {
+            links.push(link)
+        }
+        debug!("received {} links", links.len());
+        Ok(links)
+    }
+
+    pub async fn upload_links(
+        &mut self,
+        links: &[atomic_repository::links::Link],
+    ) -> Result<(), anyhow::Error> {
+        self.run_protocol().await?;
+        let body = serde_json::to_vec(links)?;
+        self.send(Command::Linksup { size: body.len() }).await?;
+        self.c.data(&body[..]).await?;
+        Ok(())
+    }
+
+    /// The attribution protocol announced by the remote in its last answer
+    /// to `state`, if any.
+    pub fn attribution_protocol(&self) -> Option<AttributionProtocol> {
+        match self.attribution.load(Ordering::Relaxed) {
+            0 => None,
+            version => Some(AttributionProtocol::new(version)),
+        }
+    }
+
+    /// Download the attribution of the changes `hashes`, asked for in
+    /// runs of up to [`bundle::MAX_CHANGES`] changes.
+    pub async fn download_attribution(
+        &mut self,
+        hashes: &[Hash],
+    ) -> Result<Vec<ChangeAttribution>, anyhow::Error> {
+        self.run_protocol().await?;
+        let mut received = Vec::new();
+        for hashes in hashes.chunks(bundle::MAX_CHANGES) {
+            let (sender_, mut recv) = tokio::sync::mpsc::channel(100);
+            *self.state.lock().await = State::Attribution {
+                sender: Some(sender_),
+                buf: Vec::new(),
+            };
+            self.send(Command::Attribution {
+                hashes: hashes.to_vec(),
+            })
+            .await?;
+            while let Some(attribution) = recv.recv().await {
+                received.push(attribution)
+            }
+        }
+        debug!("received the attribution of {} changes", received.len());
+        Ok(received)
+    }
+
+    pub async fn upload_attribution(
+        &mut self,
+        attribution: &[ChangeAttribution],
+    ) -> Result<(), anyhow::Error> {
+        self.run_protocol().await?;
+        let body = serde_json::to_vec(attribution)?;
+        self.send(Command::Attributionup { size: body.len() })
+            .await?;
+        self.c.data(&body[..]).await?;
+        Ok(())
+    }
 }
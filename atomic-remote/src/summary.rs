@@ -0,0 +1,109 @@
+//! Summaries of changes, to validate them before uploading them.
+//!
+//! A push of a change the remote rejects, e.g. because one of its
+//! dependencies is missing or it touches a forbidden path, only fails
+//! once the whole change was sent. HTTP remotes announcing `"validate":
+//! true` in their discovery answer check a [`ChangeSummary`] (the header,
+//! dependencies and paths of a change), posted as JSON to
+//! `?validate=<hash>&to_channel=<channel>`, against the policies they
+//! check when applying the change, and answer with the error the apply
+//! would return. Clients validate the changes of at least
+//! [`VALIDATE_SIZE`] bytes before uploading them.
+
+use libatomic::change::{Change, ChangeHeader};
+use libatomic::Base32;
+use serde::{Deserialize, Serialize};
+
+/// Size from which changes are validated before being uploaded. Smaller
+/// changes are cheaper to upload than to validate first.
+pub const VALIDATE_SIZE: u64 = 1 << 20;
+
+/// What a remote needs to know about a change to tell whether it would
+/// apply it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChangeSummary {
+    pub header: ChangeHeader,
+    /// Base32 hashes of the dependencies of the change
+    pub dependencies: Vec<String>,
+    /// Paths added or edited by the change
+    #[serde(default)]
+    pub files: Vec<FileStat>,
+    /// Size of the change file, in bytes
+    #[serde(default)]
+    pub size: u64,
+}
+
+/// A path added or edited by a change
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStat {
+    pub path: String,
+    /// Number of hunks of the change on this path
+    pub hunks: usize,
+}
+
+impl ChangeSummary {
+    /// The summary of `change`, whose file is `size` bytes long
+    pub fn new(change: &Change, size: u64) -> Self {
+        let mut files: Vec<FileStat> = Vec::new();
+        for hunk in change.changes.iter() {
+            let path = match libatomic::forbidden::written_path(hunk, |l| &l.path) {
+                Some(path) => path,
+                None => continue,
+            };
+            if let Some(file) = files.iter_mut().find(|f| f.path == path) {
+                file.hunks += 1
+            } else {
+                files.push(FileStat {
+                    path: path.to_string(),
+                    hunks: 1,
+                })
+            }
+        }
+        ChangeSummary {
+            header: change.header.clone(),
+            dependencies: change.dependencies.iter().map(|h| h.to_base32()).collect(),
+            files,
+            size,
+        }
+    }
+
+    /// The paths added or edited by the change
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.files.iter().map(|f| f.path.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_json() {
+        let summary = ChangeSummary {
+            header: ChangeHeader {
+                message: "Fix the build".to_string(),
+                ..ChangeHeader::default()
+            },
+            dependencies: vec!["AAAA".to_string()],
+            files: vec![FileStat {
+                path: "src/main.rs".to_string(),
+                hunks: 2,
+            }],
+            size: 123,
+        };
+        let json = serde_json::to_string(&summary).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ChangeSummary>(&json).unwrap(),
+            summary
+        );
+        assert_eq!(summary.paths().collect::<Vec<_>>(), vec!["src/main.rs"]);
+
+        // Clients may leave out the files and size.
+        let json = serde_json::json!({
+            "header": summary.header,
+            "dependencies": [],
+        });
+        let summary: ChangeSummary = serde_json::from_value(json).unwrap();
+        assert!(summary.files.is_empty());
+    }
+}
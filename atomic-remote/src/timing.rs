@@ -0,0 +1,162 @@
+//! Time spent in each phase of a pull
+//!
+//! A slow pull can be slow to download, to resolve the dependencies of
+//! the changes, to apply them, to store the metadata of the pulled tags,
+//! or to output the working copy. [`PullTimings`] adds up the time spent
+//! in each of these phases, is returned in the
+//! [`PullReport`](crate::quarantine::PullReport), and is emitted as
+//! tracing events once the pull is over.
+
+use std::time::{Duration, Instant};
+
+/// A phase of a pull
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PullPhase {
+    /// Downloading the changes and tags, including waiting for the remote
+    Download,
+    /// Completing the dependencies of the nodes to pull
+    DepCheck,
+    /// Applying the nodes to the channel
+    Apply,
+    /// Storing the consolidating metadata of the pulled tags
+    TagMetadata,
+    /// Outputting the touched files to the working copy
+    Output,
+}
+
+impl PullPhase {
+    pub const ALL: [PullPhase; 5] = [
+        PullPhase::Download,
+        PullPhase::DepCheck,
+        PullPhase::Apply,
+        PullPhase::TagMetadata,
+        PullPhase::Output,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            PullPhase::Download => "download",
+            PullPhase::DepCheck => "dep_check",
+            PullPhase::Apply => "apply",
+            PullPhase::TagMetadata => "tag_metadata",
+            PullPhase::Output => "output",
+        }
+    }
+}
+
+/// Time spent in each phase of a pull
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PullTimings {
+    pub download: Duration,
+    pub dep_check: Duration,
+    pub apply: Duration,
+    pub tag_metadata: Duration,
+    pub output: Duration,
+}
+
+impl PullTimings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, phase: PullPhase) -> Duration {
+        match phase {
+            PullPhase::Download => self.download,
+            PullPhase::DepCheck => self.dep_check,
+            PullPhase::Apply => self.apply,
+            PullPhase::TagMetadata => self.tag_metadata,
+            PullPhase::Output => self.output,
+        }
+    }
+
+    /// Add `elapsed` to the time spent in `phase`
+    pub fn add(&mut self, phase: PullPhase, elapsed: Duration) {
+        let d = match phase {
+            PullPhase::Download => &mut self.download,
+            PullPhase::DepCheck => &mut self.dep_check,
+            PullPhase::Apply => &mut self.apply,
+            PullPhase::TagMetadata => &mut self.tag_metadata,
+            PullPhase::Output => &mut self.output,
+        };
+        *d += elapsed
+    }
+
+    /// Add the time since `start` to `phase`
+    pub fn since(&mut self, phase: PullPhase, start: Instant) {
+        self.add(phase, start.elapsed())
+    }
+
+    /// Run `f`, in a span named after `phase`, and add its duration to
+    /// `phase`
+    pub fn time<R, F: FnOnce() -> R>(&mut self, phase: PullPhase, f: F) -> R {
+        let _span = tracing::debug_span!("pull_phase", phase = phase.name()).entered();
+        let start = Instant::now();
+        let r = f();
+        self.since(phase, start);
+        r
+    }
+
+    /// Add the timings of another pull, e.g. the download of a pull
+    /// applied in a second step
+    pub fn merge(&mut self, other: &PullTimings) {
+        for phase in PullPhase::ALL {
+            self.add(phase, other.get(phase))
+        }
+    }
+
+    pub fn total(&self) -> Duration {
+        PullPhase::ALL.iter().map(|p| self.get(*p)).sum()
+    }
+
+    /// Emit the timings as tracing events, one per phase, in a `pull`
+    /// span, with the durations in milliseconds
+    pub fn emit(&self, remote: &str, nodes: usize) {
+        let span = tracing::info_span!("pull", remote, nodes);
+        let _span = span.enter();
+        for phase in PullPhase::ALL {
+            tracing::info!(
+                phase = phase.name(),
+                elapsed_ms = self.get(phase).as_millis() as u64,
+                "pull phase"
+            );
+        }
+        tracing::info!(elapsed_ms = self.total().as_millis() as u64, "pull total");
+    }
+}
+
+impl std::fmt::Display for PullTimings {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, phase) in PullPhase::ALL.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} {:.3}s", phase.name(), self.get(*phase).as_secs_f64())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timings() {
+        let mut timings = PullTimings::new();
+        timings.add(PullPhase::Download, Duration::from_millis(1500));
+        timings.add(PullPhase::Download, Duration::from_millis(500));
+        let n = timings.time(PullPhase::Apply, || 42);
+        assert_eq!(n, 42);
+        let mut other = PullTimings::new();
+        other.add(PullPhase::Output, Duration::from_millis(250));
+        timings.merge(&other);
+
+        assert_eq!(timings.download, Duration::from_secs(2));
+        assert_eq!(timings.output, Duration::from_millis(250));
+        assert_eq!(timings.dep_check, Duration::ZERO);
+        assert!(timings.total() >= Duration::from_millis(2250));
+        assert!(timings
+            .to_string()
+            .starts_with("download 2.000s, dep_check 0.000s, apply "));
+    }
+}
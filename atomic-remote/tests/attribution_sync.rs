@@ -0,0 +1,113 @@
+//! Attribution exchanged alongside changes, with a local remote.
+
+mod common;
+
+use atomic_remote::attribution::{self, AttributedPatch, RemoteAttributionConfig};
+use atomic_remote::local::Local;
+use atomic_remote::{Node, RemoteRepo};
+use common::{not_interactive, Fixture};
+use libatomic::attribution::{AuthorId, AuthorInfo, SanakirjaAttributionStore};
+use libatomic::{Base32, Hash, MutTxnT};
+use std::collections::HashSet;
+
+fn local_remote(source: &Fixture) -> RemoteRepo {
+    RemoteRepo::Local(Local {
+        channel: "main".to_string(),
+        root: source.path().to_path_buf(),
+        changes_dir: source.repo.changes_dir.clone(),
+        pristine: std::sync::Arc::new(source.repo.pristine.clone()),
+        name: source.path().to_str().unwrap().to_string(),
+    })
+}
+
+fn attribute(fixture: &Fixture, hash: &Hash, description: &str) {
+    let store = SanakirjaAttributionStore::new(fixture.repo.pristine.clone());
+    store
+        .put_attribution(&AttributedPatch {
+            patch_id: attribution::patch_id(hash),
+            author: AuthorInfo {
+                id: AuthorId::new(1),
+                name: "Alice".to_string(),
+                email: "alice@example.com".to_string(),
+                is_ai: false,
+            },
+            timestamp: chrono::Utc::now(),
+            ai_assisted: false,
+            ai_metadata: None,
+            dependencies: HashSet::new(),
+            conflicts_with: HashSet::new(),
+            description: description.to_string(),
+            confidence: None,
+        })
+        .unwrap();
+}
+
+fn stored(fixture: &Fixture, hash: &Hash) -> Option<AttributedPatch> {
+    SanakirjaAttributionStore::new(fixture.repo.pristine.clone())
+        .get_attribution(&attribution::patch_id(hash))
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_pull_attribution() {
+    not_interactive();
+    let source = Fixture::new().unwrap();
+    let a = source.commit("main", "a.txt", "a\n", "Add a").unwrap();
+    attribute(&source, &a, "Add a");
+
+    let mut target = Fixture::new().unwrap();
+    let mut remote = local_remote(&source);
+    assert!(remote.exchanges_attribution().await);
+    let pristine = target.repo.pristine.clone();
+    let mut txn = pristine.mut_txn_begin().unwrap();
+    let mut channel = txn.open_or_create_channel("main").unwrap();
+    remote
+        .clone_channel(&mut target.repo, &mut txn, &mut channel, &[])
+        .await
+        .unwrap();
+    let received = remote.download_attribution(&[a]).await.unwrap();
+    txn.commit().unwrap();
+
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].hash, a.to_base32());
+    // The change travels separately.
+    assert!(received[0].bundle.patch_data.is_empty());
+    let config = RemoteAttributionConfig::default();
+    let n = attribution::incoming(
+        &target.repo.pristine,
+        &target.repo.changes_dir,
+        received,
+        &config,
+    )
+    .unwrap();
+    assert_eq!(n, 1);
+    assert_eq!(stored(&target, &a).unwrap().description, "Add a");
+}
+
+#[tokio::test]
+async fn test_push_attribution() {
+    not_interactive();
+    let source = Fixture::new().unwrap();
+    let a = source.commit("main", "a.txt", "a\n", "Add a").unwrap();
+    attribute(&source, &a, "Add a");
+
+    // The remote doesn't have the change: its attribution is ignored.
+    let target = Fixture::new().unwrap();
+    let mut remote = local_remote(&target);
+    assert_eq!(
+        remote.upload_attribution(&source.repo, &[a]).await.unwrap(),
+        1
+    );
+    assert!(stored(&target, &a).is_none());
+
+    // Once the change is pushed, it is stored.
+    let node = Node::change(a, source.state("main").unwrap());
+    let mut txn = source.repo.pristine.mut_txn_begin().unwrap();
+    remote
+        .upload_nodes(&mut txn, source.repo.changes_dir.clone(), None, &[node])
+        .await
+        .unwrap();
+    std::mem::drop(txn);
+    remote.upload_attribution(&source.repo, &[a]).await.unwrap();
+    assert_eq!(stored(&target, &a).unwrap().description, "Add a");
+}
@@ -0,0 +1,91 @@
+//! Git remotes, against a bare Git repository on disk
+
+#![cfg(feature = "git")]
+
+mod common;
+
+use atomic_remote::git::{Git, CHANGE_TRAILER};
+use common::*;
+use libatomic::pristine::Base32;
+use std::path::Path;
+
+/// A bare repository with a commit adding `README` to `main`.
+fn bare_with_commit(path: &Path) -> git2::Repository {
+    let bare = git2::Repository::init_bare(path).expect("Failed to create Git repository");
+    {
+        let blob = bare.blob(b"Hello\n").unwrap();
+        let mut tree = bare.treebuilder(None).unwrap();
+        tree.insert("README", blob, 0o100644).unwrap();
+        let tree = bare.find_tree(tree.write().unwrap()).unwrap();
+        let signature = git2::Signature::now("Alice", "alice@example.com").unwrap();
+        bare.commit(
+            Some("refs/heads/main"),
+            &signature,
+            &signature,
+            "Add a README\n\nFor newcomers.",
+            &tree,
+            &[],
+        )
+        .unwrap();
+    }
+    bare
+}
+
+#[test]
+fn test_mirror_git_branch() {
+    atomic_interaction::set_context(atomic_interaction::InteractiveContext::NotInteractive);
+    let tmp = tempfile::tempdir().unwrap();
+    let bare = bare_with_commit(&tmp.path().join("remote.git"));
+    let name = format!("git+file://{}", tmp.path().join("remote.git").display());
+
+    // Commits are recorded as changes of the channel of the branch.
+    let mut git = Git::open_in(&tmp.path().join("mirror"), &name, "main").unwrap();
+    let (n, _, _) = git.local.get_state(None).unwrap().unwrap();
+    assert_eq!(n, 0);
+    assert_eq!(
+        std::fs::read_to_string(tmp.path().join("mirror").join("README")).unwrap(),
+        "Hello\n"
+    );
+
+    // Pushed changes become commits of the branch.
+    let (fixture, hashes) = repo_with_changes(1);
+    git.upload_nodes(
+        atomic_interaction::ProgressBar::new(1, "Uploading").unwrap(),
+        fixture.repo.changes_dir.clone(),
+        None,
+        &channel_nodes(&fixture, "main"),
+    )
+    .unwrap();
+    assert!(git
+        .upload_nodes(
+            atomic_interaction::ProgressBar::new(0, "Uploading").unwrap(),
+            fixture.repo.changes_dir.clone(),
+            Some("dev"),
+            &[],
+        )
+        .is_err());
+    git.finish().unwrap();
+    std::mem::drop(git);
+
+    let head = bare
+        .find_commit(bare.refname_to_id("refs/heads/main").unwrap())
+        .unwrap();
+    assert!(head.message().unwrap().starts_with("Change 0"));
+    assert!(head.message().unwrap().contains(&format!(
+        "{}: {}",
+        CHANGE_TRAILER,
+        hashes[0].to_base32()
+    )));
+    let tree = head.tree().unwrap();
+    assert!(tree.get_name("README").is_some());
+    assert!(tree.get_name("file0.txt").is_some());
+
+    // Another mirror of the branch sees both commits.
+    let mut other = Git::open_in(&tmp.path().join("other"), &name, "main").unwrap();
+    let (n, _, _) = other.local.get_state(None).unwrap().unwrap();
+    assert_eq!(n, 1);
+    assert_eq!(
+        std::fs::read_to_string(tmp.path().join("other").join("file0.txt")).unwrap(),
+        "content 0\n"
+    );
+}
@@ -234,8 +234,9 @@ fn test_changelist_entry_format() {
 fn test_protocol_version_updated() {
     use atomic_remote::PROTOCOL_VERSION;
 
-    // Following AGENTS.md: Protocol version should be 4 for node-type-aware system
-    assert_eq!(PROTOCOL_VERSION, 4);
+    // Version 4 made the protocol node-type-aware, and version 5 added
+    // bundles of changes
+    assert_eq!(PROTOCOL_VERSION, 5);
 }
 
 // Note: Integration tests that require database access should be in separate
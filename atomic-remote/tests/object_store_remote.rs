@@ -0,0 +1,196 @@
+//! Object store remotes, against an in-memory S3-compatible server.
+
+mod common;
+
+use atomic_remote::object_store::{Condition, ObjectStore};
+use common::*;
+use libatomic::TxnT;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+type Objects = Arc<Mutex<HashMap<String, (Vec<u8>, String)>>>;
+
+/// Serve the GET and (conditional) PUT requests of an S3 client from
+/// `objects`, one request per connection.
+async fn serve(listener: tokio::net::TcpListener, objects: Objects) {
+    let mut version = 0;
+    loop {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = Vec::new();
+        let mut chunk = [0; 4096];
+        let end = loop {
+            let n = socket.read(&mut chunk).await.unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+            if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+        };
+        let head = String::from_utf8(buf[..end].to_vec()).unwrap();
+        let mut lines = head.lines();
+        let mut request = lines.next().unwrap().split(' ');
+        let (method, path) = (request.next().unwrap(), request.next().unwrap());
+        let headers: HashMap<String, String> = lines
+            .filter_map(|l| l.split_once(": "))
+            .map(|(k, v)| (k.to_lowercase(), v.to_string()))
+            .collect();
+        let len: usize = headers
+            .get("content-length")
+            .map_or(0, |l| l.parse().unwrap());
+        while buf.len() < end + len {
+            let n = socket.read(&mut chunk).await.unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        let body = buf[end..end + len].to_vec();
+
+        let (status, etag, body) = {
+            let mut objects = objects.lock().unwrap();
+            match method {
+                "GET" => match objects.get(path) {
+                    Some((body, etag)) => ("200 OK", Some(etag.clone()), body.clone()),
+                    None => ("404 Not Found", None, Vec::new()),
+                },
+                "PUT" => {
+                    let current = objects.get(path).map(|(_, etag)| etag);
+                    let failed = match (headers.get("if-match"), headers.get("if-none-match")) {
+                        (Some(expected), _) => current != Some(expected),
+                        (_, Some(_)) => current.is_some(),
+                        _ => false,
+                    };
+                    if failed {
+                        ("412 Precondition Failed", None, Vec::new())
+                    } else {
+                        version += 1;
+                        let etag = format!("\"{}\"", version);
+                        objects.insert(path.to_string(), (body, etag.clone()));
+                        ("200 OK", Some(etag), Vec::new())
+                    }
+                }
+                _ => ("405 Method Not Allowed", None, Vec::new()),
+            }
+        };
+        let mut response = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+            status,
+            body.len()
+        );
+        if let Some(etag) = etag {
+            response.push_str(&format!("ETag: {}\r\n", etag))
+        }
+        response.push_str("\r\n");
+        socket.write_all(response.as_bytes()).await.unwrap();
+        socket.write_all(&body).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_push_pull_object_store() {
+    atomic_interaction::set_context(atomic_interaction::InteractiveContext::NotInteractive);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let objects = Objects::default();
+    tokio::spawn(serve(listener, objects.clone()));
+    std::env::remove_var("AWS_ENDPOINT_URL_S3");
+    std::env::set_var("AWS_ENDPOINT_URL", format!("http://{}", addr));
+    std::env::remove_var("AWS_ACCESS_KEY_ID");
+
+    let (fixture, hashes) = repo_with_changes(2);
+    let tag = fixture.tag("main", "v1").unwrap();
+    let nodes = channel_nodes(&fixture, "main");
+    let mut store = ObjectStore::open("s3://bucket/repo", "main").unwrap();
+    assert!(store.get_id().await.unwrap().is_none());
+    assert!(store.get_state(None).await.unwrap().is_none());
+
+    store
+        .upload_nodes(
+            atomic_interaction::ProgressBar::new(3, "Uploading").unwrap(),
+            fixture.repo.changes_dir.clone(),
+            None,
+            &nodes,
+        )
+        .await
+        .unwrap();
+    assert!(objects
+        .lock()
+        .unwrap()
+        .contains_key("/bucket/repo/channels/main.json"));
+
+    // The states of the remote are those of the channel it was pushed from.
+    assert!(store.get_id().await.unwrap().is_some());
+    let (n, state, tag_state) = store.get_state(None).await.unwrap().unwrap();
+    let txn = fixture.repo.pristine.txn_begin().unwrap();
+    let channel = txn.load_channel("main").unwrap().unwrap();
+    let (local_n, local_state, _) = atomic_remote::local::get_state(&txn, &channel, None)
+        .unwrap()
+        .unwrap();
+    assert_eq!((n, state, tag_state), (local_n, local_state, tag));
+    std::mem::drop(txn);
+
+    let mut list = Vec::new();
+    store
+        .download_changelist(
+            |l: &mut Vec<_>, n, h, m, is_tag| {
+                l.push((n, h, m, is_tag));
+                Ok(())
+            },
+            &mut list,
+            0,
+            &[],
+        )
+        .await
+        .unwrap();
+    assert_eq!(list.iter().map(|x| x.1).collect::<Vec<_>>(), hashes);
+    assert!(list[1].3);
+
+    // Pulling downloads the change and tag files.
+    let target = tempfile::tempdir().unwrap();
+    let (to_download, mut from) = tokio::sync::mpsc::unbounded_channel();
+    for node in nodes.iter() {
+        to_download.send(*node).unwrap();
+    }
+    std::mem::drop(to_download);
+    let (mut send, mut recv) = tokio::sync::mpsc::channel(10);
+    store
+        .download_nodes(
+            atomic_interaction::ProgressBar::new(3, "Downloading").unwrap(),
+            &mut from,
+            &mut send,
+            &target.path().to_path_buf(),
+            2,
+        )
+        .await
+        .unwrap();
+    std::mem::drop(send);
+    let mut downloaded = 0;
+    while let Some((node, _)) = recv.recv().await {
+        let mut path = target.path().to_path_buf();
+        if node.is_change() {
+            libatomic::changestore::filesystem::push_filename(&mut path, &node.hash)
+        } else {
+            libatomic::changestore::filesystem::push_tag_filename(&mut path, &node.state)
+        }
+        assert!(path.exists(), "{:?}", path);
+        downloaded += 1
+    }
+    assert_eq!(downloaded, 3);
+
+    // Changelists are only replaced if nobody else did it in the meantime.
+    assert!(!store
+        .bucket
+        .put(
+            "repo/channels/main.json",
+            b"{}".to_vec(),
+            Some(Condition::IfMatch("\"0\"".to_string()))
+        )
+        .await
+        .unwrap());
+    assert!(!store
+        .bucket
+        .put(
+            "repo/channels/main.json",
+            b"{}".to_vec(),
+            Some(Condition::IfNoneMatch)
+        )
+        .await
+        .unwrap());
+}
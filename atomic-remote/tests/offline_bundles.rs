@@ -0,0 +1,83 @@
+//! Bundle files between repositories built from real changes and tags.
+
+mod common;
+
+use atomic_remote::RemoteRepo;
+use common::{channel_nodes, count_channel_tags, repo_with_changes, Fixture};
+use libatomic::{MutTxnT, TxnT};
+
+#[test]
+fn test_export_import_bundle() {
+    let (source, hashes) = repo_with_changes(3);
+    let tag = source.tag("main", "v1").expect("Failed to tag");
+    let nodes = channel_nodes(&source, "main");
+    assert_eq!(nodes.len(), 4);
+    assert!(nodes[3].is_tag());
+
+    // Exporting the tag alone brings every change it depends on.
+    let mut file = Vec::new();
+    let txn = source.repo.pristine.txn_begin().unwrap();
+    let channel = txn.load_channel("main").unwrap().unwrap();
+    let manifest =
+        RemoteRepo::export_bundle(&source.repo, &txn, &channel, &nodes[3..], &mut file).unwrap();
+    drop(txn);
+    assert_eq!(manifest.nodes.len(), 4);
+
+    let target = Fixture::new().unwrap();
+    let mut txn = target.repo.pristine.mut_txn_begin().unwrap();
+    let mut channel = txn.open_or_create_channel("main").unwrap();
+    let applied =
+        RemoteRepo::import_bundle(&target.repo, &mut txn, &mut channel, &file[..]).unwrap();
+    txn.commit().unwrap();
+    assert_eq!(applied, nodes);
+
+    assert_eq!(target.log("main").unwrap(), hashes);
+    assert_eq!(target.state("main").unwrap(), tag);
+    assert_eq!(count_channel_tags(&target, "main"), 1);
+
+    // Importing the same file again applies nothing.
+    let mut txn = target.repo.pristine.mut_txn_begin().unwrap();
+    let mut channel = txn.open_or_create_channel("main").unwrap();
+    let applied =
+        RemoteRepo::import_bundle(&target.repo, &mut txn, &mut channel, &file[..]).unwrap();
+    assert!(applied.is_empty());
+}
+
+fn export(fixture: &Fixture, channel: &str, nodes: &[atomic_remote::Node]) -> Vec<u8> {
+    let mut file = Vec::new();
+    let txn = fixture.repo.pristine.txn_begin().unwrap();
+    let channel = txn.load_channel(channel).unwrap().unwrap();
+    RemoteRepo::export_bundle(&fixture.repo, &txn, &channel, nodes, &mut file).unwrap();
+    file
+}
+
+fn import(fixture: &Fixture, channel: &str, file: &[u8]) -> Vec<atomic_remote::Node> {
+    let mut txn = fixture.repo.pristine.mut_txn_begin().unwrap();
+    let mut channel = txn.open_or_create_channel(channel).unwrap();
+    let applied = RemoteRepo::import_bundle(&fixture.repo, &mut txn, &mut channel, file).unwrap();
+    txn.commit().unwrap();
+    applied
+}
+
+#[test]
+fn test_import_bundle_of_fork() {
+    let (source, hashes) = repo_with_changes(2);
+    source.fork("main", "dev").unwrap();
+    let c = source.commit("dev", "dev.txt", "dev\n", "On dev").unwrap();
+
+    let target = Fixture::new().unwrap();
+    let main = channel_nodes(&source, "main");
+    assert_eq!(
+        import(&target, "main", &export(&source, "main", &main)),
+        main
+    );
+
+    // The target already has the changes of main: only the change of dev
+    // is applied.
+    let dev = channel_nodes(&source, "dev");
+    let applied = import(&target, "main", &export(&source, "dev", &dev[2..]));
+    assert_eq!(applied, &dev[2..]);
+    assert_eq!(applied[0].hash, c);
+    assert_eq!(target.log("main").unwrap(), vec![hashes[0], hashes[1], c]);
+    assert_eq!(target.state("main").unwrap(), source.state("dev").unwrap());
+}
@@ -0,0 +1,68 @@
+//! Quarantining nodes that can't be applied, without touching the channel.
+
+mod common;
+
+use atomic_remote::quarantine::PullReport;
+use atomic_remote::Node;
+use common::Fixture;
+use libatomic::pristine::{Hash, Merkle};
+use libatomic::{ApplyWorkspace, MutTxnT};
+
+fn copy_change(from: &Fixture, to: &Fixture, hash: &Hash) {
+    let mut src = from.repo.changes_dir.clone();
+    libatomic::changestore::filesystem::push_filename(&mut src, hash);
+    let mut dst = to.repo.changes_dir.clone();
+    libatomic::changestore::filesystem::push_filename(&mut dst, hash);
+    std::fs::create_dir_all(dst.parent().unwrap()).unwrap();
+    std::fs::copy(&src, &dst).unwrap();
+}
+
+#[test]
+fn test_missing_dependency_is_quarantined_before_applying() {
+    let source = Fixture::new().unwrap();
+    let first = source.commit("main", "a.txt", "a\n", "First").unwrap();
+    let second = source.commit("main", "a.txt", "a\nb\n", "Second").unwrap();
+
+    // The target only has the second change, which depends on the first.
+    let target = Fixture::new().unwrap();
+    copy_change(&source, &target, &second);
+
+    let mut ws = ApplyWorkspace::new();
+    let mut report = PullReport::new();
+    let mut txn = target.repo.pristine.mut_txn_begin().unwrap();
+    let channel = txn.open_or_create_channel("main").unwrap();
+    let node = Node::change(second, Merkle::zero());
+    let applied = report
+        .apply(
+            &target.repo.changes,
+            &mut txn,
+            &mut *channel.write(),
+            &node,
+            &mut ws,
+        )
+        .unwrap();
+    assert!(!applied);
+    assert_eq!(report.quarantined.len(), 1);
+    assert!(report.quarantined[0].reason.contains("Dependency"));
+    txn.commit().unwrap();
+    assert!(target.log("main").unwrap().is_empty());
+
+    // Once the dependency is there, retrying applies both changes.
+    copy_change(&source, &target, &first);
+    let mut report = PullReport::new();
+    let mut txn = target.repo.pristine.mut_txn_begin().unwrap();
+    let channel = txn.open_or_create_channel("main").unwrap();
+    let applied = report
+        .apply(
+            &target.repo.changes,
+            &mut txn,
+            &mut *channel.write(),
+            &node,
+            &mut ws,
+        )
+        .unwrap();
+    assert!(applied);
+    assert!(!report.is_partial());
+    txn.commit().unwrap();
+    assert_eq!(target.log("main").unwrap(), vec![first, second]);
+}
@@ -0,0 +1,76 @@
+//! Sparse clones, of some paths of a local remote.
+
+mod common;
+
+use atomic_remote::local::Local;
+use atomic_remote::RemoteRepo;
+use common::{not_interactive, Fixture};
+use libatomic::MutTxnT;
+
+fn local_remote(source: &Fixture) -> RemoteRepo {
+    RemoteRepo::Local(Local {
+        channel: "main".to_string(),
+        root: source.path().to_path_buf(),
+        changes_dir: source.repo.changes_dir.clone(),
+        pristine: std::sync::Arc::new(source.repo.pristine.clone()),
+        name: source.path().to_str().unwrap().to_string(),
+    })
+}
+
+#[tokio::test]
+async fn test_sparse_clone() {
+    not_interactive();
+    let source = Fixture::new().unwrap();
+    let a = source.commit("main", "src/a.txt", "a\n", "Add a").unwrap();
+    let c = source.commit("main", "src/c.txt", "c\n", "Add c").unwrap();
+    // Each change depends on the one before it, `docs` is recorded last
+    // so that the changes in `src` don't depend on it.
+    source.commit("main", "docs/b.txt", "b\n", "Add b").unwrap();
+
+    let mut target = Fixture::new().unwrap();
+    let pristine = target.repo.pristine.clone();
+    let mut txn = pristine.mut_txn_begin().unwrap();
+    let mut channel = txn.open_or_create_channel("main").unwrap();
+    let applied = local_remote(&source)
+        .clone_channel(
+            &mut target.repo,
+            &mut txn,
+            &mut channel,
+            &["./src/".to_string()],
+        )
+        .await
+        .unwrap();
+    txn.commit().unwrap();
+
+    // Only the changes touching `src` are applied.
+    assert_eq!(target.log("main").unwrap(), vec![a, c]);
+    let applied: Vec<_> = applied.iter().map(|n| n.hash).collect();
+    assert_eq!(applied, vec![a, c]);
+
+    // The normalized prefixes are recorded, for the next pulls.
+    assert_eq!(target.repo.config.sparse, vec!["src".to_string()]);
+    let config = std::fs::read_to_string(target.path().join(".atomic").join("config")).unwrap();
+    assert!(config.contains("sparse = [\"src\"]"), "{}", config);
+}
+
+#[tokio::test]
+async fn test_sparse_clone_missing_path() {
+    not_interactive();
+    let source = Fixture::new().unwrap();
+    source.commit("main", "src/a.txt", "a\n", "Add a").unwrap();
+
+    let mut target = Fixture::new().unwrap();
+    let pristine = target.repo.pristine.clone();
+    let mut txn = pristine.mut_txn_begin().unwrap();
+    let mut channel = txn.open_or_create_channel("main").unwrap();
+    let cloned = local_remote(&source)
+        .clone_channel(
+            &mut target.repo,
+            &mut txn,
+            &mut channel,
+            &["lib".to_string()],
+        )
+        .await;
+    assert!(cloned.is_err());
+    assert!(target.repo.config.sparse.is_empty());
+}
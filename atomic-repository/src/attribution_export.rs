@@ -0,0 +1,396 @@
+//! Bulk export of the attribution of changes, for analytics warehouses.
+//!
+//! Each change of a channel gives one [`AttributionRecord`]: its position
+//! in the channel, header, and AI attribution, taken from the attribution
+//! metadata recorded with the change or, for changes recorded without it,
+//! detected from the message (`detected` is then true). Records are
+//! written as newline-delimited JSON, or as Parquet with the `parquet`
+//! feature.
+//!
+//! Exports are incremental: each batch ends with a cursor, the position
+//! after its last record, and passing that cursor to the next export only
+//! returns the changes applied since. [`export_dir`] exports a repository
+//! directory offline; the API server serves the same records.
+
+use crate::Repository;
+use chrono::{DateTime, Utc};
+use libatomic::attribution::SerializedAttribution;
+use libatomic::change::ChangeHeader;
+use libatomic::changestore::filesystem::FileSystem;
+use libatomic::changestore::ChangeStore;
+use libatomic::pristine::sanakirja::Txn;
+use libatomic::pristine::{Base32, ChannelRef, Hash};
+use libatomic::{ChannelTxnT, TxnT, TxnTExt};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+
+/// Attribution of one change of a channel.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AttributionRecord {
+    /// Position of the change in the channel.
+    pub position: u64,
+    pub channel: String,
+    /// Hash of the change, in base32.
+    pub hash: String,
+    pub timestamp: DateTime<Utc>,
+    pub message: String,
+    /// Names (or public keys) of the authors.
+    pub authors: Vec<String>,
+    pub ai_assisted: bool,
+    /// Whether the attribution was detected from the message, for changes
+    /// recorded without attribution metadata.
+    pub detected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ai_provider: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ai_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggestion_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_count: Option<u32>,
+}
+
+impl AttributionRecord {
+    /// The record of change `hash`, at `position` in `channel`, from its
+    /// header and hashed metadata.
+    pub fn new(
+        position: u64,
+        channel: &str,
+        hash: &Hash,
+        header: &ChangeHeader,
+        metadata: &[u8],
+    ) -> Self {
+        let authors = header
+            .authors
+            .iter()
+            .filter_map(|a| a.0.get("name").or_else(|| a.0.get("key")).cloned())
+            .collect();
+        let mut record = AttributionRecord {
+            position,
+            channel: channel.to_string(),
+            hash: hash.to_base32(),
+            timestamp: header.timestamp,
+            message: header.message.clone(),
+            authors,
+            ai_assisted: false,
+            detected: false,
+            ai_provider: None,
+            ai_model: None,
+            suggestion_type: None,
+            confidence: None,
+            token_count: None,
+        };
+        match bincode::deserialize::<SerializedAttribution>(metadata) {
+            Ok(attribution) if !metadata.is_empty() => {
+                record.ai_assisted = attribution.ai_assisted;
+                record.confidence = attribution.confidence;
+                if let Some(m) = attribution.ai_metadata {
+                    record.ai_provider = Some(m.provider);
+                    record.ai_model = Some(m.model);
+                    record.suggestion_type = Some(format!("{:?}", m.suggestion_type));
+                    record.token_count = m.token_count;
+                }
+            }
+            _ => {
+                let text = format!(
+                    "{} {}",
+                    header.message,
+                    header.description.as_deref().unwrap_or("")
+                );
+                record.detected = true;
+                record.ai_assisted =
+                    libatomic::attribution::integration::detect_ai_assistance(&text);
+            }
+        }
+        record
+    }
+}
+
+/// A batch of records, and the cursor to pass to the next export.
+#[derive(Debug, Clone)]
+pub struct ExportBatch {
+    pub records: Vec<AttributionRecord>,
+    pub next_cursor: String,
+}
+
+/// Formats of exported records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Newline-delimited JSON, one record per line.
+    Ndjson,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ndjson" | "jsonl" => Ok(ExportFormat::Ndjson),
+            #[cfg(feature = "parquet")]
+            "parquet" => Ok(ExportFormat::Parquet),
+            #[cfg(not(feature = "parquet"))]
+            "parquet" => anyhow::bail!("Parquet exports need the `parquet` feature"),
+            _ => anyhow::bail!("Unknown export format {:?}", s),
+        }
+    }
+}
+
+/// Position of the first change after `cursor`. Cursors are the
+/// positions returned by previous exports; no cursor starts at the first
+/// change.
+pub fn parse_cursor(cursor: Option<&str>) -> Result<u64, anyhow::Error> {
+    match cursor {
+        None | Some("") => Ok(0),
+        Some(c) => c
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid export cursor {:?}", c)),
+    }
+}
+
+/// The records of at most `limit` changes of `channel`, starting after
+/// `cursor`, in the order they were applied.
+pub fn collect(
+    txn: &Txn,
+    changes: &FileSystem,
+    channel: &ChannelRef<Txn>,
+    cursor: Option<&str>,
+    limit: Option<usize>,
+) -> Result<ExportBatch, anyhow::Error> {
+    let from = parse_cursor(cursor)?;
+    let channel = channel.read();
+    let name = txn.name(&*channel).to_string();
+    let mut records = Vec::new();
+    let mut next = from;
+    for entry in txn.log(&*channel, from)? {
+        if limit.map_or(false, |l| records.len() >= l) {
+            break;
+        }
+        let (n, (h, _)) = entry?;
+        let hash: Hash = h.into();
+        let change = changes.get_change(&hash)?;
+        records.push(AttributionRecord::new(
+            n,
+            &name,
+            &hash,
+            &change.hashed.header,
+            &change.hashed.metadata,
+        ));
+        next = n + 1;
+    }
+    Ok(ExportBatch {
+        records,
+        next_cursor: next.to_string(),
+    })
+}
+
+/// Write `records` as newline-delimited JSON.
+pub fn write_ndjson<W: Write>(
+    records: &[AttributionRecord],
+    mut w: W,
+) -> Result<(), anyhow::Error> {
+    for record in records {
+        serde_json::to_writer(&mut w, record)?;
+        w.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Write `records` as a Parquet file, with the authors of each change
+/// joined by commas.
+#[cfg(feature = "parquet")]
+pub fn write_parquet<W: Write + Send>(
+    records: &[AttributionRecord],
+    w: W,
+) -> Result<(), anyhow::Error> {
+    use arrow_array::{
+        ArrayRef, BooleanArray, Float64Array, RecordBatch, StringArray, TimestampMillisecondArray,
+        UInt32Array, UInt64Array,
+    };
+    use arrow_schema::{DataType, Field, Schema, TimeUnit};
+    use std::sync::Arc;
+
+    let strings = |f: fn(&AttributionRecord) -> Option<&str>| -> ArrayRef {
+        Arc::new(records.iter().map(f).collect::<StringArray>())
+    };
+    let schema = Arc::new(Schema::new(vec![
+        Field::new("position", DataType::UInt64, false),
+        Field::new("channel", DataType::Utf8, false),
+        Field::new("hash", DataType::Utf8, false),
+        Field::new(
+            "timestamp",
+            DataType::Timestamp(TimeUnit::Millisecond, Some("UTC".into())),
+            false,
+        ),
+        Field::new("message", DataType::Utf8, false),
+        Field::new("authors", DataType::Utf8, false),
+        Field::new("ai_assisted", DataType::Boolean, false),
+        Field::new("detected", DataType::Boolean, false),
+        Field::new("ai_provider", DataType::Utf8, true),
+        Field::new("ai_model", DataType::Utf8, true),
+        Field::new("suggestion_type", DataType::Utf8, true),
+        Field::new("confidence", DataType::Float64, true),
+        Field::new("token_count", DataType::UInt32, true),
+    ]));
+    let authors: StringArray = records.iter().map(|r| Some(r.authors.join(","))).collect();
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(records.iter().map(|r| r.position).collect::<UInt64Array>()),
+        strings(|r| Some(r.channel.as_str())),
+        strings(|r| Some(r.hash.as_str())),
+        Arc::new(
+            records
+                .iter()
+                .map(|r| r.timestamp.timestamp_millis())
+                .collect::<TimestampMillisecondArray>()
+                .with_timezone("UTC"),
+        ),
+        strings(|r| Some(r.message.as_str())),
+        Arc::new(authors),
+        Arc::new(
+            records
+                .iter()
+                .map(|r| Some(r.ai_assisted))
+                .collect::<BooleanArray>(),
+        ),
+        Arc::new(
+            records
+                .iter()
+                .map(|r| Some(r.detected))
+                .collect::<BooleanArray>(),
+        ),
+        strings(|r| r.ai_provider.as_deref()),
+        strings(|r| r.ai_model.as_deref()),
+        strings(|r| r.suggestion_type.as_deref()),
+        Arc::new(
+            records
+                .iter()
+                .map(|r| r.confidence)
+                .collect::<Float64Array>(),
+        ),
+        Arc::new(
+            records
+                .iter()
+                .map(|r| r.token_count)
+                .collect::<UInt32Array>(),
+        ),
+    ];
+    let batch = RecordBatch::try_new(schema.clone(), columns)?;
+    let mut writer = parquet::arrow::ArrowWriter::try_new(w, schema, None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+/// Write `records` in `format`.
+pub fn write<W: Write + Send>(
+    records: &[AttributionRecord],
+    format: ExportFormat,
+    w: W,
+) -> Result<(), anyhow::Error> {
+    match format {
+        ExportFormat::Ndjson => write_ndjson(records, w),
+        #[cfg(feature = "parquet")]
+        ExportFormat::Parquet => write_parquet(records, w),
+    }
+}
+
+/// Export the attribution of `channel` (by default, the current channel)
+/// of the repository at `path`, without a server, writing at most `limit`
+/// records after `cursor` to `w` in `format`. Returns the cursor of the
+/// next export.
+pub fn export_dir<W: Write + Send>(
+    path: &Path,
+    channel: Option<&str>,
+    cursor: Option<&str>,
+    limit: Option<usize>,
+    format: ExportFormat,
+    w: W,
+) -> Result<String, anyhow::Error> {
+    let repo = Repository::find_root(Some(path.to_path_buf()))?;
+    let txn = repo.pristine.txn_begin()?;
+    let name = channel
+        .or_else(|| txn.current_channel().ok())
+        .unwrap_or(libatomic::DEFAULT_CHANNEL)
+        .to_string();
+    let channel = txn
+        .load_channel(&name)?
+        .ok_or_else(|| anyhow::anyhow!("No such channel: {}", name))?;
+    let batch = collect(&txn, &repo.changes, &channel, cursor, limit)?;
+    write(&batch.records, format, w)?;
+    Ok(batch.next_cursor)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libatomic::attribution::{AIMetadata, SuggestionType};
+
+    fn header(message: &str) -> ChangeHeader {
+        let mut author = std::collections::BTreeMap::new();
+        author.insert("name".to_string(), "alice".to_string());
+        ChangeHeader {
+            message: message.to_string(),
+            authors: vec![libatomic::change::Author(author)],
+            ..ChangeHeader::default()
+        }
+    }
+
+    #[test]
+    fn test_records() {
+        let mut hasher = libatomic::pristine::Hasher::default();
+        hasher.update(b"change");
+        let hash = hasher.finish();
+
+        let metadata = bincode::serialize(&SerializedAttribution {
+            author: None,
+            ai_assisted: true,
+            ai_metadata: Some(AIMetadata {
+                provider: "anthropic".to_string(),
+                model: "model".to_string(),
+                prompt_hash: hash,
+                suggestion_type: SuggestionType::Partial,
+                human_review_time: None,
+                acceptance_confidence: 0.9,
+                generation_timestamp: Utc::now(),
+                token_count: Some(120),
+                model_params: None,
+            }),
+            confidence: Some(0.8),
+            attribution_version: 1,
+        })
+        .unwrap();
+        let record = AttributionRecord::new(3, "main", &hash, &header("Fix parser"), &metadata);
+        assert_eq!(record.position, 3);
+        assert_eq!(record.authors, vec!["alice".to_string()]);
+        assert!(record.ai_assisted && !record.detected);
+        assert_eq!(record.ai_provider.as_deref(), Some("anthropic"));
+        assert_eq!(record.suggestion_type.as_deref(), Some("Partial"));
+        assert_eq!(record.token_count, Some(120));
+
+        // Without metadata, attribution is detected from the message.
+        let detected = AttributionRecord::new(4, "main", &hash, &header("AI-assisted fix"), &[]);
+        assert!(detected.ai_assisted && detected.detected);
+        assert!(detected.ai_provider.is_none());
+
+        let mut out = Vec::new();
+        write_ndjson(&[record.clone(), detected], &mut out).unwrap();
+        let lines: Vec<_> = std::str::from_utf8(&out).unwrap().lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            serde_json::from_str::<AttributionRecord>(lines[0]).unwrap(),
+            record
+        );
+
+        assert_eq!(parse_cursor(None).unwrap(), 0);
+        assert_eq!(parse_cursor(Some("5")).unwrap(), 5);
+        assert!(parse_cursor(Some("x")).is_err());
+        assert_eq!(
+            "ndjson".parse::<ExportFormat>().unwrap(),
+            ExportFormat::Ndjson
+        );
+        assert!("csv".parse::<ExportFormat>().is_err());
+    }
+}
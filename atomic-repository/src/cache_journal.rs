@@ -0,0 +1,154 @@
+//! Journal of the destructive overwrites of remote caches.
+//!
+//! The local cache of a remote lists the nodes it had at the last push or
+//! pull. When the remote unrecords nodes that the current channel has,
+//! pushes and pulls leave the cache alone, unless they are forced to
+//! overwrite it (`--force-cache`), which discards the entries the remote
+//! doesn't have anymore. Each such overwrite is appended to
+//! `.atomic/cache-journal`, one JSON object per line, with the positions,
+//! hashes and states discarded, so that an accidental loss can be
+//! reconstructed later, like with a reflog.
+
+use chrono::{DateTime, Utc};
+use libatomic::pristine::{Base32, Hash, Merkle, NodeType};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// An entry discarded from the cache of a remote.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiscardedNode {
+    /// Position of the entry in the cache.
+    pub position: u64,
+    /// Hash of the node, in base32.
+    pub hash: String,
+    /// State of the remote after the node, in base32.
+    pub state: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tag: bool,
+}
+
+impl DiscardedNode {
+    pub fn new(position: u64, hash: &Hash, state: &Merkle, node_type: NodeType) -> Self {
+        DiscardedNode {
+            position,
+            hash: hash.to_base32(),
+            state: state.to_base32(),
+            tag: node_type == NodeType::Tag,
+        }
+    }
+
+    pub fn hash(&self) -> Option<Hash> {
+        Hash::from_base32(self.hash.as_bytes())
+    }
+}
+
+/// A forced overwrite of the cache of a remote.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheOverwrite {
+    pub remote: String,
+    /// Local channel whose nodes the remote unrecorded.
+    pub channel: String,
+    pub time: DateTime<Utc>,
+    pub discarded: Vec<DiscardedNode>,
+}
+
+/// The journal of the overwrites of the remote caches of a repository.
+#[derive(Debug, Clone)]
+pub struct CacheJournal {
+    path: PathBuf,
+}
+
+impl CacheJournal {
+    /// Journal stored in `path` (usually `.atomic/cache-journal`).
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        CacheJournal { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append `overwrite` to the journal.
+    pub fn append(&self, overwrite: &CacheOverwrite) -> Result<(), anyhow::Error> {
+        let mut line = serde_json::to_vec(overwrite)?;
+        line.push(b'\n');
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&line)?;
+        Ok(())
+    }
+
+    /// The overwrites of the caches of `remote`, or of all remotes, oldest
+    /// first. Lines that can't be parsed, e.g. cut by a crash, are
+    /// skipped.
+    pub fn list(&self, remote: Option<&str>) -> Result<Vec<CacheOverwrite>, anyhow::Error> {
+        let buf = match std::fs::read_to_string(&self.path) {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(buf
+            .lines()
+            .filter_map(|line| serde_json::from_str::<CacheOverwrite>(line).ok())
+            .filter(|o| remote.is_none() || remote == Some(o.remote.as_str()))
+            .collect())
+    }
+}
+
+impl crate::Repository {
+    /// The journal of the forced overwrites of the remote caches of this
+    /// repository.
+    pub fn cache_journal(&self) -> CacheJournal {
+        CacheJournal::new(
+            self.path
+                .join(libatomic::DOT_DIR)
+                .join(crate::CACHE_JOURNAL_FILE),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::hash;
+
+    fn overwrite(remote: &str, n: u8) -> CacheOverwrite {
+        CacheOverwrite {
+            remote: remote.to_string(),
+            channel: "main".to_string(),
+            time: Utc::now(),
+            discarded: vec![DiscardedNode::new(
+                n as u64,
+                &hash(n as u64),
+                &Merkle::zero(),
+                NodeType::Change,
+            )],
+        }
+    }
+
+    #[test]
+    fn test_append_and_list() {
+        let tmp = tempfile::tempdir().unwrap();
+        let journal = CacheJournal::new(tmp.path().join("cache-journal"));
+        assert!(journal.list(None).unwrap().is_empty());
+
+        journal.append(&overwrite("origin", 3)).unwrap();
+        journal.append(&overwrite("mirror", 4)).unwrap();
+        journal.append(&overwrite("origin", 5)).unwrap();
+        // A line cut by a crash.
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(journal.path())
+            .unwrap();
+        file.write_all(b"{\"remote\":\"ori").unwrap();
+
+        let origin = journal.list(Some("origin")).unwrap();
+        assert_eq!(origin.len(), 2);
+        assert_eq!(origin[0].discarded[0].position, 3);
+        assert_eq!(origin[1].discarded[0].hash(), Some(hash(5)));
+        assert_eq!(journal.list(None).unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,112 @@
+//! Changes downloaded by interrupted clones and pulls.
+//!
+//! Clones and pulls only commit their transaction once every change is
+//! downloaded and applied, so an interrupted clone of a large repository
+//! used to download everything again. Pulls now append the hash of each
+//! change to `.atomic/checkpoint` once it is fully downloaded and its
+//! contents match its hash. The next pull (or `atomic clone --resume`)
+//! takes these changes from the change store instead of downloading them,
+//! and removes the checkpoint when it completes.
+//!
+//! The file has one base32 hash per line, appended as downloads complete.
+//! Lines that don't parse, such as a last line cut by the interruption,
+//! are ignored.
+
+use libatomic::pristine::{Base32, Hash};
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The changes downloaded and verified by an unfinished pull.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    path: PathBuf,
+}
+
+impl Checkpoint {
+    /// Checkpoint stored in `path` (usually `.atomic/checkpoint`).
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Checkpoint { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether an unfinished pull left changes to resume from.
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// The changes recorded so far.
+    pub fn verified(&self) -> Result<HashSet<Hash>, anyhow::Error> {
+        match std::fs::read_to_string(&self.path) {
+            Ok(s) => Ok(s
+                .lines()
+                .filter_map(|l| Hash::from_base32(l.trim().as_bytes()))
+                .collect()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashSet::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Record `hash`, fully downloaded and verified.
+    pub fn add(&self, hash: &Hash) -> Result<(), anyhow::Error> {
+        let mut f = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(f, "{}", hash.to_base32())?;
+        Ok(())
+    }
+
+    /// Forget the recorded changes, once the pull that downloaded them
+    /// is committed.
+    pub fn clear(&self) -> Result<(), anyhow::Error> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl crate::Repository {
+    /// The changes downloaded by an unfinished pull into this repository.
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint::new(
+            self.path
+                .join(libatomic::DOT_DIR)
+                .join(crate::CHECKPOINT_FILE),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::hash;
+
+    #[test]
+    fn test_add_and_clear() {
+        let tmp = tempfile::tempdir().unwrap();
+        let checkpoint = Checkpoint::new(tmp.path().join("checkpoint"));
+        assert!(!checkpoint.exists());
+        assert!(checkpoint.verified().unwrap().is_empty());
+
+        checkpoint.add(&hash(0)).unwrap();
+        checkpoint.add(&hash(1)).unwrap();
+        // A line cut by an interruption.
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(checkpoint.path())
+            .unwrap()
+            .write_all(&hash(2).to_base32().as_bytes()[..10])
+            .unwrap();
+        let verified = checkpoint.verified().unwrap();
+        assert_eq!(verified, [hash(0), hash(1)].into_iter().collect());
+
+        checkpoint.clear().unwrap();
+        assert!(!checkpoint.exists());
+        checkpoint.clear().unwrap();
+    }
+}
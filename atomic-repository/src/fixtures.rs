@@ -0,0 +1,310 @@
+//! Repositories for tests, built through the same paths as the commands.
+//!
+//! A [`Fixture`] is a repository in a temporary directory, where changes
+//! are recorded from its working copy and saved to its change store, tags
+//! are written as tag files and registered with their consolidating
+//! metadata, and channels are forked, as `atomic record`, `atomic tag
+//! create` and `atomic fork` do. Changes can carry attribution, to seed
+//! the attribution of a repository.
+//!
+//! This module is behind the `fixtures` feature, for the tests of other
+//! crates:
+//!
+//! ```toml
+//! [dev-dependencies]
+//! atomic-repository = { path = "../atomic-repository", features = ["fixtures"] }
+//! ```
+
+use crate::Repository;
+use anyhow::bail;
+use libatomic::attribution::SerializedAttribution;
+use libatomic::change::{Author, ChangeHeader};
+use libatomic::pristine::{Hash, Merkle, SerializedTag, Tag, TagMetadataMutTxnT};
+use libatomic::{ChannelMutTxnT, ChannelTxnT, MutTxnT, MutTxnTExt, TxnT, TxnTExt};
+use std::path::Path;
+
+/// A repository in a temporary directory, removed when dropped.
+pub struct Fixture {
+    pub repo: Repository,
+    _tmp: tempfile::TempDir,
+}
+
+impl Fixture {
+    /// An empty repository, with an empty `main` channel.
+    pub fn new() -> Result<Self, anyhow::Error> {
+        let tmp = tempfile::tempdir()?;
+        let repo = Repository::init(Some(tmp.path().to_path_buf()), None, None)?;
+        let txn = repo.pristine.arc_txn_begin()?;
+        txn.write()
+            .open_or_create_channel(libatomic::DEFAULT_CHANNEL)?;
+        txn.commit()?;
+        Ok(Fixture { repo, _tmp: tmp })
+    }
+
+    /// The root of the working copy.
+    pub fn path(&self) -> &Path {
+        &self.repo.path
+    }
+
+    /// Write `contents` to `path`, relative to the root of the working
+    /// copy, and track it if it isn't yet.
+    pub fn write(&self, path: &str, contents: &str) -> Result<(), anyhow::Error> {
+        let full = self.repo.path.join(path);
+        if let Some(parent) = full.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&full, contents)?;
+        let txn = self.repo.pristine.arc_txn_begin()?;
+        if !txn.read().is_tracked(path)? {
+            txn.write().add_file(path, 0)?;
+            txn.commit()?;
+        }
+        Ok(())
+    }
+
+    /// Record the working copy to `channel`, with message `message`.
+    pub fn record(&self, channel: &str, message: &str) -> Result<Hash, anyhow::Error> {
+        self.record_with(channel, header(message), Vec::new())
+    }
+
+    /// Record the working copy to `channel`, with message `message` and
+    /// `attribution`.
+    pub fn record_attributed(
+        &self,
+        channel: &str,
+        message: &str,
+        attribution: &SerializedAttribution,
+    ) -> Result<Hash, anyhow::Error> {
+        self.record_with(channel, header(message), bincode::serialize(attribution)?)
+    }
+
+    /// Write `contents` to `path` and record it to `channel`.
+    pub fn commit(
+        &self,
+        channel: &str,
+        path: &str,
+        contents: &str,
+        message: &str,
+    ) -> Result<Hash, anyhow::Error> {
+        self.write(path, contents)?;
+        self.record(channel, message)
+    }
+
+    fn record_with(
+        &self,
+        channel: &str,
+        header: ChangeHeader,
+        metadata: Vec<u8>,
+    ) -> Result<Hash, anyhow::Error> {
+        use libatomic::changestore::ChangeStore;
+        let txn = self.repo.pristine.arc_txn_begin()?;
+        let channel = txn.write().open_or_create_channel(channel)?;
+        let mut state = libatomic::RecordBuilder::new();
+        state.record(
+            txn.clone(),
+            libatomic::Algorithm::default(),
+            false,
+            &libatomic::DEFAULT_SEPARATOR,
+            channel.clone(),
+            &self.repo.working_copy,
+            &self.repo.changes,
+            "",
+            1,
+        )?;
+        let rec = state.finish();
+        if rec.actions.is_empty() {
+            bail!("Nothing to record")
+        }
+        let actions = rec
+            .actions
+            .into_iter()
+            .map(|rec| rec.globalize(&*txn.read()).unwrap())
+            .collect();
+        let contents = std::mem::take(&mut *rec.contents.lock());
+        let mut change = libatomic::change::Change::make_change(
+            &*txn.read(),
+            &channel,
+            actions,
+            contents,
+            header,
+            Vec::new(),
+        )?;
+        change.hashed.metadata = metadata;
+        let hash = self
+            .repo
+            .changes
+            .save_change(&mut change, |_, _| Ok::<_, anyhow::Error>(()))?;
+        txn.write()
+            .apply_local_change(&channel, &change, &hash, &rec.updatables)?;
+        txn.commit()?;
+        Ok(hash)
+    }
+
+    /// Tag the current state of `channel`, returning that state.
+    pub fn tag(&self, channel: &str, message: &str) -> Result<Merkle, anyhow::Error> {
+        let txn = self.repo.pristine.arc_txn_begin()?;
+        let Some(channel_ref) = txn.read().load_channel(channel)? else {
+            bail!("No such channel: {}", channel)
+        };
+        let last_t = if let Some(n) = txn.read().reverse_log(&*channel_ref.read(), None)?.next() {
+            n?.0
+        } else {
+            bail!("Channel {} is empty", channel)
+        };
+        if txn.read().is_tagged(&channel_ref.read().tags, last_t)? {
+            bail!("Current state is already tagged")
+        }
+        let mut buf = Vec::new();
+        let state =
+            libatomic::tag::from_channel(&*txn.read(), channel, &header(message), &mut buf)?;
+        let mut tag_path = self.repo.changes_dir.clone();
+        libatomic::changestore::filesystem::push_tag_filename(&mut tag_path, &state);
+        std::fs::create_dir_all(tag_path.parent().unwrap())?;
+        std::fs::write(&tag_path, &buf)?;
+
+        // The changes since the previous tag, as `atomic tag create`.
+        let consolidated = {
+            let txn = txn.read();
+            let channel_read = channel_ref.read();
+            let start = match txn.rev_iter_tags(txn.tags(&*channel_read), None)?.next() {
+                Some(entry) => entry?.0 .0 + 1,
+                None => 0,
+            };
+            let mut consolidated = Vec::new();
+            for entry in txn.log(&*channel_read, start)? {
+                let (_, (hash, _)) = entry?;
+                consolidated.push(hash.into())
+            }
+            consolidated
+        };
+        let count = consolidated.len() as u64;
+        let mut tag = Tag::new(
+            state,
+            state,
+            channel.to_string(),
+            None,
+            count,
+            count,
+            consolidated,
+        );
+        tag.change_file_hash = Some(state);
+        txn.write()
+            .put_tag(&state, &SerializedTag::from_tag(&tag)?)?;
+        txn.write()
+            .put_tags(&mut channel_ref.write().tags, last_t.into(), &state)?;
+        txn.commit()?;
+        Ok(state)
+    }
+
+    /// Fork `from` into a new channel `to`.
+    pub fn fork(&self, from: &str, to: &str) -> Result<(), anyhow::Error> {
+        let txn = self.repo.pristine.arc_txn_begin()?;
+        let Some(channel) = txn.read().load_channel(from)? else {
+            bail!("No such channel: {}", from)
+        };
+        txn.write().fork(&channel, to)?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// The current state of `channel`.
+    pub fn state(&self, channel: &str) -> Result<Merkle, anyhow::Error> {
+        let txn = self.repo.pristine.txn_begin()?;
+        let Some(channel_ref) = txn.load_channel(channel)? else {
+            bail!("No such channel: {}", channel)
+        };
+        let state = txn.current_state(&*channel_ref.read())?;
+        Ok(state)
+    }
+
+    /// The changes of `channel`, in the order they were applied.
+    pub fn log(&self, channel: &str) -> Result<Vec<Hash>, anyhow::Error> {
+        let txn = self.repo.pristine.txn_begin()?;
+        let Some(channel_ref) = txn.load_channel(channel)? else {
+            bail!("No such channel: {}", channel)
+        };
+        let mut log = Vec::new();
+        for entry in txn.log(&*channel_ref.read(), 0)? {
+            let (_, (hash, _)) = entry?;
+            log.push(hash.into())
+        }
+        Ok(log)
+    }
+}
+
+/// A hash standing for the `n`-th change of a test, for tests that only
+/// need distinct hashes, not the changes themselves.
+pub fn hash(n: u64) -> Hash {
+    let mut h = libatomic::pristine::Hasher::default();
+    h.update(&n.to_le_bytes());
+    h.finish()
+}
+
+/// Attribution of a change written with the help of `model` of `provider`.
+pub fn ai_assisted(provider: &str, model: &str) -> SerializedAttribution {
+    use libatomic::attribution::{AIMetadata, SuggestionType};
+    SerializedAttribution {
+        author: None,
+        ai_assisted: true,
+        ai_metadata: Some(AIMetadata {
+            provider: provider.to_string(),
+            model: model.to_string(),
+            prompt_hash: Hash::NONE,
+            suggestion_type: SuggestionType::Partial,
+            human_review_time: None,
+            acceptance_confidence: 1.0,
+            generation_timestamp: chrono::Utc::now(),
+            token_count: None,
+            model_params: None,
+        }),
+        confidence: Some(1.0),
+        attribution_version: 1,
+    }
+}
+
+fn header(message: &str) -> ChangeHeader {
+    let mut author = std::collections::BTreeMap::new();
+    author.insert("name".to_string(), "fixture".to_string());
+    ChangeHeader {
+        message: message.to_string(),
+        authors: vec![Author(author)],
+        ..ChangeHeader::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixture() {
+        let fixture = Fixture::new().unwrap();
+        let a = fixture.commit("main", "a.txt", "a\n", "First").unwrap();
+        fixture.write("a.txt", "a\nb\n").unwrap();
+        let b = fixture
+            .record_attributed("main", "Second", &ai_assisted("anthropic", "model"))
+            .unwrap();
+        assert_eq!(fixture.log("main").unwrap(), vec![a, b]);
+
+        let state = fixture.tag("main", "v1").unwrap();
+        assert_eq!(fixture.state("main").unwrap(), state);
+        assert!(fixture.tag("main", "again").is_err());
+        let mut tag_path = fixture.repo.changes_dir.clone();
+        libatomic::changestore::filesystem::push_tag_filename(&mut tag_path, &state);
+        libatomic::tag::OpenTagFile::open(&tag_path, &state).unwrap();
+
+        fixture.fork("main", "dev").unwrap();
+        let c = fixture.commit("dev", "c.txt", "c\n", "Third").unwrap();
+        assert_eq!(fixture.log("dev").unwrap(), vec![a, b, c]);
+        assert_eq!(fixture.log("main").unwrap(), vec![a, b]);
+
+        let txn = fixture.repo.pristine.txn_begin().unwrap();
+        let channel = txn.load_channel("main").unwrap().unwrap();
+        let batch =
+            crate::attribution_export::collect(&txn, &fixture.repo.changes, &channel, None, None)
+                .unwrap();
+        assert!(!batch.records[0].ai_assisted);
+        assert!(batch.records[1].ai_assisted);
+        assert_eq!(batch.records[1].ai_provider.as_deref(), Some("anthropic"));
+    }
+}
@@ -0,0 +1,344 @@
+//! Hooks run around pushes, pulls and applies.
+//!
+//! Executables of `.atomic/hooks` named after an event (`pre-push`,
+//! `post-pull`…), or after an event followed by a dot and anything else
+//! (`pre-push.lint`), run for that event, in the order of their names.
+//! WebAssembly modules (`pre-push.wasm`) run with the runtime named by
+//! `ATOMIC_WASM_RUNNER` (`wasmtime` by default), as `<runner> run
+//! <module>`. Files ending in `.sample` are ignored.
+//!
+//! Hooks run in the root of the working copy, with a JSON [`Payload`] on
+//! their standard input: the event, the repository, the remote and
+//! channel, and the nodes pushed, pulled or applied.
+//!
+//! A `pre-` hook exiting with a non-zero status aborts the operation
+//! before anything is uploaded, downloaded or applied. `post-` hooks run
+//! once the operation is committed, so their failures are only reported.
+//! Setting `ATOMIC_NO_HOOKS` skips all the hooks.
+
+use libatomic::pristine::{Base32, Hash, Merkle, NodeType};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Runtime of the WebAssembly hooks, unless `ATOMIC_WASM_RUNNER` is set.
+const DEFAULT_WASM_RUNNER: &str = "wasmtime";
+
+/// The operations hooks run around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    PrePush,
+    PostPush,
+    PrePull,
+    PostPull,
+    PreApply,
+    PostApply,
+}
+
+impl Event {
+    /// The name of the event, and of its hooks.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::PrePush => "pre-push",
+            Event::PostPush => "post-push",
+            Event::PrePull => "pre-pull",
+            Event::PostPull => "post-pull",
+            Event::PreApply => "pre-apply",
+            Event::PostApply => "post-apply",
+        }
+    }
+
+    /// Whether a failing hook aborts the operation.
+    pub fn aborts(&self) -> bool {
+        matches!(self, Event::PrePush | Event::PrePull | Event::PreApply)
+    }
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A node pushed, pulled or applied.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HookNode {
+    /// Hash of the change, or state of the tag, in base32.
+    pub hash: String,
+    /// Channel state after the node, in base32, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tag: bool,
+}
+
+impl HookNode {
+    pub fn new(hash: &Hash, state: Option<&Merkle>, node_type: NodeType) -> Self {
+        HookNode {
+            hash: hash.to_base32(),
+            state: state.map(|s| s.to_base32()),
+            tag: node_type == NodeType::Tag,
+        }
+    }
+}
+
+/// The standard input of hooks.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Payload {
+    pub event: String,
+    /// Root of the working copy.
+    pub repository: PathBuf,
+    /// Name of the remote pushed to or pulled from, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub remote: Option<String>,
+    /// Local channel.
+    pub channel: String,
+    pub nodes: Vec<HookNode>,
+}
+
+/// A `pre-` hook failed, aborting the operation.
+#[derive(Debug, Clone)]
+pub struct HookFailed {
+    pub event: Event,
+    pub hook: PathBuf,
+    /// Exit status, if the hook exited.
+    pub status: Option<i32>,
+}
+
+impl std::fmt::Display for HookFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Hook {} ", self.hook.display())?;
+        match self.status {
+            Some(status) => write!(f, "exited with status {}", status)?,
+            None => write!(f, "failed")?,
+        }
+        write!(f, ", aborting the {}", &self.event.name()[4..])
+    }
+}
+
+impl std::error::Error for HookFailed {}
+
+/// The hooks of a repository.
+#[derive(Debug, Clone)]
+pub struct Hooks {
+    dir: PathBuf,
+    root: PathBuf,
+}
+
+impl Hooks {
+    /// The hooks of `dir`, run in `root`.
+    pub fn new(dir: PathBuf, root: PathBuf) -> Self {
+        Hooks { dir, root }
+    }
+
+    /// The hooks of `event`, in the order they run.
+    pub fn find(&self, event: Event) -> Result<Vec<PathBuf>, std::io::Error> {
+        let dir = match std::fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut hooks = Vec::new();
+        for entry in dir {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            let matches = match name.strip_prefix(event.name()) {
+                Some(rest) => rest.is_empty() || rest.starts_with('.'),
+                None => false,
+            };
+            if !matches || name.ends_with(".sample") {
+                continue;
+            }
+            let meta = std::fs::metadata(entry.path())?;
+            if !meta.is_file() {
+                continue;
+            }
+            let executable = libatomic::permissions::mode(&meta).map_or(true, |m| m & 0o111 != 0);
+            if executable || is_wasm(&entry.path()) {
+                hooks.push(entry.path())
+            } else {
+                debug!("skipping non-executable hook {:?}", entry.path());
+            }
+        }
+        hooks.sort();
+        Ok(hooks)
+    }
+
+    /// Run the hooks of `event` with `payload`. Fails if a hook of a
+    /// `pre-` event fails, and only warns about failed `post-` hooks.
+    pub fn run(&self, event: Event, payload: &Payload) -> Result<(), anyhow::Error> {
+        if std::env::var_os("ATOMIC_NO_HOOKS").is_some() {
+            return Ok(());
+        }
+        let input = serde_json::to_vec(payload)?;
+        for hook in self.find(event)? {
+            debug!("running hook {:?}", hook);
+            let status = match self.spawn(&hook, &input) {
+                Ok(status) if status.success() => continue,
+                Ok(status) => status.code(),
+                Err(e) => {
+                    warn!("Failed to run hook {:?}: {}", hook, e);
+                    None
+                }
+            };
+            let failed = HookFailed {
+                event,
+                hook,
+                status,
+            };
+            if event.aborts() {
+                return Err(failed.into());
+            }
+            warn!("{}", failed);
+        }
+        Ok(())
+    }
+
+    fn spawn(&self, hook: &Path, input: &[u8]) -> Result<std::process::ExitStatus, std::io::Error> {
+        let mut command = if is_wasm(hook) {
+            let runner = std::env::var("ATOMIC_WASM_RUNNER")
+                .unwrap_or_else(|_| DEFAULT_WASM_RUNNER.to_string());
+            let mut command = std::process::Command::new(runner);
+            command.arg("run").arg(hook);
+            command
+        } else {
+            std::process::Command::new(hook)
+        };
+        let mut child = command
+            .current_dir(&self.root)
+            .stdin(std::process::Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        // Hooks don't have to read their input.
+        match stdin.write_all(input) {
+            Err(e) if e.kind() != std::io::ErrorKind::BrokenPipe => return Err(e),
+            _ => {}
+        }
+        std::mem::drop(stdin);
+        child.wait()
+    }
+}
+
+fn is_wasm(path: &Path) -> bool {
+    path.extension().map_or(false, |e| e == "wasm")
+}
+
+impl crate::Repository {
+    /// The hooks of this repository.
+    pub fn hooks(&self) -> Hooks {
+        Hooks::new(
+            self.path.join(libatomic::DOT_DIR).join(crate::HOOKS_DIR),
+            self.path.clone(),
+        )
+    }
+
+    /// The payload of the hooks of `event`, for `nodes` of `channel`.
+    pub fn hook_payload(
+        &self,
+        event: Event,
+        remote: Option<&str>,
+        channel: &str,
+        nodes: Vec<HookNode>,
+    ) -> Payload {
+        Payload {
+            event: event.name().to_string(),
+            repository: self.path.clone(),
+            remote: remote.map(|r| r.to_string()),
+            channel: channel.to_string(),
+            nodes,
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn write_hook(dir: &Path, name: &str, script: &str, mode: u32) {
+        let path = dir.join(name);
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    fn payload(event: Event, root: &Path) -> Payload {
+        Payload {
+            event: event.name().to_string(),
+            repository: root.to_path_buf(),
+            remote: Some("origin".to_string()),
+            channel: "main".to_string(),
+            nodes: vec![HookNode {
+                hash: "AAAA".to_string(),
+                state: None,
+                tag: false,
+            }],
+        }
+    }
+
+    #[test]
+    fn find_hooks() {
+        let root = tempfile::tempdir().unwrap();
+        let hooks = Hooks::new(root.path().join("hooks"), root.path().to_path_buf());
+        // No hooks directory.
+        assert!(hooks.find(Event::PrePush).unwrap().is_empty());
+
+        let dir = root.path().join("hooks");
+        std::fs::create_dir(&dir).unwrap();
+        write_hook(&dir, "pre-push.lint", "#!/bin/sh\n", 0o755);
+        write_hook(&dir, "pre-push", "#!/bin/sh\n", 0o755);
+        write_hook(&dir, "pre-push.sample", "#!/bin/sh\n", 0o755);
+        write_hook(&dir, "pre-push.txt", "notes", 0o644);
+        write_hook(&dir, "pre-pushed", "#!/bin/sh\n", 0o755);
+        write_hook(&dir, "pre-push.wasm", "", 0o644);
+        assert_eq!(
+            hooks.find(Event::PrePush).unwrap(),
+            vec![
+                dir.join("pre-push"),
+                dir.join("pre-push.lint"),
+                dir.join("pre-push.wasm")
+            ]
+        );
+        assert!(hooks.find(Event::PostPush).unwrap().is_empty());
+    }
+
+    #[test]
+    fn run_hooks() {
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("hooks");
+        std::fs::create_dir(&dir).unwrap();
+        let hooks = Hooks::new(dir.clone(), root.path().to_path_buf());
+
+        // Hooks run in the root, with the payload on their input.
+        write_hook(&dir, "pre-push", "#!/bin/sh\ncat > payload.json\n", 0o755);
+        let input = payload(Event::PrePush, root.path());
+        hooks.run(Event::PrePush, &input).unwrap();
+        let written = std::fs::read(root.path().join("payload.json")).unwrap();
+        assert_eq!(serde_json::from_slice::<Payload>(&written).unwrap(), input);
+
+        // Failing `pre-` hooks abort.
+        write_hook(&dir, "pre-pull", "#!/bin/sh\nexit 3\n", 0o755);
+        let err = hooks
+            .run(Event::PrePull, &payload(Event::PrePull, root.path()))
+            .unwrap_err();
+        let failed = err.downcast_ref::<HookFailed>().unwrap();
+        assert_eq!(failed.status, Some(3));
+        assert_eq!(
+            failed.to_string(),
+            format!(
+                "Hook {} exited with status 3, aborting the pull",
+                dir.join("pre-pull").display()
+            )
+        );
+
+        // Failing `post-` hooks don't.
+        write_hook(&dir, "post-pull", "#!/bin/sh\nexit 1\n", 0o755);
+        hooks
+            .run(Event::PostPull, &payload(Event::PostPull, root.path()))
+            .unwrap();
+    }
+}
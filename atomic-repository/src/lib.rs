@@ -7,6 +7,22 @@ use anyhow::bail;
 use libatomic::DOT_DIR;
 use log::debug;
 
+pub mod attribution_export;
+pub mod cache_journal;
+pub mod checkpoint;
+#[cfg(any(test, feature = "fixtures"))]
+pub mod fixtures;
+pub mod hooks;
+pub mod links;
+pub mod lock;
+pub mod notes;
+pub mod provenance;
+pub mod quarantine;
+pub mod reviews;
+pub mod supersede;
+#[cfg(feature = "watch")]
+pub mod watch;
+
 pub struct Repository {
     pub pristine: libatomic::pristine::sanakirja::Pristine,
     pub changes: libatomic::changestore::filesystem::FileSystem,
@@ -19,6 +35,18 @@ pub struct Repository {
 pub const PRISTINE_DIR: &str = "pristine";
 pub const CHANGES_DIR: &str = "changes";
 pub const CONFIG_FILE: &str = "config";
+pub const NOTES_DIR: &str = "notes";
+pub const LINKS_DIR: &str = "links";
+pub const QUARANTINE_FILE: &str = "quarantine";
+pub const REVIEWS_DIR: &str = "reviews";
+pub const PROVENANCE_FILE: &str = "provenance";
+pub const SUPERSEDES_FILE: &str = "supersedes";
+pub const LOCK_FILE: &str = "lock";
+pub const ATTRIBUTION_HOOK_FILE: &str = "attribution-hook";
+pub const ATTRIBUTION_SOCKET: &str = "attribution.sock";
+pub const CHECKPOINT_FILE: &str = "checkpoint";
+pub const CACHE_JOURNAL_FILE: &str = "cache-journal";
+pub const HOOKS_DIR: &str = "hooks";
 const DEFAULT_IGNORE: [&[u8]; 2] = [b".git", b".DS_Store"];
 // Static KV map of names for project kinds |-> elements
 // that should go in the `.ignore` file by default.
@@ -28,6 +56,33 @@ const IGNORE_KINDS: &[(&[&str], &[&[u8]])] = &[
     (&["lean"], &[b"/build"]),
 ];
 
+/// The end-of-line and encoding policies of the `[[normalize]]` rules of
+/// `config`
+pub fn normalization(
+    config: &config::Config,
+) -> Result<
+    libatomic::working_copy::Normalization,
+    libatomic::working_copy::normalize::NormalizationError,
+> {
+    let mut normalization = libatomic::working_copy::Normalization::new();
+    for rule in config.normalize.iter() {
+        let eol = rule.eol.as_deref().map(str::parse).transpose()?;
+        normalization.add_rule(&rule.path, eol, rule.encoding.as_deref())?;
+    }
+    Ok(normalization)
+}
+
+/// The paths forbidden by the `[[forbid]]` rules of `config`
+pub fn forbidden_paths(
+    config: &config::Config,
+) -> Result<libatomic::ForbiddenPaths, libatomic::forbidden::ForbiddenPathError> {
+    let mut forbidden = libatomic::ForbiddenPaths::new();
+    for rule in config.forbid.iter() {
+        forbidden.add_rule(&rule.path, rule.reason.as_deref())?;
+    }
+    Ok(forbidden)
+}
+
 #[cfg(unix)]
 pub fn max_files() -> std::io::Result<usize> {
     let n = if let Ok((n, _)) = rlimit::getrlimit(rlimit::Resource::NOFILE) {
@@ -77,8 +132,54 @@ impl Repository {
         dot_dir: &str,
     ) -> Result<Self, anyhow::Error> {
         let cur = Self::find_root_(cur, dot_dir)?;
-        let mut pristine_dir = cur.clone();
-        pristine_dir.push(PRISTINE_DIR);
+        let pristine = Self::open_pristine(&cur)?;
+        Self::open_with_pristine(cur, pristine)
+    }
+
+    /// Open the pristine of the repository whose dot directory is
+    /// `dot_dir`. Its mutable transactions take the lock of the
+    /// repository (see [`lock::WriteLock`]).
+    pub fn open_pristine(
+        dot_dir: &std::path::Path,
+    ) -> Result<
+        libatomic::pristine::sanakirja::Pristine,
+        libatomic::pristine::sanakirja::SanakirjaError,
+    > {
+        let pristine =
+            libatomic::pristine::sanakirja::Pristine::new(&dot_dir.join(PRISTINE_DIR).join("db"))?;
+        let guard = lock::WriteLock::new(lock::Lock::new(dot_dir.join(LOCK_FILE)));
+        Ok(pristine.with_write_guard(std::sync::Arc::new(guard)))
+    }
+
+    /// Find the repository containing `cur`, and take its lock for
+    /// `operation` before opening it. The lock is held until the returned
+    /// [`lock::RepositoryLock`] is dropped.
+    pub fn find_root_locked(
+        cur: Option<PathBuf>,
+        operation: &str,
+        options: &lock::LockOptions,
+    ) -> Result<(Self, lock::RepositoryLock), anyhow::Error> {
+        let cur = Self::find_root_(cur, DOT_DIR)?;
+        let lock = lock::Lock::new(cur.join(LOCK_FILE)).acquire(operation, options)?;
+        let pristine = Self::open_pristine(&cur)?;
+        Ok((Self::open_with_pristine(cur, pristine)?, lock))
+    }
+
+    /// Find the repository containing `cur`, but read from `pristine`
+    /// instead of the repository's own pristine, for example a
+    /// read-only snapshot of it.
+    pub fn find_root_with_pristine(
+        cur: Option<PathBuf>,
+        pristine: libatomic::pristine::sanakirja::Pristine,
+    ) -> Result<Self, anyhow::Error> {
+        let cur = Self::find_root_(cur, DOT_DIR)?;
+        Self::open_with_pristine(cur, pristine)
+    }
+
+    fn open_with_pristine(
+        cur: PathBuf,
+        pristine: libatomic::pristine::sanakirja::Pristine,
+    ) -> Result<Self, anyhow::Error> {
         let mut changes_dir = cur.clone();
         changes_dir.push(CHANGES_DIR);
         let mut working_copy_dir = cur.clone();
@@ -93,11 +194,16 @@ impl Repository {
         } else {
             config::Config::default()
         };
+        let normalization = normalization(&config)
+            .map_err(|e| anyhow::anyhow!("Invalid normalize rule in {:?}: {}", config_path, e))?;
+        forbidden_paths(&config)
+            .map_err(|e| anyhow::anyhow!("Invalid forbid rule in {:?}: {}", config_path, e))?;
         Ok(Repository {
-            pristine: libatomic::pristine::sanakirja::Pristine::new(&pristine_dir.join("db"))?,
+            pristine,
             working_copy: libatomic::working_copy::filesystem::FileSystem::from_root(
                 &working_copy_dir,
-            ),
+            )
+            .with_normalization(normalization),
             changes: libatomic::changestore::filesystem::FileSystem::from_root(
                 &working_copy_dir,
                 max_files()?,
@@ -141,7 +247,7 @@ impl Repository {
             writeln!(stderr, "Repository created at {}", cur.to_string_lossy())?;
 
             Ok(Repository {
-                pristine: libatomic::pristine::sanakirja::Pristine::new(&pristine_dir.join("db"))?,
+                pristine: Self::open_pristine(&cur.join(DOT_DIR))?,
                 working_copy: libatomic::working_copy::filesystem::FileSystem::from_root(&cur),
                 changes: libatomic::changestore::filesystem::FileSystem::from_root(
                     &cur,
@@ -163,6 +269,13 @@ impl Repository {
         )?;
         Ok(())
     }
+
+    /// The AI metadata registered by tools for the next recorded change.
+    pub fn attribution_hooks(&self) -> libatomic::attribution::AttributionHooks {
+        libatomic::attribution::AttributionHooks::new(
+            self.path.join(DOT_DIR).join(ATTRIBUTION_HOOK_FILE),
+        )
+    }
 }
 
 fn init_default_config(path: &std::path::Path, remote: Option<&str>) -> Result<(), anyhow::Error> {
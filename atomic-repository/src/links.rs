@@ -0,0 +1,466 @@
+//! External links attached to changes.
+//!
+//! Links associate changes with issues of external trackers (Jira,
+//! GitHub, GitLab…), with a typed relation: a change *fixes* an issue, or
+//! merely *relates to* it. Like notes, they live outside of the hashed
+//! change data, in `.atomic/links`, one JSON file per change laid out
+//! like change files (`AB/CDEF….json`) and holding all the links of the
+//! change.
+//!
+//! Each link records the issue key derived from its URL (`PROJ-123`,
+//! `org/repo#42`), so that the changes linked to an issue can be found
+//! from either its key or its URL.
+//!
+//! Links are only synchronised with remotes if the repository
+//! configuration sets `sync_links`. Concurrent edits of a link are
+//! resolved by keeping the most recently updated version, and removed
+//! links are kept as tombstones so that removals propagate.
+
+use chrono::{DateTime, Utc};
+use libatomic::pristine::{Base32, Hash};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// How a change relates to the target of a link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Relation {
+    /// The change fixes the issue.
+    Fixes,
+    /// The change is related to the issue, without fixing it.
+    RelatesTo,
+}
+
+impl Relation {
+    pub const ALL: [Relation; 2] = [Relation::Fixes, Relation::RelatesTo];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Relation::Fixes => "fixes",
+            Relation::RelatesTo => "relates-to",
+        }
+    }
+}
+
+impl std::fmt::Display for Relation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl std::str::FromStr for Relation {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(r) = Relation::ALL
+            .iter()
+            .find(|r| r.name() == s.replace('_', "-"))
+        {
+            Ok(*r)
+        } else {
+            anyhow::bail!(
+                "Unknown link relation {:?}, expected one of: fixes, relates-to",
+                s
+            )
+        }
+    }
+}
+
+/// A link from a change to an external URL.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Link {
+    /// Hash of the change, in base32.
+    pub change: String,
+    pub url: String,
+    pub relation: Relation,
+    /// Issue key derived from the URL, if it is a known tracker URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// Time of the last edit, used to resolve concurrent edits.
+    pub updated: DateTime<Utc>,
+    /// Whether this link is a removal tombstone.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+}
+
+impl Link {
+    /// Hash of the change this link is attached to.
+    pub fn hash(&self) -> Option<Hash> {
+        Hash::from_base32(self.change.as_bytes())
+    }
+
+    /// Whether this link points to `reference`, an issue key or a URL.
+    pub fn matches(&self, reference: &str) -> bool {
+        let reference = reference.trim();
+        if let Some(ref key) = self.key {
+            if key.eq_ignore_ascii_case(reference) {
+                return true;
+            }
+        }
+        self.url.trim_end_matches('/') == reference.trim_end_matches('/')
+    }
+}
+
+/// The issue key of a tracker URL: `PROJ-123` for Jira issues, and
+/// `org/repo#42` for GitHub and GitLab issues and pull or merge requests.
+pub fn issue_key(url: &str) -> Option<String> {
+    let (_, rest) = url.split_once("://")?;
+    let path = rest.split(|c| c == '?' || c == '#').next()?;
+    let segments: Vec<&str> = path.split('/').skip(1).filter(|s| !s.is_empty()).collect();
+    // Jira: `…/browse/PROJ-123`
+    if let Some(i) = segments.iter().position(|s| *s == "browse") {
+        let key = segments.get(i + 1)?;
+        return if is_jira_key(key) {
+            Some(key.to_string())
+        } else {
+            None
+        };
+    }
+    // GitHub: `org/repo/issues/42`, GitLab: `group/repo/-/issues/42`
+    let n = segments.len();
+    if n >= 4 && segments[n - 1].bytes().all(|c| c.is_ascii_digit()) {
+        if let "issues" | "pull" | "merge_requests" = segments[n - 2] {
+            let repo = if segments[n - 3] == "-" {
+                &segments[..n - 3]
+            } else {
+                &segments[..n - 2]
+            };
+            if repo.len() >= 2 {
+                return Some(format!("{}#{}", repo.join("/"), segments[n - 1]));
+            }
+        }
+    }
+    None
+}
+
+fn is_jira_key(key: &str) -> bool {
+    if let Some((project, number)) = key.rsplit_once('-') {
+        project.starts_with(|c: char| c.is_ascii_uppercase())
+            && project
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+            && !number.is_empty()
+            && number.bytes().all(|c| c.is_ascii_digit())
+    } else {
+        false
+    }
+}
+
+/// The links of a repository.
+#[derive(Debug, Clone)]
+pub struct Links {
+    dir: PathBuf,
+}
+
+impl Links {
+    /// Links stored in `dir` (usually `.atomic/links`).
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Links { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, hash: &Hash) -> PathBuf {
+        let h32 = hash.to_base32();
+        let (a, b) = h32.split_at(2);
+        let mut path = self.dir.join(a);
+        path.push(b);
+        path.set_extension("json");
+        path
+    }
+
+    fn read(&self, hash: &Hash) -> Result<Vec<Link>, anyhow::Error> {
+        read_file(&self.path(hash))
+    }
+
+    fn write(&self, hash: &Hash, links: &[Link]) -> Result<(), anyhow::Error> {
+        let path = self.path(hash);
+        let dir = path.parent().unwrap();
+        std::fs::create_dir_all(dir)?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(links)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// The links attached to `hash`.
+    pub fn get(&self, hash: &Hash) -> Result<Vec<Link>, anyhow::Error> {
+        let mut links = self.read(hash)?;
+        links.retain(|l| !l.deleted);
+        Ok(links)
+    }
+
+    /// Link `hash` to `url`, replacing the relation of a previous link to
+    /// the same URL.
+    pub fn add(
+        &self,
+        hash: &Hash,
+        url: &str,
+        relation: Relation,
+        author: Option<&str>,
+    ) -> Result<Link, anyhow::Error> {
+        if !url.contains("://") {
+            anyhow::bail!("Invalid link URL: {:?}", url)
+        }
+        let link = Link {
+            change: hash.to_base32(),
+            url: url.to_string(),
+            relation,
+            key: issue_key(url),
+            author: author.map(String::from),
+            updated: Utc::now(),
+            deleted: false,
+        };
+        let mut links = self.read(hash)?;
+        links.retain(|l| l.url != link.url);
+        links.push(link.clone());
+        self.write(hash, &links)?;
+        Ok(link)
+    }
+
+    /// Remove the link from `hash` to `url`. Returns `false` if there
+    /// was no such link.
+    pub fn remove(
+        &self,
+        hash: &Hash,
+        url: &str,
+        author: Option<&str>,
+    ) -> Result<bool, anyhow::Error> {
+        let mut links = self.read(hash)?;
+        let link = if let Some(l) = links.iter_mut().find(|l| l.url == url && !l.deleted) {
+            l
+        } else {
+            return Ok(false);
+        };
+        link.author = author.map(String::from);
+        link.updated = Utc::now();
+        link.deleted = true;
+        self.write(hash, &links)?;
+        Ok(true)
+    }
+
+    /// All links, including tombstones, updated strictly after `since`
+    /// if given. This is what gets sent to remotes.
+    pub fn all(&self, since: Option<DateTime<Utc>>) -> Result<Vec<Link>, anyhow::Error> {
+        let mut links = Vec::new();
+        let prefixes = match std::fs::read_dir(&self.dir) {
+            Ok(r) => r,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(links),
+            Err(e) => return Err(e.into()),
+        };
+        for prefix in prefixes {
+            let prefix = prefix?;
+            if !prefix.file_type()?.is_dir() {
+                continue;
+            }
+            for file in std::fs::read_dir(prefix.path())? {
+                let path = file?.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                    continue;
+                }
+                for link in read_file(&path)? {
+                    if since.map(|s| link.updated > s).unwrap_or(true) {
+                        links.push(link)
+                    }
+                }
+            }
+        }
+        links.sort_by(|a, b| {
+            a.updated
+                .cmp(&b.updated)
+                .then(a.change.cmp(&b.change))
+                .then(a.url.cmp(&b.url))
+        });
+        Ok(links)
+    }
+
+    /// The links that aren't removed.
+    pub fn list(&self) -> Result<Vec<Link>, anyhow::Error> {
+        let mut links = self.all(None)?;
+        links.retain(|l| !l.deleted);
+        Ok(links)
+    }
+
+    /// The links to `reference`, an issue key (`PROJ-123`) or a URL,
+    /// i.e. the changes linked to it.
+    pub fn find(&self, reference: &str) -> Result<Vec<Link>, anyhow::Error> {
+        let mut links = self.list()?;
+        links.retain(|l| l.matches(reference));
+        Ok(links)
+    }
+
+    /// Merge a link received from a remote, keeping the most recently
+    /// updated version. Returns whether the local link was replaced.
+    pub fn merge(&self, link: Link) -> Result<bool, anyhow::Error> {
+        let hash = if let Some(hash) = link.hash() {
+            hash
+        } else {
+            anyhow::bail!("Invalid change hash in link: {:?}", link.change)
+        };
+        let mut links = self.read(&hash)?;
+        if let Some(local) = links.iter_mut().find(|l| l.url == link.url) {
+            if local.updated >= link.updated {
+                return Ok(false);
+            }
+            *local = link
+        } else {
+            links.push(link)
+        }
+        self.write(&hash, &links)?;
+        Ok(true)
+    }
+
+    /// Merge several links, returning the number of links replaced.
+    pub fn merge_all<I: IntoIterator<Item = Link>>(
+        &self,
+        links: I,
+    ) -> Result<usize, anyhow::Error> {
+        let mut n = 0;
+        for link in links {
+            if self.merge(link)? {
+                n += 1
+            }
+        }
+        Ok(n)
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<Link>, anyhow::Error> {
+    match std::fs::read(path) {
+        Ok(buf) => Ok(serde_json::from_slice(&buf)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl crate::Repository {
+    /// The external links attached to the changes of this repository.
+    pub fn links(&self) -> Links {
+        Links::new(self.path.join(libatomic::DOT_DIR).join(crate::LINKS_DIR))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::hash;
+
+    #[test]
+    fn test_issue_key() {
+        assert_eq!(
+            issue_key("https://example.atlassian.net/browse/PROJ-123").as_deref(),
+            Some("PROJ-123")
+        );
+        assert_eq!(
+            issue_key("https://github.com/org/repo/issues/42").as_deref(),
+            Some("org/repo#42")
+        );
+        assert_eq!(
+            issue_key("https://github.com/org/repo/pull/7?x=1#discussion").as_deref(),
+            Some("org/repo#7")
+        );
+        assert_eq!(
+            issue_key("https://gitlab.com/group/sub/repo/-/issues/3").as_deref(),
+            Some("group/sub/repo#3")
+        );
+        assert_eq!(issue_key("https://example.com/browse/lowercase-1"), None);
+        assert_eq!(issue_key("https://example.com/docs/design"), None);
+        assert_eq!(issue_key("PROJ-123"), None);
+    }
+
+    #[test]
+    fn test_add_find_remove() {
+        let tmp = tempfile::tempdir().unwrap();
+        let links = Links::new(tmp.path());
+        let jira = "https://example.atlassian.net/browse/PROJ-123";
+        links
+            .add(&hash(0), jira, Relation::Fixes, Some("alice"))
+            .unwrap();
+        links
+            .add(&hash(1), jira, Relation::RelatesTo, None)
+            .unwrap();
+        links
+            .add(
+                &hash(1),
+                "https://github.com/org/repo/issues/42",
+                Relation::Fixes,
+                None,
+            )
+            .unwrap();
+        assert!(links
+            .add(&hash(2), "PROJ-1", Relation::Fixes, None)
+            .is_err());
+
+        // Linking again to the same URL replaces the relation.
+        links.add(&hash(1), jira, Relation::Fixes, None).unwrap();
+        assert_eq!(links.get(&hash(1)).unwrap().len(), 2);
+        assert!(links
+            .get(&hash(1))
+            .unwrap()
+            .iter()
+            .all(|l| l.relation == Relation::Fixes));
+
+        // Reverse lookups, by key or by URL.
+        let found = links.find("proj-123").unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(links.find(&format!("{}/", jira)).unwrap().len(), 2);
+        assert_eq!(links.find("org/repo#42").unwrap().len(), 1);
+
+        assert!(links.remove(&hash(0), jira, None).unwrap());
+        assert!(!links.remove(&hash(0), jira, None).unwrap());
+        assert_eq!(
+            links.find("PROJ-123").unwrap()[0].change,
+            hash(1).to_base32()
+        );
+        // The tombstone is still sent to remotes.
+        assert_eq!(
+            links
+                .all(None)
+                .unwrap()
+                .iter()
+                .filter(|l| l.deleted)
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_merge_keeps_latest() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let (a, b) = (Links::new(a.path()), Links::new(b.path()));
+        let url = "https://github.com/org/repo/issues/1";
+        let old = a.add(&hash(1), url, Relation::RelatesTo, None).unwrap();
+        let new = Link {
+            relation: Relation::Fixes,
+            updated: old.updated + chrono::Duration::seconds(1),
+            ..old.clone()
+        };
+        assert!(b.merge(new).unwrap());
+
+        assert_eq!(a.merge_all(b.all(None).unwrap()).unwrap(), 1);
+        assert_eq!(a.get(&hash(1)).unwrap()[0].relation, Relation::Fixes);
+        // Merging an older link back is a no-op.
+        assert!(!b.merge(old).unwrap());
+        assert_eq!(b.get(&hash(1)).unwrap()[0].relation, Relation::Fixes);
+    }
+
+    #[test]
+    fn test_relation_names() {
+        for r in Relation::ALL {
+            assert_eq!(r.name().parse::<Relation>().unwrap(), r);
+            assert_eq!(
+                serde_json::to_string(&r).unwrap(),
+                format!("\"{}\"", r.name())
+            );
+        }
+        assert_eq!(
+            "relates_to".parse::<Relation>().unwrap(),
+            Relation::RelatesTo
+        );
+        assert!("blocks".parse::<Relation>().is_err());
+    }
+}
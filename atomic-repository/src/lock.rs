@@ -0,0 +1,497 @@
+//! Advisory lock of a repository
+//!
+//! The CLI and the API server can work on the same repository at the same
+//! time, but only one write transaction can be open on a pristine, and the
+//! others fail with errors that don't say who holds it. Commands and
+//! requests that write take the lock of the repository first:
+//!
+//! - `.atomic/lock` is locked exclusively by the holder (an OS file lock,
+//!   released even if the holder dies), which writes its PID and operation
+//!   in it;
+//! - processes waiting for the lock register in `.atomic/lock.queue`, one
+//!   file per waiter, also locked while it waits, and take the lock in the
+//!   order they arrived.
+//!
+//! Waiting gives up after a timeout (`ATOMIC_LOCK_TIMEOUT`, in seconds, 30
+//! by default), with an error naming the holder and the waiters.
+//!
+//! The pristines opened by [`crate::Repository`] take the lock (as
+//! [`WriteLock`]) whenever a mutable transaction starts, and release it
+//! when the transaction ends, so every writer is serialised even if it
+//! didn't take the lock itself. A transaction started while this process
+//! already holds the lock shares it instead of waiting for itself.
+
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+/// Default time to wait for the lock
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default time between two attempts to take the lock
+const DEFAULT_POLL: Duration = Duration::from_millis(50);
+
+/// A process holding, or waiting for, the lock
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockHolder {
+    pub pid: u32,
+    /// What the process does, e.g. `pull` or `apply`
+    pub operation: String,
+    /// When the process took the lock, or started waiting
+    pub since: DateTime<Utc>,
+}
+
+impl LockHolder {
+    fn current(operation: &str) -> Self {
+        LockHolder {
+            pid: std::process::id(),
+            operation: operation.to_string(),
+            since: Utc::now(),
+        }
+    }
+}
+
+impl std::fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "PID {} ({}) since {}",
+            self.pid,
+            self.operation,
+            self.since.format("%Y-%m-%d %H:%M:%S UTC")
+        )
+    }
+}
+
+/// How long to wait for the lock
+#[derive(Debug, Clone)]
+pub struct LockOptions {
+    /// Time to wait for the lock, zero to fail at once if it is held
+    pub timeout: Duration,
+    /// Time between two attempts to take the lock
+    pub poll: Duration,
+}
+
+impl Default for LockOptions {
+    fn default() -> Self {
+        LockOptions {
+            timeout: DEFAULT_TIMEOUT,
+            poll: DEFAULT_POLL,
+        }
+    }
+}
+
+impl LockOptions {
+    /// Read the timeout, in seconds, from `ATOMIC_LOCK_TIMEOUT`
+    pub fn from_env() -> Self {
+        LockOptions {
+            timeout: std::env::var("ATOMIC_LOCK_TIMEOUT")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .and_then(|s| Duration::try_from_secs_f64(s).ok())
+                .unwrap_or(DEFAULT_TIMEOUT),
+            ..LockOptions::default()
+        }
+    }
+
+    /// Fail at once if the lock is held
+    pub fn no_wait() -> Self {
+        LockOptions {
+            timeout: Duration::ZERO,
+            ..LockOptions::default()
+        }
+    }
+}
+
+/// The lock is held by another process, and wasn't released in time
+#[derive(Debug, Clone)]
+pub struct Locked {
+    /// The holder, if it could be read
+    pub holder: Option<LockHolder>,
+    /// The other processes waiting for the lock, first first
+    pub waiting: Vec<LockHolder>,
+    pub waited: Duration,
+}
+
+impl std::fmt::Display for Locked {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (&self.holder, self.waiting.first()) {
+            (Some(holder), _) => write!(f, "Repository locked by {}", holder)?,
+            // Free, but promised to the first waiter.
+            (None, Some(first)) => write!(f, "Repository lock reserved for {}", first)?,
+            (None, None) => write!(f, "Repository locked by another process")?,
+        }
+        if self.holder.is_some() && !self.waiting.is_empty() {
+            write!(f, ", {} other(s) waiting", self.waiting.len())?;
+        } else if self.waiting.len() > 1 {
+            write!(f, ", {} other(s) waiting", self.waiting.len() - 1)?;
+        }
+        if self.waited.is_zero() {
+            Ok(())
+        } else {
+            write!(
+                f,
+                " (gave up after {:.1}s, set ATOMIC_LOCK_TIMEOUT to wait longer)",
+                self.waited.as_secs_f64()
+            )
+        }
+    }
+}
+
+impl std::error::Error for Locked {}
+
+/// The lock, held until dropped
+#[derive(Debug)]
+pub struct RepositoryLock {
+    _file: Arc<LockFile>,
+    holder: LockHolder,
+}
+
+impl RepositoryLock {
+    pub fn holder(&self) -> &LockHolder {
+        &self.holder
+    }
+}
+
+/// The locked file, unlocked when the last of the explicit lock and the
+/// transactions sharing it is dropped
+#[derive(Debug)]
+struct LockFile(File);
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        // Don't leave a stale holder behind, for diagnostics.
+        let _ = self.0.set_len(0);
+        let _ = self.0.unlock();
+    }
+}
+
+/// The locks held by this process, by canonical path
+fn held() -> &'static Mutex<HashMap<PathBuf, Weak<LockFile>>> {
+    static HELD: std::sync::OnceLock<Mutex<HashMap<PathBuf, Weak<LockFile>>>> =
+        std::sync::OnceLock::new();
+    HELD.get_or_init(Default::default)
+}
+
+/// A place in the queue of waiters, left when dropped
+struct Ticket {
+    path: PathBuf,
+    _file: File,
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Contents of a lock or ticket file, `None` if empty or being written
+fn read_holder(file: &mut File) -> Option<LockHolder> {
+    let mut buf = Vec::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_end(&mut buf).ok()?;
+    serde_json::from_slice(&buf).ok()
+}
+
+/// The lock of a repository
+#[derive(Debug, Clone)]
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    /// Lock at `path` (usually `.atomic/lock`)
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Lock { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn queue_dir(&self) -> PathBuf {
+        self.path.with_extension("queue")
+    }
+
+    fn open(&self) -> Result<File, anyhow::Error> {
+        Ok(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)?)
+    }
+
+    /// The process holding the lock, `None` if the lock is free (or its
+    /// holder is still writing its information)
+    pub fn holder(&self) -> Result<Option<LockHolder>, anyhow::Error> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if file.try_lock_shared().is_ok() {
+            file.unlock()?;
+            return Ok(None);
+        }
+        Ok(read_holder(&mut file))
+    }
+
+    /// The tickets of the live waiters, in order of arrival. The tickets
+    /// of waiters that died are removed.
+    fn tickets(&self) -> Result<Vec<(PathBuf, Option<LockHolder>)>, anyhow::Error> {
+        let mut paths = match std::fs::read_dir(self.queue_dir()) {
+            Ok(dir) => dir
+                .map(|e| Ok(e?.path()))
+                .collect::<Result<Vec<_>, std::io::Error>>()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        paths.retain(|p| p.extension().is_some_and(|e| e == "json"));
+        paths.sort();
+        let mut tickets = Vec::with_capacity(paths.len());
+        for path in paths {
+            let mut file = match File::open(&path) {
+                Ok(file) => file,
+                // Left the queue meanwhile.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if file.try_lock_exclusive().is_ok() {
+                // Nobody holds the ticket anymore.
+                std::fs::remove_file(&path).unwrap_or(());
+                continue;
+            }
+            tickets.push((path, read_holder(&mut file)))
+        }
+        Ok(tickets)
+    }
+
+    /// The processes waiting for the lock, in order of arrival
+    pub fn waiting(&self) -> Result<Vec<LockHolder>, anyhow::Error> {
+        Ok(self
+            .tickets()?
+            .into_iter()
+            .filter_map(|(_, holder)| holder)
+            .collect())
+    }
+
+    /// Join the queue. The ticket is locked before it appears in the
+    /// queue, so that it isn't mistaken for the ticket of a dead waiter.
+    fn enqueue(&self, holder: &LockHolder) -> Result<Ticket, anyhow::Error> {
+        let dir = self.queue_dir();
+        std::fs::create_dir_all(&dir)?;
+        let name = format!(
+            "{:020}-{}",
+            holder.since.timestamp_nanos_opt().unwrap_or_default(),
+            holder.pid
+        );
+        let tmp = dir.join(&name).with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.lock_exclusive()?;
+        file.write_all(&serde_json::to_vec(holder)?)?;
+        let path = tmp.with_extension("json");
+        std::fs::rename(&tmp, &path)?;
+        Ok(Ticket { path, _file: file })
+    }
+
+    fn hold(&self, mut file: File, holder: LockHolder) -> Result<RepositoryLock, anyhow::Error> {
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&serde_json::to_vec(&holder)?)?;
+        file.flush()?;
+        let file = Arc::new(LockFile(file));
+        let mut held = held().lock().unwrap();
+        held.retain(|_, file| file.strong_count() > 0);
+        held.insert(std::fs::canonicalize(&self.path)?, Arc::downgrade(&file));
+        Ok(RepositoryLock {
+            _file: file,
+            holder,
+        })
+    }
+
+    /// The lock file, if this process holds the lock
+    fn held(&self) -> Option<Arc<LockFile>> {
+        let path = std::fs::canonicalize(&self.path).ok()?;
+        held().lock().unwrap().get(&path)?.upgrade()
+    }
+
+    fn locked(&self, start: Instant, ticket: Option<&Ticket>) -> anyhow::Error {
+        let waiting = self
+            .tickets()
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(path, _)| Some(path) != ticket.map(|t| &t.path))
+            .filter_map(|(_, holder)| holder)
+            .collect();
+        Locked {
+            holder: self.holder().unwrap_or(None),
+            waiting,
+            waited: start.elapsed(),
+        }
+        .into()
+    }
+
+    /// Take the lock for `operation`, waiting for the holder and the
+    /// processes that were waiting before, up to the timeout of
+    /// `options`. Fails with [`Locked`] on timeout.
+    pub fn acquire(
+        &self,
+        operation: &str,
+        options: &LockOptions,
+    ) -> Result<RepositoryLock, anyhow::Error> {
+        let start = Instant::now();
+        let holder = LockHolder::current(operation);
+        let file = self.open()?;
+        if self.tickets()?.is_empty() && file.try_lock_exclusive().is_ok() {
+            return self.hold(file, holder);
+        }
+        if options.timeout.is_zero() {
+            return Err(self.locked(start, None));
+        }
+        let ticket = self.enqueue(&holder)?;
+        log::debug!("waiting for the lock at {:?}", self.path);
+        loop {
+            let first = self
+                .tickets()?
+                .first()
+                .is_none_or(|(path, _)| *path == ticket.path);
+            if first && file.try_lock_exclusive().is_ok() {
+                return self.hold(file, holder);
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= options.timeout {
+                return Err(self.locked(start, Some(&ticket)));
+            }
+            std::thread::sleep(options.poll.min(options.timeout - elapsed));
+        }
+    }
+}
+
+/// The [`WriteGuard`](libatomic::pristine::sanakirja::WriteGuard) of the
+/// pristines of a repository: takes the lock for the duration of each
+/// mutable transaction, unless this process already holds it.
+pub struct WriteLock {
+    lock: Lock,
+}
+
+impl WriteLock {
+    pub fn new(lock: Lock) -> Self {
+        WriteLock { lock }
+    }
+}
+
+impl libatomic::pristine::sanakirja::WriteGuard for WriteLock {
+    fn acquire(
+        &self,
+    ) -> Result<Box<dyn std::any::Any + Send + Sync>, libatomic::pristine::sanakirja::WriteGuardError>
+    {
+        if let Some(file) = self.lock.held() {
+            return Ok(Box::new(file));
+        }
+        let lock = self.lock.acquire("write", &LockOptions::from_env())?;
+        Ok(Box::new(lock))
+    }
+}
+
+impl crate::Repository {
+    /// The advisory lock of this repository, taken by the operations that
+    /// write to it.
+    pub fn lock(&self) -> Lock {
+        Lock::new(self.path.join(libatomic::DOT_DIR).join(crate::LOCK_FILE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(timeout: u64) -> LockOptions {
+        LockOptions {
+            timeout: Duration::from_millis(timeout),
+            poll: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn test_lock_held() {
+        let tmp = tempfile::tempdir().unwrap();
+        let lock = Lock::new(tmp.path().join("lock"));
+        assert_eq!(lock.holder().unwrap(), None);
+
+        let held = lock.acquire("pull", &options(0)).unwrap();
+        let holder = lock.holder().unwrap().unwrap();
+        assert_eq!(holder.pid, std::process::id());
+        assert_eq!(holder.operation, "pull");
+        assert_eq!(held.holder(), &holder);
+
+        let err = lock.acquire("apply", &options(20)).unwrap_err();
+        let locked = err.downcast_ref::<Locked>().unwrap();
+        assert_eq!(locked.holder.as_ref(), Some(&holder));
+        assert!(locked.waited >= Duration::from_millis(20));
+        assert!(err.to_string().starts_with(&format!(
+            "Repository locked by PID {} (pull)",
+            std::process::id()
+        )));
+        // The ticket of the waiter was removed.
+        assert!(lock.waiting().unwrap().is_empty());
+
+        drop(held);
+        assert_eq!(lock.holder().unwrap(), None);
+        lock.acquire("apply", &options(0)).unwrap();
+    }
+
+    #[test]
+    fn test_lock_queue() {
+        let tmp = tempfile::tempdir().unwrap();
+        let lock = Lock::new(tmp.path().join("lock"));
+        let held = lock.acquire("record", &options(0)).unwrap();
+        let waiter = std::thread::spawn({
+            let lock = lock.clone();
+            move || lock.acquire("apply", &options(10_000)).map(|_| ())
+        });
+        while lock.waiting().unwrap().is_empty() {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(lock.waiting().unwrap()[0].operation, "apply");
+        // A newcomer that doesn't wait sees the waiter.
+        let err = lock.acquire("push", &options(0)).unwrap_err();
+        assert_eq!(err.downcast_ref::<Locked>().unwrap().waiting.len(), 1);
+        drop(held);
+        waiter.join().unwrap().unwrap();
+        assert!(lock.waiting().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_lock_write_txn() {
+        use libatomic::MutTxnT;
+        let fixture = crate::fixtures::Fixture::new().unwrap();
+        let lock = fixture.repo.lock();
+
+        // A transaction takes the lock until it ends.
+        let txn = fixture.repo.pristine.mut_txn_begin().unwrap();
+        assert_eq!(lock.holder().unwrap().unwrap().operation, "write");
+        assert!(lock.acquire("pull", &options(0)).is_err());
+        txn.commit().unwrap();
+        assert_eq!(lock.holder().unwrap(), None);
+
+        // It shares the lock already held by this process.
+        let (repo, held) = crate::Repository::find_root_locked(
+            Some(fixture.path().to_path_buf()),
+            "pull",
+            &options(0),
+        )
+        .unwrap();
+        let txn = repo.pristine.mut_txn_begin().unwrap();
+        txn.commit().unwrap();
+        assert_eq!(lock.holder().unwrap().unwrap().operation, "pull");
+        drop(held);
+        assert_eq!(lock.holder().unwrap(), None);
+    }
+}
@@ -0,0 +1,370 @@
+//! Sidecar notes attached to changes.
+//!
+//! Notes are mutable annotations keyed by change hash (ticket links,
+//! post-hoc context…). They live in `.atomic/notes`, outside of the
+//! hashed change data, so editing them never rewrites history. Each note
+//! is a JSON file laid out like change files (`AB/CDEF….json`).
+//!
+//! Notes are synchronised with remotes alongside identities, with the
+//! remotes that announce it. Each edit increments the version of a note,
+//! and a remote keeps the notes it receives only if they are of a newer
+//! version than its own, so concurrent edits are resolved by keeping the
+//! first one pushed; pulls replace local notes with the remote's version
+//! unless they are newer. Deleted notes are kept as tombstones so that
+//! deletions propagate.
+//!
+//! The versions of the notes last exchanged with each remote are recorded
+//! in `.atomic/notes/synced`, so that pushes only send the notes edited
+//! since.
+
+use chrono::{DateTime, Utc};
+use libatomic::pristine::{Base32, Hash};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Directory of the sync records, in the notes directory.
+const SYNCED_DIR: &str = "synced";
+
+/// A note attached to a change.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Note {
+    /// Hash of the change, in base32.
+    pub change: String,
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// Time of the last edit, by the clock of the repository that stored
+    /// this version.
+    pub updated: DateTime<Utc>,
+    /// Number of edits of this note, used to resolve concurrent edits.
+    #[serde(default)]
+    pub version: u64,
+    /// Whether this note is a deletion tombstone.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+}
+
+impl Note {
+    /// Hash of the change this note is attached to.
+    pub fn hash(&self) -> Option<Hash> {
+        Hash::from_base32(self.change.as_bytes())
+    }
+}
+
+/// The notes of a repository.
+#[derive(Debug, Clone)]
+pub struct Notes {
+    dir: PathBuf,
+}
+
+impl Notes {
+    /// Notes stored in `dir` (usually `.atomic/notes`).
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Notes { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, hash: &Hash) -> PathBuf {
+        let h32 = hash.to_base32();
+        let (a, b) = h32.split_at(2);
+        let mut path = self.dir.join(a);
+        path.push(b);
+        path.set_extension("json");
+        path
+    }
+
+    fn read(&self, hash: &Hash) -> Result<Option<Note>, anyhow::Error> {
+        match std::fs::read(self.path(hash)) {
+            Ok(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn next_version(&self, hash: &Hash) -> Result<u64, anyhow::Error> {
+        Ok(self.read(hash)?.map_or(0, |n| n.version) + 1)
+    }
+
+    fn write(&self, note: &Note) -> Result<(), anyhow::Error> {
+        let hash = if let Some(hash) = note.hash() {
+            hash
+        } else {
+            anyhow::bail!("Invalid change hash in note: {:?}", note.change)
+        };
+        let path = self.path(&hash);
+        let dir = path.parent().unwrap();
+        std::fs::create_dir_all(dir)?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(note)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// The note attached to `hash`, if any.
+    pub fn get(&self, hash: &Hash) -> Result<Option<Note>, anyhow::Error> {
+        Ok(self.read(hash)?.filter(|n| !n.deleted))
+    }
+
+    /// Attach `text` to `hash`, replacing the previous note.
+    pub fn set(
+        &self,
+        hash: &Hash,
+        text: &str,
+        author: Option<&str>,
+    ) -> Result<Note, anyhow::Error> {
+        let note = Note {
+            change: hash.to_base32(),
+            text: text.to_string(),
+            author: author.map(String::from),
+            updated: Utc::now(),
+            version: self.next_version(hash)?,
+            deleted: false,
+        };
+        self.write(&note)?;
+        Ok(note)
+    }
+
+    /// Delete the note attached to `hash`. Returns `false` if there
+    /// was no such note.
+    pub fn remove(&self, hash: &Hash, author: Option<&str>) -> Result<bool, anyhow::Error> {
+        if self.get(hash)?.is_none() {
+            return Ok(false);
+        }
+        self.write(&Note {
+            change: hash.to_base32(),
+            text: String::new(),
+            author: author.map(String::from),
+            updated: Utc::now(),
+            version: self.next_version(hash)?,
+            deleted: true,
+        })?;
+        Ok(true)
+    }
+
+    /// All notes, including tombstones, updated strictly after `since`
+    /// if given. This is what gets sent to remotes.
+    pub fn all(&self, since: Option<DateTime<Utc>>) -> Result<Vec<Note>, anyhow::Error> {
+        let mut notes = Vec::new();
+        let prefixes = match std::fs::read_dir(&self.dir) {
+            Ok(r) => r,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(notes),
+            Err(e) => return Err(e.into()),
+        };
+        for prefix in prefixes {
+            let prefix = prefix?;
+            if !prefix.file_type()?.is_dir() || prefix.file_name() == SYNCED_DIR {
+                continue;
+            }
+            for file in std::fs::read_dir(prefix.path())? {
+                let path = file?.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                    continue;
+                }
+                let note: Note = serde_json::from_slice(&std::fs::read(&path)?)?;
+                if since.map(|s| note.updated > s).unwrap_or(true) {
+                    notes.push(note)
+                }
+            }
+        }
+        notes.sort_by(|a, b| a.updated.cmp(&b.updated).then(a.change.cmp(&b.change)));
+        Ok(notes)
+    }
+
+    /// The notes that aren't deleted.
+    pub fn list(&self) -> Result<Vec<Note>, anyhow::Error> {
+        let mut notes = self.all(None)?;
+        notes.retain(|n| !n.deleted);
+        Ok(notes)
+    }
+
+    /// Merge a note pushed by a client, keeping it only if it is of a
+    /// newer version than the local one. The version kept is stamped with
+    /// the local time, whatever the clock of the client. Returns whether
+    /// the local note was replaced.
+    pub fn merge(&self, mut note: Note) -> Result<bool, anyhow::Error> {
+        let hash = note_hash(&note)?;
+        if let Some(local) = self.read(&hash)? {
+            if local.version >= note.version {
+                return Ok(false);
+            }
+        }
+        note.updated = Utc::now();
+        self.write(&note)?;
+        Ok(true)
+    }
+
+    /// Merge several notes pushed by a client, returning the number of
+    /// notes replaced.
+    pub fn merge_all<I: IntoIterator<Item = Note>>(
+        &self,
+        notes: I,
+    ) -> Result<usize, anyhow::Error> {
+        let mut n = 0;
+        for note in notes {
+            if self.merge(note)? {
+                n += 1
+            }
+        }
+        Ok(n)
+    }
+
+    /// Merge the notes pulled from `remote`, which replace the local
+    /// notes unless these are of a newer version, and record them as
+    /// synced with `remote`. Returns the number of notes replaced.
+    pub fn pull_all(&self, remote: &str, notes: Vec<Note>) -> Result<usize, anyhow::Error> {
+        let mut n = 0;
+        for note in notes.iter() {
+            let hash = note_hash(note)?;
+            let local = self.read(&hash)?;
+            if local
+                .as_ref()
+                .map_or(true, |l| l.version <= note.version && l != note)
+            {
+                self.write(note)?;
+                n += 1
+            }
+        }
+        self.mark_synced(remote, &notes)?;
+        Ok(n)
+    }
+
+    fn synced_path(&self, remote: &str) -> PathBuf {
+        // Remote names are URLs or paths, not file names.
+        let mut hasher = libatomic::pristine::Hasher::default();
+        hasher.update(remote.as_bytes());
+        let mut path = self.dir.join(SYNCED_DIR).join(hasher.finish().to_base32());
+        path.set_extension("json");
+        path
+    }
+
+    fn synced(&self, remote: &str) -> Result<HashMap<String, u64>, anyhow::Error> {
+        match std::fs::read(self.synced_path(remote)) {
+            Ok(buf) => Ok(serde_json::from_slice(&buf)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The notes (including tombstones) edited since they were last
+    /// exchanged with `remote`. This is what gets pushed to remotes.
+    pub fn unsynced(&self, remote: &str) -> Result<Vec<Note>, anyhow::Error> {
+        let synced = self.synced(remote)?;
+        let mut notes = self.all(None)?;
+        notes.retain(|n| synced.get(&n.change) != Some(&n.version));
+        Ok(notes)
+    }
+
+    /// Record that `remote` has `notes`.
+    pub fn mark_synced(&self, remote: &str, notes: &[Note]) -> Result<(), anyhow::Error> {
+        if notes.is_empty() {
+            return Ok(());
+        }
+        let mut synced = self.synced(remote)?;
+        for note in notes {
+            synced.insert(note.change.clone(), note.version);
+        }
+        let path = self.synced_path(remote);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&synced)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+fn note_hash(note: &Note) -> Result<Hash, anyhow::Error> {
+    if let Some(hash) = note.hash() {
+        Ok(hash)
+    } else {
+        anyhow::bail!("Invalid change hash in note: {:?}", note.change)
+    }
+}
+
+impl crate::Repository {
+    /// The notes attached to the changes of this repository.
+    pub fn notes(&self) -> Notes {
+        Notes::new(self.path.join(libatomic::DOT_DIR).join(crate::NOTES_DIR))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::hash;
+
+    #[test]
+    fn test_set_get_remove() {
+        let tmp = tempfile::tempdir().unwrap();
+        let notes = Notes::new(tmp.path());
+        assert!(notes.get(&hash(0)).unwrap().is_none());
+
+        notes
+            .set(&hash(0), "Fixes TICKET-1", Some("alice"))
+            .unwrap();
+        let note = notes.get(&hash(0)).unwrap().unwrap();
+        assert_eq!(note.text, "Fixes TICKET-1");
+        assert_eq!(notes.list().unwrap().len(), 1);
+
+        assert!(notes.remove(&hash(0), None).unwrap());
+        assert!(notes.get(&hash(0)).unwrap().is_none());
+        assert!(notes.list().unwrap().is_empty());
+        // The tombstone is still sent to remotes.
+        assert!(notes.all(None).unwrap()[0].deleted);
+    }
+
+    #[test]
+    fn test_merge_keeps_newest_version() {
+        let a = tempfile::tempdir().unwrap();
+        let b = tempfile::tempdir().unwrap();
+        let (a, b) = (Notes::new(a.path()), Notes::new(b.path()));
+        let old = a.set(&hash(1), "old", None).unwrap();
+        assert_eq!(old.version, 1);
+        let new = a.set(&hash(1), "new", None).unwrap();
+        assert_eq!(new.version, 2);
+        assert!(b.merge(new).unwrap());
+
+        // An older version, even with a later time, is a no-op.
+        let stale = Note {
+            text: "stale".to_string(),
+            updated: old.updated + chrono::Duration::days(365),
+            ..old
+        };
+        assert!(!b.merge(stale).unwrap());
+        assert_eq!(b.get(&hash(1)).unwrap().unwrap().text, "new");
+
+        // A concurrent edit of the same version loses on the remote, and
+        // the remote's version wins on the next pull.
+        let c = tempfile::tempdir().unwrap();
+        let c = Notes::new(c.path());
+        c.set(&hash(1), "first", None).unwrap();
+        let concurrent = c.set(&hash(1), "concurrent", None).unwrap();
+        assert!(!b.merge(concurrent).unwrap());
+        assert_eq!(c.pull_all("b", b.all(None).unwrap()).unwrap(), 1);
+        assert_eq!(c.get(&hash(1)).unwrap().unwrap().text, "new");
+    }
+
+    #[test]
+    fn test_unsynced() {
+        let tmp = tempfile::tempdir().unwrap();
+        let notes = Notes::new(tmp.path());
+        notes.set(&hash(2), "a", None).unwrap();
+        notes.set(&hash(3), "b", None).unwrap();
+        let pending = notes.unsynced("origin").unwrap();
+        assert_eq!(pending.len(), 2);
+        notes.mark_synced("origin", &pending).unwrap();
+        assert!(notes.unsynced("origin").unwrap().is_empty());
+        // Other remotes are tracked separately.
+        assert_eq!(notes.unsynced("backup").unwrap().len(), 2);
+        // The sync records aren't notes.
+        assert_eq!(notes.all(None).unwrap().len(), 2);
+
+        notes.set(&hash(2), "a, edited", None).unwrap();
+        let pending = notes.unsynced("origin").unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].text, "a, edited");
+    }
+}
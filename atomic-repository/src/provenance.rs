@@ -0,0 +1,204 @@
+//! Where changes came from, and where they were pushed.
+//!
+//! Changes travel between mirrors: pulled from one remote, pushed to
+//! another, pulled again elsewhere. The provenance of each change, kept in
+//! `.atomic/provenance` (a JSON file), records the remote it was first
+//! pulled from, when it was first seen in this repository, and the remotes
+//! it was pushed to, so that distribution issues can be traced across a
+//! fleet of mirrors.
+//!
+//! Changes recorded locally have no source, and are only tracked once
+//! pushed.
+
+use chrono::{DateTime, Utc};
+use libatomic::pristine::{Base32, Hash};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The travels of a change.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProvenanceRecord {
+    /// Hash of the change, in base32.
+    pub hash: String,
+    /// Remote the change was first pulled from, `None` if it was here
+    /// before it was pulled, e.g. recorded locally.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// When this repository first saw the change, by pull or push.
+    pub first_seen: DateTime<Utc>,
+    /// Remotes the change was pushed to, with the time of the first push.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pushed_to: BTreeMap<String, DateTime<Utc>>,
+}
+
+impl ProvenanceRecord {
+    fn new(hash: &Hash, source: Option<&str>, now: DateTime<Utc>) -> Self {
+        ProvenanceRecord {
+            hash: hash.to_base32(),
+            source: source.map(|s| s.to_string()),
+            first_seen: now,
+            pushed_to: BTreeMap::new(),
+        }
+    }
+
+    pub fn hash(&self) -> Option<Hash> {
+        Hash::from_base32(self.hash.as_bytes())
+    }
+}
+
+/// The provenance of the changes of a repository.
+#[derive(Debug, Clone)]
+pub struct Provenance {
+    path: PathBuf,
+}
+
+impl Provenance {
+    /// Provenance stored in `path` (usually `.atomic/provenance`).
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Provenance { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read(&self) -> Result<Vec<ProvenanceRecord>, anyhow::Error> {
+        match std::fs::read(&self.path) {
+            Ok(buf) => Ok(serde_json::from_slice(&buf)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, records: &[ProvenanceRecord]) -> Result<(), anyhow::Error> {
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(records)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// All the records, in the order the changes were first seen.
+    pub fn list(&self) -> Result<Vec<ProvenanceRecord>, anyhow::Error> {
+        self.read()
+    }
+
+    pub fn get(&self, hash: &Hash) -> Result<Option<ProvenanceRecord>, anyhow::Error> {
+        let hash = hash.to_base32();
+        Ok(self.read()?.into_iter().find(|r| r.hash == hash))
+    }
+
+    /// Update the records of `hashes` with `update`, creating the missing
+    /// ones with `source`.
+    fn update<'a, I: IntoIterator<Item = &'a Hash>>(
+        &self,
+        hashes: I,
+        source: Option<&str>,
+        now: DateTime<Utc>,
+        update: impl Fn(&mut ProvenanceRecord),
+    ) -> Result<(), anyhow::Error> {
+        let mut records = self.read()?;
+        let mut index: BTreeMap<String, usize> = records
+            .iter()
+            .enumerate()
+            .map(|(i, r)| (r.hash.clone(), i))
+            .collect();
+        let mut modified = false;
+        for hash in hashes {
+            let key = hash.to_base32();
+            let i = match index.get(&key) {
+                Some(&i) => i,
+                None => {
+                    records.push(ProvenanceRecord::new(hash, source, now));
+                    index.insert(key, records.len() - 1);
+                    modified = true;
+                    records.len() - 1
+                }
+            };
+            let before = records[i].clone();
+            update(&mut records[i]);
+            modified |= records[i] != before;
+        }
+        if modified {
+            self.write(&records)?;
+        }
+        Ok(())
+    }
+
+    /// Record that `hashes` were pulled from `remote`. Changes that were
+    /// already known keep their source.
+    pub fn record_pull<'a, I: IntoIterator<Item = &'a Hash>>(
+        &self,
+        remote: &str,
+        hashes: I,
+        now: DateTime<Utc>,
+    ) -> Result<(), anyhow::Error> {
+        self.update(hashes, Some(remote), now, |_| {})
+    }
+
+    /// Record that `hashes` were pushed to `remote`.
+    pub fn record_push<'a, I: IntoIterator<Item = &'a Hash>>(
+        &self,
+        remote: &str,
+        hashes: I,
+        now: DateTime<Utc>,
+    ) -> Result<(), anyhow::Error> {
+        self.update(hashes, None, now, |r| {
+            r.pushed_to.entry(remote.to_string()).or_insert(now);
+        })
+    }
+}
+
+impl crate::Repository {
+    /// Where the changes of this repository came from, and where they
+    /// were pushed.
+    pub fn provenance(&self) -> Provenance {
+        Provenance::new(
+            self.path
+                .join(libatomic::DOT_DIR)
+                .join(crate::PROVENANCE_FILE),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::hash;
+    use chrono::{Duration, TimeZone};
+
+    #[test]
+    fn test_pull_then_push() {
+        let tmp = tempfile::tempdir().unwrap();
+        let provenance = Provenance::new(tmp.path().join("provenance"));
+        let t0 = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let t1 = t0 + Duration::seconds(60);
+        assert!(provenance.list().unwrap().is_empty());
+
+        provenance
+            .record_pull("origin", &[hash(0), hash(1)], t0)
+            .unwrap();
+        // Pulling again from another remote keeps the first source.
+        provenance.record_pull("backup", &[hash(1)], t1).unwrap();
+        // A local change, pushed twice to the same mirror.
+        provenance
+            .record_push("mirror", &[hash(1), hash(2)], t1)
+            .unwrap();
+        provenance
+            .record_push("mirror", &[hash(2)], t1 + Duration::seconds(1))
+            .unwrap();
+
+        let records = provenance.list().unwrap();
+        assert_eq!(records.len(), 3);
+        let one = provenance.get(&hash(1)).unwrap().unwrap();
+        assert_eq!(one.source.as_deref(), Some("origin"));
+        assert_eq!(one.first_seen, t0);
+        assert_eq!(one.pushed_to.get("mirror"), Some(&t1));
+        let two = provenance.get(&hash(2)).unwrap().unwrap();
+        assert_eq!(two.source, None);
+        assert_eq!(two.first_seen, t1);
+        assert_eq!(two.pushed_to.get("mirror"), Some(&t1));
+        assert_eq!(records[2].hash(), Some(hash(2)));
+        assert_eq!(provenance.get(&hash(3)).unwrap(), None);
+    }
+}
@@ -0,0 +1,195 @@
+//! Nodes quarantined by failed pulls.
+//!
+//! When a change or tag fails to apply during a pull (corrupt change
+//! file, missing dependency…), the pull quarantines it instead of
+//! aborting, and goes on with the nodes that don't depend on it. The
+//! quarantined nodes of each channel are kept in `.atomic/quarantine`, a
+//! JSON file, with the reason they failed, so that they can be listed and
+//! retried once the cause is fixed.
+
+use chrono::{DateTime, Utc};
+use libatomic::pristine::{Base32, Hash, Merkle, NodeType};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// A node that failed to apply to a channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuarantinedNode {
+    pub channel: String,
+    /// Hash of the node, in base32.
+    pub hash: String,
+    /// Channel state after the node on the remote, in base32.
+    pub state: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub tag: bool,
+    pub reason: String,
+    /// Time of the last failure.
+    pub time: DateTime<Utc>,
+}
+
+impl QuarantinedNode {
+    pub fn new(
+        channel: &str,
+        hash: &Hash,
+        state: &Merkle,
+        node_type: NodeType,
+        reason: impl Into<String>,
+    ) -> Self {
+        QuarantinedNode {
+            channel: channel.to_string(),
+            hash: hash.to_base32(),
+            state: state.to_base32(),
+            tag: node_type == NodeType::Tag,
+            reason: reason.into(),
+            time: Utc::now(),
+        }
+    }
+
+    pub fn hash(&self) -> Option<Hash> {
+        Hash::from_base32(self.hash.as_bytes())
+    }
+
+    pub fn state(&self) -> Option<Merkle> {
+        Merkle::from_base32(self.state.as_bytes())
+    }
+
+    pub fn node_type(&self) -> NodeType {
+        if self.tag {
+            NodeType::Tag
+        } else {
+            NodeType::Change
+        }
+    }
+}
+
+/// The quarantined nodes of a repository.
+#[derive(Debug, Clone)]
+pub struct Quarantine {
+    path: PathBuf,
+}
+
+impl Quarantine {
+    /// Quarantine stored in `path` (usually `.atomic/quarantine`).
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Quarantine { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read(&self) -> Result<Vec<QuarantinedNode>, anyhow::Error> {
+        match std::fs::read(&self.path) {
+            Ok(buf) => Ok(serde_json::from_slice(&buf)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, nodes: &[QuarantinedNode]) -> Result<(), anyhow::Error> {
+        if nodes.is_empty() {
+            return match std::fs::remove_file(&self.path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(nodes)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// The quarantined nodes of `channel`, or of all channels, in the
+    /// order they were pulled.
+    pub fn list(&self, channel: Option<&str>) -> Result<Vec<QuarantinedNode>, anyhow::Error> {
+        let mut nodes = self.read()?;
+        if let Some(channel) = channel {
+            nodes.retain(|n| n.channel == channel)
+        }
+        Ok(nodes)
+    }
+
+    /// Update the quarantine of `channel` after a pull or a retry: `failed`
+    /// nodes are added, or get their new reason, and `applied` nodes are
+    /// removed.
+    pub fn update<'a, I: IntoIterator<Item = &'a Hash>>(
+        &self,
+        channel: &str,
+        failed: Vec<QuarantinedNode>,
+        applied: I,
+    ) -> Result<(), anyhow::Error> {
+        let mut nodes = self.read()?;
+        let applied: Vec<String> = applied.into_iter().map(|h| h.to_base32()).collect();
+        nodes.retain(|n| {
+            n.channel != channel
+                || !(applied.contains(&n.hash) || failed.iter().any(|f| f.hash == n.hash))
+        });
+        nodes.extend(failed);
+        self.write(&nodes)
+    }
+
+    /// Forget the quarantined nodes of `channel`, returning how many
+    /// there were.
+    pub fn clear(&self, channel: &str) -> Result<usize, anyhow::Error> {
+        let mut nodes = self.read()?;
+        let len = nodes.len();
+        nodes.retain(|n| n.channel != channel);
+        self.write(&nodes)?;
+        Ok(len - nodes.len())
+    }
+}
+
+impl crate::Repository {
+    /// The nodes quarantined by failed pulls into this repository.
+    pub fn quarantine(&self) -> Quarantine {
+        Quarantine::new(
+            self.path
+                .join(libatomic::DOT_DIR)
+                .join(crate::QUARANTINE_FILE),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::hash;
+
+    #[test]
+    fn test_update_and_clear() {
+        let tmp = tempfile::tempdir().unwrap();
+        let quarantine = Quarantine::new(tmp.path().join("quarantine"));
+        assert!(quarantine.list(None).unwrap().is_empty());
+
+        let failed = |n, channel, reason| {
+            QuarantinedNode::new(channel, &hash(n), &Merkle::zero(), NodeType::Change, reason)
+        };
+        quarantine
+            .update(
+                "main",
+                vec![failed(0, "main", "corrupt"), failed(1, "main", "missing")],
+                &[],
+            )
+            .unwrap();
+        quarantine
+            .update("dev", vec![failed(0, "dev", "corrupt")], &[])
+            .unwrap();
+        assert_eq!(quarantine.list(Some("main")).unwrap().len(), 2);
+        assert_eq!(quarantine.list(None).unwrap().len(), 3);
+
+        // A retry: 0 applies, 1 fails again for another reason.
+        quarantine
+            .update("main", vec![failed(1, "main", "still missing")], &[hash(0)])
+            .unwrap();
+        let main = quarantine.list(Some("main")).unwrap();
+        assert_eq!(main.len(), 1);
+        assert_eq!(main[0].hash(), Some(hash(1)));
+        assert_eq!(main[0].reason, "still missing");
+        // Other channels are left alone.
+        assert_eq!(quarantine.list(Some("dev")).unwrap().len(), 1);
+
+        assert_eq!(quarantine.clear("main").unwrap(), 1);
+        assert_eq!(quarantine.clear("dev").unwrap(), 1);
+        assert!(!quarantine.path().exists());
+    }
+}
@@ -0,0 +1,510 @@
+//! Review comments on changes, and the edits they suggest.
+//!
+//! Reviewers comment on changes, optionally on a line of a file, and can
+//! attach a suggestion: a replacement for a range of lines of a file. An
+//! accepted suggestion is recorded as a real change on a review channel
+//! (see [`crate::Repository::record_suggestion`]), with the reviewer as a
+//! co-author.
+//!
+//! Like notes, comments live outside of the hashed change data, in
+//! `.atomic/reviews`, one JSON file per change laid out like change
+//! files (`AB/CDEF….json`) and holding all the comments of the change.
+//! They aren't synchronised with remotes.
+
+use chrono::{DateTime, Utc};
+use libatomic::changestore::ChangeStore;
+use libatomic::pristine::{Base32, Hash};
+use libatomic::{MutTxnT, MutTxnTExt, TxnT};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A replacement for lines `start_line..=end_line` (counted from 1) of
+/// `path`. An `end_line` of `start_line - 1` inserts the replacement
+/// before `start_line`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Suggestion {
+    pub path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    /// The lines the reviewer saw, if known. The suggestion no longer
+    /// applies once they have changed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original: Option<String>,
+    pub replacement: String,
+    /// Hash of the change recording this suggestion, once accepted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accepted: Option<String>,
+}
+
+impl Suggestion {
+    /// `contents` with the suggested replacement, or `None` if the
+    /// suggestion doesn't apply to `contents` anymore.
+    pub fn apply(&self, contents: &str) -> Option<String> {
+        let lines: Vec<&str> = contents.split_inclusive('\n').collect();
+        if self.start_line == 0
+            || self.end_line + 1 < self.start_line
+            || self.end_line > lines.len()
+        {
+            return None;
+        }
+        let (before, rest) = lines.split_at(self.start_line - 1);
+        let (replaced, after) = rest.split_at(self.end_line + 1 - self.start_line);
+        let replaced = replaced.concat();
+        if let Some(ref original) = self.original {
+            if *original != replaced {
+                return None;
+            }
+        }
+        let mut result = before.concat();
+        result.push_str(&self.replacement);
+        // Keep the line structure: the replacement ends a line unless it
+        // replaces the last line of a file without a final newline.
+        let ends_line = replaced.ends_with('\n') || (replaced.is_empty() && !after.is_empty());
+        if ends_line && !self.replacement.is_empty() && !self.replacement.ends_with('\n') {
+            result.push('\n')
+        }
+        result.push_str(&after.concat());
+        Some(result)
+    }
+}
+
+/// A comment on a change.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Comment {
+    /// Identifier of the comment among the comments of the change.
+    pub id: u64,
+    /// Hash of the change, in base32.
+    pub change: String,
+    pub author: String,
+    pub text: String,
+    /// File and line the comment is about, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    pub created: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suggestion: Option<Suggestion>,
+}
+
+/// The suggestion of a comment can't be applied to the file on the
+/// review channel: the file or the suggested lines changed since.
+#[derive(Debug, Clone)]
+pub struct StaleSuggestion {
+    pub path: String,
+}
+
+impl std::fmt::Display for StaleSuggestion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "The suggestion no longer applies to {}", self.path)
+    }
+}
+
+impl std::error::Error for StaleSuggestion {}
+
+/// The review comments of a repository.
+#[derive(Debug, Clone)]
+pub struct Reviews {
+    dir: PathBuf,
+}
+
+impl Reviews {
+    /// Comments stored in `dir` (usually `.atomic/reviews`).
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Reviews { dir: dir.into() }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, hash: &Hash) -> PathBuf {
+        let h32 = hash.to_base32();
+        let (a, b) = h32.split_at(2);
+        let mut path = self.dir.join(a);
+        path.push(b);
+        path.set_extension("json");
+        path
+    }
+
+    /// The comments on `hash`, oldest first.
+    pub fn comments(&self, hash: &Hash) -> Result<Vec<Comment>, anyhow::Error> {
+        match std::fs::read(self.path(hash)) {
+            Ok(buf) => Ok(serde_json::from_slice(&buf)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, hash: &Hash, comments: &[Comment]) -> Result<(), anyhow::Error> {
+        let path = self.path(hash);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(comments)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// The comment `id` on `hash`, if any.
+    pub fn get(&self, hash: &Hash, id: u64) -> Result<Option<Comment>, anyhow::Error> {
+        Ok(self.comments(hash)?.into_iter().find(|c| c.id == id))
+    }
+
+    /// Add a comment on `hash`. Its `id`, `change` and `created` fields are
+    /// set here.
+    pub fn add(&self, hash: &Hash, mut comment: Comment) -> Result<Comment, anyhow::Error> {
+        let mut comments = self.comments(hash)?;
+        comment.id = comments.iter().map(|c| c.id).max().unwrap_or(0) + 1;
+        comment.change = hash.to_base32();
+        comment.created = Utc::now();
+        comments.push(comment.clone());
+        self.write(hash, &comments)?;
+        Ok(comment)
+    }
+
+    /// Mark the suggestion of comment `id` on `hash` as accepted, recorded
+    /// as change `accepted`.
+    pub fn accept(&self, hash: &Hash, id: u64, accepted: &Hash) -> Result<Comment, anyhow::Error> {
+        let mut comments = self.comments(hash)?;
+        let comment = if let Some(c) = comments.iter_mut().find(|c| c.id == id) {
+            c
+        } else {
+            anyhow::bail!("No comment {} on {}", id, hash.to_base32())
+        };
+        if let Some(ref mut suggestion) = comment.suggestion {
+            suggestion.accepted = Some(accepted.to_base32())
+        } else {
+            anyhow::bail!("Comment {} has no suggestion", id)
+        }
+        let comment = comment.clone();
+        self.write(hash, &comments)?;
+        Ok(comment)
+    }
+}
+
+/// Author of a change, as in change headers.
+fn author(name: &str, co_author: bool) -> libatomic::change::Author {
+    let mut author = BTreeMap::new();
+    author.insert("name".to_string(), name.to_string());
+    if co_author {
+        author.insert("role".to_string(), "co-author".to_string());
+    }
+    libatomic::change::Author(author)
+}
+
+impl crate::Repository {
+    /// Review comments of this repository.
+    pub fn reviews(&self) -> Reviews {
+        Reviews::new(self.path.join(libatomic::DOT_DIR).join(crate::REVIEWS_DIR))
+    }
+
+    /// Record the suggestion of `comment` as a new change, by `author` with
+    /// the author of the comment as co-author, and apply it to `channel`.
+    /// The file is edited as it is on `channel`; if the suggestion doesn't
+    /// apply there, this fails with [`StaleSuggestion`].
+    ///
+    /// The file is output and recorded in a transaction that is then
+    /// dropped, so that neither the working copy nor the tree of the
+    /// repository are touched; only the application of the new change to
+    /// `channel` is committed.
+    pub fn record_suggestion(
+        &self,
+        channel: &str,
+        comment: &Comment,
+        author_name: &str,
+        message: &str,
+    ) -> Result<Hash, anyhow::Error> {
+        let suggestion = if let Some(ref s) = comment.suggestion {
+            s
+        } else {
+            anyhow::bail!("Comment {} has no suggestion", comment.id)
+        };
+        let mut change = {
+            let txn = self.pristine.arc_txn_begin()?;
+            let channel_ref = if let Some(c) = txn.read().load_channel(channel)? {
+                c
+            } else {
+                anyhow::bail!("Channel {} not found", channel)
+            };
+            let copy = libatomic::working_copy::memory::Memory::new();
+            libatomic::output::output_repository_no_pending(
+                &copy,
+                &self.changes,
+                &txn,
+                &channel_ref,
+                &suggestion.path,
+                true,
+                None,
+                1,
+                0,
+            )?;
+            let mut contents = Vec::new();
+            use libatomic::working_copy::{WorkingCopy, WorkingCopyRead};
+            if copy.read_file(&suggestion.path, &mut contents).is_err() {
+                return Err(StaleSuggestion {
+                    path: suggestion.path.clone(),
+                }
+                .into());
+            }
+            let edited = std::str::from_utf8(&contents)
+                .ok()
+                .and_then(|c| suggestion.apply(c))
+                .ok_or_else(|| StaleSuggestion {
+                    path: suggestion.path.clone(),
+                })?;
+            copy.write_file(&suggestion.path, libatomic::pristine::Inode::ROOT)?
+                .write_all(edited.as_bytes())?;
+
+            let mut state = libatomic::RecordBuilder::new();
+            // Modification times of the in-memory copy don't tell whether
+            // the file was edited.
+            state.force_rediff = true;
+            state.record(
+                txn.clone(),
+                libatomic::Algorithm::default(),
+                false,
+                &libatomic::DEFAULT_SEPARATOR,
+                channel_ref.clone(),
+                &copy,
+                &self.changes,
+                &suggestion.path,
+                1,
+            )?;
+            let rec = state.finish();
+            if rec.actions.is_empty() {
+                anyhow::bail!("The suggestion doesn't change {}", suggestion.path)
+            }
+            let txn_ = txn.read();
+            let actions = rec
+                .actions
+                .into_iter()
+                .map(|rec| rec.globalize(&*txn_).unwrap())
+                .collect();
+            let contents = std::mem::take(&mut *rec.contents.lock());
+            libatomic::change::Change::make_change(
+                &*txn_,
+                &channel_ref,
+                actions,
+                contents,
+                libatomic::change::ChangeHeader {
+                    message: message.to_string(),
+                    authors: vec![author(author_name, false), author(&comment.author, true)],
+                    description: None,
+                    timestamp: Utc::now(),
+                },
+                Vec::new(),
+            )?
+            // `txn` is dropped here, without committing the output.
+        };
+        let hash = self
+            .changes
+            .save_change(&mut change, |_, _| Ok::<_, anyhow::Error>(()))?;
+
+        let mut txn = self.pristine.mut_txn_begin()?;
+        let channel_ref = if let Some(c) = txn.load_channel(channel)? {
+            c
+        } else {
+            anyhow::bail!("Channel {} not found", channel)
+        };
+        txn.apply_change_rec(&self.changes, &mut *channel_ref.write(), &hash)?;
+        txn.commit()?;
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn suggestion(start_line: usize, end_line: usize, replacement: &str) -> Suggestion {
+        Suggestion {
+            path: "a".to_string(),
+            start_line,
+            end_line,
+            original: None,
+            replacement: replacement.to_string(),
+            accepted: None,
+        }
+    }
+
+    #[test]
+    fn test_apply_suggestion() {
+        let contents = "a\nb\nc\n";
+        assert_eq!(
+            suggestion(2, 2, "x").apply(contents).as_deref(),
+            Some("a\nx\nc\n")
+        );
+        assert_eq!(
+            suggestion(2, 3, "x\ny\nz\n").apply(contents).as_deref(),
+            Some("a\nx\ny\nz\n")
+        );
+        // Insertions and deletions.
+        assert_eq!(
+            suggestion(1, 0, "x").apply(contents).as_deref(),
+            Some("x\na\nb\nc\n")
+        );
+        assert_eq!(
+            suggestion(4, 3, "d\n").apply(contents).as_deref(),
+            Some("a\nb\nc\nd\n")
+        );
+        assert_eq!(suggestion(1, 2, "").apply(contents).as_deref(), Some("c\n"));
+        assert_eq!(suggestion(3, 4, "x").apply(contents), None);
+        assert_eq!(suggestion(0, 1, "x").apply(contents), None);
+
+        let mut s = suggestion(2, 2, "x");
+        s.original = Some("b\n".to_string());
+        assert!(s.apply(contents).is_some());
+        assert_eq!(s.apply("a\nB\nc\n"), None);
+    }
+
+    #[test]
+    fn test_record_suggestion() {
+        use libatomic::working_copy::WorkingCopyRead;
+        let tmp = tempfile::tempdir().unwrap();
+        let repo = crate::Repository::init(Some(tmp.path().to_path_buf()), None, None).unwrap();
+
+        // A change adding `a` to the review channel.
+        let hash = {
+            let copy = libatomic::working_copy::memory::Memory::new();
+            copy.add_file("a", b"a\nb\nc\n".to_vec());
+            let txn = repo.pristine.arc_txn_begin().unwrap();
+            let channel = txn.write().open_or_create_channel("review").unwrap();
+            txn.write().add_file("a", 0).unwrap();
+            let mut state = libatomic::RecordBuilder::new();
+            state
+                .record(
+                    txn.clone(),
+                    libatomic::Algorithm::default(),
+                    false,
+                    &libatomic::DEFAULT_SEPARATOR,
+                    channel.clone(),
+                    &copy,
+                    &repo.changes,
+                    "",
+                    1,
+                )
+                .unwrap();
+            let rec = state.finish();
+            let actions = rec
+                .actions
+                .into_iter()
+                .map(|rec| rec.globalize(&*txn.read()).unwrap())
+                .collect();
+            let mut change = libatomic::change::Change::make_change(
+                &*txn.read(),
+                &channel,
+                actions,
+                std::mem::take(&mut *rec.contents.lock()),
+                libatomic::change::ChangeHeader {
+                    message: "Add a".to_string(),
+                    authors: vec![author("bob", false)],
+                    description: None,
+                    timestamp: Utc::now(),
+                },
+                Vec::new(),
+            )
+            .unwrap();
+            repo.changes
+                .save_change(&mut change, |_, _| Ok::<_, anyhow::Error>(()))
+                .unwrap()
+        };
+        let mut txn = repo.pristine.mut_txn_begin().unwrap();
+        let channel = txn.open_or_create_channel("review").unwrap();
+        txn.apply_change_rec(&repo.changes, &mut *channel.write(), &hash)
+            .unwrap();
+        txn.commit().unwrap();
+
+        let mut s = suggestion(2, 2, "B");
+        s.original = Some("b\n".to_string());
+        let comment = Comment {
+            id: 1,
+            change: hash.to_base32(),
+            author: "alice".to_string(),
+            text: "Capitalise".to_string(),
+            path: Some("a".to_string()),
+            line: Some(2),
+            created: Utc::now(),
+            suggestion: Some(s),
+        };
+        let accepted = repo
+            .record_suggestion("review", &comment, "bob", "Capitalise b")
+            .unwrap();
+
+        let header = repo.changes.get_header(&accepted).unwrap();
+        assert_eq!(header.message, "Capitalise b");
+        assert_eq!(header.authors[0].0["name"], "bob");
+        assert_eq!(header.authors[1].0["name"], "alice");
+        assert_eq!(header.authors[1].0["role"], "co-author");
+
+        let txn = repo.pristine.arc_txn_begin().unwrap();
+        let channel = txn.read().load_channel("review").unwrap().unwrap();
+        let copy = libatomic::working_copy::memory::Memory::new();
+        libatomic::output::output_repository_no_pending(
+            &copy,
+            &repo.changes,
+            &txn,
+            &channel,
+            "",
+            true,
+            None,
+            1,
+            0,
+        )
+        .unwrap();
+        let mut contents = Vec::new();
+        copy.read_file("a", &mut contents).unwrap();
+        assert_eq!(contents, b"a\nB\nc\n");
+        std::mem::drop(txn);
+
+        // `b` is gone, the suggestion doesn't apply anymore.
+        let err = repo
+            .record_suggestion("review", &comment, "bob", "Again")
+            .unwrap_err();
+        assert!(err.downcast_ref::<StaleSuggestion>().is_some());
+    }
+
+    #[test]
+    fn test_comments() {
+        let tmp = tempfile::tempdir().unwrap();
+        let reviews = Reviews::new(tmp.path().join("reviews"));
+        let hash = {
+            let mut h = libatomic::pristine::Hasher::default();
+            h.update(b"change");
+            h.finish()
+        };
+        assert!(reviews.comments(&hash).unwrap().is_empty());
+        let comment = |text: &str, suggestion: Option<Suggestion>| Comment {
+            id: 0,
+            change: String::new(),
+            author: "alice".to_string(),
+            text: text.to_string(),
+            path: None,
+            line: None,
+            created: Utc::now(),
+            suggestion,
+        };
+        let c1 = reviews.add(&hash, comment("Looks good", None)).unwrap();
+        let c2 = reviews
+            .add(&hash, comment("Typo", Some(suggestion(1, 1, "b"))))
+            .unwrap();
+        assert_eq!((c1.id, c2.id), (1, 2));
+        assert_eq!(c2.change, hash.to_base32());
+        assert!(reviews.accept(&hash, c1.id, &hash).is_err());
+        let accepted = reviews.accept(&hash, c2.id, &hash).unwrap();
+        assert_eq!(
+            accepted.suggestion.unwrap().accepted,
+            Some(hash.to_base32())
+        );
+        assert_eq!(reviews.comments(&hash).unwrap().len(), 2);
+        assert!(reviews
+            .get(&hash, 2)
+            .unwrap()
+            .unwrap()
+            .suggestion
+            .unwrap()
+            .accepted
+            .is_some());
+    }
+}
@@ -0,0 +1,195 @@
+//! Which changes supersede which.
+//!
+//! Re-recording a change after review (`record --amend`) or squashing
+//! changes produces a new change, and the earlier versions would
+//! otherwise linger with no link to it. The new change lists the hashes
+//! it supersedes in its unhashed section (see
+//! [`libatomic::change::SUPERSEDES_KEY`]), so the relationship travels
+//! with it. Repositories keep a table of these relationships in
+//! `.atomic/supersedes` (a JSON file), filled when changes are recorded
+//! or applied, so that review tools can collapse obsolete versions
+//! without reading every change.
+
+use chrono::{DateTime, Utc};
+use libatomic::pristine::{Base32, Hash};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// A change and the earlier versions it supersedes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Supersession {
+    /// Hash of the new change, in base32.
+    pub change: String,
+    /// Hashes of the superseded changes, in base32.
+    pub supersedes: Vec<String>,
+    /// When this repository learnt about the relationship.
+    pub recorded: DateTime<Utc>,
+}
+
+/// The relationships of a table, indexed both ways.
+#[derive(Debug, Clone, Default)]
+pub struct SupersedeIndex {
+    supersedes: BTreeMap<String, Vec<String>>,
+    superseded_by: BTreeMap<String, String>,
+}
+
+impl SupersedeIndex {
+    /// Hashes of the changes `change` supersedes, in base32.
+    pub fn supersedes(&self, change: &str) -> &[String] {
+        self.supersedes.get(change).map_or(&[], |s| &s[..])
+    }
+
+    /// Hash of the change superseding `change`, in base32. If several
+    /// changes supersede it, the last recorded one wins.
+    pub fn superseded_by(&self, change: &str) -> Option<&str> {
+        self.superseded_by.get(change).map(|s| s.as_str())
+    }
+
+    /// The latest version of `change`, following supersessions.
+    pub fn latest<'a>(&'a self, mut change: &'a str) -> &'a str {
+        let mut seen = std::collections::BTreeSet::new();
+        while let Some(next) = self.superseded_by(change) {
+            if !seen.insert(next) {
+                break;
+            }
+            change = next
+        }
+        change
+    }
+}
+
+/// The supersession table of a repository.
+#[derive(Debug, Clone)]
+pub struct Supersedes {
+    path: PathBuf,
+}
+
+impl Supersedes {
+    /// Table stored in `path` (usually `.atomic/supersedes`).
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Supersedes { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read(&self) -> Result<Vec<Supersession>, anyhow::Error> {
+        match std::fs::read(&self.path) {
+            Ok(buf) => Ok(serde_json::from_slice(&buf)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self, records: &[Supersession]) -> Result<(), anyhow::Error> {
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(records)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// All the relationships, in the order they were recorded.
+    pub fn list(&self) -> Result<Vec<Supersession>, anyhow::Error> {
+        self.read()
+    }
+
+    /// The relationships, indexed both ways.
+    pub fn index(&self) -> Result<SupersedeIndex, anyhow::Error> {
+        let mut index = SupersedeIndex::default();
+        for r in self.read()? {
+            for old in r.supersedes.iter() {
+                index.superseded_by.insert(old.clone(), r.change.clone());
+            }
+            index.supersedes.insert(r.change, r.supersedes);
+        }
+        Ok(index)
+    }
+
+    /// Record that `change` supersedes `old`. Recording the same
+    /// relationship again does nothing.
+    pub fn record(
+        &self,
+        change: &Hash,
+        old: &[Hash],
+        now: DateTime<Utc>,
+    ) -> Result<(), anyhow::Error> {
+        if old.is_empty() {
+            return Ok(());
+        }
+        let record = Supersession {
+            change: change.to_base32(),
+            supersedes: old.iter().map(|h| h.to_base32()).collect(),
+            recorded: now,
+        };
+        let mut records = self.read()?;
+        if let Some(r) = records.iter_mut().find(|r| r.change == record.change) {
+            if r.supersedes == record.supersedes {
+                return Ok(());
+            }
+            *r = record
+        } else {
+            records.push(record)
+        }
+        self.write(&records)
+    }
+
+    /// Record the changes that `change`, of hash `hash`, supersedes
+    /// according to its unhashed section, if any.
+    pub fn record_change(
+        &self,
+        hash: &Hash,
+        change: &libatomic::change::Change,
+        now: DateTime<Utc>,
+    ) -> Result<(), anyhow::Error> {
+        self.record(hash, &change.supersedes(), now)
+    }
+}
+
+impl crate::Repository {
+    /// Which changes of this repository supersede which.
+    pub fn supersedes(&self) -> Supersedes {
+        Supersedes::new(
+            self.path
+                .join(libatomic::DOT_DIR)
+                .join(crate::SUPERSEDES_FILE),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::hash;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_amend_then_squash() {
+        let tmp = tempfile::tempdir().unwrap();
+        let supersedes = Supersedes::new(tmp.path().join("supersedes"));
+        let t0 = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        assert!(supersedes.list().unwrap().is_empty());
+
+        // 0 is amended into 1, which is squashed with 2 into 3.
+        supersedes.record(&hash(1), &[hash(0)], t0).unwrap();
+        supersedes.record(&hash(1), &[hash(0)], t0).unwrap();
+        supersedes
+            .record(&hash(3), &[hash(1), hash(2)], t0)
+            .unwrap();
+        supersedes.record(&hash(4), &[], t0).unwrap();
+        assert_eq!(supersedes.list().unwrap().len(), 2);
+
+        let index = supersedes.index().unwrap();
+        let (h0, h1, h3) = (
+            hash(0).to_base32(),
+            hash(1).to_base32(),
+            hash(3).to_base32(),
+        );
+        assert_eq!(index.supersedes(&h3), &[h1.clone(), hash(2).to_base32()]);
+        assert_eq!(index.superseded_by(&h0), Some(h1.as_str()));
+        assert_eq!(index.superseded_by(&h3), None);
+        assert_eq!(index.latest(&h0), h3);
+        assert!(index.supersedes(&h0).is_empty());
+    }
+}
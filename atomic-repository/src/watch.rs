@@ -0,0 +1,525 @@
+//! Watcher of the working copy
+//!
+//! Computing what `atomic record` would capture means diffing the whole
+//! working copy against the channel, which is too slow to do on every
+//! keystroke of an editor or every refresh of a UI. [`WorkingCopyWatcher`]
+//! keeps that pending diff up to date instead: it subscribes to the file
+//! notifications of the operating system (inotify, FSEvents or
+//! ReadDirectoryChangesW, through `notify`), waits for the writes to
+//! settle (debouncing), and records again only the paths that changed.
+//! Paths matched by the `.ignore` and `.gitignore` rules are skipped, as
+//! are the writes under `.atomic`, except the ones to the pristine, after
+//! which the whole working copy is compared again if the channel moved.
+//!
+//! Nothing is written to the repository: records are made in a
+//! transaction that is never committed, like `atomic diff`, and the
+//! repository is only opened for the time of an update, so that the
+//! watcher doesn't hold the pristine while commands run.
+
+use crate::Repository;
+use canonical_path::CanonicalPathBuf;
+use chrono::{DateTime, Utc};
+use libatomic::change::{BaseHunk, LocalByte};
+use libatomic::pristine::{Base32, Merkle};
+use libatomic::{ChannelRef, TxnT};
+use log::{debug, warn};
+use notify::Watcher as _;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Default quiet period after the last write before updating
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(200);
+/// Default longest wait for a quiet period
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(2);
+/// Number of changed paths from which the whole working copy is recorded
+const MAX_PREFIXES: usize = 64;
+
+/// Configuration of a [`WorkingCopyWatcher`]
+#[derive(Debug, Clone)]
+pub struct WatchConfig {
+    /// Channel to compare the working copy with, the current channel by
+    /// default
+    pub channel: Option<String>,
+    /// Quiet period after the last write before the pending diff is
+    /// updated
+    pub debounce: Duration,
+    /// Longest wait for a quiet period, so that a file written constantly
+    /// still gets updates
+    pub max_delay: Duration,
+}
+
+impl Default for WatchConfig {
+    fn default() -> Self {
+        WatchConfig {
+            channel: None,
+            debounce: DEFAULT_DEBOUNCE,
+            max_delay: DEFAULT_MAX_DELAY,
+        }
+    }
+}
+
+/// An operation that record would capture on a path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PendingOp {
+    Add,
+    Delete,
+    Undelete,
+    Move,
+    Edit,
+    Replace,
+    SolveNameConflict,
+    UnsolveNameConflict,
+    SolveOrderConflict,
+    UnsolveOrderConflict,
+    ResurrectZombies,
+    AddRoot,
+    DelRoot,
+}
+
+impl PendingOp {
+    /// The path of a recorded hunk, and its operation
+    fn of<A>(hunk: &BaseHunk<A, LocalByte>) -> (&str, PendingOp) {
+        match hunk {
+            BaseHunk::FileAdd { path, .. } => (path, PendingOp::Add),
+            BaseHunk::FileDel { path, .. } => (path, PendingOp::Delete),
+            BaseHunk::FileUndel { path, .. } => (path, PendingOp::Undelete),
+            BaseHunk::FileMove { path, .. } => (path, PendingOp::Move),
+            BaseHunk::SolveNameConflict { path, .. } => (path, PendingOp::SolveNameConflict),
+            BaseHunk::UnsolveNameConflict { path, .. } => (path, PendingOp::UnsolveNameConflict),
+            BaseHunk::Edit { local, .. } => (&local.path, PendingOp::Edit),
+            BaseHunk::Replacement { local, .. } => (&local.path, PendingOp::Replace),
+            BaseHunk::SolveOrderConflict { local, .. } => {
+                (&local.path, PendingOp::SolveOrderConflict)
+            }
+            BaseHunk::UnsolveOrderConflict { local, .. } => {
+                (&local.path, PendingOp::UnsolveOrderConflict)
+            }
+            BaseHunk::ResurrectZombies { local, .. } => (&local.path, PendingOp::ResurrectZombies),
+            BaseHunk::AddRoot { .. } => ("", PendingOp::AddRoot),
+            BaseHunk::DelRoot { .. } => ("", PendingOp::DelRoot),
+        }
+    }
+}
+
+/// What record would capture, by path
+pub type PendingFiles = BTreeMap<String, BTreeSet<PendingOp>>;
+
+/// The pending diff of the working copy, as of its last update
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PendingDiff {
+    /// Channel the working copy is compared with
+    pub channel: String,
+    /// State of the channel at the last update, in base32
+    pub state: String,
+    /// Time of the last update, `None` before the first one
+    pub updated: Option<DateTime<Utc>>,
+    /// Number of updates so far, see [`WorkingCopyWatcher::wait`]
+    pub generation: u64,
+    pub files: PendingFiles,
+    /// Error of the last update, if it failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl PendingDiff {
+    /// Whether record would capture nothing
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+/// Record the working copy of the repository at `repo_path` under
+/// `prefixes` (all of it if empty) against `channel` (the current
+/// channel if `None`), without committing. Returns the name and state of
+/// the channel, and what record would capture.
+pub fn pending(
+    repo_path: &Path,
+    channel: Option<&str>,
+    prefixes: &[String],
+) -> Result<(String, Merkle, PendingFiles), anyhow::Error> {
+    let repo = Repository::find_root(Some(repo_path.to_path_buf()))?;
+    let txn = repo.pristine.arc_txn_begin()?;
+    let (name, channel_ref) = load_channel(&*txn.read(), channel)?;
+    let state = libatomic::pristine::current_state(&*txn.read(), &*channel_ref.read())?;
+    let mut builder = libatomic::RecordBuilder::new();
+    repo.working_copy.record_prefixes(
+        txn.clone(),
+        libatomic::Algorithm::default(),
+        channel_ref,
+        &repo.changes,
+        &mut builder,
+        CanonicalPathBuf::canonicalize(&repo.path)?,
+        prefixes,
+        false,
+        std::thread::available_parallelism()?.get(),
+        0,
+    )?;
+    let mut files = PendingFiles::new();
+    for hunk in builder.finish().actions.iter() {
+        let (path, op) = PendingOp::of(hunk);
+        files.entry(path.to_string()).or_default().insert(op);
+    }
+    Ok((name, state, files))
+}
+
+/// State of `channel` (the current channel if `None`) in the repository at
+/// `repo_path`, read without starting a mutable transaction
+fn channel_state(repo_path: &Path, channel: Option<&str>) -> Result<Merkle, anyhow::Error> {
+    let repo = Repository::find_root(Some(repo_path.to_path_buf()))?;
+    let txn = repo.pristine.txn_begin()?;
+    let (_, channel) = load_channel(&txn, channel)?;
+    let state = libatomic::pristine::current_state(&txn, &*channel.read())?;
+    Ok(state)
+}
+
+/// `channel`, or the current channel if `None`, and its name
+fn load_channel<T: TxnT>(
+    txn: &T,
+    channel: Option<&str>,
+) -> Result<(String, ChannelRef<T>), anyhow::Error> {
+    let name = channel
+        .or_else(|| txn.current_channel().ok())
+        .unwrap_or(libatomic::DEFAULT_CHANNEL)
+        .to_string();
+    match txn.load_channel(&name)? {
+        Some(c) => Ok((name, c)),
+        None => anyhow::bail!("No channel {}", name),
+    }
+}
+
+/// What a write to a path means for the pending diff
+#[derive(Debug, PartialEq, Eq)]
+enum Touched {
+    /// A path of the working copy, relative to the root
+    Path(String),
+    /// The pristine, the channel may have moved
+    Pristine,
+    Ignored,
+}
+
+fn touched(root: &CanonicalPathBuf, path: &Path) -> Touched {
+    let relative = match path.strip_prefix(root.as_path()) {
+        Ok(relative) => relative,
+        Err(_) => return Touched::Ignored,
+    };
+    let mut components = relative.components();
+    if components.next().map(|c| c.as_os_str()) == Some(std::ffi::OsStr::new(libatomic::DOT_DIR)) {
+        return match components.next() {
+            Some(c) if c.as_os_str() == crate::PRISTINE_DIR => Touched::Pristine,
+            _ => Touched::Ignored,
+        };
+    }
+    // Deleted paths can't be canonicalized, they are kept.
+    if let Ok(full) = CanonicalPathBuf::canonicalize(path) {
+        let is_dir = full.as_path().is_dir();
+        if !libatomic::working_copy::filesystem::filter_ignore(
+            root.as_canonical_path(),
+            full.as_canonical_path(),
+            is_dir,
+        ) {
+            return Touched::Ignored;
+        }
+    }
+    match relative.to_str() {
+        Some(relative) if !relative.is_empty() => Touched::Path(relative.replace('\\', "/")),
+        _ => Touched::Ignored,
+    }
+}
+
+/// The prefixes to record for the changed `paths`: the closest existing
+/// ancestor of each path, without the ones under another. `None` if the
+/// whole working copy should be recorded.
+fn prefixes(root: &Path, paths: &BTreeSet<String>) -> Option<Vec<String>> {
+    if paths.len() > MAX_PREFIXES {
+        return None;
+    }
+    let mut existing = BTreeSet::new();
+    for path in paths {
+        let mut path = path.as_str();
+        while !root.join(path).exists() {
+            path = match path.rfind('/') {
+                Some(i) => &path[..i],
+                None => return None,
+            };
+        }
+        existing.insert(path);
+    }
+    let mut prefixes: Vec<String> = Vec::new();
+    for path in existing {
+        if !prefixes.iter().any(|p| is_under(path, p)) {
+            prefixes.push(path.to_string())
+        }
+    }
+    Some(prefixes)
+}
+
+/// Whether `path` is `prefix` or under it
+fn is_under(path: &str, prefix: &str) -> bool {
+    prefix.is_empty()
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+enum Message {
+    Event(notify::Result<notify::Event>),
+    Refresh,
+    Stop,
+}
+
+struct Shared {
+    diff: Mutex<PendingDiff>,
+    updated: Condvar,
+}
+
+/// Changes waiting for the next update
+#[derive(Default)]
+struct Dirty {
+    /// Record the whole working copy
+    all: bool,
+    /// Check whether the channel moved
+    pristine: bool,
+    paths: BTreeSet<String>,
+}
+
+/// Keeps the pending diff of a working copy up to date, until dropped
+pub struct WorkingCopyWatcher {
+    shared: Arc<Shared>,
+    sender: Sender<Message>,
+    // Dropped before the thread is joined.
+    watcher: Option<notify::RecommendedWatcher>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl WorkingCopyWatcher {
+    /// Watch the working copy of the repository at `repo_path`. The first
+    /// pending diff is computed in the background, see
+    /// [`WorkingCopyWatcher::wait`].
+    pub fn start(repo_path: &Path, config: WatchConfig) -> Result<Self, anyhow::Error> {
+        let root = CanonicalPathBuf::canonicalize(repo_path)?;
+        let (sender, receiver) = channel();
+        let events = sender.clone();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            events.send(Message::Event(event)).unwrap_or(())
+        })?;
+        watcher.watch(root.as_path(), notify::RecursiveMode::Recursive)?;
+
+        let shared = Arc::new(Shared {
+            diff: Mutex::new(PendingDiff::default()),
+            updated: Condvar::new(),
+        });
+        let shared_ = shared.clone();
+        let thread = std::thread::spawn(move || {
+            let mut dirty = Dirty {
+                all: true,
+                ..Dirty::default()
+            };
+            loop {
+                update(&shared_, &root, &config, std::mem::take(&mut dirty));
+                // Wait for a first write, then for the writes to settle.
+                match receiver.recv() {
+                    Ok(Message::Stop) | Err(_) => return,
+                    Ok(message) => dirty.add(&root, message),
+                }
+                let start = Instant::now();
+                loop {
+                    let left = config
+                        .max_delay
+                        .saturating_sub(start.elapsed())
+                        .min(config.debounce);
+                    if left.is_zero() {
+                        break;
+                    }
+                    match receiver.recv_timeout(left) {
+                        Ok(Message::Stop) | Err(RecvTimeoutError::Disconnected) => return,
+                        Ok(message) => dirty.add(&root, message),
+                        Err(RecvTimeoutError::Timeout) => break,
+                    }
+                }
+            }
+        });
+        Ok(WorkingCopyWatcher {
+            shared,
+            sender,
+            watcher: Some(watcher),
+            thread: Some(thread),
+        })
+    }
+
+    /// What record would capture, as of the last update
+    pub fn pending(&self) -> PendingDiff {
+        self.shared.diff.lock().unwrap().clone()
+    }
+
+    /// Wait for an update after `generation`, for at most `timeout`, and
+    /// return the pending diff then
+    pub fn wait(&self, generation: u64, timeout: Duration) -> PendingDiff {
+        let diff = self.shared.diff.lock().unwrap();
+        let (diff, _) = self
+            .shared
+            .updated
+            .wait_timeout_while(diff, timeout, |d| d.generation <= generation)
+            .unwrap();
+        diff.clone()
+    }
+
+    /// Compare the whole working copy again, e.g. after a command changed
+    /// files the notifications may have missed
+    pub fn refresh(&self) {
+        self.sender.send(Message::Refresh).unwrap_or(())
+    }
+}
+
+impl Drop for WorkingCopyWatcher {
+    fn drop(&mut self) {
+        self.watcher.take();
+        self.sender.send(Message::Stop).unwrap_or(());
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(())
+        }
+    }
+}
+
+impl Dirty {
+    fn add(&mut self, root: &CanonicalPathBuf, message: Message) {
+        let event = match message {
+            Message::Event(Ok(event)) => event,
+            Message::Event(Err(e)) => {
+                warn!("Working copy notification error: {}", e);
+                self.all = true;
+                return;
+            }
+            Message::Refresh => {
+                self.all = true;
+                return;
+            }
+            Message::Stop => return,
+        };
+        if event.need_rescan() {
+            self.all = true;
+        }
+        if let notify::EventKind::Access(_) = event.kind {
+            return;
+        }
+        for path in event.paths.iter() {
+            match touched(root, path) {
+                Touched::Path(path) => {
+                    self.paths.insert(path);
+                }
+                Touched::Pristine => self.pristine = true,
+                Touched::Ignored => {}
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        !self.all && !self.pristine && self.paths.is_empty()
+    }
+}
+
+/// Update the pending diff in `shared` after the changes in `dirty`
+fn update(shared: &Shared, root: &CanonicalPathBuf, config: &WatchConfig, mut dirty: Dirty) {
+    if dirty.is_empty() {
+        return;
+    }
+    let channel = config.channel.as_deref();
+    let last_state = shared.diff.lock().unwrap().state.clone();
+    if dirty.pristine && !dirty.all {
+        // Our own records write to the pristine too, without moving the
+        // channel.
+        match channel_state(root.as_path(), channel) {
+            Ok(state) if state.to_base32() == last_state => {}
+            _ => dirty.all = true,
+        }
+    }
+    let prefixes = if dirty.all {
+        Vec::new()
+    } else if dirty.paths.is_empty() {
+        return;
+    } else {
+        prefixes(root.as_path(), &dirty.paths).unwrap_or_default()
+    };
+    debug!("Updating the pending diff of {:?}", prefixes);
+    let mut result = pending(root.as_path(), channel, &prefixes);
+    if let Ok((_, ref state, _)) = result {
+        if !prefixes.is_empty() && state.to_base32() != last_state {
+            // The rest of the diff is against another state.
+            result = pending(root.as_path(), channel, &[]);
+        }
+    }
+    let mut diff = shared.diff.lock().unwrap();
+    match result {
+        Ok((name, state, files)) => {
+            let partial = !prefixes.is_empty() && state.to_base32() == last_state;
+            if partial {
+                diff.files
+                    .retain(|path, _| !prefixes.iter().any(|p| is_under(path, p)));
+                diff.files.extend(files);
+            } else {
+                diff.files = files;
+            }
+            diff.channel = name;
+            diff.state = state.to_base32();
+            diff.error = None;
+        }
+        Err(e) => {
+            warn!("Failed to update the pending diff: {}", e);
+            diff.error = Some(e.to_string());
+        }
+    }
+    diff.updated = Some(Utc::now());
+    diff.generation += 1;
+    shared.updated.notify_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefixes() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("src/bin")).unwrap();
+        std::fs::write(tmp.path().join("src/lib.rs"), "").unwrap();
+        std::fs::write(tmp.path().join("README"), "").unwrap();
+
+        let paths: BTreeSet<String> = ["src/lib.rs", "src", "src/bin/gone.rs", "README"]
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        assert_eq!(
+            prefixes(tmp.path(), &paths),
+            Some(vec!["README".to_string(), "src".to_string()])
+        );
+        // A deleted directory at the root: everything.
+        let paths = ["gone/file".to_string()].into_iter().collect();
+        assert_eq!(prefixes(tmp.path(), &paths), None);
+
+        assert!(is_under("src/lib.rs", "src"));
+        assert!(!is_under("srcs/lib.rs", "src"));
+        assert!(is_under("anything", ""));
+    }
+
+    #[test]
+    fn test_touched() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = CanonicalPathBuf::canonicalize(tmp.path()).unwrap();
+        std::fs::create_dir_all(root.as_path().join("target")).unwrap();
+        std::fs::write(root.as_path().join(".ignore"), "target\n").unwrap();
+        std::fs::write(root.as_path().join("target/out"), "").unwrap();
+        std::fs::write(root.as_path().join("main.rs"), "").unwrap();
+
+        let at = |p: &str| touched(&root, &root.as_path().join(p));
+        assert_eq!(at("main.rs"), Touched::Path("main.rs".to_string()));
+        assert_eq!(at("deleted.rs"), Touched::Path("deleted.rs".to_string()));
+        assert_eq!(at("target/out"), Touched::Ignored);
+        assert_eq!(at(".atomic/pristine/db"), Touched::Pristine);
+        assert_eq!(at(".atomic/changes/AB/CDEF.change"), Touched::Ignored);
+        assert_eq!(touched(&root, Path::new("/elsewhere")), Touched::Ignored);
+    }
+}
@@ -0,0 +1,652 @@
+//! Audit log of workflow events
+//!
+//! Workflow stores only keep the current state of each workflow. The
+//! [`WorkflowAuditLog`] keeps how it got there: every [`WorkflowEvent`]
+//! (state changes, approvals, rejections, role grants) is appended to a
+//! log, usually [`AUDIT_LOG_FILE`] in the `.atomic` directory. Iterating
+//! over the log replays the history of the workflows of a change, e.g. in
+//! `atomic workflow history`.
+//!
+//! The log has one JSON [`AuditRecord`] per line. Each record is written
+//! with a single append, so that concurrent writers don't interleave.
+//!
+//! The records of a workflow on a change form a chain: each of them holds
+//! the hash of the previous one, so that removing or editing a record
+//! other than the last one breaks the chain ([`WorkflowAuditLog::verify`]). Long-lived changes
+//! accumulate many state changes, which [`WorkflowAuditLog::compact`]
+//! folds into [`Checkpoint`]s. A checkpoint holds the hash of the last
+//! record it folds, which is what the next record of the chain points to,
+//! and is signed by whoever compacted the log: verifying the chain then
+//! only needs to trust that signature instead of the folded records.
+
+use crate::simple::{WorkflowContext, WorkflowEvent};
+use atomic_config::events::NodeKind;
+use atomic_config::Author;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+/// Name of the audit log in the `.atomic` directory
+pub const AUDIT_LOG_FILE: &str = "workflow-log";
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuditLogError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("Failed to encode workflow event: {0}")]
+    Encode(serde_json::Error),
+    #[error("Invalid entry on line {line} of the workflow log: {source}")]
+    Decode {
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[error(
+        "Broken chain of workflow {workflow} on {change_id} at line {line} of the workflow log"
+    )]
+    BrokenChain {
+        workflow: String,
+        change_id: String,
+        line: usize,
+    },
+    #[error("Invalid checkpoint signature at line {line} of the workflow log")]
+    InvalidCheckpoint { line: usize },
+}
+
+/// An event of a workflow on a change or tag
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    /// Name of the workflow
+    pub workflow: String,
+    /// Whether the workflow runs on a change or on a tag
+    pub kind: NodeKind,
+    /// Hash of the change, or state of the tag
+    pub change_id: String,
+    /// Author of the context the event happened in
+    pub actor: Author,
+    pub event: WorkflowEvent,
+    /// Hash of the previous record of the workflow on the change, set by
+    /// [`WorkflowAuditLog::append`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev: Option<String>,
+}
+
+impl AuditEntry {
+    /// An entry for `event`, which just happened in `context`
+    pub fn new(workflow: &str, context: &WorkflowContext, event: WorkflowEvent) -> Self {
+        AuditEntry {
+            timestamp: Utc::now(),
+            workflow: workflow.to_string(),
+            kind: context.kind,
+            change_id: context.change_id.clone(),
+            actor: context.author.clone(),
+            event,
+            prev: None,
+        }
+    }
+}
+
+/// Consecutive state changes of a workflow on a change, folded by
+/// [`WorkflowAuditLog::compact`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub workflow: String,
+    pub kind: NodeKind,
+    pub change_id: String,
+    /// State before the first folded state change
+    pub from: String,
+    /// State after the last folded state change
+    pub to: String,
+    /// Number of folded state changes
+    pub events: usize,
+    pub first: DateTime<Utc>,
+    pub last: DateTime<Utc>,
+    /// Hash of the record preceding the folded ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prev: Option<String>,
+    /// Hash of the last folded record
+    pub folded: String,
+    /// Signature of [`Checkpoint::signed_bytes`]. This crate doesn't
+    /// interpret it: `atomic workflow compact` stores a signature by the
+    /// identity of the user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<serde_json::Value>,
+}
+
+impl Checkpoint {
+    /// The bytes covered by the signature: everything but the signature
+    pub fn signed_bytes(&self) -> Vec<u8> {
+        format!(
+            "{}\n{}\n{}\n{} -> {}\n{}\n{}\n{}\n{}",
+            self.workflow,
+            self.change_id,
+            self.prev.as_deref().unwrap_or(""),
+            self.from,
+            self.to,
+            self.events,
+            self.first.to_rfc3339(),
+            self.last.to_rfc3339(),
+            self.folded,
+        )
+        .into_bytes()
+    }
+}
+
+/// A line of the log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum AuditRecord {
+    Checkpoint(Checkpoint),
+    Entry(AuditEntry),
+}
+
+impl AuditRecord {
+    pub fn workflow(&self) -> &str {
+        match self {
+            AuditRecord::Checkpoint(c) => &c.workflow,
+            AuditRecord::Entry(e) => &e.workflow,
+        }
+    }
+
+    pub fn change_id(&self) -> &str {
+        match self {
+            AuditRecord::Checkpoint(c) => &c.change_id,
+            AuditRecord::Entry(e) => &e.change_id,
+        }
+    }
+
+    /// Time of the event, or of the last folded event
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            AuditRecord::Checkpoint(c) => c.last,
+            AuditRecord::Entry(e) => e.timestamp,
+        }
+    }
+
+    fn prev(&self) -> Option<&str> {
+        match self {
+            AuditRecord::Checkpoint(c) => c.prev.as_deref(),
+            AuditRecord::Entry(e) => e.prev.as_deref(),
+        }
+    }
+
+    /// Hash of this record in its chain, where `line` is its line in the
+    /// log. Checkpoints stand for the last record they fold.
+    fn hash(&self, line: &str) -> String {
+        match self {
+            AuditRecord::Checkpoint(c) => c.folded.clone(),
+            AuditRecord::Entry(_) => {
+                data_encoding::HEXLOWER.encode(&Sha256::digest(line.as_bytes()))
+            }
+        }
+    }
+
+    /// `(from, to)` if this record can be folded into a checkpoint
+    fn transition(&self) -> Option<(&str, &str)> {
+        match self {
+            AuditRecord::Checkpoint(c) => Some((&c.from, &c.to)),
+            AuditRecord::Entry(AuditEntry {
+                event: WorkflowEvent::StateChanged { from, to },
+                ..
+            }) => Some((from, to)),
+            AuditRecord::Entry(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for AuditRecord {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AuditRecord::Checkpoint(c) => {
+                write!(fmt, "{} -> {} ({} state changes)", c.from, c.to, c.events)
+            }
+            AuditRecord::Entry(e) => write!(fmt, "{}", e.event),
+        }
+    }
+}
+
+/// Result of [`WorkflowAuditLog::compact`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compaction {
+    /// Number of records before compaction
+    pub before: usize,
+    /// Number of records after compaction
+    pub after: usize,
+}
+
+/// An append-only log of workflow events
+#[derive(Debug, Clone)]
+pub struct WorkflowAuditLog {
+    path: PathBuf,
+}
+
+impl WorkflowAuditLog {
+    /// The log in the file `path`, created by the first append
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        WorkflowAuditLog { path: path.into() }
+    }
+
+    /// The log of the repository whose `.atomic` directory is `dot_dir`
+    pub fn in_dot_dir(dot_dir: &Path) -> Self {
+        Self::new(dot_dir.join(AUDIT_LOG_FILE))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append `entry` to the chain of its workflow on its change, returning
+    /// it with its `prev` hash. Appends to the same chain must not run
+    /// concurrently, or the chain forks: the caller holds the lock of the
+    /// repository.
+    pub fn append(&self, mut entry: AuditEntry) -> Result<AuditEntry, AuditLogError> {
+        entry.prev = self.head(&entry.workflow, &entry.change_id)?;
+        let mut line = serde_json::to_vec(&entry).map_err(AuditLogError::Encode)?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&line)?;
+        file.sync_data()?;
+        Ok(entry)
+    }
+
+    /// Append `event`, which just happened in `context`, to the log of
+    /// `workflow`, returning the new entry
+    pub fn record(
+        &self,
+        workflow: &str,
+        context: &WorkflowContext,
+        event: &WorkflowEvent,
+    ) -> Result<AuditEntry, AuditLogError> {
+        self.append(AuditEntry::new(workflow, context, event.clone()))
+    }
+
+    /// All the records of the log, oldest first
+    pub fn iter(&self) -> Result<AuditIter, AuditLogError> {
+        let lines = match File::open(&self.path) {
+            Ok(file) => Some(BufReader::new(file).lines()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(AuditIter { lines, line: 0 })
+    }
+
+    /// The records of the workflows of `change_id`, oldest first
+    pub fn history<'a>(
+        &self,
+        change_id: &'a str,
+    ) -> Result<impl Iterator<Item = Result<AuditRecord, AuditLogError>> + 'a, AuditLogError> {
+        Ok(self.iter()?.filter(move |record| match record {
+            Ok(record) => record.change_id() == change_id,
+            Err(_) => true,
+        }))
+    }
+
+    /// Hash of the last record of `workflow` on `change_id`
+    fn head(&self, workflow: &str, change_id: &str) -> Result<Option<String>, AuditLogError> {
+        let mut records = self.iter()?;
+        let mut head = None;
+        while let Some(record) = records.next_line() {
+            match record {
+                Ok((_, line, record)) => {
+                    if record.workflow() == workflow && record.change_id() == change_id {
+                        head = Some(record.hash(&line))
+                    }
+                }
+                // Torn lines aren't part of any chain.
+                Err(AuditLogError::Decode { .. }) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(head)
+    }
+
+    /// Check the chains of the workflows of `change_id`, and the signature
+    /// of their checkpoints with `verify_signature`. Returns the number of
+    /// records checked.
+    pub fn verify<F: FnMut(&Checkpoint) -> bool>(
+        &self,
+        change_id: &str,
+        mut verify_signature: F,
+    ) -> Result<usize, AuditLogError> {
+        let mut heads: BTreeMap<String, Option<String>> = BTreeMap::new();
+        let mut records = self.iter()?;
+        let mut checked = 0;
+        while let Some(record) = records.next_line() {
+            let (line_number, line, record) = record?;
+            if record.change_id() != change_id {
+                continue;
+            }
+            let head = heads.entry(record.workflow().to_string()).or_default();
+            if record.prev() != head.as_deref() {
+                return Err(AuditLogError::BrokenChain {
+                    workflow: record.workflow().to_string(),
+                    change_id: change_id.to_string(),
+                    line: line_number,
+                });
+            }
+            if let AuditRecord::Checkpoint(ref checkpoint) = record {
+                if !verify_signature(checkpoint) {
+                    return Err(AuditLogError::InvalidCheckpoint { line: line_number });
+                }
+            }
+            *head = Some(record.hash(&line));
+            checked += 1;
+        }
+        Ok(checked)
+    }
+
+    /// Fold each run of at least two consecutive state changes of a
+    /// workflow on a change into a [`Checkpoint`], signed with `sign`
+    /// (which may leave it unsigned by returning `None`). Approvals,
+    /// rejections and role grants are kept, and so are the states the
+    /// workflows were in when they happened. Checkpoints of a previous
+    /// compaction are extended with the state changes recorded since.
+    ///
+    /// The log is replaced atomically, but events appended while it is
+    /// compacted are lost: the caller holds the lock of the repository.
+    pub fn compact<F: FnMut(&[u8]) -> Option<serde_json::Value>>(
+        &self,
+        mut sign: F,
+    ) -> Result<Compaction, AuditLogError> {
+        let mut records = Vec::new();
+        let mut lines = self.iter()?;
+        while let Some(record) = lines.next_line() {
+            let (_, line, record) = record?;
+            records.push(Some((line, record)));
+        }
+        let before = records.len();
+
+        let mut chains: BTreeMap<(&str, &str), Vec<usize>> = BTreeMap::new();
+        for (i, record) in records.iter().enumerate() {
+            let (_, record) = record.as_ref().unwrap();
+            chains
+                .entry((record.change_id(), record.workflow()))
+                .or_default()
+                .push(i)
+        }
+        let mut runs = Vec::new();
+        for chain in chains.values() {
+            for run in chain.split(|&i| records[i].as_ref().unwrap().1.transition().is_none()) {
+                if run.len() >= 2 {
+                    runs.push(run.to_vec())
+                }
+            }
+        }
+        if runs.is_empty() {
+            return Ok(Compaction {
+                before,
+                after: before,
+            });
+        }
+
+        for run in runs {
+            let mut checkpoint = fold(run.iter().map(|&i| records[i].as_ref().unwrap()));
+            checkpoint.signature = sign(&checkpoint.signed_bytes());
+            let checkpoint = AuditRecord::Checkpoint(checkpoint);
+            let line = serde_json::to_string(&checkpoint).map_err(AuditLogError::Encode)?;
+            for &i in run.iter() {
+                records[i] = None
+            }
+            // The checkpoint takes the place of the last folded record.
+            records[*run.last().unwrap()] = Some((line, checkpoint));
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        let mut after = 0;
+        for (line, _) in records.iter().flatten() {
+            file.write_all(line.as_bytes())?;
+            file.write_all(b"\n")?;
+            after += 1;
+        }
+        file.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(Compaction { before, after })
+    }
+}
+
+/// An unsigned checkpoint folding `run`, consecutive records of a chain
+/// which all have a transition
+fn fold<'a, I: Iterator<Item = &'a (String, AuditRecord)>>(run: I) -> Checkpoint {
+    let mut checkpoint: Option<Checkpoint> = None;
+    for (line, record) in run {
+        let (from, to) = record.transition().unwrap();
+        let (first, events, kind) = match record {
+            AuditRecord::Checkpoint(c) => (c.first, c.events, c.kind),
+            AuditRecord::Entry(e) => (e.timestamp, 1, e.kind),
+        };
+        let c = checkpoint.get_or_insert_with(|| Checkpoint {
+            workflow: record.workflow().to_string(),
+            kind,
+            change_id: record.change_id().to_string(),
+            from: from.to_string(),
+            to: String::new(),
+            events: 0,
+            first,
+            last: first,
+            prev: record.prev().map(String::from),
+            folded: String::new(),
+            signature: None,
+        });
+        c.to = to.to_string();
+        c.events += events;
+        c.last = record.timestamp();
+        c.folded = record.hash(line);
+    }
+    checkpoint.unwrap()
+}
+
+/// Iterator over the records of a [`WorkflowAuditLog`]
+pub struct AuditIter {
+    lines: Option<std::io::Lines<BufReader<File>>>,
+    line: usize,
+}
+
+impl AuditIter {
+    /// The next record, with its line number and line
+    fn next_line(&mut self) -> Option<Result<(usize, String, AuditRecord), AuditLogError>> {
+        loop {
+            let line = match self.lines.as_mut()?.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+            self.line += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            return Some(match serde_json::from_str(&line) {
+                Ok(record) => Ok((self.line, line, record)),
+                Err(source) => Err(AuditLogError::Decode {
+                    line: self.line,
+                    source,
+                }),
+            });
+        }
+    }
+}
+
+impl Iterator for AuditIter {
+    type Item = Result<AuditRecord, AuditLogError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_line()?.map(|(_, _, record)| record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simple::{SimpleApprovalState, SimpleApprovalWorkflow};
+
+    #[test]
+    fn test_replay_history() {
+        let tmp = tempfile::tempdir().unwrap();
+        let log = WorkflowAuditLog::in_dot_dir(tmp.path());
+        assert_eq!(log.iter().unwrap().count(), 0);
+
+        let mut context = WorkflowContext::new(
+            "AAAA".to_string(),
+            Author::default(),
+            "Recorded".to_string(),
+        );
+        let event = context.grant_role("developer".to_string());
+        log.record(SimpleApprovalWorkflow::NAME, &context, &event)
+            .unwrap();
+        let event = SimpleApprovalWorkflow::execute_transition(
+            SimpleApprovalState::Recorded,
+            SimpleApprovalState::Review,
+            &mut context,
+        )
+        .unwrap();
+        log.record(SimpleApprovalWorkflow::NAME, &context, &event)
+            .unwrap();
+
+        // Another change's events are interleaved.
+        let other = WorkflowContext::new(
+            "BBBB".to_string(),
+            Author::default(),
+            "Recorded".to_string(),
+        );
+        let rejected = WorkflowEvent::ChangeRejected {
+            reason: "Too large".to_string(),
+        };
+        log.record(SimpleApprovalWorkflow::NAME, &other, &rejected)
+            .unwrap();
+
+        let history: Vec<_> = log
+            .history("AAAA")
+            .unwrap()
+            .map(|record| match record.unwrap() {
+                AuditRecord::Entry(entry) => entry.event,
+                AuditRecord::Checkpoint(c) => panic!("unexpected checkpoint {:?}", c),
+            })
+            .collect();
+        assert_eq!(
+            history,
+            vec![
+                WorkflowEvent::RoleGranted {
+                    role: "developer".to_string(),
+                    user: String::new(),
+                },
+                WorkflowEvent::StateChanged {
+                    from: "Recorded".to_string(),
+                    to: "Review".to_string(),
+                }
+            ]
+        );
+        assert_eq!(log.iter().unwrap().count(), 3);
+
+        // A torn line fails on its own, the other entries are still read.
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(log.path())
+            .unwrap()
+            .write_all(b"{\"timestamp\"\n")
+            .unwrap();
+        let entries: Vec<_> = log.iter().unwrap().collect();
+        assert_eq!(entries.len(), 4);
+        assert!(matches!(
+            entries[3],
+            Err(AuditLogError::Decode { line: 4, .. })
+        ));
+    }
+
+    fn state_changed(from: &str, to: &str) -> WorkflowEvent {
+        WorkflowEvent::StateChanged {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_compact() {
+        let tmp = tempfile::tempdir().unwrap();
+        let log = WorkflowAuditLog::in_dot_dir(tmp.path());
+        let context = WorkflowContext::new(
+            "AAAA".to_string(),
+            Author::default(),
+            "Recorded".to_string(),
+        );
+        let other = WorkflowContext::new(
+            "BBBB".to_string(),
+            Author::default(),
+            "Recorded".to_string(),
+        );
+        let workflow = SimpleApprovalWorkflow::NAME;
+        for _ in 0..3 {
+            log.record(workflow, &context, &state_changed("Recorded", "Review"))
+                .unwrap();
+            log.record(workflow, &other, &state_changed("Recorded", "Review"))
+                .unwrap();
+            log.record(workflow, &context, &state_changed("Review", "Recorded"))
+                .unwrap();
+        }
+        let rejected = WorkflowEvent::ChangeRejected {
+            reason: "Too large".to_string(),
+        };
+        log.record(workflow, &context, &rejected).unwrap();
+        log.record(workflow, &context, &state_changed("Recorded", "Review"))
+            .unwrap();
+        assert_eq!(log.verify("AAAA", |_| false).unwrap(), 8);
+
+        let sign = |bytes: &[u8]| Some(serde_json::Value::from(bytes.len()));
+        let valid = |c: &Checkpoint| c.signature == Some(c.signed_bytes().len().into());
+        assert_eq!(
+            log.compact(sign).unwrap(),
+            Compaction {
+                before: 11,
+                after: 4
+            }
+        );
+        let history: Vec<_> = log
+            .history("AAAA")
+            .unwrap()
+            .map(|record| record.unwrap().to_string())
+            .collect();
+        assert_eq!(
+            history,
+            vec![
+                "Recorded -> Recorded (6 state changes)",
+                "rejected: Too large",
+                "Recorded -> Review"
+            ]
+        );
+        assert_eq!(log.verify("AAAA", valid).unwrap(), 3);
+        assert_eq!(log.verify("BBBB", valid).unwrap(), 1);
+
+        // Checkpoints are extended by the next compaction.
+        log.record(workflow, &context, &state_changed("Review", "Approved"))
+            .unwrap();
+        assert_eq!(log.verify("AAAA", valid).unwrap(), 4);
+        log.compact(sign).unwrap();
+        let history: Vec<_> = log
+            .history("AAAA")
+            .unwrap()
+            .map(|record| record.unwrap().to_string())
+            .collect();
+        assert_eq!(
+            history.last().unwrap(),
+            "Recorded -> Approved (2 state changes)"
+        );
+        assert_eq!(log.verify("AAAA", valid).unwrap(), 3);
+        assert!(matches!(
+            log.verify("AAAA", |_| false),
+            Err(AuditLogError::InvalidCheckpoint { line: 2 })
+        ));
+
+        // Removing a record breaks the chain.
+        let contents = std::fs::read_to_string(log.path()).unwrap();
+        let lines: Vec<_> = contents.lines().collect();
+        std::fs::write(log.path(), format!("{}\n{}\n", lines[0], lines[3])).unwrap();
+        assert!(matches!(
+            log.verify("AAAA", valid),
+            Err(AuditLogError::BrokenChain { line: 2, .. })
+        ));
+    }
+}
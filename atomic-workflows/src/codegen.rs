@@ -0,0 +1,216 @@
+//! Client code generation for workflow definitions
+//!
+//! Emits TypeScript and JSON Schema descriptions of a workflow's states,
+//! transitions, roles and triggers from the [`WorkflowDefinition`]
+//! generated by [`simple_workflow!`](crate::simple_workflow), so that
+//! clients stay in lockstep with the server-side workflow.
+
+use crate::simple::WorkflowDefinition;
+use serde_json::json;
+use std::fmt::Write;
+
+/// Quote a string as a TypeScript string literal
+fn quote(s: &str) -> String {
+    serde_json::Value::from(s).to_string()
+}
+
+/// Union of string literal types, or `never` if empty
+fn union(items: &[&str]) -> String {
+    if items.is_empty() {
+        "never".to_string()
+    } else {
+        items
+            .iter()
+            .map(|i| quote(i))
+            .collect::<Vec<_>>()
+            .join(" | ")
+    }
+}
+
+/// Generate a TypeScript module describing `workflow`
+pub fn to_typescript(workflow: &WorkflowDefinition) -> String {
+    let name = &workflow.name;
+    let mut ts = String::new();
+    writeln!(
+        ts,
+        "// Generated by atomic-workflows from the {} workflow definition. Do not edit.\n",
+        quote(name)
+    )
+    .unwrap();
+
+    let states: Vec<&str> = workflow.states.iter().map(|s| s.id.as_str()).collect();
+    writeln!(
+        ts,
+        "export const {}States = [{}] as const;",
+        name,
+        states
+            .iter()
+            .map(|s| quote(s))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+    .unwrap();
+    writeln!(
+        ts,
+        "export type {}State = (typeof {}States)[number];\n",
+        name, name
+    )
+    .unwrap();
+
+    writeln!(
+        ts,
+        "export const {}InitialState: {}State = {};\n",
+        name,
+        name,
+        quote(&workflow.initial_state)
+    )
+    .unwrap();
+
+    writeln!(
+        ts,
+        "export const {}StateNames: Record<{}State, string> = {{",
+        name, name
+    )
+    .unwrap();
+    for state in workflow.states.iter() {
+        writeln!(ts, "  {}: {},", state.id, quote(&state.name)).unwrap();
+    }
+    writeln!(ts, "}};\n").unwrap();
+
+    writeln!(
+        ts,
+        "export const {}ApprovalStates: readonly {}State[] = [{}];\n",
+        name,
+        name,
+        workflow
+            .states
+            .iter()
+            .filter(|s| s.can_approve)
+            .map(|s| quote(&s.id))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+    .unwrap();
+
+    writeln!(
+        ts,
+        "export type {}Role = {};",
+        name,
+        union(&workflow.roles())
+    )
+    .unwrap();
+    writeln!(
+        ts,
+        "export type {}Trigger = {};\n",
+        name,
+        union(&workflow.triggers())
+    )
+    .unwrap();
+
+    writeln!(ts, "export interface {}Transition {{", name).unwrap();
+    writeln!(ts, "  from: {}State;", name).unwrap();
+    writeln!(ts, "  to: {}State;", name).unwrap();
+    writeln!(ts, "  trigger: {}Trigger;", name).unwrap();
+    writeln!(ts, "  needsRole: {}Role | null;", name).unwrap();
+    writeln!(ts, "}}\n").unwrap();
+
+    writeln!(
+        ts,
+        "export const {}Transitions: readonly {}Transition[] = [",
+        name, name
+    )
+    .unwrap();
+    for t in workflow.transitions.iter() {
+        writeln!(
+            ts,
+            "  {{ from: {}, to: {}, trigger: {}, needsRole: {} }},",
+            quote(&t.from),
+            quote(&t.to),
+            quote(&t.trigger),
+            t.needs_role
+                .as_deref()
+                .map(quote)
+                .unwrap_or_else(|| "null".to_string())
+        )
+        .unwrap();
+    }
+    writeln!(ts, "];").unwrap();
+    ts
+}
+
+/// Generate a JSON Schema describing `workflow`. The root schema validates
+/// a workflow instance (`{ "state": ... }`), and the `$defs` section holds
+/// the states, roles, triggers and allowed transitions.
+pub fn to_json_schema(workflow: &WorkflowDefinition) -> serde_json::Value {
+    let transitions: Vec<_> = workflow
+        .transitions
+        .iter()
+        .map(|t| {
+            json!({
+                "type": "object",
+                "properties": {
+                    "from": { "const": t.from },
+                    "to": { "const": t.to },
+                    "trigger": { "const": t.trigger },
+                    "needsRole": { "const": t.needs_role },
+                },
+                "required": ["from", "to", "trigger", "needsRole"],
+            })
+        })
+        .collect();
+    let states: Vec<&str> = workflow.states.iter().map(|s| s.id.as_str()).collect();
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "$id": format!("urn:atomic-workflows:{}", workflow.name),
+        "title": workflow.name,
+        "type": "object",
+        "properties": {
+            "state": { "$ref": "#/$defs/State" },
+        },
+        "required": ["state"],
+        "$defs": {
+            "State": { "enum": states, "default": workflow.initial_state },
+            "Role": { "enum": workflow.roles() },
+            "Trigger": { "enum": workflow.triggers() },
+            "Transition": { "oneOf": transitions },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simple::SimpleApprovalWorkflow;
+
+    #[test]
+    fn test_typescript_generation() {
+        let ts = to_typescript(&SimpleApprovalWorkflow::definition());
+        assert!(ts.contains(
+            "export const SimpleApprovalStates = [\"Recorded\", \"Review\", \"Approved\", \"Rejected\"] as const;"
+        ));
+        assert!(ts.contains("export type SimpleApprovalRole = \"developer\" | \"reviewer\";"));
+        assert!(ts.contains(
+            "  { from: \"Review\", to: \"Approved\", trigger: \"approve\", needsRole: \"reviewer\" },"
+        ));
+        assert!(ts.contains(
+            "export const SimpleApprovalInitialState: SimpleApprovalState = \"Recorded\";"
+        ));
+    }
+
+    #[test]
+    fn test_json_schema_generation() {
+        let schema = to_json_schema(&SimpleApprovalWorkflow::definition());
+        assert_eq!(schema["title"], "SimpleApproval");
+        assert_eq!(
+            schema["$defs"]["Trigger"]["enum"],
+            json!(["submit", "approve", "reject"])
+        );
+        assert_eq!(
+            schema["$defs"]["Transition"]["oneOf"]
+                .as_array()
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+}
@@ -37,10 +37,22 @@
 //! }
 //! ```
 
+pub mod audit;
+pub mod codegen;
+#[cfg(feature = "pristine")]
+pub mod pristine;
+pub mod registry;
 pub mod simple;
+pub mod store;
 
 // Re-export the main types and macros
-pub use simple::{WorkflowContext, WorkflowError, WorkflowEvent};
+pub use audit::{AuditEntry, AuditLogError, AuditRecord, Checkpoint, Compaction, WorkflowAuditLog};
+pub use registry::{RegisteredWorkflow, WorkflowRegistry};
+pub use simple::{
+    Approval, GuardCheck, StateDefinition, TransitionDefinition, TransitionEvaluation,
+    WorkflowContext, WorkflowDefinition, WorkflowError, WorkflowEvent,
+};
+pub use store::{MemoryStore, WorkflowMutStore, WorkflowState, WorkflowStore};
 
 // Re-export the macro (automatically available due to #[macro_export])
 
@@ -91,4 +103,14 @@ mod tests {
 
         assert!(matches!(event, WorkflowEvent::StateChanged { .. }));
     }
+
+    #[test]
+    fn test_definition_matches_macro() {
+        let def = TestWorkflowWorkflow::definition();
+        assert_eq!(def.name, TestWorkflowWorkflow::NAME);
+        assert_eq!(def.initial_state, "Start");
+        assert_eq!(def.states.len(), 2);
+        assert_eq!(def.transitions[0].needs_role.as_deref(), Some("user"));
+        assert_eq!(def.transitions[0].trigger, "finish");
+    }
 }
@@ -0,0 +1,196 @@
+//! Workflow states in the pristine database
+//!
+//! Transactions of the Sanakirja pristine implement
+//! [`WorkflowStore`](crate::store::WorkflowStore), and mutable ones
+//! [`WorkflowMutStore`](crate::store::WorkflowMutStore). States live in
+//! their own root table, created by the first write, so that repositories
+//! without workflows are left untouched. Writes are only visible once the
+//! transaction is committed, which lets callers move a change through its
+//! workflow atomically with applying or recording it.
+//!
+//! Keys are the workflow name and the change id separated by a NUL byte,
+//! and values are the JSON encoding of [`WorkflowState`].
+
+use crate::store::{WorkflowMutStore, WorkflowState, WorkflowStore};
+use ::sanakirja::btree::page_unsized::Page;
+use ::sanakirja::{btree, LoadPage, RootDb, RootPage};
+use libatomic::pristine::sanakirja::{GenericTxn, MutTxn, Root, SanakirjaError, UDb};
+use libatomic::small_string::{SmallStr, SmallString, MAX_LENGTH};
+
+#[derive(Debug, thiserror::Error)]
+pub enum PristineStoreError {
+    #[error(transparent)]
+    Sanakirja(#[from] SanakirjaError),
+    #[error("Workflow name or change id too long: {workflow:?}, {change_id:?}")]
+    KeyTooLong { workflow: String, change_id: String },
+    #[error("Invalid workflow name: {0:?}")]
+    InvalidWorkflow(String),
+    #[error("Invalid workflow state: {0}")]
+    Decode(#[from] serde_json::Error),
+}
+
+impl From<::sanakirja::Error> for PristineStoreError {
+    fn from(e: ::sanakirja::Error) -> Self {
+        PristineStoreError::Sanakirja(e.into())
+    }
+}
+
+type States = UDb<SmallStr, [u8]>;
+
+fn key(workflow: &str, change_id: &str) -> Result<SmallString, PristineStoreError> {
+    if workflow.contains('\0') {
+        return Err(PristineStoreError::InvalidWorkflow(workflow.to_string()));
+    }
+    if workflow.len() + 1 + change_id.len() > MAX_LENGTH {
+        return Err(PristineStoreError::KeyTooLong {
+            workflow: workflow.to_string(),
+            change_id: change_id.to_string(),
+        });
+    }
+    let mut k = SmallString::from_str(workflow);
+    k.push_str("\0");
+    k.push_str(change_id);
+    Ok(k)
+}
+
+fn states<T: RootDb>(txn: &T) -> Option<States> {
+    txn.root_db::<SmallStr, [u8], Page<SmallStr, [u8]>>(Root::WorkflowStates as usize)
+}
+
+impl<T> WorkflowStore for GenericTxn<T>
+where
+    T: LoadPage<Error = ::sanakirja::Error> + RootPage + RootDb,
+{
+    type Error = PristineStoreError;
+
+    fn get_workflow_state(
+        &self,
+        workflow: &str,
+        change_id: &str,
+    ) -> Result<Option<WorkflowState>, Self::Error> {
+        let k = key(workflow, change_id)?;
+        let db = if let Some(db) = states(&self.txn) {
+            db
+        } else {
+            return Ok(None);
+        };
+        match btree::get(&self.txn, &db, &k, None)? {
+            Some((k_, v)) if k_.as_str() == k.as_str() => Ok(Some(serde_json::from_slice(v)?)),
+            _ => Ok(None),
+        }
+    }
+
+    fn iter_workflow_states(&self, workflow: &str) -> Result<Vec<WorkflowState>, Self::Error> {
+        let prefix = key(workflow, "")?;
+        let db = if let Some(db) = states(&self.txn) {
+            db
+        } else {
+            return Ok(Vec::new());
+        };
+        let mut result = Vec::new();
+        for x in btree::iter(&self.txn, &db, Some((&prefix, None)))? {
+            let (k, v) = x?;
+            if !k.as_str().starts_with(prefix.as_str()) {
+                break;
+            }
+            result.push(serde_json::from_slice(v)?)
+        }
+        Ok(result)
+    }
+}
+
+impl WorkflowMutStore for MutTxn<()> {
+    fn put_workflow_state(&mut self, state: &WorkflowState) -> Result<(), Self::Error> {
+        let k = key(&state.workflow, &state.change_id)?;
+        let v = serde_json::to_vec(state)?;
+        let mut db = if let Some(db) = states(&self.txn) {
+            db
+        } else {
+            unsafe { btree::create_db_(&mut self.txn)? }
+        };
+        btree::del(&mut self.txn, &mut db, &k, None)?;
+        btree::put(&mut self.txn, &mut db, &k, &v[..])?;
+        self.txn
+            .set_root(Root::WorkflowStates as usize, db.db.into());
+        Ok(())
+    }
+
+    fn del_workflow_state(&mut self, workflow: &str, change_id: &str) -> Result<bool, Self::Error> {
+        let k = key(workflow, change_id)?;
+        let mut db = if let Some(db) = states(&self.txn) {
+            db
+        } else {
+            return Ok(false);
+        };
+        let deleted = btree::del(&mut self.txn, &mut db, &k, None)?;
+        self.txn
+            .set_root(Root::WorkflowStates as usize, db.db.into());
+        Ok(deleted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_config::events::NodeKind;
+    use libatomic::pristine::sanakirja::Pristine;
+    use libatomic::MutTxnT;
+
+    fn state(workflow: &str, change_id: &str, state: &str) -> WorkflowState {
+        WorkflowState {
+            workflow: workflow.to_string(),
+            kind: NodeKind::Change,
+            change_id: change_id.to_string(),
+            state: state.to_string(),
+            approvals: Vec::new(),
+            reviewers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_states_survive_commit() {
+        let pristine = Pristine::new_anon().unwrap();
+        let mut txn = pristine.mut_txn_begin().unwrap();
+        assert!(txn
+            .iter_workflow_states("SimpleApproval")
+            .unwrap()
+            .is_empty());
+        assert!(!txn.del_workflow_state("SimpleApproval", "AAAA").unwrap());
+        txn.put_workflow_state(&state("SimpleApproval", "AAAA", "Review"))
+            .unwrap();
+        txn.put_workflow_state(&state("SimpleApproval", "BBBB", "Recorded"))
+            .unwrap();
+        txn.put_workflow_state(&state("SimpleApproval2", "AAAA", "Recorded"))
+            .unwrap();
+        txn.put_workflow_state(&state("SimpleApproval", "BBBB", "Review"))
+            .unwrap();
+        txn.commit().unwrap();
+
+        // Uncommitted writes are dropped.
+        let mut txn = pristine.mut_txn_begin().unwrap();
+        assert!(txn.del_workflow_state("SimpleApproval", "AAAA").unwrap());
+        std::mem::drop(txn);
+
+        let txn = pristine.txn_begin().unwrap();
+        assert_eq!(
+            txn.get_workflow_state("SimpleApproval", "AAAA").unwrap(),
+            Some(state("SimpleApproval", "AAAA", "Review"))
+        );
+        assert_eq!(
+            txn.get_workflow_state("SimpleApproval", "CCCC").unwrap(),
+            None
+        );
+        let states = txn.iter_workflow_states("SimpleApproval").unwrap();
+        assert_eq!(
+            states,
+            vec![
+                state("SimpleApproval", "AAAA", "Review"),
+                state("SimpleApproval", "BBBB", "Review"),
+            ]
+        );
+        assert!(matches!(
+            txn.get_workflow_state(&"W".repeat(300), "AAAA"),
+            Err(PristineStoreError::KeyTooLong { .. })
+        ));
+    }
+}
@@ -0,0 +1,154 @@
+//! Workflows by name
+//!
+//! Workflows defined with [`simple_workflow!`](crate::simple_workflow) are
+//! types, checked at compile time. Servers receive workflow names and
+//! triggers in requests instead: a [`WorkflowRegistry`] maps the names of
+//! the workflows they run to their definition and to the function firing
+//! their transitions by trigger.
+
+use crate::simple::{
+    TransitionEvaluation, WorkflowContext, WorkflowDefinition, WorkflowError, WorkflowEvent,
+};
+use std::collections::BTreeMap;
+
+/// A workflow in a [`WorkflowRegistry`], usually obtained from the
+/// `registered()` function generated by
+/// [`simple_workflow!`](crate::simple_workflow)
+#[derive(Debug, Clone, Copy)]
+pub struct RegisteredWorkflow {
+    pub definition: fn() -> WorkflowDefinition,
+    pub fire: fn(&mut WorkflowContext, &str) -> Result<WorkflowEvent, WorkflowError>,
+    pub evaluate: fn(&WorkflowContext, &str) -> Result<TransitionEvaluation, WorkflowError>,
+}
+
+/// Workflows by name
+#[derive(Debug, Clone, Default)]
+pub struct WorkflowRegistry {
+    workflows: BTreeMap<String, (WorkflowDefinition, RegisteredWorkflow)>,
+}
+
+impl WorkflowRegistry {
+    /// An empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A registry of the workflows of this crate
+    pub fn builtin() -> Self {
+        use crate::simple::{
+            ReleaseApprovalWorkflow, SimpleApprovalWorkflow, TwoStageApprovalWorkflow,
+        };
+        let mut registry = Self::new();
+        registry.register(SimpleApprovalWorkflow::registered());
+        registry.register(TwoStageApprovalWorkflow::registered());
+        registry.register(ReleaseApprovalWorkflow::registered());
+        registry
+    }
+
+    /// Add `workflow`, replacing any workflow with the same name
+    pub fn register(&mut self, workflow: RegisteredWorkflow) {
+        let definition = (workflow.definition)();
+        self.workflows
+            .insert(definition.name.clone(), (definition, workflow));
+    }
+
+    /// The definition of workflow `name`, if registered
+    pub fn definition(&self, name: &str) -> Option<&WorkflowDefinition> {
+        self.workflows.get(name).map(|(d, _)| d)
+    }
+
+    /// Definitions of the registered workflows, ordered by name
+    pub fn definitions(&self) -> impl Iterator<Item = &WorkflowDefinition> {
+        self.workflows.values().map(|(d, _)| d)
+    }
+
+    /// Fire the transition of workflow `name` triggered by `trigger` from
+    /// the current state of `context`. Returns `None` if there is no such
+    /// workflow.
+    pub fn fire(
+        &self,
+        name: &str,
+        context: &mut WorkflowContext,
+        trigger: &str,
+    ) -> Option<Result<WorkflowEvent, WorkflowError>> {
+        let (_, workflow) = self.workflows.get(name)?;
+        Some((workflow.fire)(context, trigger))
+    }
+
+    /// Evaluate the transition of workflow `name` triggered by `trigger`
+    /// from the current state of `context`, without executing it.
+    /// Returns `None` if there is no such workflow.
+    pub fn evaluate(
+        &self,
+        name: &str,
+        context: &WorkflowContext,
+        trigger: &str,
+    ) -> Option<Result<TransitionEvaluation, WorkflowError>> {
+        let (_, workflow) = self.workflows.get(name)?;
+        Some((workflow.evaluate)(context, trigger))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use atomic_config::Author;
+
+    #[test]
+    fn test_fire_by_name() {
+        let registry = WorkflowRegistry::builtin();
+        assert_eq!(
+            registry
+                .definitions()
+                .map(|d| d.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["ReleaseApproval", "SimpleApproval", "TwoStageApproval"]
+        );
+        let definition = registry.definition("SimpleApproval").unwrap();
+        let mut context = WorkflowContext::new(
+            "change-123".to_string(),
+            Author::default(),
+            definition.initial_state.clone(),
+        );
+        assert!(registry.fire("Unknown", &mut context, "submit").is_none());
+        assert!(registry.evaluate("Unknown", &context, "submit").is_none());
+        let evaluation = registry
+            .evaluate("SimpleApproval", &context, "submit")
+            .unwrap()
+            .unwrap();
+        assert!(!evaluation.allowed);
+        assert_eq!(context.current_state, "Recorded");
+
+        assert!(matches!(
+            registry.fire("SimpleApproval", &mut context, "submit"),
+            Some(Err(WorkflowError::NeedRole(_)))
+        ));
+        context.add_role("developer".to_string());
+        context.add_role("reviewer".to_string());
+        assert!(matches!(
+            registry.fire("SimpleApproval", &mut context, "approve"),
+            Some(Err(WorkflowError::UnknownTrigger { .. }))
+        ));
+        let event = registry
+            .fire("SimpleApproval", &mut context, "submit")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            event,
+            WorkflowEvent::StateChanged {
+                from: "Recorded".to_string(),
+                to: "Review".to_string(),
+            }
+        );
+        let triggers: Vec<_> = definition
+            .transitions_from(&context.current_state)
+            .map(|t| t.trigger.as_str())
+            .collect();
+        assert_eq!(triggers, vec!["approve", "reject"]);
+        registry
+            .fire("SimpleApproval", &mut context, "reject")
+            .unwrap()
+            .unwrap();
+        assert_eq!(context.current_state, "Rejected");
+    }
+}
@@ -5,26 +5,74 @@
 
 #![allow(unreachable_patterns)] // Macro-generated code may have unreachable patterns
 
+use atomic_config::events::NodeKind;
 use atomic_config::Author;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 
+/// An approval of a change or tag in its current state
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Approval {
+    pub author: Author,
+    pub timestamp: DateTime<Utc>,
+    /// Signature of the approval by the author's key. This crate doesn't
+    /// verify it, callers accepting approvals from remote users should.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl Approval {
+    /// An unsigned approval by `author`, now
+    pub fn new(author: Author) -> Self {
+        Approval {
+            author,
+            timestamp: Utc::now(),
+            signature: None,
+        }
+    }
+
+    /// Whether this approval and `other` are by the same person
+    pub fn same_approver(&self, other: &Approval) -> bool {
+        self.author.username == other.author.username && self.author.email == other.author.email
+    }
+}
+
 /// Simple workflow context for MVP
 #[derive(Debug, Clone)]
 pub struct WorkflowContext {
+    /// Whether the workflow runs on a change or on a tag
+    pub kind: NodeKind,
+    /// Hash of the change, or state of the tag
     pub change_id: String,
     pub author: Author,
     pub user_roles: HashSet<String>,
     pub current_state: String,
+    /// Approvals of the change or tag in its current state, at most one
+    /// per approver. They are cleared by transitions.
+    pub approvals: Vec<Approval>,
+    /// Users asked to review the change or tag, e.g. its code owners
+    pub reviewers: Vec<String>,
 }
 
 impl WorkflowContext {
     pub fn new(change_id: String, author: Author, current_state: String) -> Self {
         Self {
+            kind: NodeKind::Change,
             change_id,
             author,
             user_roles: HashSet::new(),
             current_state,
+            approvals: Vec::new(),
+            reviewers: Vec::new(),
+        }
+    }
+
+    /// Context of a workflow on the tag of `state`, e.g. a release approval
+    pub fn for_tag(state: String, author: Author, current_state: String) -> Self {
+        Self {
+            kind: NodeKind::Tag,
+            ..Self::new(state, author, current_state)
         }
     }
 
@@ -35,15 +83,89 @@ impl WorkflowContext {
     pub fn add_role(&mut self, role: String) {
         self.user_roles.insert(role);
     }
+
+    /// Grant `role` to the author of this context, returning the event to
+    /// log, e.g. in a [`WorkflowAuditLog`](crate::audit::WorkflowAuditLog)
+    pub fn grant_role(&mut self, role: String) -> WorkflowEvent {
+        let event = WorkflowEvent::RoleGranted {
+            role: role.clone(),
+            user: self.author.username.clone(),
+        };
+        self.add_role(role);
+        event
+    }
+
+    /// Record `approval`, replacing any earlier approval by the same
+    /// approver
+    pub fn add_approval(&mut self, approval: Approval) {
+        self.approvals.retain(|a| !a.same_approver(&approval));
+        self.approvals.push(approval)
+    }
+
+    /// Ask `reviewers` to review, in addition to the reviewers already
+    /// assigned, returning the event to log if any of them is new
+    pub fn assign_reviewers<I: IntoIterator<Item = String>>(
+        &mut self,
+        reviewers: I,
+    ) -> Option<WorkflowEvent> {
+        let mut new = Vec::new();
+        for r in reviewers {
+            if !self.reviewers.contains(&r) && !new.contains(&r) {
+                new.push(r)
+            }
+        }
+        if new.is_empty() {
+            return None;
+        }
+        self.reviewers.extend(new.iter().cloned());
+        Some(WorkflowEvent::ReviewersAssigned { reviewers: new })
+    }
 }
 
 /// Simple workflow events
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum WorkflowEvent {
     StateChanged { from: String, to: String },
     ApprovalRequired { reviewer_role: String },
     ChangeApproved { approver: String },
     ChangeRejected { reason: String },
+    RoleGranted { role: String, user: String },
+    Started { state: String },
+    ReviewersAssigned { reviewers: Vec<String> },
+}
+
+impl std::fmt::Display for WorkflowEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WorkflowEvent::StateChanged { from, to } => write!(f, "{} -> {}", from, to),
+            WorkflowEvent::ApprovalRequired { reviewer_role } => {
+                write!(f, "approval required from {}", reviewer_role)
+            }
+            WorkflowEvent::ChangeApproved { approver } => write!(f, "approved by {}", approver),
+            WorkflowEvent::ChangeRejected { reason } => write!(f, "rejected: {}", reason),
+            WorkflowEvent::RoleGranted { role, user } => {
+                write!(f, "role {} granted to {}", role, user)
+            }
+            WorkflowEvent::Started { state } => write!(f, "started in {}", state),
+            WorkflowEvent::ReviewersAssigned { reviewers } => {
+                write!(f, "review requested from {}", reviewers.join(", "))
+            }
+        }
+    }
+}
+
+/// Publish state changes to the event bus, so that embedders see workflow
+/// transitions alongside the other subsystems' events.
+pub fn publish_event(workflow: &str, context: &WorkflowContext, event: &WorkflowEvent) {
+    if let WorkflowEvent::StateChanged { from, to } = event {
+        atomic_config::events::publish(atomic_config::events::Event::WorkflowTransition {
+            workflow: workflow.to_string(),
+            kind: context.kind,
+            change_id: context.change_id.clone(),
+            from: from.clone(),
+            to: to.clone(),
+        });
+    }
 }
 
 /// Simple workflow errors
@@ -53,9 +175,147 @@ pub enum WorkflowError {
     NeedRole(String),
     #[error("Cannot transition from '{from}' to '{to}'")]
     InvalidTransition { from: String, to: String },
+    #[error("No transition triggered by '{trigger}' from '{state}'")]
+    UnknownTrigger { state: String, trigger: String },
+    #[error("Need {required} approvals, got {approvals}")]
+    QuorumNotReached { required: usize, approvals: usize },
+    #[error("Guard `{guard}` forbids the transition from '{from}' to '{to}'")]
+    GuardFailed {
+        from: String,
+        to: String,
+        guard: String,
+    },
+}
+
+/// A requirement of a transition, as checked by a dry run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "check", rename_all = "snake_case")]
+pub enum GuardCheck {
+    /// The user needs `role`
+    Role { role: String, passed: bool },
+    /// The transition needs `required` distinct approvals
+    Quorum {
+        required: usize,
+        approvals: usize,
+        passed: bool,
+    },
+    /// The guard of the transition, by its source
+    Guard { guard: String, passed: bool },
+}
+
+impl GuardCheck {
+    pub fn passed(&self) -> bool {
+        match *self {
+            GuardCheck::Role { passed, .. }
+            | GuardCheck::Quorum { passed, .. }
+            | GuardCheck::Guard { passed, .. } => passed,
+        }
+    }
+}
+
+/// Whether a transition would succeed, evaluated without executing it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransitionEvaluation {
+    pub trigger: String,
+    pub from: String,
+    pub to: String,
+    /// Requirements of the transition, in the order firing checks them.
+    /// Unlike firing, which stops at the first failure, a dry run
+    /// evaluates them all.
+    pub checks: Vec<GuardCheck>,
+    /// Whether all the checks pass
+    pub allowed: bool,
+}
+
+impl TransitionEvaluation {
+    pub fn new(trigger: &str, from: &str, to: &str, checks: Vec<GuardCheck>) -> Self {
+        TransitionEvaluation {
+            trigger: trigger.to_string(),
+            from: from.to_string(),
+            to: to.to_string(),
+            allowed: checks.iter().all(GuardCheck::passed),
+            checks,
+        }
+    }
+}
+
+/// Runtime description of a workflow, generated by [`simple_workflow!`]
+/// from the same definition as the state enum and transition checks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorkflowDefinition {
+    pub name: String,
+    pub initial_state: String,
+    pub states: Vec<StateDefinition>,
+    pub transitions: Vec<TransitionDefinition>,
+}
+
+/// A state of a [`WorkflowDefinition`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StateDefinition {
+    pub id: String,
+    pub name: String,
+    pub can_approve: bool,
+}
+
+/// A transition of a [`WorkflowDefinition`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransitionDefinition {
+    pub from: String,
+    pub to: String,
+    pub needs_role: Option<String>,
+    /// Number of distinct approvals the transition needs, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quorum: Option<usize>,
+    /// Source of the guard of the transition, if any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guard: Option<String>,
+    pub trigger: String,
+}
+
+impl WorkflowDefinition {
+    /// Roles required by the transitions, in order of first appearance
+    pub fn roles(&self) -> Vec<&str> {
+        let mut roles = Vec::new();
+        for role in self
+            .transitions
+            .iter()
+            .filter_map(|t| t.needs_role.as_deref())
+        {
+            if !roles.contains(&role) {
+                roles.push(role)
+            }
+        }
+        roles
+    }
+
+    /// Transitions leaving `state`
+    pub fn transitions_from<'a>(
+        &'a self,
+        state: &'a str,
+    ) -> impl Iterator<Item = &'a TransitionDefinition> + 'a {
+        self.transitions.iter().filter(move |t| t.from == state)
+    }
+
+    /// Triggers of the transitions, in order of first appearance
+    pub fn triggers(&self) -> Vec<&str> {
+        let mut triggers = Vec::new();
+        for trigger in self.transitions.iter().map(|t| t.trigger.as_str()) {
+            if !triggers.contains(&trigger) {
+                triggers.push(trigger)
+            }
+        }
+        triggers
+    }
 }
 
 /// Simple workflow macro - just the essentials
+///
+/// Besides `needs_role`, a transition can need a `quorum` of distinct
+/// approvals in the current state (see [`WorkflowContext::add_approval`]),
+/// and have a `guard`, a predicate on the [`WorkflowContext`] checked
+/// last, e.g. `guard: |ctx| ctx.approvals.len() >= 2,`. Guards are plain
+/// functions (or closures capturing nothing), so that definitions stay
+/// static.
 #[macro_export]
 macro_rules! simple_workflow {
     (
@@ -75,6 +335,8 @@ macro_rules! simple_workflow {
             $(
                 $from_state:ident -> $to_state:ident {
                     $(needs_role: $role:literal,)?
+                    $(quorum: $quorum:literal,)?
+                    $(guard: $guard:expr,)?
                     trigger: $trigger:literal,
                 }
             )*
@@ -94,6 +356,36 @@ macro_rules! simple_workflow {
                 #[allow(dead_code)]
                 pub const INITIAL_STATE: [<$name State>] = [<$name State>]::$initial;
 
+                /// Runtime description of this workflow, e.g. for code generation
+                #[allow(dead_code)]
+                pub fn definition() -> $crate::simple::WorkflowDefinition {
+                    $crate::simple::WorkflowDefinition {
+                        name: $name.to_string(),
+                        initial_state: stringify!($initial).to_string(),
+                        states: vec![
+                            $(
+                                $crate::simple::StateDefinition {
+                                    id: stringify!($state).to_string(),
+                                    name: $state_name.to_string(),
+                                    can_approve: false $(|| $can_approve)?,
+                                },
+                            )*
+                        ],
+                        transitions: vec![
+                            $(
+                                $crate::simple::TransitionDefinition {
+                                    from: stringify!($from_state).to_string(),
+                                    to: stringify!($to_state).to_string(),
+                                    needs_role: None $(.or(Some($role.to_string())))?,
+                                    quorum: None $(.or(Some($quorum)))?,
+                                    guard: None $(.or(Some(stringify!($guard).to_string())))?,
+                                    trigger: $trigger.to_string(),
+                                },
+                            )*
+                        ],
+                    }
+                }
+
                 #[allow(dead_code)]
                 pub fn get_state_name(state: &[<$name State>]) -> &'static str {
                     match state {
@@ -114,6 +406,24 @@ macro_rules! simple_workflow {
                                         return Err($crate::simple::WorkflowError::NeedRole($role.to_string()));
                                     }
                                 )?
+                                $(
+                                    if context.approvals.len() < $quorum {
+                                        return Err($crate::simple::WorkflowError::QuorumNotReached {
+                                            required: $quorum,
+                                            approvals: context.approvals.len(),
+                                        });
+                                    }
+                                )?
+                                $(
+                                    let guard: fn(&$crate::simple::WorkflowContext) -> bool = $guard;
+                                    if !guard(context) {
+                                        return Err($crate::simple::WorkflowError::GuardFailed {
+                                            from: format!("{:?}", from),
+                                            to: format!("{:?}", to),
+                                            guard: stringify!($guard).to_string(),
+                                        });
+                                    }
+                                )?
                                 Ok(())
                             },
                         )*
@@ -132,13 +442,94 @@ macro_rules! simple_workflow {
                     Self::can_transition(&from, &to, context)?;
 
                     context.current_state = format!("{:?}", to);
+                    context.approvals.clear();
 
-                    Ok($crate::simple::WorkflowEvent::StateChanged {
+                    let event = $crate::simple::WorkflowEvent::StateChanged {
                         from: format!("{:?}", from),
                         to: format!("{:?}", to),
+                    };
+                    $crate::simple::publish_event($name, context, &event);
+                    Ok(event)
+                }
+
+                /// Execute the transition triggered by `trigger` from the
+                /// current state of `context`
+                #[allow(dead_code)]
+                pub fn fire(
+                    context: &mut $crate::simple::WorkflowContext,
+                    trigger: &str,
+                ) -> Result<$crate::simple::WorkflowEvent, $crate::simple::WorkflowError> {
+                    $(
+                        if context.current_state == stringify!($from_state) && trigger == $trigger {
+                            return Self::execute_transition(
+                                [<$name State>]::$from_state,
+                                [<$name State>]::$to_state,
+                                context,
+                            );
+                        }
+                    )*
+                    Err($crate::simple::WorkflowError::UnknownTrigger {
+                        state: context.current_state.clone(),
+                        trigger: trigger.to_string(),
                     })
                 }
 
+                /// Evaluate the requirements of the transition triggered by
+                /// `trigger` from the current state of `context`, without
+                /// executing it
+                #[allow(dead_code)]
+                pub fn evaluate(
+                    context: &$crate::simple::WorkflowContext,
+                    trigger: &str,
+                ) -> Result<$crate::simple::TransitionEvaluation, $crate::simple::WorkflowError> {
+                    $(
+                        if context.current_state == stringify!($from_state) && trigger == $trigger {
+                            #[allow(unused_mut)]
+                            let mut checks = Vec::new();
+                            $(
+                                checks.push($crate::simple::GuardCheck::Role {
+                                    role: $role.to_string(),
+                                    passed: context.user_has_role($role),
+                                });
+                            )?
+                            $(
+                                checks.push($crate::simple::GuardCheck::Quorum {
+                                    required: $quorum,
+                                    approvals: context.approvals.len(),
+                                    passed: context.approvals.len() >= $quorum,
+                                });
+                            )?
+                            $(
+                                let guard: fn(&$crate::simple::WorkflowContext) -> bool = $guard;
+                                checks.push($crate::simple::GuardCheck::Guard {
+                                    guard: stringify!($guard).to_string(),
+                                    passed: guard(context),
+                                });
+                            )?
+                            return Ok($crate::simple::TransitionEvaluation::new(
+                                trigger,
+                                stringify!($from_state),
+                                stringify!($to_state),
+                                checks,
+                            ));
+                        }
+                    )*
+                    Err($crate::simple::WorkflowError::UnknownTrigger {
+                        state: context.current_state.clone(),
+                        trigger: trigger.to_string(),
+                    })
+                }
+
+                /// This workflow, to register in a `WorkflowRegistry`
+                #[allow(dead_code)]
+                pub fn registered() -> $crate::registry::RegisteredWorkflow {
+                    $crate::registry::RegisteredWorkflow {
+                        definition: Self::definition,
+                        fire: Self::fire,
+                        evaluate: Self::evaluate,
+                    }
+                }
+
                 #[allow(dead_code)]
                 pub fn get_available_transitions(
                     state: &[<$name State>]
@@ -159,6 +550,39 @@ macro_rules! simple_workflow {
 
 // Example MVP workflows for testing
 
+simple_workflow! {
+    name: "ReleaseApproval",
+    initial_state: Draft,
+
+    states: {
+        Draft {
+            name: "Draft Release",
+        }
+        QualityAssurance {
+            name: "QA",
+        }
+        Released {
+            name: "Released",
+            can_approve: true,
+        }
+    },
+
+    transitions: {
+        Draft -> QualityAssurance {
+            needs_role: "release_manager",
+            trigger: "submit_qa",
+        }
+        QualityAssurance -> Released {
+            needs_role: "qa",
+            trigger: "release",
+        }
+        QualityAssurance -> Draft {
+            needs_role: "qa",
+            trigger: "reject",
+        }
+    }
+}
+
 simple_workflow! {
     name: "SimpleApproval",
     initial_state: Recorded,
@@ -277,6 +701,84 @@ mod tests {
         assert_eq!(context.current_state, "Approved");
     }
 
+    #[test]
+    fn test_transition_is_published() {
+        use atomic_config::events::{self, Event};
+
+        let receiver = events::global().channel();
+        let mut context = WorkflowContext::new(
+            "change-published".to_string(),
+            Author::default(),
+            "Recorded".to_string(),
+        );
+        context.add_role("developer".to_string());
+        SimpleApprovalWorkflow::execute_transition(
+            SimpleApprovalState::Recorded,
+            SimpleApprovalState::Review,
+            &mut context,
+        )
+        .unwrap();
+
+        // Other tests publish to the same bus concurrently.
+        let published = receiver.try_iter().find(|e| {
+            matches!(e, Event::WorkflowTransition { change_id, .. } if change_id == "change-published")
+        });
+        assert_eq!(
+            published,
+            Some(Event::WorkflowTransition {
+                workflow: "SimpleApproval".to_string(),
+                kind: NodeKind::Change,
+                change_id: "change-published".to_string(),
+                from: "Recorded".to_string(),
+                to: "Review".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_release_approval_on_tag() {
+        use atomic_config::events::{self, Event};
+
+        let receiver = events::global().channel();
+        let mut context = WorkflowContext::for_tag(
+            "tag-state-published".to_string(),
+            Author::default(),
+            "Draft".to_string(),
+        );
+        context.add_role("release_manager".to_string());
+        ReleaseApprovalWorkflow::execute_transition(
+            ReleaseApprovalState::Draft,
+            ReleaseApprovalState::QualityAssurance,
+            &mut context,
+        )
+        .unwrap();
+        assert!(ReleaseApprovalWorkflow::execute_transition(
+            ReleaseApprovalState::QualityAssurance,
+            ReleaseApprovalState::Released,
+            &mut context,
+        )
+        .is_err());
+        context.add_role("qa".to_string());
+        ReleaseApprovalWorkflow::execute_transition(
+            ReleaseApprovalState::QualityAssurance,
+            ReleaseApprovalState::Released,
+            &mut context,
+        )
+        .unwrap();
+        assert_eq!(context.current_state, "Released");
+
+        let kinds: Vec<_> = receiver
+            .try_iter()
+            .filter_map(|e| match e {
+                Event::WorkflowTransition {
+                    kind, change_id, ..
+                } if change_id == "tag-state-published" => Some(kind),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(kinds, vec![NodeKind::Tag, NodeKind::Tag]);
+    }
+
     #[test]
     fn test_insufficient_permissions() {
         let mut context = WorkflowContext::new(
@@ -296,6 +798,234 @@ mod tests {
         assert!(matches!(result.unwrap_err(), WorkflowError::NeedRole(_)));
     }
 
+    simple_workflow! {
+        name: "GuardedApproval",
+        initial_state: Review,
+
+        states: {
+            Review {
+                name: "Under Review",
+            }
+            Approved {
+                name: "Approved",
+            }
+        },
+
+        transitions: {
+            Review -> Approved {
+                needs_role: "reviewer",
+                guard: |ctx| ctx.approvals.len() >= 2,
+                trigger: "approve",
+            }
+        }
+    }
+
+    #[test]
+    fn test_guarded_transition() {
+        let mut context = WorkflowContext::new(
+            "change-789".to_string(),
+            Author::default(),
+            "Review".to_string(),
+        );
+
+        // The role is checked before the guard.
+        let result = GuardedApprovalWorkflow::execute_transition(
+            GuardedApprovalState::Review,
+            GuardedApprovalState::Approved,
+            &mut context,
+        );
+        assert!(matches!(result, Err(WorkflowError::NeedRole(_))));
+
+        context.add_role("reviewer".to_string());
+        context.add_approval(Approval::new(Author::default()));
+        let result = GuardedApprovalWorkflow::execute_transition(
+            GuardedApprovalState::Review,
+            GuardedApprovalState::Approved,
+            &mut context,
+        );
+        match result {
+            Err(WorkflowError::GuardFailed { from, to, guard }) => {
+                assert_eq!((from.as_str(), to.as_str()), ("Review", "Approved"));
+                assert!(guard.contains("approvals.len() >= 2"));
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(context.current_state, "Review");
+
+        context.add_approval(Approval::new(Author {
+            username: "bob".to_string(),
+            ..Author::default()
+        }));
+        GuardedApprovalWorkflow::execute_transition(
+            GuardedApprovalState::Review,
+            GuardedApprovalState::Approved,
+            &mut context,
+        )
+        .unwrap();
+        assert_eq!(context.current_state, "Approved");
+        assert!(context.approvals.is_empty());
+
+        let def = GuardedApprovalWorkflow::definition();
+        assert!(def.transitions[0].guard.is_some());
+        assert_eq!(
+            SimpleApprovalWorkflow::definition().transitions[0].guard,
+            None
+        );
+    }
+
+    simple_workflow! {
+        name: "QuorumApproval",
+        initial_state: Review,
+
+        states: {
+            Review {
+                name: "Under Review",
+            }
+            Approved {
+                name: "Approved",
+            }
+        },
+
+        transitions: {
+            Review -> Approved {
+                needs_role: "maintainer",
+                quorum: 2,
+                trigger: "merge",
+            }
+        }
+    }
+
+    #[test]
+    fn test_quorum() {
+        let approver = |username: &str| Author {
+            username: username.to_string(),
+            ..Author::default()
+        };
+        let mut context = WorkflowContext::new(
+            "change-q".to_string(),
+            approver("carol"),
+            "Review".to_string(),
+        );
+        context.add_role("maintainer".to_string());
+
+        // Approving twice doesn't count twice.
+        context.add_approval(Approval::new(approver("alice")));
+        context.add_approval(Approval {
+            signature: Some("sig".to_string()),
+            ..Approval::new(approver("alice"))
+        });
+        assert_eq!(context.approvals.len(), 1);
+        assert_eq!(context.approvals[0].signature.as_deref(), Some("sig"));
+        let result = QuorumApprovalWorkflow::execute_transition(
+            QuorumApprovalState::Review,
+            QuorumApprovalState::Approved,
+            &mut context,
+        );
+        assert!(matches!(
+            result,
+            Err(WorkflowError::QuorumNotReached {
+                required: 2,
+                approvals: 1
+            })
+        ));
+
+        context.add_approval(Approval::new(approver("bob")));
+        QuorumApprovalWorkflow::execute_transition(
+            QuorumApprovalState::Review,
+            QuorumApprovalState::Approved,
+            &mut context,
+        )
+        .unwrap();
+        assert_eq!(context.current_state, "Approved");
+        assert_eq!(
+            QuorumApprovalWorkflow::definition().transitions[0].quorum,
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn test_assign_reviewers() {
+        let mut context = WorkflowContext::new(
+            "change-r".to_string(),
+            Author::default(),
+            "Recorded".to_string(),
+        );
+        let event = context.assign_reviewers(vec![
+            "alice".to_string(),
+            "bob".to_string(),
+            "alice".to_string(),
+        ]);
+        assert_eq!(
+            event,
+            Some(WorkflowEvent::ReviewersAssigned {
+                reviewers: vec!["alice".to_string(), "bob".to_string()]
+            })
+        );
+        assert!(context.assign_reviewers(vec!["bob".to_string()]).is_none());
+        assert_eq!(context.reviewers.len(), 2);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let mut context = WorkflowContext::new(
+            "change-e".to_string(),
+            Author::default(),
+            "Review".to_string(),
+        );
+        context.add_approval(Approval::new(Author::default()));
+
+        // All the checks are evaluated, not only the first failing one.
+        let evaluation = GuardedApprovalWorkflow::evaluate(&context, "approve").unwrap();
+        assert_eq!(
+            (evaluation.from.as_str(), evaluation.to.as_str()),
+            ("Review", "Approved")
+        );
+        assert!(!evaluation.allowed);
+        assert_eq!(evaluation.checks.len(), 2);
+        assert_eq!(
+            evaluation.checks[0],
+            GuardCheck::Role {
+                role: "reviewer".to_string(),
+                passed: false,
+            }
+        );
+        match evaluation.checks[1] {
+            GuardCheck::Guard {
+                ref guard,
+                passed: false,
+            } => assert!(guard.contains("approvals.len() >= 2")),
+            ref check => panic!("unexpected check {:?}", check),
+        }
+
+        context.add_role("maintainer".to_string());
+        let evaluation = QuorumApprovalWorkflow::evaluate(&context, "merge").unwrap();
+        assert_eq!(
+            evaluation.checks[1],
+            GuardCheck::Quorum {
+                required: 2,
+                approvals: 1,
+                passed: false,
+            }
+        );
+        context.add_approval(Approval::new(Author {
+            username: "bob".to_string(),
+            ..Author::default()
+        }));
+        assert!(
+            QuorumApprovalWorkflow::evaluate(&context, "merge")
+                .unwrap()
+                .allowed
+        );
+        // Nothing was executed.
+        assert_eq!(context.current_state, "Review");
+        assert_eq!(context.approvals.len(), 2);
+
+        assert!(matches!(
+            QuorumApprovalWorkflow::evaluate(&context, "approve"),
+            Err(WorkflowError::UnknownTrigger { .. })
+        ));
+    }
+
     #[test]
     fn test_two_stage_workflow() {
         let mut context = WorkflowContext::new(
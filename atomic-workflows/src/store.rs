@@ -0,0 +1,217 @@
+//! Persistence of workflow states
+//!
+//! [`simple_workflow!`](crate::simple_workflow) only tracks the state of a
+//! change in its [`WorkflowContext`], which is lost when the process
+//! exits. A [`WorkflowStore`] keeps the current state of each workflow
+//! for each change (or tag), so that contexts can be reloaded later.
+//!
+//! [`MemoryStore`] keeps states in memory, e.g. for tests. With the
+//! `pristine` feature, the transactions of the pristine database are
+//! stores too (see [`crate::pristine`]), so that workflow states are
+//! updated in the same transaction as the changes they apply to.
+
+use crate::simple::{Approval, WorkflowContext};
+use atomic_config::events::NodeKind;
+use atomic_config::Author;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The persisted state of a workflow on a change or tag
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WorkflowState {
+    /// Name of the workflow
+    pub workflow: String,
+    /// Whether the workflow runs on a change or on a tag
+    pub kind: NodeKind,
+    /// Hash of the change, or state of the tag
+    pub change_id: String,
+    /// Current state of the workflow
+    pub state: String,
+    /// Approvals in the current state, see [`WorkflowContext::approvals`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub approvals: Vec<Approval>,
+    /// Reviewers of the change or tag, see [`WorkflowContext::reviewers`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reviewers: Vec<String>,
+}
+
+impl WorkflowState {
+    /// The state of `workflow` in `context`
+    pub fn from_context(workflow: &str, context: &WorkflowContext) -> Self {
+        WorkflowState {
+            workflow: workflow.to_string(),
+            kind: context.kind,
+            change_id: context.change_id.clone(),
+            state: context.current_state.clone(),
+            approvals: context.approvals.clone(),
+            reviewers: context.reviewers.clone(),
+        }
+    }
+
+    /// A context for `author` in this state, without any role
+    pub fn to_context(&self, author: Author) -> WorkflowContext {
+        WorkflowContext {
+            kind: self.kind,
+            approvals: self.approvals.clone(),
+            reviewers: self.reviewers.clone(),
+            ..WorkflowContext::new(self.change_id.clone(), author, self.state.clone())
+        }
+    }
+}
+
+/// Read access to persisted workflow states
+pub trait WorkflowStore {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// State of `workflow` on `change_id`, if any
+    fn get_workflow_state(
+        &self,
+        workflow: &str,
+        change_id: &str,
+    ) -> Result<Option<WorkflowState>, Self::Error>;
+
+    /// States of `workflow` on all changes and tags, ordered by `change_id`
+    fn iter_workflow_states(&self, workflow: &str) -> Result<Vec<WorkflowState>, Self::Error>;
+
+    /// Load the context of `workflow` on `change_id` for `author`,
+    /// starting from `initial_state` if the workflow has no state yet.
+    fn load_context(
+        &self,
+        workflow: &str,
+        change_id: &str,
+        author: Author,
+        initial_state: &str,
+    ) -> Result<WorkflowContext, Self::Error> {
+        Ok(match self.get_workflow_state(workflow, change_id)? {
+            Some(state) => state.to_context(author),
+            None => WorkflowContext::new(change_id.to_string(), author, initial_state.to_string()),
+        })
+    }
+}
+
+/// Write access to persisted workflow states
+pub trait WorkflowMutStore: WorkflowStore {
+    /// Set the state of `state.workflow` on `state.change_id`
+    fn put_workflow_state(&mut self, state: &WorkflowState) -> Result<(), Self::Error>;
+
+    /// Forget the state of `workflow` on `change_id`. Returns whether
+    /// there was a state.
+    fn del_workflow_state(&mut self, workflow: &str, change_id: &str) -> Result<bool, Self::Error>;
+
+    /// Persist the current state of `workflow` in `context`, e.g. after
+    /// a transition.
+    fn save_context(
+        &mut self,
+        workflow: &str,
+        context: &WorkflowContext,
+    ) -> Result<(), Self::Error> {
+        self.put_workflow_state(&WorkflowState::from_context(workflow, context))
+    }
+}
+
+/// A store keeping workflow states in memory
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStore {
+    states: BTreeMap<(String, String), WorkflowState>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl WorkflowStore for MemoryStore {
+    type Error = std::convert::Infallible;
+
+    fn get_workflow_state(
+        &self,
+        workflow: &str,
+        change_id: &str,
+    ) -> Result<Option<WorkflowState>, Self::Error> {
+        Ok(self
+            .states
+            .get(&(workflow.to_string(), change_id.to_string()))
+            .cloned())
+    }
+
+    fn iter_workflow_states(&self, workflow: &str) -> Result<Vec<WorkflowState>, Self::Error> {
+        Ok(self
+            .states
+            .range((workflow.to_string(), String::new())..)
+            .take_while(|((w, _), _)| w == workflow)
+            .map(|(_, s)| s.clone())
+            .collect())
+    }
+}
+
+impl WorkflowMutStore for MemoryStore {
+    fn put_workflow_state(&mut self, state: &WorkflowState) -> Result<(), Self::Error> {
+        self.states.insert(
+            (state.workflow.clone(), state.change_id.clone()),
+            state.clone(),
+        );
+        Ok(())
+    }
+
+    fn del_workflow_state(&mut self, workflow: &str, change_id: &str) -> Result<bool, Self::Error> {
+        Ok(self
+            .states
+            .remove(&(workflow.to_string(), change_id.to_string()))
+            .is_some())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simple::{SimpleApprovalState, SimpleApprovalWorkflow};
+
+    #[test]
+    fn test_memory_store_round_trip() {
+        let mut store = MemoryStore::new();
+        let name = SimpleApprovalWorkflow::NAME;
+        let mut context = store
+            .load_context(name, "change-1", Author::default(), "Recorded")
+            .unwrap();
+        assert_eq!(context.current_state, "Recorded");
+
+        context.add_role("developer".to_string());
+        SimpleApprovalWorkflow::execute_transition(
+            SimpleApprovalState::Recorded,
+            SimpleApprovalState::Review,
+            &mut context,
+        )
+        .unwrap();
+        context.add_approval(Approval::new(Author::default()));
+        store.save_context(name, &context).unwrap();
+        store
+            .save_context(
+                "Other",
+                &WorkflowContext::for_tag(
+                    "tag-1".to_string(),
+                    Author::default(),
+                    "Draft".to_string(),
+                ),
+            )
+            .unwrap();
+
+        // A new context starts from the saved state, without the roles.
+        let context = store
+            .load_context(name, "change-1", Author::default(), "Recorded")
+            .unwrap();
+        assert_eq!(context.current_state, "Review");
+        assert_eq!(context.approvals.len(), 1);
+        assert!(!context.user_has_role("developer"));
+        let other = store.get_workflow_state("Other", "tag-1").unwrap().unwrap();
+        assert_eq!(other.kind, NodeKind::Tag);
+
+        let states = store.iter_workflow_states(name).unwrap();
+        assert_eq!(states.len(), 1);
+        assert_eq!(states[0].change_id, "change-1");
+
+        assert!(store.del_workflow_state(name, "change-1").unwrap());
+        assert!(!store.del_workflow_state(name, "change-1").unwrap());
+        assert!(store.iter_workflow_states(name).unwrap().is_empty());
+    }
+}
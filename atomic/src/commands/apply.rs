@@ -6,11 +6,14 @@ use libatomic::attribution::{
     ApplyAttributionContext, ApplyIntegrationConfig, AuthorId, AuthorInfo,
 };
 use libatomic::changestore::ChangeStore;
+use libatomic::pristine::NodeType;
 use libatomic::{Base32, DepsTxnT, GraphTxnT, MutTxnTExt, TxnT};
 use libatomic::{HashMap, HashSet};
 use log::*;
 
 use atomic_interaction::{Spinner, OUTPUT_MESSAGE};
+use atomic_repository::hooks::{Event, HookNode};
+use atomic_repository::lock::LockOptions;
 use atomic_repository::Repository;
 
 #[derive(Parser, Debug)]
@@ -32,11 +35,15 @@ pub struct Apply {
     /// Show attribution information during apply
     #[clap(long = "show-attribution")]
     show_attribution: bool,
+    /// Retry the changes and tags quarantined by failed pulls into the channel
+    #[clap(long = "quarantined", conflicts_with_all = ["change", "deps_only"])]
+    quarantined: bool,
 }
 
 impl Apply {
     pub fn run(self) -> Result<(), anyhow::Error> {
-        let repo = Repository::find_root(self.repo_path)?;
+        let (repo, _lock) =
+            Repository::find_root_locked(self.repo_path, "apply", &LockOptions::from_env())?;
 
         // Initialize attribution context if requested
         let mut attribution_context = if self.with_attribution || self.show_attribution {
@@ -80,7 +87,7 @@ impl Apply {
         };
 
         let mut hashes = Vec::new();
-        if self.change.is_empty() {
+        if self.change.is_empty() && !self.quarantined {
             let mut change = std::io::BufReader::new(std::io::stdin());
             let mut change = libatomic::change::Change::read(&mut change, &mut HashMap::default())?;
             hashes.push(
@@ -125,7 +132,29 @@ impl Apply {
                 }
             })
         }
-        if self.deps_only {
+        // `pre-apply` hooks may refuse the changes. The quarantined nodes
+        // retried are only known once applied.
+        if !self.quarantined {
+            let nodes = hashes
+                .iter()
+                .map(|h| HookNode::new(h, None, NodeType::Change))
+                .collect();
+            let payload = repo.hook_payload(Event::PreApply, None, channel_name, nodes);
+            repo.hooks().run(Event::PreApply, &payload)?;
+        }
+        let mut report = None;
+        if self.quarantined {
+            let r = atomic_remote::quarantine::retry(
+                &repo.quarantine(),
+                &repo.changes,
+                &mut *txn.write(),
+                &mut *channel.write(),
+                channel_name,
+            )?;
+            atomic_remote::quarantine::print_report(std::io::stderr(), &r)?;
+            hashes.extend(r.applied.iter().filter(|n| n.is_change()).map(|n| n.hash));
+            report = Some(r)
+        } else if self.deps_only {
             if hashes.len() > 1 {
                 bail!("--deps-only is only applicable to a single change")
             }
@@ -311,6 +340,19 @@ impl Apply {
         }
 
         txn.commit()?;
+        let nodes = if let Some(ref report) = report {
+            report.applied.iter().map(|n| n.hook_node()).collect()
+        } else {
+            hashes
+                .iter()
+                .map(|h| HookNode::new(h, None, NodeType::Change))
+                .collect()
+        };
+        if let Some(report) = report {
+            report.save(&repo.quarantine(), channel_name)?;
+        }
+        let payload = repo.hook_payload(Event::PostApply, None, channel_name, nodes);
+        repo.hooks().run(Event::PostApply, &payload)?;
         Ok(())
     }
 }
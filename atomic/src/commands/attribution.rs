@@ -32,8 +32,13 @@ use std::io::Write;
 ///
 /// Output as JSON:
 ///   atomic attribution --output-format json
+///
+/// Let agent toolchains register the AI metadata of the next recorded change:
+///   atomic attribution serve
 #[derive(Parser, Debug)]
 pub struct Attribution {
+    #[clap(subcommand)]
+    subcmd: Option<SubCommand>,
     /// Set the repository where this command should run. Defaults to the first ancestor of the current directory that contains a `.atomic` directory.
     #[clap(long = "repository", value_hint = ValueHint::DirPath)]
     repo_path: Option<PathBuf>,
@@ -66,6 +71,21 @@ pub struct Attribution {
     hash: Option<String>,
 }
 
+#[derive(Parser, Debug)]
+enum SubCommand {
+    /// Serve the attribution hooks: tools register the AI metadata of the
+    /// next recorded change on a unix socket, in `.atomic/attribution.sock`
+    /// by default, one JSON request per line, e.g.
+    /// `{"op":"register","provider":"anthropic","model":"claude-sonnet"}`.
+    /// `atomic record` uses it unless it is given `--ai-*` flags.
+    #[clap(name = "serve")]
+    Serve {
+        /// Listen on this socket instead
+        #[clap(long = "socket", value_hint = ValueHint::FilePath)]
+        socket: Option<PathBuf>,
+    },
+}
+
 #[derive(Debug, Clone, clap::ValueEnum)]
 enum OutputFormat {
     Plaintext,
@@ -119,6 +139,14 @@ impl Attribution {
         let repo_path = self.repo_path.clone();
         let repo = Repository::find_root(repo_path)
             .with_context(|| "Failed to find atomic repository root")?;
+        if let Some(SubCommand::Serve { socket }) = self.subcmd {
+            let socket = socket.unwrap_or_else(|| {
+                repo.path
+                    .join(libatomic::DOT_DIR)
+                    .join(atomic_repository::ATTRIBUTION_SOCKET)
+            });
+            return serve_hooks(repo.attribution_hooks(), &socket);
+        }
         let txn = repo
             .pristine
             .txn_begin()
@@ -840,3 +868,93 @@ impl ChangeAttributionBuilder {
         }
     }
 }
+
+/// Answer the requests of tools registering attribution on `socket`, until
+/// the process is killed
+#[cfg(unix)]
+fn serve_hooks(
+    hooks: libatomic::attribution::AttributionHooks,
+    socket: &std::path::Path,
+) -> Result<(), anyhow::Error> {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            bail!(
+                "An attribution daemon is already listening on {}",
+                socket.display()
+            )
+        }
+        // Left by a daemon that was killed.
+        std::fs::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("Failed to listen on {}", socket.display()))?;
+    // Only tools run by this user may attribute changes.
+    std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))?;
+    writeln!(std::io::stderr(), "Listening on {}", socket.display())?;
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("Failed to accept a connection: {}", e);
+                continue;
+            }
+        };
+        let hooks = hooks.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = serve_hook_connection(&hooks, stream) {
+                debug!("attribution hook connection: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn serve_hooks(
+    _hooks: libatomic::attribution::AttributionHooks,
+    _socket: &std::path::Path,
+) -> Result<(), anyhow::Error> {
+    bail!("Attribution hooks are only served on unix")
+}
+
+#[cfg(unix)]
+fn serve_hook_connection(
+    hooks: &libatomic::attribution::AttributionHooks,
+    stream: std::os::unix::net::UnixStream,
+) -> Result<(), anyhow::Error> {
+    use libatomic::attribution::{HookRequest, HookResponse};
+    use std::io::BufRead;
+
+    let mut writer = stream.try_clone()?;
+    for line in std::io::BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<HookRequest>(&line) {
+            Ok(request) => {
+                debug!("attribution hook request {:?}", request);
+                let register = matches!(request, HookRequest::Register(_));
+                let response = hooks.handle(request, chrono::Utc::now());
+                if let (true, HookResponse::Ok { pending: Some(p) }) = (register, &response) {
+                    log::info!(
+                        "Registered {}/{} for the next change, until {}",
+                        p.provider,
+                        p.model,
+                        p.expires
+                    );
+                }
+                response
+            }
+            Err(e) => HookResponse::Error {
+                message: format!("Invalid request: {}", e),
+            },
+        };
+        serde_json::to_writer(&mut writer, &response)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
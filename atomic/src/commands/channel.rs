@@ -3,6 +3,7 @@ use std::path::PathBuf;
 
 use anyhow::anyhow;
 use anyhow::bail;
+use atomic_repository::lock::LockOptions;
 use atomic_repository::Repository;
 use clap::{Parser, ValueHint};
 use libatomic::{ChannelTxnT, MutTxnT, TxnT};
@@ -64,7 +65,11 @@ impl Channel {
                 }
             }
             Some(SubCommand::Delete { ref delete }) => {
-                let repo = Repository::find_root(self.repo_path)?;
+                let (repo, _lock) = Repository::find_root_locked(
+                    self.repo_path,
+                    "channel delete",
+                    &LockOptions::from_env(),
+                )?;
                 let mut txn = repo.pristine.mut_txn_begin()?;
                 let current = txn.current_channel().ok();
                 if Some(delete.as_str()) == current {
@@ -86,7 +91,11 @@ impl Channel {
                 .switch()?;
             }
             Some(SubCommand::Rename { ref from, ref to }) => {
-                let repo = Repository::find_root(self.repo_path)?;
+                let (repo, _lock) = Repository::find_root_locked(
+                    self.repo_path,
+                    "channel rename",
+                    &LockOptions::from_env(),
+                )?;
                 let mut txn = repo.pristine.mut_txn_begin()?;
                 let current = txn.current_channel().ok();
                 let (from, to) = if let Some(to) = to {
@@ -109,7 +118,11 @@ impl Channel {
                 if empty && !force {
                     bail!("If creating an empty channel is really what you want, please use -f.")
                 }
-                let repo = Repository::find_root(self.repo_path)?;
+                let (repo, _lock) = Repository::find_root_locked(
+                    self.repo_path,
+                    "channel new",
+                    &LockOptions::from_env(),
+                )?;
                 let mut txn = repo.pristine.mut_txn_begin()?;
                 if txn.load_channel(&name)?.is_some() {
                     bail!("Channel {} already exists", name)
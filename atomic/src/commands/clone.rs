@@ -17,12 +17,15 @@ pub struct Clone {
     /// Clone this state
     #[clap(long = "state", conflicts_with = "change")]
     state: Option<String>,
-    /// Clone this path only
+    /// Clone this path only, and restrict later pulls to it
     #[clap(long = "path")]
     partial_paths: Vec<String>,
     /// Do not check certificates (HTTPS remotes only, this option might be dangerous)
     #[clap(short = 'k')]
     no_cert_check: bool,
+    /// Resume an interrupted clone into the same path, without downloading the changes it downloaded again
+    #[clap(long = "resume")]
+    resume: bool,
     /// Clone this remote
     remote: String,
     /// Path where to clone the repository.
@@ -62,7 +65,8 @@ impl Clone {
         };
         debug!("path = {:?}", path);
 
-        if std::fs::metadata(&path).is_ok() {
+        let resuming = self.resume && path.join(libatomic::DOT_DIR).exists();
+        if std::fs::metadata(&path).is_ok() && !resuming {
             bail!("Path {:?} already exists", path)
         }
 
@@ -82,10 +86,14 @@ impl Clone {
                 .into(),
             _ => self.remote.as_str().into(),
         };
-        let mut repo = Repository::init(Some(path), None, Some(&remote_normalised))?;
+        let mut repo = if resuming {
+            Repository::find_root(Some(path))?
+        } else {
+            Repository::init(Some(path), None, Some(&remote_normalised))?
+        };
         let txn = repo.pristine.arc_txn_begin()?;
         let mut channel = txn.write().open_or_create_channel(&self.channel)?;
-        if let Some(ref change) = self.change {
+        let applied = if let Some(ref change) = self.change {
             let h = change.parse()?;
             remote
                 .clone_tag(&mut repo, &mut *txn.write(), &mut channel, &[h])
@@ -96,7 +104,7 @@ impl Clone {
                 .clone_state(&mut repo, &mut *txn.write(), &mut channel, h, &[])
                 .await?
         } else {
-            remote
+            let applied = remote
                 .clone_channel(
                     &mut repo,
                     &mut *txn.write(),
@@ -145,9 +153,11 @@ impl Clone {
                     }
                 }
             }
-        }
+            applied
+        };
 
-        if self.partial_paths.is_empty() {
+        let sparse = atomic_remote::sparse::normalize(&self.partial_paths);
+        if sparse.is_empty() {
             libatomic::output::output_repository_no_pending(
                 &repo.working_copy,
                 &repo.changes,
@@ -160,7 +170,7 @@ impl Clone {
                 self.salt.unwrap_or(0),
             )?;
         } else {
-            for p in self.partial_paths.iter() {
+            for p in sparse.iter() {
                 libatomic::output::output_repository_no_pending(
                     &repo.working_copy,
                     &repo.changes,
@@ -185,6 +195,7 @@ impl Clone {
             .touch_channel(&mut *channel.write(), Some(time * 1000 + 1));
 
         txn.commit()?;
+        atomic_remote::publish_applied(&repo, &self.channel, &applied);
         std::mem::forget(repo_path);
         Ok(())
     }
@@ -206,6 +217,15 @@ impl RepoPath {
         }
     }
     fn remove(&self) {
+        let checkpoint =
+            checkpoint::Checkpoint::new(self.path.join(libatomic::DOT_DIR).join(CHECKPOINT_FILE));
+        if (self.remove_dir || self.remove_dot) && checkpoint.exists() {
+            eprintln!(
+                "Clone interrupted, run the same command with --resume to resume it from {:?}",
+                self.path
+            );
+            return;
+        }
         if self.remove_dir {
             std::fs::remove_dir_all(&self.path).unwrap_or(());
         } else if self.remove_dot {
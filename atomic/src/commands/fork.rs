@@ -3,6 +3,7 @@ use libatomic::{Base32, ChannelTxnT, MutTxnT, MutTxnTExt, TxnT, TxnTExt};
 use log::debug;
 use std::path::PathBuf;
 
+use atomic_repository::lock::LockOptions;
 use atomic_repository::Repository;
 
 #[derive(Parser, Debug)]
@@ -25,7 +26,8 @@ pub struct Fork {
 
 impl Fork {
     pub fn run(self) -> Result<(), anyhow::Error> {
-        let repo = Repository::find_root(self.repo_path)?;
+        let (repo, _lock) =
+            Repository::find_root_locked(self.repo_path, "fork", &LockOptions::from_env())?;
         debug!("{:?}", repo.config);
         let mut txn = repo.pristine.mut_txn_begin()?;
         if let Some(ref ch) = self.change {
@@ -0,0 +1,116 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use atomic_repository::links::Relation;
+use atomic_repository::Repository;
+use clap::{Parser, ValueHint};
+use libatomic::Base32;
+
+use super::note::resolve;
+
+#[derive(Parser, Debug)]
+pub struct Link {
+    #[clap(subcommand)]
+    subcmd: SubCommand,
+    /// Set the repository where this command should run. Defaults to the
+    /// first ancestor of the current directory that contains a `.atomic`
+    /// directory.
+    #[clap(long = "repository", value_hint = ValueHint::DirPath)]
+    repo_path: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub enum SubCommand {
+    /// Link a change to an external URL, such as a Jira or GitHub issue.
+    /// Links are not part of the change, and are only synchronised on
+    /// push and pull if the repository configuration sets `sync_links`.
+    #[clap(name = "add")]
+    Add {
+        /// The hash of the change, or an unambiguous prefix thereof
+        #[clap(value_name = "HASH")]
+        hash: String,
+        #[clap(value_name = "URL")]
+        url: String,
+        /// How the change relates to the URL: `fixes` or `relates-to`
+        #[clap(short = 'r', long = "relation", default_value = "relates-to")]
+        relation: Relation,
+        /// Set the author field
+        #[clap(long = "author")]
+        author: Option<String>,
+    },
+    /// Remove the link from a change to a URL
+    #[clap(name = "remove", alias = "rm")]
+    Remove {
+        #[clap(value_name = "HASH")]
+        hash: String,
+        #[clap(value_name = "URL")]
+        url: String,
+    },
+    /// List the links of a change, or all the links
+    #[clap(name = "list", alias = "ls")]
+    List {
+        #[clap(value_name = "HASH")]
+        hash: Option<String>,
+    },
+    /// List the changes linked to an issue key (`PROJ-123`,
+    /// `org/repo#42`) or URL
+    #[clap(name = "find")]
+    Find {
+        #[clap(value_name = "REFERENCE")]
+        reference: String,
+        /// Only list the changes with this relation
+        #[clap(short = 'r', long = "relation")]
+        relation: Option<Relation>,
+    },
+}
+
+impl Link {
+    pub fn run(self) -> Result<(), anyhow::Error> {
+        let repo = Repository::find_root(self.repo_path)?;
+        let links = repo.links();
+        let mut stdout = std::io::stdout();
+        match self.subcmd {
+            SubCommand::Add {
+                hash,
+                url,
+                relation,
+                author,
+            } => {
+                let hash = resolve(&repo, &hash)?;
+                links.add(&hash, &url, relation, author.as_deref())?;
+            }
+            SubCommand::Remove { hash, url } => {
+                let hash = resolve(&repo, &hash)?;
+                if !links.remove(&hash, &url, None)? {
+                    bail!("Change {} is not linked to {}", hash.to_base32(), url)
+                }
+            }
+            SubCommand::List { hash } => {
+                let list = if let Some(hash) = hash {
+                    links.get(&resolve(&repo, &hash)?)?
+                } else {
+                    links.list()?
+                };
+                for link in list {
+                    write!(stdout, "{} {} {}", link.change, link.relation, link.url)?;
+                    if let Some(key) = link.key {
+                        write!(stdout, " ({})", key)?;
+                    }
+                    writeln!(stdout)?;
+                }
+            }
+            SubCommand::Find {
+                reference,
+                relation,
+            } => {
+                for link in links.find(&reference)? {
+                    if relation.map(|r| r == link.relation).unwrap_or(true) {
+                        writeln!(stdout, "{} {}", link.change, link.relation)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
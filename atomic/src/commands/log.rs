@@ -5,6 +5,7 @@ use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use anyhow::bail;
+use atomic_remote::combined::{self, Presence};
 use atomic_repository::Repository;
 use clap::{Parser, ValueHint};
 use libatomic::attribution::SerializedAttribution;
@@ -63,6 +64,11 @@ pub struct Log {
     /// Show only human-authored changes
     #[clap(long = "human-only")]
     human_only: bool,
+    /// Also show the changes of this remote that weren't pulled, from its
+    /// changelist as of the last push or pull, and mark each change as
+    /// local, remote or both
+    #[clap(long = "remote", conflicts_with = "filters")]
+    remote: Option<String>,
 }
 
 impl TryFrom<Log> for LogIterator {
@@ -850,6 +856,9 @@ impl Log {
     // serialization to a serde target format, this now delegates
     // mostly to [`LogIterator`].
     pub fn run(self) -> Result<(), anyhow::Error> {
+        if self.remote.is_some() {
+            return self.run_combined();
+        }
         let log_iter = LogIterator::try_from(self)?;
         let mut stdout = std::io::stdout();
 
@@ -868,3 +877,104 @@ impl Log {
         Ok(())
     }
 }
+
+/// An entry of `atomic log --remote`
+#[derive(Serialize)]
+struct CombinedLogEntry {
+    hash: String,
+    presence: Presence,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+}
+
+impl std::fmt::Display for CombinedLogEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Change {} ({})", self.hash, self.presence)?;
+        if let Some(ref state) = self.state {
+            writeln!(f, "State: {}", state)?;
+        }
+        if let Some(ref message) = self.message {
+            writeln!(f, "\n    {}\n", message)?;
+        } else {
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+impl Log {
+    /// `atomic log --remote`: the changes of the channel and of the cached
+    /// changelist of the remote, newest first
+    fn run_combined(self) -> Result<(), anyhow::Error> {
+        let repo = Repository::find_root(self.repo_path.clone())?;
+        let txn = repo.pristine.txn_begin()?;
+        let channel_name = if let Some(ref c) = self.channel {
+            c
+        } else {
+            txn.current_channel().unwrap_or(libatomic::DEFAULT_CHANNEL)
+        };
+        let channel = if let Some(channel) = txn.load_channel(channel_name)? {
+            channel
+        } else {
+            bail!("No such channel: {:?}", channel_name)
+        };
+        let name = combined::cache_name(&repo, self.remote.as_deref().unwrap());
+        let mut remotes = combined::cached_remotes(&txn, name)?;
+        let remote = match remotes.len() {
+            0 => bail!("No changelist of {} is cached, push or pull first", name),
+            1 => remotes.pop().unwrap(),
+            _ => bail!(
+                "Several channels of {} are cached, pick one by identifier: {}",
+                name,
+                remotes
+                    .iter()
+                    .map(|r| r.id().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        let entries = combined::combined_log(&txn, &*channel.read(), &remote)?;
+
+        let entries = entries
+            .into_iter()
+            .rev()
+            .skip(self.offset.unwrap_or(0))
+            .take(self.limit.unwrap_or(usize::MAX))
+            .map(|entry| {
+                let message = get_header_by_hash(&txn, &repo.changes, &entry.hash)
+                    .ok()
+                    .map(|header| header.message);
+                let state = entry.local.or(entry.remote).map(|(_, m)| m.to_base32());
+                CombinedLogEntry {
+                    hash: entry.hash.to_base32(),
+                    presence: entry.presence(),
+                    state: state.filter(|_| self.states),
+                    message: message.filter(|_| !self.hash_only),
+                }
+            });
+
+        let mut stdout = std::io::stdout();
+        match self.output_format.unwrap_or_default() {
+            OutputFormat::Json => {
+                serde_json::to_writer_pretty(&mut stdout, &entries.collect::<Vec<_>>())?
+            }
+            OutputFormat::Plaintext => {
+                super::pager(repo.config.pager.as_ref());
+                for entry in entries {
+                    let result = if self.hash_only {
+                        writeln!(stdout, "{} {}", entry.hash, entry.presence)
+                    } else {
+                        write!(stdout, "{}", entry)
+                    };
+                    match result {
+                        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => break,
+                        r => r?,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
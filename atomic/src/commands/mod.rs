@@ -59,6 +59,15 @@ pub use credit::*;
 mod tag;
 pub use tag::*;
 
+mod note;
+pub use note::Note;
+
+mod link;
+pub use link::Link;
+
+mod workflow;
+pub use workflow::Workflow;
+
 mod identity;
 pub use identity::*;
 
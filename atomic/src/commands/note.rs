@@ -0,0 +1,104 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::bail;
+use atomic_repository::Repository;
+use clap::{Parser, ValueHint};
+use libatomic::{Base32, Hash, TxnT};
+
+#[derive(Parser, Debug)]
+pub struct Note {
+    #[clap(subcommand)]
+    subcmd: SubCommand,
+    /// Set the repository where this command should run. Defaults to the
+    /// first ancestor of the current directory that contains a `.atomic`
+    /// directory.
+    #[clap(long = "repository", value_hint = ValueHint::DirPath)]
+    repo_path: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub enum SubCommand {
+    /// Attach a note to a change, replacing its previous note. Notes are
+    /// not part of the change, so they can be edited without changing its
+    /// hash, and are synchronised on push and pull.
+    #[clap(name = "add")]
+    Add {
+        /// The hash of the change, or an unambiguous prefix thereof
+        #[clap(value_name = "HASH")]
+        hash: String,
+        #[clap(short = 'm', long = "message")]
+        message: String,
+        /// Set the author field
+        #[clap(long = "author")]
+        author: Option<String>,
+    },
+    /// Show the note attached to a change
+    #[clap(name = "show")]
+    Show {
+        #[clap(value_name = "HASH")]
+        hash: String,
+    },
+    /// Remove the note attached to a change
+    #[clap(name = "remove", alias = "rm")]
+    Remove {
+        #[clap(value_name = "HASH")]
+        hash: String,
+    },
+    /// List all the notes
+    #[clap(name = "list", alias = "ls")]
+    List,
+}
+
+impl Note {
+    pub fn run(self) -> Result<(), anyhow::Error> {
+        let repo = Repository::find_root(self.repo_path)?;
+        let notes = repo.notes();
+        let mut stdout = std::io::stdout();
+        match self.subcmd {
+            SubCommand::Add {
+                hash,
+                message,
+                author,
+            } => {
+                let hash = resolve(&repo, &hash)?;
+                notes.set(&hash, &message, author.as_deref())?;
+            }
+            SubCommand::Show { hash } => {
+                let hash = resolve(&repo, &hash)?;
+                if let Some(note) = notes.get(&hash)? {
+                    writeln!(stdout, "{}", note.text)?;
+                } else {
+                    bail!("No note attached to change {}", hash.to_base32())
+                }
+            }
+            SubCommand::Remove { hash } => {
+                let hash = resolve(&repo, &hash)?;
+                if !notes.remove(&hash, None)? {
+                    bail!("No note attached to change {}", hash.to_base32())
+                }
+            }
+            SubCommand::List => {
+                for note in notes.list()? {
+                    write!(stdout, "{} {}", note.change, note.updated.to_rfc3339())?;
+                    if let Some(author) = note.author {
+                        write!(stdout, " {}", author)?;
+                    }
+                    writeln!(stdout)?;
+                    for line in note.text.lines() {
+                        writeln!(stdout, "    {}", line)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(super) fn resolve(repo: &Repository, hash: &str) -> Result<Hash, anyhow::Error> {
+    if let Some(h) = Hash::from_base32(hash.as_bytes()) {
+        return Ok(h);
+    }
+    let txn = repo.pristine.txn_begin()?;
+    Ok(txn.hash_from_prefix(hash)?.0)
+}
@@ -5,13 +5,15 @@ use std::path::PathBuf;
 use std::sync::Arc;
 
 use anyhow::bail;
+use atomic_remote::attribution::{self, ChangeAttribution, RemoteAttributionConfig};
+use atomic_remote::bundle;
+use atomic_remote::protocol::{Command, ListLine, StateLine};
+use atomic_repository::lock::LockOptions;
 use atomic_repository::Repository;
 use byteorder::{BigEndian, WriteBytesExt};
 use clap::Parser;
-use lazy_static::lazy_static;
 use libatomic::*;
 use log::{debug, error, warn};
-use regex::Regex;
 
 /// This command is not meant to be run by the user,
 /// instead it is called over SSH
@@ -25,21 +27,6 @@ pub struct Protocol {
     version: usize,
 }
 
-lazy_static! {
-    static ref STATE: Regex = Regex::new(r#"state\s+(\S+)(\s+([0-9]+)?)\s+"#).unwrap();
-    static ref ID: Regex = Regex::new(r#"id\s+(\S+)\s+"#).unwrap();
-    static ref IDENTITIES: Regex = Regex::new(r#"identities(\s+([0-9]+))?\s+"#).unwrap();
-    static ref CHANGELIST: Regex = Regex::new(r#"changelist\s+(\S+)\s+([0-9]+)(.*)\s+"#).unwrap();
-    static ref CHANGELIST_PATHS: Regex = Regex::new(r#""(((\\")|[^"])+)""#).unwrap();
-    static ref CHANGE: Regex = Regex::new(r#"((change)|(partial))\s+([^ ]*)\s+"#).unwrap();
-    static ref TAG: Regex = Regex::new(r#"^tag\s+(\S+)\s+"#).unwrap();
-    static ref TAGUP: Regex = Regex::new(r#"^tagup\s+(\S+)\s+(\S+)\s+([0-9]+)\s+"#).unwrap();
-    static ref APPLY: Regex = Regex::new(r#"apply\s+(\S+)\s+([^ ]*) ([0-9]+)\s+"#).unwrap();
-    static ref CHANNEL: Regex = Regex::new(r#"channel\s+(\S+)\s+"#).unwrap();
-    static ref ARCHIVE: Regex =
-        Regex::new(r#"archive\s+(\S+)\s*(( ([^:]+))*)( :(.*))?\n"#).unwrap();
-}
-
 fn load_channel<T: MutTxnTExt>(txn: &T, name: &str) -> Result<ChannelRef<T>, anyhow::Error> {
     if let Some(c) = txn.load_channel(name)? {
         Ok(c)
@@ -48,11 +35,40 @@ fn load_channel<T: MutTxnTExt>(txn: &T, name: &str) -> Result<ChannelRef<T>, any
     }
 }
 
+/// Refuse changes sent in clear to a `[[confidential]]` channel, which
+/// only takes the envelopes of its changes, as over HTTP.
+fn check_clear(config: &atomic_config::Config, channel: &str) -> Result<(), anyhow::Error> {
+    if config.confidential.iter().any(|c| c.channel == channel) {
+        bail!(
+            "Channel {:?} only takes changes encrypted for its recipients",
+            channel
+        )
+    }
+    Ok(())
+}
+
 const PARTIAL_CHANGE_SIZE: u64 = 1 << 20;
 
 impl Protocol {
     pub fn run(self) -> Result<(), anyhow::Error> {
-        let mut repo = Repository::find_root(self.repo_path)?;
+        // Clients from protocol version 5 on read the capabilities that
+        // follow the answers to `state`.
+        let attribution_config = RemoteAttributionConfig::from_environment();
+        let attribution_capability = attribution::capability();
+        let mut capabilities = Vec::new();
+        if self.version >= 5 {
+            capabilities.push(bundle::CAPABILITY);
+            capabilities.push(atomic_remote::NOTES_CAPABILITY);
+            if attribution_config.enabled {
+                capabilities.push(attribution_capability.as_str());
+            }
+        }
+        // Sessions apply changes and tags, and hold a write transaction
+        // throughout, so they hold the lock of the repository too.
+        let (mut repo, _lock) =
+            Repository::find_root_locked(self.repo_path, "protocol", &LockOptions::from_env())?;
+        let notes = repo.notes();
+        let links = repo.links();
         let pristine = Arc::new(repo.pristine);
         let txn = pristine.arc_txn_begin()?;
         let mut ws = libatomic::ApplyWorkspace::new();
@@ -63,133 +79,155 @@ impl Protocol {
         let o = std::io::stdout();
         let mut o = BufWriter::new(o.lock());
         let mut applied = HashMap::new();
+        let mut received_attribution: Vec<ChangeAttribution> = Vec::new();
 
         debug!("reading");
         while s.read_line(&mut buf)? > 0 {
             debug!("{:?}", buf);
-            if let Some(cap) = ID.captures(&buf) {
-                let channel = load_channel(&*txn.read(), &cap[1])?;
-                let c = channel.read();
-                writeln!(o, "{}", c.id)?;
-                o.flush()?;
-            } else if let Some(cap) = STATE.captures(&buf) {
-                let channel = load_channel(&*txn.read(), &cap[1])?;
-                let init = if let Some(u) = cap.get(3) {
-                    u.as_str().parse().ok()
-                } else {
-                    None
-                };
-                if let Some(pos) = init {
-                    let txn = txn.read();
-                    for x in txn.log(&*channel.read(), pos)? {
-                        let (n, (_, m)) = x?;
-                        match n.cmp(&pos) {
-                            std::cmp::Ordering::Less => continue,
-                            std::cmp::Ordering::Greater => {
-                                writeln!(o, "-")?;
-                                break;
-                            }
-                            std::cmp::Ordering::Equal => {
-                                let m: libatomic::Merkle = m.into();
-                                let m2 = if let Some(x) = txn
-                                    .rev_iter_tags(txn.tags(&*channel.read()), Some(n))?
-                                    .next()
-                                {
-                                    let tag_bytes = x?.1;
-                                    let serialized =
-                                        libatomic::pristine::SerializedTag::from_bytes_wrapper(
-                                            tag_bytes,
-                                        );
-                                    if let Ok(tag) = serialized.to_tag() {
-                                        tag.state
+            let command = Command::decode(&buf);
+            let full = matches!(command, Ok(Command::Change { .. }));
+            let partial = matches!(command, Ok(Command::PartialBundle { .. }));
+            match command {
+                Ok(Command::Id { channel: name }) => {
+                    let channel = load_channel(&*txn.read(), &name)?;
+                    let c = channel.read();
+                    writeln!(o, "{}", c.id)?;
+                    o.flush()?;
+                }
+                Ok(Command::State {
+                    channel: name,
+                    position,
+                }) => {
+                    let channel = load_channel(&*txn.read(), &name)?;
+                    if let Some(pos) = position {
+                        let txn = txn.read();
+                        for x in txn.log(&*channel.read(), pos)? {
+                            let (n, (_, m)) = x?;
+                            match n.cmp(&pos) {
+                                std::cmp::Ordering::Less => continue,
+                                std::cmp::Ordering::Greater => {
+                                    o.write_all(
+                                        StateLine(None).encode_with(&capabilities).as_bytes(),
+                                    )?;
+                                    break;
+                                }
+                                std::cmp::Ordering::Equal => {
+                                    let m: libatomic::Merkle = m.into();
+                                    let m2 = if let Some(x) = txn
+                                        .rev_iter_tags(txn.tags(&*channel.read()), Some(n))?
+                                        .next()
+                                    {
+                                        let tag_bytes = x?.1;
+                                        let serialized =
+                                            libatomic::pristine::SerializedTag::from_bytes_wrapper(
+                                                tag_bytes,
+                                            );
+                                        if let Ok(tag) = serialized.to_tag() {
+                                            tag.state
+                                        } else {
+                                            Merkle::zero()
+                                        }
                                     } else {
                                         Merkle::zero()
-                                    }
-                                } else {
-                                    Merkle::zero()
-                                };
-                                writeln!(o, "{} {} {}", n, m.to_base32(), m2.to_base32())?;
-                                break;
+                                    };
+                                    o.write_all(
+                                        StateLine(Some((n, m, m2)))
+                                            .encode_with(&capabilities)
+                                            .as_bytes(),
+                                    )?;
+                                    break;
+                                }
                             }
                         }
-                    }
-                } else {
-                    let txn = txn.read();
-                    if let Some(x) = txn.reverse_log(&*channel.read(), None)?.next() {
-                        let (n, (_, m)) = x?;
-                        let m: Merkle = m.into();
-                        let m2 = if let Some(x) = txn
-                            .rev_iter_tags(txn.tags(&*channel.read()), Some(n))?
-                            .next()
-                        {
-                            let tag_bytes = x?.1;
-                            let serialized =
-                                libatomic::pristine::SerializedTag::from_bytes_wrapper(tag_bytes);
-                            if let Ok(tag) = serialized.to_tag() {
-                                tag.state
+                    } else {
+                        let txn = txn.read();
+                        if let Some(x) = txn.reverse_log(&*channel.read(), None)?.next() {
+                            let (n, (_, m)) = x?;
+                            let m: Merkle = m.into();
+                            let m2 = if let Some(x) = txn
+                                .rev_iter_tags(txn.tags(&*channel.read()), Some(n))?
+                                .next()
+                            {
+                                let tag_bytes = x?.1;
+                                let serialized =
+                                    libatomic::pristine::SerializedTag::from_bytes_wrapper(
+                                        tag_bytes,
+                                    );
+                                if let Ok(tag) = serialized.to_tag() {
+                                    tag.state
+                                } else {
+                                    Merkle::zero()
+                                }
                             } else {
                                 Merkle::zero()
-                            }
+                            };
+                            o.write_all(
+                                StateLine(Some((n, m, m2)))
+                                    .encode_with(&capabilities)
+                                    .as_bytes(),
+                            )?
                         } else {
-                            Merkle::zero()
-                        };
-                        writeln!(o, "{} {} {}", n, m.to_base32(), m2.to_base32())?
-                    } else {
-                        writeln!(o, "-")?;
+                            o.write_all(StateLine(None).encode_with(&capabilities).as_bytes())?
+                        }
                     }
+                    o.flush()?;
                 }
-                o.flush()?;
-            } else if let Some(cap) = CHANGELIST.captures(&buf) {
-                let channel = load_channel(&*txn.read(), &cap[1])?;
-                let from: u64 = cap[2].parse().unwrap();
-                let mut paths = Vec::new();
-                let txn = txn.read();
-                {
-                    for r in CHANGELIST_PATHS.captures_iter(&cap[3]) {
-                        let s: String = r[1].replace("\\\"", "\"");
-                        if let Ok((p, ambiguous)) =
-                            txn.follow_oldest_path(&repo.changes, &channel, &s)
-                        {
-                            if ambiguous {
-                                bail!("Ambiguous path")
+                Ok(Command::Changelist {
+                    channel: name,
+                    from,
+                    paths: requested,
+                }) => {
+                    let channel = load_channel(&*txn.read(), &name)?;
+                    let mut paths = Vec::new();
+                    let txn = txn.read();
+                    {
+                        for s in requested {
+                            if let Ok((p, ambiguous)) =
+                                txn.follow_oldest_path(&repo.changes, &channel, &s)
+                            {
+                                if ambiguous {
+                                    bail!("Ambiguous path")
+                                }
+                                let h: libatomic::Hash =
+                                    txn.get_external(&p.change)?.unwrap().into();
+                                let line = ListLine::Position(libatomic::pristine::Position {
+                                    change: h,
+                                    pos: p.pos,
+                                });
+                                writeln!(o, "{}", line.encode())?;
+                                paths.push(s);
+                            } else {
+                                debug!("protocol line: {:?}", buf);
+                                bail!("Protocol error")
                             }
-                            let h: libatomic::Hash = txn.get_external(&p.change)?.unwrap().into();
-                            writeln!(o, "{}.{}", h.to_base32(), p.pos.0)?;
-                            paths.push(s);
-                        } else {
-                            debug!("protocol line: {:?}", buf);
-                            bail!("Protocol error")
                         }
                     }
+                    let mut tagsi = 0;
+                    (atomic_remote::local::Local {
+                        channel: name.clone(),
+                        root: PathBuf::new(),
+                        changes_dir: PathBuf::new(),
+                        pristine: pristine.clone(),
+                        name: String::new(),
+                    })
+                    .download_changelist_(
+                        |_, n, h, m, tag| {
+                            writeln!(o, "{}", ListLine::Change { n, h, m, tag }.encode())?;
+                            if tag {
+                                tagsi += 1;
+                            }
+                            Ok(())
+                        },
+                        &mut (),
+                        from,
+                        &paths,
+                        &*txn,
+                        &channel,
+                    )?;
+                    writeln!(o)?;
+                    o.flush()?;
                 }
-                let mut tagsi = 0;
-                (atomic_remote::local::Local {
-                    channel: (&cap[1]).to_string(),
-                    root: PathBuf::new(),
-                    changes_dir: PathBuf::new(),
-                    pristine: pristine.clone(),
-                    name: String::new(),
-                })
-                .download_changelist_(
-                    |_, n, h, m, is_tag| {
-                        if is_tag {
-                            writeln!(o, "{}.{}.{}.", n, h.to_base32(), m.to_base32())?;
-                            tagsi += 1;
-                        } else {
-                            writeln!(o, "{}.{}.{}", n, h.to_base32(), m.to_base32())?;
-                        }
-                        Ok(())
-                    },
-                    &mut (),
-                    from,
-                    &paths,
-                    &*txn,
-                    &channel,
-                )?;
-                writeln!(o)?;
-                o.flush()?;
-            } else if let Some(cap) = TAG.captures(&buf) {
-                if let Some(state) = Merkle::from_base32(cap[1].as_bytes()) {
+                Ok(Command::Tag { state }) => {
                     let mut tag_path = repo.changes_dir.clone();
                     libatomic::changestore::filesystem::push_tag_filename(&mut tag_path, &state);
                     let mut tag = libatomic::tag::OpenTagFile::open(&tag_path, &state)?;
@@ -199,9 +237,12 @@ impl Protocol {
                     o.write_all(&buf)?;
                     o.flush()?;
                 }
-            } else if let Some(cap) = TAGUP.captures(&buf) {
-                if let Some(state) = Merkle::from_base32(cap[1].as_bytes()) {
-                    let channel = load_channel(&*txn.read(), &cap[2])?;
+                Ok(Command::Tagup {
+                    state,
+                    channel: name,
+                    size,
+                }) => {
+                    let channel = load_channel(&*txn.read(), &name)?;
                     let m = libatomic::pristine::current_state(&*txn.read(), &*channel.read())?;
                     if m == state {
                         let mut tag_path = repo.changes_dir.clone();
@@ -215,24 +256,33 @@ impl Protocol {
                         {
                             n?.0.into()
                         } else {
-                            bail!("Channel {} is empty", &cap[2]);
+                            bail!("Channel {} is empty", name);
                         };
                         if txn.read().is_tagged(&channel.read().tags, last_t)? {
                             bail!("Current state is already tagged")
                         }
 
-                        let size: usize = cap[3].parse().unwrap();
                         let mut buf = vec![0; size];
                         s.read_exact(&mut buf)?;
 
-                        let header =
-                            libatomic::tag::read_short(std::io::Cursor::new(&buf[..]), &m)?;
+                        let (header, unhashed) =
+                            libatomic::tag::read_short_signed(std::io::Cursor::new(&buf[..]), &m)?;
+                        let dot_dir = repo.path.join(DOT_DIR);
+                        libatomic::tag::check_received(&m, &header, &unhashed, |key| {
+                            atomic_identity::is_known_key(&dot_dir, key)
+                        })?;
 
                         let temp_path = tag_path.with_extension("tmp");
 
                         std::fs::create_dir_all(temp_path.parent().unwrap())?;
                         let mut w = std::fs::File::create(&temp_path)?;
-                        libatomic::tag::from_channel(&*txn.read(), &cap[2], &header, &mut w)?;
+                        libatomic::tag::from_channel_with_unhashed(
+                            &*txn.read(),
+                            &name,
+                            &header,
+                            &unhashed,
+                            &mut w,
+                        )?;
 
                         std::fs::rename(&temp_path, &tag_path)?;
 
@@ -240,7 +290,7 @@ impl Protocol {
                         {
                             use libatomic::pristine::{SerializedTag, Tag, TagMetadataMutTxnT};
 
-                            let channel_name = &cap[2];
+                            let channel_name = &name;
 
                             // Calculate consolidating tag metadata
                             let (_start_position, consolidated_changes, change_count) = {
@@ -318,140 +368,213 @@ impl Protocol {
                         bail!("Wrong state, cannot tag")
                     }
                 }
-            } else if let Some(cap) = CHANGE.captures(&buf) {
-                let h_ = &cap[4];
-                let h = if let Some(h) = Hash::from_base32(h_.as_bytes()) {
-                    h
-                } else {
-                    debug!("protocol error: {:?}", buf);
-                    bail!("Protocol error")
-                };
-                libatomic::changestore::filesystem::push_filename(&mut repo.changes_dir, &h);
-                debug!("repo = {:?}", repo.changes_dir);
-                let mut f = std::fs::File::open(&repo.changes_dir)?;
-                let size = std::fs::metadata(&repo.changes_dir)?.len();
-                let size = if &cap[1] == "change" || size <= PARTIAL_CHANGE_SIZE {
-                    size
-                } else {
-                    libatomic::change::Change::size_no_contents(&mut f)?
-                };
-                o.write_u64::<BigEndian>(size)?;
-                let mut size = size as usize;
-                while size > 0 {
-                    if size < buf2.len() {
-                        buf2.truncate(size as usize);
+                Ok(Command::Change { hash: h } | Command::Partial { hash: h }) => {
+                    libatomic::changestore::filesystem::push_filename(&mut repo.changes_dir, &h);
+                    debug!("repo = {:?}", repo.changes_dir);
+                    let mut f = std::fs::File::open(&repo.changes_dir)?;
+                    let size = std::fs::metadata(&repo.changes_dir)?.len();
+                    let size = if full || size <= PARTIAL_CHANGE_SIZE {
+                        size
+                    } else {
+                        libatomic::change::Change::size_no_contents(&mut f)?
+                    };
+                    o.write_u64::<BigEndian>(size)?;
+                    let mut size = size as usize;
+                    while size > 0 {
+                        if size < buf2.len() {
+                            buf2.truncate(size as usize);
+                        }
+                        let n = f.read(&mut buf2[..])?;
+                        if n == 0 {
+                            break;
+                        }
+                        size -= n;
+                        o.write_all(&buf2[..n])?;
                     }
-                    let n = f.read(&mut buf2[..])?;
-                    if n == 0 {
-                        break;
+                    o.flush()?;
+                    libatomic::changestore::filesystem::pop_filename(&mut repo.changes_dir);
+                }
+                Ok(Command::Bundle { hashes } | Command::PartialBundle { hashes }) => {
+                    let mut changes = Vec::with_capacity(hashes.len());
+                    for h in hashes.iter() {
+                        let mut path = repo.changes_dir.clone();
+                        libatomic::changestore::filesystem::push_filename(&mut path, h);
+                        let mut f = std::fs::File::open(&path)?;
+                        let size = std::fs::metadata(&path)?.len();
+                        let size = if !partial || size <= PARTIAL_CHANGE_SIZE {
+                            size
+                        } else {
+                            libatomic::change::Change::size_no_contents(&mut f)?
+                        };
+                        let mut change = Vec::with_capacity(size as usize);
+                        (&mut f).take(size).read_to_end(&mut change)?;
+                        changes.push(change);
                     }
-                    size -= n;
-                    o.write_all(&buf2[..n])?;
+                    let bundle = bundle::encode(hashes.iter().zip(changes.iter().map(|c| &c[..])))?;
+                    o.write_u64::<BigEndian>(bundle.len() as u64)?;
+                    o.write_all(&bundle)?;
+                    o.flush()?;
                 }
-                o.flush()?;
-                libatomic::changestore::filesystem::pop_filename(&mut repo.changes_dir);
-            } else if let Some(cap) = APPLY.captures(&buf) {
-                let h = if let Some(h) = Hash::from_base32(cap[2].as_bytes()) {
-                    h
-                } else {
-                    debug!("protocol error {:?}", buf);
-                    bail!("Protocol error");
-                };
-                let mut path = repo.changes_dir.clone();
-                libatomic::changestore::filesystem::push_filename(&mut path, &h);
-                std::fs::create_dir_all(path.parent().unwrap())?;
-                let size: usize = cap[3].parse().unwrap();
-                buf2.resize(size, 0);
-                s.read_exact(&mut buf2)?;
-                std::fs::write(&path, &buf2)?;
-                libatomic::change::Change::deserialize(&path.to_string_lossy(), Some(&h))?;
-                let channel = load_channel(&*txn.read(), &cap[1])?;
-                {
-                    let mut channel_ = channel.write();
-                    txn.write().apply_node_ws(
+                Ok(Command::Apply {
+                    channel: name,
+                    hash: h,
+                    size,
+                }) => {
+                    check_clear(&repo.config, &name)?;
+                    buf2.resize(size, 0);
+                    s.read_exact(&mut buf2)?;
+                    let channel = load_channel(&*txn.read(), &name)?;
+                    apply(
+                        &repo.changes_dir,
                         &repo.changes,
-                        &mut channel_,
+                        &txn,
+                        &channel,
                         &h,
-                        libatomic::pristine::NodeType::Change,
+                        &buf2,
                         &mut ws,
                     )?;
+                    applied.insert(name, channel);
                 }
-                applied.insert(cap[1].to_string(), channel);
-            } else if let Some(cap) = ARCHIVE.captures(&buf) {
-                let mut w = Vec::new();
-                let mut tarball = libatomic::output::Tarball::new(
-                    &mut w,
-                    cap.get(6).map(|x| x.as_str().to_string()),
-                    0,
-                );
-                let channel = load_channel(&*txn.read(), &cap[1])?;
-                let conflicts = if let Some(caps) = cap.get(2) {
-                    debug!("caps = {:?}", caps.as_str());
-                    let mut hashes = caps.as_str().split(' ').filter(|x| !x.is_empty());
-                    let state: libatomic::Merkle = hashes.next().unwrap().parse().unwrap();
-                    let extra: Vec<libatomic::Hash> = hashes.map(|x| x.parse().unwrap()).collect();
-                    debug!("state = {:?}, extra = {:?}", state, extra);
-                    if txn.read().current_state(&*channel.read())? == state && extra.is_empty() {
-                        txn.archive(&repo.changes, &channel, &mut tarball)?
-                    } else {
-                        use rand::Rng;
-                        let fork_name: String = rand::thread_rng()
-                            .sample_iter(&rand::distributions::Alphanumeric)
-                            .take(30)
-                            .map(|x| x as char)
-                            .collect();
-                        let mut fork = {
-                            let mut txn = txn.write();
-                            txn.fork(&channel, &fork_name)?
-                        };
-                        let conflicts = txn.archive_with_state(
+                Ok(Command::Applybundle {
+                    channel: name,
+                    size,
+                }) => {
+                    check_clear(&repo.config, &name)?;
+                    buf2.resize(size, 0);
+                    s.read_exact(&mut buf2)?;
+                    let channel = load_channel(&*txn.read(), &name)?;
+                    for (h, change) in bundle::decode(&buf2)? {
+                        apply(
+                            &repo.changes_dir,
                             &repo.changes,
-                            &mut fork,
-                            &state,
-                            &extra,
-                            &mut tarball,
-                            0,
+                            &txn,
+                            &channel,
+                            &h,
+                            &change,
+                            &mut ws,
                         )?;
-                        txn.write().drop_channel(&fork_name)?;
-                        conflicts
                     }
-                } else {
-                    txn.archive(&repo.changes, &channel, &mut tarball)?
-                };
-                std::mem::drop(tarball);
-                let mut o = std::io::stdout();
-                o.write_u64::<BigEndian>(w.len() as u64)?;
-                o.write_u64::<BigEndian>(conflicts.len() as u64)?;
-                o.write_all(&w)?;
-                o.flush()?;
-            } else if let Some(cap) = IDENTITIES.captures(&buf) {
-                let last_touched: u64 = if let Some(last) = cap.get(2) {
-                    last.as_str().parse().unwrap()
-                } else {
-                    0
-                };
-                let mut id_dir = repo.path.clone();
-                id_dir.push(DOT_DIR);
-                id_dir.push("identities");
-                let r = if let Ok(r) = std::fs::read_dir(&id_dir) {
-                    r
-                } else {
+                    applied.insert(name, channel);
+                }
+                Ok(Command::Archive {
+                    channel: name,
+                    state,
+                    prefix,
+                }) => {
+                    let mut w = Vec::new();
+                    let mut tarball = libatomic::output::Tarball::new(&mut w, prefix, 0);
+                    let channel = load_channel(&*txn.read(), &name)?;
+                    let conflicts = if let Some((state, extra)) = state {
+                        debug!("state = {:?}, extra = {:?}", state, extra);
+                        if txn.read().current_state(&*channel.read())? == state && extra.is_empty()
+                        {
+                            txn.archive(&repo.changes, &channel, &mut tarball)?
+                        } else {
+                            use rand::Rng;
+                            let fork_name: String = rand::thread_rng()
+                                .sample_iter(&rand::distributions::Alphanumeric)
+                                .take(30)
+                                .map(|x| x as char)
+                                .collect();
+                            let mut fork = {
+                                let mut txn = txn.write();
+                                txn.fork(&channel, &fork_name)?
+                            };
+                            let conflicts = txn.archive_with_state(
+                                &repo.changes,
+                                &mut fork,
+                                &state,
+                                &extra,
+                                &mut tarball,
+                                0,
+                            )?;
+                            txn.write().drop_channel(&fork_name)?;
+                            conflicts
+                        }
+                    } else {
+                        txn.archive(&repo.changes, &channel, &mut tarball)?
+                    };
+                    std::mem::drop(tarball);
+                    let mut o = std::io::stdout();
+                    o.write_u64::<BigEndian>(w.len() as u64)?;
+                    o.write_u64::<BigEndian>(conflicts.len() as u64)?;
+                    o.write_all(&w)?;
+                    o.flush()?;
+                }
+                Ok(Command::Identities { since }) => {
+                    let last_touched = since.unwrap_or(0);
+                    let mut id_dir = repo.path.clone();
+                    id_dir.push(DOT_DIR);
+                    id_dir.push("identities");
+                    let r = if let Ok(r) = std::fs::read_dir(&id_dir) {
+                        r
+                    } else {
+                        writeln!(o)?;
+                        o.flush()?;
+                        continue;
+                    };
+                    let mut at_least_one = false;
+                    for id in r {
+                        at_least_one |= output_id(id, last_touched, &mut o).unwrap_or(false);
+                    }
+                    debug!("at least one {:?}", at_least_one);
+                    if !at_least_one {
+                        writeln!(o)?;
+                    }
                     writeln!(o)?;
                     o.flush()?;
-                    continue;
-                };
-                let mut at_least_one = false;
-                for id in r {
-                    at_least_one |= output_id(id, last_touched, &mut o).unwrap_or(false);
                 }
-                debug!("at least one {:?}", at_least_one);
-                if !at_least_one {
+                Ok(Command::Notes) => {
+                    // One JSON note per line, terminated by an empty line.
+                    for note in notes.all(None)? {
+                        serde_json::to_writer(&mut o, &note)?;
+                        writeln!(o)?;
+                    }
                     writeln!(o)?;
+                    o.flush()?;
                 }
-                writeln!(o)?;
-                o.flush()?;
-            } else {
-                error!("unmatched")
+                Ok(Command::Notesup { size }) => {
+                    let mut buf = vec![0; size];
+                    s.read_exact(&mut buf)?;
+                    let received: Vec<atomic_repository::notes::Note> =
+                        serde_json::from_slice(&buf)?;
+                    let n = notes.merge_all(received)?;
+                    debug!("merged {} notes", n);
+                }
+                Ok(Command::Links) => {
+                    // Like notes, one JSON link per line.
+                    for link in links.all(None)? {
+                        serde_json::to_writer(&mut o, &link)?;
+                        writeln!(o)?;
+                    }
+                    writeln!(o)?;
+                    o.flush()?;
+                }
+                Ok(Command::Linksup { size }) => {
+                    let mut buf = vec![0; size];
+                    s.read_exact(&mut buf)?;
+                    let received: Vec<atomic_repository::links::Link> =
+                        serde_json::from_slice(&buf)?;
+                    let n = links.merge_all(received)?;
+                    debug!("merged {} links", n);
+                }
+                Ok(Command::Attribution { hashes }) => {
+                    // Like notes, one JSON attribution per line.
+                    for a in attribution::outgoing(&pristine, &hashes)? {
+                        serde_json::to_writer(&mut o, &a)?;
+                        writeln!(o)?;
+                    }
+                    writeln!(o)?;
+                    o.flush()?;
+                }
+                Ok(Command::Attributionup { size }) => {
+                    let mut buf = vec![0; size];
+                    s.read_exact(&mut buf)?;
+                    let received: Vec<ChangeAttribution> = serde_json::from_slice(&buf)?;
+                    received_attribution.extend(received);
+                }
+                Ok(command) => error!("unsupported command {:?}", command),
+                Err(e) => error!("{}", e),
             }
             buf.clear();
         }
@@ -471,11 +594,51 @@ impl Protocol {
         }
         if applied_nonempty {
             txn.commit()?;
+        } else {
+            std::mem::drop(txn);
+        }
+        // The attribution store opens its own transactions, so received
+        // attribution is only stored once the session's is closed.
+        if !received_attribution.is_empty() {
+            let n = attribution::incoming(
+                &pristine,
+                &repo.changes_dir,
+                received_attribution,
+                &attribution_config,
+            )?;
+            debug!("stored the attribution of {} changes", n);
         }
         Ok(())
     }
 }
 
+/// Write `change`, received from the client, to the change store, and
+/// apply it to `channel`.
+fn apply(
+    changes_dir: &std::path::Path,
+    changes: &libatomic::changestore::filesystem::FileSystem,
+    txn: &ArcTxn<pristine::sanakirja::MutTxn<()>>,
+    channel: &ChannelRef<pristine::sanakirja::MutTxn<()>>,
+    h: &Hash,
+    change: &[u8],
+    ws: &mut libatomic::ApplyWorkspace,
+) -> Result<(), anyhow::Error> {
+    let mut path = changes_dir.to_path_buf();
+    libatomic::changestore::filesystem::push_filename(&mut path, h);
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    std::fs::write(&path, change)?;
+    libatomic::change::Change::deserialize(&path.to_string_lossy(), Some(h))?;
+    let mut channel_ = channel.write();
+    txn.write().apply_node_ws(
+        changes,
+        &mut channel_,
+        h,
+        libatomic::pristine::NodeType::Change,
+        ws,
+    )?;
+    Ok(())
+}
+
 fn output_id<W: Write>(
     id: Result<std::fs::DirEntry, std::io::Error>,
     last_touched: u64,
@@ -10,11 +10,15 @@ use libatomic::changestore::ChangeStore;
 use libatomic::pristine::sanakirja::MutTxn;
 use libatomic::pristine::TagMetadataMutTxnT;
 use libatomic::*;
-use log::debug;
+use log::{debug, warn};
 use regex::Regex;
 
 use atomic_interaction::{ProgressBar, Spinner, APPLY_MESSAGE, OUTPUT_MESSAGE};
+use atomic_remote::failure::{FailureKind, FailureReport};
+use atomic_remote::timing::{PullPhase, PullTimings};
 use atomic_remote::{self as remote, Node, PushDelta, RemoteDelta, RemoteRepo};
+use atomic_repository::hooks;
+use atomic_repository::lock::LockOptions;
 use atomic_repository::Repository;
 
 #[derive(Parser, Debug)]
@@ -34,10 +38,42 @@ pub enum SubRemote {
     /// Deletes the remote
     #[clap(name = "delete")]
     Delete { remote: String },
+    /// Pin the certificate currently presented by an HTTPS remote,
+    /// replacing its previous pin
+    #[clap(name = "pin")]
+    Pin {
+        /// Name or URL of the remote
+        remote: String,
+    },
+    /// Remove the pinned certificate of an HTTPS remote
+    #[clap(name = "unpin")]
+    Unpin {
+        /// Name or URL of the remote
+        remote: String,
+    },
 }
 
 impl Remote {
-    pub fn run(self) -> Result<(), anyhow::Error> {
+    pub async fn run(self) -> Result<(), anyhow::Error> {
+        match self.subcmd {
+            Some(SubRemote::Pin { ref remote }) => {
+                let url = self.https_url(remote)?;
+                let fp = remote::pin::fetch_fingerprint(&url).await?;
+                let origin = remote::pin::origin(&url).unwrap();
+                remote::pin::Pins::global()?.set(&origin, &fp)?;
+                writeln!(std::io::stdout(), "{} {}", origin, fp)?;
+                return Ok(());
+            }
+            Some(SubRemote::Unpin { ref remote }) => {
+                let url = self.https_url(remote)?;
+                let origin = remote::pin::origin(&url).unwrap();
+                if !remote::pin::Pins::global()?.remove(&origin)? {
+                    bail!("No pinned certificate for {}", origin)
+                }
+                return Ok(());
+            }
+            _ => {}
+        }
         let repo = Repository::find_root(self.repo_path)?;
         debug!("{:?}", repo.config);
         let mut stdout = std::io::stdout();
@@ -69,9 +105,29 @@ impl Remote {
                     txn.commit()?;
                 }
             }
+            Some(SubRemote::Pin { .. }) | Some(SubRemote::Unpin { .. }) => unreachable!(),
         }
         Ok(())
     }
+
+    /// URL of `remote`, a remote of the repository or a URL
+    fn https_url(&self, remote: &str) -> Result<url::Url, anyhow::Error> {
+        let configured = Repository::find_root(self.repo_path.clone())
+            .ok()
+            .and_then(|repo| {
+                repo.config.remotes.iter().find_map(|r| match r {
+                    atomic_config::RemoteConfig::Http { name, http, .. } if name == remote => {
+                        Some(http.clone())
+                    }
+                    _ => None,
+                })
+            });
+        let url: url::Url = configured.as_deref().unwrap_or(remote).parse()?;
+        if remote::pin::origin(&url).is_none() {
+            bail!("Not an HTTPS remote: {}", url)
+        }
+        Ok(url)
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -85,8 +141,9 @@ pub struct Push {
     /// Push all changes
     #[clap(long = "all", short = 'a', conflicts_with = "changes")]
     all: bool,
-    /// Force an update of the local remote cache. May effect some
-    /// reporting of unrecords/concurrent changes in the remote.
+    /// Force an update of the local remote cache, even if the remote
+    /// unrecorded changes of this channel. The discarded cache entries are
+    /// listed, and journaled in `.atomic/cache-journal`.
     #[clap(long = "force-cache", short = 'f')]
     force_cache: bool,
     /// Do not check certificates (HTTPS remotes only, this option might be dangerous)
@@ -109,6 +166,12 @@ pub struct Push {
     /// Skip attribution sync even if configured
     #[clap(long = "skip-attribution", conflicts_with = "with_attribution")]
     skip_attribution: bool,
+    /// Exit with status 2 if there is nothing to push, instead of 0
+    #[clap(long = "detailed-exitcode")]
+    detailed_exitcode: bool,
+    /// Write a JSON report to this file if the push fails or has nothing to push
+    #[clap(long = "failure-report", value_hint = ValueHint::FilePath)]
+    failure_report: Option<PathBuf>,
 }
 
 #[derive(Parser, Debug)]
@@ -122,8 +185,9 @@ pub struct Pull {
     /// Pull all changes
     #[clap(long = "all", short = 'a', conflicts_with = "changes")]
     all: bool,
-    /// Force an update of the local remote cache. May effect some
-    /// reporting of unrecords/concurrent changes in the remote.
+    /// Force an update of the local remote cache, even if the remote
+    /// unrecorded changes of this channel. The discarded cache entries are
+    /// listed, and journaled in `.atomic/cache-journal`.
     #[clap(long = "force-cache", short = 'f')]
     force_cache: bool,
     /// Do not check certificates (HTTPS remotes only, this option might be dangerous)
@@ -149,6 +213,74 @@ pub struct Pull {
     /// Skip attribution sync even if configured
     #[clap(long = "skip-attribution", conflicts_with = "with_attribution")]
     skip_attribution: bool,
+    /// Exit with status 2 if there is nothing to pull, and 3 if the pull
+    /// left conflicts, instead of 0
+    #[clap(long = "detailed-exitcode")]
+    detailed_exitcode: bool,
+    /// Write a JSON report to this file if the pull fails, has nothing to
+    /// pull or leaves conflicts
+    #[clap(long = "failure-report", value_hint = ValueHint::FilePath)]
+    failure_report: Option<PathBuf>,
+}
+
+/// The cache update asked for by `--force-cache`
+fn cache_update(force_cache: bool) -> remote::CacheUpdate {
+    if force_cache {
+        remote::CacheUpdate::Force(remote::DiscardCapability::confirmed())
+    } else {
+        remote::CacheUpdate::Safe
+    }
+}
+
+/// List the cache entries discarded by `--force-cache` on stderr
+fn report_discarded(repo: &Repository, discarded: &[(u64, Node)]) -> Result<(), anyhow::Error> {
+    if discarded.is_empty() {
+        return Ok(());
+    }
+    let mut stderr = std::io::stderr();
+    writeln!(
+        stderr,
+        "Discarded {} entries of the remote cache (journaled in {}):",
+        discarded.len(),
+        repo.cache_journal().path().display()
+    )?;
+    for (n, node) in discarded {
+        writeln!(stderr, "  {} {}", n, node.hash.to_base32())?;
+    }
+    Ok(())
+}
+
+/// Report the outcome of a push or pull, as asked by `--detailed-exitcode`
+/// and `--failure-report`. Failures exit with the code of their kind (see
+/// [`FailureKind::exit_code`]) in any case, but the outcomes that aren't
+/// failures only with `--detailed-exitcode`.
+fn report_outcome(
+    outcome: Result<Option<FailureReport>, anyhow::Error>,
+    detailed_exitcode: bool,
+    failure_report: Option<&std::path::Path>,
+) -> Result<(), anyhow::Error> {
+    let write = |report: &FailureReport| -> Result<(), anyhow::Error> {
+        if let Some(path) = failure_report {
+            std::fs::write(path, serde_json::to_vec_pretty(report)?)?;
+        }
+        Ok(())
+    };
+    match outcome {
+        Ok(None) => Ok(()),
+        Ok(Some(report)) => {
+            write(&report)?;
+            if detailed_exitcode {
+                std::process::exit(report.exit_code)
+            }
+            Ok(())
+        }
+        Err(e) => {
+            if let Err(w) = write(&FailureReport::of(&e)) {
+                warn!("Failed to write the failure report: {}", w);
+            }
+            Err(e)
+        }
+    }
 }
 
 lazy_static! {
@@ -171,12 +303,13 @@ impl Push {
                 txn,
                 &self.path,
                 channel,
-                Some(self.force_cache),
+                cache_update(self.force_cache),
                 repo,
                 self.changes.as_slice(),
                 false,
             )
             .await?;
+        report_discarded(repo, &remote_delta.discarded)?;
         if let RemoteRepo::LocalChannel(ref remote_channel) = remote {
             remote_delta.to_local_channel_push(
                 remote_channel,
@@ -191,8 +324,17 @@ impl Push {
     }
 
     pub async fn run(self) -> Result<(), anyhow::Error> {
+        let detailed_exitcode = self.detailed_exitcode;
+        let failure_report = self.failure_report.clone();
+        let outcome = self.push().await;
+        report_outcome(outcome, detailed_exitcode, failure_report.as_deref())
+    }
+
+    /// Push, returning a report if there was nothing to push
+    async fn push(self) -> Result<Option<FailureReport>, anyhow::Error> {
         let mut stderr = std::io::stderr();
-        let repo = Repository::find_root(self.repo_path.clone())?;
+        let (repo, _lock) =
+            Repository::find_root_locked(self.repo_path.clone(), "push", &LockOptions::from_env())?;
         debug!("{:?}", repo.config);
         let txn = repo.pristine.arc_txn_begin()?;
         let cur = txn
@@ -250,10 +392,20 @@ impl Push {
 
         debug!("to_upload = {:?}", to_upload);
 
+        // Notes are not part of the changes, send them even if there is
+        // nothing else to push.
+        remote.upload_notes(&repo).await?;
+        if repo.config.sync_links {
+            remote.upload_links(&repo).await?;
+        }
+
         if to_upload.is_empty() {
             writeln!(stderr, "Nothing to push")?;
             txn.commit()?;
-            return Ok(());
+            return Ok(Some(FailureReport::new(
+                FailureKind::NothingToDo,
+                "Nothing to push",
+            )));
         }
 
         notify_remote_unrecords(&repo, remote_unrecs.as_slice());
@@ -286,25 +438,9 @@ impl Push {
                 }
             }
 
-            u.sort_by(|a, b| {
-                if a.is_change() && b.is_change() {
-                    let na = txn.get_revchanges(&channel, &a.hash).unwrap().unwrap();
-                    let nb = txn.get_revchanges(&channel, &b.hash).unwrap().unwrap();
-                    na.cmp(&nb)
-                } else if a.is_tag() && b.is_tag() {
-                    let na = txn
-                        .channel_has_state(txn.states(&*channel.read()), &a.state.into())
-                        .unwrap()
-                        .unwrap();
-                    let nb = txn
-                        .channel_has_state(txn.states(&*channel.read()), &b.state.into())
-                        .unwrap()
-                        .unwrap();
-                    na.cmp(&nb)
-                } else {
-                    std::cmp::Ordering::Equal
-                }
-            });
+            // Keep the order given on the command line where the
+            // dependencies allow it.
+            let u = remote::order::upload_order(&u, |h| repo.changes.get_dependencies(h))?;
 
             if !not_found.is_empty() {
                 bail!("Changes not found: {:?}", not_found)
@@ -330,26 +466,77 @@ impl Push {
         if to_upload.is_empty() {
             writeln!(stderr, "Nothing to push")?;
             txn.commit()?;
-            return Ok(());
+            return Ok(Some(FailureReport::new(
+                FailureKind::NothingToDo,
+                "Nothing to push",
+            )));
         }
 
-        remote
-            .upload_nodes(
-                &mut *txn.write(),
-                repo.changes_dir.clone(),
-                push_channel,
-                &to_upload,
-            )
-            .await?;
+        // `pre-push` hooks may refuse the nodes before they are uploaded.
+        let hook_nodes: Vec<_> = to_upload.iter().map(|n| n.hook_node()).collect();
+        let payload = repo.hook_payload(
+            hooks::Event::PrePush,
+            Some(remote_name.as_str()),
+            channel_name,
+            hook_nodes.clone(),
+        );
+        repo.hooks().run(hooks::Event::PrePush, &payload)?;
+
+        if remote::confidential::is_confidential(&repo, remote_channel) {
+            // The remote only stores these changes encrypted.
+            remote
+                .upload_envelopes(&repo, remote_channel, &to_upload)
+                .await?;
+        } else {
+            remote
+                .upload_nodes(
+                    &mut *txn.write(),
+                    repo.changes_dir.clone(),
+                    push_channel,
+                    &to_upload,
+                )
+                .await?;
+        }
 
         debug!("Upload changes completed, committing local transaction");
         txn.commit()?;
         debug!("Local transaction committed successfully");
 
+        // Like provenance, failing to send the attribution of the pushed
+        // changes doesn't fail the push. Confidential channels only get
+        // envelopes.
+        if !remote::confidential::is_confidential(&repo, remote_channel) {
+            let pushed: Vec<_> = to_upload
+                .iter()
+                .filter(|n| n.is_change())
+                .map(|n| n.hash)
+                .collect();
+            if let Err(e) = remote.upload_attribution(&repo, &pushed).await {
+                warn!("Failed to send the attribution of pushed changes: {}", e);
+            }
+        }
+
+        // Provenance only helps debugging, failing to record it doesn't
+        // fail the push.
+        let pushed = to_upload.iter().filter(|n| n.is_change()).map(|n| &n.hash);
+        if let Err(e) = repo
+            .provenance()
+            .record_push(remote_name, pushed, chrono::Utc::now())
+        {
+            warn!("Failed to record the provenance of pushed changes: {}", e);
+        }
+        let payload = repo.hook_payload(
+            hooks::Event::PostPush,
+            Some(remote_name.as_str()),
+            channel_name,
+            hook_nodes,
+        );
+        repo.hooks().run(hooks::Event::PostPush, &payload)?;
+
         debug!("Calling remote.finish()");
         remote.finish().await?;
         debug!("remote.finish() completed");
-        Ok(())
+        Ok(None)
     }
 }
 
@@ -362,24 +549,45 @@ impl Pull {
         channel: &mut ChannelRef<MutTxn<()>>,
         repo: &mut Repository,
         remote: &mut RemoteRepo,
+        timings: &mut PullTimings,
     ) -> Result<RemoteDelta<MutTxn<()>>, anyhow::Error> {
-        let force_cache = if self.force_cache {
-            Some(self.force_cache)
-        } else {
-            None
-        };
+        let from_channel = self
+            .from_channel
+            .as_deref()
+            .unwrap_or(libatomic::DEFAULT_CHANNEL);
+        if remote::confidential::is_confidential(repo, from_channel) {
+            // Confidential channels are pulled as envelopes, which the
+            // remote can't compare with our channel.
+            let complete =
+                atomic_identity::Complete::load(&atomic_identity::choose_identity_name().await?)?;
+            let (key, _) = complete.decrypt()?;
+            let to_download = remote
+                .download_envelopes(repo, txn, channel, from_channel, &key)
+                .await?;
+            return Ok(RemoteDelta {
+                inodes: HashSet::new(),
+                to_download,
+                remote_ref: None,
+                ours_ge_dichotomy_set: HashSet::new(),
+                theirs_ge_dichotomy_set: HashSet::new(),
+                theirs_ge_dichotomy: Vec::new(),
+                remote_unrecs: Vec::new(),
+                discarded: Vec::new(),
+            });
+        }
         let delta = remote
             .update_changelist_pushpull(
                 txn,
                 &self.path,
                 channel,
-                force_cache,
+                cache_update(self.force_cache),
                 repo,
                 self.changes.as_slice(),
                 true,
             )
             .await?;
-        let to_download = remote
+        report_discarded(repo, &delta.discarded)?;
+        let pulled = remote
             .pull(
                 repo,
                 txn,
@@ -389,15 +597,30 @@ impl Pull {
                 false,
             )
             .await?;
+        timings.merge(&pulled.timings);
 
         Ok(RemoteDelta {
-            to_download,
+            to_download: pulled.pulled,
             ..delta
         })
     }
 
     pub async fn run(self) -> Result<(), anyhow::Error> {
-        let mut repo = Repository::find_root(self.repo_path.clone())?;
+        let detailed_exitcode = self.detailed_exitcode;
+        let failure_report = self.failure_report.clone();
+        let outcome = self.pull().await;
+        report_outcome(outcome, detailed_exitcode, failure_report.as_deref())
+    }
+
+    /// Pull, returning a report if there was nothing to pull or if the
+    /// pull left conflicts
+    async fn pull(mut self) -> Result<Option<FailureReport>, anyhow::Error> {
+        let (mut repo, _lock) =
+            Repository::find_root_locked(self.repo_path.clone(), "pull", &LockOptions::from_env())?;
+        // Pulls into a sparse clone stay restricted to its paths.
+        if self.path.is_empty() {
+            self.path = repo.config.sparse.clone();
+        }
         let txn = repo.pristine.arc_txn_begin()?;
         let cur = txn
             .read()
@@ -412,10 +635,12 @@ impl Pull {
         let is_current_channel = channel_name == cur;
         let mut channel = txn.write().open_or_create_channel(&channel_name)?;
         debug!("{:?}", repo.config);
+        // Owned, since `repo` is borrowed mutably before the provenance of
+        // the pulled changes is recorded.
         let remote_name = if let Some(ref rem) = self.from {
-            rem
+            rem.clone()
         } else if let Some(ref def) = repo.config.default_remote {
-            def
+            def.clone()
         } else {
             bail!("Missing remote")
         };
@@ -436,6 +661,9 @@ impl Pull {
         .await?;
         debug!("downloading");
 
+        // Nodes that fail to apply are quarantined, along with the nodes
+        // depending on them, and the others are still applied.
+        let mut report = remote::quarantine::PullReport::new();
         let RemoteDelta {
             inodes,
             remote_ref,
@@ -443,7 +671,13 @@ impl Pull {
             remote_unrecs,
             ..
         } = self
-            .to_download(&mut *txn.write(), &mut channel, &mut repo, &mut remote)
+            .to_download(
+                &mut *txn.write(),
+                &mut channel,
+                &mut repo,
+                &mut remote,
+                &mut report.timings,
+            )
             .await?;
 
         let hash = super::pending(txn.clone(), &mut channel, &mut repo)?;
@@ -451,6 +685,10 @@ impl Pull {
         if let Some(ref r) = remote_ref {
             remote.update_identities(&mut repo, r).await?;
         }
+        remote.update_notes(&repo).await?;
+        if repo.config.sync_links {
+            remote.update_links(&repo).await?;
+        }
 
         notify_remote_unrecords(&repo, remote_unrecs.as_slice());
 
@@ -461,7 +699,10 @@ impl Pull {
                 txn.write().unrecord(&repo.changes, &mut channel, h, 0)?;
             }
             txn.commit()?;
-            return Ok(());
+            return Ok(Some(FailureReport::new(
+                FailureKind::NothingToDo,
+                "Nothing to pull",
+            )));
         }
 
         if self.changes.is_empty() {
@@ -469,7 +710,9 @@ impl Pull {
                 let mut o = make_changelist(&repo.changes, &to_download, "pull")?;
                 to_download = loop {
                     let d = parse_changelist(&edit::edit_bytes(&o[..])?, &to_download);
-                    let comp = complete_deps(&repo.changes, Some(&to_download), &d)?;
+                    let comp = report.timings.time(PullPhase::DepCheck, || {
+                        complete_deps(&repo.changes, Some(&to_download), &d)
+                    })?;
                     if comp.len() == d.len() {
                         break comp;
                     }
@@ -477,7 +720,9 @@ impl Pull {
                 };
             }
         } else {
-            to_download = complete_deps(&repo.changes, None, &to_download)?;
+            to_download = report.timings.time(PullPhase::DepCheck, || {
+                complete_deps(&repo.changes, None, &to_download)
+            })?;
         }
 
         // Regenerate tag files from short version after download
@@ -489,26 +734,39 @@ impl Pull {
                 libatomic::changestore::filesystem::push_tag_filename(&mut tag_path, &merkle);
 
                 if tag_path.exists() {
-                    // Read the short tag data that was downloaded
-                    let short_data = std::fs::read(&tag_path)?;
-
-                    // Check if this is already a full tag (> 1KB) or short tag (< 500 bytes)
-                    if short_data.len() < 500 {
+                    let data = std::fs::read(&tag_path)?;
+                    let short = libatomic::tag::FileHeader::read(&data[..])?.is_short();
+                    // HTTP client already stripped the 8-byte length prefix
+                    let (header, unhashed) = if short {
+                        libatomic::tag::read_short_signed(std::io::Cursor::new(&data), &merkle)?
+                    } else {
+                        let mut tag = libatomic::tag::OpenTagFile::open(&tag_path, &merkle)?;
+                        (tag.header()?, tag.unhashed()?)
+                    };
+                    // Signatures are checked whether the remote sent the
+                    // short or the full version of the tag.
+                    let dot_dir = repo.path.join(libatomic::DOT_DIR);
+                    libatomic::tag::check_received(&merkle, &header, &unhashed, |key| {
+                        atomic_identity::is_known_key(&dot_dir, key)
+                    })?;
+
+                    if short {
                         debug!(
                             "Regenerating full tag from short version for {} ({} bytes -> full)",
                             merkle.to_base32(),
-                            short_data.len()
+                            data.len()
                         );
 
-                        // HTTP client already stripped the 8-byte length prefix
-                        // Parse header from short version directly
-                        let header =
-                            libatomic::tag::read_short(std::io::Cursor::new(&short_data), &merkle)?;
-
-                        // Regenerate full tag from our channel state
+                        // Regenerate full tag from our channel state, keeping the signature
                         let temp_path = tag_path.with_extension("tmp");
                         let mut w = std::fs::File::create(&temp_path)?;
-                        libatomic::tag::from_channel(&*txn.read(), &channel_name, &header, &mut w)?;
+                        libatomic::tag::from_channel_with_unhashed(
+                            &*txn.read(),
+                            &channel_name,
+                            &header,
+                            &unhashed,
+                            &mut w,
+                        )?;
 
                         // Atomically replace with full tag
                         std::fs::rename(&temp_path, &tag_path)?;
@@ -537,17 +795,18 @@ impl Pull {
                 );
 
                 // Use unified apply for both changes and tags
-                txn.apply_node_rec_ws(
-                    &repo.changes,
-                    &mut channel,
-                    &node.hash,
-                    node.node_type,
-                    &mut ws,
-                )?;
+                let apply_start = std::time::Instant::now();
+                let applied =
+                    report.apply(&repo.changes, &mut *txn, &mut *channel, node, &mut ws)?;
+                report.timings.since(PullPhase::Apply, apply_start);
                 apply_bar.inc(1);
+                if !applied {
+                    continue;
+                }
 
                 // If it's a tag, store consolidating metadata
                 if node.is_tag() {
+                    let tag_start = std::time::Instant::now();
                     let s = node.state;
                     if let Some(_n) = txn.channel_has_state(&channel.states, &s.into())? {
                         // Read tag file header to get original timestamp
@@ -607,6 +866,11 @@ impl Pull {
                         // Set the change_file_hash to the merkle state
                         // This is what should be used as a dependency when recording changes after the tag
                         tag.change_file_hash = Some(s);
+                        libatomic::attribution::tag_summary::attach(
+                            &repo.changes,
+                            &mut *txn,
+                            &mut tag,
+                        )?;
 
                         // Serialize and store consolidating tag metadata
                         let serialized = libatomic::pristine::SerializedTag::from_tag(&tag)?;
@@ -620,15 +884,39 @@ impl Pull {
                             s.to_base32()
                         );
                     }
+                    report.timings.since(PullPhase::TagMetadata, tag_start);
                 }
             }
         }
 
+        remote::quarantine::print_report(std::io::stderr(), &report)?;
+        let quarantined: HashSet<_> = report.quarantined.iter().map(|q| q.node).collect();
+        to_download.retain(|n| !quarantined.contains(n));
+
         debug!("completing changes");
+        let download_start = std::time::Instant::now();
         remote
             .complete_changes(&repo, &*txn.read(), &mut channel, &to_download, self.full)
             .await?;
+        // Attribution only helps auditing, failing to get it doesn't fail
+        // the pull.
+        let pulled: Vec<_> = to_download
+            .iter()
+            .filter(|n| n.is_change())
+            .map(|n| n.hash)
+            .collect();
+        let attribution = remote
+            .download_attribution(&pulled)
+            .await
+            .unwrap_or_else(|e| {
+                warn!(
+                    "Failed to download the attribution of pulled changes: {}",
+                    e
+                );
+                Vec::new()
+            });
         remote.finish().await?;
+        report.timings.since(PullPhase::Download, download_start);
 
         debug!("inodes = {:?}", inodes);
         debug!("to_download: {:?}", to_download.len());
@@ -657,6 +945,8 @@ impl Pull {
             }
         }
         std::mem::drop(txn_);
+        let output_start = std::time::Instant::now();
+        let mut conflicted = 0;
         if is_current_channel {
             let mut touched_paths = BTreeSet::new();
             {
@@ -675,6 +965,12 @@ impl Pull {
             if touched_paths.is_empty() {
                 touched_paths.insert(String::from(""));
             }
+            if !repo.config.sparse.is_empty() {
+                touched_paths = remote::sparse::restrict(
+                    &repo.config.sparse,
+                    touched_paths.iter().map(|p| p.as_str()),
+                );
+            }
             let mut last: Option<&str> = None;
             let mut conflicts = Vec::new();
             let _output_spinner = Spinner::new(OUTPUT_MESSAGE);
@@ -711,7 +1007,9 @@ impl Pull {
             }
 
             super::print_conflicts(&conflicts)?;
+            conflicted = conflicts.len();
         }
+        report.timings.since(PullPhase::Output, output_start);
         if let Some(h) = hash {
             txn.write().unrecord(&repo.changes, &mut channel, &h, 0)?;
             repo.changes.del_change(&h)?;
@@ -726,7 +1024,45 @@ impl Pull {
         }
 
         txn.commit()?;
-        Ok(())
+        if !attribution.is_empty() {
+            let config = remote::attribution::RemoteAttributionConfig::from_environment();
+            if let Err(e) = remote::attribution::incoming(
+                &repo.pristine,
+                &repo.changes_dir,
+                attribution,
+                &config,
+            ) {
+                warn!("Failed to store the attribution of pulled changes: {}", e);
+            }
+        }
+        report.save(&repo.quarantine(), channel_name)?;
+        report.timings.emit(&remote_name, to_download.len());
+        let pulled = report
+            .applied
+            .iter()
+            .filter(|n| n.is_change())
+            .map(|n| &n.hash);
+        if let Err(e) = repo
+            .provenance()
+            .record_pull(&remote_name, pulled, chrono::Utc::now())
+        {
+            warn!("Failed to record the provenance of pulled changes: {}", e);
+        }
+        let hook_nodes = report.applied.iter().map(|n| n.hook_node()).collect();
+        let payload = repo.hook_payload(
+            hooks::Event::PostPull,
+            Some(remote_name.as_str()),
+            channel_name,
+            hook_nodes,
+        );
+        repo.hooks().run(hooks::Event::PostPull, &payload)?;
+        if conflicted > 0 {
+            return Ok(Some(FailureReport::new(
+                FailureKind::Conflicts,
+                format!("The pull left {} conflicts", conflicted),
+            )));
+        }
+        Ok(None)
     }
 }
 
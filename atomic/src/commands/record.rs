@@ -13,8 +13,9 @@ use libatomic::{
     ArcTxn, Base32, ChannelMutTxnT, ChannelRef, ChannelTxnT, MutTxnTExt, TxnT, TxnTExt,
 };
 use libatomic::{HashMap, HashSet};
-use log::debug;
+use log::{debug, warn};
 
+use atomic_repository::lock::LockOptions;
 use atomic_repository::*;
 
 #[derive(Parser, Debug)]
@@ -115,6 +116,15 @@ pub(crate) fn parse_datetime_rfc2822(s: &str) -> Result<i64, &'static str> {
 }
 
 impl Record {
+    /// Whether AI attribution was given on the command line
+    fn has_ai_flags(&self) -> bool {
+        self.ai_assisted
+            || self.ai_provider.is_some()
+            || self.ai_model.is_some()
+            || self.ai_suggestion_type.is_some()
+            || self.ai_confidence.is_some()
+    }
+
     pub async fn run(self) -> Result<(), anyhow::Error> {
         // Setup environment variables from CLI flags if provided
         if self.ai_assisted || self.ai_provider.is_some() {
@@ -138,7 +148,11 @@ impl Record {
             }
         }
 
-        let repo = Repository::find_root(self.repo_path.clone())?;
+        let (repo, _lock) = Repository::find_root_locked(
+            self.repo_path.clone(),
+            "record",
+            &LockOptions::from_env(),
+        )?;
         let mut stdout = std::io::stdout();
         let mut stderr = std::io::stderr();
 
@@ -179,6 +193,8 @@ impl Record {
             extra.push(h)
         }
 
+        // The change being amended, superseded by the new one.
+        let mut amended = None;
         let header = if let Some(ref amend) = self.amend {
             let h = if let Some(ref hash) = amend {
                 txn.read().hash_from_prefix(hash)?.0
@@ -202,6 +218,7 @@ impl Record {
                 &h,
                 self.timestamp.unwrap_or(0) as u64,
             )?;
+            amended = Some(h);
             header
         } else {
             self.header().await?
@@ -227,6 +244,7 @@ impl Record {
         txn.write()
             .apply_root_change_if_needed(&repo.changes, &channel, rand::thread_rng())?;
 
+        let has_ai_flags = self.has_ai_flags();
         let result = self.record(
             txn,
             channel.clone(),
@@ -240,8 +258,23 @@ impl Record {
         )?;
         match result {
             Either::A((txn, mut change, updates, oldest)) => {
-                // Add AI attribution metadata BEFORE saving the change
-                if let Some(attribution) = libatomic::helpers::create_attribution_from_env() {
+                // Add AI attribution metadata BEFORE saving the change.
+                // Flags take precedence over the metadata registered by
+                // tools, which takes precedence over the environment.
+                let hooks = repo.attribution_hooks();
+                let registered = hooks.pending(Utc::now()).unwrap_or_else(|e| {
+                    warn!("Failed to read registered attribution: {}", e);
+                    None
+                });
+                let attribution = match registered {
+                    Some(ref registered) if !has_ai_flags => {
+                        libatomic::attribution::AttributionDetector::with_defaults()
+                            .with_registered(Some(registered.clone()))
+                            .serialized_attribution()
+                    }
+                    _ => libatomic::helpers::create_attribution_from_env(),
+                };
+                if let Some(attribution) = attribution {
                     if let Ok(serialized_attribution) =
                         libatomic::helpers::serialize_attribution_for_metadata(&attribution)
                     {
@@ -253,6 +286,7 @@ impl Record {
                     change.unhashed = Some(serde_json::json!({
                         "signature": secret.sign_raw(&hash.to_bytes()).unwrap(),
                     }));
+                    change.set_supersedes(&amended.into_iter().collect::<Vec<_>>());
                     Ok::<_, anyhow::Error>(())
                 })?;
 
@@ -283,6 +317,13 @@ impl Record {
                 }
                 std::mem::drop(txn_);
                 txn.commit()?;
+                // The registration was for this change.
+                if let Some(ref registered) = registered {
+                    hooks.consume(registered)?;
+                }
+                if let Some(ref amended) = amended {
+                    repo.supersedes().record(&hash, &[*amended], Utc::now())?;
+                }
             }
             Either::B(txn) => {
                 if no_prefixes {
@@ -375,7 +416,7 @@ impl Record {
         repo_path: CanonicalPathBuf,
         header: ChangeHeader,
         extra_deps: &[libatomic::Hash],
-        _repo_config: &atomic_config::Config,
+        repo_config: &atomic_config::Config,
         _pristine: &libatomic::pristine::sanakirja::Pristine,
     ) -> Result<
         Either<
@@ -393,6 +434,7 @@ impl Record {
         if self.ignore_missing {
             state.ignore_missing = true;
         }
+        state.forbidden_paths = atomic_repository::forbidden_paths(repo_config)?;
         if self.prefixes.is_empty() {
             if self.ignore_missing {
                 for f in ignore::Walk::new(&repo_path) {
@@ -4,11 +4,12 @@ use std::path::PathBuf;
 use anyhow::bail;
 use canonical_path::CanonicalPathBuf;
 use clap::{Parser, ValueHint};
-use libatomic::pristine::{sanakirja::MutTxn, NodeId, ChannelMutTxnT, Position};
+use libatomic::pristine::{sanakirja::MutTxn, ChannelMutTxnT, NodeId, Position};
 use libatomic::{ArcTxn, ChannelRef, ChannelTxnT, DepsTxnT, MutTxnT, TxnT, TxnTExt};
 use log::*;
 
 use atomic_interaction::{Spinner, OUTPUT_MESSAGE};
+use atomic_repository::lock::LockOptions;
 use atomic_repository::Repository;
 
 #[derive(Parser, Debug)]
@@ -53,7 +54,8 @@ impl Reset {
         let mut stderr = std::io::stderr();
 
         let has_repo_path = self.repo_path.is_some();
-        let repo = Repository::find_root(self.repo_path)?;
+        let (repo, _lock) =
+            Repository::find_root_locked(self.repo_path, "reset", &LockOptions::from_env())?;
         let txn = repo.pristine.arc_txn_begin()?;
 
         let cur = txn
@@ -3,6 +3,7 @@ use std::path::PathBuf;
 
 use crate::commands::record::parse_datetime_rfc2822;
 use anyhow::bail;
+use atomic_repository::lock::LockOptions;
 use atomic_repository::Repository;
 use clap::{Parser, ValueHint};
 use libatomic::change::ChangeHeader;
@@ -60,6 +61,9 @@ pub enum SubCommand {
         /// Increment patch version (x.y.Z)
         #[clap(long = "patch", conflicts_with_all = &["major", "minor", "version"])]
         patch: bool,
+        /// Sign the tag (its state and header) with the current identity's key
+        #[clap(long = "sign")]
+        sign: bool,
     },
     /// Restore a tag into a new channel.
     #[clap(name = "checkout")]
@@ -131,8 +135,10 @@ impl Tag {
                 major,
                 minor,
                 patch,
+                sign,
             }) => {
-                let mut repo = Repository::find_root(repo_path)?;
+                let (mut repo, _lock) =
+                    Repository::find_root_locked(repo_path, "tag", &LockOptions::from_env())?;
                 let txn = repo.pristine.arc_txn_begin()?;
                 let channel_name = if let Some(c) = channel {
                     c
@@ -178,8 +184,25 @@ impl Tag {
                 // Use version as the message if no message provided
                 let tag_message = message.or(Some(tag_version.clone()));
                 let header = header(author.as_deref(), tag_message, timestamp).await?;
-                let h: libatomic::Merkle =
-                    libatomic::tag::from_channel(&*txn.read(), &channel_name, &header, &mut w)?;
+                let unhashed = if sign {
+                    let complete = atomic_identity::Complete::load(
+                        &atomic_identity::choose_identity_name().await?,
+                    )?;
+                    let (key, _) = complete.decrypt()?;
+                    let state = libatomic::pristine::current_state(&*txn.read(), &*channel.read())?;
+                    libatomic::tag::Unhashed {
+                        signature: Some(libatomic::tag::sign(&key, &state, &header)?),
+                    }
+                } else {
+                    libatomic::tag::Unhashed::default()
+                };
+                let h: libatomic::Merkle = libatomic::tag::from_channel_with_unhashed(
+                    &*txn.read(),
+                    &channel_name,
+                    &header,
+                    &unhashed,
+                    &mut w,
+                )?;
                 libatomic::changestore::filesystem::push_tag_filename(&mut tag_path, &h);
                 std::fs::create_dir_all(tag_path.parent().unwrap())?;
                 std::fs::rename(&temp_path, &tag_path)?;
@@ -285,6 +308,19 @@ impl Tag {
                     // Set the change_file_hash to the merkle state
                     // This is what should be used as a dependency when recording changes after the tag
                     tag.change_file_hash = Some(h);
+                    if let Some(ref signature) = unhashed.signature {
+                        tag.metadata
+                            .insert("signed_by".to_string(), signature.key.fingerprint());
+                    }
+                    let summary = libatomic::attribution::tag_summary::attach(
+                        &repo.changes,
+                        &mut *txn.write(),
+                        &mut tag,
+                    )?;
+                    info!(
+                        "Tag attribution: {} of {} changes AI-assisted",
+                        summary.ai_assisted_changes, summary.total_changes
+                    );
 
                     // Note: We don't set change_file_hash because tags are referenced by their
                     // merkle hash directly (the hash used for the .tag filename), not a derived hash.
@@ -312,7 +348,11 @@ impl Tag {
                 mut tag,
                 to_channel,
             }) => {
-                let repo = Repository::find_root(repo_path)?;
+                let (repo, _lock) = Repository::find_root_locked(
+                    repo_path,
+                    "tag checkout",
+                    &LockOptions::from_env(),
+                )?;
                 let mut tag_path = repo.changes_dir.clone();
                 let h = if let Some(h) = libatomic::Merkle::from_base32(tag.as_bytes()) {
                     libatomic::changestore::filesystem::push_tag_filename(&mut tag_path, &h);
@@ -337,7 +377,8 @@ impl Tag {
                 writeln!(stdout, "Tag {} restored as channel {}", tag, channel_name)?;
             }
             Some(SubCommand::Reset { repo_path, tag }) => {
-                let repo = Repository::find_root(repo_path)?;
+                let (repo, _lock) =
+                    Repository::find_root_locked(repo_path, "tag reset", &LockOptions::from_env())?;
                 let mut tag_path = repo.changes_dir.clone();
                 let h = if let Some(h) = libatomic::Merkle::from_base32(tag.as_bytes()) {
                     libatomic::changestore::filesystem::push_tag_filename(&mut tag_path, &h);
@@ -375,7 +416,11 @@ impl Tag {
                 channel,
                 tag,
             }) => {
-                let repo = Repository::find_root(repo_path)?;
+                let (repo, _lock) = Repository::find_root_locked(
+                    repo_path,
+                    "tag delete",
+                    &LockOptions::from_env(),
+                )?;
                 let mut tag_path = repo.changes_dir.clone();
                 let h = if let Some(h) = libatomic::Merkle::from_base32(tag.as_bytes()) {
                     libatomic::changestore::filesystem::push_tag_filename(&mut tag_path, &h);
@@ -499,6 +544,16 @@ impl Tag {
                                             "    AI percentage: {:.1}%",
                                             summary.ai_percentage()
                                         )?;
+                                        let mut providers: Vec<_> =
+                                            summary.ai_provider_stats.iter().collect();
+                                        providers.sort_by(|a, b| a.0.cmp(b.0));
+                                        for (provider, stats) in providers {
+                                            writeln!(
+                                                stdout,
+                                                "    {}: {}",
+                                                provider, stats.change_count
+                                            )?;
+                                        }
                                     }
                                 }
                             }
@@ -535,6 +590,15 @@ impl Tag {
                     writeln!(stdout, "State {}", m.to_base32())?;
                     writeln!(stdout, "Author: {:?}", header.authors)?;
                     writeln!(stdout, "Date: {}", header.timestamp)?;
+                    match f.verify()? {
+                        libatomic::tag::SignatureStatus::Unsigned => {}
+                        libatomic::tag::SignatureStatus::Valid { fingerprint, .. } => {
+                            writeln!(stdout, "Signed by: {}", fingerprint)?
+                        }
+                        libatomic::tag::SignatureStatus::Invalid { reason } => {
+                            writeln!(stdout, "Invalid signature: {}", reason)?
+                        }
+                    }
                     writeln!(stdout, "\n    {}\n", header.message)?;
                     libatomic::changestore::filesystem::pop_filename(&mut tag_path);
                 }
@@ -4,6 +4,7 @@ use super::{make_changelist, parse_changelist};
 use atomic_remote::Node;
 
 use anyhow::{anyhow, bail};
+use atomic_repository::lock::LockOptions;
 use atomic_repository::Repository;
 use clap::{Parser, ValueHint};
 use libatomic::changestore::ChangeStore;
@@ -32,7 +33,8 @@ pub struct Unrecord {
 
 impl Unrecord {
     pub fn run(self) -> Result<(), anyhow::Error> {
-        let mut repo = Repository::find_root(self.repo_path)?;
+        let (mut repo, _lock) =
+            Repository::find_root_locked(self.repo_path, "unrecord", &LockOptions::from_env())?;
         debug!("{:?}", repo.config);
         let txn = repo.pristine.arc_txn_begin()?;
         let cur = txn
@@ -102,10 +104,21 @@ impl Unrecord {
                 let n = txn
                     .get_changeset(txn.changes(&channel_), &change_id)
                     .unwrap();
-                if n.is_none() {
+                let n: u64 = if let Some(&n) = n {
+                    n.into()
+                } else {
                     bail!("Change not in channel: {:?}", hash)
+                };
+                // Tags are immutable: the changes they cover stay.
+                if let Some(tag) = txn.iter_tags(txn.tags(&channel_), n)?.next() {
+                    let (t, _) = tag?;
+                    bail!(
+                        "Cannot unrecord change {} because the tag at position {} covers it",
+                        hash.to_base32(),
+                        u64::from(*t)
+                    )
                 }
-                changes.push((hash, change_id, n.map(|&x| x.into())));
+                changes.push((hash, change_id, Some(n)));
             }
         }
         debug!("changes: {:?}", changes);
@@ -0,0 +1,143 @@
+use std::io::Write;
+use std::path::PathBuf;
+
+use atomic_repository::lock::LockOptions;
+use atomic_repository::Repository;
+use atomic_workflows::{AuditRecord, WorkflowAuditLog};
+use clap::{Parser, ValueHint};
+use libatomic::{Base32, Hash, TxnT};
+
+#[derive(Parser, Debug)]
+pub struct Workflow {
+    #[clap(subcommand)]
+    subcmd: SubCommand,
+    /// Set the repository where this command should run. Defaults to the
+    /// first ancestor of the current directory that contains a `.atomic`
+    /// directory.
+    #[clap(long = "repository", value_hint = ValueHint::DirPath)]
+    repo_path: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub enum SubCommand {
+    /// Replay the workflow history of a change (state changes, approvals,
+    /// rejections, role grants), oldest first, from the workflow log.
+    #[clap(name = "history")]
+    History {
+        /// The hash of the change, or an unambiguous prefix thereof, or the
+        /// state of a tag
+        #[clap(value_name = "HASH")]
+        hash: String,
+        /// Only show the events of this workflow
+        #[clap(long = "workflow")]
+        workflow: Option<String>,
+        /// Output the events in JSON format, one per line
+        #[clap(long = "json")]
+        json: bool,
+    },
+    /// Fold the consecutive state changes of each workflow into
+    /// checkpoints signed with your identity, to shrink the workflow log.
+    /// Approvals, rejections and role grants are kept.
+    #[clap(name = "compact")]
+    Compact,
+    /// Check that the workflow history of a change wasn't tampered with:
+    /// the chain of its records, and the signatures of its checkpoints.
+    #[clap(name = "verify")]
+    Verify {
+        /// The hash of the change, or an unambiguous prefix thereof, or the
+        /// state of a tag
+        #[clap(value_name = "HASH")]
+        hash: String,
+    },
+}
+
+impl Workflow {
+    pub async fn run(self) -> Result<(), anyhow::Error> {
+        let mut stdout = std::io::stdout();
+        match self.subcmd {
+            SubCommand::History {
+                hash,
+                workflow,
+                json,
+            } => {
+                let repo = Repository::find_root(self.repo_path)?;
+                let log = WorkflowAuditLog::in_dot_dir(&repo.path.join(libatomic::DOT_DIR));
+                let id = change_id(&repo, hash);
+                for record in log.history(&id)? {
+                    let record = record?;
+                    if workflow.as_ref().is_some_and(|w| w != record.workflow()) {
+                        continue;
+                    }
+                    if json {
+                        serde_json::to_writer(&mut stdout, &record)?;
+                        writeln!(stdout)?;
+                        continue;
+                    }
+                    write!(
+                        stdout,
+                        "{} {} {}",
+                        record.timestamp().to_rfc3339(),
+                        record.workflow(),
+                        record
+                    )?;
+                    if let AuditRecord::Entry(ref entry) = record {
+                        if !entry.actor.username.is_empty() {
+                            write!(stdout, " ({})", entry.actor.username)?;
+                        }
+                    }
+                    writeln!(stdout)?;
+                }
+            }
+            SubCommand::Compact => {
+                let (repo, _lock) = Repository::find_root_locked(
+                    self.repo_path,
+                    "workflow compact",
+                    &LockOptions::from_env(),
+                )?;
+                let log = WorkflowAuditLog::in_dot_dir(&repo.path.join(libatomic::DOT_DIR));
+                let complete = atomic_identity::Complete::load(
+                    &atomic_identity::choose_identity_name().await?,
+                )?;
+                let (key, _) = complete.decrypt()?;
+                // Fail before rewriting the log if the key can't sign,
+                // e.g. if it expired.
+                key.sign(&[])?;
+                let compaction =
+                    log.compact(|bytes| serde_json::to_value(key.sign(bytes).ok()?).ok())?;
+                writeln!(
+                    stdout,
+                    "Compacted the workflow log from {} to {} records",
+                    compaction.before, compaction.after
+                )?;
+            }
+            SubCommand::Verify { hash } => {
+                let repo = Repository::find_root(self.repo_path)?;
+                let log = WorkflowAuditLog::in_dot_dir(&repo.path.join(libatomic::DOT_DIR));
+                let id = change_id(&repo, hash);
+                let checked = log.verify(&id, |checkpoint| {
+                    let signature = checkpoint
+                        .signature
+                        .clone()
+                        .and_then(|s| serde_json::from_value::<libatomic::key::Signature>(s).ok());
+                    signature.is_some_and(|s| s.verify(&checkpoint.signed_bytes()).is_ok())
+                })?;
+                writeln!(stdout, "Verified {} workflow records of {}", checked, id)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The identifier of `hash` in the workflow log. Tags are identified by
+/// their state, which isn't a change.
+fn change_id(repo: &Repository, hash: String) -> String {
+    resolve(repo, &hash).map(|h| h.to_base32()).unwrap_or(hash)
+}
+
+fn resolve(repo: &Repository, hash: &str) -> Result<Hash, anyhow::Error> {
+    if let Some(h) = Hash::from_base32(hash.as_bytes()) {
+        return Ok(h);
+    }
+    let txn = repo.pristine.txn_begin()?;
+    Ok(txn.hash_from_prefix(hash)?.0)
+}
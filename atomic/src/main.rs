@@ -17,6 +17,10 @@ pub struct Opts {
     /// Abort rather than prompt for input
     #[clap(long, global = true)]
     pub no_prompt: bool,
+    /// Seconds to wait for the repository lock held by another command or
+    /// the API server, 0 to fail at once (default: 30)
+    #[clap(long, global = true, value_name = "SECONDS")]
+    pub lock_timeout: Option<f64>,
 }
 
 #[derive(Parser, Debug)]
@@ -121,6 +125,15 @@ pub enum SubCommand {
     /// Manage tags (create tags, check out a tag)
     Tag(Tag),
 
+    /// Manage notes attached to changes
+    Note(Note),
+
+    /// Manage links from changes to issue trackers
+    Link(Link),
+
+    /// Inspect the workflows of changes and tags
+    Workflow(Workflow),
+
     /// A collection of tools for interactively managing the user's identities.
     /// This may be useful if you use Atomic in multiple contexts, for example
     /// both work & personal projects.
@@ -165,17 +178,22 @@ async fn main() {
     } else {
         atomic_interaction::set_context(InteractiveContext::Terminal);
     }
+    if let Some(timeout) = opts.lock_timeout {
+        std::env::set_var("ATOMIC_LOCK_TIMEOUT", timeout.to_string());
+    }
 
     if let Err(e) = run(opts).await {
         // This will only activate with the following environment variables:
         // RUST_BACKTRACE=1 RUST_LOG=error
         log::error!("Error: {:#?}", e);
+        // Scripts tell the failures of push and pull apart by their code.
+        let code = atomic_remote::failure::FailureKind::of(&e).exit_code();
         match e.downcast::<std::io::Error>() {
             Ok(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {}
             Ok(e) => writeln!(std::io::stderr(), "Error: {}", e).unwrap_or(()),
             Err(e) => writeln!(std::io::stderr(), "Error: {}", e).unwrap_or(()),
         }
-        std::process::exit(1);
+        std::process::exit(code);
     } else {
         std::process::exit(0);
     }
@@ -276,10 +294,13 @@ async fn run(opts: Opts) -> Result<(), anyhow::Error> {
         SubCommand::Fork(fork) => fork.run(),
         SubCommand::Unrecord(unrecord) => unrecord.run(),
         SubCommand::Apply(apply) => apply.run(),
-        SubCommand::Remote(remote) => remote.run(),
+        SubCommand::Remote(remote) => remote.run().await,
         SubCommand::Archive(archive) => archive.run().await,
         SubCommand::Credit(credit) => credit.run(),
         SubCommand::Tag(tag) => tag.run().await,
+        SubCommand::Note(note) => note.run(),
+        SubCommand::Link(link) => link.run(),
+        SubCommand::Workflow(workflow) => workflow.run().await,
         SubCommand::Identity(identity_wizard) => identity_wizard.run().await,
         SubCommand::Client(client) => client.run().await,
         SubCommand::ExternalSubcommand(command) => Ok(run_external_command(command)?),
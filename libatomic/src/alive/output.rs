@@ -266,17 +266,22 @@ pub fn output_graph<T: ChannelTxnT, B: VertexBuffer, P: ChangeStore>(
         return Ok(());
     }
     let now0 = std::time::Instant::now();
-    let scc = graph.tarjan(); // SCCs are given here in reverse order.
-    let (conflict_tree, forward_scc) = graph.dfs(&scc);
-    {
+    let (scc, conflict_tree) = {
+        let _span = phase_span!("alive_graph", vertices = graph.lines.len());
+        let scc = graph.tarjan(); // SCCs are given here in reverse order.
+        let (conflict_tree, forward_scc) = graph.dfs(&scc);
         let txn = txn.read();
         let channel = channel.read();
         graph.collect_forward_edges(&*txn, txn.graph(&*channel), &scc, &forward_scc, forward)?;
-    }
+        (scc, conflict_tree)
+    };
     crate::TIMERS.lock().unwrap().alive_graph += now0.elapsed();
     let now1 = std::time::Instant::now();
     debug!("conflict_tree = {:?}", conflict_tree);
-    output_conflict(changes, txn, channel, line_buf, graph, &scc, conflict_tree)?;
+    {
+        let _span = phase_span!("output_conflict", sccs = scc.len());
+        output_conflict(changes, txn, channel, line_buf, graph, &scc, conflict_tree)?;
+    }
     crate::TIMERS.lock().unwrap().alive_output += now1.elapsed();
     Ok(())
 }
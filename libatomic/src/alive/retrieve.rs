@@ -9,6 +9,7 @@ pub fn retrieve<T: GraphTxnT>(
     pos0: Position<NodeId>,
     include_deleted: bool,
 ) -> Result<Graph, TxnErr<T::GraphError>> {
+    let _span = phase_span!("alive_retrieve");
     let now = std::time::Instant::now();
     let mut graph = Graph {
         lines: Vec::new(),
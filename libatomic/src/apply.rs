@@ -430,6 +430,7 @@ fn apply_change_ws_impl<
     workspace: &mut Workspace,
 ) -> Result<(u64, Merkle), ApplyError<P::Error, T>> {
     debug!("apply_change {:?}", hash.to_base32());
+    let _span = phase_span!("apply_change", hash = %hash.to_base32());
     workspace.clear();
     let change = changes.get_change(hash).map_err(ApplyError::Changestore)?;
 
@@ -766,23 +767,47 @@ fn apply_change_to_channel<T: ChannelMutTxnT + TreeTxnT, F: FnMut(&Hash) -> bool
             return Err(LocalApplyError::ChangeAlreadyOnChannel { hash: *hash });
         };
     debug!("apply change to channel");
-    let now = std::time::Instant::now();
-    for (n, change_) in change.changes.iter().enumerate() {
-        debug!("Applying {} {:?} (1)", n, change_);
-        for change_ in change_.iter() {
-            match *change_ {
-                Atom::NewVertex(ref n) => put_newvertex(
-                    txn,
-                    T::graph_mut(channel),
-                    changes,
-                    change,
-                    ws,
-                    change_id,
-                    n,
-                )?,
-                Atom::EdgeMap(ref n) => {
+    {
+        let _span = phase_span!("insert_graph", hunks = change.changes.len());
+        let now = std::time::Instant::now();
+        for (n, change_) in change.changes.iter().enumerate() {
+            debug!("Applying {} {:?} (1)", n, change_);
+            for change_ in change_.iter() {
+                match *change_ {
+                    Atom::NewVertex(ref n) => put_newvertex(
+                        txn,
+                        T::graph_mut(channel),
+                        changes,
+                        change,
+                        ws,
+                        change_id,
+                        n,
+                    )?,
+                    Atom::EdgeMap(ref n) => {
+                        for edge in n.edges.iter() {
+                            if !edge.flag.contains(EdgeFlags::DELETED) {
+                                put_newedge(
+                                    txn,
+                                    T::graph_mut(channel),
+                                    ws,
+                                    change_id,
+                                    n.inode,
+                                    edge,
+                                    |_, _| true,
+                                    |h| change.knows(h),
+                                )?;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        for change_ in change.changes.iter() {
+            debug!("Applying {:?} (2)", change_);
+            for change_ in change_.iter() {
+                if let Atom::EdgeMap(ref n) = *change_ {
                     for edge in n.edges.iter() {
-                        if !edge.flag.contains(EdgeFlags::DELETED) {
+                        if edge.flag.contains(EdgeFlags::DELETED) {
                             put_newedge(
                                 txn,
                                 T::graph_mut(channel),
@@ -798,45 +823,27 @@ fn apply_change_to_channel<T: ChannelMutTxnT + TreeTxnT, F: FnMut(&Hash) -> bool
                 }
             }
         }
+        crate::TIMERS.lock().unwrap().apply += now.elapsed();
     }
-    for change_ in change.changes.iter() {
-        debug!("Applying {:?} (2)", change_);
-        for change_ in change_.iter() {
-            if let Atom::EdgeMap(ref n) = *change_ {
-                for edge in n.edges.iter() {
-                    if edge.flag.contains(EdgeFlags::DELETED) {
-                        put_newedge(
-                            txn,
-                            T::graph_mut(channel),
-                            ws,
-                            change_id,
-                            n.inode,
-                            edge,
-                            |_, _| true,
-                            |h| change.knows(h),
-                        )?;
-                    }
-                }
-            }
+
+    {
+        let _span = phase_span!("repair_context");
+        let mut inodes = clean_obsolete_pseudo_edges(txn, T::graph_mut(channel), ws, change_id)?;
+        collect_missing_contexts(txn, txn.graph(channel), ws, &change, change_id, &mut inodes)?;
+        for i in inodes {
+            repair_zombies(txn, T::graph_mut(channel), i)?;
         }
-    }
-    crate::TIMERS.lock().unwrap().apply += now.elapsed();
 
-    let mut inodes = clean_obsolete_pseudo_edges(txn, T::graph_mut(channel), ws, change_id)?;
-    collect_missing_contexts(txn, txn.graph(channel), ws, &change, change_id, &mut inodes)?;
-    for i in inodes {
-        repair_zombies(txn, T::graph_mut(channel), i)?;
+        detect_folder_conflict_resolutions(
+            txn,
+            T::graph_mut(channel),
+            &mut ws.missing_context,
+            change_id,
+            change,
+        )
+        .map_err(LocalApplyError::from_missing)?;
     }
 
-    detect_folder_conflict_resolutions(
-        txn,
-        T::graph_mut(channel),
-        &mut ws.missing_context,
-        change_id,
-        change,
-    )
-    .map_err(LocalApplyError::from_missing)?;
-
     repair_cyclic_paths(txn, T::graph_mut(channel), ws)?;
     info!("done applying change");
     Ok((n, merkle))
@@ -860,6 +867,7 @@ pub fn apply_local_change_ws<
     inode_updates: &HashMap<usize, InodeUpdate>,
     workspace: &mut Workspace,
 ) -> Result<(u64, Merkle), LocalApplyError<T>> {
+    let _span = phase_span!("apply_local_change", hash = %hash.to_base32());
     let mut channel = channel.write();
     let internal: NodeId = make_changeid(txn, hash)?;
     debug!("make_changeid {:?} {:?}", hash, internal);
@@ -1417,6 +1425,7 @@ pub(crate) fn repair_cyclic_paths<T: GraphMutTxnT + TreeTxnT>(
     channel: &mut T::Graph,
     ws: &mut Workspace,
 ) -> Result<(), LocalApplyError<T>> {
+    let _span = phase_span!("repair_cyclic_paths");
     let now = std::time::Instant::now();
     let mut files = std::mem::replace(&mut ws.missing_context.files, HashSet::default());
     for file in files.drain() {
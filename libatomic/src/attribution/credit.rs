@@ -0,0 +1,213 @@
+//! Per-line attribution of files
+//!
+//! [`credit`] outputs a file like `atomic credit` does, and attributes
+//! each of its lines: the changes that introduced a line are read from
+//! the graph (the `introduced_by` of the edges pointing to its vertex),
+//! and combined with the [`AttributedPatch`] of these changes to tell
+//! whether the line is AI-assisted, which model produced it, and with
+//! which confidence.
+//!
+//! The file is read in a channel, so lines are attributed at the state
+//! of that channel. To attribute a file at an older state, fork the
+//! channel and unrecord the changes after that state first.
+
+use super::{ApplyAttributionContext, ApplyIntegrationConfig, AttributedPatch};
+use crate::changestore::ChangeStore;
+use crate::output::FileError;
+use crate::pristine::{ArcTxn, Base32, ChannelRef, EdgeFlags, Hash, NodeId, Position, Vertex};
+use crate::vertex_buffer::{change_message, VertexBuffer};
+use crate::{HashMap, TxnTExt};
+
+/// Attribution of a line of a file
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineAttribution {
+    /// Contents of the line, without its end of line
+    pub contents: String,
+    /// Changes that introduced the line, empty for conflict markers
+    pub changes: Vec<Hash>,
+    /// Whether one of `changes` is AI-assisted
+    pub ai_assisted: bool,
+    /// Provider of the first AI-assisted change of `changes`
+    pub provider: Option<String>,
+    /// Model of the first AI-assisted change of `changes`
+    pub model: Option<String>,
+    /// Confidence of the first AI-assisted change of `changes`
+    pub confidence: Option<f64>,
+}
+
+impl LineAttribution {
+    fn new(contents: String) -> Self {
+        LineAttribution {
+            contents,
+            changes: Vec::new(),
+            ai_assisted: false,
+            provider: None,
+            model: None,
+            confidence: None,
+        }
+    }
+
+    fn attribute(&mut self, patch: &AttributedPatch) {
+        if self.ai_assisted || !patch.ai_assisted {
+            return;
+        }
+        self.ai_assisted = true;
+        let metadata = patch.ai_metadata.as_ref();
+        self.provider = metadata.map(|m| m.provider.clone());
+        self.model = metadata.map(|m| m.model.clone());
+        self.confidence = patch
+            .confidence
+            .or_else(|| metadata.map(|m| m.acceptance_confidence));
+    }
+}
+
+/// A line of the output, with the vertices it was read from
+struct Line {
+    contents: Vec<u8>,
+    vertices: Vec<Vertex<NodeId>>,
+}
+
+/// Vertex buffer splitting a file into lines
+struct Lines {
+    lines: Vec<Line>,
+    buf: Vec<u8>,
+    new_line: bool,
+}
+
+impl VertexBuffer for Lines {
+    fn output_line<E, C>(&mut self, v: Vertex<NodeId>, c: C) -> Result<(), E>
+    where
+        E: From<std::io::Error>,
+        C: FnOnce(&mut [u8]) -> Result<(), E>,
+    {
+        self.buf.resize(v.end - v.start, 0);
+        c(&mut self.buf)?;
+        for chunk in self.buf.split_inclusive(|&b| b == b'\n') {
+            let contents = chunk.strip_suffix(b"\n").unwrap_or(chunk);
+            match self.lines.last_mut() {
+                Some(line) if !self.new_line => {
+                    line.contents.extend_from_slice(contents);
+                    if !line.vertices.contains(&v) {
+                        line.vertices.push(v)
+                    }
+                }
+                _ => self.lines.push(Line {
+                    contents: contents.to_vec(),
+                    vertices: vec![v],
+                }),
+            }
+            self.new_line = chunk.ends_with(b"\n");
+        }
+        Ok(())
+    }
+
+    fn output_conflict_marker<C: ChangeStore>(
+        &mut self,
+        s: &str,
+        id: usize,
+        sides: Option<(&C, &[&Hash])>,
+    ) -> Result<(), std::io::Error> {
+        let mut marker = format!("{} {}", s, id);
+        if let Some((changes, sides)) = sides {
+            for side in sides {
+                let h = side.to_base32();
+                marker.push_str(&format!(
+                    " [{} {}]",
+                    h.split_at(8).0,
+                    change_message(changes, side)
+                ));
+            }
+        }
+        self.lines.push(Line {
+            contents: marker.into_bytes(),
+            vertices: Vec::new(),
+        });
+        self.new_line = true;
+        Ok(())
+    }
+}
+
+/// Attribute each line of the file at `pos` in `channel`, looking the
+/// attribution of the changes that introduced the lines up with
+/// `attribution`. Each change is looked up once.
+pub fn credit<T, C, F>(
+    changes: &C,
+    txn: &ArcTxn<T>,
+    channel: &ChannelRef<T>,
+    pos: Position<NodeId>,
+    mut attribution: F,
+) -> Result<Vec<LineAttribution>, FileError<C::Error, T>>
+where
+    T: TxnTExt,
+    C: ChangeStore,
+    F: FnMut(&Hash) -> Option<AttributedPatch>,
+{
+    let mut lines = Lines {
+        lines: Vec::new(),
+        buf: Vec::new(),
+        new_line: true,
+    };
+    crate::output::output_file(changes, txn, channel, pos, &mut lines)?;
+
+    let txn = txn.read();
+    let channel = channel.read();
+    let mut patches: HashMap<Hash, Option<AttributedPatch>> = HashMap::default();
+    let mut result = Vec::with_capacity(lines.lines.len());
+    for line in lines.lines {
+        let mut hashes = Vec::new();
+        for v in line.vertices.iter() {
+            if v.change.is_root() {
+                continue;
+            }
+            for e in txn.iter_adjacent(&channel, *v, EdgeFlags::PARENT, EdgeFlags::all())? {
+                let e = e?;
+                if e.introduced_by().is_root() {
+                    continue;
+                }
+                if let Some(intro) = txn.get_external(&e.introduced_by())? {
+                    let hash: Hash = intro.into();
+                    if !hashes.contains(&hash) {
+                        hashes.push(hash)
+                    }
+                }
+            }
+        }
+        let mut credited =
+            LineAttribution::new(String::from_utf8_lossy(&line.contents).into_owned());
+        for hash in hashes.iter() {
+            let patch = patches.entry(*hash).or_insert_with(|| attribution(hash));
+            if let Some(patch) = patch {
+                credited.attribute(patch)
+            }
+        }
+        credited.changes = hashes;
+        result.push(credited);
+    }
+    Ok(result)
+}
+
+/// Like [`credit`], reading the attribution of changes the way they
+/// are attributed when applied: from their metadata, or detected from
+/// their message.
+pub fn credit_from_changes<T, C>(
+    changes: &C,
+    txn: &ArcTxn<T>,
+    channel: &ChannelRef<T>,
+    pos: Position<NodeId>,
+) -> Result<Vec<LineAttribution>, FileError<C::Error, T>>
+where
+    T: TxnTExt,
+    C: ChangeStore,
+{
+    let mut context = ApplyAttributionContext::new(ApplyIntegrationConfig::default());
+    credit(changes, txn, channel, pos, |hash| {
+        let change = match changes.get_change(hash) {
+            Ok(change) => change,
+            Err(e) => {
+                debug!("No attribution for {}: {}", hash.to_base32(), e);
+                return None;
+            }
+        };
+        context.pre_apply_hook(&change, hash).ok().flatten()
+    })
+}
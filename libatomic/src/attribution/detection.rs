@@ -4,7 +4,11 @@
 //! factory pattern, following Atomic's architectural guidelines for configuration-driven
 //! design and robust error handling.
 
-use super::{AIMetadata, AttributedPatch, AuthorInfo, ModelParameters, PatchId, SuggestionType};
+use super::hooks::RegisteredAttribution;
+use super::{
+    AIMetadata, AttributedPatch, AuthorInfo, ModelParameters, PatchId, SerializedAttribution,
+    SuggestionType,
+};
 use crate::pristine::Hash;
 use chrono::Utc;
 use std::collections::HashMap;
@@ -18,6 +22,9 @@ pub struct AttributionDetector {
     config: AttributionConfig,
     /// Cached environment variables
     env_cache: HashMap<String, String>,
+    /// Metadata registered by a tool for this change, see
+    /// [`super::hooks`]
+    registered: Option<RegisteredAttribution>,
 }
 
 /// Configuration for attribution detection
@@ -112,7 +119,20 @@ impl AttributionDetector {
     pub fn new(config: AttributionConfig) -> Self {
         let env_cache = Self::cache_environment_variables();
 
-        Self { config, env_cache }
+        Self {
+            config,
+            env_cache,
+            registered: None,
+        }
+    }
+
+    /// Use the metadata registered by a tool, which takes precedence over
+    /// the environment and the configuration. Registering is an explicit
+    /// opt-in, so it enables attribution unless `ATOMIC_AI_ENABLED` is
+    /// `false`.
+    pub fn with_registered(mut self, registered: Option<RegisteredAttribution>) -> Self {
+        self.registered = registered.filter(|r| !r.is_expired(Utc::now()));
+        self
     }
 
     /// Factory method with default configuration
@@ -168,6 +188,13 @@ impl AttributionDetector {
 
     /// Detect if AI assistance is enabled
     pub fn is_ai_enabled(&self) -> bool {
+        if self.registered.is_some() {
+            return self
+                .env_cache
+                .get(env_vars::ATOMIC_AI_ENABLED)
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(true);
+        }
         if !self.config.enabled {
             return false;
         }
@@ -185,6 +212,26 @@ impl AttributionDetector {
             return None;
         }
 
+        if let Some(ref registered) = self.registered {
+            return Some(AIProviderInfo {
+                provider: registered.provider.clone(),
+                model: registered.model.clone(),
+                suggestion_type: registered.suggestion_type,
+                prompt_hash: None,
+                confidence: registered.confidence.or_else(|| {
+                    self.env_cache
+                        .get(env_vars::ATOMIC_AI_CONFIDENCE)
+                        .and_then(|s| s.parse().ok())
+                }),
+                token_count: registered.token_count.or_else(|| {
+                    self.env_cache
+                        .get(env_vars::ATOMIC_AI_TOKEN_COUNT)
+                        .and_then(|s| s.parse().ok())
+                }),
+                model_params: self.parse_model_parameters(),
+            });
+        }
+
         let provider = self
             .env_cache
             .get(env_vars::ATOMIC_AI_PROVIDER)
@@ -304,17 +351,33 @@ impl AttributionDetector {
         }
     }
 
+    /// Attribution metadata to embed in the change being recorded, if it
+    /// is AI-assisted
+    pub fn serialized_attribution(&self) -> Option<SerializedAttribution> {
+        let ai = self.detect_ai_provider()?;
+        let confidence = ai.confidence.unwrap_or(0.8);
+        Some(SerializedAttribution {
+            author: None, // Will be filled from change author
+            ai_assisted: true,
+            ai_metadata: Some(AIMetadata {
+                provider: ai.provider,
+                model: ai.model,
+                prompt_hash: ai.prompt_hash.unwrap_or(Hash::NONE),
+                suggestion_type: ai.suggestion_type,
+                human_review_time: None,
+                acceptance_confidence: confidence,
+                generation_timestamp: Utc::now(),
+                token_count: ai.token_count,
+                model_params: ai.model_params,
+            }),
+            confidence: Some(confidence),
+            attribution_version: 1,
+        })
+    }
+
     /// Parse suggestion type from string
     fn parse_suggestion_type(&self, s: &str) -> Option<SuggestionType> {
-        match s.to_lowercase().as_str() {
-            "complete" => Some(SuggestionType::Complete),
-            "partial" => Some(SuggestionType::Partial),
-            "collaborative" => Some(SuggestionType::Collaborative),
-            "inspired" => Some(SuggestionType::Inspired),
-            "review" => Some(SuggestionType::Review),
-            "refactor" => Some(SuggestionType::Refactor),
-            _ => None,
-        }
+        parse_suggestion_type(s)
     }
 
     /// Parse hash from string (placeholder implementation)
@@ -376,6 +439,19 @@ impl AttributionDetector {
     }
 }
 
+/// Parse a suggestion type, case-insensitively
+pub(crate) fn parse_suggestion_type(s: &str) -> Option<SuggestionType> {
+    match s.to_lowercase().as_str() {
+        "complete" => Some(SuggestionType::Complete),
+        "partial" => Some(SuggestionType::Partial),
+        "collaborative" => Some(SuggestionType::Collaborative),
+        "inspired" => Some(SuggestionType::Inspired),
+        "review" => Some(SuggestionType::Review),
+        "refactor" => Some(SuggestionType::Refactor),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -438,6 +514,47 @@ mod tests {
         assert_eq!(detector.parse_suggestion_type("invalid"), None);
     }
 
+    #[test]
+    fn test_registered_precedence() {
+        // Disabled in the configuration, but registered by a tool.
+        let detector = AttributionDetector::from_config_values(
+            false,
+            "config-provider".to_string(),
+            "config-model".to_string(),
+            true,
+            false,
+        )
+        .with_registered(Some(RegisteredAttribution {
+            provider: "registered-provider".to_string(),
+            model: "registered-model".to_string(),
+            suggestion_type: SuggestionType::Review,
+            confidence: Some(0.5),
+            token_count: None,
+            tool: None,
+            registered: Utc::now(),
+            expires: Utc::now() + chrono::Duration::minutes(1),
+        }));
+        let attribution = detector.serialized_attribution().unwrap();
+        let ai = attribution.ai_metadata.unwrap();
+        assert_eq!(ai.provider, "registered-provider");
+        assert_eq!(ai.suggestion_type, SuggestionType::Review);
+        assert_eq!(attribution.confidence, Some(0.5));
+
+        // Expired registrations are ignored.
+        let detector =
+            AttributionDetector::with_defaults().with_registered(Some(RegisteredAttribution {
+                provider: "registered-provider".to_string(),
+                model: "registered-model".to_string(),
+                suggestion_type: SuggestionType::Complete,
+                confidence: None,
+                token_count: None,
+                tool: None,
+                registered: Utc::now() - chrono::Duration::minutes(2),
+                expires: Utc::now() - chrono::Duration::minutes(1),
+            }));
+        assert!(detector.serialized_attribution().is_none());
+    }
+
     #[test]
     fn test_config_integration() {
         let detector = AttributionDetector::from_config_values(
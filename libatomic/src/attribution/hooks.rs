@@ -0,0 +1,299 @@
+//! Attribution registered by agent toolchains
+//!
+//! Coding agents know which model wrote the code they hand over, but they
+//! rarely run `atomic record` themselves, let alone with the right
+//! `--ai-*` flags. They can instead register the AI metadata of the next
+//! recorded change with the attribution daemon (`atomic attribution
+//! serve`), which listens on a unix socket in `.atomic` and answers one
+//! JSON [`HookRequest`] per line with one JSON [`HookResponse`] per line:
+//!
+//! ```text
+//! {"op":"register","provider":"anthropic","model":"claude-sonnet","tool":"my-agent"}
+//! {"status":"ok","pending":{"provider":"anthropic",...}}
+//! ```
+//!
+//! The registration is kept in `.atomic/attribution-hook`, and consumed
+//! by the next `atomic record` through
+//! [`AttributionDetector::with_registered`](super::AttributionDetector::with_registered).
+//! From the highest precedence down: the `--ai-*` flags of `record`, the
+//! registration, the `ATOMIC_AI_*` environment variables, and the
+//! configured defaults. Registrations expire after their time to live
+//! ([`DEFAULT_TTL`] by default), so that an agent that crashed before
+//! the record doesn't attribute a later, unrelated change.
+
+use super::{AttributionError, SuggestionType};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Time to live of registrations, in seconds, unless they set their own
+pub const DEFAULT_TTL: u64 = 10 * 60;
+/// Longest time to live of a registration, in seconds
+pub const MAX_TTL: u64 = 24 * 60 * 60;
+
+/// AI metadata of the next recorded change, as sent by a tool
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Registration {
+    pub provider: String,
+    pub model: String,
+    /// `complete` (the default), `partial`, `collaborative`, `inspired`,
+    /// `review` or `refactor`
+    #[serde(default)]
+    pub suggestion_type: Option<String>,
+    #[serde(default)]
+    pub confidence: Option<f64>,
+    #[serde(default)]
+    pub token_count: Option<u32>,
+    /// Name of the tool registering the metadata
+    #[serde(default)]
+    pub tool: Option<String>,
+    /// Time to live, in seconds
+    #[serde(default)]
+    pub ttl: Option<u64>,
+}
+
+/// A registration waiting for the next recorded change
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegisteredAttribution {
+    pub provider: String,
+    pub model: String,
+    pub suggestion_type: SuggestionType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_count: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool: Option<String>,
+    pub registered: DateTime<Utc>,
+    pub expires: DateTime<Utc>,
+}
+
+impl RegisteredAttribution {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        now >= self.expires
+    }
+}
+
+/// A request to the attribution daemon
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum HookRequest {
+    /// Register the metadata of the next recorded change, replacing the
+    /// previous registration
+    Register(Registration),
+    /// Show the pending registration
+    Status,
+    /// Drop the pending registration
+    Clear,
+}
+
+/// The answer of the attribution daemon
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum HookResponse {
+    Ok {
+        pending: Option<RegisteredAttribution>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// The pending registration of a repository
+#[derive(Debug, Clone)]
+pub struct AttributionHooks {
+    path: PathBuf,
+}
+
+impl AttributionHooks {
+    /// Registration stored in `path` (usually `.atomic/attribution-hook`)
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        AttributionHooks { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read(&self) -> Result<Option<RegisteredAttribution>, AttributionError> {
+        match std::fs::read(&self.path) {
+            Ok(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Register `registration`, replacing the previous one
+    pub fn register(
+        &self,
+        registration: Registration,
+        now: DateTime<Utc>,
+    ) -> Result<RegisteredAttribution, AttributionError> {
+        let invalid = |m: &str| Err(AttributionError::InvalidRegistration(m.to_string()));
+        if registration.provider.trim().is_empty() || registration.model.trim().is_empty() {
+            return invalid("provider and model are required");
+        }
+        if registration
+            .confidence
+            .map_or(false, |c| !(0.0..=1.0).contains(&c))
+        {
+            return invalid("confidence must be between 0 and 1");
+        }
+        let suggestion_type = match registration.suggestion_type {
+            Some(ref s) => match super::detection::parse_suggestion_type(s) {
+                Some(t) => t,
+                None => return invalid(&format!("unknown suggestion type {:?}", s)),
+            },
+            None => SuggestionType::Complete,
+        };
+        let ttl = registration.ttl.unwrap_or(DEFAULT_TTL);
+        if ttl == 0 || ttl > MAX_TTL {
+            return invalid(&format!("ttl must be between 1 and {} seconds", MAX_TTL));
+        }
+        let registered = RegisteredAttribution {
+            provider: registration.provider,
+            model: registration.model,
+            suggestion_type,
+            confidence: registration.confidence,
+            token_count: registration.token_count,
+            tool: registration.tool,
+            registered: now,
+            expires: now + Duration::seconds(ttl as i64),
+        };
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&registered)?)?;
+        std::fs::rename(&tmp, &self.path)?;
+        Ok(registered)
+    }
+
+    /// The pending registration, unless it expired
+    pub fn pending(
+        &self,
+        now: DateTime<Utc>,
+    ) -> Result<Option<RegisteredAttribution>, AttributionError> {
+        match self.read()? {
+            Some(r) if r.is_expired(now) => {
+                self.clear()?;
+                Ok(None)
+            }
+            r => Ok(r),
+        }
+    }
+
+    /// Remove `registered` once it has been used, unless a tool replaced
+    /// it in the meantime
+    pub fn consume(&self, registered: &RegisteredAttribution) -> Result<(), AttributionError> {
+        if self.read()?.as_ref() == Some(registered) {
+            self.clear()?;
+        }
+        Ok(())
+    }
+
+    pub fn clear(&self) -> Result<(), AttributionError> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Answer a request of the daemon protocol
+    pub fn handle(&self, request: HookRequest, now: DateTime<Utc>) -> HookResponse {
+        let result = match request {
+            HookRequest::Register(registration) => self.register(registration, now).map(Some),
+            HookRequest::Status => self.pending(now),
+            HookRequest::Clear => self.clear().map(|_| None),
+        };
+        match result {
+            Ok(pending) => HookResponse::Ok { pending },
+            Err(e) => HookResponse::Error {
+                message: e.to_string(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn registration(ttl: Option<u64>) -> Registration {
+        Registration {
+            provider: "anthropic".to_string(),
+            model: "claude".to_string(),
+            suggestion_type: Some("partial".to_string()),
+            tool: Some("agent".to_string()),
+            ttl,
+            ..Registration::default()
+        }
+    }
+
+    #[test]
+    fn test_register_and_expire() {
+        let tmp = tempfile::tempdir().unwrap();
+        let hooks = AttributionHooks::new(tmp.path().join("attribution-hook"));
+        let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        assert_eq!(hooks.pending(now).unwrap(), None);
+
+        let registered = hooks.register(registration(Some(60)), now).unwrap();
+        assert_eq!(registered.suggestion_type, SuggestionType::Partial);
+        assert_eq!(
+            hooks.pending(now + Duration::seconds(59)).unwrap(),
+            Some(registered.clone())
+        );
+        assert_eq!(hooks.pending(now + Duration::seconds(60)).unwrap(), None);
+        assert!(!hooks.path().exists());
+
+        // A registration replaced before the record isn't consumed.
+        let first = hooks.register(registration(None), now).unwrap();
+        let second = hooks
+            .register(registration(None), now + Duration::seconds(1))
+            .unwrap();
+        hooks.consume(&first).unwrap();
+        assert_eq!(hooks.pending(now).unwrap(), Some(second.clone()));
+        hooks.consume(&second).unwrap();
+        assert_eq!(hooks.pending(now).unwrap(), None);
+    }
+
+    #[test]
+    fn test_protocol() {
+        let tmp = tempfile::tempdir().unwrap();
+        let hooks = AttributionHooks::new(tmp.path().join("attribution-hook"));
+        let now = Utc::now();
+        let request: HookRequest = serde_json::from_str(
+            r#"{"op":"register","provider":"openai","model":"gpt-4","confidence":0.9}"#,
+        )
+        .unwrap();
+        let HookResponse::Ok {
+            pending: Some(pending),
+        } = hooks.handle(request, now)
+        else {
+            panic!("registration failed")
+        };
+        assert_eq!(pending.provider, "openai");
+        assert_eq!(pending.expires, now + Duration::seconds(DEFAULT_TTL as i64));
+
+        for invalid in [
+            r#"{"op":"register","provider":"","model":"gpt-4"}"#,
+            r#"{"op":"register","provider":"openai","model":"gpt-4","confidence":2}"#,
+            r#"{"op":"register","provider":"openai","model":"gpt-4","suggestion_type":"x"}"#,
+            r#"{"op":"register","provider":"openai","model":"gpt-4","ttl":0}"#,
+        ] {
+            let request = serde_json::from_str(invalid).unwrap();
+            assert!(
+                matches!(hooks.handle(request, now), HookResponse::Error { .. }),
+                "{}",
+                invalid
+            );
+        }
+
+        assert_eq!(
+            hooks.handle(HookRequest::Clear, now),
+            HookResponse::Ok { pending: None }
+        );
+        assert_eq!(
+            hooks.handle(HookRequest::Status, now),
+            HookResponse::Ok { pending: None }
+        );
+    }
+}
@@ -12,11 +12,14 @@ use std::time::Duration;
 
 // Submodules
 pub mod apply_integration;
+pub mod credit;
 pub mod detection;
+pub mod hooks;
 pub mod remote_integration;
 pub mod sanakirja_impl;
 pub mod sync;
 pub mod tables;
+pub mod tag_summary;
 
 // Re-exports
 pub use apply_integration::{
@@ -24,11 +27,12 @@ pub use apply_integration::{
     SerializedAttribution,
 };
 pub use detection::{env_vars, AIProviderInfo, AttributionContext, AttributionDetector};
+pub use hooks::{AttributionHooks, HookRequest, HookResponse, RegisteredAttribution, Registration};
 pub use sanakirja_impl::AttributionStore as SanakirjaAttributionStore;
 pub use sync::{
     AttributedPatchBundle, AttributionConflictDetector, AttributionProtocol, AttributionRemoteSync,
     AttributionSyncManager, AttributionSyncState, PatchSignature, ProtocolFeature,
-    RemoteAttributionStats, SignatureAlgorithm,
+    RemoteAttributionStats, SignatureAlgorithm, VerificationLevel, VerificationReport,
 };
 pub use tables::{
     queries, AttributionMutTxnT, AttributionStore, AttributionTxnT, ConflictResolutionStrategy,
@@ -398,6 +402,15 @@ pub enum AttributionError {
 
     #[error("Serialization error: {0}")]
     Serialization(String),
+
+    #[error("Invalid attribution registration: {0}")]
+    InvalidRegistration(String),
+
+    #[error("Invalid attribution configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
 }
 
 impl From<bincode::Error> for AttributionError {
@@ -13,7 +13,10 @@
 //! - Factory pattern for creating attribution-aware remote instances
 //! - Protocol negotiation for attribution capabilities
 
-use super::{sync::AttributedPatchBundle, *};
+use super::{
+    sync::{AttributedPatchBundle, AttributionSyncState, VerificationLevel, VerificationReport},
+    *,
+};
 use crate::pristine::MutTxnT;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
@@ -51,6 +54,9 @@ pub struct RemoteAttributionConfig {
     pub sync_on_pull: bool,
     /// Require signature verification for remote attributions
     pub require_signatures: bool,
+    /// Which signatures of pulled bundles to check
+    #[serde(default)]
+    pub verification: VerificationLevel,
     /// Maximum number of attribution bundles to process in one batch
     pub batch_size: usize,
     /// Timeout for remote attribution operations (in seconds)
@@ -65,6 +71,7 @@ impl Default for RemoteAttributionConfig {
             sync_on_push: true,
             sync_on_pull: true,
             require_signatures: false,
+            verification: VerificationLevel::default(),
             batch_size: 100,
             timeout_seconds: 30,
             fallback_on_unsupported: true,
@@ -106,6 +113,10 @@ impl AttributionRemoteFactory {
             config.require_signatures = value.parse().unwrap_or(false);
         }
 
+        if let Ok(value) = std::env::var("ATOMIC_ATTRIBUTION_VERIFY") {
+            config.verification = value.parse()?;
+        }
+
         if let Ok(value) = std::env::var("ATOMIC_ATTRIBUTION_BATCH_SIZE") {
             config.batch_size = value.parse().unwrap_or(100);
         }
@@ -237,6 +248,26 @@ where
         Ok(processed_ids)
     }
 
+    /// Check the signatures of all the bundles of `channel` on the remote,
+    /// named `remote` in `state`, regardless of the configured
+    /// verification level. Meant for periodic compliance runs, between
+    /// which pulls only check new bundles.
+    pub async fn full_audit(
+        &mut self,
+        remote: &str,
+        channel: &str,
+        state: &mut AttributionSyncState,
+    ) -> Result<VerificationReport, RemoteAttributionError> {
+        let bundles = self
+            .inner
+            .pull_attributed_patches(0, channel)
+            .await
+            .map_err(|e| RemoteAttributionError::SyncFailed {
+                reason: e.to_string(),
+            })?;
+        Ok(state.full_audit(remote, &bundles, chrono::Utc::now().timestamp() as u64))
+    }
+
     /// Extract attribution metadata for a change
     fn extract_attribution_for_change<T: AttributionTxnT>(
         &self,
@@ -469,6 +500,7 @@ mod tests {
         assert!(config.sync_on_push);
         assert!(config.sync_on_pull);
         assert!(!config.require_signatures);
+        assert_eq!(config.verification, VerificationLevel::NewOnly);
         assert_eq!(config.batch_size, 100);
         assert_eq!(config.timeout_seconds, 30);
         assert!(config.fallback_on_unsupported);
@@ -479,14 +511,17 @@ mod tests {
         // Set environment variables
         std::env::set_var("ATOMIC_ATTRIBUTION_SYNC_PUSH", "false");
         std::env::set_var("ATOMIC_ATTRIBUTION_BATCH_SIZE", "50");
+        std::env::set_var("ATOMIC_ATTRIBUTION_VERIFY", "full-audit");
 
         let factory = AttributionRemoteFactory::from_environment().unwrap();
         assert!(!factory.config.sync_on_push);
         assert_eq!(factory.config.batch_size, 50);
+        assert_eq!(factory.config.verification, VerificationLevel::FullAudit);
 
         // Clean up
         std::env::remove_var("ATOMIC_ATTRIBUTION_SYNC_PUSH");
         std::env::remove_var("ATOMIC_ATTRIBUTION_BATCH_SIZE");
+        std::env::remove_var("ATOMIC_ATTRIBUTION_VERIFY");
     }
 
     #[test]
@@ -68,6 +68,95 @@ pub enum SignatureAlgorithm {
     RSA4096,
 }
 
+impl AttributedPatchBundle {
+    /// The bytes covered by the signature of this bundle: the patch
+    /// data, followed by the fields of the attribution that don't depend
+    /// on the order of sets.
+    pub fn signed_message(&self) -> Vec<u8> {
+        let mut msg = self.patch_data.clone();
+        let a = &self.attribution;
+        bincode::serialize_into(
+            &mut msg,
+            &(
+                &a.patch_id,
+                &a.author.id,
+                a.timestamp.timestamp_millis(),
+                a.ai_assisted,
+                &a.description,
+            ),
+        )
+        .unwrap();
+        msg
+    }
+
+    /// Check the signature of this bundle, `None` if it isn't signed.
+    /// Only Ed25519 signatures can be checked, other algorithms are
+    /// rejected.
+    pub fn verify_signature(&self) -> Option<bool> {
+        let sig = self.signature.as_ref()?;
+        Some(match sig.algorithm {
+            SignatureAlgorithm::Ed25519 => {
+                let key = ed25519_dalek::PublicKey::from_bytes(&sig.public_key);
+                let signature = ed25519_dalek::Signature::from_bytes(&sig.signature);
+                match (key, signature) {
+                    (Ok(key), Ok(signature)) => key
+                        .verify_strict(&self.signed_message(), &signature)
+                        .is_ok(),
+                    _ => false,
+                }
+            }
+            SignatureAlgorithm::RSA2048 | SignatureAlgorithm::RSA4096 => false,
+        })
+    }
+}
+
+/// How much of the signatures of pulled bundles to check
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum VerificationLevel {
+    /// Don't check signatures
+    None,
+    /// Only check the bundles that weren't verified in a previous sync
+    /// with the same remote
+    NewOnly,
+    /// Check every bundle
+    FullAudit,
+}
+
+impl Default for VerificationLevel {
+    fn default() -> Self {
+        VerificationLevel::NewOnly
+    }
+}
+
+impl std::str::FromStr for VerificationLevel {
+    type Err = AttributionError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(VerificationLevel::None),
+            "new-only" => Ok(VerificationLevel::NewOnly),
+            "full-audit" => Ok(VerificationLevel::FullAudit),
+            _ => Err(AttributionError::InvalidConfig(format!(
+                "Unknown verification level {:?}, expected none, new-only or full-audit",
+                s
+            ))),
+        }
+    }
+}
+
+/// Outcome of the verification of a batch of bundles
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct VerificationReport {
+    /// Bundles whose signature was checked and valid
+    pub verified: usize,
+    /// Bundles not checked, because of the verification level
+    pub skipped: usize,
+    /// Bundles without a signature
+    pub unsigned: usize,
+    /// Bundles with an invalid signature
+    pub failed: Vec<PatchId>,
+}
+
 /// Statistics about attribution in a remote repository
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RemoteAttributionStats {
@@ -122,20 +211,46 @@ impl<T: AttributionTxnT> AttributionSyncManager<T> {
         Ok(bundles)
     }
 
-    /// Process pulled bundles and store attribution
+    /// Process pulled bundles and store attribution, checking all the
+    /// signatures
     pub fn process_pull_bundles<M: AttributionMutTxnT>(
         &mut self,
         txn: &mut M,
         bundles: Vec<AttributedPatchBundle>,
     ) -> Result<Vec<PatchId>, TxnErr<<M as crate::pristine::GraphTxnT>::GraphError>> {
+        let mut state = AttributionSyncState::new();
+        let (processed_ids, _) = self.process_pull_bundles_from(
+            txn,
+            "",
+            bundles,
+            VerificationLevel::FullAudit,
+            &mut state,
+        )?;
+        Ok(processed_ids)
+    }
+
+    /// Process bundles pulled from `remote` and store attribution,
+    /// checking signatures according to `level`. The verifications are
+    /// recorded in `state`, so that the next sync with the same remote
+    /// at level [`VerificationLevel::NewOnly`] only checks new bundles.
+    /// Bundles with invalid signatures are skipped.
+    pub fn process_pull_bundles_from<M: AttributionMutTxnT>(
+        &mut self,
+        txn: &mut M,
+        remote: &str,
+        bundles: Vec<AttributedPatchBundle>,
+        level: VerificationLevel,
+        state: &mut AttributionSyncState,
+    ) -> Result<
+        (Vec<PatchId>, VerificationReport),
+        TxnErr<<M as crate::pristine::GraphTxnT>::GraphError>,
+    > {
+        let report = state.verify_bundles(remote, &bundles, level);
         let mut processed_ids = Vec::new();
 
         for bundle in bundles {
-            // Verify signature if present
-            if let Some(sig) = &bundle.signature {
-                if !self.verify_signature(&bundle, sig) {
-                    continue; // Skip patches with invalid signatures
-                }
+            if report.failed.contains(&bundle.attribution.patch_id) {
+                continue; // Skip patches with invalid signatures
             }
 
             // Store attribution
@@ -156,23 +271,7 @@ impl<T: AttributionTxnT> AttributionSyncManager<T> {
             processed_ids.push(bundle.attribution.patch_id);
         }
 
-        Ok(processed_ids)
-    }
-
-    /// Verify a patch signature
-    fn verify_signature(&self, _bundle: &AttributedPatchBundle, sig: &PatchSignature) -> bool {
-        // Placeholder - would implement actual signature verification
-        // using the specified algorithm
-        match sig.algorithm {
-            SignatureAlgorithm::Ed25519 => {
-                // Verify Ed25519 signature
-                true
-            }
-            SignatureAlgorithm::RSA2048 | SignatureAlgorithm::RSA4096 => {
-                // Verify RSA signature
-                true
-            }
-        }
+        Ok((processed_ids, report))
     }
 
     /// Merge attribution from multiple sources
@@ -224,6 +323,12 @@ pub struct AttributionSyncState {
     pub pending_push: HashSet<PatchId>,
     /// Protocol versions supported by remotes
     pub remote_versions: HashMap<String, u32>,
+    /// Patches whose signature was verified, for each remote
+    #[serde(default)]
+    pub verified: HashMap<String, HashSet<PatchId>>,
+    /// Timestamp of the last full audit of each remote
+    #[serde(default)]
+    pub last_full_audit: HashMap<String, u64>,
 }
 
 impl AttributionSyncState {
@@ -233,6 +338,8 @@ impl AttributionSyncState {
             last_sync_time: HashMap::new(),
             pending_push: HashSet::new(),
             remote_versions: HashMap::new(),
+            verified: HashMap::new(),
+            last_full_audit: HashMap::new(),
         }
     }
 
@@ -253,6 +360,66 @@ impl AttributionSyncState {
             .map(|last| last >= patch_id)
             .unwrap_or(false)
     }
+
+    pub fn is_verified(&self, remote: &str, patch_id: &PatchId) -> bool {
+        self.verified
+            .get(remote)
+            .map_or(false, |v| v.contains(patch_id))
+    }
+
+    /// Check the signatures of `bundles`, pulled from `remote`, according
+    /// to `level`, and record the valid ones.
+    pub fn verify_bundles(
+        &mut self,
+        remote: &str,
+        bundles: &[AttributedPatchBundle],
+        level: VerificationLevel,
+    ) -> VerificationReport {
+        let mut report = VerificationReport::default();
+        if level == VerificationLevel::None {
+            report.skipped = bundles.len();
+            return report;
+        }
+        let verified = self.verified.entry(remote.to_string()).or_default();
+        for bundle in bundles {
+            let id = bundle.attribution.patch_id;
+            if level == VerificationLevel::NewOnly && verified.contains(&id) {
+                report.skipped += 1;
+                continue;
+            }
+            match bundle.verify_signature() {
+                None => {
+                    verified.remove(&id);
+                    report.unsigned += 1
+                }
+                Some(true) => {
+                    verified.insert(id);
+                    report.verified += 1
+                }
+                Some(false) => {
+                    verified.remove(&id);
+                    report.failed.push(id)
+                }
+            }
+        }
+        report
+    }
+
+    /// Check the signatures of all of `bundles`, regardless of previous
+    /// verifications, e.g. for periodic compliance runs. `bundles` should
+    /// be all the bundles of `remote`: the verifications of patches that
+    /// aren't in `bundles` are forgotten.
+    pub fn full_audit(
+        &mut self,
+        remote: &str,
+        bundles: &[AttributedPatchBundle],
+        now: u64,
+    ) -> VerificationReport {
+        self.verified.remove(remote);
+        let report = self.verify_bundles(remote, bundles, VerificationLevel::FullAudit);
+        self.last_full_audit.insert(remote.to_string(), now);
+        report
+    }
 }
 
 /// Conflict detection for attribution during sync
@@ -390,6 +557,9 @@ pub enum ProtocolFeature {
     Compression,
     /// Support for incremental sync
     IncrementalSync,
+    /// Exchange of attribution bundles alongside the changes pushed and
+    /// pulled
+    Attribution,
 }
 
 impl AttributionProtocol {
@@ -400,6 +570,7 @@ impl AttributionProtocol {
         if version >= 1 {
             features.insert(ProtocolFeature::AIMetadata);
             features.insert(ProtocolFeature::DependencyWeights);
+            features.insert(ProtocolFeature::Attribution);
         }
 
         // Version 2 features
@@ -446,11 +617,83 @@ mod tests {
         assert_eq!(negotiated.version, 2);
         assert!(negotiated.features.contains(&ProtocolFeature::AIMetadata));
         assert!(negotiated.features.contains(&ProtocolFeature::Signatures));
+        assert!(negotiated.features.contains(&ProtocolFeature::Attribution));
         assert!(!negotiated
             .features
             .contains(&ProtocolFeature::IncrementalSync));
     }
 
+    fn bundle(n: u64, keypair: Option<&ed25519_dalek::Keypair>) -> AttributedPatchBundle {
+        use ed25519_dalek::Signer;
+        let mut bundle = AttributedPatchBundle {
+            patch_data: vec![n as u8; 4],
+            attribution: AttributedPatch {
+                patch_id: PatchId::new(NodeId(L64(n))),
+                author: AuthorInfo {
+                    id: AuthorId::new(0),
+                    name: "Test User".to_string(),
+                    email: "test@example.com".to_string(),
+                    is_ai: false,
+                },
+                timestamp: chrono::Utc::now(),
+                ai_assisted: false,
+                ai_metadata: None,
+                dependencies: HashSet::new(),
+                conflicts_with: HashSet::new(),
+                description: format!("Patch {}", n),
+                confidence: None,
+            },
+            signature: None,
+        };
+        if let Some(keypair) = keypair {
+            bundle.signature = Some(PatchSignature {
+                public_key: keypair.public.to_bytes().to_vec(),
+                signature: keypair.sign(&bundle.signed_message()).to_bytes().to_vec(),
+                algorithm: SignatureAlgorithm::Ed25519,
+            })
+        }
+        bundle
+    }
+
+    #[test]
+    fn test_verification_levels() {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[7; 32]).unwrap();
+        let public = ed25519_dalek::PublicKey::from(&secret);
+        let keypair = ed25519_dalek::Keypair { secret, public };
+
+        let mut forged = bundle(3, Some(&keypair));
+        forged.patch_data = vec![0; 4];
+        let bundles = vec![bundle(1, Some(&keypair)), bundle(2, None), forged];
+        assert_eq!(bundles[0].verify_signature(), Some(true));
+        assert_eq!(
+            "new-only".parse::<VerificationLevel>().unwrap(),
+            VerificationLevel::NewOnly
+        );
+        assert!("some".parse::<VerificationLevel>().is_err());
+
+        let mut state = AttributionSyncState::new();
+        let report = state.verify_bundles("origin", &bundles, VerificationLevel::None);
+        assert_eq!(report.skipped, 3);
+
+        let report = state.verify_bundles("origin", &bundles, VerificationLevel::NewOnly);
+        assert_eq!(report.verified, 1);
+        assert_eq!(report.unsigned, 1);
+        assert_eq!(report.failed, vec![bundles[2].attribution.patch_id]);
+        assert!(state.is_verified("origin", &bundles[0].attribution.patch_id));
+        assert!(!state.is_verified("mirror", &bundles[0].attribution.patch_id));
+
+        // Only the bundle verified before is skipped.
+        let report = state.verify_bundles("origin", &bundles, VerificationLevel::NewOnly);
+        assert_eq!(report.skipped, 1);
+        assert_eq!(report.failed.len(), 1);
+
+        let report = state.full_audit("origin", &bundles[1..], 1_700_000_000);
+        assert_eq!(report.verified, 0);
+        assert_eq!(report.failed.len(), 1);
+        assert!(!state.is_verified("origin", &bundles[0].attribution.patch_id));
+        assert_eq!(state.last_full_audit.get("origin"), Some(&1_700_000_000));
+    }
+
     #[test]
     fn test_sync_state() {
         let mut state = AttributionSyncState::new();
@@ -243,6 +243,110 @@ pub mod queries {
         // Implementation would use actual database operations
         todo!("Implement using database operations")
     }
+
+    /// A page of patches, in increasing order of [`PatchId`]
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct PatchPage {
+        pub patches: Vec<PatchId>,
+        /// Cursor to pass to get the next page, `None` on the last page
+        pub next: Option<PatchId>,
+    }
+
+    /// Patches of an author, in increasing order of [`PatchId`]
+    pub fn iter_patches_by_author<T: AttributionTxnT>(
+        txn: &T,
+        author_id: &AuthorId,
+    ) -> Result<impl Iterator<Item = PatchId>, TxnErr<<T as crate::pristine::GraphTxnT>::GraphError>>
+    {
+        let mut patches = txn.get_author_patches(author_id)?;
+        patches.sort_unstable();
+        patches.dedup();
+        Ok(patches.into_iter())
+    }
+
+    /// AI-assisted patches generated by `provider`, in increasing order of
+    /// [`PatchId`]. The AI metadata of each patch is only read when the
+    /// iterator reaches it.
+    pub fn iter_ai_patches_by_provider<'a, T: AttributionTxnT>(
+        txn: &'a T,
+        provider: &'a str,
+    ) -> Result<
+        impl Iterator<Item = Result<PatchId, TxnErr<<T as crate::pristine::GraphTxnT>::GraphError>>>
+            + 'a,
+        TxnErr<<T as crate::pristine::GraphTxnT>::GraphError>,
+    > {
+        let mut patches = txn.iter_ai_patches()?;
+        patches.sort_unstable();
+        patches.dedup();
+        Ok(patches
+            .into_iter()
+            .filter_map(move |patch_id| match txn.get_ai_metadata(&patch_id) {
+                Ok(Some(metadata)) if metadata.provider == provider => Some(Ok(patch_id)),
+                Ok(_) => None,
+                Err(e) => Some(Err(e)),
+            }))
+    }
+
+    /// The page of at most `limit` patches of `patches` (in increasing
+    /// order of [`PatchId`]) following the `after` cursor, or the first
+    /// page if `after` is `None`.
+    pub fn paginate<E, I: IntoIterator<Item = Result<PatchId, E>>>(
+        patches: I,
+        after: Option<PatchId>,
+        limit: usize,
+    ) -> Result<PatchPage, E> {
+        let mut page = PatchPage::default();
+        for patch_id in patches {
+            let patch_id = patch_id?;
+            if after.map_or(false, |after| patch_id <= after) {
+                continue;
+            }
+            if page.patches.len() >= limit {
+                page.next = page.patches.last().copied();
+                break;
+            }
+            page.patches.push(patch_id)
+        }
+        Ok(page)
+    }
+
+    /// A page of the AI-assisted patches applied to `channel`, optionally
+    /// restricted to those generated by `provider`. Membership is tested
+    /// against the changeset of the channel, so that listing the AI
+    /// patches of a channel doesn't scan its log.
+    pub fn ai_patches_in_channel<T: AttributionTxnT>(
+        txn: &T,
+        channel: &T::Channel,
+        provider: Option<&str>,
+        after: Option<PatchId>,
+        limit: usize,
+    ) -> Result<PatchPage, TxnErr<<T as crate::pristine::GraphTxnT>::GraphError>> {
+        let changes = txn.changes(channel);
+        let in_channel = |patch_id: Result<PatchId, _>| match patch_id {
+            Ok(patch_id) => match txn.get_changeset(changes, &patch_id.0) {
+                Ok(Some(_)) => Some(Ok(patch_id)),
+                Ok(None) => None,
+                Err(e) => Some(Err(e)),
+            },
+            Err(e) => Some(Err(e)),
+        };
+        if let Some(provider) = provider {
+            paginate(
+                iter_ai_patches_by_provider(txn, provider)?.filter_map(in_channel),
+                after,
+                limit,
+            )
+        } else {
+            let mut patches = txn.iter_ai_patches()?;
+            patches.sort_unstable();
+            patches.dedup();
+            paginate(
+                patches.into_iter().map(Ok).filter_map(in_channel),
+                after,
+                limit,
+            )
+        }
+    }
 }
 
 /// Conflict resolution strategies based on attribution
@@ -340,6 +444,32 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_paginate() {
+        use crate::pristine::NodeId;
+        let patches: Vec<PatchId> = (1..=5).map(|n| PatchId::new(NodeId(L64(n)))).collect();
+        let all = || patches.iter().copied().map(Ok::<_, ()>);
+
+        let first = queries::paginate(all(), None, 2).unwrap();
+        assert_eq!(first.patches, &patches[..2]);
+        assert_eq!(first.next, Some(patches[1]));
+
+        let second = queries::paginate(all(), first.next, 2).unwrap();
+        assert_eq!(second.patches, &patches[2..4]);
+
+        let last = queries::paginate(all(), second.next, 2).unwrap();
+        assert_eq!(last.patches, &patches[4..]);
+        assert_eq!(last.next, None);
+
+        // A full last page has no next page either.
+        let exact = queries::paginate(all(), None, 5).unwrap();
+        assert_eq!(exact.patches.len(), 5);
+        assert_eq!(exact.next, None);
+
+        let failing = vec![Ok(patches[0]), Err("broken")];
+        assert_eq!(queries::paginate(failing, None, 10), Err("broken"));
+    }
+
     #[test]
     fn test_attribution_store_type_parameter() {
         // Test that AttributionStore can be parameterized with different types
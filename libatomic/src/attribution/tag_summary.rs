@@ -0,0 +1,115 @@
+//! Attribution statistics of consolidating tags
+//!
+//! When a consolidating tag is created, the attribution of the changes it
+//! consolidates is aggregated into a [`TagAttributionSummary`], stored in
+//! the tag attribution table and copied into the tag's metadata, so that
+//! the AI involvement of a release can be reported without loading its
+//! changes again.
+
+use super::SerializedAttribution;
+use crate::changestore::ChangeStore;
+use crate::pristine::{
+    Base32, Hash, ProviderStats, SerializedTagAttributionSummary, Tag, TagAttributionSummary,
+    TagMetadataMutTxnT, TxnErr,
+};
+use log::debug;
+
+/// Confidence above which an AI suggestion counts as high-confidence
+const HIGH_CONFIDENCE: f64 = 0.8;
+/// Confidence above which an AI suggestion counts as medium-confidence
+const MEDIUM_CONFIDENCE: f64 = 0.5;
+
+/// Aggregate the attribution of `hashes`, read from the metadata of the
+/// changes in `changes`. Changes without attribution metadata count as
+/// human-authored; changes that can't be loaded are only counted in
+/// `total_changes`.
+pub fn summarize<C: ChangeStore>(
+    changes: &C,
+    tag_hash: Hash,
+    hashes: &[Hash],
+) -> TagAttributionSummary {
+    let mut summary = TagAttributionSummary::new(tag_hash);
+    summary.total_changes = hashes.len() as u64;
+    let mut confidence_sum = 0.0;
+    for hash in hashes {
+        let change = match changes.get_change(hash) {
+            Ok(change) => change,
+            Err(e) => {
+                debug!("No attribution for {}: {}", hash.to_base32(), e);
+                continue;
+            }
+        };
+        let timestamp = change.hashed.header.timestamp.timestamp().max(0) as u64;
+        if summary.creation_time_span == (0, 0) {
+            summary.creation_time_span = (timestamp, timestamp);
+        } else {
+            summary.creation_time_span.0 = summary.creation_time_span.0.min(timestamp);
+            summary.creation_time_span.1 = summary.creation_time_span.1.max(timestamp);
+        }
+
+        let attribution = if change.hashed.metadata.is_empty() {
+            None
+        } else {
+            bincode::deserialize::<SerializedAttribution>(&change.hashed.metadata).ok()
+        };
+        let attribution = match attribution {
+            Some(a) if a.ai_assisted => a,
+            _ => {
+                summary.human_authored_changes += 1;
+                continue;
+            }
+        };
+        summary.ai_assisted_changes += 1;
+
+        let confidence = attribution
+            .confidence
+            .or_else(|| {
+                attribution
+                    .ai_metadata
+                    .as_ref()
+                    .map(|m| m.acceptance_confidence)
+            })
+            .unwrap_or(0.0);
+        confidence_sum += confidence;
+        if confidence >= HIGH_CONFIDENCE {
+            summary.confidence_high += 1
+        } else if confidence >= MEDIUM_CONFIDENCE {
+            summary.confidence_medium += 1
+        } else {
+            summary.confidence_low += 1
+        }
+
+        if let Some(ref m) = attribution.ai_metadata {
+            summary
+                .ai_provider_stats
+                .entry(m.provider.clone())
+                .or_insert_with(ProviderStats::new)
+                .add_change(
+                    confidence as f32,
+                    m.model.clone(),
+                    format!("{:?}", m.suggestion_type),
+                );
+        }
+    }
+    if summary.ai_assisted_changes > 0 {
+        summary.average_confidence = (confidence_sum / summary.ai_assisted_changes as f64) as f32;
+    }
+    summary
+}
+
+/// Summarize the attribution of the changes consolidated by `tag`, copy
+/// the summary into its metadata, and store it in the tag attribution
+/// table. The tag itself still has to be stored by the caller.
+pub fn attach<C: ChangeStore, T: TagMetadataMutTxnT>(
+    changes: &C,
+    txn: &mut T,
+    tag: &mut Tag,
+) -> Result<TagAttributionSummary, TxnErr<T::TagError>> {
+    let summary = summarize(changes, tag.tag_hash, &tag.consolidated_changes);
+    summary.insert_into_metadata(&mut tag.metadata);
+    txn.put_tag_attribution_summary(
+        &tag.tag_hash,
+        &SerializedTagAttributionSummary::from(summary.clone()),
+    )?;
+    Ok(summary)
+}
@@ -27,6 +27,9 @@ mod change_file;
 #[cfg(feature = "zstd")]
 pub use change_file::*;
 
+#[cfg(feature = "zstd")]
+pub mod stream;
+
 pub mod noenc;
 
 #[derive(Debug, Error)]
@@ -55,6 +58,8 @@ pub enum ChangeError {
     Json(#[from] serde_json::Error),
     #[error("Missing contents for change {:?}", hash)]
     MissingContents { hash: crate::pristine::Hash },
+    #[error("Section of {} bytes in a change, at most {} are accepted", len, max)]
+    SectionTooLarge { len: u64, max: u64 },
     #[error("Change hash mismatch, claimed {:?}, computed {:?}", claimed, computed)]
     ChangeHashMismatch {
         claimed: crate::pristine::Hash,
@@ -171,13 +176,62 @@ impl std::ops::DerefMut for LocalChange<Hunk<Option<Hash>, Local>, Author> {
     }
 }
 
+/// Key of the unhashed section listing the changes a change supersedes,
+/// i.e. the earlier versions it was re-recorded or squashed from.
+pub const SUPERSEDES_KEY: &str = "supersedes";
+
+impl LocalChange<Hunk<Option<Hash>, Local>, Author> {
+    /// The changes this change supersedes, read from its unhashed
+    /// section. Since that section isn't hashed, the same change can
+    /// supersede different changes in different repositories.
+    pub fn supersedes(&self) -> Vec<Hash> {
+        self.unhashed
+            .as_ref()
+            .and_then(|u| u.get(SUPERSEDES_KEY))
+            .and_then(|s| s.as_array())
+            .map(|s| {
+                s.iter()
+                    .filter_map(|h| Hash::from_base32(h.as_str()?.as_bytes()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Set the changes this change supersedes, keeping the rest of the
+    /// unhashed section.
+    pub fn set_supersedes(&mut self, hashes: &[Hash]) {
+        let hashes: Vec<_> = hashes
+            .iter()
+            .map(|h| serde_json::Value::String(h.to_base32()))
+            .collect();
+        match self.unhashed {
+            Some(serde_json::Value::Object(ref mut u)) => {
+                if hashes.is_empty() {
+                    u.remove(SUPERSEDES_KEY);
+                } else {
+                    u.insert(SUPERSEDES_KEY.to_string(), hashes.into());
+                }
+            }
+            _ if hashes.is_empty() => {}
+            _ => {
+                let mut u = serde_json::Map::new();
+                u.insert(SUPERSEDES_KEY.to_string(), hashes.into());
+                self.unhashed = Some(u.into())
+            }
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Author(pub std::collections::BTreeMap<String, String>);
 
 // Beware of changes in the version, tags also use that.
 // VERSION 6: Without tag field
 // VERSION 7: Current version - Added tag field for tag change serialization
+// VERSION 8: Streaming container for VERSION 7 contents, see `stream`
 pub const VERSION: u64 = 7;
+pub const VERSION_V6: u64 = 6;
+pub const VERSION_STREAM: u64 = 8;
 pub const VERSION_NOENC: u64 = 4;
 
 /// Lightweight metadata about a consolidating tag for serialization in change files.
@@ -267,6 +321,27 @@ struct HashedV6<Hunk, Author> {
     pub contents_hash: Hash,
 }
 
+/// Decode the hashed part of a change stored in a container with
+/// offsets (versions 4, 6 and 7).
+fn decode_hashed(
+    version: u64,
+    buf: &[u8],
+) -> Result<Hashed<Hunk<Option<Hash>, Local>, Author>, ChangeError> {
+    match version {
+        VERSION => Ok(bincode::deserialize(buf)?),
+        VERSION_V6 => {
+            let h: HashedV6<Hunk<Option<Hash>, Local>, Author> = bincode::deserialize(buf)?;
+            Ok(h.into())
+        }
+        VERSION_NOENC => {
+            let h: Hashed<noenc::Hunk<Option<Hash>, Local>, noenc::Author> =
+                bincode::deserialize(buf)?;
+            Ok(h.into())
+        }
+        got => Err(ChangeError::VersionMismatch { got }),
+    }
+}
+
 impl<Hunk, Author> From<HashedV6<Hunk, Author>> for Hashed<Hunk, Author> {
     fn from(v6: HashedV6<Hunk, Author>) -> Self {
         Self {
@@ -1843,9 +1918,15 @@ impl Change {
     ) -> Result<u64, ChangeError> {
         let pos = r.seek(std::io::SeekFrom::Current(0))?;
         let mut off = [0u8; Self::OFFSETS_SIZE as usize];
-        r.read_exact(&mut off)?;
+        r.read_exact(&mut off[..8])?;
+        #[cfg(feature = "zstd")]
+        if u64::from_le_bytes(off[..8].try_into().unwrap()) == VERSION_STREAM {
+            r.seek(std::io::SeekFrom::Start(pos))?;
+            return stream::prefix_len(r, stream::Section::Unhashed);
+        }
+        r.read_exact(&mut off[8..])?;
         let off: Offsets = bincode::deserialize(&off)?;
-        if off.version != VERSION && off.version != VERSION_NOENC {
+        if off.version != VERSION && off.version != VERSION_V6 && off.version != VERSION_NOENC {
             return Err(ChangeError::VersionMismatch { got: off.version });
         }
         r.seek(std::io::SeekFrom::Start(pos))?;
@@ -1926,8 +2007,15 @@ impl Change {
     /// Deserialise a change from the file given as input `file`.
     #[cfg(feature = "zstd")]
     pub fn check_from_buffer(buf: &[u8], hash: &Hash) -> Result<(), ChangeError> {
+        if buf.len() >= 8 && buf[..8] == VERSION_STREAM.to_le_bytes() {
+            stream::ChangeStream::open(buf)?.finish(Some(hash))?;
+            return Ok(());
+        }
         let offsets: Offsets = bincode::deserialize_from(&buf[..Self::OFFSETS_SIZE as usize])?;
-        if offsets.version != VERSION && offsets.version != VERSION_NOENC {
+        if offsets.version != VERSION
+            && offsets.version != VERSION_V6
+            && offsets.version != VERSION_NOENC
+        {
             return Err(ChangeError::VersionMismatch {
                 got: offsets.version,
             });
@@ -1953,13 +2041,7 @@ impl Change {
             .into());
         }
 
-        let hashed: Hashed<Hunk<Option<Hash>, Local>, Author> = if offsets.version == VERSION {
-            bincode::deserialize(&buf_)?
-        } else {
-            let h: Hashed<noenc::Hunk<Option<Hash>, Local>, noenc::Author> =
-                bincode::deserialize(&buf_)?;
-            h.into()
-        };
+        let hashed = decode_hashed(offsets.version, &buf_)?;
         buf_.clear();
         buf_.resize(offsets.contents_len as usize, 0);
         let mut s = zstd_seekable::Seekable::init_buf(&buf[offsets.contents_off as usize..])?;
@@ -1994,11 +2076,16 @@ impl Change {
             }
         })?;
         let mut buf = vec![0u8; Self::OFFSETS_SIZE as usize];
-        r.read_exact(&mut buf)?;
+        r.read_exact(&mut buf[..8])?;
+        if buf[..8] == VERSION_STREAM.to_le_bytes() {
+            return stream::ChangeStream::open_after_version(std::io::BufReader::new(r))?
+                .finish(hash);
+        }
+        r.read_exact(&mut buf[8..])?;
         let offsets: Offsets = bincode::deserialize(&buf)?;
         if offsets.version == VERSION_NOENC {
             return Self::deserialize_noenc(offsets, r, hash);
-        } else if offsets.version != VERSION {
+        } else if offsets.version != VERSION && offsets.version != VERSION_V6 {
             return Err(ChangeError::VersionMismatch {
                 got: offsets.version,
             });
@@ -2023,7 +2110,7 @@ impl Change {
                     });
                 }
             }
-            bincode::deserialize_from(&out[..])?
+            decode_hashed(offsets.version, &out)?
         };
         buf.clear();
         buf.resize((offsets.contents_off - offsets.unhashed_off) as usize, 0);
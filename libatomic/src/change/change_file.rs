@@ -2,12 +2,19 @@ use super::*;
 
 /// An open, seekable change file.
 pub struct ChangeFile {
-    s: Option<zstd_seekable::Seekable<'static, OffFile>>,
+    s: Option<Contents>,
     hashed: Hashed<Hunk<Option<Hash>, Local>, Author>,
     hash: Hash,
     unhashed: Option<toml::Value>,
 }
 
+/// The contents of a change file: files with offsets are read lazily,
+/// whereas streaming (version 8) files are decompressed when opened.
+enum Contents {
+    Seekable(zstd_seekable::Seekable<'static, OffFile>),
+    Buffered(Vec<u8>),
+}
+
 struct OffFile {
     f: std::fs::File,
     start: u64,
@@ -39,9 +46,23 @@ impl ChangeFile {
         let mut r = std::fs::File::open(path).map_err(|err| ChangeError::IoHash { err, hash })?;
         let mut buf = Vec::new();
         buf.resize(Change::OFFSETS_SIZE as usize, 0);
-        r.read_exact(&mut buf)?;
+        r.read_exact(&mut buf[..8])?;
+        if buf[..8] == VERSION_STREAM.to_le_bytes() {
+            let change = stream::ChangeStream::open_after_version(std::io::BufReader::new(r))?
+                .finish(None)?;
+            return Ok(ChangeFile {
+                s: Some(Contents::Buffered(change.contents)),
+                hashed: change.hashed,
+                hash,
+                unhashed: change.unhashed.and_then(|u| serde_json::from_value(u).ok()),
+            });
+        }
+        r.read_exact(&mut buf[8..])?;
         let offsets: Offsets = bincode::deserialize(&buf)?;
-        if offsets.version != VERSION && offsets.version != VERSION_NOENC {
+        if offsets.version != VERSION
+            && offsets.version != VERSION_V6
+            && offsets.version != VERSION_NOENC
+        {
             return Err(ChangeError::VersionMismatch {
                 got: offsets.version,
             });
@@ -51,20 +72,9 @@ impl ChangeFile {
         buf.resize((offsets.unhashed_off - Change::OFFSETS_SIZE) as usize, 0);
         r.read_exact(&mut buf)?;
         let mut buf2 = vec![0u8; offsets.hashed_len as usize];
-        let hashed: Hashed<Hunk<Option<Hash>, Local>, Author> = if offsets.version == VERSION {
-            let mut s = zstd_seekable::Seekable::init_buf(&buf)?;
-            s.decompress(&mut buf2, 0)?;
-            trace!("deserialize current version {:?}", buf2.len());
-            bincode::deserialize(&buf2)?
-        } else {
-            assert_eq!(offsets.version, VERSION_NOENC);
-            let mut s = zstd_seekable::Seekable::init_buf(&buf)?;
-            s.decompress(&mut buf2, 0)?;
-            trace!("deserialize noenc {:?}", buf2.len());
-            let h: Hashed<noenc::Hunk<Option<Hash>, Local>, noenc::Author> =
-                bincode::deserialize(&buf2)?;
-            h.into()
-        };
+        zstd_seekable::Seekable::init_buf(&buf)?.decompress(&mut buf2, 0)?;
+        trace!("deserialize version {:?} {:?}", offsets.version, buf2.len());
+        let hashed = decode_hashed(offsets.version, &buf2)?;
 
         buf.resize((offsets.contents_off - offsets.unhashed_off) as usize, 0);
         let unhashed = if buf.is_empty() {
@@ -82,10 +92,12 @@ impl ChangeFile {
         let s = if offsets.contents_off >= m.len() {
             None
         } else {
-            Some(zstd_seekable::Seekable::init(Box::new(OffFile {
-                f: r,
-                start: offsets.contents_off,
-            }))?)
+            Some(Contents::Seekable(zstd_seekable::Seekable::init(
+                Box::new(OffFile {
+                    f: r,
+                    start: offsets.contents_off,
+                }),
+            )?))
         };
         Ok(ChangeFile {
             s,
@@ -104,10 +116,15 @@ impl ChangeFile {
     /// section are not checked.
     pub fn read_contents(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize, ChangeError> {
         trace!("read_contents {:?} {:?}", offset, buf.len());
-        if let Some(ref mut s) = self.s {
-            Ok(s.decompress(buf, offset)?)
-        } else {
-            Err(ChangeError::MissingContents { hash: self.hash })
+        match self.s {
+            Some(Contents::Seekable(ref mut s)) => Ok(s.decompress(buf, offset)?),
+            Some(Contents::Buffered(ref c)) => {
+                let start = (offset as usize).min(c.len());
+                let n = buf.len().min(c.len() - start);
+                buf[..n].copy_from_slice(&c[start..start + n]);
+                Ok(n)
+            }
+            None => Err(ChangeError::MissingContents { hash: self.hash }),
         }
     }
 
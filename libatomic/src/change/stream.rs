@@ -0,0 +1,323 @@
+//! Streaming-friendly change container (version 8).
+//!
+//! Versions 6 and 7 of the change file start with a fixed-size table
+//! of [`Offsets`], and every section is a `zstd_seekable` stream, so
+//! that readers need to seek inside the file. Version 8 stores the
+//! same data header-first, as a sequence of length-prefixed sections
+//! which can be consumed from any [`std::io::Read`]:
+//!
+//! ```text
+//! version | header | dependencies | hunks | unhashed | contents frames | end
+//! ```
+//!
+//! The version is a little-endian `u64`. Each section is encoded as
+//! `raw_len: u64 | compressed_len: u64 | compressed bytes`, an empty
+//! section having both lengths set to 0. The contents are split into
+//! frames of at most [`CONTENTS_FRAME_SIZE`] bytes, and terminated by
+//! an empty section.
+//!
+//! The header, dependencies and hunks sections concatenated are
+//! exactly the bincode serialisation of [`Hashed`], hence a change
+//! has the same hash whichever container it is stored in.
+//!
+//! Streams come from remotes, so the lengths of their sections are
+//! checked before anything is allocated: sections are at most
+//! [`MAX_SECTION_SIZE`] bytes, frames of contents at most
+//! [`CONTENTS_FRAME_SIZE`], and the contents at most
+//! [`MAX_SECTION_SIZE`] bytes in total.
+//!
+//! Change stores still write version 7, [`Change::serialize_stream`]
+//! writes this container.
+use super::*;
+use std::io::{Read, Seek, SeekFrom};
+
+/// Maximal size of a decompressed frame of contents.
+pub const CONTENTS_FRAME_SIZE: usize = 1 << 16;
+
+/// Maximal size of a section other than the contents, compressed or
+/// decompressed.
+pub const MAX_SECTION_SIZE: u64 = 1 << 30;
+
+/// Size of the prefix of each section.
+const SECTION_PREFIX_SIZE: u64 = 16;
+
+/// Sections of a version 8 change file, in the order in which they
+/// appear in the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Section {
+    /// Version of the hashed part, and the change header.
+    Header,
+    /// Dependencies, extra known changes and metadata.
+    Dependencies,
+    /// Hunks, hash of the contents and tag metadata.
+    Hunks,
+    /// Unhashed JSON contents.
+    Unhashed,
+}
+
+fn write_section<W: Write>(w: &mut W, raw: &[u8]) -> Result<(), ChangeError> {
+    let mut comp = Vec::new();
+    if !raw.is_empty() {
+        compress(raw, &mut comp)?;
+    }
+    w.write_all(&(raw.len() as u64).to_le_bytes())?;
+    w.write_all(&(comp.len() as u64).to_le_bytes())?;
+    w.write_all(&comp)?;
+    Ok(())
+}
+
+fn read_u64<R: Read>(r: &mut R) -> Result<u64, ChangeError> {
+    let mut b = [0; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+/// Read a section of at most `max` bytes once decompressed.
+fn read_section<R: Read>(r: &mut R, max: u64) -> Result<Vec<u8>, ChangeError> {
+    let raw_len = read_u64(r)?;
+    let comp_len = read_u64(r)?;
+    if raw_len == 0 {
+        return Ok(Vec::new());
+    }
+    if raw_len > max || comp_len > MAX_SECTION_SIZE {
+        return Err(ChangeError::SectionTooLarge {
+            len: raw_len.max(comp_len),
+            max,
+        });
+    }
+    // Only allocate what the stream actually holds.
+    let mut comp = Vec::new();
+    r.by_ref().take(comp_len).read_to_end(&mut comp)?;
+    if (comp.len() as u64) < comp_len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    let mut s = zstd_seekable::Seekable::init_buf(&comp)?;
+    let mut raw = vec![0; raw_len as usize];
+    s.decompress(&mut raw, 0)?;
+    Ok(raw)
+}
+
+/// Length of the prefix of a version 8 change file that ends with
+/// section `upto`, starting from the current position of `r` (which
+/// must be the start of the file). This lets servers answer
+/// header-only requests by sending that prefix, which
+/// [`ChangeStream::open`] can read.
+pub fn prefix_len<R: Read + Seek>(r: &mut R, upto: Section) -> Result<u64, ChangeError> {
+    let start = r.stream_position()?;
+    let version = read_u64(r)?;
+    if version != VERSION_STREAM {
+        return Err(ChangeError::VersionMismatch { got: version });
+    }
+    let mut len = 8;
+    for section in [
+        Section::Header,
+        Section::Dependencies,
+        Section::Hunks,
+        Section::Unhashed,
+    ] {
+        if section > upto {
+            break;
+        }
+        read_u64(r)?;
+        let comp_len = read_u64(r)?;
+        r.seek(SeekFrom::Current(comp_len as i64))?;
+        len += SECTION_PREFIX_SIZE + comp_len;
+    }
+    r.seek(SeekFrom::Start(start))?;
+    Ok(len)
+}
+
+/// Dependencies of a change, as read from the dependencies section.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamDependencies {
+    pub dependencies: Vec<Hash>,
+    pub extra_known: Vec<Hash>,
+    pub metadata: Vec<u8>,
+}
+
+/// A version 8 change being read from a stream. The header is read
+/// when opening the stream, and the other sections are read on
+/// demand, in order.
+pub struct ChangeStream<R> {
+    r: R,
+    hasher: Hasher,
+    version: u64,
+    header: ChangeHeader,
+    dependencies: Option<StreamDependencies>,
+}
+
+impl<R: Read> ChangeStream<R> {
+    /// Start reading a change, reading the container version and the
+    /// header section.
+    pub fn open(mut r: R) -> Result<Self, ChangeError> {
+        let version = read_u64(&mut r)?;
+        if version != VERSION_STREAM {
+            return Err(ChangeError::VersionMismatch { got: version });
+        }
+        Self::open_after_version(r)
+    }
+
+    /// Same as [`ChangeStream::open`], when the container version has
+    /// already been read.
+    pub(super) fn open_after_version(mut r: R) -> Result<Self, ChangeError> {
+        let raw = read_section(&mut r, MAX_SECTION_SIZE)?;
+        let (version, header): (u64, ChangeHeader) = bincode::deserialize(&raw)?;
+        if version != VERSION {
+            return Err(ChangeError::VersionMismatch { got: version });
+        }
+        let mut hasher = Hasher::default();
+        hasher.update(&raw);
+        Ok(ChangeStream {
+            r,
+            hasher,
+            version,
+            header,
+            dependencies: None,
+        })
+    }
+
+    /// The header of this change.
+    pub fn header(&self) -> &ChangeHeader {
+        &self.header
+    }
+
+    /// The dependencies of this change, read from the stream if this
+    /// hasn't been done yet. The hunks and contents are not needed
+    /// for this, so dependency resolution can start before the end
+    /// of a download.
+    pub fn dependencies(&mut self) -> Result<&StreamDependencies, ChangeError> {
+        if self.dependencies.is_none() {
+            let raw = read_section(&mut self.r, MAX_SECTION_SIZE)?;
+            let (dependencies, extra_known, metadata) = bincode::deserialize(&raw)?;
+            self.hasher.update(&raw);
+            self.dependencies = Some(StreamDependencies {
+                dependencies,
+                extra_known,
+                metadata,
+            });
+        }
+        Ok(self.dependencies.as_ref().unwrap())
+    }
+
+    /// Read the rest of the stream, check the hashes (if `hash` is
+    /// provided) and return the full change.
+    pub fn finish(mut self, hash: Option<&Hash>) -> Result<Change, ChangeError> {
+        self.dependencies()?;
+        let raw = read_section(&mut self.r, MAX_SECTION_SIZE)?;
+        let (changes, contents_hash, tag) = bincode::deserialize(&raw)?;
+        self.hasher.update(&raw);
+        let computed_hash = self.hasher.finish();
+        if let Some(hash) = hash {
+            if &computed_hash != hash {
+                return Err(ChangeError::ChangeHashMismatch {
+                    claimed: *hash,
+                    computed: computed_hash,
+                });
+            }
+        }
+
+        let raw = read_section(&mut self.r, MAX_SECTION_SIZE)?;
+        let unhashed = if raw.is_empty() {
+            None
+        } else {
+            serde_json::from_slice(&raw).ok()
+        };
+
+        let mut contents = Vec::new();
+        loop {
+            let frame = read_section(&mut self.r, CONTENTS_FRAME_SIZE as u64)?;
+            if frame.is_empty() {
+                break;
+            }
+            let len = (contents.len() + frame.len()) as u64;
+            if len > MAX_SECTION_SIZE {
+                return Err(ChangeError::SectionTooLarge {
+                    len,
+                    max: MAX_SECTION_SIZE,
+                });
+            }
+            contents.extend_from_slice(&frame);
+        }
+        let mut hasher = Hasher::default();
+        hasher.update(&contents);
+        let computed = hasher.finish();
+        if computed != contents_hash {
+            return Err(ChangeError::ContentsHashMismatch {
+                claimed: contents_hash,
+                computed,
+            });
+        }
+
+        let StreamDependencies {
+            dependencies,
+            extra_known,
+            metadata,
+        } = self.dependencies.take().unwrap();
+        Ok(LocalChange {
+            offsets: Offsets {
+                version: VERSION_STREAM,
+                contents_len: contents.len() as u64,
+                ..Offsets::default()
+            },
+            hashed: Hashed {
+                version: self.version,
+                header: self.header,
+                dependencies,
+                extra_known,
+                metadata,
+                changes,
+                contents_hash,
+                tag,
+            },
+            unhashed,
+            contents,
+        })
+    }
+}
+
+impl Change {
+    /// Serialise the change in the streaming container (version 8),
+    /// and return its hash. `f` is called with the hash before
+    /// anything is written, as in [`Change::serialize`].
+    pub fn serialize_stream<
+        W: Write,
+        E: From<ChangeError>,
+        F: FnOnce(&mut Self, &Hash) -> Result<(), E>,
+    >(
+        &mut self,
+        mut w: W,
+        f: F,
+    ) -> Result<Hash, E> {
+        let h = &self.hashed;
+        let header = bincode::serialize(&(&h.version, &h.header)).map_err(ChangeError::from)?;
+        let deps = bincode::serialize(&(&h.dependencies, &h.extra_known, &h.metadata))
+            .map_err(ChangeError::from)?;
+        let hunks = bincode::serialize(&(&h.changes, &h.contents_hash, &h.tag))
+            .map_err(ChangeError::from)?;
+        let mut hasher = Hasher::default();
+        hasher.update(&header);
+        hasher.update(&deps);
+        hasher.update(&hunks);
+        let hash = hasher.finish();
+        debug!("serialize_stream {:?}", hash);
+
+        f(self, &hash)?;
+
+        let unhashed = if let Some(ref un) = self.unhashed {
+            serde_json::to_vec(un).map_err(ChangeError::from)?
+        } else {
+            Vec::new()
+        };
+        w.write_all(&VERSION_STREAM.to_le_bytes())
+            .map_err(ChangeError::from)?;
+        write_section(&mut w, &header)?;
+        write_section(&mut w, &deps)?;
+        write_section(&mut w, &hunks)?;
+        write_section(&mut w, &unhashed)?;
+        for frame in self.contents.chunks(CONTENTS_FRAME_SIZE) {
+            write_section(&mut w, frame)?;
+        }
+        write_section(&mut w, &[])?;
+        Ok(hash)
+    }
+}
@@ -258,7 +258,6 @@ impl LocalChange<Hunk<Option<Hash>, Local>, Author> {
                     dep.to_base32(),
                     change_message(changes, dep)
                 )?;
-                i += 1;
             }
         }
 
@@ -0,0 +1,213 @@
+//! A cache of decompressed change contents, shared by all the change
+//! stores of a process.
+//!
+//! Outputting files, diffing and blaming read the same vertices of the
+//! same changes over and over, and each read of a change file
+//! decompresses a whole frame. This cache keeps the decompressed bytes of
+//! the vertices read recently, bounded by their total size rather than
+//! their number, and counts its hits and misses.
+//!
+//! Change files are addressed by their hash, so entries never go stale.
+//! The cache used by [`FileSystem`](super::filesystem::FileSystem) stores
+//! is [`ContentsCache::global`], of [`DEFAULT_CAPACITY`] bytes unless
+//! `ATOMIC_CONTENTS_CACHE_MB` is set, or changed with
+//! [`ContentsCache::set_capacity`].
+use crate::pristine::{ChangePosition, Hash};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Default capacity of the global cache, in bytes
+pub const DEFAULT_CAPACITY: usize = 64 << 20;
+
+lazy_static! {
+    static ref GLOBAL: ContentsCache = ContentsCache::new(
+        std::env::var("ATOMIC_CONTENTS_CACHE_MB")
+            .ok()
+            .and_then(|mb| mb.parse::<usize>().ok())
+            .map_or(DEFAULT_CAPACITY, |mb| mb << 20)
+    );
+}
+
+type Key = (Hash, ChangePosition, ChangePosition);
+
+struct Inner {
+    entries: lru_cache::LruCache<Key, Arc<[u8]>>,
+    /// Total size of the entries
+    size: usize,
+    capacity: usize,
+}
+
+/// A size-bounded LRU cache of decompressed contents. Clones share the
+/// same entries and counters.
+#[derive(Clone)]
+pub struct ContentsCache {
+    inner: Arc<Mutex<Inner>>,
+    hits: Arc<AtomicU64>,
+    misses: Arc<AtomicU64>,
+}
+
+/// Usage of a [`ContentsCache`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ContentsCacheMetrics {
+    /// Capacity, in bytes
+    pub capacity: usize,
+    /// Total size of the cached contents, in bytes
+    pub size: usize,
+    pub entries: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Share of the reads served by the cache, between 0 and 1
+    pub hit_rate: f64,
+}
+
+impl ContentsCache {
+    /// A new cache of `capacity` bytes, `0` to disable caching
+    pub fn new(capacity: usize) -> Self {
+        ContentsCache {
+            inner: Arc::new(Mutex::new(Inner {
+                // Entries are bounded by their size, below.
+                entries: lru_cache::LruCache::new(usize::MAX),
+                size: 0,
+                capacity,
+            })),
+            hits: Arc::new(AtomicU64::new(0)),
+            misses: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// The cache shared by the change stores of this process
+    pub fn global() -> &'static ContentsCache {
+        &GLOBAL
+    }
+
+    /// Change the capacity, evicting the least recently used entries if
+    /// needed
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.capacity = capacity;
+        inner.evict();
+    }
+
+    /// Copy the contents of `change` between `start` and `end` into `buf`,
+    /// reading them with `read` if they are not cached, and return the
+    /// number of bytes copied
+    pub fn read<E, F: FnOnce(&mut [u8]) -> Result<usize, E>>(
+        &self,
+        change: Hash,
+        start: ChangePosition,
+        end: ChangePosition,
+        buf: &mut [u8],
+        read: F,
+    ) -> Result<usize, E> {
+        let key = (change, start, end);
+        if let Some(contents) = self.inner.lock().unwrap().entries.get_mut(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            let n = contents.len().min(buf.len());
+            buf[..n].copy_from_slice(&contents[..n]);
+            return Ok(n);
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        // Read without the lock, other threads may read other contents.
+        let n = read(buf)?;
+        let mut inner = self.inner.lock().unwrap();
+        if n <= inner.capacity && !inner.entries.contains_key(&key) {
+            inner.size += n;
+            inner.entries.insert(key, Arc::from(&buf[..n]));
+            inner.evict();
+        }
+        Ok(n)
+    }
+
+    /// Forget all entries, keeping the counters
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.entries.clear();
+        inner.size = 0;
+    }
+
+    pub fn metrics(&self) -> ContentsCacheMetrics {
+        let inner = self.inner.lock().unwrap();
+        let hits = self.hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        ContentsCacheMetrics {
+            capacity: inner.capacity,
+            size: inner.size,
+            entries: inner.entries.len(),
+            hits,
+            misses,
+            hit_rate: if hits + misses == 0 {
+                0.
+            } else {
+                hits as f64 / (hits + misses) as f64
+            },
+        }
+    }
+}
+
+impl Inner {
+    fn evict(&mut self) {
+        while self.size > self.capacity {
+            match self.entries.remove_lru() {
+                Some((_, contents)) => self.size -= contents.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pristine::Hasher;
+
+    fn hash(s: &[u8]) -> Hash {
+        let mut hasher = Hasher::default();
+        hasher.update(s);
+        hasher.finish()
+    }
+
+    fn read(cache: &ContentsCache, change: Hash, len: u64, fill: u8) -> Vec<u8> {
+        let mut buf = vec![0; len as usize];
+        let n = cache
+            .read::<(), _>(
+                change,
+                ChangePosition(0u64.into()),
+                ChangePosition(len.into()),
+                &mut buf,
+                |buf| {
+                    buf.fill(fill);
+                    Ok(buf.len())
+                },
+            )
+            .unwrap();
+        assert_eq!(n, len as usize);
+        buf
+    }
+
+    #[test]
+    fn test_contents_cache() {
+        let cache = ContentsCache::new(10);
+        let (a, b) = (hash(b"a"), hash(b"b"));
+        assert_eq!(read(&cache, a, 4, 1), vec![1; 4]);
+        // Cached: the reader isn't called.
+        assert_eq!(read(&cache, a, 4, 2), vec![1; 4]);
+        assert_eq!(read(&cache, b, 4, 3), vec![3; 4]);
+        let metrics = cache.metrics();
+        assert_eq!((metrics.hits, metrics.misses), (1, 2));
+        assert_eq!((metrics.entries, metrics.size), (2, 8));
+
+        // `a` is the least recently used, and goes.
+        read(&cache, b, 6, 4);
+        assert_eq!(cache.metrics().size, 10);
+        assert_eq!(read(&cache, a, 4, 5), vec![5; 4]);
+        assert!(cache.metrics().size <= 10);
+
+        // Too large to be cached.
+        assert_eq!(read(&cache, a, 11, 6), vec![6; 11]);
+        assert_eq!(read(&cache, a, 11, 7), vec![7; 11]);
+
+        cache.set_capacity(0);
+        assert_eq!(cache.metrics().entries, 0);
+        assert_eq!(cache.metrics().hit_rate, 1. / 7.);
+    }
+}
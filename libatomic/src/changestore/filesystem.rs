@@ -1,3 +1,4 @@
+use super::contents_cache::ContentsCache;
 use super::*;
 use crate::change::{Change, ChangeFile};
 use crate::pristine::{Base32, Hash, Merkle, NodeId, Vertex};
@@ -7,6 +8,7 @@ use std::path::{Path, PathBuf};
 /// A file system change store.
 pub struct FileSystem {
     change_cache: RefCell<lru_cache::LruCache<NodeId, ChangeFile>>,
+    contents_cache: ContentsCache,
     changes_dir: PathBuf,
 }
 
@@ -16,6 +18,7 @@ impl Clone for FileSystem {
         FileSystem {
             changes_dir: self.changes_dir.clone(),
             change_cache: RefCell::new(lru_cache::LruCache::new(len)),
+            contents_cache: self.contents_cache.clone(),
         }
     }
 }
@@ -53,6 +56,61 @@ pub fn pop_filename(changes_dir: &mut PathBuf) {
     changes_dir.pop();
 }
 
+/// `path`, made absolute and in the extended-length (`\\?\`) form on
+/// Windows, so that change and tag files deep in a repository aren't
+/// limited to `MAX_PATH` (260 characters). Verbatim and device paths are
+/// kept as they are. Other platforms have no such limit.
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> PathBuf {
+    use std::ffi::OsString;
+    use std::path::{Component, Prefix};
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else if let Ok(cur) = std::env::current_dir() {
+        cur.join(path)
+    } else {
+        return path.to_path_buf();
+    };
+    let mut components = path.components();
+    let mut long = match components.next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(_) => {
+                let mut long = OsString::from(r"\\?\");
+                long.push(prefix.as_os_str());
+                PathBuf::from(long)
+            }
+            Prefix::UNC(server, share) => {
+                let mut long = OsString::from(r"\\?\UNC\");
+                long.push(server);
+                long.push(r"\");
+                long.push(share);
+                PathBuf::from(long)
+            }
+            _ => return path,
+        },
+        _ => return path,
+    };
+    // Extended-length paths are not normalized by Windows.
+    for component in components {
+        match component {
+            Component::RootDir => long.push(r"\"),
+            Component::ParentDir => {
+                long.pop();
+            }
+            Component::Normal(c) => long.push(c),
+            Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    long
+}
+
+/// `path`, made absolute and in the extended-length (`\\?\`) form on
+/// Windows. Other platforms have no such limit.
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> PathBuf {
+    path.to_path_buf()
+}
+
 impl FileSystem {
     pub fn filename(&self, hash: &Hash) -> PathBuf {
         let mut path = self.changes_dir.clone();
@@ -93,13 +151,26 @@ impl FileSystem {
     /// Construct a `FileSystem`, starting from the root of the
     /// repository (i.e. the parent of the `.atomic` directory).
     pub fn from_changes(changes_dir: PathBuf, cap: usize) -> Self {
+        let changes_dir = long_path(&changes_dir);
         std::fs::create_dir_all(&changes_dir).unwrap();
         FileSystem {
             changes_dir,
             change_cache: RefCell::new(lru_cache::LruCache::new(cap)),
+            contents_cache: ContentsCache::global().clone(),
         }
     }
 
+    /// Use `cache` for the decompressed contents instead of the cache
+    /// shared by the whole process.
+    pub fn with_contents_cache(mut self, cache: ContentsCache) -> Self {
+        self.contents_cache = cache;
+        self
+    }
+
+    pub fn contents_cache(&self) -> &ContentsCache {
+        &self.contents_cache
+    }
+
     fn load<'a, F: Fn(NodeId) -> Option<Hash>>(
         &'a self,
         hash: F,
@@ -220,9 +291,14 @@ impl ChangeStore for FileSystem {
             return Ok(0);
         }
         assert_eq!(key.end - key.start, buf.len());
-        let mut cache = self.load(hash, key.change)?;
-        let p = cache.get_mut(&key.change).unwrap();
-        let n = p.read_contents(key.start.into(), buf)?;
+        let h = hash(key.change).unwrap();
+        let n = self
+            .contents_cache
+            .read(h, key.start, key.end, buf, |buf| {
+                let mut cache = self.load(|_| Some(h), key.change)?;
+                let p = cache.get_mut(&key.change).unwrap();
+                Ok::<_, Self::Error>(p.read_contents(key.start.into(), buf)?)
+            })?;
         debug!("get_contents {:?}", n);
         Ok(n)
     }
@@ -236,10 +312,12 @@ impl ChangeStore for FileSystem {
             if key.end <= key.start {
                 return Ok(0);
             }
-            let path = self.filename(&change);
-            let mut p = crate::change::ChangeFile::open(change, &path.to_str().unwrap())?;
-            let n = p.read_contents(key.start.into(), buf)?;
-            Ok(n)
+            self.contents_cache
+                .read(change, key.start, key.end, buf, |buf| {
+                    let path = self.filename(&change);
+                    let mut p = crate::change::ChangeFile::open(change, &path.to_str().unwrap())?;
+                    Ok::<_, Self::Error>(p.read_contents(key.start.into(), buf)?)
+                })
         } else {
             Ok(0)
         }
@@ -442,3 +520,51 @@ impl ChangeStore for FileSystem {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(windows))]
+    #[test]
+    fn long_path_unchanged() {
+        assert_eq!(long_path(Path::new("a/../b")), Path::new("a/../b"));
+        assert_eq!(long_path(Path::new("/a/b")), Path::new("/a/b"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn long_path_windows() {
+        assert_eq!(
+            long_path(Path::new(r"C:\repo\.atomic\..\.atomic\changes")),
+            Path::new(r"\\?\C:\repo\.atomic\changes")
+        );
+        assert_eq!(
+            long_path(Path::new(r"\\server\share\repo\.\changes")),
+            Path::new(r"\\?\UNC\server\share\repo\changes")
+        );
+        let verbatim = Path::new(r"\\?\C:\repo\changes");
+        assert_eq!(long_path(verbatim), verbatim);
+        assert!(long_path(Path::new("changes")).starts_with(r"\\?\"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn long_changes_dir() {
+        use crate::pristine::Hasher;
+        let dir = tempfile::tempdir().unwrap();
+        let mut changes_dir = dir.path().to_path_buf();
+        while changes_dir.as_os_str().len() < 300 {
+            changes_dir.push("a-rather-long-directory-name");
+        }
+        let store = FileSystem::from_changes(changes_dir, 10);
+        let mut hasher = Hasher::default();
+        hasher.update(b"change");
+        let hash = hasher.finish();
+        store
+            .save_from_buf_unchecked(b"contents", &hash, None)
+            .unwrap();
+        assert!(store.has_change(&hash));
+        assert!(store.filename(&hash).as_os_str().len() > 300);
+    }
+}
@@ -8,6 +8,10 @@ use crate::{
     text_encoding::Encoding,
 };
 
+#[cfg(feature = "ondisk-repos")]
+/// A cache of decompressed contents, shared by the `filesystem` stores.
+pub mod contents_cache;
+
 #[cfg(feature = "ondisk-repos")]
 /// If this crate is compiled with the `ondisk-repos` feature (the
 /// default), this module stores changes on the file system, under
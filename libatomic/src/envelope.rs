@@ -0,0 +1,305 @@
+//! Encrypted changes, for confidential channels.
+//!
+//! An [`Envelope`] holds a change file encrypted for a set of recipients,
+//! so that servers can store and serve changes of embargoed channels
+//! without being able to read them. Each envelope has:
+//!
+//! - the hash of the change, in clear, so that it can be stored and
+//!   listed like the change itself;
+//! - the change sealed with ChaCha20-Poly1305 under a random content key,
+//!   with the hash of the change as associated data;
+//! - the content key, sealed for each recipient. Recipients are
+//!   identified by their identity public key, and the content key is
+//!   sealed with ChaCha20-Poly1305 under a key derived with HKDF-SHA256
+//!   from an X25519 agreement between an ephemeral key and the Montgomery
+//!   form of the recipient's Ed25519 key, as age does for `ssh-ed25519`
+//!   recipients.
+//!
+//! Each key seals a single message, so nonces are all zeros. Opening an
+//! envelope checks that the decrypted change has the hash of the envelope.
+
+use crate::key::{PKey, SKey};
+use crate::pristine::{Base32, Hash};
+use bincode::Options;
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use curve25519_dalek::scalar::Scalar;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::hkdf::{Salt, HKDF_SHA256};
+
+/// First bytes of serialized envelopes, distinct from the version of
+/// change files.
+pub const MAGIC: &[u8; 8] = b"ATOMENV\0";
+
+pub const VERSION: u64 = 1;
+
+/// Context of the keys derived for recipients
+const WRAP_INFO: &[u8] = b"atomic envelope v1 content key";
+
+#[derive(Debug, Error)]
+pub enum EnvelopeError {
+    #[error("Not an envelope")]
+    NotAnEnvelope,
+    #[error("Unsupported envelope version {0}")]
+    Version(u64),
+    #[error("Invalid public key of recipient {0}")]
+    InvalidKey(String),
+    #[error("The envelope has no recipients")]
+    NoRecipients,
+    #[error("The envelope of {0} isn't addressed to this key")]
+    NotARecipient(String),
+    #[error("The envelope of {0} was tampered with")]
+    Tampered(String),
+    #[error("The envelope doesn't contain change {0}")]
+    WrongChange(String),
+    #[error(transparent)]
+    Encoding(#[from] bincode::Error),
+}
+
+/// The content key of an [`Envelope`], sealed for a recipient
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recipient {
+    /// Public key of the recipient, as in its identity
+    pub key: String,
+    /// Ephemeral X25519 public key of the agreement with the recipient
+    pub ephemeral: [u8; 32],
+    /// The content key, followed by its authentication tag
+    pub wrapped: Vec<u8>,
+}
+
+/// A change encrypted for a set of recipients
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub version: u64,
+    /// Hash of the change, in base32
+    pub hash: String,
+    pub recipients: Vec<Recipient>,
+    /// The change, followed by its authentication tag
+    ciphertext: Vec<u8>,
+}
+
+/// Whether `buf` starts like a serialized envelope
+pub fn is_envelope(buf: &[u8]) -> bool {
+    buf.starts_with(MAGIC)
+}
+
+impl Envelope {
+    /// Encrypt `change`, the contents of the change file of `hash`, for
+    /// `recipients`.
+    pub fn seal(hash: &Hash, change: &[u8], recipients: &[PKey]) -> Result<Self, EnvelopeError> {
+        if recipients.is_empty() {
+            return Err(EnvelopeError::NoRecipients);
+        }
+        use rand::RngCore;
+        let mut rng = rand::thread_rng();
+        let mut content_key = [0; 32];
+        rng.fill_bytes(&mut content_key);
+
+        let hash = hash.to_base32();
+        let mut ciphertext = change.to_vec();
+        aead_key(&content_key)
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key([0; 12]),
+                Aad::from(hash.as_bytes()),
+                &mut ciphertext,
+            )
+            .expect("ChaCha20-Poly1305 can seal any change");
+
+        let mut sealed = Vec::with_capacity(recipients.len());
+        for recipient in recipients {
+            let PKey::Ed25519 { key, .. } = recipient;
+            let name = bs58::encode(key.as_bytes()).into_string();
+            let invalid = || EnvelopeError::InvalidKey(name.clone());
+            let point = montgomery(key.as_bytes()).ok_or_else(invalid)?;
+            let mut secret = [0; 32];
+            rng.fill_bytes(&mut secret);
+            let secret = Scalar::from_bits(clamp(secret));
+            let ephemeral = &curve25519_dalek::constants::X25519_BASEPOINT * &secret;
+            let wrap_key = wrap_key(&(&point * &secret), &ephemeral, &point).ok_or_else(invalid)?;
+            let mut wrapped = content_key.to_vec();
+            wrap_key
+                .seal_in_place_append_tag(
+                    Nonce::assume_unique_for_key([0; 12]),
+                    Aad::from(hash.as_bytes()),
+                    &mut wrapped,
+                )
+                .expect("ChaCha20-Poly1305 can seal a key");
+            sealed.push(Recipient {
+                key: name,
+                ephemeral: ephemeral.to_bytes(),
+                wrapped,
+            })
+        }
+        Ok(Envelope {
+            version: VERSION,
+            hash,
+            recipients: sealed,
+            ciphertext,
+        })
+    }
+
+    /// Decrypt the change of this envelope with `key`, checking that it
+    /// has the hash of the envelope.
+    pub fn open(&self, key: &SKey) -> Result<Vec<u8>, EnvelopeError> {
+        let SKey::Ed25519 { key, .. } = key;
+        let name = bs58::encode(key.public.as_bytes()).into_string();
+        let recipient = self
+            .recipients
+            .iter()
+            .find(|r| r.key == name)
+            .ok_or_else(|| EnvelopeError::NotARecipient(self.hash.clone()))?;
+        let point = montgomery(key.public.as_bytes()).ok_or(EnvelopeError::InvalidKey(name))?;
+        // The X25519 secret of an Ed25519 key is the (clamped) scalar of
+        // its expanded secret key.
+        let expanded = ed25519_dalek::ExpandedSecretKey::from(&key.secret).to_bytes();
+        let mut secret = [0; 32];
+        secret.clone_from_slice(&expanded[..32]);
+        let secret = Scalar::from_bits(clamp(secret));
+        let ephemeral = MontgomeryPoint(recipient.ephemeral);
+
+        let tampered = || EnvelopeError::Tampered(self.hash.clone());
+        let wrap_key =
+            wrap_key(&(&ephemeral * &secret), &ephemeral, &point).ok_or_else(tampered)?;
+        let mut content_key = recipient.wrapped.clone();
+        let content_key = wrap_key
+            .open_in_place(
+                Nonce::assume_unique_for_key([0; 12]),
+                Aad::from(self.hash.as_bytes()),
+                &mut content_key,
+            )
+            .map_err(|_| tampered())?;
+        if content_key.len() != 32 {
+            return Err(tampered());
+        }
+
+        let mut change = self.ciphertext.clone();
+        let len = aead_key(content_key)
+            .open_in_place(
+                Nonce::assume_unique_for_key([0; 12]),
+                Aad::from(self.hash.as_bytes()),
+                &mut change,
+            )
+            .map_err(|_| tampered())?
+            .len();
+        change.truncate(len);
+        let hash = Hash::from_base32(self.hash.as_bytes())
+            .ok_or_else(|| EnvelopeError::WrongChange(self.hash.clone()))?;
+        crate::change::Change::check_from_buffer(&change, &hash)
+            .map_err(|_| EnvelopeError::WrongChange(self.hash.clone()))?;
+        Ok(change)
+    }
+
+    /// The hash of the change in this envelope
+    pub fn hash(&self) -> Option<Hash> {
+        Hash::from_base32(self.hash.as_bytes())
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, EnvelopeError> {
+        let mut buf = MAGIC.to_vec();
+        bincode::options().serialize_into(&mut buf, self)?;
+        Ok(buf)
+    }
+
+    /// Read a serialized envelope. Envelopes come from the network, so
+    /// their fields can't claim more bytes than `buf` has.
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, EnvelopeError> {
+        if !is_envelope(buf) {
+            return Err(EnvelopeError::NotAnEnvelope);
+        }
+        let envelope: Envelope = bincode::options()
+            .with_limit(buf.len() as u64)
+            .deserialize(&buf[MAGIC.len()..])?;
+        if envelope.version != VERSION {
+            return Err(EnvelopeError::Version(envelope.version));
+        }
+        Ok(envelope)
+    }
+}
+
+/// A clamped X25519 secret scalar
+fn clamp(mut bytes: [u8; 32]) -> [u8; 32] {
+    bytes[0] &= 248;
+    bytes[31] &= 127;
+    bytes[31] |= 64;
+    bytes
+}
+
+/// The Montgomery form of an Ed25519 public key
+fn montgomery(key: &[u8; 32]) -> Option<MontgomeryPoint> {
+    curve25519_dalek::edwards::CompressedEdwardsY(*key)
+        .decompress()
+        .map(|p| p.to_montgomery())
+}
+
+/// The key sealing the content key for a recipient, derived from their
+/// shared secret, or `None` if the agreement produced the all-zero secret
+/// of low-order points.
+fn wrap_key(
+    shared: &MontgomeryPoint,
+    ephemeral: &MontgomeryPoint,
+    recipient: &MontgomeryPoint,
+) -> Option<LessSafeKey> {
+    if shared.as_bytes() == &[0; 32] {
+        return None;
+    }
+    let mut salt = [0; 64];
+    salt[..32].copy_from_slice(ephemeral.as_bytes());
+    salt[32..].copy_from_slice(recipient.as_bytes());
+    let prk = Salt::new(HKDF_SHA256, &salt).extract(shared.as_bytes());
+    let okm = prk.expand(&[WRAP_INFO], &CHACHA20_POLY1305).ok()?;
+    Some(LessSafeKey::new(UnboundKey::from(okm)))
+}
+
+fn aead_key(key: &[u8]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).expect("32-byte key"))
+}
+
+#[test]
+fn seal_and_open() {
+    let change: Vec<u8> = (0..=255).collect();
+    let mut hasher = crate::pristine::Hasher::default();
+    hasher.update(&change);
+    let hash = hasher.finish();
+    let alice = SKey::generate(None);
+    let bob = SKey::generate(None);
+    let eve = SKey::generate(None);
+
+    let envelope = Envelope::seal(&hash, &change, &[alice.pkey(), bob.pkey()]).unwrap();
+    let bytes = envelope.to_bytes().unwrap();
+    assert!(is_envelope(&bytes));
+    assert!(!is_envelope(&change));
+    assert!(!bytes.windows(change.len()).any(|w| w == &change[..]));
+    let envelope = Envelope::from_bytes(&bytes).unwrap();
+    assert_eq!(envelope.hash(), Some(hash));
+
+    // Only recipients can open envelopes, and the contents of envelopes
+    // are checked against their hash.
+    assert!(matches!(
+        envelope.open(&eve),
+        Err(EnvelopeError::NotARecipient(_))
+    ));
+    assert!(matches!(
+        envelope.open(&alice),
+        Err(EnvelopeError::WrongChange(_))
+    ));
+
+    let mut tampered = envelope.clone();
+    tampered.ciphertext[0] ^= 1;
+    assert!(matches!(
+        tampered.open(&bob),
+        Err(EnvelopeError::Tampered(_))
+    ));
+    assert!(matches!(
+        Envelope::seal(&hash, &change, &[]),
+        Err(EnvelopeError::NoRecipients)
+    ));
+
+    // Lengths read from the envelope can't exceed its size.
+    let mut huge = MAGIC.to_vec();
+    huge.push(VERSION as u8);
+    huge.push(253);
+    huge.extend(&u64::MAX.to_le_bytes());
+    assert!(matches!(
+        Envelope::from_bytes(&huge),
+        Err(EnvelopeError::Encoding(_))
+    ));
+}
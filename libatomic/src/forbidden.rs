@@ -0,0 +1,234 @@
+//! Paths that changes may not add or edit
+//!
+//! Ignore files are only read by the client recording a change, so a
+//! misconfigured client can still push build artifacts. The `[[forbid]]`
+//! rules of the repository configuration are checked both when recording
+//! and by servers applying changes, and a change adding or editing a
+//! forbidden path is rejected with the list of [`Violation`]s.
+//!
+//! Patterns have the syntax of normalization rules: patterns without a `/`
+//! match names in any directory, other patterns match paths from the root
+//! of the repository. A rule forbids the paths it matches and everything
+//! below them. Deleting a forbidden path is always allowed, so that
+//! repositories can be cleaned up.
+
+use crate::change::{Change, Hunk};
+use crate::working_copy::normalize::glob_to_regex;
+
+#[derive(Debug, Error)]
+pub enum ForbiddenPathError {
+    #[error("Invalid path pattern: {pattern}")]
+    InvalidPattern { pattern: String },
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    glob: String,
+    pattern: regex::Regex,
+    reason: Option<String>,
+}
+
+/// The forbidden paths of a repository
+#[derive(Debug, Clone, Default)]
+pub struct ForbiddenPaths {
+    rules: Vec<Rule>,
+}
+
+/// A path added or edited by a change, and the rule forbidding it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Violation {
+    pub path: String,
+    pub pattern: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{} (forbidden by {:?}", self.path, self.pattern)?;
+        if let Some(ref reason) = self.reason {
+            write!(fmt, ": {}", reason)?;
+        }
+        write!(fmt, ")")
+    }
+}
+
+/// The error returned when a change touches forbidden paths
+#[derive(Debug, Clone, Error)]
+pub struct ForbiddenPathViolation {
+    pub violations: Vec<Violation>,
+}
+
+impl std::fmt::Display for ForbiddenPathViolation {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "Forbidden paths: ")?;
+        for (n, v) in self.violations.iter().enumerate() {
+            if n > 0 {
+                write!(fmt, ", ")?;
+            }
+            write!(fmt, "{}", v)?;
+        }
+        Ok(())
+    }
+}
+
+impl ForbiddenPaths {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Forbid the paths matching `pattern`, and everything below them.
+    pub fn add_rule(
+        &mut self,
+        pattern: &str,
+        reason: Option<&str>,
+    ) -> Result<(), ForbiddenPathError> {
+        let regex = regex::Regex::new(&glob_to_regex(pattern)).map_err(|_| {
+            ForbiddenPathError::InvalidPattern {
+                pattern: pattern.to_string(),
+            }
+        })?;
+        self.rules.push(Rule {
+            glob: pattern.to_string(),
+            pattern: regex,
+            reason: reason.map(|r| r.to_string()),
+        });
+        Ok(())
+    }
+
+    /// The first rule forbidding `path`, relative to the root of the
+    /// repository, if any.
+    pub fn matching(&self, path: &str) -> Option<Violation> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let path = path.trim_start_matches('/');
+        // Test the path and all its ancestors.
+        let ends = path
+            .match_indices('/')
+            .map(|(i, _)| i)
+            .chain(std::iter::once(path.len()));
+        for end in ends {
+            let prefix = &path[..end];
+            if prefix.is_empty() {
+                continue;
+            }
+            if let Some(rule) = self.rules.iter().find(|r| r.pattern.is_match(prefix)) {
+                return Some(Violation {
+                    path: path.to_string(),
+                    pattern: rule.glob.clone(),
+                    reason: rule.reason.clone(),
+                });
+            }
+        }
+        None
+    }
+
+    /// Check the paths added or edited by `hunks`, where `local` returns
+    /// the path of the local part of a hunk.
+    pub fn check_hunks<'a, H: 'a, L: 'a, I: IntoIterator<Item = &'a Hunk<H, L>>>(
+        &self,
+        hunks: I,
+        local: impl Fn(&L) -> &str,
+    ) -> Result<(), ForbiddenPathViolation> {
+        self.check_paths(
+            hunks
+                .into_iter()
+                .filter_map(|hunk| written_path(hunk, |l| local(l))),
+        )
+    }
+
+    /// Check `paths`, added or edited by a change, e.g. as listed in a
+    /// summary of the change sent before the change itself.
+    pub fn check_paths<'a, I: IntoIterator<Item = &'a str>>(
+        &self,
+        paths: I,
+    ) -> Result<(), ForbiddenPathViolation> {
+        if self.rules.is_empty() {
+            return Ok(());
+        }
+        let mut violations: Vec<Violation> = Vec::new();
+        for path in paths {
+            if let Some(v) = self.matching(path) {
+                // Report forbidden directories once, not each file below.
+                let reported = violations.iter().any(|w| {
+                    v.path == w.path
+                        || (v.path.starts_with(&w.path) && v.path[w.path.len()..].starts_with('/'))
+                });
+                if !reported {
+                    violations.push(v)
+                }
+            }
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(ForbiddenPathViolation { violations })
+        }
+    }
+
+    /// Check the paths added or edited by `change`.
+    pub fn check_change(&self, change: &Change) -> Result<(), ForbiddenPathViolation> {
+        self.check_hunks(change.changes.iter(), |l| &l.path)
+    }
+}
+
+/// The path added or edited by `hunk`, if any, where `local` returns the
+/// path of the local part of a hunk. Deletions and conflict resolutions
+/// don't write any path.
+pub fn written_path<'a, H, L>(
+    hunk: &'a Hunk<H, L>,
+    local: impl Fn(&'a L) -> &'a str,
+) -> Option<&'a str> {
+    match hunk {
+        Hunk::FileAdd { path, .. } | Hunk::FileUndel { path, .. } | Hunk::FileMove { path, .. } => {
+            Some(path.as_str())
+        }
+        Hunk::Edit { local: l, .. }
+        | Hunk::Replacement { local: l, .. }
+        | Hunk::ResurrectZombies { local: l, .. } => Some(local(l)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matching() {
+        let mut forbidden = ForbiddenPaths::new();
+        forbidden
+            .add_rule("target", Some("build artifacts"))
+            .unwrap();
+        forbidden.add_rule("/dist/*.js", None).unwrap();
+        forbidden.add_rule("*.o", None).unwrap();
+
+        let v = forbidden.matching("target/debug/app").unwrap();
+        assert_eq!(v.pattern, "target");
+        assert_eq!(v.reason.as_deref(), Some("build artifacts"));
+        assert!(forbidden.matching("crates/a/target").is_some());
+        assert!(forbidden.matching("targets/a").is_none());
+        assert!(forbidden.matching("dist/app.js").is_some());
+        assert!(forbidden.matching("src/dist/app.js").is_none());
+        assert!(forbidden.matching("src/main.o").is_some());
+        assert!(forbidden.matching("src/main.rs").is_none());
+        assert!(ForbiddenPaths::new().matching("target").is_none());
+    }
+
+    #[test]
+    fn check_paths() {
+        let mut forbidden = ForbiddenPaths::new();
+        forbidden.add_rule("target", None).unwrap();
+        assert!(forbidden.check_paths(["src/main.rs"]).is_ok());
+        let err = forbidden
+            .check_paths(["target", "target/debug/app", "src/main.rs"])
+            .unwrap_err();
+        assert_eq!(err.violations.len(), 1);
+        assert_eq!(err.violations[0].path, "target");
+    }
+}
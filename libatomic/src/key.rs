@@ -65,7 +65,7 @@ fn sign_public_key() {
     println!("{:?}", pk);
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Signature {
     pub version: u64,
     pub key: PublicKey,
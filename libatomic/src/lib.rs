@@ -14,19 +14,43 @@ extern crate lazy_static;
 #[macro_use]
 extern crate quickcheck;
 
+/// Enter a `tracing` span around a phase of apply or output, exited when
+/// the returned guard is dropped. Without the `tracing` feature, this
+/// expands to a guard that does nothing, and the fields are not evaluated.
+#[cfg(feature = "tracing")]
+macro_rules! phase_span {
+    ($($arg:tt)*) => {
+        tracing::info_span!($($arg)*).entered()
+    };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! phase_span {
+    ($($arg:tt)*) => {
+        crate::NoSpan
+    };
+}
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct NoSpan;
+
 pub mod alive;
 mod apply;
 pub mod attribution;
 pub mod change;
+pub mod forbidden;
 pub mod changestore;
 mod diff;
+#[cfg(feature = "zstd")]
+pub mod envelope;
 pub mod fs;
+pub mod manifest;
 mod missing_context;
 pub mod output;
 pub mod path;
+pub mod permissions;
 pub mod pristine;
 pub mod record;
 pub mod small_string;
+pub mod squash;
 pub mod tag;
 mod text_encoding;
 mod unrecord;
@@ -73,6 +97,7 @@ pub use crate::attribution::{
     AuthorId, AuthorInfo, PatchId, SuggestionType,
 };
 pub use crate::diff::DEFAULT_SEPARATOR;
+pub use crate::forbidden::{ForbiddenPathViolation, ForbiddenPaths};
 pub use crate::fs::{FsError, WorkingCopyIterator};
 pub use crate::output::{Archive, Conflict};
 pub use crate::pristine::{
@@ -82,6 +107,7 @@ pub use crate::pristine::{
 };
 pub use crate::record::Builder as RecordBuilder;
 pub use crate::record::{Algorithm, InodeUpdate};
+pub use crate::squash::SquashError;
 pub use crate::unrecord::UnrecordError;
 
 // Making hashmaps deterministic (for testing)
@@ -285,6 +311,19 @@ pub trait MutTxnTExt:
         unrecord::unrecord(self, channel, changes, hash, salt)
     }
 
+    /// Squash consecutive changes of `channel` into a single change,
+    /// see [`squash::squash`].
+    fn squash<C: changestore::ChangeStore>(
+        &mut self,
+        changes: &C,
+        channel: &pristine::ChannelRef<Self>,
+        hashes: &[pristine::Hash],
+        message: Option<String>,
+        salt: u64,
+    ) -> Result<(pristine::Hash, change::Change), squash::SquashError<C::Error, Self>> {
+        squash::squash(self, channel, changes, hashes, message, salt)
+    }
+
     /// Register a file in the working copy, where the file is given by
     /// its path from the root of the repository, where the components of
     /// the path are separated by `/` (example path: `a/b/c`).
@@ -546,6 +585,29 @@ impl<T: ChannelTxnT + TreeTxnT + DepsTxnT<DepsError = <T as GraphTxnT>::GraphErr
     ) -> Result<Vec<output::Conflict>, output::ArchiveError<C::Error, T, A::Error>> {
         output::archive(changes, self, channel, prefix, arch)
     }
+
+    /// The verification manifest of the current state of `channel`, see
+    /// [`manifest`]. Files with conflicts are hashed with their conflict
+    /// markers, and the conflicts returned.
+    pub fn manifest<C: changestore::ChangeStore>(
+        &self,
+        changes: &C,
+        channel: &pristine::ChannelRef<T>,
+    ) -> Result<
+        (manifest::Manifest, Vec<output::Conflict>),
+        output::ArchiveError<C::Error, T, std::convert::Infallible>,
+    > {
+        let mut arch = manifest::ManifestArchive::default();
+        let conflicts = self.archive(changes, channel, &mut arch)?;
+        let txn = self.read();
+        let channel = channel.read();
+        let manifest = manifest::Manifest {
+            channel: txn.name(&*channel).to_string(),
+            state: pristine::current_state(&*txn, &*channel)?,
+            files: arch.files,
+        };
+        Ok((manifest, conflicts))
+    }
 }
 
 impl<
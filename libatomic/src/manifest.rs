@@ -0,0 +1,324 @@
+//! Verification manifests
+//!
+//! A manifest lists the files of a channel at a state, with the SHA-256
+//! of their contents and whether they are executable. Publishing the
+//! manifest of a release lets consumers check, without the pristine or
+//! the changes, that a clone, a working copy or an archive holds exactly
+//! the files of that state, e.g. in a CI supply-chain check.
+//!
+//! Manifests are computed with [`ArcTxn::manifest`](crate::pristine::ArcTxn),
+//! from the same output as archives, and written and parsed in a line
+//! oriented text format:
+//!
+//! ```text
+//! atomic-manifest 1
+//! channel main
+//! state MERKLE…
+//! file 644 <sha256> README.md
+//! file 755 <sha256> scripts/build.sh
+//! ```
+
+use crate::output::Archive;
+use crate::pristine::{Base32, Merkle};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// First line of manifests in the text format
+const HEADER: &str = "atomic-manifest 1";
+
+/// Digest of the contents of a file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileDigest {
+    /// SHA-256 of the contents, in lowercase hexadecimal
+    pub sha256: String,
+    pub executable: bool,
+}
+
+impl FileDigest {
+    /// Digest of a file with contents `contents`
+    pub fn new(contents: &[u8], executable: bool) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(contents);
+        Self::finish(hasher, executable)
+    }
+
+    fn finish(hasher: Sha256, executable: bool) -> Self {
+        FileDigest {
+            sha256: data_encoding::HEXLOWER.encode(&hasher.finalize()),
+            executable,
+        }
+    }
+}
+
+/// Files of a tree, by path relative to its root
+pub type Files = BTreeMap<String, FileDigest>;
+
+/// The files of a channel at a state
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub channel: String,
+    pub state: Merkle,
+    pub files: Files,
+}
+
+/// A difference between a manifest and a copy of its files
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Mismatch {
+    /// The file is in the manifest but not in the copy
+    Missing { path: String },
+    /// The file is in the copy but not in the manifest
+    Unexpected { path: String },
+    /// The contents of the file differ
+    Modified { path: String },
+    /// The file should be executable, or shouldn't
+    Permissions { path: String, executable: bool },
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Mismatch::Missing { path } => write!(fmt, "missing: {}", path),
+            Mismatch::Unexpected { path } => write!(fmt, "unexpected: {}", path),
+            Mismatch::Modified { path } => write!(fmt, "modified: {}", path),
+            Mismatch::Permissions { path, executable } => {
+                if *executable {
+                    write!(fmt, "not executable: {}", path)
+                } else {
+                    write!(fmt, "executable: {}", path)
+                }
+            }
+        }
+    }
+}
+
+impl Manifest {
+    /// Compare the files of a copy with this manifest, returning the
+    /// differences ordered by path.
+    pub fn verify(&self, files: &Files) -> Vec<Mismatch> {
+        let mut mismatches = Vec::new();
+        for (path, expected) in self.files.iter() {
+            match files.get(path) {
+                None => mismatches.push(Mismatch::Missing { path: path.clone() }),
+                Some(found) if found.sha256 != expected.sha256 => {
+                    mismatches.push(Mismatch::Modified { path: path.clone() })
+                }
+                Some(found) if found.executable != expected.executable => {
+                    mismatches.push(Mismatch::Permissions {
+                        path: path.clone(),
+                        executable: expected.executable,
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+        for path in files.keys() {
+            if !self.files.contains_key(path) {
+                mismatches.push(Mismatch::Unexpected { path: path.clone() })
+            }
+        }
+        mismatches.sort_by(|a, b| a.path().cmp(b.path()));
+        mismatches
+    }
+
+    /// Compare the files below `root`, e.g. a working copy or an
+    /// extracted archive, with this manifest. The `.atomic` directory is
+    /// skipped. Executable bits are only compared on Unix.
+    pub fn verify_dir<P: AsRef<Path>>(&self, root: P) -> Result<Vec<Mismatch>, std::io::Error> {
+        let mut mismatches = self.verify(&files_of_dir(root.as_ref())?);
+        if !cfg!(unix) {
+            mismatches.retain(|m| !matches!(m, Mismatch::Permissions { .. }))
+        }
+        Ok(mismatches)
+    }
+
+    /// Compare a gzipped tarball, as produced by
+    /// [`Tarball`](crate::output::Tarball), with this manifest. `prefix`
+    /// is stripped from the paths of the archive.
+    #[cfg(feature = "tarball")]
+    pub fn verify_tarball<R: std::io::Read>(
+        &self,
+        r: R,
+        prefix: Option<&str>,
+    ) -> Result<Vec<Mismatch>, std::io::Error> {
+        Ok(self.verify(&files_of_tarball(r, prefix)?))
+    }
+}
+
+impl Mismatch {
+    pub fn path(&self) -> &str {
+        match self {
+            Mismatch::Missing { path }
+            | Mismatch::Unexpected { path }
+            | Mismatch::Modified { path }
+            | Mismatch::Permissions { path, .. } => path,
+        }
+    }
+}
+
+impl std::fmt::Display for Manifest {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(fmt, "{}", HEADER)?;
+        writeln!(fmt, "channel {}", self.channel)?;
+        writeln!(fmt, "state {}", self.state.to_base32())?;
+        for (path, digest) in self.files.iter() {
+            let mode = if digest.executable { 755 } else { 644 };
+            writeln!(fmt, "file {} {} {}", mode, digest.sha256, path)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Manifest {
+    type Err = crate::ParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |line: &str| crate::ParseError {
+            s: line.to_string(),
+        };
+        let mut lines = s.lines();
+        if lines.next() != Some(HEADER) {
+            return Err(err(s.lines().next().unwrap_or("")));
+        }
+        let mut channel = None;
+        let mut state = None;
+        let mut files = Files::new();
+        for line in lines {
+            if let Some(c) = line.strip_prefix("channel ") {
+                channel = Some(c.to_string())
+            } else if let Some(m) = line.strip_prefix("state ") {
+                state = Some(Merkle::from_base32(m.as_bytes()).ok_or_else(|| err(line))?)
+            } else if let Some(f) = line.strip_prefix("file ") {
+                let mut fields = f.splitn(3, ' ');
+                let executable = match fields.next() {
+                    Some("644") => false,
+                    Some("755") => true,
+                    _ => return Err(err(line)),
+                };
+                let sha256 = fields.next().ok_or_else(|| err(line))?;
+                let path = fields.next().ok_or_else(|| err(line))?;
+                files.insert(
+                    path.to_string(),
+                    FileDigest {
+                        sha256: sha256.to_string(),
+                        executable,
+                    },
+                );
+            } else if !line.is_empty() {
+                return Err(err(line));
+            }
+        }
+        Ok(Manifest {
+            channel: channel.ok_or_else(|| err("missing channel"))?,
+            state: state.ok_or_else(|| err("missing state"))?,
+            files,
+        })
+    }
+}
+
+/// The files below `root`, skipping the `.atomic` directory
+pub fn files_of_dir(root: &Path) -> Result<Files, std::io::Error> {
+    let mut files = Files::new();
+    let mut stack = vec![(root.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = stack.pop() {
+        for entry in std::fs::read_dir(&dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if prefix.is_empty() && name == crate::DOT_DIR {
+                continue;
+            }
+            let mut path = prefix.clone();
+            crate::path::push(&mut path, &name);
+            let meta = entry.metadata()?;
+            if meta.is_dir() {
+                stack.push((entry.path(), path))
+            } else {
+                let contents = std::fs::read(entry.path())?;
+                files.insert(path, FileDigest::new(&contents, is_executable(&meta)));
+            }
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(unix)]
+fn is_executable(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    meta.permissions().mode() & 0o100 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_: &std::fs::Metadata) -> bool {
+    false
+}
+
+/// The files of a gzipped tarball, with `prefix` stripped from their paths
+#[cfg(feature = "tarball")]
+pub fn files_of_tarball<R: std::io::Read>(
+    r: R,
+    prefix: Option<&str>,
+) -> Result<Files, std::io::Error> {
+    use std::io::Read;
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(r));
+    let mut files = Files::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let path = match prefix {
+            Some(prefix) => match path.strip_prefix(prefix) {
+                Some(path) => path.to_string(),
+                None => continue,
+            },
+            None => path,
+        };
+        let executable = entry.header().mode()? & 0o100 != 0;
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        files.insert(path, FileDigest::new(&contents, executable));
+    }
+    Ok(files)
+}
+
+/// An [`Archive`] hashing the files instead of storing them
+#[derive(Default)]
+pub(crate) struct ManifestArchive {
+    pub files: Files,
+}
+
+pub(crate) struct ManifestFile {
+    path: String,
+    executable: bool,
+    hasher: Sha256,
+}
+
+impl std::io::Write for ManifestFile {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
+        self.hasher.update(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+}
+
+impl Archive for ManifestArchive {
+    type File = ManifestFile;
+    type Error = std::convert::Infallible;
+    fn create_file(&mut self, path: &str, _mtime: u64, perm: u16) -> Self::File {
+        ManifestFile {
+            path: path.to_string(),
+            executable: perm & 0o100 != 0,
+            hasher: Sha256::new(),
+        }
+    }
+    fn create_dir(&mut self, _: &str, _: u64, _: u16) -> Result<(), Self::Error> {
+        Ok(())
+    }
+    fn close_file(&mut self, f: Self::File) -> Result<(), Self::Error> {
+        self.files
+            .insert(f.path, FileDigest::finish(f.hasher, f.executable));
+        Ok(())
+    }
+}
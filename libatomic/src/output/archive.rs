@@ -65,12 +65,12 @@ impl<W: std::io::Write> Archive for Tarball<W> {
                 path.to_string()
             },
             mtime,
-            permissions: permissions & !self.umask,
+            permissions: crate::permissions::masked(permissions, self.umask),
         }
     }
     fn create_dir(&mut self, path: &str, mtime: u64, permissions: u16) -> Result<(), Self::Error> {
         let mut header = tar::Header::new_gnu();
-        header.set_mode((permissions & !self.umask) as u32);
+        header.set_mode(crate::permissions::masked(permissions, self.umask) as u32);
         header.set_mtime(mtime);
         header.set_entry_type(tar::EntryType::Directory);
         if let Some(ref prefix) = self.prefix {
@@ -259,7 +259,7 @@ pub(crate) fn archive<
                         &mut next_files,
                     )?;
                     if len == next_files.len() {
-                        arch.create_dir(&path, latest_touch, 0o777)
+                        arch.create_dir(&path, latest_touch, crate::permissions::DIR_MODE)
                             .map_err(ArchiveError::A)?;
                     }
                 } else {
@@ -270,11 +270,9 @@ pub(crate) fn archive<
                         output_item.pos,
                         false,
                     )?;
-                    let perms = if output_item.meta.permissions() & 0o100 != 0 {
-                        0o777
-                    } else {
-                        0o666
-                    };
+                    let perms = crate::permissions::file_mode(crate::permissions::is_executable(
+                        output_item.meta.permissions(),
+                    ));
                     let mut f = arch.create_file(&path, latest_touch, perms);
                     {
                         let mut f = crate::vertex_buffer::ConflictsWriter::new(
@@ -336,6 +336,7 @@ pub fn output_file<
     v0: Position<NodeId>,
     out: &mut V,
 ) -> Result<(), FileError<C::Error, T>> {
+    let _span = phase_span!("output_file", vertex = ?v0);
     let mut forward = Vec::new();
     let mut graph = {
         let txn = txn.read();
@@ -237,6 +237,7 @@ fn output_repository<
 where
     T::Channel: Send + Sync + 'static,
 {
+    let _span = phase_span!("output_repository", workers = n_workers);
     let work = Arc::new(crossbeam_deque::Injector::new());
     let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
     let mut threads = Vec::new();
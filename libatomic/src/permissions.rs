@@ -0,0 +1,122 @@
+//! File permissions across platforms.
+//!
+//! Changes only record whether files are executable (the `0o100` bit of
+//! [`crate::pristine::InodeMetadata`]). On Unix, outputting a file sets
+//! or clears its user executable bit and keeps its other bits. Windows
+//! has no executable bit: files of the working copy have no mode, and
+//! outputting them leaves their permissions alone. Archives carry Unix
+//! modes on every platform, computed from the executable bit and a umask.
+
+use std::path::Path;
+
+/// Mode of archived directories, before applying the umask
+pub const DIR_MODE: u16 = 0o777;
+
+/// Mode of archived executable files, before applying the umask
+pub const EXECUTABLE_MODE: u16 = 0o777;
+
+/// Mode of other archived files, before applying the umask
+pub const FILE_MODE: u16 = 0o666;
+
+/// Whether `permissions`, as recorded in a change, make a file executable
+pub fn is_executable(permissions: u16) -> bool {
+    permissions & 0o100 != 0
+}
+
+/// Mode of an archived file, executable or not
+pub fn file_mode(executable: bool) -> u16 {
+    if executable {
+        EXECUTABLE_MODE
+    } else {
+        FILE_MODE
+    }
+}
+
+/// `mode` without the bits of `umask`, and without anything but the
+/// permission bits, so that no umask can produce set-uid files
+pub fn masked(mode: u16, umask: u16) -> u16 {
+    mode & !umask & 0o777
+}
+
+/// The mode of a file of the working copy, if the platform has modes
+#[cfg(not(windows))]
+pub fn mode(meta: &std::fs::Metadata) -> Option<usize> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode() as usize)
+}
+
+/// The mode of a file of the working copy, if the platform has modes
+#[cfg(windows)]
+pub fn mode(_: &std::fs::Metadata) -> Option<usize> {
+    None
+}
+
+/// Set or clear the user executable bit of `path`, keeping its other
+/// bits. Clearing it also clears the group and other executable bits.
+#[cfg(not(windows))]
+pub fn set_executable(path: &Path, executable: bool) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let mut current = std::fs::metadata(path)?.permissions();
+    debug!(
+        "setting {:?} executable: {:?} (currently {:?})",
+        path, executable, current
+    );
+    if executable {
+        current.set_mode(current.mode() | 0o100);
+    } else {
+        current.set_mode(current.mode() & ((!0o777) | 0o666));
+    }
+    std::fs::set_permissions(path, current)
+}
+
+/// Set or clear the user executable bit of `path`: Windows has none.
+#[cfg(windows)]
+pub fn set_executable(_: &Path, _: bool) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_modes() {
+        assert!(is_executable(0o100));
+        assert!(!is_executable(0o644));
+        assert_eq!(masked(file_mode(true), 0o022), 0o755);
+        assert_eq!(masked(file_mode(false), 0o022), 0o644);
+        assert_eq!(masked(DIR_MODE, 0o077), 0o700);
+        assert_eq!(masked(0o4777, 0), 0o777);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn set_executable_unix() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("script");
+        std::fs::write(&path, b"#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        set_executable(&path, true).unwrap();
+        let meta = std::fs::metadata(&path).unwrap();
+        assert_eq!(mode(&meta).unwrap() & 0o777, 0o740);
+        set_executable(&path, false).unwrap();
+        let meta = std::fs::metadata(&path).unwrap();
+        assert_eq!(mode(&meta).unwrap() & 0o777, 0o640);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn set_executable_windows() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("script.bat");
+        std::fs::write(&path, b"@echo off\r\n").unwrap();
+        let before = std::fs::metadata(&path).unwrap().permissions();
+        set_executable(&path, true).unwrap();
+        set_executable(&path, false).unwrap();
+        let meta = std::fs::metadata(&path).unwrap();
+        assert_eq!(meta.permissions(), before);
+        assert!(mode(&meta).is_none());
+    }
+}
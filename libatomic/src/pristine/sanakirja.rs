@@ -11,8 +11,18 @@ use std::sync::Arc;
 #[derive(Clone)]
 pub struct Pristine {
     pub env: Arc<::sanakirja::Env>,
+    write_guard: Option<Arc<dyn WriteGuard>>,
 }
 
+/// Taken by [`Pristine::mut_txn_begin`] before the transaction starts,
+/// and held until the transaction is committed or dropped. Used to
+/// serialise the writers of a pristine across processes.
+pub trait WriteGuard: Send + Sync {
+    fn acquire(&self) -> Result<Box<dyn std::any::Any + Send + Sync>, WriteGuardError>;
+}
+
+pub type WriteGuardError = Box<dyn std::error::Error + Send + Sync>;
+
 pub(crate) type P<K, V> = btree::page::Page<K, V>;
 pub type Db<K, V> = btree::Db<K, V>;
 pub(crate) type UP<K, V> = btree::page_unsized::Page<K, V>;
@@ -32,6 +42,8 @@ pub enum SanakirjaError {
     ChannelRc { c: String },
     #[error("Pristine version mismatch. Cloning over the network can fix this.")]
     Version,
+    #[error(transparent)]
+    WriteGuard(WriteGuardError),
 }
 
 impl std::convert::From<::sanakirja::CRCError> for SanakirjaError {
@@ -84,7 +96,10 @@ impl Pristine {
     pub fn new_with_size<P: AsRef<Path>>(name: P, size: u64) -> Result<Self, SanakirjaError> {
         let env = ::sanakirja::Env::new(name, size, 2);
         match env {
-            Ok(env) => Ok(Pristine { env: Arc::new(env) }),
+            Ok(env) => Ok(Pristine {
+                env: Arc::new(env),
+                write_guard: None,
+            }),
             Err(::sanakirja::Error::IO(e)) => {
                 if let std::io::ErrorKind::WouldBlock = e.kind() {
                     Err(SanakirjaError::PristineLocked)
@@ -102,6 +117,7 @@ impl Pristine {
     ) -> Result<Self, SanakirjaError> {
         Ok(Pristine {
             env: Arc::new(::sanakirja::Env::new_nolock(name, size, 2)?),
+            write_guard: None,
         })
     }
     pub fn new_anon() -> Result<Self, SanakirjaError> {
@@ -110,8 +126,15 @@ impl Pristine {
     pub fn new_anon_with_size(size: u64) -> Result<Self, SanakirjaError> {
         Ok(Pristine {
             env: Arc::new(::sanakirja::Env::new_anon(size, 2)?),
+            write_guard: None,
         })
     }
+
+    /// Take `guard` before starting each mutable transaction.
+    pub fn with_write_guard(mut self, guard: Arc<dyn WriteGuard>) -> Self {
+        self.write_guard = Some(guard);
+        self
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -143,6 +166,8 @@ pub enum Root {
     // Consolidating tags tables
     TagsMetadata,
     TagAttributionSummaries,
+    // Workflow tables
+    WorkflowStates,
 }
 
 // Semantic versioning encoded as u64: (major << 32) | (minor << 16) | patch
@@ -225,6 +250,7 @@ impl Pristine {
                 txn,
                 counter: 0,
                 cur_channel: None,
+                _write_guard: None,
             })
         }
         debug!("txn begin done");
@@ -241,6 +267,11 @@ impl Pristine {
     }
 
     pub fn mut_txn_begin(&self) -> Result<MutTxn<()>, SanakirjaError> {
+        let write_guard = if let Some(ref guard) = self.write_guard {
+            Some(guard.acquire().map_err(SanakirjaError::WriteGuard)?)
+        } else {
+            None
+        };
         unsafe {
             let mut txn = ::sanakirja::Env::mut_txn_begin(self.env.clone()).unwrap();
             if let Some(version) = txn.root(Root::Version as usize) {
@@ -350,6 +381,7 @@ impl Pristine {
                 txn,
                 counter: 0,
                 cur_channel: None,
+                _write_guard: write_guard,
             })
         }
     }
@@ -400,6 +432,8 @@ pub struct GenericTxn<T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::s
     open_remotes: Mutex<HashMap<RemoteId, RemoteRef<Self>>>,
     counter: usize,
     cur_channel: Option<String>,
+    /// Held until the transaction is committed or dropped
+    _write_guard: Option<Box<dyn std::any::Any + Send + Sync>>,
 }
 
 direct_repr!(SerializedPublicKey);
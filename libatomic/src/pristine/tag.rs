@@ -557,6 +557,256 @@ impl SemanticVersion {
             build_metadata: None,
         }
     }
+
+    /// Compare precedences, following semver.org: build metadata is
+    /// ignored, and a pre-release comes before its release.
+    pub fn cmp_precedence(&self, other: &Self) -> std::cmp::Ordering {
+        use std::cmp::Ordering;
+        (self.major, self.minor, self.patch)
+            .cmp(&(other.major, other.minor, other.patch))
+            .then_with(|| match (&self.pre_release, &other.pre_release) {
+                (None, None) => Ordering::Equal,
+                (None, Some(_)) => Ordering::Greater,
+                (Some(_), None) => Ordering::Less,
+                (Some(a), Some(b)) => cmp_pre_release(a, b),
+            })
+    }
+}
+
+/// Compare pre-releases identifier by identifier: numeric identifiers
+/// numerically and before alphanumeric ones, which compare in ASCII order,
+/// and a shorter list of identifiers first if all others are equal.
+fn cmp_pre_release(a: &str, b: &str) -> std::cmp::Ordering {
+    use std::cmp::Ordering;
+    let mut a = a.split('.');
+    let mut b = b.split('.');
+    loop {
+        let o = match (a.next(), b.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) => match (x.parse::<u64>(), y.parse::<u64>()) {
+                (Ok(x), Ok(y)) => x.cmp(&y),
+                (Ok(_), Err(_)) => Ordering::Less,
+                (Err(_), Ok(_)) => Ordering::Greater,
+                (Err(_), Err(_)) => x.cmp(y),
+            },
+        };
+        if o != Ordering::Equal {
+            return o;
+        }
+    }
+}
+
+/// Semver precedence, with build metadata as a last tie-break so that
+/// the order agrees with equality.
+impl Ord for SemanticVersion {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.cmp_precedence(other)
+            .then_with(|| self.build_metadata.cmp(&other.build_metadata))
+    }
+}
+
+impl PartialOrd for SemanticVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A comparison operator of a [`VersionRange`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VersionOp {
+    Exact,
+    Greater,
+    GreaterEq,
+    Less,
+    LessEq,
+    Tilde,
+    Caret,
+}
+
+/// One comparison of a [`VersionRange`], on a version where the minor and
+/// patch numbers may be missing or wildcards (`1`, `1.x`, `1.2.*`)
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct VersionComparator {
+    op: VersionOp,
+    major: u32,
+    minor: Option<u32>,
+    patch: Option<u32>,
+    pre_release: Option<String>,
+}
+
+impl VersionComparator {
+    fn parse(s: &str) -> Result<Self, String> {
+        let (op, rest) = [
+            (">=", VersionOp::GreaterEq),
+            ("<=", VersionOp::LessEq),
+            (">", VersionOp::Greater),
+            ("<", VersionOp::Less),
+            ("=", VersionOp::Exact),
+            ("~", VersionOp::Tilde),
+            ("^", VersionOp::Caret),
+        ]
+        .iter()
+        .find_map(|(p, op)| s.strip_prefix(p).map(|rest| (*op, rest)))
+        .unwrap_or((VersionOp::Exact, s));
+        let rest = rest.trim();
+        let rest = rest.strip_prefix('v').unwrap_or(rest);
+        // Build metadata doesn't change precedence.
+        let rest = rest.split('+').next().unwrap_or(rest);
+        let (core, pre_release) = match rest.split_once('-') {
+            Some((core, pre)) => (core, Some(pre.to_string())),
+            None => (rest, None),
+        };
+        let mut parts = core.split('.');
+        let mut number = |name: &str, required: bool| -> Result<Option<u32>, String> {
+            match parts.next() {
+                None if !required => Ok(None),
+                Some("x" | "X" | "*") if !required => Ok(None),
+                Some(n) => n
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| format!("Invalid {} version in '{}'", name, s)),
+                None => Err(format!("Missing {} version in '{}'", name, s)),
+            }
+        };
+        let major = number("major", true)?.unwrap_or(0);
+        let minor = number("minor", false)?;
+        let patch = number("patch", false)?;
+        if parts.next().is_some() || (minor.is_none() && patch.is_some()) {
+            return Err(format!("Invalid version '{}'", s));
+        }
+        if pre_release.is_some() && patch.is_none() {
+            return Err(format!("A pre-release needs a full version in '{}'", s));
+        }
+        Ok(VersionComparator {
+            op,
+            major,
+            minor,
+            patch,
+            pre_release,
+        })
+    }
+
+    fn version(major: u32, minor: u32, patch: u32) -> SemanticVersion {
+        SemanticVersion {
+            major,
+            minor,
+            patch,
+            pre_release: None,
+            build_metadata: None,
+        }
+    }
+
+    /// Smallest version matching the given numbers
+    fn lower(&self) -> SemanticVersion {
+        SemanticVersion {
+            pre_release: self.pre_release.clone(),
+            ..Self::version(self.major, self.minor.unwrap_or(0), self.patch.unwrap_or(0))
+        }
+    }
+
+    /// First version after the versions matching the given numbers, if
+    /// some numbers are missing
+    fn next(&self) -> Option<SemanticVersion> {
+        match (self.minor, self.patch) {
+            (None, _) => Some(Self::version(self.major + 1, 0, 0)),
+            (Some(minor), None) => Some(Self::version(self.major, minor + 1, 0)),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, v: &SemanticVersion) -> bool {
+        use std::cmp::Ordering;
+        let lower = self.lower();
+        let cmp = v.cmp_precedence(&lower);
+        let below = |upper: SemanticVersion| v.cmp_precedence(&upper) == Ordering::Less;
+        match self.op {
+            VersionOp::Exact => match self.next() {
+                Some(next) => cmp != Ordering::Less && below(next),
+                None => cmp == Ordering::Equal,
+            },
+            VersionOp::Greater => match self.next() {
+                Some(next) => !below(next),
+                None => cmp == Ordering::Greater,
+            },
+            VersionOp::GreaterEq => cmp != Ordering::Less,
+            VersionOp::Less => cmp == Ordering::Less,
+            VersionOp::LessEq => match self.next() {
+                Some(next) => below(next),
+                None => cmp != Ordering::Greater,
+            },
+            VersionOp::Tilde => {
+                let upper = match self.minor {
+                    Some(minor) => Self::version(self.major, minor + 1, 0),
+                    None => Self::version(self.major + 1, 0, 0),
+                };
+                cmp != Ordering::Less && below(upper)
+            }
+            VersionOp::Caret => {
+                let upper = match (self.major, self.minor, self.patch) {
+                    (0, None, _) => Self::version(1, 0, 0),
+                    (0, Some(0), None) => Self::version(0, 1, 0),
+                    (0, Some(0), Some(patch)) => Self::version(0, 0, patch + 1),
+                    (0, Some(minor), _) => Self::version(0, minor + 1, 0),
+                    (major, _, _) => Self::version(major + 1, 0, 0),
+                };
+                cmp != Ordering::Less && below(upper)
+            }
+        }
+    }
+}
+
+/// A range of versions, such as `>=1.2.0, <2`, `^1.4`, `~1.2.3` or `1.x`:
+/// comparators separated by commas or spaces, which must all match. `*`
+/// matches every release.
+///
+/// As with Cargo, pre-releases only match if one of the comparators is a
+/// pre-release of the same `major.minor.patch`, so that `>=1.2.0` doesn't
+/// match `2.0.0-beta.1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionRange {
+    comparators: Vec<VersionComparator>,
+}
+
+impl VersionRange {
+    pub fn parse(range: &str) -> Result<Self, String> {
+        let mut comparators = Vec::new();
+        // Operators may be separated from their version: `>= 1.2`.
+        let mut pending_op: Option<&str> = None;
+        for token in range.split(|c: char| c == ',' || c.is_whitespace()) {
+            if token.is_empty() || token == "*" || token == "x" || token == "X" {
+                continue;
+            }
+            if token.chars().all(|c| "<>=~^".contains(c)) {
+                pending_op = Some(token);
+                continue;
+            }
+            let comparator = match pending_op.take() {
+                Some(op) => VersionComparator::parse(&format!("{}{}", op, token))?,
+                None => VersionComparator::parse(token)?,
+            };
+            comparators.push(comparator);
+        }
+        if let Some(op) = pending_op {
+            return Err(format!("Missing version after '{}' in '{}'", op, range));
+        }
+        Ok(VersionRange { comparators })
+    }
+
+    pub fn matches(&self, version: &SemanticVersion) -> bool {
+        if version.pre_release.is_some() {
+            let allowed = self.comparators.iter().any(|c| {
+                c.pre_release.is_some()
+                    && (c.major, c.minor, c.patch)
+                        == (version.major, Some(version.minor), Some(version.patch))
+            });
+            if !allowed {
+                return false;
+            }
+        }
+        self.comparators.iter().all(|c| c.matches(version))
+    }
 }
 
 impl Tag {
@@ -942,8 +1192,65 @@ impl TagAttributionSummary {
             .1
             .saturating_sub(self.creation_time_span.0)
     }
+
+    /// Copies the change counts and providers of this summary into tag
+    /// metadata, under the `attribution.` keys. Providers are written as
+    /// `provider:count`, comma-separated and sorted by name.
+    pub fn insert_into_metadata(&self, metadata: &mut std::collections::HashMap<String, String>) {
+        metadata.insert(
+            TAG_ATTRIBUTION_TOTAL.to_string(),
+            self.total_changes.to_string(),
+        );
+        metadata.insert(
+            TAG_ATTRIBUTION_AI_ASSISTED.to_string(),
+            self.ai_assisted_changes.to_string(),
+        );
+        metadata.insert(
+            TAG_ATTRIBUTION_HUMAN.to_string(),
+            self.human_authored_changes.to_string(),
+        );
+        let mut providers: Vec<_> = self
+            .ai_provider_stats
+            .iter()
+            .map(|(name, stats)| format!("{}:{}", name, stats.change_count))
+            .collect();
+        providers.sort();
+        metadata.insert(TAG_ATTRIBUTION_PROVIDERS.to_string(), providers.join(","));
+    }
+
+    /// Reads the counts written by [`Self::insert_into_metadata`]: total,
+    /// AI-assisted and human-authored changes, and changes per provider.
+    /// Returns `None` if the tag was created without attribution statistics.
+    pub fn from_metadata(
+        metadata: &std::collections::HashMap<String, String>,
+    ) -> Option<(u64, u64, u64, Vec<(String, u64)>)> {
+        let count = |key: &str| metadata.get(key)?.parse().ok();
+        let providers = metadata
+            .get(TAG_ATTRIBUTION_PROVIDERS)?
+            .split(',')
+            .filter_map(|p| {
+                let (name, n) = p.rsplit_once(':')?;
+                Some((name.to_string(), n.parse().ok()?))
+            })
+            .collect();
+        Some((
+            count(TAG_ATTRIBUTION_TOTAL)?,
+            count(TAG_ATTRIBUTION_AI_ASSISTED)?,
+            count(TAG_ATTRIBUTION_HUMAN)?,
+            providers,
+        ))
+    }
 }
 
+/// Tag metadata key of the number of consolidated changes
+pub const TAG_ATTRIBUTION_TOTAL: &str = "attribution.total_changes";
+/// Tag metadata key of the number of AI-assisted consolidated changes
+pub const TAG_ATTRIBUTION_AI_ASSISTED: &str = "attribution.ai_assisted_changes";
+/// Tag metadata key of the number of human-authored consolidated changes
+pub const TAG_ATTRIBUTION_HUMAN: &str = "attribution.human_authored_changes";
+/// Tag metadata key of the AI providers of the consolidated changes
+pub const TAG_ATTRIBUTION_PROVIDERS: &str = "attribution.ai_providers";
+
 impl ProviderStats {
     /// Creates a new empty provider stats instance.
     pub fn new() -> Self {
@@ -1129,6 +1436,39 @@ mod tests {
         assert!(!txn.del_tag(&tag_hash).unwrap());
     }
 
+    #[test]
+    fn test_attribution_summary_metadata() {
+        let mut summary = TagAttributionSummary::new(Hash::NONE);
+        summary.total_changes = 5;
+        summary.ai_assisted_changes = 3;
+        summary.human_authored_changes = 2;
+        let mut anthropic = ProviderStats::new();
+        anthropic.add_change(0.9, "claude".to_string(), "Complete".to_string());
+        anthropic.add_change(0.7, "claude".to_string(), "Partial".to_string());
+        summary
+            .ai_provider_stats
+            .insert("anthropic".to_string(), anthropic);
+        let mut openai = ProviderStats::new();
+        openai.add_change(0.8, "gpt-4".to_string(), "Review".to_string());
+        summary
+            .ai_provider_stats
+            .insert("openai".to_string(), openai);
+
+        let mut metadata = std::collections::HashMap::new();
+        assert!(TagAttributionSummary::from_metadata(&metadata).is_none());
+        summary.insert_into_metadata(&mut metadata);
+        assert_eq!(metadata[TAG_ATTRIBUTION_PROVIDERS], "anthropic:2,openai:1");
+        assert_eq!(
+            TagAttributionSummary::from_metadata(&metadata),
+            Some((
+                5,
+                3,
+                2,
+                vec![("anthropic".to_string(), 2), ("openai".to_string(), 1)]
+            ))
+        );
+    }
+
     #[test]
     fn test_tag_attribution_database_operations() {
         use crate::pristine::sanakirja::*;
@@ -1282,4 +1622,71 @@ mod tests {
             .unwrap()
             .is_none());
     }
+
+    #[test]
+    fn test_semantic_version_order() {
+        let v = |s: &str| SemanticVersion::parse(s).unwrap();
+        let mut versions = vec![
+            v("1.10.0"),
+            v("1.2.0"),
+            v("1.0.0"),
+            v("1.0.0-rc.1"),
+            v("1.0.0-alpha.beta"),
+            v("1.0.0-alpha.1"),
+            v("1.0.0-alpha"),
+            v("0.9.12"),
+        ];
+        versions.sort();
+        let sorted: Vec<_> = versions.iter().map(|v| v.to_string()).collect();
+        assert_eq!(
+            sorted,
+            [
+                "0.9.12",
+                "1.0.0-alpha",
+                "1.0.0-alpha.1",
+                "1.0.0-alpha.beta",
+                "1.0.0-rc.1",
+                "1.0.0",
+                "1.2.0",
+                "1.10.0",
+            ]
+        );
+        assert_eq!(
+            v("1.0.0+a").cmp_precedence(&v("1.0.0+b")),
+            std::cmp::Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn test_version_range() {
+        let v = |s: &str| SemanticVersion::parse(s).unwrap();
+        let matches =
+            |range: &str, version: &str| VersionRange::parse(range).unwrap().matches(&v(version));
+        assert!(matches(">=1.2.0", "1.2.0"));
+        assert!(matches(">=1.2.0", "3.0.0"));
+        assert!(!matches(">=1.2.0", "1.1.9"));
+        assert!(matches(">=1.2.0, <2", "1.9.0"));
+        assert!(!matches(">= 1.2.0 < 2", "2.0.0"));
+        assert!(matches("1.x", "1.4.2"));
+        assert!(!matches("1.x", "2.0.0"));
+        assert!(matches("1.2.*", "1.2.7"));
+        assert!(!matches("1.2.*", "1.3.0"));
+        assert!(matches("*", "0.1.0"));
+        assert!(matches("^1.4", "1.9.0"));
+        assert!(!matches("^1.4", "1.3.0"));
+        assert!(!matches("^0.2.3", "0.3.0"));
+        assert!(matches("~1.2.3", "1.2.9"));
+        assert!(!matches("~1.2.3", "1.3.0"));
+        assert!(matches(">1", "2.0.0"));
+        assert!(!matches(">1", "1.9.0"));
+        assert!(matches("<=1.2", "1.2.9"));
+        // Pre-releases only match ranges naming them.
+        assert!(!matches(">=1.2.0", "2.0.0-beta.1"));
+        assert!(matches(">=2.0.0-beta", "2.0.0-beta.1"));
+        assert!(!matches(">=2.0.0-beta", "2.1.0-beta.1"));
+
+        assert!(VersionRange::parse(">=").is_err());
+        assert!(VersionRange::parse("1.a").is_err());
+        assert!(VersionRange::parse("1.x.3").is_err());
+    }
 }
@@ -32,6 +32,8 @@ pub enum RecordError<C: std::error::Error + 'static, W: std::error::Error, T: Gr
     PathNotInRepo(String),
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error(transparent)]
+    ForbiddenPath(#[from] crate::forbidden::ForbiddenPathViolation),
 }
 
 impl<C: std::error::Error, W: std::error::Error, T: GraphTxnT + TreeTxnT> std::fmt::Debug
@@ -47,6 +49,7 @@ impl<C: std::error::Error, W: std::error::Error, T: GraphTxnT + TreeTxnT> std::f
             RecordError::Diff(e) => std::fmt::Debug::fmt(e, fmt),
             RecordError::PathNotInRepo(p) => write!(fmt, "Path not in repository: {}", p),
             RecordError::Io(e) => std::fmt::Debug::fmt(e, fmt),
+            RecordError::ForbiddenPath(e) => std::fmt::Debug::fmt(e, fmt),
         }
     }
 }
@@ -72,6 +75,8 @@ pub struct Builder {
     pub force_rediff: bool,
     pub ignore_missing: bool,
     pub contents: Arc<Mutex<Vec<u8>>>,
+    /// Paths that the recorded hunks may not add or edit.
+    pub forbidden_paths: crate::forbidden::ForbiddenPaths,
     new_root: Arc<Mutex<Option<(Position<Option<NodeId>>, u64)>>>,
 }
 
@@ -117,6 +122,7 @@ impl Default for Builder {
             ignore_missing: false,
             deleted_vertices: Arc::new(Mutex::new(HashSet::default())),
             contents: Arc::new(Mutex::new(Vec::new())),
+            forbidden_paths: crate::forbidden::ForbiddenPaths::new(),
             new_root: Arc::new(Mutex::new(None)),
         }
     }
@@ -150,6 +156,23 @@ impl Builder {
         }
     }
 
+    /// Check the hunks recorded since `self.rec[first]` against the
+    /// forbidden paths.
+    fn check_forbidden_paths(
+        &self,
+        first: usize,
+    ) -> Result<(), crate::forbidden::ForbiddenPathViolation> {
+        if self.forbidden_paths.is_empty() {
+            return Ok(());
+        }
+        for rec in &self.rec[first.min(self.rec.len())..] {
+            let rec = rec.lock();
+            self.forbidden_paths
+                .check_hunks(rec.actions.iter(), |l| &l.path)?
+        }
+        Ok(())
+    }
+
     /// Finish the recording.
     pub fn finish(mut self) -> Recorded {
         if self.rec.is_empty() {
@@ -346,6 +369,7 @@ impl Builder {
         }
         info!("Starting to record");
         let now = std::time::Instant::now();
+        let first = self.rec.len();
         let mut stack = vec![(RecordItem::root(), components(prefix))];
         while let Some((mut item, mut components)) = stack.pop() {
             debug!("stack.pop() = Some({:?})", item);
@@ -548,6 +572,7 @@ impl Builder {
         }
         crate::TIMERS.lock().unwrap().record += now.elapsed();
         info!("record done");
+        self.check_forbidden_paths(first)?;
         Ok(())
     }
 
@@ -568,6 +593,7 @@ impl Builder {
     {
         info!("Starting to record");
         let now = std::time::Instant::now();
+        let first = self.rec.len();
         let mut stack = vec![(RecordItem::root(), components(prefix))];
         while let Some((mut item, mut components)) = stack.pop() {
             debug!("stack.pop() = Some({:?})", item);
@@ -737,6 +763,7 @@ impl Builder {
         }
         crate::TIMERS.lock().unwrap().record += now.elapsed();
         info!("record done");
+        self.check_forbidden_paths(first)?;
         Ok(())
     }
 
@@ -0,0 +1,484 @@
+//! Squash a run of consecutive changes into a single change.
+//!
+//! Squashing composes the hunks of the changes, in the order in which
+//! they were applied to the channel, into one change whose contents are
+//! the concatenation of the contents of the squashed changes. References
+//! from one squashed change to another become references to the new
+//! change itself, so that the graph obtained by applying the squashed
+//! change is the same as the one obtained by applying the original
+//! changes.
+//!
+//! The squashed changes are then unrecorded from the channel, and
+//! replaced by the new change. This is only possible if no other change
+//! on the channel depends on them.
+use crate::attribution::SerializedAttribution;
+use crate::change::*;
+use crate::changestore::ChangeStore;
+use crate::pristine::*;
+use crate::record::InodeUpdate;
+use crate::unrecord::UnrecordError;
+use crate::{HashMap, HashSet};
+
+#[derive(Error)]
+pub enum SquashError<ChangestoreError: std::error::Error + 'static, T: GraphTxnT + TreeTxnT> {
+    #[error("No change to squash")]
+    Empty,
+    #[error("Changestore error: {0}")]
+    Changestore(ChangestoreError),
+    #[error(transparent)]
+    Txn(#[from] TxnErr<T::GraphError>),
+    #[error(transparent)]
+    Tree(#[from] TreeErr<T::TreeError>),
+    #[error("Change not in channel: {}", hash.to_base32())]
+    ChangeNotInChannel { hash: Hash },
+    #[error("Changes are not consecutive: {} was applied between them", hash.to_base32())]
+    NotConsecutive { hash: Hash },
+    #[error("Change {} is depended upon by {}", hash.to_base32(), dependent.to_base32())]
+    ChangeIsDependedUpon { hash: Hash, dependent: Hash },
+    #[error("Change {} is a tag and cannot be squashed", hash.to_base32())]
+    Tag { hash: Hash },
+    #[error(transparent)]
+    Unrecord(#[from] UnrecordError<ChangestoreError, T>),
+    #[error(transparent)]
+    LocalApply(#[from] crate::apply::LocalApplyError<T>),
+}
+
+impl<C: std::error::Error, T: GraphTxnT + TreeTxnT> std::fmt::Debug for SquashError<C, T> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SquashError::Empty => write!(fmt, "No change to squash"),
+            SquashError::Changestore(e) => std::fmt::Debug::fmt(e, fmt),
+            SquashError::Txn(e) => std::fmt::Debug::fmt(e, fmt),
+            SquashError::Tree(e) => std::fmt::Debug::fmt(e, fmt),
+            SquashError::ChangeNotInChannel { hash } => {
+                write!(fmt, "Change not in channel: {}", hash.to_base32())
+            }
+            SquashError::NotConsecutive { hash } => {
+                write!(fmt, "Changes are not consecutive: {}", hash.to_base32())
+            }
+            SquashError::ChangeIsDependedUpon { hash, dependent } => write!(
+                fmt,
+                "Change {} is depended upon: {}",
+                hash.to_base32(),
+                dependent.to_base32()
+            ),
+            SquashError::Tag { hash } => write!(fmt, "Change is a tag: {}", hash.to_base32()),
+            SquashError::Unrecord(e) => std::fmt::Debug::fmt(e, fmt),
+            SquashError::LocalApply(e) => std::fmt::Debug::fmt(e, fmt),
+        }
+    }
+}
+
+/// Compose `changes`, given in application order, into a single
+/// change.
+///
+/// If `message` is `None`, the message of the first change is used.
+/// The description of the squashed change lists the messages of all
+/// the squashed changes, its authors are the union of their authors,
+/// and its timestamp is the latest of their timestamps. Attribution
+/// metadata is combined (see [`combine_metadata`]), and the unhashed
+/// sections are merged, earlier changes taking precedence, except for
+/// the list of superseded changes, which becomes the squashed changes.
+pub fn compose(changes: &[(Hash, Change)], message: Option<String>) -> Change {
+    let set: HashSet<Hash> = changes.iter().map(|(h, _)| *h).collect();
+    let mut offsets = HashMap::default();
+    let mut len = 0;
+    for (h, c) in changes.iter() {
+        offsets.insert(*h, len as u64);
+        len += c.contents.len();
+    }
+
+    let mut contents = Vec::with_capacity(len);
+    let mut hunks = Vec::new();
+    for (h, c) in changes.iter() {
+        let offset = offsets[h];
+        for hunk in c.changes.iter() {
+            let hunk: Result<_, std::convert::Infallible> = hunk
+                .clone()
+                .atom_map(|a| Ok(rebase_atom(a, offset, &offsets)), |l| l);
+            hunks.push(hunk.unwrap());
+        }
+        contents.extend_from_slice(&c.contents);
+    }
+
+    let mut dependencies = Vec::new();
+    let mut extra_known = Vec::new();
+    for (_, c) in changes.iter() {
+        for d in c.dependencies.iter() {
+            if !set.contains(d) && !dependencies.contains(d) {
+                dependencies.push(*d)
+            }
+        }
+    }
+    for (_, c) in changes.iter() {
+        for d in c.extra_known.iter() {
+            if !set.contains(d) && !dependencies.contains(d) && !extra_known.contains(d) {
+                extra_known.push(*d)
+            }
+        }
+    }
+
+    let contents_hash = {
+        let mut hasher = Hasher::default();
+        hasher.update(&contents);
+        hasher.finish()
+    };
+    let mut change = LocalChange {
+        offsets: Offsets::default(),
+        hashed: Hashed {
+            version: VERSION,
+            header: combine_headers(changes.iter().map(|(_, c)| &c.header), message),
+            dependencies,
+            extra_known,
+            metadata: combine_metadata(changes.iter().map(|(_, c)| &c.metadata[..])),
+            changes: hunks,
+            contents_hash,
+            tag: None,
+        },
+        unhashed: combine_unhashed(changes.iter().map(|(_, c)| c.unhashed.as_ref())),
+        contents,
+    };
+    let hashes: Vec<Hash> = changes.iter().map(|(h, _)| *h).collect();
+    change.set_supersedes(&hashes);
+    change
+}
+
+/// Rewrite an atom of a change at `offset` in the squashed contents:
+/// positions in the change itself or in another squashed change become
+/// positions in the squashed change.
+fn rebase_atom(
+    atom: Atom<Option<Hash>>,
+    offset: u64,
+    offsets: &HashMap<Hash, u64>,
+) -> Atom<Option<Hash>> {
+    let shift = |change: Option<Hash>| -> Option<u64> {
+        match change {
+            None => Some(offset),
+            Some(h) => offsets.get(&h).cloned(),
+        }
+    };
+    let pos = |p: Position<Option<Hash>>| -> Position<Option<Hash>> {
+        if let Some(off) = shift(p.change) {
+            Position {
+                change: None,
+                pos: p.pos + off as usize,
+            }
+        } else {
+            p
+        }
+    };
+    match atom {
+        Atom::NewVertex(v) => Atom::NewVertex(NewVertex {
+            up_context: v.up_context.into_iter().map(pos).collect(),
+            down_context: v.down_context.into_iter().map(pos).collect(),
+            flag: v.flag,
+            start: v.start + offset as usize,
+            end: v.end + offset as usize,
+            inode: pos(v.inode),
+        }),
+        Atom::EdgeMap(e) => Atom::EdgeMap(EdgeMap {
+            edges: e
+                .edges
+                .into_iter()
+                .map(|e| NewEdge {
+                    previous: e.previous,
+                    flag: e.flag,
+                    from: pos(e.from),
+                    to: if let Some(off) = shift(e.to.change) {
+                        Vertex {
+                            change: None,
+                            start: e.to.start + off as usize,
+                            end: e.to.end + off as usize,
+                        }
+                    } else {
+                        e.to
+                    },
+                    introduced_by: if shift(e.introduced_by).is_some() {
+                        None
+                    } else {
+                        e.introduced_by
+                    },
+                })
+                .collect(),
+            inode: pos(e.inode),
+        }),
+    }
+}
+
+fn combine_headers<'a, I: Iterator<Item = &'a ChangeHeader>>(
+    headers: I,
+    message: Option<String>,
+) -> ChangeHeader {
+    let mut header: Option<ChangeHeader> = None;
+    let mut description = String::new();
+    for h in headers {
+        description.push_str("* ");
+        description.push_str(&h.message);
+        description.push('\n');
+        if let Some(ref d) = h.description {
+            for l in d.lines() {
+                description.push_str("  ");
+                description.push_str(l);
+                description.push('\n');
+            }
+        }
+        if let Some(ref mut header) = header {
+            for a in h.authors.iter() {
+                if !header.authors.contains(a) {
+                    header.authors.push(a.clone())
+                }
+            }
+            header.timestamp = header.timestamp.max(h.timestamp);
+        } else {
+            header = Some(h.clone())
+        }
+    }
+    let mut header = header.unwrap_or_default();
+    if let Some(message) = message {
+        header.message = message
+    }
+    header.description = Some(description.trim_end().to_string());
+    header
+}
+
+/// Combine the attribution metadata of the squashed changes: the
+/// squashed change is AI-assisted if any of them was, and keeps the
+/// first author and AI metadata found, along with the lowest
+/// confidence. If none of the changes has attribution metadata, the
+/// metadata is kept only if all the non-empty ones are identical.
+pub fn combine_metadata<'a, I: Iterator<Item = &'a [u8]>>(metadata: I) -> Vec<u8> {
+    let mut combined: Option<SerializedAttribution> = None;
+    let mut raw: Option<&[u8]> = None;
+    let mut conflicting_raw = false;
+    for m in metadata {
+        if m.is_empty() {
+            continue;
+        }
+        if let Ok(a) = bincode::deserialize::<SerializedAttribution>(m) {
+            if let Some(ref mut c) = combined {
+                c.author = c.author.take().or(a.author);
+                c.ai_assisted |= a.ai_assisted;
+                c.ai_metadata = c.ai_metadata.take().or(a.ai_metadata);
+                c.confidence = match (c.confidence, a.confidence) {
+                    (Some(x), Some(y)) => Some(x.min(y)),
+                    (x, y) => x.or(y),
+                };
+                c.attribution_version = c.attribution_version.max(a.attribution_version);
+            } else {
+                combined = Some(a)
+            }
+        } else if raw.is_some_and(|r| r != m) {
+            conflicting_raw = true
+        } else {
+            raw = Some(m)
+        }
+    }
+    if let Some(c) = combined {
+        bincode::serialize(&c).unwrap_or_default()
+    } else if conflicting_raw {
+        Vec::new()
+    } else {
+        raw.map(|r| r.to_vec()).unwrap_or_default()
+    }
+}
+
+fn combine_unhashed<'a, I: Iterator<Item = Option<&'a serde_json::Value>>>(
+    unhashed: I,
+) -> Option<serde_json::Value> {
+    let mut result: Option<serde_json::Value> = None;
+    for u in unhashed.flatten() {
+        match (&mut result, u) {
+            (None, u) => result = Some(u.clone()),
+            (Some(serde_json::Value::Object(r)), serde_json::Value::Object(u)) => {
+                for (k, v) in u.iter() {
+                    r.entry(k.clone()).or_insert_with(|| v.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+/// Squash `hashes`, which must be consecutive on `channel` and not
+/// depended upon by any other change of `channel`, into a single
+/// change. The new change is saved to `changes`, the squashed changes
+/// are unrecorded from `channel` and the new change is applied in their
+/// place, keeping the inodes of the files they added.
+///
+/// Returns the hash of the new change, along with the change.
+pub fn squash<
+    T: MutTxnT + TagMetadataTxnT<TagError = <T as GraphTxnT>::GraphError>,
+    P: ChangeStore,
+>(
+    txn: &mut T,
+    channel: &ChannelRef<T>,
+    changes: &P,
+    hashes: &[Hash],
+    message: Option<String>,
+    salt: u64,
+) -> Result<(Hash, Change), SquashError<P::Error, T>> {
+    if hashes.is_empty() {
+        return Err(SquashError::Empty);
+    }
+
+    // Order the changes as they were applied to the channel.
+    let mut ids = Vec::with_capacity(hashes.len());
+    for h in hashes.iter() {
+        let id = if let Some(&id) = txn.get_internal(&h.into())? {
+            id
+        } else {
+            return Err(SquashError::ChangeNotInChannel { hash: *h });
+        };
+        let n = if let Some(&n) = txn.get_changeset(txn.changes(&channel.read()), &id)? {
+            u64::from_le(n.0)
+        } else {
+            return Err(SquashError::ChangeNotInChannel { hash: *h });
+        };
+        ids.push((n, id, *h))
+    }
+    ids.sort();
+    ids.dedup();
+    let set: HashSet<NodeId> = ids.iter().map(|(_, id, _)| *id).collect();
+
+    check_squashable(txn, channel, &ids, &set)?;
+
+    let mut squashed = Vec::with_capacity(ids.len());
+    for (_, _, h) in ids.iter() {
+        let c = changes.get_change(h).map_err(SquashError::Changestore)?;
+        if c.hashed.tag.is_some() {
+            return Err(SquashError::Tag { hash: *h });
+        }
+        squashed.push((*h, c))
+    }
+    let mut change = compose(&squashed, message);
+
+    // Remember the inodes of the files touched by the squashed
+    // changes, since unrecording them updates the tree.
+    let mut offset = 0;
+    let mut added = Vec::new();
+    let mut external = HashSet::default();
+    for ((_, id, _), (_, c)) in ids.iter().zip(squashed.iter()) {
+        for hunk in c.changes.iter() {
+            for atom in hunk.iter() {
+                match atom {
+                    Atom::NewVertex(v) if v.start == v.end => {
+                        let p = Position {
+                            change: *id,
+                            pos: v.start,
+                        };
+                        let inode = txn.get_revinodes(&p, None)?.cloned();
+                        added.push((p, v.start + offset, inode))
+                    }
+                    Atom::EdgeMap(e) => {
+                        for e in e.edges.iter() {
+                            if e.flag.contains(EdgeFlags::FOLDER) && e.to.start == e.to.end {
+                                if let Some(h) = e.to.change {
+                                    if let Some(&to) = txn.get_internal(&h.into())? {
+                                        if !set.contains(&to) {
+                                            external.insert(Position {
+                                                change: to,
+                                                pos: e.to.start,
+                                            });
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        offset += c.contents.len();
+    }
+    let mut external_before = HashMap::default();
+    for p in external.iter() {
+        external_before.insert(*p, txn.get_revinodes(p, None)?.cloned());
+    }
+
+    let hash = changes
+        .save_change(&mut change, |_, _| Ok(()))
+        .map_err(SquashError::Changestore)?;
+
+    let mut updates = HashMap::default();
+    for (_, id, h) in ids.iter().rev() {
+        // Files added by this change and deleted by a later squashed
+        // change are restored in the tree when unrecording the
+        // deletion, but must stay deleted after the squash.
+        for (p, _, inode) in added.iter() {
+            if p.change == *id && inode.is_none() {
+                if let Some(&restored) = txn.get_revinodes(p, None)? {
+                    updates.insert(updates.len(), InodeUpdate::Deleted { inode: restored });
+                }
+            }
+        }
+        crate::unrecord::unrecord(txn, channel, changes, h, salt)?;
+    }
+    for (p, before) in external_before {
+        if before.is_none() {
+            if let Some(&restored) = txn.get_revinodes(&p, None)? {
+                updates.insert(updates.len(), InodeUpdate::Deleted { inode: restored });
+            }
+        }
+    }
+    for (_, pos, inode) in added {
+        if let Some(inode) = inode {
+            updates.insert(updates.len(), InodeUpdate::Add { pos, inode });
+        }
+    }
+
+    crate::apply::apply_local_change(txn, channel, &change, &hash, &updates)?;
+    Ok((hash, change))
+}
+
+/// Check that the changes (sorted by position in the channel) are
+/// consecutive on the channel, and that no change outside of the set
+/// depends on them.
+fn check_squashable<T: MutTxnT, C: std::error::Error + 'static>(
+    txn: &T,
+    channel: &ChannelRef<T>,
+    ids: &[(u64, NodeId, Hash)],
+    set: &HashSet<NodeId>,
+) -> Result<(), SquashError<C, T>> {
+    let channel = channel.read();
+    let mut remaining = ids.len();
+    for x in changeid_log(txn, &channel, L64(ids[0].0.to_le()))? {
+        if remaining == 0 {
+            break;
+        }
+        let (_, p) = x?;
+        if set.contains(&p.a) {
+            remaining -= 1
+        } else {
+            return Err(SquashError::NotConsecutive {
+                hash: external_hash(txn, &p.a)?,
+            });
+        }
+    }
+
+    for (_, id, h) in ids.iter() {
+        for x in txn.iter_revdep(id)? {
+            let (p, d) = x?;
+            if p > id {
+                break;
+            } else if p < id || set.contains(d) {
+                continue;
+            }
+            if txn.get_changeset(txn.changes(&channel), d)?.is_some() {
+                return Err(SquashError::ChangeIsDependedUpon {
+                    hash: *h,
+                    dependent: external_hash(txn, d)?,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn external_hash<T: GraphTxnT>(txn: &T, id: &NodeId) -> Result<Hash, TxnErr<T::GraphError>> {
+    Ok(txn
+        .get_external(id)?
+        .map(|h| h.into())
+        .unwrap_or(Hash::NONE))
+}
@@ -2,12 +2,14 @@ use crate::pristine::sanakirja::{MutTxn, SanakirjaError, P, UP};
 use crate::pristine::*;
 use crate::HashSet;
 use crate::TxnT;
+use bincode::Options;
 use log::*;
 use serde_derive::*;
 use std::io::Read;
 use std::io::{Seek, SeekFrom};
 use std::path::Path;
 
+pub mod preview;
 pub mod txn;
 
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
@@ -21,6 +23,19 @@ pub struct FileHeader {
     pub state: Merkle,
 }
 
+impl FileHeader {
+    /// Read the header at the start of a tag file, short or full.
+    pub fn read<R: Read>(r: R) -> Result<Self, TagError> {
+        bincode::deserialize_from(r).map_err(TagError::BincodeDe)
+    }
+
+    /// Whether this is the header of the short version of a tag, without
+    /// the channel, as sent over the network (see [`OpenTagFile::short`]).
+    pub fn is_short(&self) -> bool {
+        self.unhashed <= self.channel
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct DbOffsets {
     pub internal: u64,
@@ -49,6 +64,8 @@ pub enum TagError {
     Bincode(#[from] bincode::Error),
     #[error("Tag file is corrupt")]
     BincodeDe(bincode::Error),
+    #[error("Tag file is truncated")]
+    Truncated,
     #[error(transparent)]
     Zstd(#[from] zstd_seekable::Error),
     #[error(transparent)]
@@ -57,6 +74,12 @@ pub enum TagError {
     Sync,
     #[error("Wrong state, expected {}, got {}", expected.to_base32(), got.to_base32())]
     WrongHash { expected: Merkle, got: Merkle },
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid tag signature: {reason}")]
+    InvalidSignature { reason: String },
+    #[error("Tag signed by unknown key {key}")]
+    UntrustedSigner { key: String },
 }
 
 impl From<TxnErr<SanakirjaError>> for TagError {
@@ -96,12 +119,33 @@ impl OpenTagFile {
         self.header.state.clone()
     }
 
+    /// The unhashed section of this tag file, which holds the tag
+    /// signature if there is one.
+    pub fn unhashed(&mut self) -> Result<Unhashed, TagError> {
+        read_unhashed(&mut self.file, &self.header)
+    }
+
+    /// Check the signature of this tag, if any, against its state and
+    /// header.
+    pub fn verify(&mut self) -> Result<SignatureStatus, TagError> {
+        let header = self.header()?;
+        let unhashed = self.unhashed()?;
+        Ok(verify_signature(
+            &self.header.state,
+            &header,
+            unhashed.signature.as_ref(),
+        ))
+    }
+
+    /// Write the short version of this tag (its state, header and
+    /// signature, without the channel), as sent over the network.
     pub fn short<W: std::io::Write>(&mut self, mut w: W) -> Result<(), TagError> {
         let mut header_buf = vec![0u8; (self.header.channel - self.header.header) as usize];
 
         self.file.seek(SeekFrom::Start(self.header.header))?;
         self.file.read_exact(&mut header_buf)?;
         debug!("header_buf = {:?}", header_buf);
+        let unhashed_buf = unhashed_bytes(&self.unhashed()?)?;
         let mut off = FileHeader {
             version: VERSION,
             header: 0,
@@ -113,33 +157,171 @@ impl OpenTagFile {
         };
         off.header = bincode::serialized_size(&off)?;
         off.channel = off.header + header_buf.len() as u64;
-        off.total = off.channel;
+        off.unhashed = off.channel;
+        off.total = off.unhashed + unhashed_buf.len() as u64;
         let mut off_buf = Vec::with_capacity(off.header as usize);
         bincode::serialize_into(&mut off_buf, &off)?;
         w.write_all(&off_buf)?;
         w.write_all(&header_buf)?;
+        w.write_all(&unhashed_buf)?;
         Ok(())
     }
 }
 
+/// Data stored in a tag file outside of its state and header, and not
+/// covered by the state.
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct Unhashed {
+    /// Signature of the state and header by the author of the tag.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<crate::key::Signature>,
+}
+
+/// Read the unhashed section described by `header`. Its bounds come from
+/// the file itself, which may have been received from anyone, so they are
+/// checked against the length of the file before anything is allocated.
+fn read_unhashed<R: Read + Seek>(file: &mut R, header: &FileHeader) -> Result<Unhashed, TagError> {
+    // Short tags written before signatures have `unhashed == 0`.
+    if header.total <= header.unhashed || header.unhashed < header.channel {
+        return Ok(Unhashed::default());
+    }
+    let len = header.total - header.unhashed;
+    let end = file.seek(SeekFrom::End(0))?;
+    if header.unhashed > end || len > end - header.unhashed {
+        return Err(TagError::Truncated);
+    }
+    file.seek(SeekFrom::Start(header.unhashed))?;
+    let mut buf = Vec::new();
+    file.take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len {
+        return Err(TagError::Truncated);
+    }
+    Ok(serde_json::from_slice(&buf)?)
+}
+
+fn unhashed_bytes(unhashed: &Unhashed) -> Result<Vec<u8>, TagError> {
+    if unhashed.signature.is_none() {
+        Ok(Vec::new())
+    } else {
+        Ok(serde_json::to_vec(unhashed)?)
+    }
+}
+
+/// Outcome of checking the signature of a tag.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SignatureStatus {
+    /// The tag isn't signed.
+    Unsigned,
+    /// The signature matches the state and header of the tag.
+    Valid {
+        /// Public key of the signer.
+        key: String,
+        /// Fingerprint of the public key of the signer.
+        fingerprint: String,
+        date: chrono::DateTime<chrono::Utc>,
+    },
+    /// The signature doesn't match, or the key can't be loaded.
+    Invalid { reason: String },
+}
+
+impl SignatureStatus {
+    pub fn is_invalid(&self) -> bool {
+        matches!(self, SignatureStatus::Invalid { .. })
+    }
+}
+
+/// The bytes signed by the author of a tag: its state and header.
+pub fn signed_bytes(state: &Merkle, header: &crate::change::ChangeHeader) -> Vec<u8> {
+    bincode::serialize(&(state, header)).unwrap()
+}
+
+/// Sign the state and header of a tag.
+pub fn sign(
+    key: &crate::key::SKey,
+    state: &Merkle,
+    header: &crate::change::ChangeHeader,
+) -> Result<crate::key::Signature, crate::key::KeyError> {
+    key.sign(&signed_bytes(state, header))
+}
+
+/// Check the signature of a tag received from another repository,
+/// rejecting it if the signature is invalid, or if its key isn't one of
+/// the keys for which `is_known` holds. Unsigned tags are accepted.
+pub fn check_received<F: Fn(&str) -> bool>(
+    state: &Merkle,
+    header: &crate::change::ChangeHeader,
+    unhashed: &Unhashed,
+    is_known: F,
+) -> Result<SignatureStatus, TagError> {
+    match verify_signature(state, header, unhashed.signature.as_ref()) {
+        SignatureStatus::Invalid { reason } => Err(TagError::InvalidSignature { reason }),
+        SignatureStatus::Valid { ref key, .. } if !is_known(key) => {
+            Err(TagError::UntrustedSigner { key: key.clone() })
+        }
+        status => Ok(status),
+    }
+}
+
+/// Check a tag signature against the state and header it claims to sign.
+pub fn verify_signature(
+    state: &Merkle,
+    header: &crate::change::ChangeHeader,
+    signature: Option<&crate::key::Signature>,
+) -> SignatureStatus {
+    let signature = if let Some(signature) = signature {
+        signature
+    } else {
+        return SignatureStatus::Unsigned;
+    };
+    match signature.verify(&signed_bytes(state, header)) {
+        Ok(()) => SignatureStatus::Valid {
+            key: signature.key.key.clone(),
+            fingerprint: signature.key.fingerprint(),
+            date: signature.date,
+        },
+        Err(e) => SignatureStatus::Invalid {
+            reason: e.to_string(),
+        },
+    }
+}
+
 pub fn read_short<R: std::io::Read + std::io::Seek>(
-    mut file: R,
+    file: R,
     expected: &Merkle,
 ) -> Result<crate::change::ChangeHeader, TagError> {
-    let mut off = [0u8; std::mem::size_of::<FileHeader>() as usize];
+    Ok(read_short_signed(file, expected)?.0)
+}
+
+/// Read the header and the unhashed section (including the signature)
+/// of the short version of a tag. Short tags written before signatures
+/// existed have an empty unhashed section.
+pub fn read_short_signed<R: std::io::Read + std::io::Seek>(
+    mut file: R,
+    expected: &Merkle,
+) -> Result<(crate::change::ChangeHeader, Unhashed), TagError> {
     file.seek(SeekFrom::Start(0))?;
-    file.read_exact(&mut off)?;
-    let header: FileHeader = bincode::deserialize(&off).map_err(TagError::BincodeDe)?;
+    // Short tags can be smaller than `size_of::<FileHeader>()`.
+    let header: FileHeader = bincode::deserialize_from(&mut file).map_err(TagError::BincodeDe)?;
     debug!("header = {:?}", header);
-    if &header.state == expected {
-        file.seek(SeekFrom::Start(header.header))?;
-        Ok(bincode::deserialize_from(file).map_err(TagError::BincodeDe)?)
-    } else {
-        Err(TagError::WrongHash {
+    if &header.state != expected {
+        return Err(TagError::WrongHash {
             expected: *expected,
             got: header.state,
-        })
+        });
     }
+    // The lengths in the header can't make bincode allocate more than the
+    // file holds.
+    let end = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(header.header))?;
+    let change_header = bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(end)
+        .deserialize_from(&mut file)
+        .map_err(TagError::BincodeDe)?;
+    let unhashed = read_unhashed(&mut file, &header)?;
+    Ok((change_header, unhashed))
 }
 
 pub const VERSION: u64 = 7;
@@ -316,6 +498,21 @@ pub fn from_channel<
     txn: &crate::pristine::sanakirja::GenericTxn<T>,
     channel: &str,
     header: &crate::change::ChangeHeader,
+    w: W,
+) -> Result<Merkle, TagError> {
+    from_channel_with_unhashed(txn, channel, header, &Unhashed::default(), w)
+}
+
+/// Same as [`from_channel`], also writing the unhashed section of the
+/// tag, for instance to store its signature.
+pub fn from_channel_with_unhashed<
+    W: std::io::Write,
+    T: ::sanakirja::LoadPage<Error = ::sanakirja::Error> + ::sanakirja::RootPage,
+>(
+    txn: &crate::pristine::sanakirja::GenericTxn<T>,
+    channel: &str,
+    header: &crate::change::ChangeHeader,
+    unhashed: &Unhashed,
     mut w: W,
 ) -> Result<Merkle, TagError> {
     let out = Vec::with_capacity(1 << 16);
@@ -336,8 +533,9 @@ pub fn from_channel<
     };
     off.header = bincode::serialized_size(&off)?;
     off.channel = off.header + header_buf.len() as u64;
+    let unhashed_buf = unhashed_bytes(unhashed)?;
     off.unhashed = off.channel + out.len() as u64;
-    off.total = off.unhashed;
+    off.total = off.unhashed + unhashed_buf.len() as u64;
 
     let mut off_buf = Vec::with_capacity(off.header as usize);
     bincode::serialize_into(&mut off_buf, &off)?;
@@ -347,6 +545,7 @@ pub fn from_channel<
     w.write_all(&header_buf)?;
     debug!("out = {:?}", out.len());
     w.write_all(&out)?;
+    w.write_all(&unhashed_buf)?;
     Ok(state)
 }
 
@@ -0,0 +1,132 @@
+//! Previews of consolidating tags
+//!
+//! A consolidating tag covers the changes applied to its channel since the
+//! previous tag. [`preview`] computes what a tag of the current state of a
+//! channel would cover, without writing anything: the changes, their
+//! authors, their AI-assisted share, and how many dependencies the tag
+//! would replace for the next changes.
+
+use crate::change::Author;
+use crate::changestore::ChangeStore;
+use crate::pristine::{Base32, Hash, Merkle, TagAttributionSummary, TxnErr};
+use crate::TxnTExt;
+use log::debug;
+use std::collections::{BTreeMap, HashSet};
+
+/// An author of changes covered by a tag
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthorChanges {
+    pub author: Author,
+    /// Number of covered changes by this author
+    pub changes: u64,
+}
+
+/// What a consolidating tag of the current state of a channel would cover
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TagPreview {
+    /// State the tag would be of
+    pub state: Merkle,
+    /// State of the previous tag of the channel, if any
+    pub previous_tag: Option<Merkle>,
+    /// Position in the channel log of the first covered change
+    pub start_position: u64,
+    /// Changes covered by the tag, in the order of the log
+    pub consolidated_changes: Vec<Hash>,
+    /// Covered changes that no other covered change depends on: a change
+    /// recorded now would depend on all of them without the tag
+    pub dependency_count_before: u64,
+    /// Dependencies of a change recorded after the tag: only the tag
+    pub dependency_count_after: u64,
+    /// Authors of the covered changes, most changes first
+    pub authors: Vec<AuthorChanges>,
+    /// Attribution of the covered changes, including their AI-assisted
+    /// share
+    pub attribution: TagAttributionSummary,
+    /// Whether the current state is already tagged
+    pub already_tagged: bool,
+}
+
+impl TagPreview {
+    /// Dependencies saved on the next changes by the tag
+    pub fn dependency_reduction(&self) -> u64 {
+        self.dependency_count_before
+            .saturating_sub(self.dependency_count_after)
+    }
+}
+
+/// Preview a consolidating tag of the current state of `channel`, reading
+/// the covered changes from `changes`. Changes that can't be loaded are
+/// counted, but have no author and no dependencies.
+pub fn preview<T: TxnTExt, C: ChangeStore>(
+    txn: &T,
+    channel: &T::Channel,
+    changes: &C,
+) -> Result<TagPreview, TxnErr<T::GraphError>> {
+    let state = txn.current_state(channel).map_err(TxnErr)?;
+    let mut last_tag = None;
+    if let Some(entry) = txn.rev_iter_tags(txn.tags(channel), None)?.next() {
+        let (pos, _) = entry?;
+        last_tag = Some(pos.0);
+    }
+    let mut previous_tag = None;
+    if let Some(pos) = last_tag {
+        if let Some(entry) = txn.log(channel, pos).map_err(TxnErr)?.next() {
+            let (_, (_, m)) = entry.map_err(TxnErr)?;
+            previous_tag = Some(m.into());
+        }
+    }
+    let start_position = last_tag.map_or(0, |p| p + 1);
+
+    let mut consolidated_changes = Vec::new();
+    for entry in txn.log(channel, start_position).map_err(TxnErr)? {
+        let (_, (h, _)) = entry.map_err(TxnErr)?;
+        consolidated_changes.push(Hash::from(h));
+    }
+
+    let covered: HashSet<_> = consolidated_changes.iter().collect();
+    let mut depended_on = HashSet::new();
+    let mut authors: BTreeMap<BTreeMap<String, String>, u64> = BTreeMap::new();
+    for hash in consolidated_changes.iter() {
+        let change = match changes.get_change(hash) {
+            Ok(change) => change,
+            Err(e) => {
+                debug!("Can't preview {}: {}", hash.to_base32(), e);
+                continue;
+            }
+        };
+        for dep in change.hashed.dependencies.iter() {
+            if covered.contains(dep) {
+                depended_on.insert(*dep);
+            }
+        }
+        for author in change.hashed.header.authors.iter() {
+            *authors.entry(author.0.clone()).or_default() += 1;
+        }
+    }
+    let mut authors: Vec<_> = authors
+        .into_iter()
+        .map(|(author, changes)| AuthorChanges {
+            author: Author(author),
+            changes,
+        })
+        .collect();
+    authors.sort_by(|a, b| b.changes.cmp(&a.changes));
+
+    let attribution =
+        crate::attribution::tag_summary::summarize(changes, state, &consolidated_changes);
+    Ok(TagPreview {
+        state,
+        previous_tag,
+        start_position,
+        dependency_count_before: (consolidated_changes.len() - depended_on.len()) as u64,
+        dependency_count_after: if consolidated_changes.is_empty() {
+            0
+        } else {
+            1
+        },
+        already_tagged: consolidated_changes.is_empty() && previous_tag.is_some(),
+        consolidated_changes,
+        authors,
+        attribution,
+    })
+}
@@ -29,6 +29,62 @@ fn hash_mismatch(change: &mut Change) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+fn stream_roundtrip(change: &mut Change) -> Result<(), anyhow::Error> {
+    use crate::change::stream::*;
+    let mut v7 = Vec::new();
+    let h = change.serialize(&mut v7, |_, _| Ok::<_, anyhow::Error>(()))?;
+    let mut buf = tempfile::NamedTempFile::new()?;
+    let h_ = change.serialize_stream(&mut buf, |_, _| Ok::<_, anyhow::Error>(()))?;
+    // The hash doesn't depend on the container.
+    assert_eq!(h, h_);
+    buf.flush()?;
+
+    let path = buf.path().to_str().unwrap();
+    let c = Change::deserialize(path, Some(&h))?;
+    assert_eq!(c.hashed, change.hashed);
+    assert_eq!(c.contents, change.contents);
+
+    let bytes = std::fs::read(path)?;
+    Change::check_from_buffer(&bytes, &h)?;
+
+    // A header-only prefix is enough to read the dependencies.
+    let mut f = std::fs::File::open(path)?;
+    let len = prefix_len(&mut f, Section::Dependencies)?;
+    let mut s = ChangeStream::open(&bytes[..len as usize])?;
+    assert_eq!(s.header(), &change.hashed.header);
+    assert_eq!(s.dependencies()?.dependencies, change.hashed.dependencies);
+    assert!(Change::size_no_contents(&mut f)? < bytes.len() as u64);
+
+    let mut f = ChangeFile::open(h, path)?;
+    assert_eq!(f.hashed(), &change.hashed);
+    let mut contents = vec![0; change.contents.len()];
+    f.read_contents(0, &mut contents)?;
+    assert_eq!(contents, change.contents);
+    Ok(())
+}
+
+#[test]
+fn stream_section_too_large() {
+    use crate::change::stream::*;
+    // A header section claiming more bytes than allowed, and than the
+    // stream holds.
+    let mut bytes = crate::change::VERSION_STREAM.to_le_bytes().to_vec();
+    bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+    bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+    assert!(matches!(
+        ChangeStream::open(&bytes[..]),
+        Err(ChangeError::SectionTooLarge { .. })
+    ));
+    // Within the limits, but truncated.
+    let mut bytes = crate::change::VERSION_STREAM.to_le_bytes().to_vec();
+    bytes.extend_from_slice(&10u64.to_le_bytes());
+    bytes.extend_from_slice(&MAX_SECTION_SIZE.to_le_bytes());
+    assert!(matches!(
+        ChangeStream::open(&bytes[..]),
+        Err(ChangeError::Io(_))
+    ));
+}
+
 #[test]
 fn hash_mism() -> Result<(), anyhow::Error> {
     env_logger::try_init().unwrap_or(());
@@ -90,6 +146,7 @@ fn hash_mism() -> Result<(), anyhow::Error> {
     )?;
 
     hash_mismatch(&mut change0)?;
+    stream_roundtrip(&mut change0)?;
 
     Ok(())
 }
@@ -0,0 +1,84 @@
+use super::*;
+use crate::attribution::credit::credit;
+use crate::attribution::{AIMetadata, AttributedPatch, AuthorId, AuthorInfo, PatchId};
+use std::collections::HashSet;
+use std::io::Write;
+
+fn patch(ai: Option<(&str, &str, f64)>) -> AttributedPatch {
+    AttributedPatch {
+        patch_id: PatchId::new(NodeId::ROOT),
+        author: AuthorInfo {
+            id: AuthorId::new(1),
+            name: "alice".to_string(),
+            email: "alice@example.com".to_string(),
+            is_ai: false,
+        },
+        timestamp: Utc::now(),
+        ai_assisted: ai.is_some(),
+        ai_metadata: ai.map(|(provider, model, confidence)| AIMetadata {
+            provider: provider.to_string(),
+            model: model.to_string(),
+            prompt_hash: Hash::NONE,
+            suggestion_type: crate::attribution::SuggestionType::Complete,
+            human_review_time: None,
+            acceptance_confidence: confidence,
+            generation_timestamp: Utc::now(),
+            token_count: None,
+            model_params: None,
+        }),
+        dependencies: HashSet::new(),
+        conflicts_with: HashSet::new(),
+        description: String::new(),
+        confidence: None,
+    }
+}
+
+/// Attribute the lines of a file to a human change and an AI-assisted
+/// change.
+#[test]
+fn credit_test() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    let human = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    repo.write_file("file", Inode::ROOT)
+        .unwrap()
+        .write_all(b"a\nb\nc\nd\n")?;
+    let ai = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let (pos, _) = txn.read().follow_oldest_path(&changes, &channel, "file")?;
+    let mut lookups = 0;
+    let lines = credit(&changes, &txn, &channel, pos, |h| {
+        lookups += 1;
+        if *h == ai {
+            Some(patch(Some(("anthropic", "claude", 0.9))))
+        } else {
+            Some(patch(None))
+        }
+    })?;
+    assert_eq!(lookups, 2);
+
+    let contents: Vec<_> = lines.iter().map(|l| l.contents.as_str()).collect();
+    assert_eq!(contents, vec!["a", "b", "c", "d"]);
+    for line in &lines[..2] {
+        assert_eq!(line.changes, vec![human]);
+        assert!(!line.ai_assisted);
+        assert_eq!(line.model, None);
+    }
+    for line in &lines[2..] {
+        assert_eq!(line.changes, vec![ai]);
+        assert!(line.ai_assisted);
+        assert_eq!(line.provider.as_deref(), Some("anthropic"));
+        assert_eq!(line.model.as_deref(), Some("claude"));
+        assert_eq!(line.confidence, Some(0.9));
+    }
+    Ok(())
+}
@@ -0,0 +1,29 @@
+use crate::envelope::*;
+use crate::key::SKey;
+use crate::*;
+
+use super::*;
+
+#[test]
+fn open_sealed_change() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let store = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let channel = txn.write().open_or_create_channel("main")?;
+    txn.write().add_file("file", 0)?;
+    let (_, mut change) = record_all_change(&repo, &store, &txn, &channel, "")?;
+    let mut bytes = Vec::new();
+    let hash = change.serialize(&mut bytes, |_, _| Ok::<_, anyhow::Error>(()))?;
+
+    let reviewer = SKey::generate(None);
+    let envelope = Envelope::seal(&hash, &bytes, &[reviewer.pkey()])?.to_bytes()?;
+    let envelope = Envelope::from_bytes(&envelope)?;
+    assert_eq!(envelope.hash(), Some(hash));
+    assert_eq!(envelope.open(&reviewer)?, bytes);
+    Ok(())
+}
@@ -147,3 +147,95 @@ fn overwrite_dead_symlink() -> Result<(), anyhow::Error> {
     txn.commit().unwrap();
     Ok(())
 }
+
+#[test]
+fn normalized_eol() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let mut normalization = working_copy::Normalization::new();
+    normalization.add_rule("*.txt", Some(working_copy::normalize::Eol::Crlf), None)?;
+    let r = tempfile::tempdir()?;
+    let repo =
+        working_copy::filesystem::FileSystem::from_root(r.path()).with_normalization(normalization);
+    // A working copy on another platform, without any policy.
+    let r_lf = tempfile::tempdir()?;
+    let repo_lf = working_copy::filesystem::FileSystem::from_root(r_lf.path());
+
+    let f = tempfile::tempdir()?;
+    let changes = changestore::filesystem::FileSystem::from_root(f.path(), MAX_FILES);
+
+    std::fs::create_dir_all(r.path().join("dir"))?;
+    std::fs::write(r.path().join("dir/file.txt"), b"a\r\nb\r\nc\r\n")?;
+
+    let f = tempfile::tempdir()?;
+    let env = pristine::sanakirja::Pristine::new(f.path().join("pristine"))?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("dir/file.txt", 0).unwrap();
+
+    let channel = txn.write().open_or_create_channel("main").unwrap();
+    let (_, change) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+    assert!(!change.contents.contains(&b'\r'));
+
+    output::output_repository_no_pending(&repo_lf, &changes, &txn, &channel, "", true, None, 1, 0)
+        .unwrap();
+    assert_eq!(
+        std::fs::read(r_lf.path().join("dir/file.txt"))?,
+        b"a\nb\nc\n"
+    );
+
+    // Converting the line endings of the file doesn't change it.
+    std::fs::write(r.path().join("dir/file.txt"), b"a\nb\nc\n")?;
+    let mut state = Builder::new();
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        false,
+        &crate::DEFAULT_SEPARATOR,
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    assert!(state.finish().actions.is_empty());
+
+    std::fs::remove_file(r.path().join("dir/file.txt"))?;
+    output::output_repository_no_pending(&repo, &changes, &txn, &channel, "", true, None, 1, 0)
+        .unwrap();
+    assert_eq!(
+        std::fs::read(r.path().join("dir/file.txt"))?,
+        b"a\r\nb\r\nc\r\n"
+    );
+    Ok(())
+}
+
+#[test]
+fn normalization_rules() -> Result<(), anyhow::Error> {
+    use working_copy::normalize::{crlf_to_lf, lf_to_crlf, Eol};
+
+    let mut normalization = working_copy::Normalization::new();
+    normalization.add_rule("*.bat", Some(Eol::Crlf), Some("windows-1252"))?;
+    normalization.add_rule("/scripts/**", Some(Eol::Lf), None)?;
+    assert!(normalization
+        .add_rule("*", None, Some("no-such-encoding"))
+        .is_err());
+
+    let bat = normalization.policy("dir/run.bat");
+    assert_eq!(bat.eol, Some(Eol::Crlf));
+    assert_eq!(
+        bat.encoding.as_ref().map(|e| e.label()),
+        Some("windows-1252")
+    );
+    // Later rules override earlier ones, attribute by attribute.
+    let script = normalization.policy("scripts/a/run.bat");
+    assert_eq!(script.eol, Some(Eol::Lf));
+    assert!(script.encoding.is_some());
+    assert_eq!(normalization.policy("dir/scripts/x").eol, None);
+    assert_eq!(normalization.policy("run.bat.txt").eol, None);
+
+    let mut text = b"head\r\na\r\nb\nc\r".to_vec();
+    crlf_to_lf(&mut text, 4);
+    assert_eq!(text, b"head\na\nb\nc\r");
+    assert_eq!(&lf_to_crlf(b"a\nb\r\nc")[..], b"a\r\nb\r\nc");
+    Ok(())
+}
@@ -0,0 +1,67 @@
+use super::*;
+use crate::forbidden::ForbiddenPaths;
+use crate::working_copy::WorkingCopy;
+
+fn record_forbidden<T: MutTxnT + Send + Sync + 'static>(
+    repo: &working_copy::memory::Memory,
+    changes: &changestore::memory::Memory,
+    txn: &ArcTxn<T>,
+    channel: &ChannelRef<T>,
+    forbidden: &ForbiddenPaths,
+) -> Result<Vec<crate::forbidden::Violation>, anyhow::Error> {
+    let mut state = Builder::new();
+    state.forbidden_paths = forbidden.clone();
+    match state.record(
+        txn.clone(),
+        Algorithm::default(),
+        false,
+        &crate::DEFAULT_SEPARATOR,
+        channel.clone(),
+        repo,
+        changes,
+        "",
+        1,
+    ) {
+        Ok(()) => Ok(Vec::new()),
+        Err(crate::record::RecordError::ForbiddenPath(e)) => Ok(e.violations),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Recording an addition under a forbidden directory fails, listing the
+/// directory once, but deleting it is allowed.
+#[test]
+fn record_forbidden_paths() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("src/main.rs", b"fn main() {}\n".to_vec());
+    repo.add_file("target/debug/app", b"\x7fELF".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("src/main.rs", 0)?;
+    txn.write().add_file("target/debug/app", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+
+    let mut forbidden = ForbiddenPaths::new();
+    forbidden.add_rule("target", Some("build artifacts"))?;
+
+    let violations = record_forbidden(&repo, &changes, &txn, &channel, &forbidden)?;
+    assert_eq!(violations.len(), 1);
+    assert_eq!(violations[0].path, "target");
+    assert_eq!(violations[0].reason.as_deref(), Some("build artifacts"));
+
+    // Servers find the same violation in the recorded change.
+    let (_, change) = record_all_change(&repo, &changes, &txn, &channel, "")?;
+    let err = forbidden.check_change(&change).unwrap_err();
+    assert_eq!(err.violations, violations);
+
+    repo.remove_path("target", true)?;
+    txn.write().remove_file("target")?;
+    assert!(record_forbidden(&repo, &changes, &txn, &channel, &forbidden)?.is_empty());
+
+    txn.commit()?;
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use super::*;
+use crate::manifest::{FileDigest, Files, Manifest, Mismatch};
+use crate::working_copy::WorkingCopy;
+
+/// The manifest of a channel lists its files, survives the text format,
+/// and tells a faithful copy from a tampered one.
+#[test]
+fn manifest_verify() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("README.md", b"hello\n".to_vec());
+    repo.add_file("scripts/build.sh", b"#!/bin/sh\nmake\n".to_vec());
+    repo.set_permissions("scripts/build.sh", 0o755)?;
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("README.md", 0)?;
+    txn.write().add_file("scripts/build.sh", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+    record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let (manifest, conflicts) = txn.manifest(&changes, &channel)?;
+    assert!(conflicts.is_empty());
+    assert_eq!(manifest.channel, "main");
+    assert_eq!(
+        manifest.state,
+        crate::pristine::current_state(&*txn.read(), &*channel.read())?
+    );
+    let mut files = Files::new();
+    files.insert("README.md".to_string(), FileDigest::new(b"hello\n", false));
+    files.insert(
+        "scripts/build.sh".to_string(),
+        FileDigest::new(b"#!/bin/sh\nmake\n", true),
+    );
+    assert_eq!(manifest.files, files);
+    assert!(manifest.verify(&files).is_empty());
+
+    let parsed: Manifest = manifest.to_string().parse()?;
+    assert_eq!(parsed, manifest);
+    assert!("atomic-manifest 2\n".parse::<Manifest>().is_err());
+
+    files.insert("README.md".to_string(), FileDigest::new(b"bye\n", false));
+    files.insert(
+        "scripts/build.sh".to_string(),
+        FileDigest::new(b"#!/bin/sh\nmake\n", false),
+    );
+    files.insert("extra".to_string(), FileDigest::new(b"", false));
+    assert_eq!(
+        manifest.verify(&files),
+        vec![
+            Mismatch::Modified {
+                path: "README.md".to_string()
+            },
+            Mismatch::Unexpected {
+                path: "extra".to_string()
+            },
+            Mismatch::Permissions {
+                path: "scripts/build.sh".to_string(),
+                executable: true,
+            },
+        ]
+    );
+    files.clear();
+    assert_eq!(manifest.verify(&files).len(), 2);
+
+    txn.commit()?;
+    Ok(())
+}
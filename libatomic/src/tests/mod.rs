@@ -10,14 +10,19 @@ mod add_file;
 mod change;
 mod clone;
 mod conflict;
+mod credit;
 mod diff;
+mod envelope;
 mod file_conflicts;
 mod filesystem;
+mod forbidden;
+mod manifest;
 mod missing_context;
 mod partial;
 mod performance;
 mod rm_file;
 mod rollback;
+mod squash;
 mod text;
 mod text_changes;
 mod unrecord;
@@ -0,0 +1,146 @@
+use super::*;
+use crate::working_copy::{WorkingCopy, WorkingCopyRead};
+use std::io::Write;
+
+/// Record three changes, the last two editing lines introduced by the
+/// previous ones, then squash them and check that the channel has the
+/// same contents.
+#[test]
+fn squash_edits() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("dir/file", b"a\nb\nc\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    txn.write().add_file("dir/file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("dir/file", Inode::ROOT)?
+        .write_all(b"a\nx\ny\nb\nc\n")?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("dir/file", Inode::ROOT)?
+        .write_all(b"a\nx\nb\nz\n")?;
+    let h2 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    // Non-consecutive changes can't be squashed.
+    match txn.write().squash(&changes, &channel, &[h0, h2], None, 0) {
+        Err(crate::SquashError::NotConsecutive { hash }) => assert_eq!(hash, h1),
+        r => panic!("unexpected result {:?}", r.map(|(h, _)| h)),
+    }
+    // Neither can changes that other changes depend on.
+    assert!(matches!(
+        txn.write().squash(&changes, &channel, &[h0, h1], None, 0),
+        Err(crate::SquashError::ChangeIsDependedUpon { .. })
+    ));
+
+    let (h, change) = txn.write().squash(
+        &changes,
+        &channel,
+        &[h2, h1],
+        Some("squashed".to_string()),
+        0,
+    )?;
+    assert_eq!(change.header.message, "squashed");
+    assert_eq!(change.dependencies, vec![h0]);
+    assert_eq!(change.header.description.as_deref(), Some("* test\n* test"));
+    assert_eq!(change.supersedes(), vec![h1, h2]);
+
+    let log: Vec<Hash> = txn
+        .read()
+        .log(&*channel.read(), 0)?
+        .map(|x| x.unwrap().1 .0.into())
+        .collect();
+    assert_eq!(log, vec![h0, h]);
+
+    let conflicts = output::output_repository_no_pending(
+        &repo, &changes, &txn, &channel, "", true, None, 1, 0,
+    )?;
+    if !conflicts.is_empty() {
+        panic!("conflicts = {:#?}", conflicts);
+    }
+    let mut buf = Vec::new();
+    repo.read_file("dir/file", &mut buf)?;
+    assert_eq!(std::str::from_utf8(&buf), Ok("a\nx\nb\nz\n"));
+
+    txn.commit()?;
+    Ok(())
+}
+
+/// Squash the addition of a file with an edit of that file, and check
+/// that the file is still tracked with the same inode.
+#[test]
+fn squash_file_addition() -> Result<(), anyhow::Error> {
+    env_logger::try_init().unwrap_or(());
+
+    let repo = working_copy::memory::Memory::new();
+    let changes = changestore::memory::Memory::new();
+    repo.add_file("file", b"a\nb\n".to_vec());
+
+    let env = pristine::sanakirja::Pristine::new_anon()?;
+    let txn = env.arc_txn_begin().unwrap();
+    let inode = txn.write().add_file("file", 0)?;
+    let channel = txn.write().open_or_create_channel("main")?;
+
+    let h0 = record_all(&repo, &changes, &txn, &channel, "")?;
+    repo.write_file("file", Inode::ROOT)?.write_all(b"a\nc\n")?;
+    let h1 = record_all(&repo, &changes, &txn, &channel, "")?;
+
+    let (h, change) = txn.write().squash(&changes, &channel, &[h0, h1], None, 0)?;
+    assert!(change.dependencies.is_empty());
+    assert_eq!(change.supersedes(), vec![h0, h1]);
+    assert!(txn.read().is_tracked("file")?);
+    let pos = txn.read().get_inodes(&inode, None)?.cloned().unwrap();
+    let ext: Option<Hash> = txn.read().get_external(&pos.change)?.map(|h| h.into());
+    assert_eq!(ext, Some(h));
+
+    // Nothing left to record.
+    let mut state = Builder::new();
+    state.record(
+        txn.clone(),
+        Algorithm::default(),
+        false,
+        &crate::DEFAULT_SEPARATOR,
+        channel.clone(),
+        &repo,
+        &changes,
+        "",
+        1,
+    )?;
+    assert!(state.finish().actions.is_empty());
+
+    txn.commit()?;
+    Ok(())
+}
+
+#[test]
+fn combine_attribution() {
+    use crate::attribution::SerializedAttribution;
+    let human = SerializedAttribution {
+        author: None,
+        ai_assisted: false,
+        ai_metadata: None,
+        confidence: None,
+        attribution_version: 1,
+    };
+    let ai = SerializedAttribution {
+        ai_assisted: true,
+        confidence: Some(0.7),
+        ..human.clone()
+    };
+    let human = bincode::serialize(&human).unwrap();
+    let ai = bincode::serialize(&ai).unwrap();
+    let combined = crate::squash::combine_metadata([&human[..], &[][..], &ai[..]].into_iter());
+    let combined: SerializedAttribution = bincode::deserialize(&combined).unwrap();
+    assert!(combined.ai_assisted);
+    assert_eq!(combined.confidence, Some(0.7));
+
+    assert!(crate::squash::combine_metadata([&b"x"[..], &b"y"[..]].into_iter()).is_empty());
+    assert_eq!(
+        crate::squash::combine_metadata([&b"x"[..], &[][..]].into_iter()),
+        b"x"
+    );
+}
@@ -8,6 +8,7 @@ use std::path::{Path, PathBuf};
 #[derive(Clone)]
 pub struct FileSystem {
     root: PathBuf,
+    normalization: std::sync::Arc<normalize::Normalization>,
 }
 
 /// Returns whether `path` is a child of `root_` (or `root_` itself).
@@ -153,9 +154,17 @@ impl FileSystem {
     pub fn from_root<P: AsRef<Path>>(root: P) -> Self {
         FileSystem {
             root: root.as_ref().to_path_buf(),
+            normalization: std::sync::Arc::new(normalize::Normalization::default()),
         }
     }
 
+    /// Apply the end-of-line and encoding policies of `normalization` to
+    /// the files read and written in this working copy.
+    pub fn with_normalization(mut self, normalization: normalize::Normalization) -> Self {
+        self.normalization = std::sync::Arc::new(normalization);
+        self
+    }
+
     pub fn record_prefixes<
         T: crate::MutTxnTExt + crate::TxnTExt + Send + Sync + 'static,
         C: crate::changestore::ChangeStore + Clone + Send + 'static,
@@ -379,7 +388,7 @@ impl WorkingCopyRead for FileSystem {
     fn file_metadata(&self, file: &str) -> Result<InodeMetadata, Self::Error> {
         debug!("metadata {:?}", file);
         let attr = std::fs::metadata(&self.path(file))?;
-        let permissions = permissions(&attr).unwrap_or(0o700);
+        let permissions = crate::permissions::mode(&attr).unwrap_or(0o700);
         debug!("permissions = {:?}", permissions);
         Ok(InodeMetadata::new(permissions & 0o100, attr.is_dir()))
     }
@@ -387,10 +396,25 @@ impl WorkingCopyRead for FileSystem {
         use std::io::Read;
         debug!("read_file {:?}", file);
         let mut f = std::fs::File::open(&self.path(file))?;
+        let init = buffer.len();
         f.read_to_end(buffer)?;
+        self.normalization.policy(file).to_recorded(buffer, init);
         Ok(())
     }
 
+    fn decode_file(
+        &self,
+        file: &str,
+        buffer: &mut Vec<u8>,
+    ) -> Result<Option<Encoding>, Self::Error> {
+        let init = buffer.len();
+        self.read_file(file, buffer)?;
+        if let Some(encoding) = self.normalization.policy(file).encoding {
+            return Ok(Some(encoding));
+        }
+        Ok(detect_encoding(&buffer[init..]))
+    }
+
     #[cfg(not(unix))]
     fn modified_time(&self, file: &str) -> Result<std::time::SystemTime, Self::Error> {
         debug!("modified_time {:?}", file);
@@ -445,33 +469,14 @@ impl WorkingCopy for FileSystem {
         std::fs::rename(&former, &new)?;
         Ok(())
     }
-    #[cfg(not(windows))]
     fn set_permissions(&self, name: &str, permissions: u16) -> Result<(), Self::Error> {
-        use std::os::unix::fs::PermissionsExt;
         let name = self.path(name);
-        debug!("set_permissions: {:?}", name);
-        let metadata = std::fs::metadata(&name)?;
-        let mut current = metadata.permissions();
-        debug!(
-            "setting mode for {:?} to {:?} (currently {:?})",
-            name, permissions, current
-        );
-        if permissions & 0o100 != 0 {
-            current.set_mode(current.mode() | 0o100);
-        } else {
-            current.set_mode(current.mode() & ((!0o777) | 0o666));
-        }
-        debug!("setting {:?}", current);
-        std::fs::set_permissions(name, current)?;
-        debug!("set");
-        Ok(())
-    }
-    #[cfg(windows)]
-    fn set_permissions(&self, _name: &str, _permissions: u16) -> Result<(), Self::Error> {
+        debug!("set_permissions: {:?} {:?}", name, permissions);
+        crate::permissions::set_executable(&name, crate::permissions::is_executable(permissions))?;
         Ok(())
     }
 
-    type Writer = std::io::BufWriter<std::fs::File>;
+    type Writer = normalize::Writer<std::io::BufWriter<std::fs::File>>;
     fn write_file(&self, file: &str, _: Inode) -> Result<Self::Writer, Self::Error> {
         let path = self.path(file);
         debug!("path = {:?}", path);
@@ -480,18 +485,8 @@ impl WorkingCopy for FileSystem {
         }
         debug!("write_file: dir created");
         std::fs::remove_file(&path).unwrap_or(());
-        let file = std::io::BufWriter::new(std::fs::File::create(&path)?);
+        let w = std::io::BufWriter::new(std::fs::File::create(&path)?);
         debug!("file");
-        Ok(file)
+        Ok(self.normalization.policy(file).writer(w))
     }
 }
-
-#[cfg(not(windows))]
-fn permissions(attr: &std::fs::Metadata) -> Option<usize> {
-    use std::os::unix::fs::PermissionsExt;
-    Some(attr.permissions().mode() as usize)
-}
-#[cfg(windows)]
-fn permissions(_: &std::fs::Metadata) -> Option<usize> {
-    None
-}
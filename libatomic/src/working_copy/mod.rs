@@ -11,6 +11,9 @@ pub use filesystem::FileSystem;
 pub mod memory;
 pub use memory::Memory;
 
+pub mod normalize;
+pub use normalize::Normalization;
+
 pub trait WorkingCopyRead {
     type Error: std::error::Error + Send;
     fn file_metadata(&self, file: &str) -> Result<InodeMetadata, Self::Error>;
@@ -26,16 +29,17 @@ pub trait WorkingCopyRead {
     ) -> Result<Option<Encoding>, Self::Error> {
         let init = buffer.len();
         self.read_file(&file, buffer)?;
-        let mut detector = EncodingDetector::new();
-        detector.feed(&buffer[init..], true);
-        if let Some(e) = crate::get_valid_encoding(&detector, None, true, &buffer[init..]) {
-            Ok(Some(Encoding(e)))
-        } else {
-            Ok(None)
-        }
+        Ok(detect_encoding(&buffer[init..]))
     }
 }
 
+/// Guess the text encoding of `contents`, or `None` if it is binary
+pub(crate) fn detect_encoding(contents: &[u8]) -> Option<Encoding> {
+    let mut detector = EncodingDetector::new();
+    detector.feed(contents, true);
+    crate::get_valid_encoding(&detector, None, true, contents).map(Encoding)
+}
+
 pub trait WorkingCopy: WorkingCopyRead {
     fn is_writable(&self, _path: &str) -> Result<bool, Self::Error> {
         Ok(true)
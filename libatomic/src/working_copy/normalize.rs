@@ -0,0 +1,256 @@
+//! End-of-line and encoding policies of the working copy
+//!
+//! Teams on different platforms otherwise record spurious hunks when
+//! editors change line endings. A [`Normalization`] is a list of rules,
+//! each one matching paths with a glob, and setting:
+//!
+//! - `eol`: files are recorded with LF line endings, and output with LF,
+//!   CRLF, or the line ending of the platform (`native`). Setting `eol`
+//!   makes the files text files, as in Git.
+//! - `encoding`: the text encoding recorded in changes, instead of the one
+//!   guessed from the contents of the file.
+//!
+//! Rules are applied in order, later rules overriding the attributes set
+//! by earlier ones. Patterns without a `/` match file names in any
+//! directory; other patterns match paths from the root of the repository,
+//! with `**` matching any number of directories.
+
+use std::borrow::Cow;
+use std::io::Write;
+
+use crate::text_encoding::Encoding;
+
+#[derive(Debug, Error)]
+pub enum NormalizationError {
+    #[error("Invalid path pattern: {pattern}")]
+    InvalidPattern { pattern: String },
+    #[error("Unknown line ending {eol:?}, expected lf, crlf or native")]
+    UnknownEol { eol: String },
+    #[error("Unknown text encoding {label:?}")]
+    UnknownEncoding { label: String },
+}
+
+/// Line endings of the files output in the working copy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eol {
+    Lf,
+    Crlf,
+    /// CRLF on Windows, LF elsewhere
+    Native,
+}
+
+impl Eol {
+    fn is_crlf(self) -> bool {
+        match self {
+            Eol::Lf => false,
+            Eol::Crlf => true,
+            Eol::Native => cfg!(windows),
+        }
+    }
+}
+
+impl std::str::FromStr for Eol {
+    type Err = NormalizationError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lf" => Ok(Eol::Lf),
+            "crlf" => Ok(Eol::Crlf),
+            "native" => Ok(Eol::Native),
+            _ => Err(NormalizationError::UnknownEol { eol: s.to_string() }),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: regex::Regex,
+    eol: Option<Eol>,
+    encoding: Option<Encoding>,
+}
+
+/// The policies of a working copy
+#[derive(Debug, Clone, Default)]
+pub struct Normalization {
+    rules: Vec<Rule>,
+}
+
+impl Normalization {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Add a rule for the paths matching `pattern`. `encoding` is a label
+    /// of the [Encoding Standard](https://encoding.spec.whatwg.org/),
+    /// such as `utf-8` or `windows-1252`.
+    pub fn add_rule(
+        &mut self,
+        pattern: &str,
+        eol: Option<Eol>,
+        encoding: Option<&str>,
+    ) -> Result<(), NormalizationError> {
+        let encoding = if let Some(label) = encoding {
+            if let Some(e) = encoding_rs::Encoding::for_label_no_replacement(label.as_bytes()) {
+                Some(Encoding(e))
+            } else {
+                return Err(NormalizationError::UnknownEncoding {
+                    label: label.to_string(),
+                });
+            }
+        } else {
+            None
+        };
+        let regex = glob_to_regex(pattern);
+        let pattern =
+            regex::Regex::new(&regex).map_err(|_| NormalizationError::InvalidPattern {
+                pattern: pattern.to_string(),
+            })?;
+        self.rules.push(Rule {
+            pattern,
+            eol,
+            encoding,
+        });
+        Ok(())
+    }
+
+    /// The policy of `path`, relative to the root of the repository
+    pub fn policy(&self, path: &str) -> Policy {
+        let mut policy = Policy::default();
+        for rule in self.rules.iter() {
+            if rule.pattern.is_match(path) {
+                if rule.eol.is_some() {
+                    policy.eol = rule.eol
+                }
+                if rule.encoding.is_some() {
+                    policy.encoding = rule.encoding.clone()
+                }
+            }
+        }
+        policy
+    }
+}
+
+/// Translate a glob into an anchored regular expression. Globs without
+/// a `/` match names in any directory, other globs match paths from the
+/// root.
+pub fn glob_to_regex(glob: &str) -> String {
+    let mut re = String::from(if glob.contains('/') { "^" } else { "^(?:.*/)?" });
+    let glob = glob.trim_start_matches('/');
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?")
+                } else {
+                    re.push_str(".*")
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    re.push('$');
+    re
+}
+
+/// The policy of a single file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Policy {
+    pub eol: Option<Eol>,
+    pub encoding: Option<Encoding>,
+}
+
+impl Policy {
+    /// Whether line endings can be converted: CR and LF are single bytes
+    /// only in ASCII-compatible encodings.
+    fn converts_eol(&self) -> bool {
+        self.eol.is_some()
+            && self
+                .encoding
+                .as_ref()
+                .map_or(true, |e| e.0.is_ascii_compatible())
+    }
+
+    /// Normalize `buffer[start..]`, read from the working copy, to the
+    /// contents recorded in changes
+    pub fn to_recorded(&self, buffer: &mut Vec<u8>, start: usize) {
+        if self.converts_eol() {
+            crlf_to_lf(buffer, start)
+        }
+    }
+
+    /// Wrap a writer of the working copy, so that it converts recorded
+    /// contents to this policy
+    pub fn writer<W: Write>(&self, w: W) -> Writer<W> {
+        Writer {
+            w,
+            crlf: self.converts_eol() && self.eol.map_or(false, Eol::is_crlf),
+            last_cr: false,
+        }
+    }
+}
+
+/// Replace CRLF with LF in `buffer[start..]`, in place
+pub fn crlf_to_lf(buffer: &mut Vec<u8>, start: usize) {
+    let mut w = start;
+    let mut r = start;
+    while r < buffer.len() {
+        if buffer[r] == b'\r' && buffer.get(r + 1) == Some(&b'\n') {
+            r += 1;
+        }
+        buffer[w] = buffer[r];
+        w += 1;
+        r += 1;
+    }
+    buffer.truncate(w)
+}
+
+/// Replace LF with CRLF in `text`, leaving existing CRLFs alone
+pub fn lf_to_crlf(text: &[u8]) -> Cow<'_, [u8]> {
+    if !text.contains(&b'\n') {
+        return Cow::Borrowed(text);
+    }
+    let mut out = Vec::with_capacity(text.len() + text.len() / 16);
+    let mut last_cr = false;
+    push_crlf(&mut out, text, &mut last_cr);
+    Cow::Owned(out)
+}
+
+fn push_crlf(out: &mut Vec<u8>, text: &[u8], last_cr: &mut bool) {
+    for &b in text {
+        if b == b'\n' && !*last_cr {
+            out.push(b'\r')
+        }
+        out.push(b);
+        *last_cr = b == b'\r'
+    }
+}
+
+/// A writer of the working copy, converting line endings on the fly
+pub struct Writer<W: Write> {
+    w: W,
+    crlf: bool,
+    last_cr: bool,
+}
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if !self.crlf {
+            return self.w.write(buf);
+        }
+        let mut out = Vec::with_capacity(buf.len() + buf.len() / 16);
+        push_crlf(&mut out, buf, &mut self.last_cr);
+        self.w.write_all(&out)?;
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.w.flush()
+    }
+}
@@ -0,0 +1,103 @@
+//! Signed tags: the signature covers the state and header of the tag, is
+//! stored in the unhashed section of the tag file, and survives the short
+//! version sent over the network.
+
+use libatomic::change::ChangeHeader;
+use libatomic::key::SKey;
+use libatomic::pristine::MutTxnT;
+use libatomic::tag::{OpenTagFile, SignatureStatus, Unhashed};
+use tempfile::tempdir;
+
+fn tag_header(message: &str) -> ChangeHeader {
+    ChangeHeader {
+        message: message.to_string(),
+        authors: vec![],
+        description: None,
+        timestamp: chrono::Utc::now(),
+    }
+}
+
+#[test]
+fn test_signed_tag_roundtrip() {
+    let tmp = tempdir().unwrap();
+    let pristine =
+        libatomic::pristine::sanakirja::Pristine::new(&tmp.path().join("pristine.db")).unwrap();
+    {
+        let mut txn = pristine.mut_txn_begin().unwrap();
+        txn.open_or_create_channel("main").unwrap();
+        txn.commit().unwrap();
+    }
+    let txn = pristine.txn_begin().unwrap();
+    let header = tag_header("v1.0.0");
+
+    // The state is known before writing the tag, since it is the
+    // current state of the channel.
+    let mut unsigned = Vec::new();
+    let state = libatomic::tag::from_channel(&txn, "main", &header, &mut unsigned).unwrap();
+
+    let key = SKey::generate(None);
+    let unhashed = Unhashed {
+        signature: Some(libatomic::tag::sign(&key, &state, &header).unwrap()),
+    };
+    let path = tmp.path().join("signed.tag");
+    let mut w = std::fs::File::create(&path).unwrap();
+    libatomic::tag::from_channel_with_unhashed(&txn, "main", &header, &unhashed, &mut w).unwrap();
+    drop(w);
+
+    let mut f = OpenTagFile::open(&path, &state).unwrap();
+    match f.verify().unwrap() {
+        SignatureStatus::Valid { key: k, .. } => assert_eq!(k, key.public_key().key),
+        s => panic!("unexpected status {:?}", s),
+    }
+
+    // The signature is transmitted with the short version.
+    let mut short = Vec::new();
+    f.short(&mut short).unwrap();
+    assert!(!f.header.is_short());
+    let short_header = libatomic::tag::FileHeader::read(&short[..]).unwrap();
+    assert!(short_header.is_short());
+    let (h, u) = libatomic::tag::read_short_signed(std::io::Cursor::new(&short), &state).unwrap();
+    assert_eq!(h, header);
+    let signer = key.public_key().key;
+    assert!(
+        !libatomic::tag::check_received(&state, &h, &u, |k| k == signer)
+            .unwrap()
+            .is_invalid()
+    );
+    // Signatures by keys the receiver doesn't know are rejected.
+    assert!(matches!(
+        libatomic::tag::check_received(&state, &h, &u, |_| false),
+        Err(libatomic::tag::TagError::UntrustedSigner { .. })
+    ));
+    assert_eq!(
+        libatomic::tag::read_short(std::io::Cursor::new(&short), &state).unwrap(),
+        header
+    );
+
+    // Lengths beyond the received bytes are rejected before anything is
+    // allocated.
+    let mut forged: libatomic::tag::FileHeader = bincode::deserialize(&short).unwrap();
+    forged.total = u64::MAX;
+    let mut bytes = bincode::serialize(&forged).unwrap();
+    bytes.extend_from_slice(&short[bytes.len()..]);
+    assert!(matches!(
+        libatomic::tag::read_short_signed(std::io::Cursor::new(&bytes), &state),
+        Err(libatomic::tag::TagError::Truncated)
+    ));
+
+    // A signature doesn't carry over to a different header.
+    assert!(matches!(
+        libatomic::tag::check_received(&state, &tag_header("v2.0.0"), &u, |_| true),
+        Err(libatomic::tag::TagError::InvalidSignature { .. })
+    ));
+
+    // Unsigned tags are still accepted.
+    let unsigned_path = tmp.path().join("unsigned.tag");
+    std::fs::write(&unsigned_path, &unsigned).unwrap();
+    let mut f = OpenTagFile::open(&unsigned_path, &state).unwrap();
+    assert_eq!(f.verify().unwrap(), SignatureStatus::Unsigned);
+    let mut short = Vec::new();
+    f.short(&mut short).unwrap();
+    let (_, u) = libatomic::tag::read_short_signed(std::io::Cursor::new(&short), &state).unwrap();
+    assert!(u.signature.is_none());
+}